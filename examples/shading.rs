@@ -2,10 +2,11 @@ use std::time::Instant;
 
 use mars::{
 	buffer::Buffer,
-	function::{FunctionDef, FunctionImpl, FunctionPrototype},
+	function::{Bindings, FunctionDef, FunctionImpl, FunctionPrototype},
 	image::{format, samples::SampleCount8, usage, DynImageUsage},
 	math::*,
 	pass::{Attachments, DepthAttachment, MultisampledColorAttachment, RenderPass, RenderPassPrototype},
+	shapes::{self, Vertex},
 	target::Target,
 	window::WindowEngine,
 	Context,
@@ -101,20 +102,28 @@ impl RenderPassPrototype for ShadingPass {
 	type DepthAttachment = DepthAttachment<format::D32Sfloat, Self::SampleCount>;
 }
 
+#[derive(Bindings)]
+struct CubeBindings {
+	mvp: Mvp,
+	light_position: Vec3,
+}
+
 struct CubeShadingFunction;
 
 impl FunctionPrototype for CubeShadingFunction {
 	type RenderPass = ShadingPass;
-	type VertexInput = (Vec3, Vec3);
-	type Bindings = (Mvp, Vec3);
+	type VertexInput = Vertex;
+	type Bindings = CubeBindings;
+	type PushConstants = ();
 }
 
 struct LightShadingFunction;
 
 impl FunctionPrototype for LightShadingFunction {
 	type RenderPass = ShadingPass;
-	type VertexInput = (Vec3, Vec3);
+	type VertexInput = Vertex;
 	type Bindings = (Mvp,);
+	type PushConstants = ();
 }
 
 fn main() {
@@ -144,53 +153,7 @@ fn main() {
 		unsafe { FunctionImpl::<LightShadingFunction>::from_raw(light_vert_shader, light_frag_shader) };
 	let mut light_function_def = FunctionDef::create(&context, &render_pass, light_function_impl).unwrap();
 
-	#[rustfmt::skip]
-	let vertices: [(Vec3, Vec3); 36] = [
-		(Vec3::new(-0.5, -0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-		(Vec3::new( 0.5, -0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-		(Vec3::new( 0.5,  0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-		(Vec3::new( 0.5,  0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-		(Vec3::new(-0.5,  0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-		(Vec3::new(-0.5, -0.5, -0.5), Vec3::new(0.0,  0.0, -1.0)),
-	
-		(Vec3::new(-0.5, -0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-		(Vec3::new( 0.5, -0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-		(Vec3::new(-0.5,  0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-		(Vec3::new(-0.5, -0.5,  0.5), Vec3::new( 0.0,  0.0,  1.0)),
-	
-		(Vec3::new(-0.5,  0.5,  0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-		(Vec3::new(-0.5,  0.5, -0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-		(Vec3::new(-0.5, -0.5, -0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-		(Vec3::new(-0.5, -0.5, -0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-		(Vec3::new(-0.5, -0.5,  0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-		(Vec3::new(-0.5,  0.5,  0.5),  Vec3::new(-1.0,  0.0,  0.0)),
-	
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 1.0,  0.0,  0.0)),
-		(Vec3::new( 0.5,  0.5, -0.5), Vec3::new( 1.0,  0.0,  0.0)),
-		(Vec3::new( 0.5, -0.5, -0.5), Vec3::new( 1.0,  0.0,  0.0)),
-		(Vec3::new( 0.5, -0.5, -0.5), Vec3::new( 1.0,  0.0,  0.0)),
-		(Vec3::new( 0.5, -0.5,  0.5), Vec3::new( 1.0,  0.0,  0.0)),
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 1.0,  0.0,  0.0)),
-	
-		(Vec3::new(-0.5, -0.5, -0.5), Vec3::new( 0.0, -1.0,  0.0)),
-		(Vec3::new( 0.5, -0.5, -0.5), Vec3::new( 0.0, -1.0,  0.0)),
-		(Vec3::new( 0.5, -0.5,  0.5), Vec3::new( 0.0, -1.0,  0.0)),
-		(Vec3::new( 0.5, -0.5,  0.5), Vec3::new( 0.0, -1.0,  0.0)),
-		(Vec3::new(-0.5, -0.5,  0.5), Vec3::new( 0.0, -1.0,  0.0)),
-		(Vec3::new(-0.5, -0.5, -0.5), Vec3::new( 0.0, -1.0,  0.0)),
-	
-		(Vec3::new(-0.5,  0.5, -0.5), Vec3::new( 0.0,  1.0,  0.0)),
-		(Vec3::new( 0.5,  0.5, -0.5), Vec3::new( 0.0,  1.0,  0.0)),
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 0.0,  1.0,  0.0)),
-		(Vec3::new( 0.5,  0.5,  0.5), Vec3::new( 0.0,  1.0,  0.0)),
-		(Vec3::new(-0.5,  0.5,  0.5), Vec3::new( 0.0,  1.0,  0.0)),
-		(Vec3::new(-0.5,  0.5, -0.5), Vec3::new( 0.0,  1.0,  0.0)),
-	];
-	let indices = (0..36).collect::<Vec<_>>();
-	let vertex_buffer = Buffer::make_array_buffer(&context, &vertices).unwrap();
-	let index_buffer = Buffer::make_array_buffer(&context, &indices).unwrap();
+	let (vertex_buffer, index_buffer) = shapes::cube(1.0).buffers(&context).unwrap();
 
 	let extent = window_engine.current_extent();
 	let aspect = extent.width as f32 / extent.height as f32;
@@ -199,12 +162,18 @@ fn main() {
 		create_mvp(aspect, Point3::new(1.0, -1.5, 0.0), Vec3::new(0.0, 0.0, 0.0)),
 	)
 	.unwrap();
-	let light_position_buffer = Buffer::make_item_buffer(&context, Vec3::new(0.0, 0.0, 0.0)).unwrap();
+	let light_position_buffer = Buffer::make_item_buffer(&context, Vec3A::new(0.0, 0.0, 0.0)).unwrap();
 	let light_mvp_buffer =
 		Buffer::make_item_buffer(&context, Mvp::new(Mat4::identity(), Mat4::identity(), Mat4::identity())).unwrap();
 
 	let mut cube_arguments = cube_function_def
-		.make_arguments(&context, (cube_mvp_buffer, light_position_buffer))
+		.make_arguments(
+			&context,
+			CubeBindingsArguments {
+				mvp: cube_mvp_buffer,
+				light_position: light_position_buffer,
+			},
+		)
 		.unwrap();
 	let mut light_arguments = light_function_def
 		.make_arguments(&context, (light_mvp_buffer,))
@@ -220,25 +189,21 @@ fn main() {
 		let light_pos = Point3::new(t.cos() * 3.0, 1.5, t.sin() * 3.0);
 
 		cube_arguments
-			.arguments
-			.0
+			.mvp
 			.with_map_mut(|map| *map = create_mvp(aspect, Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0)))
 			.unwrap();
 		cube_arguments
-			.arguments
-			.1
-			.with_map_mut(|map| *map = Vec3::new(light_pos.x, light_pos.y, light_pos.z))
+			.light_position
+			.with_map_mut(|map| *map = Vec3A::new(light_pos.x, light_pos.y, light_pos.z))
 			.unwrap();
 
 		light_arguments
 			.arguments
 			.0
 			.with_map_mut(|map| {
-				let model = nalgebra::Isometry3::new(
-					Vec3::new(light_pos.x, light_pos.y, light_pos.z),
-					Vec3::new(0.0, 0.0, 0.0),
-				)
-				.to_homogeneous() * Mat4::new_scaling(0.3);
+				let model = Transform::from_translation(Vec3::new(light_pos.x, light_pos.y, light_pos.z))
+					.with_scale(Vec3::new(0.3, 0.3, 0.3))
+					.to_matrix();
 				let view = create_view();
 				let proj = create_proj(aspect);
 				let mvp = Mvp::new(model, view, proj);
@@ -304,7 +269,7 @@ fn compile_shader(source: &str, filename: &str, kind: shaderc::ShaderKind) -> Ve
 }
 
 fn create_model(position: Point3, rotation: Vec3) -> Mat4 {
-	nalgebra::Isometry3::new(position.to_homogeneous().xyz(), rotation).to_homogeneous()
+	Transform::from_axis_angle(position.coords, rotation).to_matrix()
 }
 
 fn create_view() -> Mat4 {
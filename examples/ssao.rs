@@ -0,0 +1,242 @@
+use std::time::Instant;
+
+use mars::{
+	buffer::Buffer,
+	camera::Camera,
+	function::{FunctionDef, FunctionImpl, FunctionPrototype},
+	image::{format, samples::SampleCount1, usage, DynImageUsage},
+	math::*,
+	pass::{Attachments, ColorAttachment, NoDepthAttachment, RenderPass, RenderPassPrototype},
+	render::DrawArgs,
+	shapes,
+	ssao::{NormalDepthFunction, NormalDepthPass, Ssao, SsaoOptions},
+	target::{OffscreenTarget, Target, TargetOutput},
+	window::WindowEngine,
+	Context,
+};
+
+use winit::{
+	event::{Event, WindowEvent},
+	event_loop::{ControlFlow, EventLoop},
+	window::WindowBuilder,
+};
+
+const COMPOSITE_VERTEX_SHADER: &str = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = position * 0.5 + 0.5;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+";
+
+const COMPOSITE_FRAGMENT_SHADER: &str = "
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D normal_depth;
+layout(set = 0, binding = 1) uniform sampler2D occlusion;
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	vec4 surface = texture(normal_depth, uv);
+	if (surface.w <= 0.0) {
+		color = vec4(0.3, 0.3, 0.35, 1.0);
+		return;
+	}
+	// A light shining from behind the camera, in view space
+	vec3 light = normalize(vec3(0.3, 0.5, 1.0));
+	float diffuse = max(dot(normalize(surface.xyz), light), 0.0);
+	float ambient = 0.4 * texture(occlusion, uv).r;
+	color = vec4(vec3(0.8) * (0.6 * diffuse + ambient), 1.0);
+}
+";
+
+struct CompositePass;
+
+impl RenderPassPrototype for CompositePass {
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<format::R8G8B8A8Unorm>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+struct CompositeFunction;
+
+impl FunctionPrototype for CompositeFunction {
+	type RenderPass = CompositePass;
+	type VertexInput = Vec2;
+	type Bindings = (TargetOutput, TargetOutput);
+	type PushConstants = ();
+}
+
+fn main() {
+	simple_logger::SimpleLogger::new().init().unwrap();
+
+	let event_loop = EventLoop::new();
+	let window = WindowBuilder::new().build(&event_loop).unwrap();
+
+	let context = Context::create("mars_ssao_example", rk::FirstPhysicalDeviceChooser).unwrap();
+
+	let mut window_engine = WindowEngine::new(&context, &window).unwrap();
+	let extent = window_engine.current_extent();
+
+	// The scene's normals and depth, then the raw and blurred occlusion at half resolution
+	let normal_depth_pass = RenderPass::<NormalDepthPass>::create(&context).unwrap();
+	let mut normal_depth = OffscreenTarget::create(&context, &normal_depth_pass, extent).unwrap();
+	let mut ssao = Ssao::create(&context, SsaoOptions::default()).unwrap();
+	let mut occlusion = OffscreenTarget::create(&context, ssao.render_pass(), half(extent)).unwrap();
+	let mut blurred = OffscreenTarget::create(&context, ssao.render_pass(), half(extent)).unwrap();
+
+	let composite_pass = RenderPass::<CompositePass>::create(&context).unwrap();
+	let attachments = Attachments::create(&context, extent, DynImageUsage::TRANSFER_SRC).unwrap();
+	let mut target = Target::create(&context, &composite_pass, attachments).unwrap();
+
+	let mut normal_depth_function = NormalDepthFunction::create(&context, &normal_depth_pass).unwrap();
+	let composite_vert_shader = compile_shader(COMPOSITE_VERTEX_SHADER, "vert.glsl", shaderc::ShaderKind::Vertex);
+	let composite_frag_shader = compile_shader(COMPOSITE_FRAGMENT_SHADER, "frag.glsl", shaderc::ShaderKind::Fragment);
+	let composite_function_impl =
+		unsafe { FunctionImpl::<CompositeFunction>::from_raw(composite_vert_shader, composite_frag_shader) };
+	let mut composite_function_def = FunctionDef::create(&context, &composite_pass, composite_function_impl).unwrap();
+
+	// A floor with a few cubes standing on it, where SSAO darkens the creases between them
+	let mut camera = Camera::perspective(3.14 / 3.0, 1.0, 0.1, 100.0);
+	camera.set_extent(extent);
+	let models = vec![
+		(shapes::plane(10.0, 10.0, 1), Mat4::identity()),
+		(shapes::cube(1.0), Mat4::new_translation(&Vec3::new(0.0, 0.5, 0.0))),
+		(shapes::cube(1.0), Mat4::new_translation(&Vec3::new(1.0, 0.5, 0.0))),
+		(shapes::cube(1.0), Mat4::new_translation(&Vec3::new(0.5, 1.5, 0.0))),
+		(
+			shapes::uv_sphere(0.6, 24, 16),
+			Mat4::new_translation(&Vec3::new(-1.5, 0.6, 1.0)),
+		),
+	];
+	let objects = models
+		.into_iter()
+		.map(|(mesh, model)| {
+			let (vertices, indices) = mesh.buffers(&context).unwrap();
+			let mvp = Buffer::make_item_buffer(&context, camera.mvp(model)).unwrap();
+			let arguments = normal_depth_function.make_arguments(&context, (mvp,)).unwrap();
+			(model, arguments, vertices, indices)
+		})
+		.collect::<Vec<_>>();
+
+	let fullscreen_vertices = Buffer::make_array_buffer(
+		&context,
+		&[Vec2::new(-1.0, -1.0), Vec2::new(3.0, -1.0), Vec2::new(-1.0, 3.0)],
+	)
+	.unwrap();
+	let fullscreen_indices = Buffer::make_array_buffer(&context, &[0, 1, 2]).unwrap();
+	let mut composite_arguments = composite_function_def
+		.make_arguments(&context, (normal_depth.output(0), blurred.output(0)))
+		.unwrap();
+
+	let start = Instant::now();
+	event_loop.run(move |event, _, control_flow| {
+		let t = start.elapsed().as_secs_f32() * 0.3;
+		camera.position = Point3::new(t.cos() * 5.0, 3.0, t.sin() * 5.0);
+		camera.look_at(&Point3::new(0.0, 0.5, 0.0), &Vec3::y());
+		for (model, arguments, _, _) in &objects {
+			arguments.arguments.0.upload(&context, camera.mvp(*model)).unwrap();
+		}
+
+		let engine = &mut window_engine.render;
+		engine
+			.clear(&context, normal_depth.target(), (Vec4::zeros(),), 1.0)
+			.unwrap();
+		engine
+			.pass(
+				&context,
+				normal_depth.target(),
+				&normal_depth_function,
+				objects.iter().map(|(_, arguments, vertices, indices)| DrawArgs {
+					bindings: arguments,
+					vertices,
+					indices,
+					push_constants: (),
+				}),
+			)
+			.unwrap();
+		ssao.apply(
+			&context,
+			engine,
+			&mut normal_depth,
+			&camera.proj(),
+			&mut occlusion,
+			&mut blurred,
+		)
+		.unwrap();
+
+		// Both offscreen targets are read by the composite, so both have to be sampleable
+		normal_depth
+			.sample(&context, engine, |engine| {
+				blurred.sample(&context, engine, |engine| {
+					engine.pass(
+						&context,
+						&mut target,
+						&composite_function_def,
+						Some(DrawArgs {
+							bindings: &composite_arguments,
+							vertices: &fullscreen_vertices,
+							indices: &fullscreen_indices,
+							push_constants: (),
+						}),
+					)
+				})
+			})
+			.unwrap();
+
+		if let Some(new_extent) = window_engine
+			.present(
+				&context,
+				target
+					.color_attachments()
+					.0
+					.image
+					.cast_usage_ref(usage::TransferSrc)
+					.unwrap(),
+			)
+			.unwrap()
+		{
+			let attachments = Attachments::create(&context, new_extent, DynImageUsage::TRANSFER_SRC).unwrap();
+			target.change_attachments(&context, attachments).unwrap();
+			normal_depth.resize(&context, new_extent).unwrap();
+			occlusion.resize(&context, half(new_extent)).unwrap();
+			blurred.resize(&context, half(new_extent)).unwrap();
+			composite_arguments = composite_function_def
+				.make_arguments(&context, (normal_depth.output(0), blurred.output(0)))
+				.unwrap();
+			camera.set_extent(new_extent);
+		}
+
+		match event {
+			Event::WindowEvent {
+				event: WindowEvent::CloseRequested,
+				..
+			} => *control_flow = ControlFlow::Exit,
+			_ => {}
+		}
+	});
+}
+
+fn half(extent: rk::vk::Extent2D) -> rk::vk::Extent2D {
+	rk::vk::Extent2D {
+		width: (extent.width / 2).max(1),
+		height: (extent.height / 2).max(1),
+	}
+}
+
+fn compile_shader(source: &str, filename: &str, kind: shaderc::ShaderKind) -> Vec<u32> {
+	let mut compiler = shaderc::Compiler::new().expect("Failed to initialize compiler");
+	let artifact = compiler
+		.compile_into_spirv(source, kind, filename, "main", None)
+		.expect("Failed to compile shader");
+	artifact.as_binary().to_owned()
+}
@@ -3,7 +3,7 @@ use std::time::Instant;
 use mars::{
 	buffer::Buffer,
 	function::{FunctionDef, FunctionImpl, FunctionPrototype},
-	image::{format, samples::SampleCount8, usage, DynImageUsage, Image, SampledImage},
+	image::{format, samples::SampleCount8, usage, DynImageUsage, Image, MipmapMode, SampledImage},
 	math::*,
 	pass::{Attachments, MultisampledColorAttachment, NoDepthAttachment, RenderPass, RenderPassPrototype},
 	target::Target,
@@ -67,6 +67,7 @@ impl FunctionPrototype for TextureFunction {
 	type RenderPass = TexturePass;
 	type VertexInput = (Vec2, Vec2);
 	type Bindings = (Mvp, SampledImage<format::R8G8B8A8Srgb>);
+	type PushConstants = ();
 }
 
 fn main() {
@@ -95,7 +96,7 @@ fn main() {
 		(Vec2::new(0.5, 0.5), Vec2::new(1.0, 1.0)),
 		(Vec2::new(-0.5, 0.5), Vec2::new(0.0, 1.0)),
 	];
-	let indices = [0, 1, 2, 2, 3, 0];
+	let indices: [u32; 6] = [0, 1, 2, 2, 3, 0];
 	let vertex_buffer = Buffer::make_array_buffer(&context, &vertices).unwrap();
 	let index_buffer = Buffer::make_array_buffer(&context, &indices).unwrap();
 
@@ -111,6 +112,7 @@ fn main() {
 			height: texture_data.height(),
 		},
 		&texture_data,
+		MipmapMode::Generate,
 	)
 	.unwrap();
 	let sampled_image = SampledImage::create(&context, image).unwrap();
@@ -172,7 +174,7 @@ fn main() {
 }
 
 fn create_model(position: Point3, rotation: Vec3) -> Mat4 {
-	nalgebra::Isometry3::new(position.to_homogeneous().xyz(), rotation).to_homogeneous()
+	Transform::from_axis_angle(position.coords, rotation).to_matrix()
 }
 
 fn create_view() -> Mat4 {
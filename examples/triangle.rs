@@ -1,6 +1,6 @@
 use mars::{
 	buffer::Buffer,
-	function::{FunctionDef, FunctionImpl, FunctionPrototype},
+	function::{ArgumentsContainer, FunctionDef, FunctionImpl, FunctionPrototype},
 	image::{format, usage, DynImageUsage, SampleCount1},
 	math::*,
 	pass::{Attachments, ColorAttachment, NoDepthAttachment, RenderPass, RenderPassPrototype},
@@ -56,6 +56,7 @@ impl FunctionPrototype for TriangleFunction {
 	type RenderPass = TrianglePass;
 	type VertexInput = (Vec4, Vec4);
 	type Bindings = ();
+	type PushConstants = ();
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -64,7 +65,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let event_loop = EventLoop::new();
 	let window = WindowBuilder::new().build(&event_loop)?;
 
-	let context = Context::create("mars_triangle_example", rk::FirstPhysicalDeviceChooser)?;
+	let context = Context::create("mars_triangle_example", mars::device::PreferDiscrete)?;
 
 	let mut window_engine = WindowEngine::new(&context, &window)?;
 
@@ -75,18 +76,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let vert_shader = compile_shader(TRIANGLE_VERTEX_SHADER, "vert.glsl", shaderc::ShaderKind::Vertex)?;
 	let frag_shader = compile_shader(TRIANGLE_FRAGMENT_SHADER, "frag.glsl", shaderc::ShaderKind::Fragment)?;
 	let function_impl = unsafe { FunctionImpl::<TriangleFunction>::from_raw(vert_shader, frag_shader) };
-	let mut function_def = FunctionDef::create(&context, &render_pass, function_impl)?;
+	let function_def = FunctionDef::create(&context, &render_pass, function_impl)?;
 
 	let vertices = [
 		(Vec4::new(-0.5, 0.5, 0.0, 1.0), Vec4::new(1.0, 0.0, 0.0, 1.0)),
 		(Vec4::new(0.0, -0.5, 0.0, 1.0), Vec4::new(0.0, 1.0, 0.0, 1.0)),
 		(Vec4::new(0.5, 0.5, 0.0, 1.0), Vec4::new(0.0, 0.0, 1.0, 1.0)),
 	];
-	let indices = [0, 1, 2];
+	let indices: [u32; 3] = [0, 1, 2];
 	let vertex_buffer = Buffer::make_array_buffer(&context, &vertices)?;
 	let index_buffer = Buffer::make_array_buffer(&context, &indices)?;
 
-	let set = function_def.make_arguments(&context, ())?;
+	let set = ArgumentsContainer::empty();
 
 	event_loop.run(move |event, _, control_flow| {
 		window_engine
@@ -64,6 +64,7 @@ impl FunctionPrototype for UniformFunction {
 	type RenderPass = UniformPass;
 	type VertexInput = (Vec4, Vec4);
 	type Bindings = (Mvp,);
+	type PushConstants = ();
 }
 
 fn main() {
@@ -92,7 +93,7 @@ fn main() {
 		(Vec4::new(0.5, 0.5, 0.0, 1.0), Vec4::new(0.0, 0.0, 1.0, 1.0)),
 		(Vec4::new(0.0, 0.0, -0.5, 1.0), Vec4::new(1.0, 1.0, 1.0, 1.0)),
 	];
-	let indices = [0, 1, 2, 0, 1, 3, 1, 2, 3, 0, 2, 3];
+	let indices: [u32; 12] = [0, 1, 2, 0, 1, 3, 1, 2, 3, 0, 2, 3];
 	let vertex_buffer = Buffer::make_array_buffer(&context, &vertices).unwrap();
 	let index_buffer = Buffer::make_array_buffer(&context, &indices).unwrap();
 
@@ -179,7 +180,7 @@ fn compile_shader(source: &str, filename: &str, kind: shaderc::ShaderKind) -> Ve
 }
 
 fn create_model(position: Point3, rotation: Vec3) -> Mat4 {
-	nalgebra::Isometry3::new(position.to_homogeneous().xyz(), rotation).to_homogeneous()
+	Transform::from_axis_angle(position.coords, rotation).to_matrix()
 }
 
 fn create_view() -> Mat4 {
@@ -0,0 +1,165 @@
+//! Derive macros for mars, re-exported by it next to the traits they implement.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, FieldsNamed, Lit, Meta, NestedMeta, Path};
+
+/// Implements `Parameter` for a `#[repr(C)]` struct with named fields, whose fields are all
+/// parameters themselves. The attributes of the fields are given consecutive locations in
+/// declaration order, at the offsets of the fields. If mars is reachable under another path, such as
+/// when it's renamed or re-exported, give it with `#[mars(crate = "path::to::mars")]`.
+#[proc_macro_derive(Parameter, attributes(mars))]
+pub fn derive_parameter(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	expand_parameter(input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand_parameter(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+	if !is_repr_c(&input) {
+		return Err(syn::Error::new_spanned(
+			&input.ident,
+			"Parameter can only be derived for #[repr(C)] structs, whose field offsets are fixed",
+		));
+	}
+	let mars = crate_path(&input)?;
+	let fields = named_fields(&input, "Parameter")?.clone();
+	let names = fields.named.iter().map(|field| &field.ident).collect::<Vec<_>>();
+	let types = fields.named.iter().map(|field| &field.ty).collect::<Vec<_>>();
+
+	let where_clause = input.generics.make_where_clause();
+	for ty in &types {
+		where_clause
+			.predicates
+			.push(parse_quote!(#ty: #mars::function::Parameter));
+	}
+	let name = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+	Ok(quote! {
+		unsafe impl #impl_generics #mars::function::Parameter for #name #ty_generics #where_clause {
+			fn attributes() -> ::std::vec::Vec<#mars::function::AttributeDesc> {
+				let uninit = ::std::mem::MaybeUninit::<Self>::uninit();
+				let base = uninit.as_ptr();
+				let mut attributes = ::std::vec::Vec::new();
+				#(
+					// Only the address of the field is taken, nothing is read
+					let offset = unsafe { ::std::ptr::addr_of!((*base).#names) as usize - base as usize };
+					attributes.extend(#mars::function::AttributeDesc::offset_by(
+						<#types as #mars::function::Parameter>::attributes(),
+						offset as u32,
+					));
+				)*
+				attributes
+			}
+		}
+	})
+}
+
+/// Implements `Bindings` for a struct with named fields, whose fields are all bindings. The fields
+/// are bound in declaration order, starting at binding 0. The struct only describes the bindings and
+/// is never constructed. Their arguments are given in a struct generated next to it, named after it
+/// with `Arguments` appended, with a public field of the same name for each binding. If the struct
+/// is generic, the arguments struct takes the same generic parameters and has an extra `_marker`
+/// field to set to `PhantomData`. Takes the same `#[mars(crate = "...")]` attribute as `Parameter`.
+#[proc_macro_derive(Bindings, attributes(mars))]
+pub fn derive_bindings(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	expand_bindings(input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand_bindings(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+	let mars = crate_path(&input)?;
+	let fields = named_fields(&input, "Bindings")?.clone();
+	let names = fields.named.iter().map(|field| &field.ident).collect::<Vec<_>>();
+	let types = fields.named.iter().map(|field| &field.ty).collect::<Vec<_>>();
+
+	let where_clause = input.generics.make_where_clause();
+	for ty in &types {
+		where_clause
+			.predicates
+			.push(parse_quote!(#ty: #mars::function::Binding));
+	}
+	let name = &input.ident;
+	let vis = &input.vis;
+	let arguments = format_ident!("{}Arguments", name);
+	let arguments_doc = format!("The arguments of the bindings of [`{}`]", name);
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	// The fields only name the generic parameters through associated types, which doesn't count as
+	// using them
+	let marker = if input.generics.params.is_empty() {
+		None
+	} else {
+		Some(quote!(pub _marker: ::std::marker::PhantomData<fn() -> #name #ty_generics>,))
+	};
+
+	Ok(quote! {
+		#[doc = #arguments_doc]
+		#vis struct #arguments #impl_generics #where_clause {
+			#(pub #names: <#types as #mars::function::Binding>::Argument,)*
+			#marker
+		}
+
+		impl #impl_generics #mars::function::Arguments for #arguments #ty_generics #where_clause {
+			fn as_writes(&self) -> ::std::vec::Vec<#mars::function::WriteArgument<'_>> {
+				::std::vec![#(#mars::function::Argument::as_write(&self.#names)),*]
+			}
+		}
+
+		unsafe impl #impl_generics #mars::function::Bindings for #name #ty_generics #where_clause {
+			type Arguments = #arguments #ty_generics;
+
+			fn descriptions() -> ::std::vec::Vec<#mars::function::BindingDesc> {
+				::std::vec![#(<#types as #mars::function::Binding>::description()),*]
+			}
+		}
+	})
+}
+
+/// The path to mars given by a `#[mars(crate = "...")]` attribute, or `::mars`
+fn crate_path(input: &DeriveInput) -> syn::Result<Path> {
+	for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("mars")) {
+		let list = match attr.parse_meta()? {
+			Meta::List(list) => list,
+			meta => return Err(syn::Error::new_spanned(meta, "expected #[mars(crate = \"...\")]")),
+		};
+		for nested in &list.nested {
+			match nested {
+				NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("crate") => match &value.lit {
+					Lit::Str(path) => return path.parse(),
+					lit => return Err(syn::Error::new_spanned(lit, "expected a string containing a path")),
+				},
+				_ => return Err(syn::Error::new_spanned(nested, "unknown mars attribute")),
+			}
+		}
+	}
+	Ok(parse_quote!(::mars))
+}
+
+/// Whether any `repr` attribute of `input` contains `C`, alongside options like `align(16)` or not
+fn is_repr_c(input: &DeriveInput) -> bool {
+	input.attrs.iter().any(|attr| match attr.parse_meta() {
+		Ok(Meta::List(list)) if list.path.is_ident("repr") => list
+			.nested
+			.iter()
+			.any(|repr| matches!(repr, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("C"))),
+		_ => false,
+	})
+}
+
+/// The fields of a struct with named fields, or an error naming `derive` for anything else
+fn named_fields<'a>(input: &'a DeriveInput, derive: &str) -> syn::Result<&'a FieldsNamed> {
+	match &input.data {
+		Data::Struct(data) => match &data.fields {
+			Fields::Named(fields) => Ok(fields),
+			_ => Err(syn::Error::new_spanned(
+				&input.ident,
+				format!("{} can only be derived for structs with named fields", derive),
+			)),
+		},
+		_ => Err(syn::Error::new_spanned(
+			&input.ident,
+			format!("{} can only be derived for structs", derive),
+		)),
+	}
+}
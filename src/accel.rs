@@ -0,0 +1,383 @@
+use std::sync::Arc;
+
+use rk::{ash::extensions::khr, vk};
+
+use crate::{
+	buffer::{Buffer, IndexBufferUsage, IndexType, VertexBufferUsage},
+	function::{
+		Argument, AttributeFormat, Binding, BindingDesc, BindingType, Parameter, WriteAccelerationStructureArgument,
+		WriteArgument,
+	},
+	math::*,
+	memory::DeviceBuffer,
+	raw_device, raw_instance, raw_physical_device, sync, Context, MarsResult,
+};
+
+/// A bottom or top level acceleration structure stored on the GPU.
+///
+/// Binding a top level acceleration structure to a function lets its shaders trace rays against it
+/// with `VK_KHR_ray_query`, which requires the `RayQuery` device extension to be enabled.
+pub struct AccelerationStructure {
+	pub(crate) acceleration_structure: vk::AccelerationStructureKHR,
+	pub(crate) loader: khr::AccelerationStructure,
+	#[allow(unused)]
+	pub(crate) storage: DeviceBuffer,
+	pub(crate) ty: vk::AccelerationStructureTypeKHR,
+	// Top level acceleration structures keep the bottom level ones they reference alive
+	#[allow(unused)]
+	pub(crate) children: Vec<Arc<AccelerationStructure>>,
+}
+
+/// Options controlling how an acceleration structure is built
+#[derive(Debug, Copy, Clone)]
+pub struct BuildOptions {
+	/// Compact the acceleration structure after building it, which takes an extra submission but
+	/// usually frees up a good amount of memory
+	pub compact: bool,
+	/// Prefer a faster build over faster ray traversal, for structures that are rebuilt often
+	pub prefer_fast_build: bool,
+}
+
+impl Default for BuildOptions {
+	fn default() -> Self {
+		Self {
+			compact: true,
+			prefer_fast_build: false,
+		}
+	}
+}
+
+/// An instance of a bottom level acceleration structure placed in a top level one
+#[derive(Clone)]
+pub struct GeometryInstance {
+	pub acceleration_structure: Arc<AccelerationStructure>,
+	pub transform: Mat4,
+	/// The value of `gl_InstanceCustomIndexEXT`/`rayQueryGetIntersectionInstanceCustomIndexEXT` for
+	/// hits on this instance. Only the low 24 bits are used.
+	pub custom_index: u32,
+	/// Rays only hit this instance if their cull mask and this mask have a bit in common
+	pub mask: u8,
+}
+
+impl GeometryInstance {
+	pub fn new(acceleration_structure: Arc<AccelerationStructure>, transform: Mat4) -> Self {
+		Self {
+			acceleration_structure,
+			transform,
+			custom_index: 0,
+			mask: 0xFF,
+		}
+	}
+}
+
+impl AccelerationStructure {
+	/// Builds a bottom level acceleration structure from an indexed triangle mesh. The first
+	/// attribute of the vertex type is taken as the vertex position.
+	pub fn build_bottom_level<T: Parameter, X: IndexType>(
+		context: &Context,
+		vertices: &Buffer<VertexBufferUsage, [T]>,
+		indices: &Buffer<IndexBufferUsage, [X]>,
+		options: &BuildOptions,
+	) -> MarsResult<Self> {
+		let attributes = T::attributes();
+		let vertex_format = match attributes[0].format {
+			AttributeFormat::Vec2F => vk::Format::R32G32_SFLOAT,
+			// The w component of a Vec4 position is ignored
+			AttributeFormat::Vec3F | AttributeFormat::Vec4F => vk::Format::R32G32B32_SFLOAT,
+		};
+		let vertex_stride = std::mem::size_of::<T>() as u32;
+
+		// The build inputs need device addresses, which regular mars buffers don't have, so they are
+		// copied into buffers that do for the duration of the build
+		let input_usage = vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+			| vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+		let vertex_input = DeviceBuffer::make(context, input_usage, &*vertices.map()?)?;
+		let index_input = DeviceBuffer::make(context, input_usage, &*indices.map()?)?;
+
+		let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
+			.vertex_format(vertex_format)
+			.vertex_data(vk::DeviceOrHostAddressConstKHR {
+				device_address: vertex_input.device_address(),
+			})
+			.vertex_stride(vertex_stride as u64)
+			.max_vertex(vertices.len as u32 - 1)
+			.index_type(X::as_raw())
+			.index_data(vk::DeviceOrHostAddressConstKHR {
+				device_address: index_input.device_address(),
+			})
+			.build();
+		let geometry = vk::AccelerationStructureGeometryKHR::builder()
+			.geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+			.geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+			.flags(vk::GeometryFlagsKHR::OPAQUE)
+			.build();
+
+		Self::build(
+			context,
+			vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+			geometry,
+			indices.len as u32 / 3,
+			options,
+			Vec::new(),
+		)
+	}
+
+	/// Builds a top level acceleration structure from instances of bottom level ones
+	pub fn build_top_level(
+		context: &Context,
+		instances: &[GeometryInstance],
+		options: &BuildOptions,
+	) -> MarsResult<Self> {
+		let loader = loader(context)?;
+		let raw_instances = instances
+			.iter()
+			.map(|instance| {
+				assert!(
+					!instance.acceleration_structure.is_top_level(),
+					"Top level acceleration structures can only contain bottom level ones"
+				);
+				let mut matrix = [0.0; 12];
+				for row in 0..3 {
+					for column in 0..4 {
+						matrix[row * 4 + column] = instance.transform[(row, column)];
+					}
+				}
+				let address_info = vk::AccelerationStructureDeviceAddressInfoKHR::builder()
+					.acceleration_structure(instance.acceleration_structure.acceleration_structure);
+				let address = unsafe { loader.get_acceleration_structure_device_address(&address_info) };
+				vk::AccelerationStructureInstanceKHR {
+					transform: vk::TransformMatrixKHR { matrix },
+					instance_custom_index_and_mask: vk::Packed24_8::new(instance.custom_index, instance.mask),
+					instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(0, 0),
+					acceleration_structure_reference: vk::AccelerationStructureReferenceKHR { device_handle: address },
+				}
+			})
+			.collect::<Vec<_>>();
+		let instance_input = DeviceBuffer::make(
+			context,
+			vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+				| vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+			&raw_instances,
+		)?;
+
+		let instances_data = vk::AccelerationStructureGeometryInstancesDataKHR::builder()
+			.array_of_pointers(false)
+			.data(vk::DeviceOrHostAddressConstKHR {
+				device_address: instance_input.device_address(),
+			})
+			.build();
+		let geometry = vk::AccelerationStructureGeometryKHR::builder()
+			.geometry_type(vk::GeometryTypeKHR::INSTANCES)
+			.geometry(vk::AccelerationStructureGeometryDataKHR {
+				instances: instances_data,
+			})
+			.build();
+
+		Self::build(
+			context,
+			vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+			geometry,
+			raw_instances.len() as u32,
+			options,
+			instances
+				.iter()
+				.map(|instance| instance.acceleration_structure.clone())
+				.collect(),
+		)
+	}
+
+	pub fn is_top_level(&self) -> bool {
+		self.ty == vk::AccelerationStructureTypeKHR::TOP_LEVEL
+	}
+
+	fn build(
+		context: &Context,
+		ty: vk::AccelerationStructureTypeKHR,
+		geometry: vk::AccelerationStructureGeometryKHR,
+		primitive_count: u32,
+		options: &BuildOptions,
+		children: Vec<Arc<AccelerationStructure>>,
+	) -> MarsResult<Self> {
+		let loader = loader(context)?;
+
+		let mut flags = if options.prefer_fast_build {
+			vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_BUILD
+		} else {
+			vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+		};
+		if options.compact {
+			flags |= vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION;
+		}
+		let geometries = [geometry];
+		let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+			.ty(ty)
+			.flags(flags)
+			.mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+			.geometries(&geometries)
+			.build();
+		let sizes = unsafe {
+			loader.get_acceleration_structure_build_sizes(
+				vk::AccelerationStructureBuildTypeKHR::DEVICE,
+				&build_info,
+				&[primitive_count],
+			)
+		};
+
+		let acceleration_structure = Self::create(context, ty, sizes.acceleration_structure_size, children)?;
+
+		// The scratch buffer is over-allocated so its address can be aligned as the device requires
+		let scratch_alignment = scratch_alignment(context);
+		let scratch = DeviceBuffer::create(
+			context,
+			sizes.build_scratch_size + scratch_alignment,
+			vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+			vk::MemoryPropertyFlags::DEVICE_LOCAL,
+		)?;
+		build_info.dst_acceleration_structure = acceleration_structure.acceleration_structure;
+		build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+			device_address: align_up(scratch.device_address(), scratch_alignment),
+		};
+		let range = vk::AccelerationStructureBuildRangeInfoKHR {
+			primitive_count,
+			primitive_offset: 0,
+			first_vertex: 0,
+			transform_offset: 0,
+		};
+
+		if !options.compact {
+			sync::one_time_submit(context, |command_buffer| unsafe {
+				loader.cmd_build_acceleration_structures(command_buffer, &[build_info], &[&[range]]);
+			})?;
+			return Ok(acceleration_structure);
+		}
+
+		let device = raw_device(&context.device);
+		let query_pool_info = vk::QueryPoolCreateInfo::builder()
+			.query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+			.query_count(1);
+		let query_pool = unsafe { device.create_query_pool(&query_pool_info, None)? };
+		let compacted_size = sync::one_time_submit(context, |command_buffer| unsafe {
+			device.cmd_reset_query_pool(command_buffer, query_pool, 0, 1);
+			loader.cmd_build_acceleration_structures(command_buffer, &[build_info], &[&[range]]);
+			sync::record_memory_barrier(
+				context,
+				command_buffer,
+				vk::PipelineStageFlags2KHR::ACCELERATION_STRUCTURE_BUILD,
+				vk::AccessFlags2KHR::ACCELERATION_STRUCTURE_WRITE,
+				vk::PipelineStageFlags2KHR::ACCELERATION_STRUCTURE_BUILD,
+				vk::AccessFlags2KHR::ACCELERATION_STRUCTURE_READ,
+			);
+			loader.cmd_write_acceleration_structures_properties(
+				command_buffer,
+				&[acceleration_structure.acceleration_structure],
+				vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+				query_pool,
+				0,
+			);
+		})
+		.and_then(|()| unsafe {
+			let mut compacted_size = [0u64];
+			device.get_query_pool_results(
+				query_pool,
+				0,
+				1,
+				&mut compacted_size,
+				vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+			)?;
+			Ok(compacted_size[0])
+		});
+		unsafe { device.destroy_query_pool(query_pool, None) };
+		let compacted_size = compacted_size?;
+
+		let compacted = Self::create(context, ty, compacted_size, acceleration_structure.children.clone())?;
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			let copy_info = vk::CopyAccelerationStructureInfoKHR::builder()
+				.src(acceleration_structure.acceleration_structure)
+				.dst(compacted.acceleration_structure)
+				.mode(vk::CopyAccelerationStructureModeKHR::COMPACT);
+			loader.cmd_copy_acceleration_structure(command_buffer, &copy_info);
+		})?;
+
+		Ok(compacted)
+	}
+
+	fn create(
+		context: &Context,
+		ty: vk::AccelerationStructureTypeKHR,
+		size: u64,
+		children: Vec<Arc<AccelerationStructure>>,
+	) -> MarsResult<Self> {
+		let loader = loader(context)?;
+		let storage = DeviceBuffer::create(
+			context,
+			size,
+			vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+			vk::MemoryPropertyFlags::DEVICE_LOCAL,
+		)?;
+		let create_info = vk::AccelerationStructureCreateInfoKHR::builder()
+			.buffer(storage.buffer)
+			.size(size)
+			.ty(ty);
+		let acceleration_structure = unsafe { loader.create_acceleration_structure(&create_info, None)? };
+		Ok(Self {
+			acceleration_structure,
+			loader: loader.clone(),
+			storage,
+			ty,
+			children,
+		})
+	}
+}
+
+impl Drop for AccelerationStructure {
+	fn drop(&mut self) {
+		unsafe {
+			self.loader
+				.destroy_acceleration_structure(self.acceleration_structure, None);
+		}
+	}
+}
+
+unsafe impl Binding for AccelerationStructure {
+	type Argument = Self;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::AccelerationStructure,
+			count: 1,
+		}
+	}
+}
+
+impl Argument for AccelerationStructure {
+	fn as_write(&self) -> WriteArgument {
+		assert!(
+			self.is_top_level(),
+			"Only top level acceleration structures can be bound"
+		);
+		WriteArgument::AccelerationStructure(WriteAccelerationStructureArgument {
+			acceleration_structure: self.acceleration_structure,
+		})
+	}
+}
+
+fn loader(context: &Context) -> MarsResult<&khr::AccelerationStructure> {
+	context
+		.acceleration_structure
+		.as_ref()
+		.ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+}
+
+fn scratch_alignment(context: &Context) -> u64 {
+	let mut acceleration_structure_properties = vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+	let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut acceleration_structure_properties);
+	unsafe {
+		raw_instance(&context.instance)
+			.get_physical_device_properties2(raw_physical_device(&context.physical_device), &mut properties);
+	}
+	acceleration_structure_properties.min_acceleration_structure_scratch_offset_alignment as u64
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+	(value + alignment - 1) / alignment * alignment
+}
@@ -0,0 +1,392 @@
+//! Texture atlases, which pack many small images into one texture so they can be drawn without
+//! switching textures, like the sprites of a 2D scene or the glyphs of a font.
+//!
+//! `TextureAtlas::build` packs a set of images at once, largest first, and entries can be added
+//! later with `TextureAtlas::insert`, which only uploads the new entry's region. Entries are placed
+//! by a `RectPacker` that fills the atlas in shelves, rows as tall as the tallest entry placed in
+//! them. Entries are separated by `padding` texels, which keeps linear filtering and mipmapping from
+//! bleeding neighbouring entries into each other.
+
+use rk::vk;
+use thiserror::Error;
+
+use crate::{
+	image::{copy_alignment, usage, DynImageUsage, FormatType, Image, ImageView, SampleCount1, Texture},
+	math::Vec2,
+	raw_device,
+	staging::Staged,
+	sync::{self, ImageTransition},
+	Context, MarsResult,
+};
+
+#[derive(Debug, Error)]
+pub enum AtlasError {
+	#[error("There is no room left in the atlas for a {width}x{height} entry")]
+	Full { width: u32, height: u32 },
+	#[error("Vulkan error: {0}")]
+	VulkanError(#[from] vk::Result),
+}
+
+/// A region of an atlas in texels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AtlasRect {
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32,
+}
+
+/// A region of an atlas in texture coordinates, from the top left corner to the bottom right
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UvRect {
+	pub min: Vec2,
+	pub max: Vec2,
+}
+
+/// An entry of a `TextureAtlas`, in the order entries were added
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct AtlasEntry(pub usize);
+
+/// A row of the atlas that entries are placed along from left to right
+#[derive(Debug, Copy, Clone)]
+struct Shelf {
+	y: u32,
+	height: u32,
+	/// Where the next entry on the shelf goes
+	x: u32,
+}
+
+/// Places rectangles in an area without overlapping, in shelves from top to bottom
+#[derive(Debug, Clone)]
+pub struct RectPacker {
+	extent: vk::Extent2D,
+	padding: u32,
+	shelves: Vec<Shelf>,
+}
+
+impl RectPacker {
+	/// Creates a packer for an empty area of `extent`, which keeps rectangles `padding` apart
+	pub fn new(extent: vk::Extent2D, padding: u32) -> Self {
+		Self {
+			extent,
+			padding,
+			shelves: Vec::new(),
+		}
+	}
+
+	/// Finds room for a rectangle of `width` by `height`, or returns `None` if there is none left.
+	/// Rectangles go on the shortest shelf they fit on, or on a new shelf below the others.
+	pub fn pack(&mut self, width: u32, height: u32) -> Option<AtlasRect> {
+		let padded_width = width + self.padding;
+		let padded_height = height + self.padding;
+		let extent = self.extent;
+		let shelf = self
+			.shelves
+			.iter_mut()
+			.filter(|shelf| shelf.height >= padded_height && shelf.x + width <= extent.width)
+			.min_by_key(|shelf| shelf.height);
+		let shelf = match shelf {
+			Some(shelf) => shelf,
+			None => {
+				let y = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+				if y + height > extent.height || width > extent.width {
+					return None;
+				}
+				self.shelves.push(Shelf {
+					y,
+					height: padded_height,
+					x: 0,
+				});
+				self.shelves.last_mut().unwrap()
+			}
+		};
+		let rect = AtlasRect {
+			x: shelf.x,
+			y: shelf.y,
+			width,
+			height,
+		};
+		shelf.x += padded_width;
+		Some(rect)
+	}
+
+	/// Forgets every rectangle placed so far
+	pub fn clear(&mut self) {
+		self.shelves.clear();
+	}
+}
+
+/// Many images packed into one texture. See the module documentation.
+pub struct TextureAtlas<F: FormatType> {
+	texture: Texture<F>,
+	packer: RectPacker,
+	rects: Vec<AtlasRect>,
+}
+
+impl<F: FormatType> TextureAtlas<F> {
+	/// Creates an empty atlas of `extent`, cleared to zero, whose entries are kept `padding` texels
+	/// apart. `F` must be a color format.
+	pub fn create(context: &Context, extent: vk::Extent2D, padding: u32) -> MarsResult<Self> {
+		let mut image = unsafe {
+			Image::<usage::SampledImage, F, SampleCount1>::create_raw(
+				context,
+				DynImageUsage::SAMPLED | DynImageUsage::TRANSFER_DST,
+				F::as_raw(),
+				extent,
+				1,
+				1,
+			)?
+		};
+		let raw = image.image.raw;
+		let range = vk::ImageSubresourceRange {
+			aspect_mask: F::aspect(),
+			base_mip_level: 0,
+			level_count: 1,
+			base_array_layer: 0,
+			layer_count: 1,
+		};
+		let to_transfer = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::CLEAR,
+			src_access_mask: vk::AccessFlags2KHR::NONE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::UNDEFINED,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, raw, &to_transfer);
+			raw_device(&context.device).cmd_clear_color_image(
+				command_buffer,
+				raw,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&vk::ClearColorValue::default(),
+				&[range],
+			);
+			sync::record_image_transition(context, command_buffer, raw, &to_sampled::<F>());
+		})?;
+		image.layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+		let image_view = ImageView::create(&image)?;
+
+		Ok(Self {
+			texture: Texture::new(image, image_view),
+			packer: RectPacker::new(extent, padding),
+			rects: Vec::new(),
+		})
+	}
+
+	/// Creates an atlas of `extent` holding `images`, each given by its extent and tightly packed
+	/// texels in rows from top to bottom. The entries are returned in the same order as `images`.
+	pub fn build(
+		context: &Context,
+		extent: vk::Extent2D,
+		padding: u32,
+		images: &[(vk::Extent2D, &[F::Pixel])],
+	) -> Result<(Self, Vec<AtlasEntry>), AtlasError> {
+		let mut atlas = Self::create(context, extent, padding)?;
+		// Shelves waste the least space when entries are placed from tallest to shortest
+		let mut order = (0..images.len()).collect::<Vec<_>>();
+		order.sort_by_key(|&i| std::cmp::Reverse(images[i].0.height));
+		let mut rects = vec![None; images.len()];
+		for i in order {
+			let (extent, _) = images[i];
+			rects[i] = Some(atlas.place(extent)?);
+		}
+		let rects = rects.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+		let regions = rects
+			.iter()
+			.zip(images)
+			.map(|(&rect, &(_, pixels))| (rect, pixels))
+			.collect::<Vec<_>>();
+		atlas.upload(context, &regions)?;
+		let entries = (0..rects.len()).map(AtlasEntry).collect();
+		atlas.rects = rects;
+		Ok((atlas, entries))
+	}
+
+	/// Adds an image of `extent` to the atlas, given as tightly packed texels in rows from top to
+	/// bottom, and uploads it to its region
+	pub fn insert(
+		&mut self,
+		context: &Context,
+		extent: vk::Extent2D,
+		pixels: &[F::Pixel],
+	) -> Result<AtlasEntry, AtlasError> {
+		let rect = self.place(extent)?;
+		self.upload(context, &[(rect, pixels)])?;
+		self.rects.push(rect);
+		Ok(AtlasEntry(self.rects.len() - 1))
+	}
+
+	/// Rewrites the texels of `entry`, which must be the same size as when it was added
+	pub fn update(&mut self, context: &Context, entry: AtlasEntry, pixels: &[F::Pixel]) -> MarsResult<()> {
+		let rect = self.rect(entry);
+		self.upload(context, &[(rect, pixels)])
+	}
+
+	/// The texture to bind to functions that sample the atlas
+	pub fn texture(&self) -> &Texture<F> {
+		&self.texture
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.texture.image.extent
+	}
+
+	/// The number of entries in the atlas
+	pub fn len(&self) -> usize {
+		self.rects.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.rects.is_empty()
+	}
+
+	/// The region of the atlas `entry` is in, in texels
+	pub fn rect(&self, entry: AtlasEntry) -> AtlasRect {
+		self.rects[entry.0]
+	}
+
+	/// The region of the atlas `entry` is in, in texture coordinates
+	pub fn uv_rect(&self, entry: AtlasEntry) -> UvRect {
+		let rect = self.rect(entry);
+		let extent = self.extent();
+		let (width, height) = (extent.width as f32, extent.height as f32);
+		UvRect {
+			min: Vec2::new(rect.x as f32 / width, rect.y as f32 / height),
+			max: Vec2::new(
+				(rect.x + rect.width) as f32 / width,
+				(rect.y + rect.height) as f32 / height,
+			),
+		}
+	}
+
+	fn place(&mut self, extent: vk::Extent2D) -> Result<AtlasRect, AtlasError> {
+		self.packer.pack(extent.width, extent.height).ok_or(AtlasError::Full {
+			width: extent.width,
+			height: extent.height,
+		})
+	}
+
+	/// Copies the texels of each region from the staging belt in one submission
+	fn upload(&mut self, context: &Context, regions: &[(AtlasRect, &[F::Pixel])]) -> MarsResult<()> {
+		let mut staged = Vec::<Staged>::with_capacity(regions.len());
+		let mut copies = Vec::with_capacity(regions.len());
+		for &(rect, pixels) in regions {
+			assert_eq!(
+				pixels.len(),
+				rect.width as usize * rect.height as usize,
+				"atlas entry data doesn't match the extent of the entry"
+			);
+			if pixels.is_empty() {
+				continue;
+			}
+			// Pixel types are arrays of plain numbers, with no padding
+			let data =
+				unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u8, std::mem::size_of_val(pixels)) };
+			let stage = context.staging.stage(context, data, copy_alignment::<F>())?;
+			copies.push(vk::BufferImageCopy {
+				buffer_offset: stage.offset,
+				buffer_row_length: 0,
+				buffer_image_height: 0,
+				image_subresource: vk::ImageSubresourceLayers {
+					aspect_mask: F::aspect(),
+					mip_level: 0,
+					base_array_layer: 0,
+					layer_count: 1,
+				},
+				image_offset: vk::Offset3D {
+					x: rect.x as i32,
+					y: rect.y as i32,
+					z: 0,
+				},
+				image_extent: vk::Extent3D {
+					width: rect.width,
+					height: rect.height,
+					depth: 1,
+				},
+			});
+			staged.push(stage);
+		}
+		if copies.is_empty() {
+			return Ok(());
+		}
+
+		let raw = self.texture.image.image.raw;
+		let to_transfer = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			src_access_mask: vk::AccessFlags2KHR::NONE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, raw, &to_transfer);
+			// Uploads can land in different staging chunks, so each is copied on its own
+			for (stage, copy) in staged.iter().zip(&copies) {
+				raw_device(&context.device).cmd_copy_buffer_to_image(
+					command_buffer,
+					stage.buffer,
+					raw,
+					vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+					std::slice::from_ref(copy),
+				);
+			}
+			sync::record_image_transition(context, command_buffer, raw, &to_sampled::<F>());
+		})
+	}
+}
+
+fn to_sampled<F: FormatType>() -> ImageTransition {
+	ImageTransition {
+		aspect: F::aspect(),
+		src_stage_mask: vk::PipelineStageFlags2KHR::COPY | vk::PipelineStageFlags2KHR::CLEAR,
+		dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+		src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+		dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+		old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rect(x: u32, y: u32, width: u32, height: u32) -> Option<AtlasRect> {
+		Some(AtlasRect { x, y, width, height })
+	}
+
+	#[test]
+	fn pack_fills_shelves_left_to_right() {
+		let mut packer = RectPacker::new(vk::Extent2D { width: 64, height: 64 }, 2);
+		assert_eq!(packer.pack(20, 10), rect(0, 0, 20, 10));
+		assert_eq!(packer.pack(20, 8), rect(22, 0, 20, 8));
+		assert_eq!(packer.pack(20, 10), rect(44, 0, 20, 10));
+		// The first shelf is full, so the next goes on a new one below it
+		assert_eq!(packer.pack(20, 10), rect(0, 12, 20, 10));
+	}
+
+	#[test]
+	fn pack_prefers_the_shortest_shelf_that_fits() {
+		let mut packer = RectPacker::new(vk::Extent2D { width: 32, height: 64 }, 0);
+		assert_eq!(packer.pack(16, 20), rect(0, 0, 16, 20));
+		assert_eq!(packer.pack(24, 5), rect(0, 20, 24, 5));
+		assert_eq!(packer.pack(8, 4), rect(24, 20, 8, 4));
+		assert_eq!(packer.pack(8, 12), rect(16, 0, 8, 12));
+	}
+
+	#[test]
+	fn pack_fails_when_full() {
+		let mut packer = RectPacker::new(vk::Extent2D { width: 16, height: 16 }, 0);
+		assert_eq!(packer.pack(17, 1), None);
+		assert_eq!(packer.pack(1, 17), None);
+		assert_eq!(packer.pack(16, 10), rect(0, 0, 16, 10));
+		assert_eq!(packer.pack(16, 7), None);
+		assert_eq!(packer.pack(16, 6), rect(0, 10, 16, 6));
+		packer.clear();
+		assert_eq!(packer.pack(16, 16), rect(0, 0, 16, 16));
+	}
+}
@@ -1,41 +1,62 @@
 use std::{
 	marker::PhantomData,
+	mem::ManuallyDrop,
 	ops::{Deref, DerefMut},
 	os::raw::c_void,
 };
 
-use rk::{buffer::Buffer as RkBuffer, vk};
+use rk::vk;
 
-use crate::{Context, MarsResult};
+use crate::{
+	destruction::{DestructionQueue, GpuUse},
+	memory::DeviceBuffer,
+	raw_device,
+	render::RenderEngine,
+	sync, Context, MarsResult,
+};
 
 pub trait BufferUsageType {
 	fn as_raw() -> vk::BufferUsageFlags;
 }
 
 pub struct Buffer<U: BufferUsageType, T: ?Sized> {
-	pub(crate) buffer: RkBuffer,
+	/// Only taken out when the buffer is dropped, to be destroyed once the device is done with it
+	pub(crate) buffer: ManuallyDrop<BufferMemory>,
 	pub(crate) len: usize,
 	pub(crate) size: usize,
+	pub(crate) destruction: DestructionQueue,
+	pub(crate) gpu_use: GpuUse,
 	pub(crate) _phantom: PhantomData<(U, T)>,
 }
 
+impl<U: BufferUsageType, T: ?Sized> Drop for Buffer<U, T> {
+	fn drop(&mut self) {
+		let buffer = unsafe { ManuallyDrop::take(&mut self.buffer) };
+		self.destruction.destroy(buffer);
+	}
+}
+
 impl<U, T> Buffer<U, [T]>
 where
 	U: BufferUsageType,
 	T: Copy,
 {
 	pub fn make_array_buffer(context: &Context, data: &[T]) -> MarsResult<Self> {
+		trace_span!("Buffer::make_array_buffer", len = data.len());
 		assert!(data.len() > 0);
-		let buffer = unsafe { RkBuffer::make(&context.device, U::as_raw(), data)? };
+		let buffer = BufferMemory::make(context, U::as_raw() | vk::BufferUsageFlags::TRANSFER_DST, data)?;
 		Ok(Self {
-			buffer,
+			buffer: ManuallyDrop::new(buffer),
 			len: data.len(),
 			size: data.len() * std::mem::size_of::<T>(),
+			destruction: context.destruction.clone(),
+			gpu_use: GpuUse::new(&context.destruction),
 			_phantom: PhantomData,
 		})
 	}
 
 	pub fn map<'a>(&'a self) -> MarsResult<Map<'a, U, T>> {
+		self.gpu_use.assert_idle("Mapped a buffer");
 		unsafe {
 			let ptr = self.buffer.map()?;
 			Ok(Map { buffer: self, ptr })
@@ -43,6 +64,7 @@ where
 	}
 
 	pub fn map_mut<'a>(&'a mut self) -> MarsResult<MapMut<'a, U, T>> {
+		self.gpu_use.assert_idle("Mapped a buffer");
 		unsafe {
 			let ptr = self.buffer.map()?;
 			Ok(MapMut { buffer: self, ptr })
@@ -58,6 +80,30 @@ where
 		f(&mut *self.map_mut()?);
 		Ok(())
 	}
+
+	/// Writes `data` to the buffer starting at element `offset`, by copying it on the device from
+	/// the context's staging belt. Unlike writing through a mapping, the copy is ordered after the
+	/// submissions already using the buffer.
+	pub fn upload(&self, context: &Context, offset: usize, data: &[T]) -> MarsResult<()> {
+		assert!(offset + data.len() <= self.len, "upload doesn't fit in the buffer");
+		let bytes = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) };
+		self.upload_bytes(context, None, (offset * std::mem::size_of::<T>()) as u64, bytes)
+	}
+
+	/// Writes `data` like `upload`, but if `engine` is recording a frame the copy is recorded into it
+	/// instead of submitted right away, so it happens after the passes recorded before it and before
+	/// the ones recorded after it
+	pub fn record_upload(
+		&self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		offset: usize,
+		data: &[T],
+	) -> MarsResult<()> {
+		assert!(offset + data.len() <= self.len, "upload doesn't fit in the buffer");
+		let bytes = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) };
+		self.upload_bytes(context, Some(engine), (offset * std::mem::size_of::<T>()) as u64, bytes)
+	}
 }
 
 impl<U, T> Buffer<U, T>
@@ -67,17 +113,21 @@ where
 {
 	// Slices don't implement Copy so this ensures that an array buffer can't be created with this constructor
 	pub fn make_item_buffer(context: &Context, data: T) -> MarsResult<Self> {
+		trace_span!("Buffer::make_item_buffer");
 		assert!(std::mem::size_of::<T>() > 0);
-		let buffer = unsafe { RkBuffer::make(&context.device, U::as_raw(), &[data])? };
+		let buffer = BufferMemory::make(context, U::as_raw() | vk::BufferUsageFlags::TRANSFER_DST, &[data])?;
 		Ok(Self {
-			buffer,
+			buffer: ManuallyDrop::new(buffer),
 			len: 1,
 			size: std::mem::size_of::<T>(),
+			destruction: context.destruction.clone(),
+			gpu_use: GpuUse::new(&context.destruction),
 			_phantom: PhantomData,
 		})
 	}
 
 	pub fn map<'a>(&'a self) -> MarsResult<ItemMap<'a, U, T>> {
+		self.gpu_use.assert_idle("Mapped a buffer");
 		unsafe {
 			let ptr = self.buffer.map()?;
 			Ok(ItemMap { buffer: self, ptr })
@@ -85,6 +135,7 @@ where
 	}
 
 	pub fn map_mut<'a>(&'a mut self) -> MarsResult<ItemMapMut<'a, U, T>> {
+		self.gpu_use.assert_idle("Mapped a buffer");
 		unsafe {
 			let ptr = self.buffer.map()?;
 			Ok(ItemMapMut { buffer: self, ptr })
@@ -101,6 +152,18 @@ where
 		Ok(())
 	}
 
+	/// Writes `data` to the buffer like `Buffer::<U, [T]>::upload`
+	pub fn upload(&self, context: &Context, data: T) -> MarsResult<()> {
+		let bytes = unsafe { std::slice::from_raw_parts(&data as *const T as *const u8, std::mem::size_of::<T>()) };
+		self.upload_bytes(context, None, 0, bytes)
+	}
+
+	/// Writes `data` to the buffer like `Buffer::<U, [T]>::record_upload`
+	pub fn record_upload(&self, context: &Context, engine: &mut RenderEngine, data: T) -> MarsResult<()> {
+		let bytes = unsafe { std::slice::from_raw_parts(&data as *const T as *const u8, std::mem::size_of::<T>()) };
+		self.upload_bytes(context, Some(engine), 0, bytes)
+	}
+
 	pub fn as_untyped(&self) -> UntypedBuffer<U> {
 		UntypedBuffer {
 			buffer: self.cast_ref::<()>(),
@@ -129,18 +192,6 @@ where
 	}
 }
 
-impl<'a, U, T> Drop for Map<'a, U, T>
-where
-	U: BufferUsageType,
-	T: Copy,
-{
-	fn drop(&mut self) {
-		unsafe {
-			self.buffer.buffer.unmap();
-		}
-	}
-}
-
 pub struct MapMut<'a, U: BufferUsageType, T: Copy> {
 	buffer: &'a Buffer<U, [T]>,
 	ptr: *mut c_void,
@@ -168,18 +219,6 @@ where
 	}
 }
 
-impl<'a, U, T> Drop for MapMut<'a, U, T>
-where
-	U: BufferUsageType,
-	T: Copy,
-{
-	fn drop(&mut self) {
-		unsafe {
-			self.buffer.buffer.unmap();
-		}
-	}
-}
-
 pub struct ItemMap<'a, U: BufferUsageType, T: Copy> {
 	buffer: &'a Buffer<U, T>,
 	ptr: *const c_void,
@@ -197,18 +236,6 @@ where
 	}
 }
 
-impl<'a, U, T> Drop for ItemMap<'a, U, T>
-where
-	U: BufferUsageType,
-	T: Copy,
-{
-	fn drop(&mut self) {
-		unsafe {
-			self.buffer.buffer.unmap();
-		}
-	}
-}
-
 pub struct ItemMapMut<'a, U: BufferUsageType, T: Copy> {
 	buffer: &'a Buffer<U, T>,
 	ptr: *mut c_void,
@@ -236,14 +263,99 @@ where
 	}
 }
 
-impl<'a, U, T> Drop for ItemMapMut<'a, U, T>
+impl<U, T> Buffer<U, T>
 where
 	U: BufferUsageType,
-	T: Copy,
+	T: ?Sized,
 {
-	fn drop(&mut self) {
-		unsafe {
-			self.buffer.buffer.unmap();
+	pub(crate) fn raw(&self) -> vk::Buffer {
+		self.buffer.raw()
+	}
+
+	/// Copies `bytes` in at `offset` from the staging belt, or writes them directly if the buffer is
+	/// in device local memory the host can write to and nothing could be using it. The copy is
+	/// recorded into the frame `engine` is recording, if it is recording one.
+	fn upload_bytes(
+		&self,
+		context: &Context,
+		engine: Option<&mut RenderEngine>,
+		offset: u64,
+		bytes: &[u8],
+	) -> MarsResult<()> {
+		trace_span!("Buffer::upload", bytes = bytes.len());
+		if bytes.is_empty() {
+			return Ok(());
+		}
+		let engine = engine.filter(|engine| engine.is_recording_frame());
+		if let BufferMemory::DeviceLocal(buffer) = &*self.buffer {
+			// Passes recorded into a frame that hasn't been submitted yet could be using it too
+			if engine.is_none() && context.destruction.is_idle() {
+				return unsafe { buffer.write(offset, bytes) };
+			}
+		}
+		let staged = context.staging.stage(context, bytes, 4)?;
+		let region = vk::BufferCopy {
+			src_offset: staged.offset,
+			dst_offset: offset,
+			size: bytes.len() as u64,
+		};
+		let source = staged.buffer;
+		let copy = |command_buffer: vk::CommandBuffer| unsafe {
+			raw_device(&context.device).cmd_copy_buffer(command_buffer, source, self.raw(), &[region]);
+		};
+		match engine {
+			Some(engine) => engine.record_copy(context, staged, &self.gpu_use, copy),
+			// The copy has completed by the time this returns, so the buffer isn't marked as used by it
+			None => sync::one_time_submit(context, copy),
+		}
+	}
+}
+
+/// The buffer and memory behind a `Buffer`
+pub(crate) enum BufferMemory {
+	/// A buffer in host visible memory
+	HostVisible(DeviceBuffer),
+	/// A buffer in device local memory that's also host visible, on devices with resizable BAR
+	DeviceLocal(DeviceBuffer),
+}
+
+impl BufferMemory {
+	/// Usages read often enough by the device that they're worth placing in device local memory
+	const DEVICE_LOCAL_USAGE: vk::BufferUsageFlags = vk::BufferUsageFlags::from_raw(
+		vk::BufferUsageFlags::VERTEX_BUFFER.as_raw()
+			| vk::BufferUsageFlags::INDEX_BUFFER.as_raw()
+			| vk::BufferUsageFlags::UNIFORM_BUFFER.as_raw(),
+	);
+
+	/// Creates a buffer filled with `data`. Vertex, index and uniform buffers are placed in device
+	/// local memory if the device has resizable BAR, and all other buffers in host visible memory.
+	fn make<T: Copy>(context: &Context, usage: vk::BufferUsageFlags, data: &[T]) -> MarsResult<Self> {
+		if context.resizable_bar && usage.intersects(Self::DEVICE_LOCAL_USAGE) {
+			let properties = vk::MemoryPropertyFlags::DEVICE_LOCAL
+				| vk::MemoryPropertyFlags::HOST_VISIBLE
+				| vk::MemoryPropertyFlags::HOST_COHERENT;
+			match DeviceBuffer::make_with_properties(context, usage, properties, data) {
+				Ok(buffer) => return Ok(BufferMemory::DeviceLocal(buffer)),
+				// The BAR heap can fill up before the rest of memory does
+				Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY) => (),
+				Err(e) => return Err(e),
+			}
+		}
+		let properties = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+		Ok(BufferMemory::HostVisible(DeviceBuffer::make_with_properties(
+			context, usage, properties, data,
+		)?))
+	}
+
+	pub(crate) fn raw(&self) -> vk::Buffer {
+		match self {
+			BufferMemory::HostVisible(buffer) | BufferMemory::DeviceLocal(buffer) => buffer.buffer,
+		}
+	}
+
+	unsafe fn map(&self) -> MarsResult<*mut c_void> {
+		match self {
+			BufferMemory::HostVisible(buffer) | BufferMemory::DeviceLocal(buffer) => buffer.map(),
 		}
 	}
 }
@@ -253,12 +365,13 @@ pub struct UntypedBuffer<'a, U: BufferUsageType> {
 }
 
 macro_rules! buffer_usage {
-	($name:ident, $usage:ident) => {
+	($(#[$attr:meta])* $name:ident, $($usage:ident)|+) => {
+		$(#[$attr])*
 		pub struct $name;
 
 		impl BufferUsageType for $name {
 			fn as_raw() -> vk::BufferUsageFlags {
-				vk::BufferUsageFlags::$usage
+				$(vk::BufferUsageFlags::$usage)|+
 			}
 		}
 	};
@@ -267,4 +380,35 @@ macro_rules! buffer_usage {
 buffer_usage!(VertexBufferUsage, VERTEX_BUFFER);
 buffer_usage!(IndexBufferUsage, INDEX_BUFFER);
 buffer_usage!(UniformBufferUsage, UNIFORM_BUFFER);
+buffer_usage!(StorageBufferUsage, STORAGE_BUFFER);
 buffer_usage!(TransferSrcBufferUsage, TRANSFER_SRC);
+buffer_usage!(
+	/// Buffers holding the parameters of indirect draws, which can also be written as storage buffers
+	/// by compute shaders
+	IndirectBufferUsage,
+	INDIRECT_BUFFER | STORAGE_BUFFER
+);
+buffer_usage!(
+	/// Buffers holding the predicates of conditional draws, which can also be written as storage
+	/// buffers by compute shaders
+	ConditionBufferUsage,
+	CONDITIONAL_RENDERING_EXT | STORAGE_BUFFER
+);
+
+/// The types of the indices in index buffers. Small meshes can use `u16` indices to take half the
+/// memory of `u32` ones.
+pub unsafe trait IndexType: Copy + 'static {
+	fn as_raw() -> vk::IndexType;
+}
+
+unsafe impl IndexType for u16 {
+	fn as_raw() -> vk::IndexType {
+		vk::IndexType::UINT16
+	}
+}
+
+unsafe impl IndexType for u32 {
+	fn as_raw() -> vk::IndexType {
+		vk::IndexType::UINT32
+	}
+}
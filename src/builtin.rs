@@ -0,0 +1,134 @@
+//! Pieces shared by the built-in passes, like tone mapping and SSAO, which draw a triangle covering
+//! their whole target and keep their arguments between frames.
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, IndexBufferUsage, VertexBufferUsage},
+	function::{ArgumentsContainer, FunctionPrototype},
+	image::{FormatType, Image, ImageUsageType, SampleCountType},
+	math::*,
+	render::{DrawArgs, RenderEngine},
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+/// A single triangle covering the whole target, for functions whose vertex input is the position in
+/// normalized device coordinates
+pub(crate) struct FullscreenTriangle {
+	pub(crate) vertices: Buffer<VertexBufferUsage, [Vec2]>,
+	pub(crate) indices: Buffer<IndexBufferUsage, [u32]>,
+}
+
+impl FullscreenTriangle {
+	pub(crate) fn create(context: &Context) -> MarsResult<Self> {
+		let vertices = Buffer::make_array_buffer(
+			context,
+			&[Vec2::new(-1.0, -1.0), Vec2::new(3.0, -1.0), Vec2::new(-1.0, 3.0)],
+		)?;
+		let indices = Buffer::make_array_buffer(context, &[0, 1, 2])?;
+		Ok(Self { vertices, indices })
+	}
+
+	/// The arguments to draw the triangle with `bindings`
+	pub(crate) fn draw_args<'a, F>(&'a self, bindings: &'a ArgumentsContainer<F>) -> DrawArgs<'a, F>
+	where
+		F: FunctionPrototype<VertexInput = Vec2, PushConstants = ()>,
+	{
+		DrawArgs {
+			bindings,
+			vertices: &self.vertices,
+			indices: &self.indices,
+			push_constants: (),
+		}
+	}
+}
+
+/// Arguments kept between frames, which are made again whenever the resources they refer to change.
+/// The resources are told apart by a key, like the handles of the views the arguments read.
+pub(crate) struct CachedArguments<K, A> {
+	cached: Option<(K, A)>,
+}
+
+impl<K: PartialEq, A> CachedArguments<K, A> {
+	pub(crate) fn new() -> Self {
+		Self { cached: None }
+	}
+
+	/// The arguments for `key`, made with `make` unless the cached ones were made for the same key
+	pub(crate) fn make_or_reuse<M>(&mut self, key: K, make: M) -> MarsResult<&A>
+	where
+		M: FnOnce() -> MarsResult<A>,
+	{
+		self.make_or_update(key, make, |_| Ok(()))
+	}
+
+	/// Like `make_or_reuse`, but cached arguments that are reused are passed to `update` first, to
+	/// upload the values that change every frame
+	pub(crate) fn make_or_update<M, U>(&mut self, key: K, make: M, update: U) -> MarsResult<&A>
+	where
+		M: FnOnce() -> MarsResult<A>,
+		U: FnOnce(&A) -> MarsResult<()>,
+	{
+		match &self.cached {
+			Some((cached, arguments)) if *cached == key => update(arguments)?,
+			_ => self.cached = Some((key, make()?)),
+		}
+		Ok(&self.cached.as_ref().unwrap().1)
+	}
+
+	/// The arguments made last, if any
+	pub(crate) fn current(&self) -> Option<&A> {
+		self.cached.as_ref().map(|(_, arguments)| arguments)
+	}
+}
+
+/// The transitions that make a color attachment in `layout` readable by shaders in `stage`, and
+/// renderable again afterwards. Color attachments are kept in the transfer source layout between
+/// passes.
+pub(crate) fn sampling_transitions(
+	layout: vk::ImageLayout,
+	stage: vk::PipelineStageFlags2KHR,
+) -> (ImageTransition, ImageTransition) {
+	let readable = ImageTransition {
+		aspect: vk::ImageAspectFlags::COLOR,
+		src_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+		dst_stage_mask: stage,
+		src_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+		dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+		old_layout: layout,
+		new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+	};
+	let renderable = ImageTransition {
+		aspect: vk::ImageAspectFlags::COLOR,
+		src_stage_mask: stage,
+		dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+		src_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+		dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ | vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+		old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		new_layout: layout,
+	};
+	(readable, renderable)
+}
+
+/// Makes the color attachment `image` readable by shaders in `stage` for the passes and dispatches
+/// recorded by `record`, and renderable again afterwards
+pub(crate) fn sample_color_attachment<U, F, S, T, R>(
+	context: &Context,
+	engine: &mut RenderEngine,
+	image: &mut Image<U, F, S>,
+	stage: vk::PipelineStageFlags2KHR,
+	record: R,
+) -> MarsResult<T>
+where
+	U: ImageUsageType,
+	F: FormatType,
+	S: SampleCountType,
+	R: FnOnce(&mut RenderEngine) -> MarsResult<T>,
+{
+	let (readable, renderable) = sampling_transitions(image.layout, stage);
+	image.record_transition(context, engine, &readable)?;
+	let result = record(engine);
+	image.record_transition(context, engine, &renderable)?;
+	result
+}
@@ -0,0 +1,359 @@
+//! Precompiled shader bundles, so shipping builds don't have to compile GLSL at runtime.
+//!
+//! A bundle holds SPIR-V shaders by name along with the descriptor bindings reflected from them.
+//! Bundles are usually packed by a build script and embedded with `include_bytes!`:
+//!
+//! ```ignore
+//! // build.rs
+//! let mut bundle = ShaderBundle::new();
+//! bundle.insert_glsl("triangle.vert", VERTEX_SOURCE, ShaderStage::Vertex, &CompileOptions::default())?;
+//! bundle.insert_glsl("triangle.frag", FRAGMENT_SOURCE, ShaderStage::Fragment, &CompileOptions::default())?;
+//! bundle.write_to_file(Path::new(&env::var("OUT_DIR")?).join("shaders.bundle"))?;
+//!
+//! // main.rs
+//! let bundle = ShaderBundle::from_bytes(include_bytes!(concat!(env!("OUT_DIR"), "/shaders.bundle")))?;
+//! let function_impl = bundle.function_impl::<TriangleFunction>("triangle.vert", "triangle.frag")?;
+//! ```
+//!
+//! Loading a function from a bundle checks that its shaders are for the right stages and that
+//! they only use bindings matching the function's `Bindings`, which is what makes it safe unlike
+//! `FunctionImpl::from_raw`.
+//!
+//! The format is little endian: the magic bytes `MARSSHDR`, a `u32` version and shader count,
+//! then for every shader its name as a `u32` length and UTF-8 bytes, its stage as a `u8`, its
+//! bindings as a `u32` count of `(u32 binding, u8 type, u32 count)` entries and its code as a
+//! `u32` count of words.
+
+use std::{collections::BTreeMap, marker::PhantomData, path::Path};
+
+use thiserror::Error;
+
+use crate::{
+	compute::{ComputeFunctionImpl, ComputeFunctionPrototype},
+	function::{
+		BindingDesc, BindingType, Bindings, FunctionImpl, FunctionPrototype, MeshFunctionImpl, MeshFunctionPrototype,
+	},
+	shader::{self, CompileOptions, ShaderError, ShaderStage},
+	validation::{has_entry_point, reflect_bindings, ReflectedBinding},
+};
+
+const MAGIC: &[u8; 8] = b"MARSSHDR";
+const VERSION: u32 = 1;
+/// The descriptor count stored for runtime-sized arrays
+const RUNTIME_COUNT: u32 = u32::MAX;
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+	#[error(transparent)]
+	Shader(#[from] ShaderError),
+	#[error("The data isn't a valid shader bundle: {0}")]
+	Malformed(&'static str),
+	#[error("Shader bundle version {0} isn't supported")]
+	UnsupportedVersion(u32),
+	#[error("The bundle has no shader named {0}")]
+	MissingShader(String),
+	#[error("Shader {name} isn't SPIR-V with a main entry point for the {stage:?} stage")]
+	InvalidShader { name: String, stage: ShaderStage },
+	#[error("Shader {name} is a {found:?} shader but a {expected:?} shader is needed")]
+	WrongStage {
+		name: String,
+		expected: ShaderStage,
+		found: ShaderStage,
+	},
+	#[error("Binding {binding} of shader {name} is {found:?} but the function declares {expected:?}")]
+	BindingMismatch {
+		name: String,
+		binding: u32,
+		/// The type and descriptor count of the function's binding, if it has one there
+		expected: Option<(BindingType, u32)>,
+		found: (BindingType, Option<u32>),
+	},
+}
+
+struct BundledShader {
+	stage: ShaderStage,
+	bindings: Vec<ReflectedBinding>,
+	code: Vec<u32>,
+}
+
+/// SPIR-V shaders and their reflected bindings, keyed by name
+#[derive(Default)]
+pub struct ShaderBundle {
+	shaders: BTreeMap<String, BundledShader>,
+}
+
+impl ShaderBundle {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds compiled SPIR-V, replacing any shader with the same name
+	pub fn insert_spirv(
+		&mut self,
+		name: impl Into<String>,
+		stage: ShaderStage,
+		code: Vec<u32>,
+	) -> Result<&mut Self, BundleError> {
+		let name = name.into();
+		if !has_entry_point(&code, stage) {
+			return Err(BundleError::InvalidShader { name, stage });
+		}
+		let bindings = reflect_bindings(&code);
+		self.shaders.insert(name, BundledShader { stage, bindings, code });
+		Ok(self)
+	}
+
+	/// Compiles GLSL and adds it under `name`, which is also used as its filename
+	pub fn insert_glsl(
+		&mut self,
+		name: impl Into<String>,
+		source: &str,
+		stage: ShaderStage,
+		options: &CompileOptions,
+	) -> Result<&mut Self, BundleError> {
+		let name = name.into();
+		let code = shader::compile_glsl_with_options(source, &name, stage, options)?;
+		self.insert_spirv(name, stage, code)
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = MAGIC.to_vec();
+		let put = |bytes: &mut Vec<u8>, value: u32| bytes.extend_from_slice(&value.to_le_bytes());
+		put(&mut bytes, VERSION);
+		put(&mut bytes, self.shaders.len() as u32);
+		for (name, shader) in &self.shaders {
+			put(&mut bytes, name.len() as u32);
+			bytes.extend_from_slice(name.as_bytes());
+			bytes.push(stage_to_raw(shader.stage));
+			put(&mut bytes, shader.bindings.len() as u32);
+			for binding in &shader.bindings {
+				put(&mut bytes, binding.binding);
+				bytes.push(binding_type_to_raw(binding.binding_type));
+				put(&mut bytes, binding.count.unwrap_or(RUNTIME_COUNT));
+			}
+			put(&mut bytes, shader.code.len() as u32);
+			for &word in &shader.code {
+				put(&mut bytes, word);
+			}
+		}
+		bytes
+	}
+
+	pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), BundleError> {
+		std::fs::write(path, self.to_bytes())?;
+		Ok(())
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, BundleError> {
+		let mut reader = Reader { bytes };
+		if reader.take(MAGIC.len())? != MAGIC {
+			return Err(BundleError::Malformed("missing magic bytes"));
+		}
+		let version = reader.u32()?;
+		if version != VERSION {
+			return Err(BundleError::UnsupportedVersion(version));
+		}
+		let mut shaders = BTreeMap::new();
+		for _ in 0..reader.u32()? {
+			let name_len = reader.u32()? as usize;
+			let name = std::str::from_utf8(reader.take(name_len)?)
+				.map_err(|_| BundleError::Malformed("shader name isn't UTF-8"))?
+				.to_owned();
+			let stage = stage_from_raw(reader.u8()?)?;
+			let mut bindings = Vec::new();
+			for _ in 0..reader.u32()? {
+				let binding = reader.u32()?;
+				let binding_type = binding_type_from_raw(reader.u8()?)?;
+				let count = reader.u32()?;
+				bindings.push(ReflectedBinding {
+					binding,
+					binding_type,
+					count: if count == RUNTIME_COUNT { None } else { Some(count) },
+				});
+			}
+			let words = reader.u32()? as usize;
+			let code = (0..words).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+			if !has_entry_point(&code, stage) {
+				return Err(BundleError::InvalidShader { name, stage });
+			}
+			shaders.insert(name, BundledShader { stage, bindings, code });
+		}
+		Ok(Self { shaders })
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, BundleError> {
+		Self::from_bytes(&std::fs::read(path)?)
+	}
+
+	pub fn function_impl<F: FunctionPrototype>(&self, vert: &str, frag: &str) -> Result<FunctionImpl<F>, BundleError> {
+		let bindings = F::Bindings::descriptions();
+		Ok(FunctionImpl {
+			vert: self.shader(vert, ShaderStage::Vertex, &bindings)?,
+			geom: None,
+			frag: Some(self.shader(frag, ShaderStage::Fragment, &bindings)?),
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Like `function_impl`, without a fragment shader, see `FunctionImpl::from_raw_vertex_only`
+	pub fn vertex_only_function_impl<F: FunctionPrototype>(&self, vert: &str) -> Result<FunctionImpl<F>, BundleError> {
+		let bindings = F::Bindings::descriptions();
+		Ok(FunctionImpl {
+			vert: self.shader(vert, ShaderStage::Vertex, &bindings)?,
+			geom: None,
+			frag: None,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Like `function_impl`, with a geometry shader between the vertex and fragment shaders
+	pub fn function_impl_with_geometry<F: FunctionPrototype>(
+		&self,
+		vert: &str,
+		geom: &str,
+		frag: &str,
+	) -> Result<FunctionImpl<F>, BundleError> {
+		let bindings = F::Bindings::descriptions();
+		Ok(FunctionImpl {
+			vert: self.shader(vert, ShaderStage::Vertex, &bindings)?,
+			geom: Some(self.shader(geom, ShaderStage::Geometry, &bindings)?),
+			frag: Some(self.shader(frag, ShaderStage::Fragment, &bindings)?),
+			_phantom: PhantomData,
+		})
+	}
+
+	pub fn mesh_function_impl<F: MeshFunctionPrototype>(
+		&self,
+		task: Option<&str>,
+		mesh: &str,
+		frag: &str,
+	) -> Result<MeshFunctionImpl<F>, BundleError> {
+		let bindings = F::Bindings::descriptions();
+		Ok(MeshFunctionImpl {
+			task: task
+				.map(|task| self.shader(task, ShaderStage::Task, &bindings))
+				.transpose()?,
+			mesh: self.shader(mesh, ShaderStage::Mesh, &bindings)?,
+			frag: self.shader(frag, ShaderStage::Fragment, &bindings)?,
+			_phantom: PhantomData,
+		})
+	}
+
+	pub fn compute_function_impl<F: ComputeFunctionPrototype>(
+		&self,
+		comp: &str,
+	) -> Result<ComputeFunctionImpl<F>, BundleError> {
+		let bindings = F::Bindings::descriptions();
+		Ok(ComputeFunctionImpl {
+			comp: self.shader(comp, ShaderStage::Compute, &bindings)?,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// The code of a shader, checked against the stage and bindings of the function it's for
+	fn shader(&self, name: &str, stage: ShaderStage, bindings: &[BindingDesc]) -> Result<Vec<u32>, BundleError> {
+		let shader = self
+			.shaders
+			.get(name)
+			.ok_or_else(|| BundleError::MissingShader(name.to_owned()))?;
+		if shader.stage != stage {
+			return Err(BundleError::WrongStage {
+				name: name.to_owned(),
+				expected: stage,
+				found: shader.stage,
+			});
+		}
+		for reflected in &shader.bindings {
+			let expected = bindings.get(reflected.binding as usize);
+			let matches = expected.map_or(false, |desc| {
+				desc.binding_type == reflected.binding_type && reflected.count.map_or(true, |count| count == desc.count)
+			});
+			if !matches {
+				return Err(BundleError::BindingMismatch {
+					name: name.to_owned(),
+					binding: reflected.binding,
+					expected: expected.map(|desc| (desc.binding_type, desc.count)),
+					found: (reflected.binding_type, reflected.count),
+				});
+			}
+		}
+		Ok(shader.code.clone())
+	}
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> Result<&'a [u8], BundleError> {
+		if self.bytes.len() < len {
+			return Err(BundleError::Malformed("unexpected end of data"));
+		}
+		let (taken, rest) = self.bytes.split_at(len);
+		self.bytes = rest;
+		Ok(taken)
+	}
+
+	fn u8(&mut self) -> Result<u8, BundleError> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u32(&mut self) -> Result<u32, BundleError> {
+		let mut word = [0; 4];
+		word.copy_from_slice(self.take(4)?);
+		Ok(u32::from_le_bytes(word))
+	}
+}
+
+fn stage_to_raw(stage: ShaderStage) -> u8 {
+	match stage {
+		ShaderStage::Vertex => 0,
+		ShaderStage::Fragment => 1,
+		ShaderStage::Compute => 2,
+		ShaderStage::Task => 3,
+		ShaderStage::Mesh => 4,
+		ShaderStage::Geometry => 5,
+	}
+}
+
+fn stage_from_raw(raw: u8) -> Result<ShaderStage, BundleError> {
+	Ok(match raw {
+		0 => ShaderStage::Vertex,
+		1 => ShaderStage::Fragment,
+		2 => ShaderStage::Compute,
+		3 => ShaderStage::Task,
+		4 => ShaderStage::Mesh,
+		5 => ShaderStage::Geometry,
+		_ => return Err(BundleError::Malformed("unknown shader stage")),
+	})
+}
+
+fn binding_type_to_raw(binding_type: BindingType) -> u8 {
+	match binding_type {
+		BindingType::Uniform => 0,
+		BindingType::SampledImage => 1,
+		BindingType::AccelerationStructure => 2,
+		BindingType::InputAttachment => 3,
+		BindingType::StorageBuffer => 4,
+		BindingType::StorageImage => 5,
+		BindingType::Sampler => 6,
+		BindingType::SampledImageOnly => 7,
+	}
+}
+
+fn binding_type_from_raw(raw: u8) -> Result<BindingType, BundleError> {
+	Ok(match raw {
+		0 => BindingType::Uniform,
+		1 => BindingType::SampledImage,
+		2 => BindingType::AccelerationStructure,
+		3 => BindingType::InputAttachment,
+		4 => BindingType::StorageBuffer,
+		5 => BindingType::StorageImage,
+		6 => BindingType::Sampler,
+		7 => BindingType::SampledImageOnly,
+		_ => return Err(BundleError::Malformed("unknown binding type")),
+	})
+}
@@ -0,0 +1,160 @@
+//! Cameras and the view and projection matrices they produce.
+//!
+//! A camera looks down its local -Z axis with +Y up, like `Mat4::look_at_rh`. Its projections follow
+//! Vulkan's conventions, with depth going from 0 at the near plane to 1 at the far plane and +Y
+//! pointing down in clip space, so scenes don't need a flipped up vector to render right side up.
+
+use rk::vk;
+
+use crate::math::*;
+
+/// How a camera maps view space to clip space
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+	Perspective {
+		/// The vertical field of view in radians
+		fov_y: f32,
+		near: f32,
+		far: f32,
+	},
+	/// A perspective projection with reverse-Z and no far plane, see
+	/// `math::infinite_reverse_perspective`
+	InfiniteReversePerspective {
+		/// The vertical field of view in radians
+		fov_y: f32,
+		near: f32,
+	},
+	Orthographic {
+		/// The height of the view volume in world units
+		height: f32,
+		near: f32,
+		far: f32,
+	},
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Camera {
+	pub position: Point3,
+	pub orientation: Quat,
+	pub projection: Projection,
+	/// The width of the viewport divided by its height
+	pub aspect: f32,
+}
+
+impl Camera {
+	pub fn new(position: Point3, orientation: Quat, projection: Projection, aspect: f32) -> Self {
+		Self {
+			position,
+			orientation,
+			projection,
+			aspect,
+		}
+	}
+
+	/// A perspective camera at the origin looking down -Z
+	pub fn perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> Self {
+		Self::new(
+			Point3::origin(),
+			Quat::identity(),
+			Projection::Perspective { fov_y, near, far },
+			aspect,
+		)
+	}
+
+	/// An orthographic camera at the origin looking down -Z
+	pub fn orthographic(height: f32, aspect: f32, near: f32, far: f32) -> Self {
+		Self::new(
+			Point3::origin(),
+			Quat::identity(),
+			Projection::Orthographic { height, near, far },
+			aspect,
+		)
+	}
+
+	/// A perspective camera at the origin looking down -Z, using reverse-Z with no far plane
+	pub fn infinite_reverse_perspective(fov_y: f32, aspect: f32, near: f32) -> Self {
+		Self::new(
+			Point3::origin(),
+			Quat::identity(),
+			Projection::InfiniteReversePerspective { fov_y, near },
+			aspect,
+		)
+	}
+
+	/// Moves the camera to `position`, keeping its orientation
+	pub fn at(self, position: Point3) -> Self {
+		Self { position, ..self }
+	}
+
+	/// Turns the camera to face `target`, with its up vector as close to `up` as possible
+	pub fn look_at(&mut self, target: &Point3, up: &Vec3) {
+		self.orientation = Quat::face_towards(&(self.position - target), up);
+	}
+
+	/// Updates the aspect ratio to match a viewport of size `extent`, like the one returned by
+	/// `WindowEngine::current_extent` after the window is resized
+	pub fn set_extent(&mut self, extent: vk::Extent2D) {
+		self.aspect = extent.width as f32 / extent.height.max(1) as f32;
+	}
+
+	/// The direction the camera is looking in
+	pub fn forward(&self) -> Vec3 {
+		self.orientation * -Vec3::z()
+	}
+
+	pub fn right(&self) -> Vec3 {
+		self.orientation * Vec3::x()
+	}
+
+	pub fn up(&self) -> Vec3 {
+		self.orientation * Vec3::y()
+	}
+
+	/// The matrix transforming world space to the camera's view space
+	pub fn view(&self) -> Mat4 {
+		view_matrix(&self.position, &self.orientation)
+	}
+
+	/// The matrix transforming view space to clip space
+	pub fn proj(&self) -> Mat4 {
+		match self.projection {
+			Projection::Perspective { fov_y, near, far } => {
+				let f = 1.0 / (fov_y / 2.0).tan();
+				let mut proj = Mat4::zeros();
+				proj[(0, 0)] = f / self.aspect;
+				proj[(1, 1)] = -f;
+				proj[(2, 2)] = far / (near - far);
+				proj[(2, 3)] = near * far / (near - far);
+				proj[(3, 2)] = -1.0;
+				proj
+			}
+			Projection::InfiniteReversePerspective { fov_y, near } => {
+				infinite_reverse_perspective(self.aspect, fov_y, near)
+			}
+			Projection::Orthographic { height, near, far } => {
+				let half_height = height / 2.0;
+				let half_width = half_height * self.aspect;
+				let mut proj = Mat4::identity();
+				proj[(0, 0)] = 1.0 / half_width;
+				proj[(1, 1)] = -1.0 / half_height;
+				proj[(2, 2)] = 1.0 / (near - far);
+				proj[(2, 3)] = near / (near - far);
+				proj
+			}
+		}
+	}
+
+	pub fn view_proj(&self) -> Mat4 {
+		self.proj() * self.view()
+	}
+
+	/// The matrices for drawing a model with this camera
+	pub fn mvp(&self, model: Mat4) -> Mvp {
+		Mvp::new(model, self.view(), self.proj())
+	}
+}
+
+/// The view matrix of a camera at `position` with the given orientation
+pub fn view_matrix(position: &Point3, orientation: &Quat) -> Mat4 {
+	orientation.inverse().to_homogeneous() * Mat4::new_translation(&-position.coords)
+}
@@ -0,0 +1,112 @@
+//! Triggering RenderDoc captures from the application, through RenderDoc's in-application API.
+//!
+//! RenderDoc is loaded when a context is created, before the instance, so it can hook Vulkan even
+//! if the application wasn't launched from it. `Context::capture_next_frame` then captures
+//! everything submitted between the next two presents of a `WindowEngine`, or frames ended in XR,
+//! however many submissions the frame is made of. Applications without a window, or that want to
+//! capture something other than a frame, can bracket the work themselves with
+//! `Context::start_capture` and `Context::end_capture`.
+
+use std::{os::raw::c_void, ptr, sync::Mutex};
+
+use renderdoc::{RenderDoc, V141};
+
+use crate::Context;
+
+/// RenderDoc, if it could be loaded, and what is being captured
+pub(crate) struct FrameCapture {
+	state: Mutex<CaptureState>,
+}
+
+struct CaptureState {
+	renderdoc: RenderDoc<V141>,
+	/// A capture should start at the next present
+	requested: bool,
+	/// A capture is in progress
+	capturing: bool,
+}
+
+impl FrameCapture {
+	/// Loads RenderDoc, or returns `None` if it isn't installed
+	pub(crate) fn load() -> Option<Self> {
+		let renderdoc = RenderDoc::<V141>::new()
+			.map_err(|e| log::info!("RenderDoc isn't available, captures are disabled: {}", e))
+			.ok()?;
+		Some(Self {
+			state: Mutex::new(CaptureState {
+				renderdoc,
+				requested: false,
+				capturing: false,
+			}),
+		})
+	}
+
+	/// Ends the capture of the frame just presented and starts the one requested for the next
+	pub(crate) fn frame_presented(&self) {
+		let mut state = self.state.lock().unwrap();
+		if state.capturing {
+			state.end();
+		}
+		if state.requested {
+			state.requested = false;
+			state.start();
+		}
+	}
+}
+
+impl CaptureState {
+	fn start(&mut self) {
+		// Null device and window pointers capture whatever the application renders
+		self.renderdoc.start_frame_capture(ptr::null::<c_void>(), ptr::null());
+		self.capturing = true;
+	}
+
+	fn end(&mut self) {
+		self.renderdoc.end_frame_capture(ptr::null::<c_void>(), ptr::null());
+		self.capturing = false;
+	}
+}
+
+impl Context {
+	/// Returns whether RenderDoc was loaded, and captures can be taken
+	pub fn renderdoc_available(&self) -> bool {
+		self.frame_capture.is_some()
+	}
+
+	/// Captures everything submitted between the next two presents of a `WindowEngine`, or frames
+	/// ended in XR. Does nothing if RenderDoc isn't available.
+	pub fn capture_next_frame(&self) {
+		if let Some(capture) = &self.frame_capture {
+			capture.state.lock().unwrap().requested = true;
+		}
+	}
+
+	/// Starts capturing everything submitted until `end_capture`. Does nothing if RenderDoc isn't
+	/// available or a capture is already in progress.
+	pub fn start_capture(&self) {
+		if let Some(capture) = &self.frame_capture {
+			let mut state = capture.state.lock().unwrap();
+			if !state.capturing {
+				state.start();
+			}
+		}
+	}
+
+	/// Ends the capture in progress, whether it was started by `start_capture` or
+	/// `capture_next_frame`
+	pub fn end_capture(&self) {
+		if let Some(capture) = &self.frame_capture {
+			let mut state = capture.state.lock().unwrap();
+			if state.capturing {
+				state.end();
+			}
+		}
+	}
+
+	/// Returns whether a capture is in progress
+	pub fn is_capturing(&self) -> bool {
+		self.frame_capture
+			.as_ref()
+			.map_or(false, |capture| capture.state.lock().unwrap().capturing)
+	}
+}
@@ -0,0 +1,182 @@
+//! Colors with an explicit encoding.
+//!
+//! Shaders, blending and clear values all work with linear colors, while colors picked in an image
+//! editor or written as hex codes are almost always sRGB encoded. Keeping the two in different types
+//! means the conversion can't be forgotten.
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	function::{AttributeDesc, AttributeFormat, Binding, BindingDesc, BindingType, Parameter},
+	math::*,
+	pass::ColorClearValue,
+};
+
+/// A color with linear components, laid out like a `vec4`
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[repr(C)]
+pub struct Rgba {
+	pub r: f32,
+	pub g: f32,
+	pub b: f32,
+	pub a: f32,
+}
+
+impl Rgba {
+	pub const BLACK: Rgba = Rgba::new(0.0, 0.0, 0.0, 1.0);
+	pub const WHITE: Rgba = Rgba::new(1.0, 1.0, 1.0, 1.0);
+	pub const TRANSPARENT: Rgba = Rgba::new(0.0, 0.0, 0.0, 0.0);
+
+	pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
+		Self { r, g, b, a }
+	}
+
+	/// An opaque color
+	pub const fn rgb(r: f32, g: f32, b: f32) -> Self {
+		Self::new(r, g, b, 1.0)
+	}
+
+	/// This color with its alpha replaced
+	pub fn with_alpha(self, a: f32) -> Self {
+		Self { a, ..self }
+	}
+
+	/// Encodes the color as sRGB
+	pub fn to_srgb(self) -> Srgb {
+		Srgb::new(
+			linear_to_srgb(self.r),
+			linear_to_srgb(self.g),
+			linear_to_srgb(self.b),
+			self.a,
+		)
+	}
+
+	pub fn to_array(self) -> [f32; 4] {
+		[self.r, self.g, self.b, self.a]
+	}
+}
+
+/// A color with sRGB encoded components and linear alpha, the way colors are usually written down
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[repr(C)]
+pub struct Srgb {
+	pub r: f32,
+	pub g: f32,
+	pub b: f32,
+	pub a: f32,
+}
+
+impl Srgb {
+	pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
+		Self { r, g, b, a }
+	}
+
+	/// A color from 8 bit components, as in `Srgb::from_u8(255, 128, 0, 255)`
+	pub fn from_u8(r: u8, g: u8, b: u8, a: u8) -> Self {
+		let unorm = |c: u8| c as f32 / 255.0;
+		Self::new(unorm(r), unorm(g), unorm(b), unorm(a))
+	}
+
+	/// An opaque color from a hex code like `0xff8000`
+	pub fn hex(rgb: u32) -> Self {
+		Self::from_u8((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255)
+	}
+
+	/// Decodes the color into linear components
+	pub fn to_linear(self) -> Rgba {
+		Rgba::new(
+			srgb_to_linear(self.r),
+			srgb_to_linear(self.g),
+			srgb_to_linear(self.b),
+			self.a,
+		)
+	}
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+	if c <= 0.04045 {
+		c / 12.92
+	} else {
+		((c + 0.055) / 1.055).powf(2.4)
+	}
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+	if c <= 0.0031308 {
+		c * 12.92
+	} else {
+		1.055 * c.powf(1.0 / 2.4) - 0.055
+	}
+}
+
+impl From<Srgb> for Rgba {
+	fn from(color: Srgb) -> Self {
+		color.to_linear()
+	}
+}
+
+impl From<Rgba> for Srgb {
+	fn from(color: Rgba) -> Self {
+		color.to_srgb()
+	}
+}
+
+impl From<Vec4> for Rgba {
+	fn from(v: Vec4) -> Self {
+		Rgba::new(v.x, v.y, v.z, v.w)
+	}
+}
+
+impl From<Rgba> for Vec4 {
+	fn from(color: Rgba) -> Self {
+		Vec4::new(color.r, color.g, color.b, color.a)
+	}
+}
+
+// Clear values are linear, and are encoded by the device when clearing sRGB attachments
+impl ColorClearValue for Rgba {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			float32: self.to_array(),
+		}
+	}
+}
+
+impl ColorClearValue for Srgb {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		self.to_linear().as_raw()
+	}
+}
+
+unsafe impl Parameter for Rgba {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![AttributeDesc {
+			format: AttributeFormat::Vec4F,
+			offset: None,
+		}]
+	}
+}
+
+unsafe impl Binding for Rgba {
+	type Argument = Buffer<UniformBufferUsage, Rgba>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Rgba {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Rgba {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Srgb {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Srgb {}
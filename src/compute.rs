@@ -0,0 +1,135 @@
+use std::{marker::PhantomData, mem::ManuallyDrop};
+
+use rk::{
+	descriptor::{DescriptorPool, DescriptorSet},
+	pipe::{DescriptorSetLayout, PipelineLayout},
+	vk,
+};
+
+use crate::{
+	destruction::{DestructionQueue, GpuUse, RecordedUses},
+	function::{bindings_descs_to_raw, create_descriptor_pool, writes_to_raw, Arguments, Bindings, WriteArgument},
+	pipeline::ComputePipeline,
+	raw_pipeline_layout,
+	validation::{validate_arguments, UniformBlockSizes},
+	Context, MarsResult,
+};
+
+/// A function run by dispatching workgroups of a compute shader, outside of any render pass
+pub trait ComputeFunctionPrototype {
+	type Bindings: Bindings;
+}
+
+pub struct ComputeFunctionImpl<F: ComputeFunctionPrototype> {
+	pub(crate) comp: Vec<u32>,
+	pub(crate) _phantom: PhantomData<F>,
+}
+
+impl<F> ComputeFunctionImpl<F>
+where
+	F: ComputeFunctionPrototype,
+{
+	pub unsafe fn from_raw(comp: Vec<u32>) -> Self {
+		Self {
+			comp,
+			_phantom: PhantomData,
+		}
+	}
+}
+
+pub struct ComputeFunctionDef<F: ComputeFunctionPrototype> {
+	pub(crate) descriptor_pool: ManuallyDrop<DescriptorPool>,
+	pub(crate) descriptor_set_layout: ManuallyDrop<DescriptorSetLayout>,
+	pub(crate) pipeline: ManuallyDrop<ComputePipeline>,
+	pub(crate) pipeline_layout: ManuallyDrop<PipelineLayout>,
+	destruction: DestructionQueue,
+	uniform_sizes: UniformBlockSizes,
+	_phantom: PhantomData<F>,
+}
+
+impl<F: ComputeFunctionPrototype> Drop for ComputeFunctionDef<F> {
+	fn drop(&mut self) {
+		let resources = unsafe {
+			(
+				ManuallyDrop::take(&mut self.descriptor_pool),
+				ManuallyDrop::take(&mut self.descriptor_set_layout),
+				ManuallyDrop::take(&mut self.pipeline),
+				ManuallyDrop::take(&mut self.pipeline_layout),
+			)
+		};
+		self.destruction.destroy(resources);
+	}
+}
+
+impl<F> ComputeFunctionDef<F>
+where
+	F: ComputeFunctionPrototype,
+{
+	pub fn create(context: &Context, function_impl: ComputeFunctionImpl<F>) -> MarsResult<Self> {
+		Self::create_specialized(context, function_impl, &[])
+	}
+
+	/// Creates the function with the given values of the compute shader's specialization constants,
+	/// as in `FunctionOptions::specialization_constants`
+	pub fn create_specialized(
+		context: &Context,
+		function_impl: ComputeFunctionImpl<F>,
+		specialization_constants: &[u32],
+	) -> MarsResult<Self> {
+		let bindings = F::Bindings::descriptions();
+		let descriptor_pool = create_descriptor_pool(context, &bindings)?;
+		let descriptor_bindings = bindings_descs_to_raw(&bindings, vk::ShaderStageFlags::COMPUTE);
+		let descriptor_set_layout = context.device.create_descriptor_set_layout(&descriptor_bindings)?;
+		let pipeline_layout = context.device.create_pipeline_layout(&descriptor_set_layout)?;
+		let pipeline = ComputePipeline::create(
+			&context.device,
+			&function_impl.comp,
+			specialization_constants,
+			raw_pipeline_layout(&pipeline_layout),
+		)?;
+		Ok(Self {
+			descriptor_pool: ManuallyDrop::new(descriptor_pool),
+			descriptor_set_layout: ManuallyDrop::new(descriptor_set_layout),
+			pipeline: ManuallyDrop::new(pipeline),
+			pipeline_layout: ManuallyDrop::new(pipeline_layout),
+			destruction: context.destruction.clone(),
+			uniform_sizes: UniformBlockSizes::reflect(&[function_impl.comp.as_slice()]),
+			_phantom: PhantomData,
+		})
+	}
+
+	pub fn make_arguments(
+		&mut self,
+		context: &Context,
+		arguments: <F::Bindings as Bindings>::Arguments,
+	) -> MarsResult<ComputeArgumentsContainer<F>> {
+		let descriptor_set = context
+			.device
+			.allocate_descriptor_set(&self.descriptor_pool, &self.descriptor_set_layout)?;
+		let writes = arguments.as_writes();
+		validate_arguments(&self.uniform_sizes, &writes);
+		let (raw_writes, _backing) = writes_to_raw(***descriptor_set, &writes);
+		unsafe { context.device.write_descriptor_set(&raw_writes)? };
+		let gpu_uses = writes.iter().filter_map(WriteArgument::gpu_use).collect();
+		Ok(ComputeArgumentsContainer {
+			arguments,
+			descriptor_set,
+			gpu_uses,
+		})
+	}
+}
+
+pub struct ComputeArgumentsContainer<F: ComputeFunctionPrototype> {
+	pub arguments: <F::Bindings as Bindings>::Arguments,
+	pub(crate) descriptor_set: DescriptorSet,
+	/// The buffers the arguments bind, marked as used whenever the arguments are
+	pub(crate) gpu_uses: Vec<GpuUse>,
+}
+
+impl<F: ComputeFunctionPrototype> ComputeArgumentsContainer<F> {
+	pub(crate) fn mark_used(&self, recorded: &RecordedUses) {
+		for gpu_use in &self.gpu_uses {
+			gpu_use.mark(recorded);
+		}
+	}
+}
@@ -0,0 +1,223 @@
+//! Camera controllers driven by winit events, for navigating a scene without writing input handling.
+//!
+//! Feed every event from the event loop to `handle_event`, and once per frame call `apply` to write
+//! the controller's position and orientation into a `Camera` (after `FpsController::update`, which
+//! moves according to the keys held). Mouse look only happens while a mouse button is held, so the
+//! cursor never needs to be grabbed.
+
+use std::{collections::HashSet, f32::consts::FRAC_PI_2};
+
+use winit::event::{
+	DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+use crate::{
+	camera::{view_matrix, Camera},
+	math::*,
+};
+
+// Keeps the camera from flipping over when looking straight up or down
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+fn orientation(yaw: f32, pitch: f32) -> Quat {
+	Quat::from_axis_angle(&Vec3::y_axis(), yaw) * Quat::from_axis_angle(&Vec3::x_axis(), pitch)
+}
+
+/// A flying first person controller. WASD moves, space and left shift move up and down, and
+/// dragging with the right mouse button looks around.
+#[derive(Debug, Clone)]
+pub struct FpsController {
+	pub position: Point3,
+	/// The rotation about the world Y axis in radians
+	pub yaw: f32,
+	/// The rotation above (positive) or below the horizon in radians
+	pub pitch: f32,
+	/// The movement speed in units per second
+	pub speed: f32,
+	/// The rotation per pixel of mouse movement in radians
+	pub sensitivity: f32,
+	pressed: HashSet<VirtualKeyCode>,
+	looking: bool,
+}
+
+impl FpsController {
+	pub fn new(position: Point3, yaw: f32, pitch: f32) -> Self {
+		Self {
+			position,
+			yaw,
+			pitch,
+			speed: 5.0,
+			sensitivity: 0.003,
+			pressed: HashSet::new(),
+			looking: false,
+		}
+	}
+
+	pub fn handle_event<T>(&mut self, event: &Event<T>) {
+		match event {
+			Event::WindowEvent { event, .. } => match event {
+				WindowEvent::KeyboardInput {
+					input: KeyboardInput {
+						virtual_keycode: Some(key),
+						state,
+						..
+					},
+					..
+				} => match state {
+					ElementState::Pressed => {
+						self.pressed.insert(*key);
+					}
+					ElementState::Released => {
+						self.pressed.remove(key);
+					}
+				},
+				WindowEvent::MouseInput {
+					state,
+					button: MouseButton::Right,
+					..
+				} => self.looking = *state == ElementState::Pressed,
+				WindowEvent::Focused(false) => {
+					self.pressed.clear();
+					self.looking = false;
+				}
+				_ => {}
+			},
+			Event::DeviceEvent {
+				event: DeviceEvent::MouseMotion { delta: (x, y) },
+				..
+			} if self.looking => {
+				self.yaw -= *x as f32 * self.sensitivity;
+				self.pitch = (self.pitch - *y as f32 * self.sensitivity)
+					.max(-MAX_PITCH)
+					.min(MAX_PITCH);
+			}
+			_ => {}
+		}
+	}
+
+	/// Moves the controller according to the keys held, over `dt` seconds
+	pub fn update(&mut self, dt: f32) {
+		let orientation = self.orientation();
+		let axis = |positive, negative| {
+			let held = |key| self.pressed.contains(&key) as i32 as f32;
+			held(positive) - held(negative)
+		};
+		let movement = orientation * -Vec3::z() * axis(VirtualKeyCode::W, VirtualKeyCode::S)
+			+ orientation * Vec3::x() * axis(VirtualKeyCode::D, VirtualKeyCode::A)
+			+ Vec3::y() * axis(VirtualKeyCode::Space, VirtualKeyCode::LShift);
+		if movement.norm_squared() > 0.0 {
+			self.position += movement.normalize() * self.speed * dt;
+		}
+	}
+
+	pub fn orientation(&self) -> Quat {
+		orientation(self.yaw, self.pitch)
+	}
+
+	/// Writes the controller's position and orientation into `camera`
+	pub fn apply(&self, camera: &mut Camera) {
+		camera.position = self.position;
+		camera.orientation = self.orientation();
+	}
+
+	/// The view matrix of a camera controlled by this controller
+	pub fn view(&self) -> Mat4 {
+		view_matrix(&self.position, &self.orientation())
+	}
+}
+
+/// A controller orbiting around a target point. Dragging with the left mouse button orbits, dragging
+/// with the middle mouse button pans the target, and scrolling zooms in and out.
+#[derive(Debug, Clone)]
+pub struct OrbitController {
+	pub target: Point3,
+	pub distance: f32,
+	/// The rotation about the world Y axis in radians
+	pub yaw: f32,
+	/// The angle above (positive) or below the target in radians
+	pub pitch: f32,
+	/// The rotation per pixel of mouse movement in radians
+	pub sensitivity: f32,
+	/// The fraction of the distance zoomed per line scrolled
+	pub zoom_speed: f32,
+	orbiting: bool,
+	panning: bool,
+}
+
+impl OrbitController {
+	pub fn new(target: Point3, distance: f32) -> Self {
+		Self {
+			target,
+			distance,
+			yaw: 0.0,
+			pitch: 0.0,
+			sensitivity: 0.005,
+			zoom_speed: 0.1,
+			orbiting: false,
+			panning: false,
+		}
+	}
+
+	pub fn handle_event<T>(&mut self, event: &Event<T>) {
+		match event {
+			Event::WindowEvent { event, .. } => match event {
+				WindowEvent::MouseInput { state, button, .. } => {
+					let pressed = *state == ElementState::Pressed;
+					match button {
+						MouseButton::Left => self.orbiting = pressed,
+						MouseButton::Middle => self.panning = pressed,
+						_ => {}
+					}
+				}
+				WindowEvent::MouseWheel { delta, .. } => {
+					let lines = match delta {
+						MouseScrollDelta::LineDelta(_, y) => *y,
+						MouseScrollDelta::PixelDelta(position) => position.y as f32 / 50.0,
+					};
+					self.distance = (self.distance * (1.0 - lines * self.zoom_speed)).max(0.01);
+				}
+				WindowEvent::Focused(false) => {
+					self.orbiting = false;
+					self.panning = false;
+				}
+				_ => {}
+			},
+			Event::DeviceEvent {
+				event: DeviceEvent::MouseMotion { delta: (x, y) },
+				..
+			} => {
+				let (x, y) = (*x as f32, *y as f32);
+				if self.orbiting {
+					self.yaw -= x * self.sensitivity;
+					self.pitch = (self.pitch + y * self.sensitivity).max(-MAX_PITCH).min(MAX_PITCH);
+				} else if self.panning {
+					// Pan faster the farther away the target is, so it tracks the cursor
+					let orientation = self.orientation();
+					let scale = self.distance * self.sensitivity * 0.5;
+					self.target += (orientation * Vec3::y() * y - orientation * Vec3::x() * x) * scale;
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// The orientation of the camera looking at the target
+	pub fn orientation(&self) -> Quat {
+		orientation(self.yaw, -self.pitch)
+	}
+
+	pub fn position(&self) -> Point3 {
+		self.target + self.orientation() * Vec3::z() * self.distance
+	}
+
+	/// Writes the controller's position and orientation into `camera`
+	pub fn apply(&self, camera: &mut Camera) {
+		camera.position = self.position();
+		camera.orientation = self.orientation();
+	}
+
+	/// The view matrix of a camera controlled by this controller
+	pub fn view(&self) -> Mat4 {
+		view_matrix(&self.position(), &self.orientation())
+	}
+}
@@ -0,0 +1,151 @@
+//! Cubemaps, images of six square faces sampled by direction, for environment maps and the maps
+//! baked from them for image based lighting.
+//!
+//! Cubemaps are written by compute shaders as well as sampled, so unlike other images they're kept
+//! in the `GENERAL` layout for their whole life.
+
+use std::sync::Arc;
+
+use rk::vk;
+
+use crate::{
+	function::{Argument, Binding, BindingDesc, BindingType, WriteArgument, WriteSampledImageArgument},
+	image::{usage, DynImageUsage, FormatType, Image, ImageViewHandle, SampleCount1, Sampler, SamplerHandle},
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+/// GLSL that maps a texel of a face of a cubemap to the direction it's sampled from, shared by the
+/// shaders that write cubemaps. `uv` is the position of the texel's center on the face from 0 to 1.
+pub(crate) const CUBE_DIRECTION_GLSL: &str = r#"
+vec3 cube_direction(uint face, vec2 uv) {
+	vec2 p = uv * 2.0 - 1.0;
+	switch (face) {
+	case 0: return normalize(vec3(1.0, -p.y, -p.x));
+	case 1: return normalize(vec3(-1.0, -p.y, p.x));
+	case 2: return normalize(vec3(p.x, 1.0, p.y));
+	case 3: return normalize(vec3(p.x, -1.0, -p.y));
+	case 4: return normalize(vec3(p.x, -p.y, 1.0));
+	default: return normalize(vec3(-p.x, -p.y, -1.0));
+	}
+}
+"#;
+
+/// An image with six square faces, in the order +X, -X, +Y, -Y, +Z, -Z, and a chain of mip levels
+pub struct Cubemap<F: FormatType> {
+	pub(crate) image: Image<usage::SampledImage, F, SampleCount1>,
+	/// A cube view of every mip level
+	pub(crate) view: ImageViewHandle,
+	sampler: Sampler,
+}
+
+impl<F: FormatType> Cubemap<F> {
+	/// Creates a cubemap with faces of `size` by `size` texels and `mip_levels` mip levels, which are
+	/// left uninitialized. It can be sampled, written as a storage image and copied into.
+	pub fn create(context: &Context, size: u32, mip_levels: u32) -> MarsResult<Self> {
+		let extent = vk::Extent2D {
+			width: size,
+			height: size,
+		};
+		assert!(mip_levels > 0 && mip_levels <= crate::image::max_mip_levels(extent));
+		let mut image = unsafe {
+			Image::create_raw_with_flags(
+				context,
+				DynImageUsage::SAMPLED | DynImageUsage::STORAGE | DynImageUsage::TRANSFER_DST,
+				F::as_raw(),
+				extent,
+				6,
+				mip_levels,
+				vk::ImageCreateFlags::CUBE_COMPATIBLE,
+			)?
+		};
+		image.transition(
+			context,
+			&ImageTransition {
+				aspect: F::aspect(),
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::MEMORY_READ | vk::AccessFlags2KHR::MEMORY_WRITE,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::GENERAL,
+			},
+		)?;
+		let view = ImageViewHandle::create(
+			&image.image,
+			vk::ImageViewType::CUBE,
+			F::as_raw(),
+			subresource_range::<F>(0, mip_levels),
+		)?;
+		let sampler = Sampler::create_trilinear(context)?;
+		Ok(Self { image, view, sampler })
+	}
+
+	/// The size of the faces of the base mip level
+	pub fn size(&self) -> u32 {
+		self.image.extent.width
+	}
+
+	pub fn mip_levels(&self) -> u32 {
+		self.image.mip_levels
+	}
+
+	/// The argument to bind the cubemap to a `SampledCubemap` binding with
+	pub fn argument(&self) -> CubemapArgument {
+		CubemapArgument {
+			sampler: self.sampler.sampler.clone(),
+			image_view: self.view.raw,
+		}
+	}
+
+	/// Creates a view of the six faces of `mip_level` as a 2D array, for compute shaders to write
+	/// them as an `image2DArray`
+	pub(crate) fn face_view(&self, mip_level: u32) -> MarsResult<ImageViewHandle> {
+		ImageViewHandle::create(
+			&self.image.image,
+			vk::ImageViewType::TYPE_2D_ARRAY,
+			F::as_raw(),
+			subresource_range::<F>(mip_level, 1),
+		)
+	}
+}
+
+fn subresource_range<F: FormatType>(base_mip_level: u32, level_count: u32) -> vk::ImageSubresourceRange {
+	vk::ImageSubresourceRange {
+		aspect_mask: F::aspect(),
+		base_mip_level,
+		level_count,
+		base_array_layer: 0,
+		layer_count: 6,
+	}
+}
+
+/// A cubemap sampled by direction, for GLSL `samplerCube`s
+pub struct SampledCubemap;
+
+unsafe impl Binding for SampledCubemap {
+	type Argument = CubemapArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+/// A cubemap and its sampler. The cubemap must outlive the arguments this is written to.
+pub struct CubemapArgument {
+	sampler: Arc<SamplerHandle>,
+	image_view: vk::ImageView,
+}
+
+impl Argument for CubemapArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: vk::ImageLayout::GENERAL,
+		})
+	}
+}
@@ -0,0 +1,579 @@
+//! Occlusion culling on the device against a hierarchical depth buffer.
+//!
+//! An `OcclusionCuller` keeps a depth pyramid, where every texel of a mip level holds the farthest
+//! depth of the texels it covers in the level above (the greatest depth, or the smallest with
+//! reverse-Z). After the pyramid is built from the depth attachment of a frame (usually the
+//! previous frame, or a depth pre-pass of the current one), a compute shader tests the bounding
+//! sphere of every object against the view frustum and against the pyramid level where the object
+//! covers about a texel. The draws of the objects that pass are compacted into an indirect command buffer along with their count, ready for
+//! `RenderEngine::pass_indirect_count`.
+
+use std::sync::Arc;
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, IndirectBufferUsage, StorageBufferUsage, UniformBufferUsage},
+	builtin::CachedArguments,
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	function::{
+		compile_shader, Argument, Binding, BindingDesc, BindingType, DepthConvention, RawStorage, RawStorageArgument,
+		StorageImageViews, WriteArgument, WriteSampledImageArgument,
+	},
+	image::{
+		format::R32Sfloat, max_mip_levels, DynImageUsage, FormatType, Image, ImageView, ImageViewHandle, SampleCount1,
+		Sampler, SamplerHandle,
+	},
+	math::*,
+	pass::DepthAttachment,
+	render::{GeneratedDraws, RenderEngine},
+	shader::ShaderStage,
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+const PYRAMID_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+// Whether depth uses the reverse-Z convention, where the farthest depth is the smallest
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1, r32f) uniform writeonly image2D destination;
+
+void main() {
+	ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+	ivec2 destination_size = imageSize(destination);
+	if (any(greaterThanEqual(p, destination_size))) {
+		return;
+	}
+
+	// Every source texel the destination texel overlaps, so odd sizes stay conservative
+	ivec2 source_size = textureSize(source, 0);
+	ivec2 start = p * source_size / destination_size;
+	ivec2 end = max(((p + 1) * source_size + destination_size - 1) / destination_size, start + 1);
+	float depth = REVERSE_Z ? 1.0 : 0.0;
+	for (int y = start.y; y < end.y; y++) {
+		for (int x = start.x; x < end.x; x++) {
+			float texel = texelFetch(source, ivec2(x, y), 0).r;
+			depth = REVERSE_Z ? min(depth, texel) : max(depth, texel);
+		}
+	}
+	imageStore(destination, p, vec4(depth));
+}
+"#;
+
+const CULL_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 64) in;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+struct DrawObject {
+	vec3 center;
+	float radius;
+	uint index_count;
+	uint first_index;
+	int vertex_offset;
+	uint first_instance;
+};
+
+struct DrawCommand {
+	uint index_count;
+	uint instance_count;
+	uint first_index;
+	int vertex_offset;
+	uint first_instance;
+};
+
+layout(set = 0, binding = 0) readonly buffer Objects {
+	DrawObject objects[];
+};
+layout(set = 0, binding = 1) writeonly buffer Commands {
+	DrawCommand commands[];
+};
+layout(set = 0, binding = 2) buffer Count {
+	uint count;
+};
+layout(set = 0, binding = 3) uniform sampler2D pyramid;
+layout(set = 0, binding = 4) uniform Cull {
+	mat4 view_proj;
+	vec4 planes[6];
+	uint object_count;
+	uint occlusion;
+};
+
+bool in_frustum(vec3 center, float radius) {
+	for (int i = 0; i < 6; i++) {
+		if (dot(planes[i].xyz, center) + planes[i].w < -radius) {
+			return false;
+		}
+	}
+	return true;
+}
+
+bool occluded(vec3 center, float radius) {
+	vec3 ndc_min = vec3(1.0);
+	vec3 ndc_max = vec3(-1.0);
+	for (int i = 0; i < 8; i++) {
+		vec3 corner = center + radius * vec3(
+			(i & 1) != 0 ? 1.0 : -1.0,
+			(i & 2) != 0 ? 1.0 : -1.0,
+			(i & 4) != 0 ? 1.0 : -1.0);
+		vec4 clip = view_proj * vec4(corner, 1.0);
+		if (clip.w <= 0.0) {
+			// The bounds cross the plane of the camera, so they can't be behind anything
+			return false;
+		}
+		vec3 ndc = clip.xyz / clip.w;
+		ndc_min = min(ndc_min, ndc);
+		ndc_max = max(ndc_max, ndc);
+	}
+
+	vec2 uv_min = clamp(ndc_min.xy * 0.5 + 0.5, 0.0, 1.0);
+	vec2 uv_max = clamp(ndc_max.xy * 0.5 + 0.5, 0.0, 1.0);
+	// The level where the bounds are at most a texel across, so four texels cover them
+	vec2 extent = (uv_max - uv_min) * vec2(textureSize(pyramid, 0));
+	int level = clamp(int(ceil(log2(max(max(extent.x, extent.y), 1.0)))), 0, textureQueryLevels(pyramid) - 1);
+	ivec2 size = textureSize(pyramid, level);
+	ivec2 lo = clamp(ivec2(uv_min * vec2(size)), ivec2(0), size - 1);
+	ivec2 hi = clamp(ivec2(uv_max * vec2(size)), ivec2(0), size - 1);
+	vec4 depths = vec4(
+		texelFetch(pyramid, lo, level).r,
+		texelFetch(pyramid, ivec2(hi.x, lo.y), level).r,
+		texelFetch(pyramid, ivec2(lo.x, hi.y), level).r,
+		texelFetch(pyramid, hi, level).r);
+	if (REVERSE_Z) {
+		return ndc_max.z < min(min(depths.x, depths.y), min(depths.z, depths.w));
+	} else {
+		return ndc_min.z > max(max(depths.x, depths.y), max(depths.z, depths.w));
+	}
+}
+
+void main() {
+	uint i = gl_GlobalInvocationID.x;
+	if (i >= object_count) {
+		return;
+	}
+	DrawObject object = objects[i];
+	if (!in_frustum(object.center, object.radius)) {
+		return;
+	}
+	if (occlusion != 0 && occluded(object.center, object.radius)) {
+		return;
+	}
+
+	uint slot = atomicAdd(count, 1);
+	commands[slot] = DrawCommand(object.index_count, 1, object.first_index, object.vertex_offset, object.first_instance);
+}
+"#;
+
+/// An object to cull, along with the indexed draw that renders it. Laid out as the `DrawObject`
+/// struct of the culling shader.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct DrawObject {
+	/// The center of the object's bounding sphere in world space
+	pub center: Vec3,
+	pub radius: f32,
+	pub index_count: u32,
+	pub first_index: u32,
+	pub vertex_offset: i32,
+	pub first_instance: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct CullParams {
+	view_proj: Mat4,
+	planes: [Vec4; 6],
+	object_count: u32,
+	// Whether the depth pyramid has been built yet
+	occlusion: u32,
+}
+
+unsafe impl Binding for CullParams {
+	type Argument = Buffer<UniformBufferUsage, CullParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+/// An image sampled by the culling shaders, bound by its view
+struct Sampled;
+
+unsafe impl Binding for Sampled {
+	type Argument = SampledView;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+struct SampledView {
+	sampler: Arc<SamplerHandle>,
+	image_view: vk::ImageView,
+	image_layout: vk::ImageLayout,
+}
+
+impl Argument for SampledView {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: self.image_layout,
+		})
+	}
+}
+
+/// A single mip level of the depth pyramid, written as a storage image
+struct PyramidLevel;
+
+unsafe impl Binding for PyramidLevel {
+	type Argument = StorageImageViews;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageImage,
+			count: 1,
+		}
+	}
+}
+
+struct PyramidFunction;
+
+impl ComputeFunctionPrototype for PyramidFunction {
+	// The level above (or the depth attachment) and the level to write
+	type Bindings = (Sampled, PyramidLevel);
+}
+
+struct CullFunction;
+
+impl ComputeFunctionPrototype for CullFunction {
+	// The objects, the compacted commands, the command count, and the depth pyramid
+	type Bindings = (RawStorage, RawStorage, RawStorage, Sampled, CullParams);
+}
+
+/// Culls a fixed list of objects against the view frustum and the depth of a previous frame,
+/// producing the indirect draws of the objects that may be visible
+pub struct OcclusionCuller {
+	pyramid_function: ComputeFunctionDef<PyramidFunction>,
+	cull_function: ComputeFunctionDef<CullFunction>,
+	// The arguments building every level of the pyramid from the one above it. Those for the first
+	// level are keyed by the view of the depth attachment they read.
+	source_arguments: CachedArguments<vk::ImageView, ComputeArgumentsContainer<PyramidFunction>>,
+	level_arguments: Vec<ComputeArgumentsContainer<PyramidFunction>>,
+	cull_arguments: ComputeArgumentsContainer<CullFunction>,
+	// Everything the arguments above refer to by handle, declared after them to be dropped last
+	level_views: Vec<ImageViewHandle>,
+	_pyramid_view: ImageView<DynImageUsage, R32Sfloat, SampleCount1>,
+	_pyramid: Image<DynImageUsage, R32Sfloat, SampleCount1>,
+	sampler: Sampler,
+	objects: Buffer<StorageBufferUsage, [DrawObject]>,
+	commands: Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]>,
+	count: Buffer<IndirectBufferUsage, u32>,
+	extent: vk::Extent2D,
+	built: bool,
+}
+
+impl OcclusionCuller {
+	/// Creates a culler for `objects`, with a depth pyramid for depth attachments of size `extent`
+	pub fn create(context: &Context, extent: vk::Extent2D, objects: &[DrawObject]) -> MarsResult<Self> {
+		Self::create_with_convention(context, extent, objects, DepthConvention::Standard)
+	}
+
+	/// Creates a culler for scenes rendered with the given depth convention
+	pub fn create_with_convention(
+		context: &Context,
+		extent: vk::Extent2D,
+		objects: &[DrawObject],
+		convention: DepthConvention,
+	) -> MarsResult<Self> {
+		let reverse_z = [(convention == DepthConvention::Reverse) as u32];
+		let comp = compile_shader(PYRAMID_SHADER, "pyramid.comp", ShaderStage::Compute)?;
+		let mut pyramid_function = ComputeFunctionDef::create_specialized(
+			context,
+			unsafe { ComputeFunctionImpl::from_raw(comp) },
+			&reverse_z,
+		)?;
+		let comp = compile_shader(CULL_SHADER, "cull.comp", ShaderStage::Compute)?;
+		let mut cull_function = ComputeFunctionDef::create_specialized(
+			context,
+			unsafe { ComputeFunctionImpl::from_raw(comp) },
+			&reverse_z,
+		)?;
+		let sampler = Sampler::create_trilinear(context)?;
+
+		// The pyramid stays in the general layout, since it's both sampled and written
+		let mut pyramid = Image::create_with_mips(
+			context,
+			DynImageUsage::SAMPLED | DynImageUsage::STORAGE,
+			extent,
+			max_mip_levels(extent),
+		)?;
+		pyramid.transition(
+			context,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ | vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::GENERAL,
+			},
+		)?;
+		let pyramid_view = ImageView::create(&pyramid)?;
+		let level_views = (0..pyramid.mip_levels())
+			.map(|level| {
+				ImageViewHandle::create(
+					&pyramid.image,
+					vk::ImageViewType::TYPE_2D,
+					R32Sfloat::as_raw(),
+					vk::ImageSubresourceRange {
+						aspect_mask: vk::ImageAspectFlags::COLOR,
+						base_mip_level: level,
+						level_count: 1,
+						base_array_layer: 0,
+						layer_count: 1,
+					},
+				)
+			})
+			.collect::<MarsResult<Vec<_>>>()?;
+		let level_arguments = level_views
+			.windows(2)
+			.map(|views| {
+				pyramid_function.make_arguments(
+					context,
+					(
+						SampledView {
+							sampler: sampler.sampler.clone(),
+							image_view: views[0].raw,
+							image_layout: vk::ImageLayout::GENERAL,
+						},
+						StorageImageViews(vec![views[1].raw]),
+					),
+				)
+			})
+			.collect::<MarsResult<Vec<_>>>()?;
+
+		let objects = Buffer::make_array_buffer(context, objects)?;
+		let commands = Buffer::make_array_buffer(
+			context,
+			&vec![
+				vk::DrawIndexedIndirectCommand {
+					index_count: 0,
+					instance_count: 0,
+					first_index: 0,
+					vertex_offset: 0,
+					first_instance: 0,
+				};
+				objects.len
+			],
+		)?;
+		let count = Buffer::make_item_buffer(context, 0u32)?;
+		let cull_arguments = cull_function.make_arguments(
+			context,
+			(
+				RawStorageArgument::new(&objects),
+				RawStorageArgument::new(&commands),
+				RawStorageArgument::new(&count),
+				SampledView {
+					sampler: sampler.sampler.clone(),
+					image_view: pyramid_view.image_view.raw,
+					image_layout: vk::ImageLayout::GENERAL,
+				},
+				Buffer::make_item_buffer(
+					context,
+					CullParams {
+						view_proj: Mat4::identity(),
+						planes: [Vec4::zeros(); 6],
+						object_count: objects.len as u32,
+						occlusion: 0,
+					},
+				)?,
+			),
+		)?;
+
+		Ok(Self {
+			pyramid_function,
+			cull_function,
+			source_arguments: CachedArguments::new(),
+			level_arguments,
+			cull_arguments,
+			level_views,
+			_pyramid_view: pyramid_view,
+			_pyramid: pyramid,
+			sampler,
+			objects,
+			commands,
+			count,
+			extent,
+			built: false,
+		})
+	}
+
+	/// Replaces the objects to cull. There must be as many objects as the culler was created with.
+	pub fn set_objects(&mut self, objects: &[DrawObject]) -> MarsResult<()> {
+		assert_eq!(
+			objects.len(),
+			self.objects.len,
+			"the number of culled objects can't change"
+		);
+		self.objects.with_map_mut(|slots| slots.copy_from_slice(objects))
+	}
+
+	/// Builds the depth pyramid from `depth`, which must have the extent the culler was created with
+	/// and have been created with the `SAMPLED` usage (see `Attachments::create_with_usages`)
+	pub fn build_pyramid<D>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		depth: &mut DepthAttachment<D, SampleCount1>,
+	) -> MarsResult<()>
+	where
+		D: FormatType,
+	{
+		assert!(
+			depth.image.usage().contains(DynImageUsage::SAMPLED),
+			"depth attachments must be sampleable to build a depth pyramid"
+		);
+		assert_eq!(
+			(depth.image.extent().width, depth.image.extent().height),
+			(self.extent.width, self.extent.height),
+			"the depth attachment must match the size of the pyramid"
+		);
+
+		let image_view = depth.view.image_view.raw;
+		let (pyramid_function, sampler, level_views) = (&mut self.pyramid_function, &self.sampler, &self.level_views);
+		self.source_arguments.make_or_reuse(image_view, || {
+			let source = SampledView {
+				sampler: sampler.sampler.clone(),
+				image_view,
+				image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			};
+			pyramid_function.make_arguments(context, (source, StorageImageViews(vec![level_views[0].raw])))
+		})?;
+
+		// Depth attachments are kept in the depth attachment layout between passes
+		let layout = depth.image.layout;
+		depth.image.record_transition(
+			context,
+			engine,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::DEPTH,
+				src_stage_mask: vk::PipelineStageFlags2KHR::LATE_FRAGMENT_TESTS,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+				old_layout: layout,
+				new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			},
+		)?;
+		let result = self.dispatch_levels(context, engine);
+		depth.image.record_transition(
+			context,
+			engine,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::DEPTH,
+				src_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::EARLY_FRAGMENT_TESTS,
+				src_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+				dst_access_mask: vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+				new_layout: layout,
+			},
+		)?;
+		result?;
+		self.built = true;
+		Ok(())
+	}
+
+	fn dispatch_levels(&self, context: &Context, engine: &mut RenderEngine) -> MarsResult<()> {
+		let source_arguments = self.source_arguments.current().unwrap();
+		for (level, arguments) in std::iter::once(source_arguments)
+			.chain(self.level_arguments.iter())
+			.enumerate()
+		{
+			let width = (self.extent.width >> level).max(1);
+			let height = (self.extent.height >> level).max(1);
+			engine.dispatch(
+				context,
+				&self.pyramid_function,
+				arguments,
+				[(width + 7) / 8, (height + 7) / 8, 1],
+			)?;
+		}
+		Ok(())
+	}
+
+	/// Culls the objects as seen through `view_proj`, writing the draws of the ones that may be
+	/// visible to `commands` and their number to `count`. Until a pyramid has been built, objects
+	/// are only culled against the frustum.
+	pub fn cull(&mut self, context: &Context, engine: &mut RenderEngine, view_proj: Mat4) -> MarsResult<()> {
+		let occlusion = self.built as u32;
+		self.cull_arguments.arguments.4.with_map_mut(|params| {
+			params.view_proj = view_proj;
+			params.planes = frustum_planes(&view_proj);
+			params.occlusion = occlusion;
+		})?;
+
+		let group_count = (self.objects.len as u32 + 63) / 64;
+		engine.dispatch_draws(
+			context,
+			&self.cull_function,
+			&self.cull_arguments,
+			[group_count, 1, 1],
+			GeneratedDraws {
+				commands: &self.commands,
+				count: Some(&self.count),
+			},
+		)
+	}
+
+	/// The draws of the objects that passed the last cull, compacted at the start of the buffer
+	pub fn commands(&self) -> &Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]> {
+		&self.commands
+	}
+
+	/// The number of objects that passed the last cull
+	pub fn count(&self) -> &Buffer<IndirectBufferUsage, u32> {
+		&self.count
+	}
+}
+
+/// The planes of the frustum of a Vulkan projection (with depth from 0 to 1, or 1 to 0), normalized
+/// and pointing inwards
+fn frustum_planes(view_proj: &Mat4) -> [Vec4; 6] {
+	let row = |i: usize| view_proj.row(i).transpose();
+	let planes = [
+		row(3) + row(0),
+		row(3) - row(0),
+		row(3) + row(1),
+		row(3) - row(1),
+		row(2),
+		row(3) - row(2),
+	];
+	let mut normalized = [Vec4::zeros(); 6];
+	for (plane, normalized) in planes.iter().zip(normalized.iter_mut()) {
+		let norm = plane.xyz().norm();
+		// Projections without a far plane have a degenerate one, which nothing is outside of
+		*normalized = if norm > 1e-6 {
+			plane / norm
+		} else {
+			Vec4::new(0.0, 0.0, 0.0, 1.0)
+		};
+	}
+	normalized
+}
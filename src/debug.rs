@@ -0,0 +1,145 @@
+//! Capturing the messages of the validation layers, so tests can fail on misuse of the API instead
+//! of only logging it.
+//!
+//! `Context::capture_validation_messages` installs a sink that keeps every message of the given
+//! severities reported from then on, next to the messenger that logs them. Tests can then inspect
+//! them with `Context::validation_messages`, or fail outright with
+//! `Context::assert_no_validation_errors`.
+
+use std::{
+	ffi::CStr,
+	os::raw::{c_char, c_void},
+	sync::{Arc, Mutex},
+};
+
+use rk::{ash::extensions::ext, instance::Instance, vk};
+
+use crate::{raw_instance, Context, MarsResult};
+
+/// A message reported by the validation layers
+#[derive(Debug, Clone)]
+pub struct ValidationMessage {
+	pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+	pub message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+	/// The name of the check that failed, like `VUID-vkCmdDraw-None-02699`
+	pub id_name: String,
+	pub text: String,
+}
+
+/// A messenger keeping the messages it receives
+pub(crate) struct ValidationCapture {
+	// Kept so the instance outlives the messenger
+	#[allow(unused)]
+	instance: Instance,
+	loader: ext::DebugUtils,
+	messenger: vk::DebugUtilsMessengerEXT,
+	messages: Arc<Mutex<Vec<ValidationMessage>>>,
+}
+
+impl Drop for ValidationCapture {
+	fn drop(&mut self) {
+		unsafe {
+			self.loader.destroy_debug_utils_messenger(self.messenger, None);
+		}
+	}
+}
+
+impl Context {
+	/// Starts keeping the validation messages of `severities`, replacing the messages kept so far
+	pub fn capture_validation_messages(&self, severities: vk::DebugUtilsMessageSeverityFlagsEXT) -> MarsResult<()> {
+		let loader = ext::DebugUtils::new(&self.entry, raw_instance(&self.instance));
+		let messages = Arc::new(Mutex::new(Vec::new()));
+		let create_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+			.message_severity(severities)
+			.message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
+			.pfn_user_callback(Some(capture_message))
+			.user_data(Arc::as_ptr(&messages) as *mut c_void);
+		let messenger = unsafe { loader.create_debug_utils_messenger(&create_info, None)? };
+		*self.validation_capture.lock().unwrap() = Some(ValidationCapture {
+			instance: self.instance.clone(),
+			loader,
+			messenger,
+			messages,
+		});
+		Ok(())
+	}
+
+	/// Stops keeping validation messages and drops the ones kept so far
+	pub fn stop_capturing_validation_messages(&self) {
+		*self.validation_capture.lock().unwrap() = None;
+	}
+
+	/// The validation messages kept since capturing started
+	pub fn validation_messages(&self) -> Vec<ValidationMessage> {
+		self.with_messages(|messages| messages.clone())
+	}
+
+	/// Returns the validation messages kept since capturing started or the last call, and forgets them
+	pub fn take_validation_messages(&self) -> Vec<ValidationMessage> {
+		self.with_messages(std::mem::take)
+	}
+
+	/// The number of validation errors kept since capturing started
+	pub fn validation_error_count(&self) -> usize {
+		self.with_messages(|messages| messages.iter().filter(|message| is_error(message)).count())
+	}
+
+	/// Panics, listing them, if any validation errors were kept since capturing started. Capturing
+	/// must have been started with the `ERROR` severity.
+	pub fn assert_no_validation_errors(&self) {
+		let errors = self.with_messages(|messages| {
+			messages
+				.iter()
+				.filter(|message| is_error(message))
+				.map(|message| format!("\t{}: {}", message.id_name, message.text))
+				.collect::<Vec<_>>()
+		});
+		if !errors.is_empty() {
+			panic!(
+				"{} validation errors were reported:\n{}",
+				errors.len(),
+				errors.join("\n")
+			);
+		}
+	}
+
+	fn with_messages<T, F: FnOnce(&mut Vec<ValidationMessage>) -> T>(&self, f: F) -> T {
+		let capture = self.validation_capture.lock().unwrap();
+		let capture = capture
+			.as_ref()
+			.expect("Validation messages aren't being captured, see Context::capture_validation_messages");
+		let mut messages = capture.messages.lock().unwrap();
+		f(&mut messages)
+	}
+}
+
+fn is_error(message: &ValidationMessage) -> bool {
+	message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR)
+}
+
+unsafe extern "system" fn capture_message(
+	severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+	message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+	data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+	user_data: *mut c_void,
+) -> vk::Bool32 {
+	let messages = &*(user_data as *const Mutex<Vec<ValidationMessage>>);
+	let data = &*data;
+	let string = |chars: *const c_char| {
+		if chars.is_null() {
+			String::new()
+		} else {
+			CStr::from_ptr(chars).to_string_lossy().into_owned()
+		}
+	};
+	let message = ValidationMessage {
+		severity,
+		message_type,
+		id_name: string(data.p_message_id_name),
+		text: string(data.p_message),
+	};
+	// A poisoned lock only means a test panicked while holding it, and the messages are still fine
+	let mut messages = messages.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	messages.push(message);
+	vk::FALSE
+}
@@ -0,0 +1,309 @@
+//! Immediate mode drawing of lines and wireframe shapes, for visualizing things like bounding
+//! volumes, physics shapes and frusta while debugging.
+//!
+//! Shapes are added to a `DebugDraw` anywhere during a frame, and drawn all at once by
+//! `DebugDraw::draw`, usually at the end of the frame after the scene. Drawing clears the shapes, so
+//! anything that should stay visible has to be added again every frame.
+
+use std::{f32::consts::PI, marker::PhantomData};
+
+use crate::{
+	buffer::{Buffer, IndexBufferUsage, VertexBufferUsage},
+	color::Rgba,
+	function::{
+		compile_shader, ArgumentsContainer, AttributeDesc, AttributeFormat, DepthConvention, FunctionDef, FunctionImpl,
+		FunctionOptions, FunctionPrototype, Parameter, Topology,
+	},
+	math::*,
+	pass::{RenderPass, RenderPassPrototype},
+	render::{DrawArgs, RenderEngine},
+	shader::ShaderStage,
+	target::Target,
+	Context, MarsResult,
+};
+
+const DEBUG_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform ViewProj {
+	mat4 view_proj;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+	f_color = color;
+	gl_Position = view_proj * vec4(position, 1.0);
+}
+"#;
+
+const DEBUG_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec4 f_color;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	color = f_color;
+}
+"#;
+
+/// The number of segments circles are drawn with
+const CIRCLE_SEGMENTS: usize = 32;
+
+/// An end of a debug line, in world space
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct DebugVertex {
+	pub position: Vec3,
+	pub color: Rgba,
+}
+
+unsafe impl Parameter for DebugVertex {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![
+			AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec4F,
+				offset: None,
+			},
+		]
+	}
+}
+
+pub struct DebugDrawFunction<P>(PhantomData<P>);
+
+impl<P> FunctionPrototype for DebugDrawFunction<P>
+where
+	P: RenderPassPrototype,
+{
+	type RenderPass = P;
+	type VertexInput = DebugVertex;
+	type Bindings = (Mat4,);
+	type PushConstants = ();
+}
+
+/// Accumulates lines during a frame and draws them into targets of render passes of prototype `P`.
+/// See the module documentation.
+pub struct DebugDraw<P: RenderPassPrototype> {
+	function: FunctionDef<DebugDrawFunction<P>>,
+	arguments: ArgumentsContainer<DebugDrawFunction<P>>,
+	/// Pairs of vertices, one pair per line
+	vertices: Vec<DebugVertex>,
+	// Reused between frames drawing the same number of lines, since draws always use every index
+	// of their index buffer
+	buffers: Option<(
+		Buffer<VertexBufferUsage, [DebugVertex]>,
+		Buffer<IndexBufferUsage, [u32]>,
+	)>,
+}
+
+impl<P> DebugDraw<P>
+where
+	P: RenderPassPrototype,
+{
+	/// Creates a debug drawer whose lines are depth tested against the scene, which is drawn with
+	/// the depth convention `depth`
+	pub fn create(context: &Context, render_pass: &RenderPass<P>, depth: DepthConvention) -> MarsResult<Self> {
+		let vert = compile_shader(DEBUG_VERTEX_SHADER, "debugdraw.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(DEBUG_FRAGMENT_SHADER, "debugdraw.frag", ShaderStage::Fragment)?;
+		let mut function = FunctionDef::create_with_options(
+			context,
+			render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			&FunctionOptions {
+				depth_test: depth.depth_test(),
+				topology: Topology::LineList,
+				..Default::default()
+			},
+		)?;
+		let view_proj = Buffer::make_item_buffer(context, Mat4::identity())?;
+		let arguments = function.make_arguments(context, (view_proj,))?;
+		Ok(Self {
+			function,
+			arguments,
+			vertices: Vec::new(),
+			buffers: None,
+		})
+	}
+
+	/// The number of lines added since the last draw
+	pub fn len(&self) -> usize {
+		self.vertices.len() / 2
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.vertices.is_empty()
+	}
+
+	/// Removes every line added since the last draw
+	pub fn clear(&mut self) {
+		self.vertices.clear();
+	}
+
+	pub fn line(&mut self, from: Vec3, to: Vec3, color: Rgba) {
+		self.vertices.push(DebugVertex { position: from, color });
+		self.vertices.push(DebugVertex { position: to, color });
+	}
+
+	/// Draws lines between consecutive points, and between the last and first if `closed`
+	pub fn polyline(&mut self, points: &[Vec3], closed: bool, color: Rgba) {
+		for pair in points.windows(2) {
+			self.line(pair[0], pair[1], color);
+		}
+		if closed && points.len() > 2 {
+			self.line(points[points.len() - 1], points[0], color);
+		}
+	}
+
+	/// The edges of an axis aligned box
+	pub fn aabb(&mut self, min: Vec3, max: Vec3, color: Rgba) {
+		let corners = box_corners(min, max);
+		self.box_edges(&corners, color);
+	}
+
+	/// The edges of the cube from -0.5 to 0.5 on every axis transformed by `transform`, which can
+	/// place, rotate and scale it into any box
+	pub fn wire_box(&mut self, transform: &Mat4, color: Rgba) {
+		let mut corners = [Vec3::zeros(); 8];
+		let (min, max) = (Vec3::repeat(-0.5), Vec3::repeat(0.5));
+		for (corner, unit) in corners.iter_mut().zip(&box_corners(min, max)) {
+			*corner = transform.transform_point(&Point3::from(*unit)).coords;
+		}
+		self.box_edges(&corners, color);
+	}
+
+	/// Draws the edges between corners given as two faces of four corners each, with each face in
+	/// order around its edges and the `i`th corner of one face joined to the `i`th of the other
+	fn box_edges(&mut self, corners: &[Vec3; 8], color: Rgba) {
+		for i in 0..4 {
+			let j = (i + 1) % 4;
+			self.line(corners[i], corners[j], color);
+			self.line(corners[i + 4], corners[j + 4], color);
+			self.line(corners[i], corners[i + 4], color);
+		}
+	}
+
+	/// A circle of `radius` around `center`, in the plane perpendicular to `normal`
+	pub fn circle(&mut self, center: Vec3, normal: Vec3, radius: f32, color: Rgba) {
+		let normal = normal.normalize();
+		// Any vector not parallel to the normal gives a basis of the circle's plane
+		let other = if normal.x.abs() < 0.9 { Vec3::x() } else { Vec3::y() };
+		let u = normal.cross(&other).normalize() * radius;
+		let v = normal.cross(&u);
+		let point = |i: usize| {
+			let angle = i as f32 / CIRCLE_SEGMENTS as f32 * 2.0 * PI;
+			center + u * angle.cos() + v * angle.sin()
+		};
+		for i in 0..CIRCLE_SEGMENTS {
+			self.line(point(i), point(i + 1), color);
+		}
+	}
+
+	/// A sphere, drawn as a circle around each axis
+	pub fn sphere(&mut self, center: Vec3, radius: f32, color: Rgba) {
+		self.circle(center, Vec3::x(), radius, color);
+		self.circle(center, Vec3::y(), radius, color);
+		self.circle(center, Vec3::z(), radius, color);
+	}
+
+	/// The axes of the space `transform` maps into world space, as lines of length `size` from its
+	/// origin: X in red, Y in green and Z in blue
+	pub fn axes(&mut self, transform: &Mat4, size: f32) {
+		let origin = transform.transform_point(&Point3::origin()).coords;
+		let axes = [
+			(Vec3::x(), Rgba::rgb(1.0, 0.0, 0.0)),
+			(Vec3::y(), Rgba::rgb(0.0, 1.0, 0.0)),
+			(Vec3::z(), Rgba::rgb(0.0, 0.0, 1.0)),
+		];
+		for &(axis, color) in &axes {
+			let end = transform.transform_point(&Point3::from(axis * size)).coords;
+			self.line(origin, end, color);
+		}
+	}
+
+	/// The edges of the volume a camera with the view-projection matrix `view_proj` sees, like
+	/// `Camera::view_proj`. Projections without a far plane, like
+	/// `math::infinite_reverse_perspective`, have no edges to draw and draw nothing.
+	pub fn frustum(&mut self, view_proj: &Mat4, color: Rgba) {
+		let inverse = match view_proj.try_inverse() {
+			Some(inverse) => inverse,
+			None => return,
+		};
+		let mut corners = [Vec3::zeros(); 8];
+		let ndc = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+		for (i, &(x, y)) in ndc.iter().enumerate() {
+			for (face, &z) in [0.0, 1.0].iter().enumerate() {
+				let corner = inverse * Vec4::new(x, y, z, 1.0);
+				if corner.w.abs() < f32::EPSILON {
+					return;
+				}
+				corners[i + face * 4] = corner.xyz() / corner.w;
+			}
+		}
+		self.box_edges(&corners, color);
+	}
+
+	/// Draws every line added since the last draw into `target` as seen through `view_proj`, then
+	/// clears them
+	pub fn draw(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		target: &mut Target<P>,
+		view_proj: Mat4,
+	) -> MarsResult<()> {
+		if self.vertices.is_empty() {
+			return Ok(());
+		}
+		match &self.buffers {
+			Some((vertices, _)) if vertices.len == self.vertices.len() => {
+				vertices.record_upload(context, engine, 0, &self.vertices)?
+			}
+			_ => {
+				let indices = (0..self.vertices.len() as u32).collect::<Vec<_>>();
+				self.buffers = Some((
+					Buffer::make_array_buffer(context, &self.vertices)?,
+					Buffer::make_array_buffer(context, &indices)?,
+				));
+			}
+		}
+		self.arguments.arguments.0.record_upload(context, engine, view_proj)?;
+		self.vertices.clear();
+
+		let (vertices, indices) = self.buffers.as_ref().unwrap();
+		engine.pass(
+			context,
+			target,
+			&self.function,
+			Some(DrawArgs {
+				bindings: &self.arguments,
+				vertices,
+				indices,
+				push_constants: (),
+			}),
+		)
+	}
+}
+
+/// The corners of the box from `min` to `max`, as two faces of four corners each as expected by
+/// `DebugDraw::box_edges`
+fn box_corners(min: Vec3, max: Vec3) -> [Vec3; 8] {
+	[
+		Vec3::new(min.x, min.y, min.z),
+		Vec3::new(max.x, min.y, min.z),
+		Vec3::new(max.x, max.y, min.z),
+		Vec3::new(min.x, max.y, min.z),
+		Vec3::new(min.x, min.y, max.z),
+		Vec3::new(max.x, min.y, max.z),
+		Vec3::new(max.x, max.y, max.z),
+		Vec3::new(min.x, max.y, max.z),
+	]
+}
@@ -0,0 +1,453 @@
+//! A starter kit for deferred shading.
+//!
+//! A `DeferredRenderPass` has two subpasses. The geometry subpass (`GBufferPass`) draws the scene
+//! with ordinary functions that write the albedo, normal and material of each visible surface into
+//! the G-buffer. The lighting subpass (`LightingPass`) then runs a single `LightingFunction` over
+//! the whole target, which reads the G-buffer through input attachments and writes the shaded
+//! result into the output attachment. Both subpasses are recorded by `RenderEngine::deferred_pass`.
+//!
+//! Lighting functions see the G-buffer at the first four bindings, as `subpassInput`s with input
+//! attachment indices 0 to 3: albedo, normal, material and depth. Their own bindings follow from
+//! binding 4. Their vertex input is the position of a triangle covering the whole target.
+
+use std::{iter, marker::PhantomData, sync::Arc};
+
+use rk::{pass, vk};
+
+use crate::{
+	buffer::IndexType,
+	builtin::FullscreenTriangle,
+	function::{
+		Arguments, ArgumentsContainer, BindingDesc, BindingType, Bindings, DepthConvention, DepthTest, DynamicState,
+		FunctionDef, FunctionPrototype, WriteArgument, WriteInputAttachmentArgument,
+	},
+	image::{
+		format::{D32Sfloat, R16G16B16A16Sfloat, R8G8B8A8Unorm},
+		DynImageUsage, FormatType, SampleCount1,
+	},
+	math::*,
+	pass::{
+		external_dependencies, Attachments, ColorAttachment, ColorAttachmentType, ColorAttachments, ColorClearValue,
+		DepthAttachment, DepthAttachmentType, NoDepthAttachment, RenderPass, RenderPassHandle, RenderPassPrototype,
+	},
+	raw_command_buffer, raw_device,
+	render::{self, DrawArgs, RenderEngine},
+	target::Framebuffer,
+	Context, MarsResult,
+};
+
+pub type AlbedoFormat = R8G8B8A8Unorm;
+pub type NormalFormat = R16G16B16A16Sfloat;
+pub type MaterialFormat = R8G8B8A8Unorm;
+pub type GBufferDepthFormat = D32Sfloat;
+
+/// The geometry subpass of a deferred render pass. The G-buffer is cleared at the start of every
+/// deferred pass, and since color attachments are blended, functions should write an alpha of 1.
+pub struct GBufferPass;
+
+impl RenderPassPrototype for GBufferPass {
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (
+		ColorAttachment<AlbedoFormat>,
+		ColorAttachment<NormalFormat>,
+		ColorAttachment<MaterialFormat>,
+	);
+	type DepthAttachment = DepthAttachment<GBufferDepthFormat, SampleCount1>;
+}
+
+/// The lighting subpass of a deferred render pass, which writes to an output attachment of format
+/// `F`
+pub struct LightingPass<F>(PhantomData<F>);
+
+impl<F> RenderPassPrototype for LightingPass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<F>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+/// A function that shades the G-buffer into an output of format `F`, with the additional bindings
+/// `B`
+pub struct LightingFunction<F, B>(PhantomData<(F, B)>);
+
+impl<F, B> FunctionPrototype for LightingFunction<F, B>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+	B: Bindings,
+{
+	type RenderPass = LightingPass<F>;
+	type VertexInput = Vec2;
+	type Bindings = LightingBindings<B>;
+	type PushConstants = ();
+}
+
+/// The G-buffer input attachments followed by the bindings `B`
+pub struct LightingBindings<B>(PhantomData<B>);
+
+unsafe impl<B> Bindings for LightingBindings<B>
+where
+	B: Bindings,
+{
+	type Arguments = LightingArguments<B::Arguments>;
+
+	fn descriptions() -> Vec<BindingDesc> {
+		let mut descriptions = (0..4)
+			.map(|_| BindingDesc {
+				binding_type: BindingType::InputAttachment,
+				count: 1,
+			})
+			.collect::<Vec<_>>();
+		descriptions.append(&mut B::descriptions());
+		descriptions
+	}
+}
+
+pub struct LightingArguments<A> {
+	/// The G-buffer of the target the lighting function will be used with
+	pub inputs: GBufferInputs,
+	pub arguments: A,
+}
+
+impl<A> Arguments for LightingArguments<A>
+where
+	A: Arguments,
+{
+	fn as_writes(&self) -> Vec<WriteArgument> {
+		let mut writes = self.inputs.as_writes();
+		writes.append(&mut self.arguments.as_writes());
+		writes
+	}
+}
+
+/// The G-buffer attachments of a `DeferredTarget`, as read by lighting functions. These refer to
+/// the target's images, so lighting arguments have to be made again whenever the target is
+/// recreated.
+#[derive(Debug, Copy, Clone)]
+pub struct GBufferInputs {
+	albedo: vk::ImageView,
+	normal: vk::ImageView,
+	material: vk::ImageView,
+	depth: vk::ImageView,
+}
+
+impl GBufferInputs {
+	fn as_writes(&self) -> Vec<WriteArgument<'static>> {
+		let color = |image_view| {
+			WriteArgument::InputAttachment(WriteInputAttachmentArgument {
+				image_view,
+				image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			})
+		};
+		vec![
+			color(self.albedo),
+			color(self.normal),
+			color(self.material),
+			WriteArgument::InputAttachment(WriteInputAttachmentArgument {
+				image_view: self.depth,
+				image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+			}),
+		]
+	}
+}
+
+// The attachments of a deferred render pass, in framebuffer order
+const ALBEDO: u32 = 0;
+const NORMAL: u32 = 1;
+const MATERIAL: u32 = 2;
+const DEPTH: u32 = 3;
+const OUTPUT: u32 = 4;
+
+pub struct DeferredRenderPass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	geometry: RenderPass<GBufferPass>,
+	lighting: RenderPass<LightingPass<F>>,
+}
+
+impl<F> DeferredRenderPass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	pub fn create(context: &Context) -> MarsResult<Self> {
+		// The G-buffer only lives for the duration of the pass, so it's cleared instead of loaded.
+		// Each attachment ends up back in the layout mars keeps it in between passes.
+		let attachments = [
+			attachment(
+				AlbedoFormat::as_raw(),
+				vk::AttachmentLoadOp::CLEAR,
+				vk::ImageLayout::UNDEFINED,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			),
+			attachment(
+				NormalFormat::as_raw(),
+				vk::AttachmentLoadOp::CLEAR,
+				vk::ImageLayout::UNDEFINED,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			),
+			attachment(
+				MaterialFormat::as_raw(),
+				vk::AttachmentLoadOp::CLEAR,
+				vk::ImageLayout::UNDEFINED,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			),
+			attachment(
+				GBufferDepthFormat::as_raw(),
+				vk::AttachmentLoadOp::CLEAR,
+				vk::ImageLayout::UNDEFINED,
+				vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+			),
+			attachment(
+				F::as_raw(),
+				vk::AttachmentLoadOp::LOAD,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			),
+		];
+		let reference = |attachment, layout| pass::AttachmentRef { attachment, layout };
+		let color = |attachment| pass::ColorAttachment {
+			color: reference(attachment, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+			resolve: None,
+		};
+		let subpasses = [
+			pass::Subpass {
+				input_attachments: Vec::new(),
+				color_attachments: vec![color(ALBEDO), color(NORMAL), color(MATERIAL)],
+				depth_stencil_attachment: Some(reference(DEPTH, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)),
+			},
+			pass::Subpass {
+				input_attachments: vec![
+					reference(ALBEDO, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+					reference(NORMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+					reference(MATERIAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+					reference(DEPTH, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+				],
+				color_attachments: vec![color(OUTPUT)],
+				depth_stencil_attachment: None,
+			},
+		];
+		let [before, after] = external_dependencies(1);
+		let dependencies = [
+			before,
+			vk::SubpassDependency {
+				src_subpass: 0,
+				dst_subpass: 1,
+				src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+					| vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+					| vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+				dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+				src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+					| vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags::INPUT_ATTACHMENT_READ,
+				dependency_flags: vk::DependencyFlags::BY_REGION,
+			},
+			after,
+		];
+		let render_pass = RenderPassHandle::get_or_create(context, &attachments, &subpasses, &dependencies, 0)?;
+
+		Ok(Self {
+			geometry: RenderPass::from_handle(render_pass.clone(), 0),
+			lighting: RenderPass::from_handle(render_pass, 1),
+		})
+	}
+
+	/// The geometry subpass, for creating functions that write to the G-buffer. Functions must be
+	/// created with this rather than a separately created `RenderPass<GBufferPass>`.
+	pub fn geometry(&self) -> &RenderPass<GBufferPass> {
+		&self.geometry
+	}
+
+	/// The lighting subpass, for creating `LightingFunction`s
+	pub fn lighting(&self) -> &RenderPass<LightingPass<F>> {
+		&self.lighting
+	}
+}
+
+fn attachment(
+	format: vk::Format,
+	load_op: vk::AttachmentLoadOp,
+	initial_layout: vk::ImageLayout,
+	final_layout: vk::ImageLayout,
+) -> pass::Attachment {
+	pass::Attachment {
+		format,
+		samples: vk::SampleCountFlags::TYPE_1,
+		load_op,
+		store_op: vk::AttachmentStoreOp::STORE,
+		stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+		stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+		initial_layout,
+		final_layout,
+	}
+}
+
+/// The G-buffer and output attachment rendered to by a deferred pass
+pub struct DeferredTarget<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	pub(crate) render_pass: Arc<RenderPassHandle>,
+	pub(crate) gbuffer: Attachments<GBufferPass>,
+	pub(crate) output: ColorAttachment<F>,
+	pub(crate) framebuffer: Framebuffer,
+	pub(crate) triangle: FullscreenTriangle,
+}
+
+impl<F> DeferredTarget<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	pub fn create(
+		context: &Context,
+		render_pass: &DeferredRenderPass<F>,
+		extent: vk::Extent2D,
+		output_usages: DynImageUsage,
+	) -> MarsResult<Self> {
+		let render_pass = render_pass.geometry.render_pass.clone();
+		let gbuffer = Attachments {
+			extent,
+			input_attachments: (),
+			color_attachments: <<GBufferPass as RenderPassPrototype>::ColorAttachments as ColorAttachments<
+				SampleCount1,
+			>>::create(context, DynImageUsage::INPUT_ATTACHMENT, extent, 1)?,
+			depth_attachment: DepthAttachment::create(context, DynImageUsage::INPUT_ATTACHMENT, extent, 1)?,
+		};
+		let output =
+			<ColorAttachment<F> as ColorAttachmentType<SampleCount1>>::create(context, output_usages, extent, 1)?;
+		let mut views = gbuffer.as_raw();
+		views.push(output.view.image_view.raw);
+		let framebuffer = Framebuffer::create_raw(context, &render_pass, extent, 1, &views)?;
+
+		Ok(Self {
+			render_pass,
+			gbuffer,
+			output,
+			framebuffer,
+			triangle: FullscreenTriangle::create(context)?,
+		})
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.gbuffer.extent
+	}
+
+	pub fn gbuffer(&self) -> &Attachments<GBufferPass> {
+		&self.gbuffer
+	}
+
+	/// The attachment the lighting subpass writes to
+	pub fn output(&self) -> &ColorAttachment<F> {
+		&self.output
+	}
+
+	/// The values the attachments are cleared to when a deferred pass begins, in framebuffer order,
+	/// with the G-buffer's depth cleared to `depth`
+	fn load_clear_values(&self, depth: f32) -> Vec<vk::ClearValue> {
+		let mut clear_values = Vec::new();
+		let colors = <<GBufferPass as RenderPassPrototype>::ColorAttachments as ColorAttachments<
+			SampleCount1,
+		>>::load_clear_values(&self.gbuffer.color_attachments);
+		for (color, resolve) in colors {
+			clear_values.push(color);
+			clear_values.extend(resolve);
+		}
+		clear_values.extend(self.gbuffer.depth_attachment.clear(depth));
+		// The output attachment is loaded, so its clear value is ignored
+		let (output, _) = <ColorAttachment<F> as ColorAttachmentType<SampleCount1>>::load_clear_values(&self.output);
+		clear_values.push(output);
+		clear_values
+	}
+
+	/// The G-buffer attachments, for making the arguments of lighting functions
+	pub fn inputs(&self) -> GBufferInputs {
+		let (albedo, normal, material) = &self.gbuffer.color_attachments;
+		GBufferInputs {
+			albedo: albedo.view.image_view.raw,
+			normal: normal.view.image_view.raw,
+			material: material.view.image_view.raw,
+			depth: self.gbuffer.depth_attachment.view.image_view.raw,
+		}
+	}
+}
+
+impl RenderEngine {
+	/// Renders a frame with deferred shading. The G-buffer of the target is cleared, the draws are
+	/// run with the geometry function to fill it, and then the lighting function shades it into the
+	/// output attachment.
+	pub fn deferred_pass<'a, F, G, B, X, I>(
+		&mut self,
+		context: &Context,
+		target: &mut DeferredTarget<F>,
+		geometry: &FunctionDef<G>,
+		draws: I,
+		lighting: &FunctionDef<LightingFunction<F, B>>,
+		lighting_arguments: &ArgumentsContainer<LightingFunction<F, B>>,
+	) -> MarsResult<()>
+	where
+		F: FormatType,
+		F::ClearValue: ColorClearValue,
+		G: FunctionPrototype<RenderPass = GBufferPass> + 'a,
+		B: Bindings,
+		X: IndexType,
+		I: IntoIterator<Item = DrawArgs<'a, G, X>>,
+	{
+		let dynamic_state = *self.dynamic_state();
+		self.submit(context, |this, command_buffer, recorded| {
+			unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				let extent = target.extent();
+				// The G-buffer is cleared to the farthest depth of the geometry function's convention
+				let depth = match geometry.depth_test {
+					DepthTest::Greater => DepthConvention::Reverse,
+					_ => DepthConvention::Standard,
+				}
+				.clear_value();
+				render::begin_raw_render_pass(
+					context,
+					raw,
+					target.render_pass.raw,
+					target.framebuffer.raw,
+					extent,
+					&target.load_clear_values(depth),
+					vk::SubpassContents::INLINE,
+				);
+				this.record_subpass(
+					context,
+					command_buffer,
+					recorded,
+					extent,
+					geometry.into(),
+					&dynamic_state,
+					draws,
+					|this, command_buffer, draw| render::record_indexed_draw(context, this, command_buffer, &draw),
+				);
+
+				raw_device(&context.device).cmd_next_subpass(raw, vk::SubpassContents::INLINE);
+				// The fullscreen lighting draw isn't affected by the state set for the geometry
+				this.record_subpass(
+					context,
+					command_buffer,
+					recorded,
+					extent,
+					lighting.into(),
+					&DynamicState::default(),
+					iter::once(target.triangle.draw_args(lighting_arguments)),
+					|this, command_buffer, draw| render::record_indexed_draw(context, this, command_buffer, &draw),
+				);
+				raw_device(&context.device).cmd_end_render_pass(raw);
+			}
+
+			Ok(())
+		})
+	}
+}
@@ -0,0 +1,142 @@
+//! Deferred destruction of resources the device may still be using.
+//!
+//! Every submission to the context's queue is tracked from when it's submitted until it's known to
+//! have completed. Resources dropped while submissions are in flight are held until every one of
+//! those submissions has completed, since any of them could be using the resource, and only then
+//! destroyed. Resources dropped while nothing is in flight are destroyed right away.
+//!
+//! Debug builds also remember the last submission each buffer was recorded into, and panic if the
+//! buffer is mapped while that submission may still be executing, instead of letting the device
+//! read data that's being rewritten or the host read data that's still being written. Uses are
+//! collected in a `RecordedUses` kept with the command buffer they were recorded into, and given
+//! the serial of the submission that command buffer ends up in.
+
+use std::{
+	cell::RefCell,
+	collections::BTreeSet,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+};
+
+#[derive(Clone, Default)]
+pub(crate) struct DestructionQueue {
+	state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+	/// The serial of the latest submission
+	submitted: u64,
+	in_flight: BTreeSet<u64>,
+	/// Destructors waiting on every submission up to and including their serial
+	pending: Vec<(u64, Box<dyn FnOnce() + Send>)>,
+}
+
+impl DestructionQueue {
+	pub(crate) fn new() -> Self {
+		Self::default()
+	}
+
+	/// Records that a submission is about to be made, returning the serial to complete it with. The
+	/// resources in `recorded` are marked as used by it.
+	pub(crate) fn begin_submission(&self, recorded: &RecordedUses) -> u64 {
+		let mut state = self.state.lock().unwrap();
+		state.submitted += 1;
+		let serial = state.submitted;
+		state.in_flight.insert(serial);
+		drop(state);
+		for last_use in recorded.uses.borrow_mut().drain(..) {
+			last_use.store(serial, Ordering::Release);
+		}
+		serial
+	}
+
+	/// Returns whether no submissions are in flight
+	pub(crate) fn is_idle(&self) -> bool {
+		self.state.lock().unwrap().in_flight.is_empty()
+	}
+
+	fn is_in_flight(&self, serial: u64) -> bool {
+		self.state.lock().unwrap().in_flight.contains(&serial)
+	}
+
+	/// Records that a submission has completed, or failed to be submitted, and destroys the
+	/// resources that were only waiting on it
+	pub(crate) fn end_submission(&self, serial: u64) {
+		let ready = {
+			let mut state = self.state.lock().unwrap();
+			state.in_flight.remove(&serial);
+			let oldest = state.in_flight.iter().next().copied().unwrap_or(u64::MAX);
+			let (ready, pending) = state.pending.drain(..).partition(|&(waits_on, _)| waits_on < oldest);
+			state.pending = pending;
+			ready
+		};
+		// Destructors can drop other resources, so they run without the lock held
+		for (_, destroy) in ready {
+			destroy();
+		}
+	}
+
+	/// Runs `destroy` once every submission made so far has completed. Destructors can run on
+	/// whichever thread ends the last submission, so they must be `Send`.
+	pub(crate) fn defer<D: FnOnce() + Send + 'static>(&self, destroy: D) {
+		{
+			let mut state = self.state.lock().unwrap();
+			if !state.in_flight.is_empty() {
+				let serial = state.submitted;
+				state.pending.push((serial, Box::new(destroy)));
+				return;
+			}
+		}
+		destroy();
+	}
+
+	/// Drops `resource` once every submission made so far has completed
+	pub(crate) fn destroy<T: Send + 'static>(&self, resource: T) {
+		self.defer(move || drop(resource));
+	}
+}
+
+/// The resources recorded into one command buffer, which are marked as used by the submission the
+/// command buffer is submitted in
+#[derive(Default)]
+pub(crate) struct RecordedUses {
+	uses: RefCell<Vec<Arc<AtomicU64>>>,
+}
+
+/// Tracks the last submission a resource was recorded into. Only debug builds do the tracking.
+#[derive(Clone)]
+pub(crate) struct GpuUse {
+	/// The serial of the submission, or 0 if the resource was never used
+	last: Arc<AtomicU64>,
+	queue: DestructionQueue,
+}
+
+impl GpuUse {
+	pub(crate) fn new(queue: &DestructionQueue) -> Self {
+		Self {
+			last: Arc::new(AtomicU64::new(0)),
+			queue: queue.clone(),
+		}
+	}
+
+	/// Marks the resource as used by the command buffer `recorded` belongs to
+	pub(crate) fn mark(&self, recorded: &RecordedUses) {
+		if cfg!(debug_assertions) {
+			recorded.uses.borrow_mut().push(self.last.clone());
+		}
+	}
+
+	/// Panics if a submission the resource was recorded into may still be executing. `action` is
+	/// what was about to be done to the resource, like "Mapped a buffer".
+	pub(crate) fn assert_idle(&self, action: &str) {
+		if cfg!(debug_assertions) {
+			let last = self.last.load(Ordering::Acquire);
+			if last != 0 && self.queue.is_in_flight(last) {
+				panic!("{} while submission {} using it may still be executing", action, last);
+			}
+		}
+	}
+}
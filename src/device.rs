@@ -0,0 +1,573 @@
+use std::{ffi::CStr, os::raw::c_void, ptr};
+
+use rk::{instance::Instance, vk, PhysicalDevice};
+
+use crate::{raw_instance, raw_physical_device};
+
+pub use rk::{FirstPhysicalDeviceChooser, PhysicalDeviceChooser};
+
+/// Chooses the first discrete GPU, falling back to an integrated GPU and then to any device at all
+#[derive(Debug, Copy, Clone)]
+pub struct PreferDiscrete;
+
+impl PhysicalDeviceChooser for PreferDiscrete {
+	fn choose(&self, physical_devices: &[PhysicalDevice]) -> Option<usize> {
+		let find = |ty: vk::PhysicalDeviceType| {
+			physical_devices
+				.iter()
+				.position(|physical_device| physical_device.properties().device_type == ty)
+		};
+		find(vk::PhysicalDeviceType::DISCRETE_GPU)
+			.or_else(|| find(vk::PhysicalDeviceType::INTEGRATED_GPU))
+			.or_else(|| if physical_devices.is_empty() { None } else { Some(0) })
+	}
+}
+
+/// Chooses the first device whose name contains the given string, ignoring case
+#[derive(Debug, Copy, Clone)]
+pub struct ByName<'a>(pub &'a str);
+
+impl<'a> PhysicalDeviceChooser for ByName<'a> {
+	fn choose(&self, physical_devices: &[PhysicalDevice]) -> Option<usize> {
+		let needle = self.0.to_lowercase();
+		physical_devices
+			.iter()
+			.position(|physical_device| device_name(physical_device).to_lowercase().contains(&needle))
+	}
+}
+
+/// Chooses the device at the given index in the order the driver enumerates them
+#[derive(Debug, Copy, Clone)]
+pub struct ByIndex(pub usize);
+
+impl PhysicalDeviceChooser for ByIndex {
+	fn choose(&self, physical_devices: &[PhysicalDevice]) -> Option<usize> {
+		if self.0 < physical_devices.len() {
+			Some(self.0)
+		} else {
+			None
+		}
+	}
+}
+
+/// Gets the human readable name of a physical device, as reported by the driver
+pub fn device_name(physical_device: &PhysicalDevice) -> String {
+	let properties = physical_device.properties();
+	unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// The device features a context should enable when it's created
+///
+/// Features in `required` cause context creation to fail if the chosen device doesn't support them,
+/// while features in `optional` are only enabled if they're available. Which features actually got
+/// enabled can be checked afterwards with `Context::features`. Device extensions work the same
+/// way, and can be checked with `Context::has_extension`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFeatures {
+	pub required: vk::PhysicalDeviceFeatures,
+	pub optional: vk::PhysicalDeviceFeatures,
+	pub required_extensions: Vec<DeviceExtension>,
+	pub optional_extensions: Vec<DeviceExtension>,
+	/// The priorities, from 0 to 1, of extra queues to create from the graphics family alongside the
+	/// one mars submits to, which has the highest priority. Lower priority queues are for background
+	/// work like streaming textures that shouldn't hold up rendering frames. Only as many are created
+	/// as the family has room for, which can be checked with `Context::queue_count`.
+	pub queue_priorities: Vec<f32>,
+	/// Whether to enable the `VK_KHR_display` instance extension, for presenting straight to
+	/// displays with `WindowEngine::new_for_display`. It's only enabled if the Vulkan loader has it,
+	/// which can be checked with `Context::has_direct_display`.
+	pub direct_display: bool,
+}
+
+impl DeviceFeatures {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds the required and optional features of `other` to this set, so that multiple
+	/// subsystems can declare what they need independently
+	pub fn merge(&mut self, other: &DeviceFeatures) {
+		union_features(&mut self.required, &other.required);
+		union_features(&mut self.optional, &other.optional);
+		for extension in &other.required_extensions {
+			if !self.required_extensions.contains(extension) {
+				self.required_extensions.push(*extension);
+			}
+		}
+		for extension in &other.optional_extensions {
+			if !self.optional_extensions.contains(extension) {
+				self.optional_extensions.push(*extension);
+			}
+		}
+		self.queue_priorities.extend_from_slice(&other.queue_priorities);
+		self.direct_display |= other.direct_display;
+	}
+
+	/// Determines the features to enable given the features the device supports, or the names of
+	/// the required features that are missing
+	pub(crate) fn resolve(
+		&self,
+		supported: &vk::PhysicalDeviceFeatures,
+	) -> Result<vk::PhysicalDeviceFeatures, Vec<&'static str>> {
+		let mut enabled = vk::PhysicalDeviceFeatures::default();
+		let mut missing = Vec::new();
+		{
+			let required = features_as_slice(&self.required);
+			let optional = features_as_slice(&self.optional);
+			let supported = features_as_slice(supported);
+			let enabled = features_as_slice_mut(&mut enabled);
+			for (i, name) in FEATURE_NAMES.iter().enumerate() {
+				if required[i] == vk::TRUE && supported[i] != vk::TRUE {
+					missing.push(*name);
+				} else if (required[i] == vk::TRUE || optional[i] == vk::TRUE) && supported[i] == vk::TRUE {
+					enabled[i] = vk::TRUE;
+				}
+			}
+		}
+		if missing.is_empty() {
+			Ok(enabled)
+		} else {
+			Err(missing)
+		}
+	}
+
+	/// Determines the extensions to enable given the extensions (and the features they bring) that
+	/// the device supports, or the required extensions that are missing. Dependencies of the
+	/// requested extensions are enabled along with them.
+	pub(crate) fn resolve_extensions(
+		&self,
+		instance: &Instance,
+		physical_device: &PhysicalDevice,
+	) -> Result<Vec<DeviceExtension>, Vec<DeviceExtension>> {
+		let instance = raw_instance(instance);
+		let physical_device = raw_physical_device(physical_device);
+		let available = unsafe { instance.enumerate_device_extension_properties(physical_device) }.unwrap_or_default();
+		let is_available = |extension: DeviceExtension| {
+			available
+				.iter()
+				.any(|properties| unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) } == extension.name())
+		};
+		let required = with_dependencies(&self.required_extensions);
+		let candidates = with_dependencies(&[&required[..], &self.optional_extensions[..]].concat())
+			.into_iter()
+			.filter(|extension| is_available(*extension))
+			.collect::<Vec<_>>();
+
+		let mut supported_features = ExtensionFeatures::default();
+		unsafe {
+			let mut features = vk::PhysicalDeviceFeatures2::default();
+			features.p_next = supported_features.chain(&candidates);
+			instance.get_physical_device_features2(physical_device, &mut features);
+		}
+		let mut supported = candidates
+			.into_iter()
+			.filter(|extension| supported_features.supports(*extension))
+			.collect::<Vec<_>>();
+		// Drop extensions whose dependencies didn't make it, until nothing changes
+		loop {
+			let before = supported.len();
+			let snapshot = supported.clone();
+			supported.retain(|extension| extension.dependencies().iter().all(|dep| snapshot.contains(dep)));
+			if supported.len() == before {
+				break;
+			}
+		}
+
+		let missing = required
+			.into_iter()
+			.filter(|extension| !supported.contains(extension))
+			.collect::<Vec<_>>();
+		if missing.is_empty() {
+			Ok(supported)
+		} else {
+			Err(missing)
+		}
+	}
+}
+
+fn with_dependencies(extensions: &[DeviceExtension]) -> Vec<DeviceExtension> {
+	let mut all = Vec::new();
+	let mut pending = extensions.to_vec();
+	while let Some(extension) = pending.pop() {
+		if !all.contains(&extension) {
+			all.push(extension);
+			pending.extend_from_slice(extension.dependencies());
+		}
+	}
+	all
+}
+
+/// Device extensions that mars knows how to enable and make use of
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceExtension {
+	/// `VK_KHR_synchronization2`, used internally for barriers and submissions when enabled
+	Synchronization2,
+	/// `VK_KHR_deferred_host_operations`, a dependency of `AccelerationStructure`
+	DeferredHostOperations,
+	/// `VK_KHR_acceleration_structure`, along with the buffer device address feature it relies on
+	AccelerationStructure,
+	/// `VK_KHR_ray_query`, for tracing rays from within the shaders of regular functions
+	RayQuery,
+	/// `VK_EXT_mesh_shader`, for mesh functions. Both the task and mesh shader features are required.
+	MeshShader,
+	/// `VK_KHR_fragment_shading_rate`, for functions with a coarser shading rate
+	FragmentShadingRate,
+	/// `VK_KHR_multiview`, for render passes that draw to several views at once
+	Multiview,
+	/// `VK_KHR_draw_indirect_count`, for indirect draws whose draw count is read from a buffer
+	DrawIndirectCount,
+	/// `VK_EXT_conditional_rendering`, for draws that are skipped depending on a value in a buffer
+	ConditionalRendering,
+	/// `VK_KHR_maintenance1`, for functions that flip the viewport with a negative height
+	Maintenance1,
+	/// `VK_KHR_external_memory_fd`, for sharing memory with other APIs and processes as file
+	/// descriptors
+	ExternalMemoryFd,
+	/// `VK_EXT_external_memory_dma_buf`, for sharing images as Linux DMA-BUFs
+	ExternalMemoryDmaBuf,
+	/// `VK_EXT_image_drm_format_modifier`, for sharing DMA-BUFs in tiled layouts described by DRM
+	/// format modifiers
+	ImageDrmFormatModifier,
+	/// `VK_EXT_device_fault`, for the driver's description of why the device was lost
+	DeviceFault,
+	/// `VK_NV_device_diagnostic_checkpoints`, for finding out which labelled passes the device was
+	/// executing when it was lost
+	DiagnosticCheckpoints,
+	/// `VK_EXT_shader_viewport_index_layer`, for vertex shaders that write `gl_Layer` to draw into
+	/// a layer of a layered render pass without a geometry shader
+	ShaderViewportIndexLayer,
+	/// `VK_KHR_maintenance3`, a dependency of `DescriptorIndexing`
+	Maintenance3,
+	/// `VK_EXT_descriptor_indexing`, for indexing arrays of sampled images with values that differ
+	/// between invocations, like a material index read per pixel. Such indices have to be wrapped in
+	/// `nonuniformEXT` in GLSL. Runtime-sized and partially bound descriptor arrays are enabled too.
+	DescriptorIndexing,
+	/// `VK_EXT_extended_dynamic_state`, for changing the cull mode, topology and depth comparison of
+	/// functions with `DynamicState` instead of creating a function for each combination
+	ExtendedDynamicState,
+}
+
+impl DeviceExtension {
+	pub fn name(self) -> &'static CStr {
+		match self {
+			DeviceExtension::Synchronization2 => vk::KhrSynchronization2Fn::name(),
+			DeviceExtension::DeferredHostOperations => vk::KhrDeferredHostOperationsFn::name(),
+			DeviceExtension::AccelerationStructure => vk::KhrAccelerationStructureFn::name(),
+			DeviceExtension::RayQuery => vk::KhrRayQueryFn::name(),
+			DeviceExtension::MeshShader => vk::ExtMeshShaderFn::name(),
+			DeviceExtension::FragmentShadingRate => vk::KhrFragmentShadingRateFn::name(),
+			DeviceExtension::Multiview => vk::KhrMultiviewFn::name(),
+			DeviceExtension::DrawIndirectCount => vk::KhrDrawIndirectCountFn::name(),
+			DeviceExtension::ConditionalRendering => vk::ExtConditionalRenderingFn::name(),
+			DeviceExtension::Maintenance1 => vk::KhrMaintenance1Fn::name(),
+			DeviceExtension::ExternalMemoryFd => vk::KhrExternalMemoryFdFn::name(),
+			DeviceExtension::ExternalMemoryDmaBuf => vk::ExtExternalMemoryDmaBufFn::name(),
+			DeviceExtension::ImageDrmFormatModifier => vk::ExtImageDrmFormatModifierFn::name(),
+			DeviceExtension::DeviceFault => vk::ExtDeviceFaultFn::name(),
+			DeviceExtension::DiagnosticCheckpoints => vk::NvDeviceDiagnosticCheckpointsFn::name(),
+			DeviceExtension::ShaderViewportIndexLayer => vk::ExtShaderViewportIndexLayerFn::name(),
+			DeviceExtension::Maintenance3 => vk::KhrMaintenance3Fn::name(),
+			DeviceExtension::DescriptorIndexing => vk::ExtDescriptorIndexingFn::name(),
+			DeviceExtension::ExtendedDynamicState => vk::ExtExtendedDynamicStateFn::name(),
+		}
+	}
+
+	/// Other extensions that must be enabled for this one to be enabled
+	pub fn dependencies(self) -> &'static [DeviceExtension] {
+		match self {
+			DeviceExtension::AccelerationStructure => &[DeviceExtension::DeferredHostOperations],
+			DeviceExtension::RayQuery => &[DeviceExtension::AccelerationStructure],
+			DeviceExtension::ExternalMemoryDmaBuf => &[DeviceExtension::ExternalMemoryFd],
+			DeviceExtension::ImageDrmFormatModifier => &[DeviceExtension::ExternalMemoryDmaBuf],
+			DeviceExtension::DescriptorIndexing => &[DeviceExtension::Maintenance3],
+			_ => &[],
+		}
+	}
+}
+
+/// The feature structures of every extension mars knows about, which get linked into a pNext
+/// chain to query or enable the features of a set of extensions
+#[derive(Default)]
+pub(crate) struct ExtensionFeatures {
+	synchronization2: vk::PhysicalDeviceSynchronization2FeaturesKHR,
+	buffer_device_address: vk::PhysicalDeviceBufferDeviceAddressFeatures,
+	acceleration_structure: vk::PhysicalDeviceAccelerationStructureFeaturesKHR,
+	ray_query: vk::PhysicalDeviceRayQueryFeaturesKHR,
+	mesh_shader: vk::PhysicalDeviceMeshShaderFeaturesEXT,
+	fragment_shading_rate: vk::PhysicalDeviceFragmentShadingRateFeaturesKHR,
+	multiview: vk::PhysicalDeviceMultiviewFeatures,
+	conditional_rendering: vk::PhysicalDeviceConditionalRenderingFeaturesEXT,
+	device_fault: vk::PhysicalDeviceFaultFeaturesEXT,
+	descriptor_indexing: vk::PhysicalDeviceDescriptorIndexingFeatures,
+	extended_dynamic_state: vk::PhysicalDeviceExtendedDynamicStateFeaturesEXT,
+}
+
+impl ExtensionFeatures {
+	/// Links together the feature structures of the given extensions and returns the head of the
+	/// chain. `extensions` must not contain duplicates, and `self` must outlive any use of the chain.
+	pub(crate) unsafe fn chain(&mut self, extensions: &[DeviceExtension]) -> *mut c_void {
+		let mut next: *mut c_void = ptr::null_mut();
+		macro_rules! link {
+			($features:expr) => {{
+				$features.p_next = next;
+				next = &mut $features as *mut _ as *mut c_void;
+			}};
+		}
+		for extension in extensions {
+			match extension {
+				DeviceExtension::Synchronization2 => link!(self.synchronization2),
+				DeviceExtension::DeferredHostOperations => {}
+				DeviceExtension::AccelerationStructure => {
+					link!(self.buffer_device_address);
+					link!(self.acceleration_structure);
+				}
+				DeviceExtension::RayQuery => link!(self.ray_query),
+				DeviceExtension::MeshShader => link!(self.mesh_shader),
+				DeviceExtension::FragmentShadingRate => link!(self.fragment_shading_rate),
+				DeviceExtension::Multiview => link!(self.multiview),
+				DeviceExtension::DrawIndirectCount => {}
+				DeviceExtension::ConditionalRendering => link!(self.conditional_rendering),
+				DeviceExtension::Maintenance1 => {}
+				DeviceExtension::ExternalMemoryFd => {}
+				DeviceExtension::ExternalMemoryDmaBuf => {}
+				DeviceExtension::ImageDrmFormatModifier => {}
+				DeviceExtension::DeviceFault => link!(self.device_fault),
+				DeviceExtension::DiagnosticCheckpoints => {}
+				DeviceExtension::ShaderViewportIndexLayer => {}
+				DeviceExtension::Maintenance3 => {}
+				DeviceExtension::DescriptorIndexing => link!(self.descriptor_indexing),
+				DeviceExtension::ExtendedDynamicState => link!(self.extended_dynamic_state),
+			}
+		}
+		next
+	}
+
+	/// Whether the features an extension needs to be useful are set
+	pub(crate) fn supports(&self, extension: DeviceExtension) -> bool {
+		match extension {
+			DeviceExtension::Synchronization2 => self.synchronization2.synchronization2 == vk::TRUE,
+			DeviceExtension::DeferredHostOperations => true,
+			DeviceExtension::AccelerationStructure => {
+				self.buffer_device_address.buffer_device_address == vk::TRUE
+					&& self.acceleration_structure.acceleration_structure == vk::TRUE
+			}
+			DeviceExtension::RayQuery => self.ray_query.ray_query == vk::TRUE,
+			DeviceExtension::MeshShader => {
+				self.mesh_shader.task_shader == vk::TRUE && self.mesh_shader.mesh_shader == vk::TRUE
+			}
+			DeviceExtension::FragmentShadingRate => {
+				self.fragment_shading_rate.pipeline_fragment_shading_rate == vk::TRUE
+			}
+			DeviceExtension::Multiview => self.multiview.multiview == vk::TRUE,
+			DeviceExtension::DrawIndirectCount => true,
+			DeviceExtension::ConditionalRendering => self.conditional_rendering.conditional_rendering == vk::TRUE,
+			DeviceExtension::Maintenance1 => true,
+			DeviceExtension::ExternalMemoryFd => true,
+			DeviceExtension::ExternalMemoryDmaBuf => true,
+			DeviceExtension::ImageDrmFormatModifier => true,
+			DeviceExtension::DeviceFault => self.device_fault.device_fault == vk::TRUE,
+			DeviceExtension::DiagnosticCheckpoints => true,
+			DeviceExtension::ShaderViewportIndexLayer => true,
+			DeviceExtension::Maintenance3 => true,
+			DeviceExtension::DescriptorIndexing => {
+				let features = &self.descriptor_indexing;
+				features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+					&& features.runtime_descriptor_array == vk::TRUE
+					&& features.descriptor_binding_partially_bound == vk::TRUE
+			}
+			DeviceExtension::ExtendedDynamicState => self.extended_dynamic_state.extended_dynamic_state == vk::TRUE,
+		}
+	}
+
+	/// Sets the features an extension needs to be useful
+	pub(crate) fn enable(&mut self, extension: DeviceExtension) {
+		match extension {
+			DeviceExtension::Synchronization2 => self.synchronization2.synchronization2 = vk::TRUE,
+			DeviceExtension::DeferredHostOperations => {}
+			DeviceExtension::AccelerationStructure => {
+				self.buffer_device_address.buffer_device_address = vk::TRUE;
+				self.acceleration_structure.acceleration_structure = vk::TRUE;
+			}
+			DeviceExtension::RayQuery => self.ray_query.ray_query = vk::TRUE,
+			DeviceExtension::MeshShader => {
+				self.mesh_shader.task_shader = vk::TRUE;
+				self.mesh_shader.mesh_shader = vk::TRUE;
+			}
+			DeviceExtension::FragmentShadingRate => {
+				self.fragment_shading_rate.pipeline_fragment_shading_rate = vk::TRUE
+			}
+			DeviceExtension::Multiview => self.multiview.multiview = vk::TRUE,
+			DeviceExtension::DrawIndirectCount => {}
+			DeviceExtension::ConditionalRendering => self.conditional_rendering.conditional_rendering = vk::TRUE,
+			DeviceExtension::Maintenance1 => {}
+			DeviceExtension::ExternalMemoryFd => {}
+			DeviceExtension::ExternalMemoryDmaBuf => {}
+			DeviceExtension::ImageDrmFormatModifier => {}
+			DeviceExtension::DeviceFault => self.device_fault.device_fault = vk::TRUE,
+			DeviceExtension::DiagnosticCheckpoints => {}
+			DeviceExtension::ShaderViewportIndexLayer => {}
+			DeviceExtension::Maintenance3 => {}
+			DeviceExtension::DescriptorIndexing => {
+				let features = &mut self.descriptor_indexing;
+				features.shader_sampled_image_array_non_uniform_indexing = vk::TRUE;
+				features.runtime_descriptor_array = vk::TRUE;
+				features.descriptor_binding_partially_bound = vk::TRUE;
+			}
+			DeviceExtension::ExtendedDynamicState => self.extended_dynamic_state.extended_dynamic_state = vk::TRUE,
+		}
+	}
+}
+
+/// Gets the names of all the features that are set in `features`
+pub fn feature_names(features: &vk::PhysicalDeviceFeatures) -> Vec<&'static str> {
+	features_as_slice(features)
+		.iter()
+		.zip(FEATURE_NAMES.iter())
+		.filter(|(enabled, _)| **enabled == vk::TRUE)
+		.map(|(_, name)| *name)
+		.collect()
+}
+
+fn union_features(a: &mut vk::PhysicalDeviceFeatures, b: &vk::PhysicalDeviceFeatures) {
+	let b = features_as_slice(b);
+	for (a, b) in features_as_slice_mut(a).iter_mut().zip(b.iter()) {
+		if *b == vk::TRUE {
+			*a = vk::TRUE;
+		}
+	}
+}
+
+// vk::PhysicalDeviceFeatures is a #[repr(C)] struct made up entirely of Bool32s, so it can be
+// treated as an array of them in declaration order
+fn features_as_slice(features: &vk::PhysicalDeviceFeatures) -> &[vk::Bool32] {
+	unsafe { std::slice::from_raw_parts(features as *const _ as *const vk::Bool32, FEATURE_NAMES.len()) }
+}
+
+fn features_as_slice_mut(features: &mut vk::PhysicalDeviceFeatures) -> &mut [vk::Bool32] {
+	unsafe { std::slice::from_raw_parts_mut(features as *mut _ as *mut vk::Bool32, FEATURE_NAMES.len()) }
+}
+
+const FEATURE_NAMES: [&str; 55] = [
+	"robust_buffer_access",
+	"full_draw_index_uint32",
+	"image_cube_array",
+	"independent_blend",
+	"geometry_shader",
+	"tessellation_shader",
+	"sample_rate_shading",
+	"dual_src_blend",
+	"logic_op",
+	"multi_draw_indirect",
+	"draw_indirect_first_instance",
+	"depth_clamp",
+	"depth_bias_clamp",
+	"fill_mode_non_solid",
+	"depth_bounds",
+	"wide_lines",
+	"large_points",
+	"alpha_to_one",
+	"multi_viewport",
+	"sampler_anisotropy",
+	"texture_compression_etc2",
+	"texture_compression_astc_ldr",
+	"texture_compression_bc",
+	"occlusion_query_precise",
+	"pipeline_statistics_query",
+	"vertex_pipeline_stores_and_atomics",
+	"fragment_stores_and_atomics",
+	"shader_tessellation_and_geometry_point_size",
+	"shader_image_gather_extended",
+	"shader_storage_image_extended_formats",
+	"shader_storage_image_multisample",
+	"shader_storage_image_read_without_format",
+	"shader_storage_image_write_without_format",
+	"shader_uniform_buffer_array_dynamic_indexing",
+	"shader_sampled_image_array_dynamic_indexing",
+	"shader_storage_buffer_array_dynamic_indexing",
+	"shader_storage_image_array_dynamic_indexing",
+	"shader_clip_distance",
+	"shader_cull_distance",
+	"shader_float64",
+	"shader_int64",
+	"shader_int16",
+	"shader_resource_residency",
+	"shader_resource_min_lod",
+	"sparse_binding",
+	"sparse_residency_buffer",
+	"sparse_residency_image2_d",
+	"sparse_residency_image3_d",
+	"sparse_residency2_samples",
+	"sparse_residency4_samples",
+	"sparse_residency8_samples",
+	"sparse_residency16_samples",
+	"sparse_residency_aliased",
+	"variable_multisample_rate",
+	"inherited_queries",
+];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn resolve_enables_supported_features() {
+		let mut features = DeviceFeatures::new();
+		features.required.sampler_anisotropy = vk::TRUE;
+		features.optional.wide_lines = vk::TRUE;
+		features.optional.geometry_shader = vk::TRUE;
+		let supported = vk::PhysicalDeviceFeatures {
+			sampler_anisotropy: vk::TRUE,
+			geometry_shader: vk::TRUE,
+			fill_mode_non_solid: vk::TRUE,
+			..Default::default()
+		};
+		let enabled = features.resolve(&supported).unwrap();
+		assert_eq!(enabled.sampler_anisotropy, vk::TRUE);
+		assert_eq!(enabled.geometry_shader, vk::TRUE);
+		// Optional features the device lacks and supported features nobody asked for stay off
+		assert_eq!(enabled.wide_lines, vk::FALSE);
+		assert_eq!(enabled.fill_mode_non_solid, vk::FALSE);
+	}
+
+	#[test]
+	fn resolve_names_missing_required_features() {
+		let mut features = DeviceFeatures::new();
+		features.required.sampler_anisotropy = vk::TRUE;
+		features.required.wide_lines = vk::TRUE;
+		features.required.geometry_shader = vk::TRUE;
+		let supported = vk::PhysicalDeviceFeatures {
+			geometry_shader: vk::TRUE,
+			..Default::default()
+		};
+		assert_eq!(
+			features.resolve(&supported).unwrap_err(),
+			vec!["wide_lines", "sampler_anisotropy"]
+		);
+	}
+
+	#[test]
+	fn merge_unions_features_and_extensions() {
+		let mut features = DeviceFeatures::new();
+		features.required.wide_lines = vk::TRUE;
+		features.required_extensions.push(DeviceExtension::Multiview);
+		let mut other = DeviceFeatures::new();
+		other.optional.geometry_shader = vk::TRUE;
+		other.required_extensions.push(DeviceExtension::Multiview);
+		other.optional_extensions.push(DeviceExtension::MeshShader);
+		features.merge(&other);
+		assert_eq!(features.required.wide_lines, vk::TRUE);
+		assert_eq!(features.optional.geometry_shader, vk::TRUE);
+		assert_eq!(features.required_extensions, vec![DeviceExtension::Multiview]);
+		assert_eq!(features.optional_extensions, vec![DeviceExtension::MeshShader]);
+	}
+
+	#[test]
+	fn with_dependencies_follows_chains() {
+		let extensions = with_dependencies(&[DeviceExtension::RayQuery]);
+		assert_eq!(extensions.len(), 3);
+		assert!(extensions.contains(&DeviceExtension::AccelerationStructure));
+		assert!(extensions.contains(&DeviceExtension::DeferredHostOperations));
+	}
+}
@@ -0,0 +1,120 @@
+//! Presentation straight to displays through `VK_KHR_display`, for kiosks and embedded devices
+//! without a window system or compositor.
+//!
+//! `displays` lists the displays attached to the context's device along with their modes. A
+//! `WindowEngine` created with `WindowEngine::new_for_display` then presents full screen to one of
+//! them, on the first display plane that can show it, just like it would to a window. Both need a
+//! context created with `DeviceFeatures::direct_display`.
+
+use std::ffi::CStr;
+
+use rk::{ash::extensions::khr, vk};
+
+use crate::{raw_instance, raw_physical_device, Context, MarsResult};
+
+/// A display attached to the device
+#[derive(Debug, Clone)]
+pub struct Display {
+	pub name: String,
+	/// The native resolution of the display
+	pub physical_resolution: vk::Extent2D,
+	/// The modes the display can be driven in
+	pub modes: Vec<DisplayMode>,
+	pub(crate) raw: vk::DisplayKHR,
+}
+
+/// A resolution and refresh rate a display can be driven in
+#[derive(Debug, Copy, Clone)]
+pub struct DisplayMode {
+	pub extent: vk::Extent2D,
+	/// The refresh rate in millihertz
+	pub refresh_rate: u32,
+	pub(crate) raw: vk::DisplayModeKHR,
+}
+
+/// Lists the displays attached to the context's device. Fails with `ERROR_EXTENSION_NOT_PRESENT`
+/// unless the context was created with `DeviceFeatures::direct_display` and the loader has it.
+pub fn displays(context: &Context) -> MarsResult<Vec<Display>> {
+	if !context.has_direct_display() {
+		return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+	}
+	let loader = khr::Display::new(&context.entry, raw_instance(&context.instance));
+	let physical_device = raw_physical_device(&context.physical_device);
+	unsafe {
+		loader
+			.get_physical_device_display_properties(physical_device)?
+			.into_iter()
+			.map(|properties| {
+				let name = if properties.display_name.is_null() {
+					String::new()
+				} else {
+					CStr::from_ptr(properties.display_name).to_string_lossy().into_owned()
+				};
+				let modes = loader
+					.get_display_mode_properties(physical_device, properties.display)?
+					.into_iter()
+					.map(|mode| DisplayMode {
+						extent: mode.parameters.visible_region,
+						refresh_rate: mode.parameters.refresh_rate,
+						raw: mode.display_mode,
+					})
+					.collect();
+				Ok(Display {
+					name,
+					physical_resolution: properties.physical_resolution,
+					modes,
+					raw: properties.display,
+				})
+			})
+			.collect()
+	}
+}
+
+/// Creates a surface covering `display` in `mode`, on the first plane that can show the display and
+/// isn't already showing another one
+pub(crate) unsafe fn create_surface(
+	context: &Context,
+	display: &Display,
+	mode: &DisplayMode,
+) -> MarsResult<vk::SurfaceKHR> {
+	if !context.has_direct_display() {
+		return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+	}
+	let loader = khr::Display::new(&context.entry, raw_instance(&context.instance));
+	let physical_device = raw_physical_device(&context.physical_device);
+	let planes = loader.get_physical_device_display_plane_properties(physical_device)?;
+	let mut plane_index = None;
+	for (index, plane) in planes.iter().enumerate() {
+		let index = index as u32;
+		if plane.current_display != vk::DisplayKHR::null() && plane.current_display != display.raw {
+			continue;
+		}
+		if loader
+			.get_display_plane_supported_displays(physical_device, index)?
+			.contains(&display.raw)
+		{
+			plane_index = Some(index);
+			break;
+		}
+	}
+	let plane_index = plane_index.ok_or(vk::Result::ERROR_INITIALIZATION_FAILED)?;
+
+	let capabilities = loader.get_display_plane_capabilities(physical_device, mode.raw, plane_index)?;
+	let alpha_mode = if capabilities
+		.supported_alpha
+		.contains(vk::DisplayPlaneAlphaFlagsKHR::OPAQUE)
+	{
+		vk::DisplayPlaneAlphaFlagsKHR::OPAQUE
+	} else {
+		vk::DisplayPlaneAlphaFlagsKHR::GLOBAL
+	};
+	let create_info = vk::DisplaySurfaceCreateInfoKHR::builder()
+		.display_mode(mode.raw)
+		.plane_index(plane_index)
+		.plane_stack_index(planes[plane_index as usize].current_stack_index)
+		.transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
+		.global_alpha(1.0)
+		.alpha_mode(alpha_mode)
+		.image_extent(mode.extent);
+	loader.create_display_plane_surface(&create_info, None)
+}
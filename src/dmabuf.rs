@@ -0,0 +1,241 @@
+//! Sharing images with other Linux APIs and processes as DMA-BUFs, without copying.
+//!
+//! A DMA-BUF is a file descriptor referring to device memory, along with a DRM format modifier
+//! that says how the texels are laid out in it (linearly, or in one of the driver's tiled layouts)
+//! and where each of its memory planes starts. Wayland compositors, v4l2 cameras, video decoders
+//! and media pipelines all pass images around this way.
+//!
+//! `Image::import_dma_buf` wraps a DMA-BUF from elsewhere as an image, and `Image::export_dma_buf`
+//! creates an image in one of a list of modifiers and returns the DMA-BUF describing it. Which
+//! modifiers the device can use for a format is listed by `supported_modifiers`; the consumer of an
+//! exported image should be asked which ones it can read. Both require the `ImageDrmFormatModifier`
+//! device extension.
+
+use std::{
+	fs::File,
+	os::unix::io::{AsRawFd, FromRawFd},
+};
+
+use rk::{ash::extensions::khr, vk};
+
+use crate::{
+	device::DeviceExtension,
+	image::{DynImageUsage, FormatType, Image, ImageUsageType, SampleCount1},
+	memory::find_memory_type,
+	raw_device, raw_instance, raw_physical_device, Context, MarsResult,
+};
+
+const HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags = vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT;
+
+/// The modifier of linearly laid out images, which every driver understands
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// An image in device memory shared as a file descriptor
+#[derive(Debug)]
+pub struct DmaBuf {
+	pub fd: File,
+	pub extent: vk::Extent2D,
+	/// The DRM format modifier of the image's layout
+	pub modifier: u64,
+	/// Where each of the memory planes of the modifier is, all within `fd`
+	pub planes: Vec<DmaBufPlane>,
+}
+
+/// A memory plane of a DMA-BUF
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DmaBufPlane {
+	/// The offset of the plane in the DMA-BUF, in bytes
+	pub offset: u64,
+	/// The distance between the starts of consecutive rows, in bytes
+	pub stride: u64,
+}
+
+/// A DRM format modifier the device supports for a format
+#[derive(Debug, Copy, Clone)]
+pub struct DrmModifier {
+	pub modifier: u64,
+	/// The number of memory planes images with the modifier have
+	pub plane_count: u32,
+	/// What images with the modifier can be used for
+	pub features: vk::FormatFeatureFlags,
+}
+
+/// Lists the DRM format modifiers the device supports for images of `format`
+pub fn supported_modifiers(context: &Context, format: vk::Format) -> Vec<DrmModifier> {
+	let instance = raw_instance(&context.instance);
+	let physical_device = raw_physical_device(&context.physical_device);
+	unsafe {
+		// The first query gets the number of modifiers, and the second fills them in
+		let mut list = vk::DrmFormatModifierPropertiesListEXT::default();
+		let mut properties = vk::FormatProperties2::builder().push_next(&mut list);
+		instance.get_physical_device_format_properties2(physical_device, format, &mut properties);
+		let mut modifiers =
+			vec![vk::DrmFormatModifierPropertiesEXT::default(); list.drm_format_modifier_count as usize];
+		list.p_drm_format_modifier_properties = modifiers.as_mut_ptr();
+		let mut properties = vk::FormatProperties2::builder().push_next(&mut list);
+		instance.get_physical_device_format_properties2(physical_device, format, &mut properties);
+		modifiers.truncate(list.drm_format_modifier_count as usize);
+		modifiers
+			.into_iter()
+			.map(|modifier| DrmModifier {
+				modifier: modifier.drm_format_modifier,
+				plane_count: modifier.drm_format_modifier_plane_count,
+				features: modifier.drm_format_modifier_tiling_features,
+			})
+			.collect()
+	}
+}
+
+impl<U: ImageUsageType, F: FormatType> Image<U, F, SampleCount1> {
+	/// Wraps the memory of a DMA-BUF of texels of format `F` as an image with `usage`. The image
+	/// takes ownership of the file descriptor once it's imported, and otherwise it's closed. The image
+	/// starts in `UNDEFINED` layout, like every new image.
+	pub fn import_dma_buf(context: &Context, usage: U, dma_buf: DmaBuf) -> MarsResult<Self> {
+		check_extension(context)?;
+		let plane_layouts = dma_buf
+			.planes
+			.iter()
+			.map(|plane| vk::SubresourceLayout {
+				offset: plane.offset,
+				size: 0,
+				row_pitch: plane.stride,
+				array_pitch: 0,
+				depth_pitch: 0,
+			})
+			.collect::<Vec<_>>();
+		let mut modifier_info = vk::ImageDrmFormatModifierExplicitCreateInfoEXT::builder()
+			.drm_format_modifier(dma_buf.modifier)
+			.plane_layouts(&plane_layouts);
+		let mut external_info = vk::ExternalMemoryImageCreateInfo::builder().handle_types(HANDLE_TYPE);
+		let create_info = image_create_info::<F>(usage.as_dyn(), dma_buf.extent)
+			.push_next(&mut external_info)
+			.push_next(&mut modifier_info);
+
+		let fd = dma_buf.fd;
+		unsafe {
+			Image::create_external(context, &create_info, usage.as_dyn(), move |image, requirements| {
+				let loader = khr::ExternalMemoryFd::new(raw_instance(&context.instance), raw_device(&context.device));
+				let fd_properties = loader.get_memory_fd_properties(HANDLE_TYPE, fd.as_raw_fd())?;
+				let memory_type = find_memory_type(
+					context,
+					requirements.memory_type_bits & fd_properties.memory_type_bits,
+					vk::MemoryPropertyFlags::empty(),
+				)?;
+				let mut import_info = vk::ImportMemoryFdInfoKHR::builder()
+					.handle_type(HANDLE_TYPE)
+					.fd(fd.as_raw_fd());
+				let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+				let allocate_info = vk::MemoryAllocateInfo::builder()
+					.allocation_size(requirements.size)
+					.memory_type_index(memory_type)
+					.push_next(&mut import_info)
+					.push_next(&mut dedicated_info);
+				let memory = raw_device(&context.device).allocate_memory(&allocate_info, None)?;
+				// A successful import takes ownership of the file descriptor
+				std::mem::forget(fd);
+				Ok(memory)
+			})
+		}
+	}
+
+	/// Creates an image with `usage` laid out with one of `modifiers`, and exports its memory as a
+	/// DMA-BUF. The driver picks the modifier it prefers out of the list, which the returned
+	/// DMA-BUF records.
+	pub fn export_dma_buf(
+		context: &Context,
+		usage: U,
+		extent: vk::Extent2D,
+		modifiers: &[u64],
+	) -> MarsResult<(Self, DmaBuf)> {
+		check_extension(context)?;
+		let mut modifier_info = vk::ImageDrmFormatModifierListCreateInfoEXT::builder().drm_format_modifiers(modifiers);
+		let mut external_info = vk::ExternalMemoryImageCreateInfo::builder().handle_types(HANDLE_TYPE);
+		let create_info = image_create_info::<F>(usage.as_dyn(), extent)
+			.push_next(&mut external_info)
+			.push_next(&mut modifier_info);
+
+		unsafe {
+			let image = Image::create_external(context, &create_info, usage.as_dyn(), |image, requirements| {
+				let memory_type = find_memory_type(
+					context,
+					requirements.memory_type_bits,
+					vk::MemoryPropertyFlags::DEVICE_LOCAL,
+				)?;
+				let mut export_info = vk::ExportMemoryAllocateInfo::builder().handle_types(HANDLE_TYPE);
+				let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+				let allocate_info = vk::MemoryAllocateInfo::builder()
+					.allocation_size(requirements.size)
+					.memory_type_index(memory_type)
+					.push_next(&mut export_info)
+					.push_next(&mut dedicated_info);
+				raw_device(&context.device).allocate_memory(&allocate_info, None)
+			})?;
+
+			let drm_format_modifier = context.image_drm_format_modifier.as_ref().unwrap();
+			let mut properties = vk::ImageDrmFormatModifierPropertiesEXT::default();
+			(drm_format_modifier.get_image_drm_format_modifier_properties_ext)(
+				raw_device(&context.device).handle(),
+				image.raw(),
+				&mut properties,
+			)
+			.result()?;
+			let modifier = properties.drm_format_modifier;
+			let plane_count = supported_modifiers(context, F::as_raw())
+				.into_iter()
+				.find(|supported| supported.modifier == modifier)
+				.map_or(1, |supported| supported.plane_count);
+			let planes = (0..plane_count)
+				.map(|plane| {
+					let layout = image.subresource_layout(memory_plane_aspect(plane));
+					DmaBufPlane {
+						offset: layout.offset,
+						stride: layout.row_pitch,
+					}
+				})
+				.collect();
+			let fd = image.export_memory_fd(context, HANDLE_TYPE)?;
+			let dma_buf = DmaBuf {
+				fd: File::from_raw_fd(fd),
+				extent,
+				modifier,
+				planes,
+			};
+			Ok((image, dma_buf))
+		}
+	}
+}
+
+fn check_extension(context: &Context) -> MarsResult<()> {
+	if context.has_extension(DeviceExtension::ImageDrmFormatModifier) {
+		Ok(())
+	} else {
+		Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+	}
+}
+
+fn image_create_info<'a, F: FormatType>(usage: DynImageUsage, extent: vk::Extent2D) -> vk::ImageCreateInfoBuilder<'a> {
+	vk::ImageCreateInfo::builder()
+		.image_type(vk::ImageType::TYPE_2D)
+		.format(F::as_raw())
+		.extent(vk::Extent3D {
+			width: extent.width,
+			height: extent.height,
+			depth: 1,
+		})
+		.mip_levels(1)
+		.array_layers(1)
+		.samples(vk::SampleCountFlags::TYPE_1)
+		.tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+		.usage(usage.as_raw())
+		.sharing_mode(vk::SharingMode::EXCLUSIVE)
+		.initial_layout(vk::ImageLayout::UNDEFINED)
+}
+
+fn memory_plane_aspect(plane: u32) -> vk::ImageAspectFlags {
+	match plane {
+		0 => vk::ImageAspectFlags::MEMORY_PLANE_0_EXT,
+		1 => vk::ImageAspectFlags::MEMORY_PLANE_1_EXT,
+		2 => vk::ImageAspectFlags::MEMORY_PLANE_2_EXT,
+		_ => vk::ImageAspectFlags::MEMORY_PLANE_3_EXT,
+	}
+}
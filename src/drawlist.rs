@@ -0,0 +1,143 @@
+//! Draw lists, which collect the draws of many functions rendering to the same render pass so they
+//! can be recorded together with as little state changing between them as possible.
+//!
+//! `RenderEngine::pass` binds the descriptor set and the vertex and index buffers of every draw,
+//! even when the previous draw bound the same ones. A `DrawList` instead sorts its draws by
+//! pipeline, then descriptor set, then buffers, and `RenderEngine::pass_list` only binds what
+//! changed from the draw before. Sorting changes the order draws are recorded in, so draw lists
+//! suit opaque geometry, where the depth test makes the order irrelevant, rather than blended
+//! geometry that has to be drawn back to front. Draws that share all their state keep their
+//! relative order.
+
+use std::marker::PhantomData;
+
+use rk::vk::{self, Handle};
+
+use crate::{
+	buffer::IndexType,
+	destruction::{GpuUse, RecordedUses},
+	function::{push_constant_bytes, FixedState, FunctionDef, FunctionPrototype},
+	pass::RenderPassPrototype,
+	raw_descriptor_set, raw_pipeline_layout,
+	render::DrawArgs,
+};
+
+/// Draws of functions rendering with render passes of type `G`, to be recorded by
+/// `RenderEngine::pass_list`
+pub struct DrawList<'a, G: RenderPassPrototype> {
+	pub(crate) draws: Vec<ListedDraw<'a>>,
+	/// The push constants of every draw, which each draw has a range of
+	pub(crate) push_constants: Vec<u8>,
+	sorted: bool,
+	_phantom: PhantomData<G>,
+}
+
+/// The handles of a draw in a list, along with the function drawing it
+#[derive(Copy, Clone)]
+pub(crate) struct ListedDraw<'a> {
+	pub(crate) pipeline: vk::Pipeline,
+	pub(crate) pipeline_layout: vk::PipelineLayout,
+	pub(crate) flip_viewport: bool,
+	pub(crate) fixed_state: FixedState,
+	pub(crate) descriptor_set: Option<vk::DescriptorSet>,
+	pub(crate) vertices: vk::Buffer,
+	pub(crate) indices: vk::Buffer,
+	pub(crate) index_type: vk::IndexType,
+	pub(crate) index_count: u32,
+	/// The stages reading the function's push constants, empty if it has none
+	pub(crate) push_constant_stages: vk::ShaderStageFlags,
+	/// Where the draw's push constants are in the list's push constants
+	pub(crate) push_constants: (usize, usize),
+	argument_uses: &'a [GpuUse],
+	vertices_use: &'a GpuUse,
+	indices_use: &'a GpuUse,
+}
+
+impl<'a> ListedDraw<'a> {
+	/// Marks the buffers the draw uses as used by the submission being recorded
+	pub(crate) fn mark_used(&self, recorded: &RecordedUses) {
+		for gpu_use in self.argument_uses {
+			gpu_use.mark(recorded);
+		}
+		self.vertices_use.mark(recorded);
+		self.indices_use.mark(recorded);
+	}
+
+	fn sort_key(&self) -> (u64, u64, u64, u64) {
+		(
+			self.pipeline.as_raw(),
+			self.descriptor_set.map_or(0, |descriptor_set| descriptor_set.as_raw()),
+			self.vertices.as_raw(),
+			self.indices.as_raw(),
+		)
+	}
+}
+
+impl<'a, G: RenderPassPrototype> DrawList<'a, G> {
+	pub fn new() -> Self {
+		Self {
+			draws: Vec::new(),
+			push_constants: Vec::new(),
+			sorted: true,
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Adds a draw with `function` to the list
+	pub fn push<F, X>(&mut self, function: &'a FunctionDef<F>, draw: DrawArgs<'a, F, X>)
+	where
+		F: FunctionPrototype<RenderPass = G> + 'a,
+		X: IndexType,
+	{
+		let push_constants = push_constant_bytes(&draw.push_constants);
+		let offset = self.push_constants.len();
+		self.push_constants.extend_from_slice(push_constants);
+		self.draws.push(ListedDraw {
+			pipeline: function.pipeline.pipeline,
+			pipeline_layout: function.pipeline_layout.layout,
+			flip_viewport: function.flip_viewport,
+			fixed_state: function.fixed_state,
+			descriptor_set: draw.bindings.descriptor_set.as_ref().map(raw_descriptor_set),
+			vertices: draw.vertices.raw(),
+			indices: draw.indices.raw(),
+			index_type: X::as_raw(),
+			index_count: draw.indices.len as u32,
+			push_constant_stages: function.push_constant_stages,
+			push_constants: (offset, push_constants.len()),
+			argument_uses: &draw.bindings.gpu_uses,
+			vertices_use: &draw.vertices.gpu_use,
+			indices_use: &draw.indices.gpu_use,
+		});
+		self.sorted = false;
+	}
+
+	/// Sorts the draws so that draws sharing state are next to each other. Recording the list sorts
+	/// it anyway, so this is only useful to do ahead of time, like on another thread.
+	pub fn sort(&mut self) {
+		if !self.sorted {
+			self.draws.sort_by_key(ListedDraw::sort_key);
+			self.sorted = true;
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.draws.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.draws.is_empty()
+	}
+
+	/// Removes every draw, keeping the allocation for the next frame's draws
+	pub fn clear(&mut self) {
+		self.draws.clear();
+		self.push_constants.clear();
+		self.sorted = true;
+	}
+}
+
+impl<'a, G: RenderPassPrototype> Default for DrawList<'a, G> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
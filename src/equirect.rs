@@ -0,0 +1,170 @@
+//! Converting equirectangular environment maps, the layout HDR environments are usually
+//! distributed in, to cubemaps.
+//!
+//! The equirectangular image spans longitude from -180° at its left edge to 180° at its right and
+//! latitude from 90° at its top to -90° at its bottom, with +Y up and its center facing +X.
+
+use std::sync::Arc;
+
+use rk::vk;
+
+use crate::{
+	compute::{ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	cubemap::{Cubemap, CUBE_DIRECTION_GLSL},
+	function::{
+		compile_shader, Argument, Binding, BindingDesc, BindingType, StorageImageViews, WriteArgument,
+		WriteSampledImageArgument,
+	},
+	image::{FormatType, SampledImage, SamplerHandle},
+	render::RenderEngine,
+	shader::ShaderStage,
+	tonemap::HdrFormat,
+	Context, MarsResult,
+};
+
+// `CUBE_DIRECTION` is replaced with `CUBE_DIRECTION_GLSL`
+const EQUIRECT_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform sampler2D equirect;
+layout(set = 0, binding = 1, rgba16f) uniform writeonly image2DArray cubemap;
+
+const float PI = 3.14159265359;
+
+CUBE_DIRECTION
+
+vec2 equirect_uv(vec3 direction) {
+	return vec2(
+		atan(direction.z, direction.x) / (2.0 * PI) + 0.5,
+		acos(clamp(direction.y, -1.0, 1.0)) / PI
+	);
+}
+
+void main() {
+	ivec2 size = imageSize(cubemap).xy;
+	ivec3 texel = ivec3(gl_GlobalInvocationID);
+	if (texel.x >= size.x || texel.y >= size.y) {
+		return;
+	}
+	// Each texel averages a grid of samples, so the smaller texels of the equirectangular image
+	// don't alias at the small mip levels
+	float texels_across = float(textureSize(equirect, 0).x) / (4.0 * float(size.x));
+	int grid = clamp(int(ceil(texels_across)), 1, 8);
+	vec3 sum = vec3(0.0);
+	for (int y = 0; y < grid; y++) {
+		for (int x = 0; x < grid; x++) {
+			vec2 offset = (vec2(x, y) + 0.5) / float(grid);
+			vec3 direction = cube_direction(uint(texel.z), (vec2(texel.xy) + offset) / vec2(size));
+			sum += textureLod(equirect, equirect_uv(direction), 0.0).rgb;
+		}
+	}
+	imageStore(cubemap, texel, vec4(sum / float(grid * grid), 1.0));
+}
+"#;
+
+struct EquirectFunction;
+
+impl ComputeFunctionPrototype for EquirectFunction {
+	type Bindings = (SampledEquirect, CubemapFaces);
+}
+
+/// The equirectangular image, borrowed from a `SampledImage` of any format
+struct SampledEquirect;
+
+unsafe impl Binding for SampledEquirect {
+	type Argument = EquirectArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+struct EquirectArgument {
+	sampler: Arc<SamplerHandle>,
+	image_view: vk::ImageView,
+	image_layout: vk::ImageLayout,
+}
+
+impl Argument for EquirectArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: self.image_layout,
+		})
+	}
+}
+
+/// The faces of the mip level being written
+struct CubemapFaces;
+
+unsafe impl Binding for CubemapFaces {
+	type Argument = StorageImageViews;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageImage,
+			count: 1,
+		}
+	}
+}
+
+/// Converts equirectangular images to cubemaps. See the module documentation for the layout
+/// expected.
+pub struct EquirectConverter {
+	function: ComputeFunctionDef<EquirectFunction>,
+}
+
+impl EquirectConverter {
+	pub fn create(context: &Context) -> MarsResult<Self> {
+		let shader = EQUIRECT_SHADER.replace("CUBE_DIRECTION", CUBE_DIRECTION_GLSL);
+		let shader = compile_shader(&shader, "equirect.comp", ShaderStage::Compute)?;
+		Ok(Self {
+			function: ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(shader) })?,
+		})
+	}
+
+	/// Renders `equirect` into a new cubemap with faces of `size` texels and `mip_levels` mip
+	/// levels, each of which is rendered from `equirect` directly, ready for baking with an
+	/// `IblBaker` or sampling as a skybox. A face size of a quarter of the width of `equirect`
+	/// keeps about all of its detail.
+	///
+	/// Must not be called inside a batch, as `equirect` is only borrowed for the conversion.
+	pub fn convert<F: FormatType>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		equirect: &SampledImage<F>,
+		size: u32,
+		mip_levels: u32,
+	) -> MarsResult<Cubemap<HdrFormat>> {
+		let cubemap = Cubemap::create(context, size, mip_levels)?;
+		for mip_level in 0..mip_levels {
+			let view = cubemap.face_view(mip_level)?;
+			let arguments = self.function.make_arguments(
+				context,
+				(
+					EquirectArgument {
+						sampler: equirect.sampler.sampler.clone(),
+						image_view: equirect.image_view.image_view.raw,
+						image_layout: equirect.image.layout,
+					},
+					StorageImageViews(vec![view.raw]),
+				),
+			)?;
+			let mip_size = (size >> mip_level).max(1);
+			engine.dispatch(
+				context,
+				&self.function,
+				&arguments,
+				[(mip_size + 7) / 8, (mip_size + 7) / 8, 6],
+			)?;
+		}
+		Ok(cubemap)
+	}
+}
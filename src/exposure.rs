@@ -0,0 +1,312 @@
+//! Automatic exposure, which adapts the brightness of HDR images to their content like an eye or a
+//! camera adjusting to the light.
+//!
+//! `AutoExposure::update` builds a histogram of the logarithm of the luminance of an HDR attachment
+//! in one dispatch, then averages it in another and moves the exposure towards the one that maps
+//! the average to middle grey. The exposure stays in a storage buffer on the device, which
+//! `ToneMapper::apply_with_exposure` reads directly, so nothing has to be read back.
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, StorageBufferUsage, UniformBufferUsage},
+	builtin::{self, CachedArguments},
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	function::{compile_shader, Binding, BindingDesc, BindingType, RawStorage, RawStorageArgument},
+	image::Sampler,
+	pass::ColorAttachment,
+	render::RenderEngine,
+	shader::ShaderStage,
+	tonemap::{HdrFormat, HdrInput, HdrInputArgument},
+	Context, MarsResult,
+};
+
+// Both shaders have an invocation per bin of the histogram in each workgroup, `BIN_COUNT` of them
+const HISTOGRAM_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 16, local_size_y = 16) in;
+
+layout(set = 0, binding = 0) uniform sampler2D hdr;
+layout(set = 0, binding = 1) buffer Histogram {
+	uint bins[256];
+};
+layout(set = 0, binding = 2) uniform Params {
+	float min_log_luminance;
+	float log_luminance_range;
+	float adaptation;
+	float compensation;
+	uint pixel_count;
+};
+
+// Bin 0 holds pixels too dark to count, the rest split the range of log luminance evenly
+uint luminance_bin(vec3 color) {
+	float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+	if (luminance < 0.005) {
+		return 0;
+	}
+	float t = clamp((log2(luminance) - min_log_luminance) / log_luminance_range, 0.0, 1.0);
+	return uint(t * 254.0 + 1.0);
+}
+
+shared uint local_bins[256];
+
+void main() {
+	local_bins[gl_LocalInvocationIndex] = 0;
+	barrier();
+
+	ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+	if (all(lessThan(texel, textureSize(hdr, 0)))) {
+		atomicAdd(local_bins[luminance_bin(texelFetch(hdr, texel, 0).rgb)], 1);
+	}
+	barrier();
+
+	atomicAdd(bins[gl_LocalInvocationIndex], local_bins[gl_LocalInvocationIndex]);
+}
+"#;
+
+const ADAPT_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 256) in;
+
+layout(set = 0, binding = 0) buffer Histogram {
+	uint bins[256];
+};
+layout(set = 0, binding = 1) buffer Exposure {
+	float average_luminance;
+	float exposure;
+};
+layout(set = 0, binding = 2) uniform Params {
+	float min_log_luminance;
+	float log_luminance_range;
+	float adaptation;
+	float compensation;
+	uint pixel_count;
+};
+
+shared uint weighted[256];
+
+void main() {
+	uint bin = gl_LocalInvocationIndex;
+	uint count = bins[bin];
+	weighted[bin] = count * bin;
+	// Cleared for the next frame's histogram
+	bins[bin] = 0;
+	barrier();
+
+	for (uint stride = 128; stride > 0; stride >>= 1) {
+		if (bin < stride) {
+			weighted[bin] += weighted[bin + stride];
+		}
+		barrier();
+	}
+
+	if (bin == 0) {
+		// Bin 0's count is the number of pixels that were too dark to count
+		float lit = max(float(pixel_count) - float(count), 1.0);
+		float average_bin = float(weighted[0]) / lit - 1.0;
+		float average_log = average_bin / 254.0 * log_luminance_range + min_log_luminance;
+		float average = exp2(average_log);
+		// The first frame starts fully adapted
+		float previous = average_luminance > 0.0 ? average_luminance : average;
+		average_luminance = previous + (average - previous) * adaptation;
+		exposure = 0.18 / average_luminance * exp2(compensation);
+	}
+}
+"#;
+
+/// The number of bins of the luminance histogram
+const BIN_COUNT: usize = 256;
+
+/// The exposure and the luminance it's adapted to, laid out as the `Exposure` buffer of the shaders
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct ExposureState {
+	/// The adapted average luminance of the image, or 0 before the first update
+	pub average_luminance: f32,
+	/// The factor HDR colors are multiplied by before tone mapping
+	pub exposure: f32,
+}
+
+impl ExposureState {
+	/// A fixed exposure that leaves colors unchanged
+	pub fn identity() -> Self {
+		Self {
+			average_luminance: 0.0,
+			exposure: 1.0,
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AutoExposureOptions {
+	/// The base 2 logarithm of the lowest luminance the histogram distinguishes
+	pub min_log_luminance: f32,
+	/// The base 2 logarithm of the highest luminance the histogram distinguishes
+	pub max_log_luminance: f32,
+	/// How quickly the exposure adapts. The difference from the exposure being adapted to shrinks
+	/// by a factor of e every `1 / speed` seconds.
+	pub speed: f32,
+	/// Stops of exposure added to the automatic exposure, negative values darkening the image
+	pub compensation: f32,
+}
+
+impl Default for AutoExposureOptions {
+	fn default() -> Self {
+		Self {
+			min_log_luminance: -8.0,
+			max_log_luminance: 4.0,
+			speed: 1.5,
+			compensation: 0.0,
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct ExposureParams {
+	min_log_luminance: f32,
+	log_luminance_range: f32,
+	adaptation: f32,
+	compensation: f32,
+	pixel_count: u32,
+	_padding: [u32; 3],
+}
+
+unsafe impl Binding for ExposureParams {
+	type Argument = Buffer<UniformBufferUsage, ExposureParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+struct HistogramFunction;
+
+impl ComputeFunctionPrototype for HistogramFunction {
+	// The HDR image, the histogram, and the parameters
+	type Bindings = (HdrInput, RawStorage, ExposureParams);
+}
+
+struct AdaptFunction;
+
+impl ComputeFunctionPrototype for AdaptFunction {
+	// The histogram, the exposure, and the parameters
+	type Bindings = (RawStorage, RawStorage, ExposureParams);
+}
+
+/// Adapts an exposure to the brightness of HDR attachments. See the module documentation.
+pub struct AutoExposure {
+	histogram: ComputeFunctionDef<HistogramFunction>,
+	adapt: ComputeFunctionDef<AdaptFunction>,
+	sampler: Sampler,
+	// Keyed by the view of the attachment measured
+	histogram_arguments: CachedArguments<vk::ImageView, ComputeArgumentsContainer<HistogramFunction>>,
+	adapt_arguments: ComputeArgumentsContainer<AdaptFunction>,
+	// The arguments above refer to these buffers by handle, so they're declared after them to be
+	// dropped last
+	bins: Buffer<StorageBufferUsage, [u32]>,
+	exposure: Buffer<StorageBufferUsage, ExposureState>,
+	options: AutoExposureOptions,
+}
+
+impl AutoExposure {
+	pub fn create(context: &Context, options: AutoExposureOptions) -> MarsResult<Self> {
+		let shader = compile_shader(HISTOGRAM_SHADER, "histogram.comp", ShaderStage::Compute)?;
+		let histogram = ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(shader) })?;
+		let shader = compile_shader(ADAPT_SHADER, "adapt_exposure.comp", ShaderStage::Compute)?;
+		let mut adapt = ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(shader) })?;
+
+		let bins = Buffer::make_array_buffer(context, &[0u32; BIN_COUNT])?;
+		let exposure = Buffer::make_item_buffer(context, ExposureState::identity())?;
+		let params = Buffer::make_item_buffer(context, params(&options, 0.0, 0))?;
+		let adapt_arguments = adapt.make_arguments(
+			context,
+			(
+				RawStorageArgument::new(&bins),
+				RawStorageArgument::new(&exposure),
+				params,
+			),
+		)?;
+
+		Ok(Self {
+			histogram,
+			adapt,
+			sampler: Sampler::create(context)?,
+			histogram_arguments: CachedArguments::new(),
+			adapt_arguments,
+			bins,
+			exposure,
+			options,
+		})
+	}
+
+	pub fn options(&self) -> &AutoExposureOptions {
+		&self.options
+	}
+
+	/// Changes the options used from the next `update` on
+	pub fn set_options(&mut self, options: AutoExposureOptions) {
+		self.options = options;
+	}
+
+	/// The buffer holding the current exposure, for tone mapping with or for reading back
+	pub fn exposure(&self) -> &Buffer<StorageBufferUsage, ExposureState> {
+		&self.exposure
+	}
+
+	/// Measures `source` and adapts the exposure towards it for a frame `dt` seconds after the
+	/// last. The source must have been created with the `SAMPLED` usage.
+	pub fn update(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		source: &mut ColorAttachment<HdrFormat>,
+		dt: f32,
+	) -> MarsResult<()> {
+		let extent = source.image.extent();
+		let params = params(&self.options, dt, extent.width * extent.height);
+
+		let image_view = source.view.image_view.raw;
+		let (histogram, sampler, bins) = (&mut self.histogram, &self.sampler, &self.bins);
+		let histogram_arguments = self.histogram_arguments.make_or_update(
+			image_view,
+			|| {
+				let hdr = HdrInputArgument {
+					sampler: sampler.sampler.clone(),
+					image_view,
+				};
+				let params = Buffer::make_item_buffer(context, params)?;
+				histogram.make_arguments(context, (hdr, RawStorageArgument::new(bins), params))
+			},
+			|arguments| arguments.arguments.2.record_upload(context, engine, params),
+		)?;
+		self.adapt_arguments
+			.arguments
+			.2
+			.record_upload(context, engine, params)?;
+
+		let stage = vk::PipelineStageFlags2KHR::COMPUTE_SHADER;
+		builtin::sample_color_attachment(context, engine, &mut source.image, stage, |engine| {
+			let group_count = [(extent.width + 15) / 16, (extent.height + 15) / 16, 1];
+			engine.dispatch(context, histogram, histogram_arguments, group_count)
+		})?;
+		engine.dispatch(context, &self.adapt, &self.adapt_arguments, [1, 1, 1])
+	}
+}
+
+fn params(options: &AutoExposureOptions, dt: f32, pixel_count: u32) -> ExposureParams {
+	ExposureParams {
+		min_log_luminance: options.min_log_luminance,
+		log_luminance_range: options.max_log_luminance - options.min_log_luminance,
+		// Exponential decay of the difference, so adapting doesn't depend on the frame rate
+		adaptation: 1.0 - (-dt * options.speed).exp(),
+		compensation: options.compensation,
+		pixel_count,
+		_padding: [0; 3],
+	}
+}
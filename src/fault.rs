@@ -0,0 +1,230 @@
+//! Diagnostics for when the device is lost, which otherwise leaves nothing to go on after a GPU
+//! crash or hang.
+//!
+//! The passes and dispatches a `RenderEngine` records are labelled with `RenderEngine::set_label`.
+//! With the `DiagnosticCheckpoints` device extension, every label is also written into the command
+//! buffer as a checkpoint, so the device itself can tell which labels it had started and finished
+//! when it was lost. Without it, only the last label recorded on the host is known. With the
+//! `DeviceFault` extension, the driver's description of the fault and the addresses involved in it
+//! are reported too.
+//!
+//! When a submission fails with `ERROR_DEVICE_LOST`, the report is logged as an error and kept for
+//! `Context::device_fault`.
+
+use std::{
+	collections::HashMap,
+	ffi::CStr,
+	fmt,
+	os::raw::{c_char, c_void},
+	ptr,
+	sync::Mutex,
+};
+
+use rk::vk;
+
+use crate::{raw_device, Context};
+
+/// The label of commands recorded without one
+pub(crate) const UNLABELLED: &str = "(unlabelled)";
+
+/// What is known about why and where the device was lost
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFaultReport {
+	/// The label last recorded on the host before the device was lost
+	pub last_recorded: Option<String>,
+	/// The last label the device started executing, if checkpoints were recorded
+	pub last_started: Option<String>,
+	/// The last label the device finished executing, if checkpoints were recorded
+	pub last_finished: Option<String>,
+	/// The driver's description of the fault
+	pub description: Option<String>,
+	/// The addresses the fault happened at or near
+	pub addresses: Vec<FaultAddress>,
+	/// Vendor specific details of the fault
+	pub vendor_faults: Vec<VendorFault>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FaultAddress {
+	/// What the device was doing with the address, like reading from or executing it
+	pub address_type: vk::DeviceFaultAddressTypeEXT,
+	pub address: vk::DeviceAddress,
+	/// The address is only known to within this many bytes, a power of two
+	pub precision: vk::DeviceSize,
+}
+
+#[derive(Debug, Clone)]
+pub struct VendorFault {
+	pub description: String,
+	pub code: u64,
+	pub data: u64,
+}
+
+impl fmt::Display for DeviceFaultReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let label = |label: &Option<String>| label.clone().unwrap_or_else(|| String::from("unknown"));
+		write!(f, "device lost after recording {}", label(&self.last_recorded))?;
+		if self.last_started.is_some() || self.last_finished.is_some() {
+			write!(
+				f,
+				", last started {} and last finished {}",
+				label(&self.last_started),
+				label(&self.last_finished)
+			)?;
+		}
+		if let Some(description) = &self.description {
+			write!(f, ": {}", description)?;
+		}
+		for address in &self.addresses {
+			write!(
+				f,
+				"\n\t{:?} at {:#x} (+/- {:#x})",
+				address.address_type, address.address, address.precision
+			)?;
+		}
+		for vendor_fault in &self.vendor_faults {
+			write!(
+				f,
+				"\n\t{} (code {:#x}, data {:#x})",
+				vendor_fault.description, vendor_fault.code, vendor_fault.data
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// The labels recorded so far, shared by every engine of a context
+pub(crate) struct Breadcrumbs {
+	state: Mutex<BreadcrumbState>,
+}
+
+#[derive(Default)]
+struct BreadcrumbState {
+	/// Every label recorded, which checkpoint markers index into
+	labels: Vec<String>,
+	indices: HashMap<String, usize>,
+	last_recorded: Option<usize>,
+	fault: Option<DeviceFaultReport>,
+}
+
+impl Breadcrumbs {
+	pub(crate) fn new() -> Self {
+		Self {
+			state: Mutex::new(BreadcrumbState::default()),
+		}
+	}
+
+	/// Marks the commands recorded next into `command_buffer` as belonging to `label`
+	pub(crate) unsafe fn record(&self, context: &Context, command_buffer: vk::CommandBuffer, label: Option<&str>) {
+		let label = label.unwrap_or(UNLABELLED);
+		let mut state = self.state.lock().unwrap();
+		let index = match state.indices.get(label) {
+			Some(&index) => index,
+			None => {
+				let index = state.labels.len();
+				state.labels.push(String::from(label));
+				state.indices.insert(String::from(label), index);
+				index
+			}
+		};
+		state.last_recorded = Some(index);
+		drop(state);
+		if let Some(checkpoints) = &context.diagnostic_checkpoints {
+			// Markers are opaque to the device, so they hold the index of the label offset by one to
+			// never be null
+			(checkpoints.cmd_set_checkpoint_nv)(command_buffer, (index + 1) as *const c_void);
+		}
+	}
+
+	pub(crate) fn fault(&self) -> Option<DeviceFaultReport> {
+		self.state.lock().unwrap().fault.clone()
+	}
+}
+
+/// Gathers everything known about the device being lost while running a submission on `queue`,
+/// logs it and keeps it for `Context::device_fault`
+pub(crate) fn report_device_lost(context: &Context, queue: vk::Queue) {
+	let mut report = DeviceFaultReport::default();
+	{
+		let state = context.breadcrumbs.state.lock().unwrap();
+		let label = |index: usize| state.labels.get(index).cloned();
+		report.last_recorded = state.last_recorded.and_then(label);
+		if context.diagnostic_checkpoints.is_some() {
+			for checkpoint in unsafe { queue_checkpoints(context, queue) } {
+				let index = (checkpoint.p_checkpoint_marker as usize).checked_sub(1);
+				let label = index.and_then(label);
+				if checkpoint.stage.contains(vk::PipelineStageFlags::TOP_OF_PIPE) {
+					report.last_started = label;
+				} else if checkpoint.stage.contains(vk::PipelineStageFlags::BOTTOM_OF_PIPE) {
+					report.last_finished = label;
+				}
+			}
+		}
+	}
+	if context.device_fault.is_some() {
+		if let Err(e) = unsafe { query_fault_info(context, &mut report) } {
+			log::warn!("Failed to query why the device was lost: {}", e);
+		}
+	}
+
+	log::error!("{}", report);
+	context.breadcrumbs.state.lock().unwrap().fault = Some(report);
+}
+
+/// The last checkpoints the device reached in each pipeline stage on `queue`
+unsafe fn queue_checkpoints(context: &Context, queue: vk::Queue) -> Vec<vk::CheckpointDataNV> {
+	let checkpoints = context.diagnostic_checkpoints.as_ref().unwrap();
+	let mut count = 0;
+	(checkpoints.get_queue_checkpoint_data_nv)(queue, &mut count, ptr::null_mut());
+	let mut data = vec![vk::CheckpointDataNV::default(); count as usize];
+	(checkpoints.get_queue_checkpoint_data_nv)(queue, &mut count, data.as_mut_ptr());
+	data.truncate(count as usize);
+	data
+}
+
+unsafe fn query_fault_info(context: &Context, report: &mut DeviceFaultReport) -> Result<(), vk::Result> {
+	let device_fault = context.device_fault.as_ref().unwrap();
+	let device = raw_device(&context.device).handle();
+
+	// The first query gets the number of addresses and vendor faults, and the second fills them in
+	let mut counts = vk::DeviceFaultCountsEXT::default();
+	(device_fault.get_device_fault_info_ext)(device, &mut counts, ptr::null_mut()).result()?;
+	let mut addresses = vec![vk::DeviceFaultAddressInfoEXT::default(); counts.address_info_count as usize];
+	let mut vendor_faults = vec![vk::DeviceFaultVendorInfoEXT::default(); counts.vendor_info_count as usize];
+	// Vendor binary data is only useful to the vendor's tools, so it isn't retrieved
+	counts.vendor_binary_size = 0;
+	let mut info = vk::DeviceFaultInfoEXT {
+		p_address_infos: addresses.as_mut_ptr(),
+		p_vendor_infos: vendor_faults.as_mut_ptr(),
+		..Default::default()
+	};
+	let result = (device_fault.get_device_fault_info_ext)(device, &mut counts, &mut info);
+	if result != vk::Result::INCOMPLETE {
+		result.result()?;
+	}
+	addresses.truncate(counts.address_info_count as usize);
+	vendor_faults.truncate(counts.vendor_info_count as usize);
+
+	report.description = Some(c_string(&info.description));
+	report.addresses = addresses
+		.into_iter()
+		.map(|address| FaultAddress {
+			address_type: address.address_type,
+			address: address.reported_address,
+			precision: address.address_precision,
+		})
+		.collect();
+	report.vendor_faults = vendor_faults
+		.into_iter()
+		.map(|vendor_fault| VendorFault {
+			description: c_string(&vendor_fault.description),
+			code: vendor_fault.vendor_fault_code,
+			data: vendor_fault.vendor_fault_data,
+		})
+		.collect();
+	Ok(())
+}
+
+fn c_string(chars: &[c_char]) -> String {
+	unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned()
+}
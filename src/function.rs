@@ -1,30 +1,64 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{
+	marker::PhantomData,
+	mem::{self, ManuallyDrop},
+	ops::{Deref, DerefMut},
+	os::raw::c_void,
+	sync::Arc,
+};
 
 use rk::{
+	ash,
 	descriptor::{DescriptorPool, DescriptorSet},
-	device::Device,
-	pass::RenderPass as RkRenderPass,
-	pipe::{DescriptorSetLayout, Pipeline, PipelineLayout},
-	shader::ShaderModule,
-	vk,
+	instance::Instance,
+	pipe::DescriptorSetLayout,
+	vk, PhysicalDevice,
 };
 
 use crate::{
-	buffer::{Buffer, UniformBufferUsage, UntypedBuffer},
-	image::{FormatType, SampleCountType, SampledImage},
-	pass::{ColorAttachments, RenderPass, RenderPassPrototype},
+	buffer::{Buffer, BufferUsageType, IndirectBufferUsage, StorageBufferUsage, UniformBufferUsage, UntypedBuffer},
+	destruction::{DestructionQueue, GpuUse, RecordedUses},
+	device::DeviceExtension,
+	image::{FormatType, SampleCountType, SampledImage, SampledImageArray, Sampler, SamplerHandle, Texture},
+	math::Mvp,
+	pass::{depth_aspect, ColorAttachments, DepthAttachmentType, RenderPass, RenderPassPrototype},
+	pipeline::{GraphicsPipeline, GraphicsPipelineDesc, PipelineLayout},
+	raw_descriptor_set_layout, raw_instance, raw_physical_device,
+	shader::{self, ShaderError, ShaderStage},
+	validation::{validate_arguments, UniformBlockSizes},
 	Context, MarsResult,
 };
 
+pub use mars_derive::{Bindings, Parameter};
+
 pub trait FunctionPrototype {
 	type RenderPass: RenderPassPrototype;
 	type VertexInput: Parameter;
 	type Bindings: Bindings;
+	/// Small data given with each draw instead of through a binding, like a transform or a material
+	/// index, read in the function's shaders as a `layout(push_constant)` block laid out like the
+	/// type. `()` for functions without push constants. Must be a multiple of 4 bytes and fit in the
+	/// device's `maxPushConstantsSize`, which is at least 128 bytes on every device. Indirect draws
+	/// push the same constants for every command they read.
+	type PushConstants: PushConstantData;
 }
 
+/// A type whose bytes can be given to the shaders as push constants. Implemented for numbers, the
+/// math types and arrays of them, and implementable for `#[repr(C)]` structs of them. Implementors
+/// must have no padding or other uninitialized bytes, since every byte of them is read.
+pub unsafe trait PushConstantData: Copy {}
+
+unsafe impl PushConstantData for () {}
+unsafe impl PushConstantData for u32 {}
+unsafe impl PushConstantData for i32 {}
+unsafe impl PushConstantData for f32 {}
+unsafe impl<T: PushConstantData, const N: usize> PushConstantData for [T; N] {}
+unsafe impl PushConstantData for Mvp {}
+
 pub struct FunctionImpl<F: FunctionPrototype> {
 	pub(crate) vert: Vec<u32>,
-	pub(crate) frag: Vec<u32>,
+	pub(crate) geom: Option<Vec<u32>>,
+	/// `None` for functions that only write depth or discard their primitives
+	pub(crate) frag: Option<Vec<u32>>,
 	pub(crate) _phantom: PhantomData<F>,
 }
 
@@ -35,18 +69,69 @@ where
 	pub unsafe fn from_raw(vert: Vec<u32>, frag: Vec<u32>) -> Self {
 		Self {
 			vert,
-			frag,
+			geom: None,
+			frag: Some(frag),
+			_phantom: PhantomData,
+		}
+	}
+
+	/// A function with only a vertex shader, for render passes without color attachments like a
+	/// depth pre-pass or a shadow map, or for functions with `rasterizer_discard` whose vertex shader
+	/// writes its results to storage buffers (which requires the `vertex_pipeline_stores_and_atomics`
+	/// device feature). Functions without a fragment shader can't be used with render passes that
+	/// have color attachments unless their primitives are discarded.
+	pub unsafe fn from_raw_vertex_only(vert: Vec<u32>) -> Self {
+		Self {
+			vert,
+			geom: None,
+			frag: None,
+			_phantom: PhantomData,
+		}
+	}
+
+	/// Like `from_raw`, with a geometry shader between the vertex and fragment shaders, such as one
+	/// that writes `gl_Layer` to route each primitive to a layer of a layered render pass. Requires
+	/// the `geometry_shader` device feature.
+	pub unsafe fn from_raw_with_geometry(vert: Vec<u32>, geom: Vec<u32>, frag: Vec<u32>) -> Self {
+		Self {
+			vert,
+			geom: Some(geom),
+			frag: Some(frag),
 			_phantom: PhantomData,
 		}
 	}
 }
 
 pub struct FunctionDef<F: FunctionPrototype> {
-	pub(crate) descriptor_pool: DescriptorPool,
-	pub(crate) descriptor_set_layout: DescriptorSetLayout,
-	pub(crate) pipeline: Pipeline,
-	pub(crate) pipeline_layout: PipelineLayout,
-	_phantom: PhantomData<F>,
+	/// Functions without bindings have nothing to allocate descriptor sets for, so they have no pool
+	/// or descriptor set layout
+	pub(crate) descriptor_pool: ManuallyDrop<Option<DescriptorPool>>,
+	pub(crate) descriptor_set_layout: ManuallyDrop<Option<DescriptorSetLayout>>,
+	pub(crate) pipeline: ManuallyDrop<GraphicsPipeline>,
+	pub(crate) pipeline_layout: ManuallyDrop<PipelineLayout>,
+	destruction: DestructionQueue,
+	pub(crate) depth_test: DepthTest,
+	pub(crate) flip_viewport: bool,
+	pub(crate) fixed_state: FixedState,
+	/// The stages reading the push constants, empty if the function has none
+	pub(crate) push_constant_stages: vk::ShaderStageFlags,
+	uniform_sizes: UniformBlockSizes,
+	/// Kept to create variants of the function from
+	function_impl: FunctionImpl<F>,
+}
+
+impl<F: FunctionPrototype> Drop for FunctionDef<F> {
+	fn drop(&mut self) {
+		let resources = unsafe {
+			(
+				ManuallyDrop::take(&mut self.descriptor_pool),
+				ManuallyDrop::take(&mut self.descriptor_set_layout),
+				ManuallyDrop::take(&mut self.pipeline),
+				ManuallyDrop::take(&mut self.pipeline_layout),
+			)
+		};
+		self.destruction.destroy(resources);
+	}
 }
 
 impl<F> FunctionDef<F>
@@ -58,33 +143,95 @@ where
 		render_pass: &RenderPass<F::RenderPass>,
 		function_impl: FunctionImpl<F>,
 	) -> MarsResult<Self> {
+		Self::create_with_options(context, render_pass, function_impl, &FunctionOptions::default())
+	}
+
+	pub fn create_with_options(
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		function_impl: FunctionImpl<F>,
+		options: &FunctionOptions,
+	) -> MarsResult<Self> {
+		Self::create_derived(context, render_pass, function_impl, options, None)
+	}
+
+	/// Creates another function with the same shaders but different options. The variant's pipeline
+	/// is derived from this function's pipeline, which lets drivers create it faster than a new
+	/// function when loading many similar functions.
+	pub fn create_variant(
+		&self,
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		options: &FunctionOptions,
+	) -> MarsResult<Self> {
+		let function_impl = FunctionImpl {
+			vert: self.function_impl.vert.clone(),
+			geom: self.function_impl.geom.clone(),
+			frag: self.function_impl.frag.clone(),
+			_phantom: PhantomData,
+		};
+		Self::create_derived(context, render_pass, function_impl, options, Some(&*self.pipeline))
+	}
+
+	fn create_derived(
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		function_impl: FunctionImpl<F>,
+		options: &FunctionOptions,
+		base: Option<&GraphicsPipeline>,
+	) -> MarsResult<Self> {
+		check_options::<F::RenderPass>(context, options)?;
+
 		//let parameters = F::VertexInputs::parameters(); // TODO: multiple vertex bindings
-		let parameters = vec![ParameterDesc {
-			attributes: F::VertexInput::attributes(),
-		}];
+		let parameters = vec![ParameterDesc::of::<F::VertexInput>()];
 		let (vertex_bindings, vertex_attributes) = parameter_descs_to_raw(&parameters);
 		let bindings = F::Bindings::descriptions();
-		let descriptor_pool = create_descriptor_pool(&context.device, &bindings)?;
-		let descriptor_bindings = bindings_descs_to_raw(&bindings);
-		let color_blend_states = create_blend_states::<F::RenderPass>();
-		let multisample_state = create_multisample_state::<F::RenderPass>();
-		let (pipeline, pipeline_layout, descriptor_set_layout) = create_pipeline(
-			&context.device,
-			&render_pass.render_pass,
-			vertex_bindings,
-			vertex_attributes,
+		let descriptor_pool = create_function_descriptor_pool(context, &bindings)?;
+		let mut stages = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+		let mut shaders = vec![(vk::ShaderStageFlags::VERTEX, function_impl.vert.as_slice())];
+		if let Some(geom) = &function_impl.geom {
+			stages |= vk::ShaderStageFlags::GEOMETRY;
+			shaders.push((vk::ShaderStageFlags::GEOMETRY, geom.as_slice()));
+		}
+		if let Some(frag) = &function_impl.frag {
+			shaders.push((vk::ShaderStageFlags::FRAGMENT, frag.as_slice()));
+		} else {
+			assert!(
+				options.rasterizer_discard || !has_color_attachments::<F::RenderPass>(),
+				"functions rendering to color attachments need a fragment shader"
+			);
+		}
+		let descriptor_bindings = bindings_descs_to_raw(&bindings, stages);
+		let push_constant_ranges = push_constant_ranges::<F::PushConstants>(context, stages)?;
+		let push_constant_stages = push_constant_stages(&push_constant_ranges);
+		let modules = shaders.iter().map(|(_, code)| *code).collect::<Vec<_>>();
+		let uniform_sizes = UniformBlockSizes::reflect(&modules);
+		let (pipeline, pipeline_layout, descriptor_set_layout) = create_pipeline::<F::RenderPass>(
+			context,
+			render_pass,
+			shaders,
+			Some((&vertex_bindings, &vertex_attributes)),
 			descriptor_bindings,
-			&color_blend_states,
-			&multisample_state,
-			&function_impl.vert,
-			&function_impl.frag,
+			&push_constant_ranges,
+			options,
+			base,
 		)?;
 		Ok(Self {
-			descriptor_pool,
-			descriptor_set_layout,
-			pipeline,
-			pipeline_layout,
-			_phantom: PhantomData,
+			descriptor_pool: ManuallyDrop::new(descriptor_pool),
+			descriptor_set_layout: ManuallyDrop::new(descriptor_set_layout),
+			pipeline: ManuallyDrop::new(pipeline),
+			pipeline_layout: ManuallyDrop::new(pipeline_layout),
+			destruction: context.destruction.clone(),
+			depth_test: options.depth_test,
+			flip_viewport: options.flip_viewport,
+			fixed_state: FixedState {
+				topology: Some(options.topology.into()),
+				depth_compare_op: options.depth_test.compare_op(),
+				stencil_reference: options.stencil_test.map_or(0, |stencil| stencil.reference),
+			},
+			push_constant_stages,
+			uniform_sizes,
+			function_impl,
 		})
 	}
 
@@ -93,45 +240,273 @@ where
 		context: &Context,
 		arguments: <F::Bindings as Bindings>::Arguments,
 	) -> MarsResult<ArgumentsContainer<F>> {
-		let descriptor_set = context
-			.device
-			.allocate_descriptor_set(&self.descriptor_pool, &self.descriptor_set_layout)?;
-		let writes = arguments.as_writes();
-		let (raw_writes, _backing) = writes_to_raw(***descriptor_set, &writes);
-		unsafe { context.device.write_descriptor_set(&raw_writes)? };
+		let (descriptor_set, gpu_uses) = write_function_arguments(
+			context,
+			self.descriptor_pool.as_ref(),
+			self.descriptor_set_layout.as_ref(),
+			&self.uniform_sizes,
+			&arguments,
+		)?;
 		Ok(ArgumentsContainer {
 			arguments,
 			descriptor_set,
+			gpu_uses,
 		})
 	}
 }
 
 pub struct ArgumentsContainer<F: FunctionPrototype> {
 	pub arguments: <F::Bindings as Bindings>::Arguments,
-	pub(crate) descriptor_set: DescriptorSet,
+	/// `None` for functions without bindings, which don't bind a descriptor set
+	pub(crate) descriptor_set: Option<DescriptorSet>,
+	/// The buffers the arguments bind, marked as used whenever the arguments are
+	pub(crate) gpu_uses: Vec<GpuUse>,
+}
+
+impl<F> ArgumentsContainer<F>
+where
+	F: FunctionPrototype<Bindings = ()>,
+{
+	/// The arguments of a function without bindings, which can be made without the function since
+	/// there's no descriptor set to allocate
+	pub fn empty() -> Self {
+		Self {
+			arguments: (),
+			descriptor_set: None,
+			gpu_uses: Vec::new(),
+		}
+	}
+}
+
+/// Gives access to the arguments by name when the bindings are derived, like `arguments.mvp`
+impl<F: FunctionPrototype> Deref for ArgumentsContainer<F> {
+	type Target = <F::Bindings as Bindings>::Arguments;
+
+	fn deref(&self) -> &Self::Target {
+		&self.arguments
+	}
 }
 
-/* fn compile_shader(source: &str, filename: &str, kind: shaderc::ShaderKind) -> Vec<u32> {
-	let mut compiler = shaderc::Compiler::new().expect("Failed to initialize compiler");
-	let artifact = compiler.compile_into_spirv(source, kind, filename, "main", None)
-		.expect("Failed to compile shader");
-	artifact.as_binary().to_owned()
-} */
+impl<F: FunctionPrototype> DerefMut for ArgumentsContainer<F> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.arguments
+	}
+}
+
+impl<F: FunctionPrototype> ArgumentsContainer<F> {
+	pub(crate) fn mark_used(&self, recorded: &RecordedUses) {
+		for gpu_use in &self.gpu_uses {
+			gpu_use.mark(recorded);
+		}
+	}
+}
 
-fn create_shader_module(device: &Device, spirv: &[u32]) -> ShaderModule {
-	device
-		.create_shader_module_from_spirv(spirv)
-		.expect("Failed to create shader_module")
+/// A function whose geometry is generated by task and mesh shaders instead of read from vertex
+/// buffers. Requires the `MeshShader` device extension.
+pub trait MeshFunctionPrototype {
+	type RenderPass: RenderPassPrototype;
+	type Bindings: Bindings;
+	/// Like `FunctionPrototype::PushConstants`, read by the task, mesh and fragment shaders
+	type PushConstants: PushConstantData;
+}
+
+pub struct MeshFunctionImpl<F: MeshFunctionPrototype> {
+	pub(crate) task: Option<Vec<u32>>,
+	pub(crate) mesh: Vec<u32>,
+	pub(crate) frag: Vec<u32>,
+	pub(crate) _phantom: PhantomData<F>,
 }
 
-fn create_descriptor_pool(device: &Device, binding_descs: &[BindingDesc]) -> MarsResult<DescriptorPool> {
+impl<F> MeshFunctionImpl<F>
+where
+	F: MeshFunctionPrototype,
+{
+	/// The task shader is optional, without one the mesh shader is dispatched directly by the draw
+	pub unsafe fn from_raw(task: Option<Vec<u32>>, mesh: Vec<u32>, frag: Vec<u32>) -> Self {
+		Self {
+			task,
+			mesh,
+			frag,
+			_phantom: PhantomData,
+		}
+	}
+}
+
+pub struct MeshFunctionDef<F: MeshFunctionPrototype> {
+	/// `None` for functions without bindings, like for `FunctionDef`
+	pub(crate) descriptor_pool: ManuallyDrop<Option<DescriptorPool>>,
+	pub(crate) descriptor_set_layout: ManuallyDrop<Option<DescriptorSetLayout>>,
+	pub(crate) pipeline: ManuallyDrop<GraphicsPipeline>,
+	pub(crate) pipeline_layout: ManuallyDrop<PipelineLayout>,
+	destruction: DestructionQueue,
+	pub(crate) flip_viewport: bool,
+	pub(crate) fixed_state: FixedState,
+	/// The stages reading the push constants, empty if the function has none
+	pub(crate) push_constant_stages: vk::ShaderStageFlags,
+	uniform_sizes: UniformBlockSizes,
+	_phantom: PhantomData<F>,
+}
+
+impl<F: MeshFunctionPrototype> Drop for MeshFunctionDef<F> {
+	fn drop(&mut self) {
+		let resources = unsafe {
+			(
+				ManuallyDrop::take(&mut self.descriptor_pool),
+				ManuallyDrop::take(&mut self.descriptor_set_layout),
+				ManuallyDrop::take(&mut self.pipeline),
+				ManuallyDrop::take(&mut self.pipeline_layout),
+			)
+		};
+		self.destruction.destroy(resources);
+	}
+}
+
+impl<F> MeshFunctionDef<F>
+where
+	F: MeshFunctionPrototype,
+{
+	pub fn create(
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		function_impl: MeshFunctionImpl<F>,
+	) -> MarsResult<Self> {
+		Self::create_with_options(context, render_pass, function_impl, &FunctionOptions::default())
+	}
+
+	pub fn create_with_options(
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		function_impl: MeshFunctionImpl<F>,
+		options: &FunctionOptions,
+	) -> MarsResult<Self> {
+		if context.mesh_shader.is_none() {
+			return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+		}
+		check_options::<F::RenderPass>(context, options)?;
+
+		let bindings = F::Bindings::descriptions();
+		let descriptor_pool = create_function_descriptor_pool(context, &bindings)?;
+		let stages = vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT | vk::ShaderStageFlags::FRAGMENT;
+		let descriptor_bindings = bindings_descs_to_raw(&bindings, stages);
+		let push_constant_ranges = push_constant_ranges::<F::PushConstants>(context, stages)?;
+		let push_constant_stages = push_constant_stages(&push_constant_ranges);
+		let mut shaders = Vec::new();
+		if let Some(task) = &function_impl.task {
+			shaders.push((vk::ShaderStageFlags::TASK_EXT, task.as_slice()));
+		}
+		shaders.push((vk::ShaderStageFlags::MESH_EXT, function_impl.mesh.as_slice()));
+		shaders.push((vk::ShaderStageFlags::FRAGMENT, function_impl.frag.as_slice()));
+		let uniform_sizes = UniformBlockSizes::reflect(&shaders.iter().map(|(_, code)| *code).collect::<Vec<_>>());
+		let (pipeline, pipeline_layout, descriptor_set_layout) = create_pipeline::<F::RenderPass>(
+			context,
+			render_pass,
+			shaders,
+			None,
+			descriptor_bindings,
+			&push_constant_ranges,
+			options,
+			None,
+		)?;
+
+		Ok(Self {
+			descriptor_pool: ManuallyDrop::new(descriptor_pool),
+			descriptor_set_layout: ManuallyDrop::new(descriptor_set_layout),
+			pipeline: ManuallyDrop::new(pipeline),
+			pipeline_layout: ManuallyDrop::new(pipeline_layout),
+			destruction: context.destruction.clone(),
+			flip_viewport: options.flip_viewport,
+			fixed_state: FixedState {
+				topology: None,
+				depth_compare_op: options.depth_test.compare_op(),
+				stencil_reference: options.stencil_test.map_or(0, |stencil| stencil.reference),
+			},
+			push_constant_stages,
+			uniform_sizes,
+			_phantom: PhantomData,
+		})
+	}
+
+	pub fn make_arguments(
+		&mut self,
+		context: &Context,
+		arguments: <F::Bindings as Bindings>::Arguments,
+	) -> MarsResult<MeshArgumentsContainer<F>> {
+		let (descriptor_set, gpu_uses) = write_function_arguments(
+			context,
+			self.descriptor_pool.as_ref(),
+			self.descriptor_set_layout.as_ref(),
+			&self.uniform_sizes,
+			&arguments,
+		)?;
+		Ok(MeshArgumentsContainer {
+			arguments,
+			descriptor_set,
+			gpu_uses,
+		})
+	}
+}
+
+pub struct MeshArgumentsContainer<F: MeshFunctionPrototype> {
+	pub arguments: <F::Bindings as Bindings>::Arguments,
+	/// `None` for functions without bindings, which don't bind a descriptor set
+	pub(crate) descriptor_set: Option<DescriptorSet>,
+	/// The buffers the arguments bind, marked as used whenever the arguments are
+	pub(crate) gpu_uses: Vec<GpuUse>,
+}
+
+impl<F> MeshArgumentsContainer<F>
+where
+	F: MeshFunctionPrototype<Bindings = ()>,
+{
+	/// Like `ArgumentsContainer::empty`
+	pub fn empty() -> Self {
+		Self {
+			arguments: (),
+			descriptor_set: None,
+			gpu_uses: Vec::new(),
+		}
+	}
+}
+
+impl<F: MeshFunctionPrototype> MeshArgumentsContainer<F> {
+	pub(crate) fn mark_used(&self, recorded: &RecordedUses) {
+		for gpu_use in &self.gpu_uses {
+			gpu_use.mark(recorded);
+		}
+	}
+}
+
+/// Compiles the GLSL source of one of mars' built-in shaders. The compiler's error is logged, and
+/// reported as `ERROR_FEATURE_NOT_PRESENT` if the backend can't compile the stage at all, or as
+/// `ERROR_INVALID_SHADER_NV` if it couldn't compile the source.
+pub(crate) fn compile_shader(source: &str, filename: &str, stage: ShaderStage) -> MarsResult<Vec<u32>> {
+	shader::compile_glsl(source, filename, stage).map_err(|e| {
+		log::error!("Failed to compile a built-in shader: {}", e);
+		match e {
+			ShaderError::UnsupportedStage(_) => vk::Result::ERROR_FEATURE_NOT_PRESENT,
+			_ => vk::Result::ERROR_INVALID_SHADER_NV,
+		}
+	})
+}
+
+/// Creates a pool with room for `MAX_SETS` sets of `binding_descs`, though fewer sets fit if a
+/// binding is an array so large that it's capped by the device's limit on descriptors of its type
+pub(crate) fn create_descriptor_pool(context: &Context, binding_descs: &[BindingDesc]) -> MarsResult<DescriptorPool> {
 	const MAX_SETS: u32 = 1024;
-	const PER_BINDING: u32 = 128;
+	let limits = unsafe {
+		raw_instance(&context.instance)
+			.get_physical_device_properties(raw_physical_device(&context.physical_device))
+			.limits
+	};
 	let mut pool_sizes = binding_descs
 		.iter()
+		// Pool sizes can't be empty, and empty arrays don't take any descriptors anyway
+		.filter(|b| b.count > 0)
 		.map(|b| vk::DescriptorPoolSize {
 			ty: b.binding_type.into(),
-			descriptor_count: PER_BINDING,
+			descriptor_count: MAX_SETS
+				.saturating_mul(b.count)
+				.min(b.binding_type.max_per_set(&limits).max(b.count)),
 		})
 		.collect::<Vec<_>>();
 	if pool_sizes.is_empty() {
@@ -142,13 +517,47 @@ fn create_descriptor_pool(device: &Device, binding_descs: &[BindingDesc]) -> Mar
 		})
 	}
 
-	let pool = device.create_descriptor_pool(MAX_SETS, &pool_sizes)?;
+	let pool = context.device.create_descriptor_pool(MAX_SETS, &pool_sizes)?;
 	Ok(pool)
 }
 
+/// The descriptor pool of a graphics or mesh function with `bindings`, or `None` if it has none and
+/// never allocates a descriptor set
+fn create_function_descriptor_pool(context: &Context, bindings: &[BindingDesc]) -> MarsResult<Option<DescriptorPool>> {
+	if bindings.is_empty() {
+		Ok(None)
+	} else {
+		create_descriptor_pool(context, bindings).map(Some)
+	}
+}
+
+/// Allocates a descriptor set from the pool of a graphics or mesh function and writes `arguments`
+/// to it, returning the set (`None` for functions without bindings) and the buffers it binds
+fn write_function_arguments<A: Arguments>(
+	context: &Context,
+	descriptor_pool: Option<&DescriptorPool>,
+	descriptor_set_layout: Option<&DescriptorSetLayout>,
+	uniform_sizes: &UniformBlockSizes,
+	arguments: &A,
+) -> MarsResult<(Option<DescriptorSet>, Vec<GpuUse>)> {
+	let (descriptor_pool, descriptor_set_layout) = match (descriptor_pool, descriptor_set_layout) {
+		(Some(descriptor_pool), Some(descriptor_set_layout)) => (descriptor_pool, descriptor_set_layout),
+		_ => return Ok((None, Vec::new())),
+	};
+	let descriptor_set = context
+		.device
+		.allocate_descriptor_set(descriptor_pool, descriptor_set_layout)?;
+	let writes = arguments.as_writes();
+	validate_arguments(uniform_sizes, &writes);
+	let (raw_writes, _backing) = writes_to_raw(***descriptor_set, &writes);
+	unsafe { context.device.write_descriptor_set(&raw_writes)? };
+	let gpu_uses = writes.iter().flat_map(WriteArgument::gpu_uses).collect();
+	Ok((Some(descriptor_set), gpu_uses))
+}
+
 // TODO: make blend states customizable
 fn create_blend_states<G: RenderPassPrototype>() -> Vec<vk::PipelineColorBlendAttachmentState> {
-	let default = vk::PipelineColorBlendAttachmentState::builder()
+	let blended = vk::PipelineColorBlendAttachmentState::builder()
 		.blend_enable(true)
 		.src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
 		.dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
@@ -158,8 +567,72 @@ fn create_blend_states<G: RenderPassPrototype>() -> Vec<vk::PipelineColorBlendAt
 		.alpha_blend_op(vk::BlendOp::ADD)
 		.color_write_mask(vk::ColorComponentFlags::all())
 		.build();
-	let amount = <G::ColorAttachments as ColorAttachments<G::SampleCount>>::desc().len();
-	vec![default; amount]
+	// Integer attachments can't be blended
+	let unblended = vk::PipelineColorBlendAttachmentState::builder()
+		.blend_enable(false)
+		.color_write_mask(vk::ColorComponentFlags::all())
+		.build();
+	<G::ColorAttachments as ColorAttachments<G::SampleCount>>::integer()
+		.into_iter()
+		.map(|integer| if integer { unblended } else { blended })
+		.collect()
+}
+
+/// The push constant range of a function with push constants of type `P`, read by `stages`, or no
+/// range if `P` is empty. Fails with `ERROR_INITIALIZATION_FAILED` if the device can't take push
+/// constants of that size.
+fn push_constant_ranges<P: PushConstantData>(
+	context: &Context,
+	stages: vk::ShaderStageFlags,
+) -> MarsResult<Vec<vk::PushConstantRange>> {
+	let size = mem::size_of::<P>() as u32;
+	if size == 0 {
+		return Ok(Vec::new());
+	}
+	let max_size = unsafe {
+		raw_instance(&context.instance)
+			.get_physical_device_properties(raw_physical_device(&context.physical_device))
+			.limits
+			.max_push_constants_size
+	};
+	if size % 4 != 0 {
+		log::error!("Push constants are {} bytes, which isn't a multiple of 4", size);
+		return Err(vk::Result::ERROR_INITIALIZATION_FAILED);
+	}
+	if size > max_size {
+		log::error!(
+			"Push constants are {} bytes, but the device supports at most {}",
+			size,
+			max_size
+		);
+		return Err(vk::Result::ERROR_INITIALIZATION_FAILED);
+	}
+	Ok(vec![vk::PushConstantRange {
+		stage_flags: stages,
+		offset: 0,
+		size,
+	}])
+}
+
+/// The stages reading the push constants of a function with `ranges`, empty if it has none
+fn push_constant_stages(ranges: &[vk::PushConstantRange]) -> vk::ShaderStageFlags {
+	ranges
+		.first()
+		.map_or(vk::ShaderStageFlags::empty(), |range| range.stage_flags)
+}
+
+/// The bytes of push constants, given to `vkCmdPushConstants`
+pub(crate) fn push_constant_bytes<P: PushConstantData>(push_constants: &P) -> &[u8] {
+	// Push constant data has no uninitialized bytes to read
+	unsafe { std::slice::from_raw_parts(push_constants as *const P as *const u8, mem::size_of::<P>()) }
+}
+
+fn has_color_attachments<G: RenderPassPrototype>() -> bool {
+	!<G::ColorAttachments as ColorAttachments<G::SampleCount>>::desc().is_empty()
+}
+
+fn has_depth_attachment<G: RenderPassPrototype>() -> bool {
+	<G::DepthAttachment as DepthAttachmentType<G::SampleCount>>::desc().is_some()
 }
 
 fn create_multisample_state<G: RenderPassPrototype>() -> vk::PipelineMultisampleStateCreateInfo {
@@ -171,47 +644,529 @@ fn create_multisample_state<G: RenderPassPrototype>() -> vk::PipelineMultisample
 		.build()
 }
 
-fn create_pipeline(
-	device: &Device,
-	render_pass: &RkRenderPass,
-	vertex_binding_descs: Vec<vk::VertexInputBindingDescription>,
-	vertex_attribute_descs: Vec<vk::VertexInputAttributeDescription>,
+fn create_pipeline<G: RenderPassPrototype>(
+	context: &Context,
+	render_pass: &RenderPass<G>,
+	shaders: Vec<(vk::ShaderStageFlags, &[u32])>,
+	vertex_input: Option<(
+		&[vk::VertexInputBindingDescription],
+		&[vk::VertexInputAttributeDescription],
+	)>,
 	binding_descs: Vec<vk::DescriptorSetLayoutBinding>,
-	color_blend_attachment_states: &[vk::PipelineColorBlendAttachmentState],
-	multisample_state: &vk::PipelineMultisampleStateCreateInfo,
-	vert_spirv: &[u32],
-	frag_spirv: &[u32],
-) -> MarsResult<(Pipeline, PipelineLayout, DescriptorSetLayout)> {
-	let vertex_shader = create_shader_module(device, &vert_spirv);
-	let fragment_shader = create_shader_module(device, &frag_spirv);
-	let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-		.logic_op_enable(false)
-		.attachments(color_blend_attachment_states)
-		.blend_constants([1.0, 1.0, 1.0, 1.0])
-		.build();
-	let descriptor_set_layout = device.create_descriptor_set_layout(&binding_descs)?;
-	let pipeline_layout = device.create_pipeline_layout(&descriptor_set_layout)?;
-	let pipeline = device.create_pipeline(
-		&vertex_shader,
-		&vertex_binding_descs,
-		&vertex_attribute_descs,
-		&fragment_shader,
-		&color_blend_state,
-		multisample_state,
-		&pipeline_layout,
-		render_pass,
-		0,
+	push_constant_ranges: &[vk::PushConstantRange],
+	options: &FunctionOptions,
+	base: Option<&GraphicsPipeline>,
+) -> MarsResult<(GraphicsPipeline, PipelineLayout, Option<DescriptorSetLayout>)> {
+	assert!(
+		options.stencil_test.is_none() || depth_aspect::<G>().contains(vk::ImageAspectFlags::STENCIL),
+		"stencil tests need a depth attachment with a stencil aspect"
+	);
+	let device = &context.device;
+	let color_blend_states = create_blend_states::<G>();
+	// Functions without bindings bind no descriptor set, so their pipeline layout has none
+	let descriptor_set_layout = if binding_descs.is_empty() {
+		None
+	} else {
+		Some(device.create_descriptor_set_layout(&binding_descs)?)
+	};
+	let set_layouts = descriptor_set_layout
+		.iter()
+		.map(raw_descriptor_set_layout)
+		.collect::<Vec<_>>();
+	let pipeline_layout = PipelineLayout::create(device, &set_layouts, push_constant_ranges)?;
+	let pipeline = GraphicsPipeline::create(
+		device,
+		&GraphicsPipelineDesc {
+			shaders,
+			specialization_constants: &options.specialization_constants,
+			vertex_input,
+			color_blend_attachments: &color_blend_states,
+			multisample_state: create_multisample_state::<G>(),
+			topology: options.topology.into(),
+			depth_test: has_depth_attachment::<G>(),
+			depth_write: has_depth_attachment::<G>() && options.depth_test.writes(),
+			depth_compare_op: options.depth_test.compare_op(),
+			stencil: options
+				.stencil_test
+				.map(|stencil| (stencil.front.as_raw(), stencil.back.as_raw())),
+			depth_clamp: options.depth_clamp,
+			rasterizer_discard: options.rasterizer_discard,
+			fragment_shading_rate: options.shading_rate.map(ShadingRate::extent),
+			extended_dynamic_state: context.extended_dynamic_state.is_some(),
+			layout: pipeline_layout.layout,
+			render_pass: render_pass.render_pass.raw,
+			subpass: render_pass.subpass,
+			base_pipeline: base.map(|base| base.pipeline),
+		},
 	)?;
 
 	Ok((pipeline, pipeline_layout, descriptor_set_layout))
 }
 
+/// Pipeline state of a function that isn't decided by its prototype
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FunctionOptions {
+	/// The size of the block of pixels covered by each fragment shader invocation, for functions
+	/// that don't need full resolution shading. Requires the `FragmentShadingRate` device extension,
+	/// and creation fails with `ERROR_FEATURE_NOT_PRESENT` if the device can't shade at the rate with
+	/// the render pass's sample count.
+	pub shading_rate: Option<ShadingRate>,
+	/// How fragments are tested against the depth attachment, if the render pass has one
+	pub depth_test: DepthTest,
+	/// Clamp the depth of fragments to the depth range instead of clipping primitives against the
+	/// near and far planes. Shadow casters behind the near plane of a directional light's shadow map
+	/// then still land in it, pancaked onto the near plane. Requires the `depth_clamp` device feature.
+	pub depth_clamp: bool,
+	/// The values of the specialization constants of the function's shaders, the `i`th value being
+	/// given to the constant with `constant_id = i`. Each value is the 32 bits of an `int`, `uint`,
+	/// `float` or `bool` constant.
+	pub specialization_constants: Vec<u32>,
+	/// Render with a negative viewport height, so +Y points up in clip space like in OpenGL and
+	/// content authored for it doesn't have to flip its view matrices. Requires the `Maintenance1`
+	/// device extension.
+	pub flip_viewport: bool,
+	/// How the indices of draws are assembled into primitives
+	pub topology: Topology,
+	/// How fragments are tested against and update the stencil aspect of the depth attachment. The
+	/// render pass must have a depth attachment with a stencil aspect, like `D24UnormS8Uint`.
+	pub stencil_test: Option<StencilTest>,
+	/// Discard primitives before they're rasterized, so the function writes to no attachments and
+	/// only its vertex (and geometry) shader runs. For functions that write their results to
+	/// storage buffers, or passes only recorded for their queries.
+	pub rasterizer_discard: bool,
+}
+
+/// Pipeline state set when passes are recorded instead of being fixed when functions are created,
+/// so one function can draw with different values instead of one function being created for each.
+/// Set with `RenderEngine::set_dynamic_state`, which applies it to every pass recorded after.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DynamicState {
+	/// The width of lines in pixels, for functions with `Topology::LineList`. Widths other than 1
+	/// require the `wide_lines` device feature.
+	pub line_width: f32,
+	/// An offset added to the depth of fragments, like the slope-scaled bias of shadow casters. A
+	/// nonzero clamp requires the `depth_bias_clamp` device feature.
+	pub depth_bias: DepthBias,
+	/// The color used by the constant color and alpha blend factors
+	pub blend_constants: [f32; 4],
+	/// The reference value stencil tests compare against and replace with, instead of the one of
+	/// the function's `StencilTest`
+	pub stencil_reference: Option<u32>,
+	/// Which faces of triangles are culled. Culling requires the `ExtendedDynamicState` device
+	/// extension.
+	pub cull_mode: CullMode,
+	/// The topology of draws instead of the function's own, which must be of the same kind of
+	/// primitive unless the device supports `dynamicPrimitiveTopologyUnrestricted`. Requires the
+	/// `ExtendedDynamicState` device extension.
+	pub topology: Option<Topology>,
+	/// The depth comparison of draws instead of the function's own. Whether the function writes
+	/// depth doesn't change. Requires the `ExtendedDynamicState` device extension.
+	pub depth_compare: Option<DepthTest>,
+}
+
+impl Default for DynamicState {
+	fn default() -> Self {
+		Self {
+			line_width: 1.0,
+			depth_bias: DepthBias::default(),
+			blend_constants: [0.0; 4],
+			stencil_reference: None,
+			cull_mode: CullMode::None,
+			topology: None,
+			depth_compare: None,
+		}
+	}
+}
+
+impl DynamicState {
+	/// Whether any of the state that needs the `ExtendedDynamicState` extension is used
+	pub(crate) fn is_extended(&self) -> bool {
+		self.cull_mode != CullMode::None || self.topology.is_some() || self.depth_compare.is_some()
+	}
+}
+
+/// An offset added to the depth of fragments, of `constant_factor` times the smallest resolvable
+/// depth difference plus `slope_factor` times the fragment's depth slope, clamped to `clamp` if it's
+/// non-zero
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct DepthBias {
+	pub constant_factor: f32,
+	pub clamp: f32,
+	pub slope_factor: f32,
+}
+
+/// The faces of triangles that are culled, where front faces are wound counter-clockwise
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CullMode {
+	None,
+	Front,
+	Back,
+}
+
+impl From<CullMode> for vk::CullModeFlags {
+	fn from(mode: CullMode) -> Self {
+		match mode {
+			CullMode::None => vk::CullModeFlags::NONE,
+			CullMode::Front => vk::CullModeFlags::FRONT,
+			CullMode::Back => vk::CullModeFlags::BACK,
+		}
+	}
+}
+
+/// The state of a function's pipeline that `DynamicState` can override, which passes fall back to
+/// when it doesn't
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct FixedState {
+	/// The topology of the function, or `None` for mesh functions which have no input assembly
+	pub(crate) topology: Option<vk::PrimitiveTopology>,
+	pub(crate) depth_compare_op: vk::CompareOp,
+	pub(crate) stencil_reference: u32,
+}
+
+/// The depth test of a function
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DepthTest {
+	/// Keep fragments closer than the stored depth and write their depth
+	Less,
+	/// Keep fragments with a greater depth than the stored depth and write their depth. With
+	/// reverse-Z projections, like `math::infinite_reverse_perspective`, these are the closer
+	/// fragments.
+	Greater,
+	/// Keep only fragments exactly at the stored depth without writing it. Used for shading after a
+	/// depth pre-pass has already laid down the depth of the visible surfaces, so that each pixel is
+	/// shaded only once.
+	Equal,
+	/// Keep every fragment without writing its depth, for overlays drawn over everything else
+	Always,
+}
+
+impl DepthTest {
+	pub(crate) fn compare_op(self) -> vk::CompareOp {
+		match self {
+			DepthTest::Less => vk::CompareOp::LESS,
+			DepthTest::Greater => vk::CompareOp::GREATER,
+			DepthTest::Equal => vk::CompareOp::EQUAL,
+			DepthTest::Always => vk::CompareOp::ALWAYS,
+		}
+	}
+
+	fn writes(self) -> bool {
+		match self {
+			DepthTest::Less | DepthTest::Greater => true,
+			DepthTest::Equal | DepthTest::Always => false,
+		}
+	}
+}
+
+/// The stencil test of a function. Fragments that fail it are discarded, and whether they passed
+/// the stencil and depth tests decides how the stencil value is updated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct StencilTest {
+	/// The test of front-facing triangles, and of lines
+	pub front: StencilFaceTest,
+	/// The test of back-facing triangles
+	pub back: StencilFaceTest,
+	/// The value the stencil is compared against, and written by `StencilOp::Replace`. Can be
+	/// changed without another function with `DynamicState::stencil_reference`.
+	pub reference: u32,
+}
+
+impl StencilTest {
+	/// A test that's the same for both faces
+	pub fn new(test: StencilFaceTest, reference: u32) -> Self {
+		Self {
+			front: test,
+			back: test,
+			reference,
+		}
+	}
+}
+
+/// The stencil test of one face of triangles
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct StencilFaceTest {
+	/// How the reference is compared with the stored value, both masked by `compare_mask`. The
+	/// reference is on the left, so `Less` passes when the reference is less than the stored value.
+	pub compare: StencilCompare,
+	/// What's written when the stencil test fails
+	pub fail: StencilOp,
+	/// What's written when both the stencil and depth tests pass
+	pub pass: StencilOp,
+	/// What's written when the stencil test passes but the depth test fails
+	pub depth_fail: StencilOp,
+	/// The bits of the reference and stored value that are compared
+	pub compare_mask: u32,
+	/// The bits of the stored value that are updated
+	pub write_mask: u32,
+}
+
+impl Default for StencilFaceTest {
+	/// Passes everywhere without changing the stencil
+	fn default() -> Self {
+		Self {
+			compare: StencilCompare::Always,
+			fail: StencilOp::Keep,
+			pass: StencilOp::Keep,
+			depth_fail: StencilOp::Keep,
+			compare_mask: !0,
+			write_mask: !0,
+		}
+	}
+}
+
+impl StencilFaceTest {
+	fn as_raw(&self) -> vk::StencilOpState {
+		vk::StencilOpState {
+			fail_op: self.fail.into(),
+			pass_op: self.pass.into(),
+			depth_fail_op: self.depth_fail.into(),
+			compare_op: self.compare.into(),
+			compare_mask: self.compare_mask,
+			write_mask: self.write_mask,
+			// Set when passes are recorded, see `FixedState`
+			reference: 0,
+		}
+	}
+}
+
+/// How the stencil reference is compared with the stored value
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StencilCompare {
+	Never,
+	Less,
+	Equal,
+	LessOrEqual,
+	Greater,
+	NotEqual,
+	GreaterOrEqual,
+	Always,
+}
+
+impl From<StencilCompare> for vk::CompareOp {
+	fn from(compare: StencilCompare) -> Self {
+		match compare {
+			StencilCompare::Never => vk::CompareOp::NEVER,
+			StencilCompare::Less => vk::CompareOp::LESS,
+			StencilCompare::Equal => vk::CompareOp::EQUAL,
+			StencilCompare::LessOrEqual => vk::CompareOp::LESS_OR_EQUAL,
+			StencilCompare::Greater => vk::CompareOp::GREATER,
+			StencilCompare::NotEqual => vk::CompareOp::NOT_EQUAL,
+			StencilCompare::GreaterOrEqual => vk::CompareOp::GREATER_OR_EQUAL,
+			StencilCompare::Always => vk::CompareOp::ALWAYS,
+		}
+	}
+}
+
+/// How the stored stencil value is updated
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StencilOp {
+	Keep,
+	Zero,
+	/// Write the reference
+	Replace,
+	IncrementAndClamp,
+	DecrementAndClamp,
+	Invert,
+	IncrementAndWrap,
+	DecrementAndWrap,
+}
+
+impl From<StencilOp> for vk::StencilOp {
+	fn from(op: StencilOp) -> Self {
+		match op {
+			StencilOp::Keep => vk::StencilOp::KEEP,
+			StencilOp::Zero => vk::StencilOp::ZERO,
+			StencilOp::Replace => vk::StencilOp::REPLACE,
+			StencilOp::IncrementAndClamp => vk::StencilOp::INCREMENT_AND_CLAMP,
+			StencilOp::DecrementAndClamp => vk::StencilOp::DECREMENT_AND_CLAMP,
+			StencilOp::Invert => vk::StencilOp::INVERT,
+			StencilOp::IncrementAndWrap => vk::StencilOp::INCREMENT_AND_WRAP,
+			StencilOp::DecrementAndWrap => vk::StencilOp::DECREMENT_AND_WRAP,
+		}
+	}
+}
+
+/// Which end of the depth range is closest to the camera
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DepthConvention {
+	/// Depth goes from 0 at the near plane to 1 at the far plane
+	Standard,
+	/// Depth goes from 1 at the near plane to 0 at the far plane (or infinitely far away), which
+	/// spreads floating point precision much more evenly across the scene
+	Reverse,
+}
+
+impl DepthConvention {
+	/// The value depth attachments should be cleared to, the depth of the farthest possible surface
+	pub fn clear_value(self) -> f32 {
+		match self {
+			DepthConvention::Standard => 1.0,
+			DepthConvention::Reverse => 0.0,
+		}
+	}
+
+	/// The depth test that keeps the closest fragments
+	pub fn depth_test(self) -> DepthTest {
+		match self {
+			DepthConvention::Standard => DepthTest::Less,
+			DepthConvention::Reverse => DepthTest::Greater,
+		}
+	}
+}
+
+impl Default for DepthConvention {
+	fn default() -> Self {
+		DepthConvention::Standard
+	}
+}
+
+impl Default for DepthTest {
+	fn default() -> Self {
+		DepthTest::Less
+	}
+}
+
+/// The kind of primitive a function draws
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Topology {
+	/// Every three indices make up a triangle
+	TriangleList,
+	/// Every two indices make up a line, one pixel wide
+	LineList,
+}
+
+impl Default for Topology {
+	fn default() -> Self {
+		Topology::TriangleList
+	}
+}
+
+impl From<Topology> for vk::PrimitiveTopology {
+	fn from(t: Topology) -> Self {
+		match t {
+			Topology::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
+			Topology::LineList => vk::PrimitiveTopology::LINE_LIST,
+		}
+	}
+}
+
+/// A fragment size for variable rate shading, in pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ShadingRate {
+	Rate1x1,
+	Rate1x2,
+	Rate2x1,
+	Rate2x2,
+	Rate2x4,
+	Rate4x2,
+	Rate4x4,
+}
+
+impl ShadingRate {
+	pub(crate) fn extent(self) -> vk::Extent2D {
+		let (width, height) = match self {
+			ShadingRate::Rate1x1 => (1, 1),
+			ShadingRate::Rate1x2 => (1, 2),
+			ShadingRate::Rate2x1 => (2, 1),
+			ShadingRate::Rate2x2 => (2, 2),
+			ShadingRate::Rate2x4 => (2, 4),
+			ShadingRate::Rate4x2 => (4, 2),
+			ShadingRate::Rate4x4 => (4, 4),
+		};
+		vk::Extent2D { width, height }
+	}
+}
+
+/// Queries the fragment sizes the device can shade at, and the sample counts each works with
+pub(crate) unsafe fn supported_shading_rates(
+	entry: &ash::Entry,
+	instance: &Instance,
+	physical_device: &PhysicalDevice,
+) -> MarsResult<Vec<vk::PhysicalDeviceFragmentShadingRateKHR>> {
+	// ash has no loader for the extension, so the query is loaded directly
+	let handle = raw_instance(instance).handle();
+	let fns =
+		vk::KhrFragmentShadingRateFn::load(|name| mem::transmute(entry.get_instance_proc_addr(handle, name.as_ptr())));
+	let physical_device = raw_physical_device(physical_device);
+	let mut count = 0;
+	fns.get_physical_device_fragment_shading_rates_khr(physical_device, &mut count, std::ptr::null_mut())
+		.result()?;
+	let mut rates = vec![vk::PhysicalDeviceFragmentShadingRateKHR::default(); count as usize];
+	fns.get_physical_device_fragment_shading_rates_khr(physical_device, &mut count, rates.as_mut_ptr())
+		.result()?;
+	rates.truncate(count as usize);
+	Ok(rates)
+}
+
+fn check_options<G: RenderPassPrototype>(context: &Context, options: &FunctionOptions) -> MarsResult<()> {
+	if let Some(shading_rate) = options.shading_rate {
+		if !context.has_extension(DeviceExtension::FragmentShadingRate) {
+			return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+		}
+		let supported = context.shading_rates.iter().any(|rate| {
+			rate.fragment_size == shading_rate.extent() && rate.sample_counts.contains(G::SampleCount::as_raw())
+		});
+		if !supported {
+			return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+		}
+	}
+	if options.depth_clamp && context.features().depth_clamp != vk::TRUE {
+		return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+	}
+	if options.flip_viewport && !context.has_extension(DeviceExtension::Maintenance1) {
+		return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+	}
+	Ok(())
+}
+
 pub struct ParameterDesc {
 	pub attributes: Vec<AttributeDesc>,
+	/// The distance between consecutive elements in the vertex buffer
+	pub stride: u32,
+}
+
+impl ParameterDesc {
+	pub fn of<P: Parameter>() -> Self {
+		Self {
+			attributes: P::attributes(),
+			stride: std::mem::size_of::<P>() as u32,
+		}
+	}
 }
 
 pub struct AttributeDesc {
-	format: AttributeFormat,
+	pub(crate) format: AttributeFormat,
+	/// The offset of the attribute from the start of the parameter, or `None` to follow right after
+	/// the attribute before it
+	pub(crate) offset: Option<u32>,
+}
+
+impl AttributeDesc {
+	pub fn new(format: AttributeFormat) -> Self {
+		Self { format, offset: None }
+	}
+
+	/// An attribute at `offset` bytes from the start of the parameter
+	pub fn at_offset(format: AttributeFormat, offset: u32) -> Self {
+		Self {
+			format,
+			offset: Some(offset),
+		}
+	}
+
+	/// Moves the attributes of a parameter that's a field of another to the offset of the field,
+	/// giving each of them an explicit offset
+	pub fn offset_by(attributes: Vec<AttributeDesc>, offset: u32) -> Vec<AttributeDesc> {
+		let mut next = 0;
+		attributes
+			.into_iter()
+			.map(|attribute| {
+				let start = attribute.offset.unwrap_or(next);
+				next = start + attribute.format.size();
+				AttributeDesc::at_offset(attribute.format, offset + start)
+			})
+			.collect()
+	}
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -219,14 +1174,22 @@ pub enum AttributeFormat {
 	Vec2F,
 	Vec3F,
 	Vec4F,
+	/// A `uint` input
+	U32,
+	/// An `int` input
+	I32,
+	/// Four bytes read as a `vec4` from 0 to 1, like an 8-bit color
+	Vec4U8Norm,
 }
 
 impl AttributeFormat {
-	fn size(self) -> u32 {
+	pub(crate) fn size(self) -> u32 {
 		match self {
 			AttributeFormat::Vec2F => 4 * 2,
 			AttributeFormat::Vec3F => 4 * 3,
 			AttributeFormat::Vec4F => 4 * 4,
+			AttributeFormat::U32 | AttributeFormat::I32 => 4,
+			AttributeFormat::Vec4U8Norm => 4,
 		}
 	}
 }
@@ -237,10 +1200,16 @@ impl From<AttributeFormat> for vk::Format {
 			AttributeFormat::Vec2F => vk::Format::R32G32_SFLOAT,
 			AttributeFormat::Vec3F => vk::Format::R32G32B32_SFLOAT,
 			AttributeFormat::Vec4F => vk::Format::R32G32B32A32_SFLOAT,
+			AttributeFormat::U32 => vk::Format::R32_UINT,
+			AttributeFormat::I32 => vk::Format::R32_SINT,
+			AttributeFormat::Vec4U8Norm => vk::Format::R8G8B8A8_UNORM,
 		}
 	}
 }
 
+/// A vertex input of a function, made of attributes read at consecutive locations. Implemented for
+/// vectors, `u32`, `i32`, `[u8; 4]` and pairs of parameters, and derivable for `#[repr(C)]` structs
+/// whose fields are parameters, like `struct Vertex { pos: Vec3, normal: Vec3, uv: Vec2 }`.
 pub unsafe trait Parameter: Copy {
 	fn attributes() -> Vec<AttributeDesc>;
 }
@@ -260,6 +1229,25 @@ where
 	}
 }
 
+unsafe impl Parameter for u32 {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![AttributeDesc::new(AttributeFormat::U32)]
+	}
+}
+
+unsafe impl Parameter for i32 {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![AttributeDesc::new(AttributeFormat::I32)]
+	}
+}
+
+// Bytes are normalized, as they usually hold colors
+unsafe impl Parameter for [u8; 4] {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![AttributeDesc::new(AttributeFormat::Vec4U8Norm)]
+	}
+}
+
 pub unsafe trait Parameters: Copy {
 	fn parameters() -> Vec<ParameterDesc>;
 }
@@ -269,9 +1257,7 @@ where
 	A: Parameter,
 {
 	fn parameters() -> Vec<ParameterDesc> {
-		vec![ParameterDesc {
-			attributes: A::attributes(),
-		}]
+		vec![ParameterDesc::of::<A>()]
 	}
 }
 
@@ -281,14 +1267,7 @@ where
 	B: Parameter,
 {
 	fn parameters() -> Vec<ParameterDesc> {
-		vec![
-			ParameterDesc {
-				attributes: A::attributes(),
-			},
-			ParameterDesc {
-				attributes: B::attributes(),
-			},
-		]
+		vec![ParameterDesc::of::<A>(), ParameterDesc::of::<B>()]
 	}
 }
 
@@ -300,56 +1279,213 @@ where
 {
 	fn parameters() -> Vec<ParameterDesc> {
 		vec![
-			ParameterDesc {
-				attributes: A::attributes(),
-			},
-			ParameterDesc {
-				attributes: B::attributes(),
-			},
-			ParameterDesc {
-				attributes: C::attributes(),
-			},
+			ParameterDesc::of::<A>(),
+			ParameterDesc::of::<B>(),
+			ParameterDesc::of::<C>(),
 		]
 	}
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum BindingType {
-	Uniform,
-	SampledImage,
-}
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BindingType {
+	Uniform,
+	SampledImage,
+	AccelerationStructure,
+	InputAttachment,
+	StorageBuffer,
+	StorageImage,
+	/// A sampler on its own, for GLSL `sampler`s
+	Sampler,
+	/// An image sampled with a separate sampler, for GLSL `texture2D`s
+	SampledImageOnly,
+}
+
+impl BindingType {
+	/// The most descriptors of this type the device allows in one set
+	fn max_per_set(self, limits: &vk::PhysicalDeviceLimits) -> u32 {
+		match self {
+			BindingType::Uniform => limits.max_descriptor_set_uniform_buffers,
+			BindingType::SampledImage | BindingType::SampledImageOnly => limits.max_descriptor_set_sampled_images,
+			BindingType::InputAttachment => limits.max_descriptor_set_input_attachments,
+			BindingType::StorageBuffer => limits.max_descriptor_set_storage_buffers,
+			BindingType::StorageImage => limits.max_descriptor_set_storage_images,
+			BindingType::Sampler => limits.max_descriptor_set_samplers,
+			// Its limit is only in the acceleration structure properties, and arrays of them are small
+			BindingType::AccelerationStructure => u32::MAX,
+		}
+	}
+}
+
+impl From<BindingType> for vk::DescriptorType {
+	fn from(t: BindingType) -> Self {
+		match t {
+			BindingType::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+			BindingType::SampledImage => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			BindingType::AccelerationStructure => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+			BindingType::InputAttachment => vk::DescriptorType::INPUT_ATTACHMENT,
+			BindingType::StorageBuffer => vk::DescriptorType::STORAGE_BUFFER,
+			BindingType::StorageImage => vk::DescriptorType::STORAGE_IMAGE,
+			BindingType::Sampler => vk::DescriptorType::SAMPLER,
+			BindingType::SampledImageOnly => vk::DescriptorType::SAMPLED_IMAGE,
+		}
+	}
+}
+
+pub struct BindingDesc {
+	pub binding_type: BindingType,
+	pub count: u32,
+}
+
+pub unsafe trait Binding {
+	type Argument: Argument;
+
+	fn description() -> BindingDesc;
+}
+
+unsafe impl<F: FormatType> Binding for SampledImage<F> {
+	type Argument = Self;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+/// Bound like a `SampledImage`, but declared in shaders as a `sampler2DArray`
+unsafe impl<F: FormatType> Binding for SampledImageArray<F> {
+	type Argument = Self;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+/// Samplers are shared through an `Arc`, so one sampler can be bound alongside many textures
+unsafe impl Binding for Sampler {
+	type Argument = Arc<Sampler>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Sampler,
+			count: 1,
+		}
+	}
+}
+
+unsafe impl<F: FormatType> Binding for Texture<F> {
+	type Argument = Self;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImageOnly,
+			count: 1,
+		}
+	}
+}
+
+/// A fixed-size array of bindings, bound to consecutive elements of one binding like a GLSL array
+/// such as `uniform sampler2D textures[4]`. Indexing an array of sampled images with a value that
+/// isn't the same for every invocation of a draw, like a per-pixel material index, needs the index
+/// wrapped in `nonuniformEXT` and the `DescriptorIndexing` device extension.
+unsafe impl<B, const N: usize> Binding for [B; N]
+where
+	B: Binding,
+{
+	type Argument = [B::Argument; N];
 
-impl From<BindingType> for vk::DescriptorType {
-	fn from(t: BindingType) -> Self {
-		match t {
-			BindingType::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
-			BindingType::SampledImage => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+	fn description() -> BindingDesc {
+		let element = B::description();
+		assert_eq!(element.count, 1, "Arrays of array bindings aren't supported");
+		BindingDesc {
+			binding_type: element.binding_type,
+			count: N as u32,
 		}
 	}
 }
 
-pub struct BindingDesc {
-	pub binding_type: BindingType,
-	pub count: u32,
+/// A storage buffer holding a `T`, which shaders can both read and write. `T` can be a slice for
+/// runtime-sized arrays.
+pub struct Storage<T: ?Sized>(PhantomData<T>);
+
+unsafe impl<T> Binding for Storage<T>
+where
+	T: ?Sized,
+{
+	type Argument = Buffer<StorageBufferUsage, T>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageBuffer,
+			count: 1,
+		}
+	}
 }
 
-pub unsafe trait Binding {
-	type Argument: Argument;
+/// A buffer of indirect draw parameters, bound as a storage buffer so a compute shader can write
+/// the draws. Dispatch such shaders with `RenderEngine::dispatch_draws` to have the draws that read
+/// the buffer wait for them.
+pub struct IndirectStorage<T: ?Sized>(PhantomData<T>);
 
-	fn description() -> BindingDesc;
+unsafe impl<T> Binding for IndirectStorage<T>
+where
+	T: ?Sized,
+{
+	type Argument = Buffer<IndirectBufferUsage, T>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageBuffer,
+			count: 1,
+		}
+	}
 }
 
-unsafe impl<F: FormatType> Binding for SampledImage<F> {
-	type Argument = Self;
+/// A storage buffer bound by its handle, for built-in functions that share one buffer between
+/// several sets of arguments
+pub(crate) struct RawStorage;
+
+unsafe impl Binding for RawStorage {
+	type Argument = RawStorageArgument;
 
 	fn description() -> BindingDesc {
 		BindingDesc {
-			binding_type: BindingType::SampledImage,
+			binding_type: BindingType::StorageBuffer,
 			count: 1,
 		}
 	}
 }
 
+/// The buffer must outlive the arguments this is written to
+pub(crate) struct RawStorageArgument {
+	buffer: vk::Buffer,
+	range: u64,
+}
+
+impl RawStorageArgument {
+	pub(crate) fn new<U, T>(buffer: &Buffer<U, T>) -> Self
+	where
+		U: BufferUsageType,
+		T: ?Sized,
+	{
+		Self {
+			buffer: buffer.raw(),
+			range: buffer.size as u64,
+		}
+	}
+
+	pub(crate) fn buffer(&self) -> vk::Buffer {
+		self.buffer
+	}
+}
+
+/// The bindings of a function's descriptor set, numbered in order from 0. Implemented for tuples of
+/// bindings, and derivable for structs whose fields are bindings, which also generates a struct
+/// holding their arguments by name.
 pub unsafe trait Bindings {
 	type Arguments: Arguments;
 
@@ -364,53 +1500,65 @@ unsafe impl Bindings for () {
 	}
 }
 
-unsafe impl<A> Bindings for (A,)
+pub trait Argument {
+	fn as_write(&self) -> WriteArgument;
+}
+
+impl<T> Argument for Buffer<UniformBufferUsage, T>
 where
-	A: Binding,
+	T: Copy,
 {
-	type Arguments = (A::Argument,);
-
-	fn descriptions() -> Vec<BindingDesc> {
-		vec![A::description()]
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::Uniform(WriteUniformArgument {
+			buffer: self.as_untyped(),
+		})
 	}
 }
 
-unsafe impl<A, B> Bindings for (A, B)
+impl<T> Argument for Buffer<StorageBufferUsage, T>
 where
-	A: Binding,
-	B: Binding,
+	T: ?Sized,
 {
-	type Arguments = (A::Argument, B::Argument);
-
-	fn descriptions() -> Vec<BindingDesc> {
-		vec![A::description(), B::description()]
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::StorageBuffer(WriteStorageBufferArgument {
+			buffer: self.raw(),
+			range: self.size as u64,
+			gpu_use: Some(self.gpu_use.clone()),
+		})
 	}
 }
 
-unsafe impl<A, B, C> Bindings for (A, B, C)
+impl<T> Argument for Buffer<IndirectBufferUsage, T>
 where
-	A: Binding,
-	B: Binding,
-	C: Binding,
+	T: ?Sized,
 {
-	type Arguments = (A::Argument, B::Argument, C::Argument);
-
-	fn descriptions() -> Vec<BindingDesc> {
-		vec![A::description(), B::description(), C::description()]
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::StorageBuffer(WriteStorageBufferArgument {
+			buffer: self.raw(),
+			range: self.size as u64,
+			gpu_use: Some(self.gpu_use.clone()),
+		})
 	}
 }
 
-pub trait Argument {
-	fn as_write(&self) -> WriteArgument;
+/// Storage images bound by their views, written to consecutive elements of the binding. The views
+/// must outlive the arguments this is written to.
+pub(crate) struct StorageImageViews(pub(crate) Vec<vk::ImageView>);
+
+impl Argument for StorageImageViews {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::StorageImage(WriteStorageImageArgument {
+			image_views: self.0.clone(),
+		})
+	}
 }
 
-impl<T> Argument for Buffer<UniformBufferUsage, T>
-where
-	T: Copy,
-{
+impl Argument for RawStorageArgument {
 	fn as_write(&self) -> WriteArgument {
-		WriteArgument::Uniform(WriteUniformArgument {
-			buffer: self.as_untyped(),
+		WriteArgument::StorageBuffer(WriteStorageBufferArgument {
+			buffer: self.buffer,
+			range: self.range,
+			gpu_use: None,
 		})
 	}
 }
@@ -422,55 +1570,118 @@ where
 	fn as_write(&self) -> WriteArgument {
 		WriteArgument::SampledImage(WriteSampledImageArgument {
 			sampler: self.sampler.sampler.clone(),
-			image_view: self.image_view.image_view.clone(),
+			image_view: self.image_view.image_view.raw,
 			image_layout: self.image.layout,
 		})
 	}
 }
 
-pub trait Arguments {
-	fn as_writes(&self) -> Vec<WriteArgument>;
+impl<F> Argument for SampledImageArray<F>
+where
+	F: FormatType,
+{
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.sampler.clone(),
+			image_view: self.image_view.image_view.raw,
+			image_layout: self.array.image.layout,
+		})
+	}
 }
 
-impl Arguments for () {
-	fn as_writes(&self) -> Vec<WriteArgument> {
-		Vec::new()
+impl Argument for Arc<Sampler> {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::Sampler(WriteSamplerArgument {
+			sampler: self.sampler.sampler.clone(),
+		})
 	}
 }
 
-impl<A> Arguments for (A,)
+impl<F> Argument for Texture<F>
 where
-	A: Argument,
+	F: FormatType,
 {
-	fn as_writes(&self) -> Vec<WriteArgument> {
-		vec![self.0.as_write()]
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImageOnly(WriteSampledImageOnlyArgument {
+			image_view: self.image_view.image_view.raw,
+			image_layout: self.image.layout,
+		})
 	}
 }
 
-impl<A, B> Arguments for (A, B)
+impl<A, const N: usize> Argument for [A; N]
 where
 	A: Argument,
-	B: Argument,
 {
-	fn as_writes(&self) -> Vec<WriteArgument> {
-		vec![self.0.as_write(), self.1.as_write()]
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::Array(self.iter().map(Argument::as_write).collect())
 	}
 }
 
-impl<A, B, C> Arguments for (A, B, C)
-where
-	A: Argument,
-	B: Argument,
-	C: Argument,
-{
+pub trait Arguments {
+	fn as_writes(&self) -> Vec<WriteArgument>;
+}
+
+impl Arguments for () {
 	fn as_writes(&self) -> Vec<WriteArgument> {
-		vec![self.0.as_write(), self.1.as_write(), self.2.as_write()]
+		Vec::new()
 	}
 }
 
+/// Implements `Bindings` and `Arguments` for tuples, given the type parameter and index of each
+/// element of each tuple
+macro_rules! tuple_bindings {
+	($(($($T:ident $i:tt),+))+) => {
+		$(
+			unsafe impl<$($T),+> Bindings for ($($T,)+)
+			where
+				$($T: Binding,)+
+			{
+				type Arguments = ($($T::Argument,)+);
+
+				fn descriptions() -> Vec<BindingDesc> {
+					vec![$($T::description()),+]
+				}
+			}
+
+			impl<$($T),+> Arguments for ($($T,)+)
+			where
+				$($T: Argument,)+
+			{
+				fn as_writes(&self) -> Vec<WriteArgument> {
+					vec![$(self.$i.as_write()),+]
+				}
+			}
+		)+
+	};
+}
+
+tuple_bindings! {
+	(A 0)
+	(A 0, B 1)
+	(A 0, B 1, C 2)
+	(A 0, B 1, C 2, D 3)
+	(A 0, B 1, C 2, D 3, E 4)
+	(A 0, B 1, C 2, D 3, E 4, F 5)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11)
+}
+
 pub enum WriteArgument<'a> {
 	Uniform(WriteUniformArgument<'a>),
 	SampledImage(WriteSampledImageArgument),
+	AccelerationStructure(WriteAccelerationStructureArgument),
+	InputAttachment(WriteInputAttachmentArgument),
+	StorageBuffer(WriteStorageBufferArgument),
+	StorageImage(WriteStorageImageArgument),
+	Sampler(WriteSamplerArgument),
+	SampledImageOnly(WriteSampledImageOnlyArgument),
+	/// Writes of the same type to consecutive elements of an array binding
+	Array(Vec<WriteArgument<'a>>),
 }
 
 impl<'a> WriteArgument<'a> {
@@ -478,6 +1689,67 @@ impl<'a> WriteArgument<'a> {
 		match *self {
 			WriteArgument::Uniform(_) => vk::DescriptorType::UNIFORM_BUFFER,
 			WriteArgument::SampledImage(_) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+			WriteArgument::AccelerationStructure(_) => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+			WriteArgument::InputAttachment(_) => vk::DescriptorType::INPUT_ATTACHMENT,
+			WriteArgument::StorageBuffer(_) => vk::DescriptorType::STORAGE_BUFFER,
+			WriteArgument::StorageImage(_) => vk::DescriptorType::STORAGE_IMAGE,
+			WriteArgument::Sampler(_) => vk::DescriptorType::SAMPLER,
+			WriteArgument::SampledImageOnly(_) => vk::DescriptorType::SAMPLED_IMAGE,
+			WriteArgument::Array(ref elements) => elements[0].descriptor_type(),
+		}
+	}
+
+	/// The use tracking of the buffers written that mars created
+	pub(crate) fn gpu_uses(&self) -> Vec<GpuUse> {
+		match self {
+			WriteArgument::Uniform(write) => vec![write.buffer.buffer.gpu_use.clone()],
+			WriteArgument::StorageBuffer(write) => write.gpu_use.iter().cloned().collect(),
+			WriteArgument::Array(elements) => elements.iter().flat_map(WriteArgument::gpu_uses).collect(),
+			_ => Vec::new(),
+		}
+	}
+
+	/// The descriptor info of a uniform or storage buffer write
+	fn buffer_info(&self) -> Option<vk::DescriptorBufferInfo> {
+		match self {
+			WriteArgument::Uniform(write) => Some(vk::DescriptorBufferInfo {
+				buffer: write.buffer.buffer.raw(),
+				offset: 0,
+				range: write.buffer.buffer.size as u64,
+			}),
+			WriteArgument::StorageBuffer(write) => Some(vk::DescriptorBufferInfo {
+				buffer: write.buffer,
+				offset: 0,
+				range: write.range,
+			}),
+			_ => None,
+		}
+	}
+
+	/// The descriptor info of a sampler, sampled image or input attachment write
+	fn image_info(&self) -> Option<vk::DescriptorImageInfo> {
+		match self {
+			WriteArgument::Sampler(write) => Some(vk::DescriptorImageInfo {
+				sampler: **write.sampler,
+				image_view: vk::ImageView::null(),
+				image_layout: vk::ImageLayout::UNDEFINED,
+			}),
+			WriteArgument::SampledImageOnly(write) => Some(vk::DescriptorImageInfo {
+				sampler: vk::Sampler::null(),
+				image_view: write.image_view,
+				image_layout: write.image_layout,
+			}),
+			WriteArgument::SampledImage(write) => Some(vk::DescriptorImageInfo {
+				sampler: **write.sampler,
+				image_view: write.image_view,
+				image_layout: write.image_layout,
+			}),
+			WriteArgument::InputAttachment(write) => Some(vk::DescriptorImageInfo {
+				sampler: vk::Sampler::null(),
+				image_view: write.image_view,
+				image_layout: write.image_layout,
+			}),
+			_ => None,
 		}
 	}
 }
@@ -486,10 +1758,47 @@ pub struct WriteUniformArgument<'a> {
 	buffer: UntypedBuffer<'a, UniformBufferUsage>,
 }
 
+impl<'a> WriteUniformArgument<'a> {
+	pub(crate) fn size(&self) -> u64 {
+		self.buffer.buffer.size as u64
+	}
+}
+
 pub struct WriteSampledImageArgument {
-	sampler: Arc<rk::image::SamplerInner>,
-	image_view: Arc<rk::image::ImageViewInner>,
-	image_layout: vk::ImageLayout,
+	pub(crate) sampler: Arc<SamplerHandle>,
+	pub(crate) image_view: vk::ImageView,
+	pub(crate) image_layout: vk::ImageLayout,
+}
+
+pub struct WriteSamplerArgument {
+	pub(crate) sampler: Arc<SamplerHandle>,
+}
+
+pub struct WriteSampledImageOnlyArgument {
+	pub(crate) image_view: vk::ImageView,
+	pub(crate) image_layout: vk::ImageLayout,
+}
+
+pub struct WriteAccelerationStructureArgument {
+	pub(crate) acceleration_structure: vk::AccelerationStructureKHR,
+}
+
+pub struct WriteInputAttachmentArgument {
+	pub(crate) image_view: vk::ImageView,
+	pub(crate) image_layout: vk::ImageLayout,
+}
+
+/// Writes a whole storage buffer. Storage buffers are written by handle so that buffers of other
+/// usages that include `STORAGE_BUFFER` can be bound too.
+pub struct WriteStorageBufferArgument {
+	pub(crate) buffer: vk::Buffer,
+	pub(crate) range: u64,
+	pub(crate) gpu_use: Option<GpuUse>,
+}
+
+/// Writes storage images in the `GENERAL` layout, to consecutive array elements of the binding
+pub struct WriteStorageImageArgument {
+	pub(crate) image_views: Vec<vk::ImageView>,
 }
 
 pub(crate) fn parameter_descs_to_raw(
@@ -505,11 +1814,12 @@ pub(crate) fn parameter_descs_to_raw(
 	for (i, parameter) in parameters.iter().enumerate() {
 		bindings.push(vk::VertexInputBindingDescription {
 			binding: i as u32,
-			stride: parameter.attributes.iter().map(|p| p.format.size()).sum(),
+			stride: parameter.stride,
 			input_rate: vk::VertexInputRate::VERTEX,
 		});
-		let mut offset = 0;
+		let mut next = 0;
 		for attribute in &parameter.attributes {
+			let offset = attribute.offset.unwrap_or(next);
 			attributes.push(vk::VertexInputAttributeDescription {
 				location,
 				binding: i as u32,
@@ -517,23 +1827,31 @@ pub(crate) fn parameter_descs_to_raw(
 				offset,
 			});
 			location += 1;
-			offset += attribute.format.size();
+			next = offset + attribute.format.size();
 		}
 	}
 
 	(bindings, attributes)
 }
 
-pub(crate) fn bindings_descs_to_raw(bindings: &[BindingDesc]) -> Vec<vk::DescriptorSetLayoutBinding> {
+pub(crate) fn bindings_descs_to_raw(
+	bindings: &[BindingDesc],
+	stages: vk::ShaderStageFlags,
+) -> Vec<vk::DescriptorSetLayoutBinding> {
 	let mut raw_bindings = Vec::new();
 
 	for (i, binding) in bindings.iter().enumerate() {
+		// Input attachments can only be read from fragment shaders
+		let stages = match binding.binding_type {
+			BindingType::InputAttachment => stages & vk::ShaderStageFlags::FRAGMENT,
+			_ => stages,
+		};
 		raw_bindings.push(
 			vk::DescriptorSetLayoutBinding::builder()
 				.binding(i as u32)
 				.descriptor_type(binding.binding_type.into())
 				.descriptor_count(binding.count)
-				.stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+				.stage_flags(stages)
 				.build(),
 		);
 	}
@@ -544,6 +1862,10 @@ pub(crate) fn bindings_descs_to_raw(bindings: &[BindingDesc]) -> Vec<vk::Descrip
 pub enum WriteBacking {
 	Buffer(Vec<vk::DescriptorBufferInfo>),
 	Image(Vec<vk::DescriptorImageInfo>),
+	AccelerationStructure(
+		Vec<vk::AccelerationStructureKHR>,
+		Box<vk::WriteDescriptorSetAccelerationStructureKHR>,
+	),
 }
 
 pub(crate) fn writes_to_raw(
@@ -554,6 +1876,12 @@ pub(crate) fn writes_to_raw(
 	let mut backing = Vec::new();
 
 	for (i, write) in writes.iter().enumerate() {
+		if let WriteArgument::Array(elements) = write {
+			// Zero-sized arrays have nothing to write
+			if elements.is_empty() {
+				continue;
+			}
+		}
 		let builder = vk::WriteDescriptorSet::builder()
 			.dst_set(set)
 			.dst_binding(i as u32)
@@ -562,7 +1890,7 @@ pub(crate) fn writes_to_raw(
 		let builder = match write {
 			WriteArgument::Uniform(write) => {
 				let buffer_info = vk::DescriptorBufferInfo {
-					buffer: ***write.buffer.buffer.buffer,
+					buffer: write.buffer.buffer.raw(),
 					offset: 0,
 					range: write.buffer.buffer.size as u64,
 				};
@@ -576,7 +1904,66 @@ pub(crate) fn writes_to_raw(
 			WriteArgument::SampledImage(write) => {
 				let image_info = vk::DescriptorImageInfo {
 					sampler: **write.sampler,
-					image_view: **write.image_view,
+					image_view: write.image_view,
+					image_layout: write.image_layout,
+				};
+				backing.push(WriteBacking::Image(vec![image_info]));
+				builder.image_info(if let WriteBacking::Image(image) = backing.last().unwrap() {
+					&image
+				} else {
+					unreachable!()
+				})
+			}
+			WriteArgument::AccelerationStructure(write) => {
+				let acceleration_structures = vec![write.acceleration_structure];
+				let mut info = Box::new(vk::WriteDescriptorSetAccelerationStructureKHR::default());
+				info.acceleration_structure_count = acceleration_structures.len() as u32;
+				info.p_acceleration_structures = acceleration_structures.as_ptr();
+				backing.push(WriteBacking::AccelerationStructure(acceleration_structures, info));
+				let mut raw_write = builder.build();
+				// The acceleration structures are passed through the pNext chain instead of the
+				// usual info arrays, so the descriptor count has to be set by hand
+				if let WriteBacking::AccelerationStructure(structures, info) = backing.last().unwrap() {
+					raw_write.descriptor_count = structures.len() as u32;
+					raw_write.p_next = &**info as *const _ as *const c_void;
+				}
+				raw_writes.push(raw_write);
+				continue;
+			}
+			WriteArgument::StorageBuffer(write) => {
+				let buffer_info = vk::DescriptorBufferInfo {
+					buffer: write.buffer,
+					offset: 0,
+					range: write.range,
+				};
+				backing.push(WriteBacking::Buffer(vec![buffer_info]));
+				builder.buffer_info(if let WriteBacking::Buffer(buffer) = backing.last().unwrap() {
+					&buffer
+				} else {
+					unreachable!()
+				})
+			}
+			WriteArgument::StorageImage(write) => {
+				let image_infos = write
+					.image_views
+					.iter()
+					.map(|&image_view| vk::DescriptorImageInfo {
+						sampler: vk::Sampler::null(),
+						image_view,
+						image_layout: vk::ImageLayout::GENERAL,
+					})
+					.collect();
+				backing.push(WriteBacking::Image(image_infos));
+				builder.image_info(if let WriteBacking::Image(image) = backing.last().unwrap() {
+					&image
+				} else {
+					unreachable!()
+				})
+			}
+			WriteArgument::InputAttachment(write) => {
+				let image_info = vk::DescriptorImageInfo {
+					sampler: vk::Sampler::null(),
+					image_view: write.image_view,
 					image_layout: write.image_layout,
 				};
 				backing.push(WriteBacking::Image(vec![image_info]));
@@ -586,6 +1973,43 @@ pub(crate) fn writes_to_raw(
 					unreachable!()
 				})
 			}
+			WriteArgument::Sampler(_) | WriteArgument::SampledImageOnly(_) => {
+				backing.push(WriteBacking::Image(write.image_info().into_iter().collect()));
+				builder.image_info(if let WriteBacking::Image(image) = backing.last().unwrap() {
+					&image
+				} else {
+					unreachable!()
+				})
+			}
+			WriteArgument::Array(elements) => {
+				let buffer_infos = elements
+					.iter()
+					.map(WriteArgument::buffer_info)
+					.collect::<Option<Vec<_>>>();
+				let image_infos = elements
+					.iter()
+					.map(WriteArgument::image_info)
+					.collect::<Option<Vec<_>>>();
+				match (buffer_infos, image_infos) {
+					(Some(buffer_infos), _) => {
+						backing.push(WriteBacking::Buffer(buffer_infos));
+						builder.buffer_info(if let WriteBacking::Buffer(buffer) = backing.last().unwrap() {
+							&buffer
+						} else {
+							unreachable!()
+						})
+					}
+					(_, Some(image_infos)) => {
+						backing.push(WriteBacking::Image(image_infos));
+						builder.image_info(if let WriteBacking::Image(image) = backing.last().unwrap() {
+							&image
+						} else {
+							unreachable!()
+						})
+					}
+					_ => panic!("Only arrays of buffers, samplers and sampled images can be bound"),
+				}
+			}
 		};
 		raw_writes.push(builder.build());
 	}
@@ -593,6 +2017,17 @@ pub(crate) fn writes_to_raw(
 	(raw_writes, backing)
 }
 
+unsafe impl Binding for Mvp {
+	type Argument = Buffer<UniformBufferUsage, Mvp>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
 mod nalgebra {
 	use super::*;
 	use crate::{
@@ -604,6 +2039,7 @@ mod nalgebra {
 		fn attributes() -> Vec<AttributeDesc> {
 			vec![AttributeDesc {
 				format: AttributeFormat::Vec2F,
+				offset: None,
 			}]
 		}
 	}
@@ -612,6 +2048,7 @@ mod nalgebra {
 		fn attributes() -> Vec<AttributeDesc> {
 			vec![AttributeDesc {
 				format: AttributeFormat::Vec3F,
+				offset: None,
 			}]
 		}
 	}
@@ -620,12 +2057,32 @@ mod nalgebra {
 		fn attributes() -> Vec<AttributeDesc> {
 			vec![AttributeDesc {
 				format: AttributeFormat::Vec4F,
+				offset: None,
 			}]
 		}
 	}
 
+	// Vectors and matrices are plain arrays of floats
+	unsafe impl PushConstantData for Vec2 {}
+	unsafe impl PushConstantData for Vec3 {}
+	unsafe impl PushConstantData for Vec4 {}
+	unsafe impl PushConstantData for Mat4 {}
+
+	// A lone vector or matrix in a uniform block still follows the block's layout rules, so vec3 and
+	// mat3 uniforms are stored padded
 	unsafe impl Binding for Vec3 {
-		type Argument = Buffer<UniformBufferUsage, Vec3>;
+		type Argument = Buffer<UniformBufferUsage, Vec3A>;
+
+		fn description() -> BindingDesc {
+			BindingDesc {
+				binding_type: BindingType::Uniform,
+				count: 1,
+			}
+		}
+	}
+
+	unsafe impl Binding for Mat3 {
+		type Argument = Buffer<UniformBufferUsage, GpuMat3>;
 
 		fn description() -> BindingDesc {
 			BindingDesc {
@@ -645,9 +2102,72 @@ mod nalgebra {
 			}
 		}
 	}
+}
+
+#[cfg(feature = "glam")]
+mod glam {
+	use super::*;
+	use crate::{
+		buffer::{Buffer, UniformBufferUsage},
+		math::GpuMat3,
+	};
+
+	unsafe impl Parameter for ::glam::Vec2 {
+		fn attributes() -> Vec<AttributeDesc> {
+			vec![AttributeDesc {
+				format: AttributeFormat::Vec2F,
+				offset: None,
+			}]
+		}
+	}
+
+	unsafe impl Parameter for ::glam::Vec3 {
+		fn attributes() -> Vec<AttributeDesc> {
+			vec![AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			}]
+		}
+	}
+
+	unsafe impl Parameter for ::glam::Vec4 {
+		fn attributes() -> Vec<AttributeDesc> {
+			vec![AttributeDesc {
+				format: AttributeFormat::Vec4F,
+				offset: None,
+			}]
+		}
+	}
+
+	unsafe impl PushConstantData for ::glam::Vec2 {}
+	unsafe impl PushConstantData for ::glam::Vec3 {}
+	unsafe impl PushConstantData for ::glam::Vec4 {}
+	unsafe impl PushConstantData for ::glam::Mat4 {}
+
+	unsafe impl Binding for ::glam::Vec3 {
+		type Argument = Buffer<UniformBufferUsage, ::glam::Vec3A>;
+
+		fn description() -> BindingDesc {
+			BindingDesc {
+				binding_type: BindingType::Uniform,
+				count: 1,
+			}
+		}
+	}
+
+	unsafe impl Binding for ::glam::Mat3 {
+		type Argument = Buffer<UniformBufferUsage, GpuMat3>;
+
+		fn description() -> BindingDesc {
+			BindingDesc {
+				binding_type: BindingType::Uniform,
+				count: 1,
+			}
+		}
+	}
 
-	unsafe impl Binding for Mvp {
-		type Argument = Buffer<UniformBufferUsage, Mvp>;
+	unsafe impl Binding for ::glam::Mat4 {
+		type Argument = Buffer<UniformBufferUsage, ::glam::Mat4>;
 
 		fn description() -> BindingDesc {
 			BindingDesc {
@@ -0,0 +1,698 @@
+//! Color grading with 3D lookup tables, the way film-style grades are usually authored and shared.
+//!
+//! A `CubeLut` is read from the `.cube` files grading tools export, uploaded into a 3D texture as a
+//! `ColorLut`, and applied to a finished image by a `ColorGrader` post pass, usually right after
+//! tone mapping.
+
+use std::{marker::PhantomData, path::Path, sync::Arc};
+
+use rk::vk;
+use thiserror::Error;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	builtin::{self, CachedArguments, FullscreenTriangle},
+	function::{
+		compile_shader, Argument, ArgumentsContainer, Binding, BindingDesc, BindingType, FunctionDef, FunctionImpl,
+		FunctionOptions, FunctionPrototype, WriteArgument, WriteSampledImageArgument,
+	},
+	image::{DynImageUsage, FormatType, ImageHandle, ImageViewHandle, SampleCount1, Sampler, SamplerHandle},
+	math::*,
+	pass::{ColorAttachment, ColorClearValue, NoDepthAttachment, RenderPass, RenderPassPrototype},
+	raw_device,
+	render::RenderEngine,
+	shader::ShaderStage,
+	sync::{self, ImageTransition},
+	target::Target,
+	Context, MarsResult,
+};
+
+const GRADING_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = position * 0.5 + 0.5;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const GRADING_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(constant_id = 0) const uint INPUT = 0;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform sampler3D lut;
+layout(set = 0, binding = 2) uniform Grading {
+	vec3 domain_min;
+	float strength;
+	vec3 domain_max;
+	float size;
+};
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+vec3 encode_srgb(vec3 x) {
+	x = clamp(x, 0.0, 1.0);
+	return mix(x * 12.92, 1.055 * pow(x, vec3(1.0 / 2.4)) - 0.055, greaterThan(x, vec3(0.0031308)));
+}
+
+vec3 decode_srgb(vec3 x) {
+	return mix(x / 12.92, pow((x + 0.055) / 1.055, vec3(2.4)), greaterThan(x, vec3(0.04045)));
+}
+
+void main() {
+	vec4 original = texture(source, uv);
+	vec3 input_color = INPUT == 0 ? original.rgb : encode_srgb(original.rgb);
+	vec3 coord = clamp((input_color - domain_min) / (domain_max - domain_min), 0.0, 1.0);
+	// The first and last texels hold the ends of the domain, so coordinates are scaled to land on
+	// texel centers
+	coord = (coord * (size - 1.0) + 0.5) / size;
+	vec3 graded = textureLod(lut, coord, 0.0).rgb;
+	if (INPUT == 1) {
+		graded = decode_srgb(graded);
+	}
+	color = vec4(mix(original.rgb, graded, strength), original.a);
+}
+"#;
+
+/// The largest LUT size `.cube` files can have
+const MAX_LUT_SIZE: u32 = 256;
+
+#[derive(Debug, Error)]
+pub enum CubeError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+	#[error("Line {line} of the .cube file is invalid: {reason}")]
+	Malformed { line: usize, reason: &'static str },
+	#[error("The .cube file has no LUT_3D_SIZE")]
+	MissingSize,
+	#[error("LUTs of size {0} aren't supported, only sizes from 2 to 256")]
+	UnsupportedSize(u32),
+	#[error("The .cube file holds a 1D LUT, but only 3D LUTs are supported")]
+	OneDimensional,
+	#[error("The .cube file has {found} entries but a LUT of its size has {expected}")]
+	WrongEntryCount { expected: usize, found: usize },
+	#[error("The .cube file's DOMAIN_MAX isn't greater than its DOMAIN_MIN")]
+	EmptyDomain,
+}
+
+/// A 3D lookup table in memory, mapping colors within the cube from `domain_min` to `domain_max`
+/// to the color of the nearest entries, interpolated
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeLut {
+	pub title: Option<String>,
+	pub domain_min: Vec3,
+	pub domain_max: Vec3,
+	size: u32,
+	entries: Vec<Vec3>,
+}
+
+impl CubeLut {
+	/// A LUT of `size` entries along each axis that leaves colors unchanged. Sizes of 32 or 33 are
+	/// typical.
+	pub fn identity(size: u32) -> Self {
+		assert!(size >= 2 && size <= MAX_LUT_SIZE);
+		let step = 1.0 / (size - 1) as f32;
+		let mut entries = Vec::with_capacity(size as usize * size as usize * size as usize);
+		for b in 0..size {
+			for g in 0..size {
+				for r in 0..size {
+					entries.push(Vec3::new(r as f32, g as f32, b as f32) * step);
+				}
+			}
+		}
+		Self {
+			title: None,
+			domain_min: Vec3::zeros(),
+			domain_max: Vec3::repeat(1.0),
+			size,
+			entries,
+		}
+	}
+
+	/// Parses the contents of a `.cube` file in the format of Adobe's Cube LUT specification
+	pub fn parse(text: &str) -> Result<Self, CubeError> {
+		let mut title = None;
+		let mut domain_min = Vec3::zeros();
+		let mut domain_max = Vec3::repeat(1.0);
+		let mut size = None;
+		let mut entries = Vec::new();
+
+		for (index, line) in text.lines().enumerate() {
+			let line_number = index + 1;
+			let malformed = |reason| CubeError::Malformed {
+				line: line_number,
+				reason,
+			};
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let mut words = line.split_whitespace();
+			let keyword = words.next().unwrap();
+			match keyword {
+				"TITLE" => {
+					let rest = line["TITLE".len()..].trim();
+					title = Some(rest.trim_matches('"').to_owned());
+				}
+				"LUT_3D_SIZE" => {
+					let value = words
+						.next()
+						.and_then(|word| word.parse::<u32>().ok())
+						.ok_or_else(|| malformed("LUT_3D_SIZE needs an integer size"))?;
+					if value < 2 || value > MAX_LUT_SIZE {
+						return Err(CubeError::UnsupportedSize(value));
+					}
+					size = Some(value);
+				}
+				"LUT_1D_SIZE" => return Err(CubeError::OneDimensional),
+				"DOMAIN_MIN" => {
+					domain_min = parse_triple(&mut words).ok_or_else(|| malformed("DOMAIN_MIN needs three numbers"))?
+				}
+				"DOMAIN_MAX" => {
+					domain_max = parse_triple(&mut words).ok_or_else(|| malformed("DOMAIN_MAX needs three numbers"))?
+				}
+				_ if keyword.parse::<f32>().is_ok() => {
+					if size.is_none() {
+						return Err(malformed("entries come before LUT_3D_SIZE"));
+					}
+					let entry = parse_triple(&mut line.split_whitespace())
+						.ok_or_else(|| malformed("entries need three numbers"))?;
+					entries.push(entry);
+				}
+				// Tools add keywords of their own, which don't affect the table
+				_ if entries.is_empty() => {}
+				_ => return Err(malformed("keywords must come before the entries")),
+			}
+		}
+
+		let size = size.ok_or(CubeError::MissingSize)?;
+		let expected = size as usize * size as usize * size as usize;
+		if entries.len() != expected {
+			return Err(CubeError::WrongEntryCount {
+				expected,
+				found: entries.len(),
+			});
+		}
+		if (0..3).any(|i| domain_max[i] <= domain_min[i]) {
+			return Err(CubeError::EmptyDomain);
+		}
+		Ok(Self {
+			title,
+			domain_min,
+			domain_max,
+			size,
+			entries,
+		})
+	}
+
+	/// Reads and parses a `.cube` file
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, CubeError> {
+		Self::parse(&std::fs::read_to_string(path)?)
+	}
+
+	/// The number of entries along each axis
+	pub fn size(&self) -> u32 {
+		self.size
+	}
+
+	/// The output colors, with red changing fastest, then green, then blue
+	pub fn entries(&self) -> &[Vec3] {
+		&self.entries
+	}
+}
+
+/// Parses the next three words as the components of a color, ignoring anything after them
+fn parse_triple<'a>(words: &mut impl Iterator<Item = &'a str>) -> Option<Vec3> {
+	let mut component = || words.next()?.parse::<f32>().ok();
+	Some(Vec3::new(component()?, component()?, component()?))
+}
+
+/// A `CubeLut` uploaded into a 3D texture, ready for a `ColorGrader` to sample
+pub struct ColorLut {
+	// Kept alive for the view
+	_image: ImageHandle,
+	view: ImageViewHandle,
+	sampler: Sampler,
+	size: u32,
+	domain_min: Vec3,
+	domain_max: Vec3,
+}
+
+impl ColorLut {
+	/// Uploads `lut` into a half float 3D texture, waiting for the upload to complete
+	pub fn create(context: &Context, lut: &CubeLut) -> MarsResult<Self> {
+		let size = lut.size;
+		let format = vk::Format::R16G16B16A16_SFLOAT;
+		let create_info = vk::ImageCreateInfo::builder()
+			.image_type(vk::ImageType::TYPE_3D)
+			.format(format)
+			.extent(vk::Extent3D {
+				width: size,
+				height: size,
+				depth: size,
+			})
+			.mip_levels(1)
+			.array_layers(1)
+			.samples(vk::SampleCountFlags::TYPE_1)
+			.tiling(vk::ImageTiling::OPTIMAL)
+			.usage((DynImageUsage::SAMPLED | DynImageUsage::TRANSFER_DST).as_raw())
+			.sharing_mode(vk::SharingMode::EXCLUSIVE)
+			.initial_layout(vk::ImageLayout::UNDEFINED);
+		let image = ImageHandle::create(
+			context,
+			&create_info,
+			&[vk::MemoryPropertyFlags::DEVICE_LOCAL],
+			vk::ExternalMemoryHandleTypeFlags::empty(),
+		)?;
+
+		// The entries are in the same order as the texels of the image, red along X
+		let texels = lut
+			.entries
+			.iter()
+			.map(|entry| {
+				[
+					f32_to_f16(entry.x),
+					f32_to_f16(entry.y),
+					f32_to_f16(entry.z),
+					f32_to_f16(1.0),
+				]
+			})
+			.collect::<Vec<[u16; 4]>>();
+		let data = unsafe { std::slice::from_raw_parts(texels.as_ptr() as *const u8, std::mem::size_of_val(&*texels)) };
+		let staged = context.staging.stage(context, data, 8)?;
+
+		let to_transfer = ImageTransition {
+			aspect: vk::ImageAspectFlags::COLOR,
+			src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			src_access_mask: vk::AccessFlags2KHR::NONE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::UNDEFINED,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		let to_sampled = ImageTransition {
+			aspect: vk::ImageAspectFlags::COLOR,
+			src_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+			src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+			old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		};
+		let region = vk::BufferImageCopy {
+			buffer_offset: staged.offset,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+			image_extent: vk::Extent3D {
+				width: size,
+				height: size,
+				depth: size,
+			},
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, image.raw, &to_transfer);
+			raw_device(&context.device).cmd_copy_buffer_to_image(
+				command_buffer,
+				staged.buffer,
+				image.raw,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&[region],
+			);
+			sync::record_image_transition(context, command_buffer, image.raw, &to_sampled);
+		})?;
+
+		let view = ImageViewHandle::create(
+			&image,
+			vk::ImageViewType::TYPE_3D,
+			format,
+			vk::ImageSubresourceRange {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				base_mip_level: 0,
+				level_count: 1,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+		)?;
+		let sampler = Sampler::create(context)?;
+		Ok(Self {
+			_image: image,
+			view,
+			sampler,
+			size,
+			domain_min: lut.domain_min,
+			domain_max: lut.domain_max,
+		})
+	}
+
+	/// The number of entries along each axis
+	pub fn size(&self) -> u32 {
+		self.size
+	}
+}
+
+/// Converts to the bits of the nearest half float, saturating to infinity
+fn f32_to_f16(value: f32) -> u16 {
+	let bits = value.to_bits();
+	let sign = ((bits >> 16) & 0x8000) as u16;
+	let exponent = ((bits >> 23) & 0xff) as i32;
+	let mantissa = bits & 0x7f_ffff;
+	if exponent == 0xff {
+		let nan = if mantissa != 0 { 0x200 } else { 0 };
+		return sign | 0x7c00 | nan;
+	}
+	let exponent = exponent - 127 + 15;
+	if exponent >= 0x1f {
+		sign | 0x7c00
+	} else if exponent <= 0 {
+		// Too small for a normal half float, so it's denormalized, or zero if it's too small for that
+		if exponent < -10 {
+			return sign;
+		}
+		let mantissa = mantissa | 0x80_0000;
+		let shift = (14 - exponent) as u32;
+		sign | ((mantissa + (1 << (shift - 1))) >> shift) as u16
+	} else {
+		// Rounding can carry into the exponent, which is still the right result
+		let half = ((exponent as u32) << 10 | mantissa >> 13) + ((mantissa >> 12) & 1);
+		sign | half as u16
+	}
+}
+
+/// How the colors a LUT maps were encoded when it was authored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LutInput {
+	/// The LUT maps linear colors, as sampled from the source
+	Linear,
+	/// The LUT maps sRGB encoded colors, like most LUTs made for display referred images. Colors
+	/// are encoded before the lookup and decoded after.
+	Srgb,
+}
+
+impl LutInput {
+	fn specialization_constant(self) -> u32 {
+		match self {
+			LutInput::Linear => 0,
+			LutInput::Srgb => 1,
+		}
+	}
+}
+
+/// The render pass color grading writes into, with a single color attachment of format `F`
+pub struct ColorGradePass<F>(PhantomData<F>);
+
+impl<F> RenderPassPrototype for ColorGradePass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<F>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+pub struct ColorGradeFunction<F>(PhantomData<F>);
+
+impl<F> FunctionPrototype for ColorGradeFunction<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type RenderPass = ColorGradePass<F>;
+	type VertexInput = Vec2;
+	type Bindings = (GradeInput, GradeLut, GradeParams);
+	type PushConstants = ();
+}
+
+/// The attachment being graded, sampled by the grading shader
+pub struct GradeInput;
+
+unsafe impl Binding for GradeInput {
+	type Argument = GradeImageArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+/// The 3D texture of a `ColorLut`
+pub struct GradeLut;
+
+unsafe impl Binding for GradeLut {
+	type Argument = GradeImageArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+pub struct GradeImageArgument {
+	sampler: Arc<SamplerHandle>,
+	image_view: vk::ImageView,
+}
+
+impl Argument for GradeImageArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		})
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct GradeParams {
+	domain_min: Vec3,
+	strength: f32,
+	domain_max: Vec3,
+	size: f32,
+}
+
+unsafe impl Binding for GradeParams {
+	type Argument = Buffer<UniformBufferUsage, GradeParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+/// Applies `ColorLut`s to color attachments, writing the graded image into targets with a color
+/// attachment of format `F`
+pub struct ColorGrader<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	render_pass: RenderPass<ColorGradePass<F>>,
+	function: FunctionDef<ColorGradeFunction<F>>,
+	sampler: Sampler,
+	// Keyed by the views of the attachment and LUT they read
+	arguments: CachedArguments<(vk::ImageView, vk::ImageView), ArgumentsContainer<ColorGradeFunction<F>>>,
+	triangle: FullscreenTriangle,
+}
+
+impl<F> ColorGrader<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	/// Creates a grader for LUTs authored against colors encoded as `input`
+	pub fn create(context: &Context, input: LutInput) -> MarsResult<Self> {
+		let render_pass = RenderPass::create(context)?;
+		let vert = compile_shader(GRADING_VERTEX_SHADER, "grading.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(GRADING_FRAGMENT_SHADER, "grading.frag", ShaderStage::Fragment)?;
+		let function = FunctionDef::create_with_options(
+			context,
+			&render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			&FunctionOptions {
+				specialization_constants: vec![input.specialization_constant()],
+				..Default::default()
+			},
+		)?;
+		let sampler = Sampler::create(context)?;
+		let triangle = FullscreenTriangle::create(context)?;
+
+		Ok(Self {
+			render_pass,
+			function,
+			sampler,
+			arguments: CachedArguments::new(),
+			triangle,
+		})
+	}
+
+	/// The render pass to create grading targets with
+	pub fn render_pass(&self) -> &RenderPass<ColorGradePass<F>> {
+		&self.render_pass
+	}
+
+	/// Grades `source` with `lut` into the color attachment of `target`, blending between the
+	/// original colors at a `strength` of 0 and the fully graded ones at 1. The source must have
+	/// been created with the `SAMPLED` usage.
+	pub fn apply<S: FormatType>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		source: &mut ColorAttachment<S>,
+		lut: &ColorLut,
+		strength: f32,
+		target: &mut Target<ColorGradePass<F>>,
+	) -> MarsResult<()> {
+		assert!(
+			source.image.usage().contains(DynImageUsage::SAMPLED),
+			"graded attachments must be sampleable"
+		);
+
+		let params = GradeParams {
+			domain_min: lut.domain_min,
+			strength,
+			domain_max: lut.domain_max,
+			size: lut.size as f32,
+		};
+		let views = (source.view.image_view.raw, lut.view.raw);
+		let (function, sampler) = (&mut self.function, &self.sampler);
+		let arguments = self.arguments.make_or_update(
+			views,
+			|| {
+				let graded = GradeImageArgument {
+					sampler: sampler.sampler.clone(),
+					image_view: views.0,
+				};
+				let table = GradeImageArgument {
+					sampler: lut.sampler.sampler.clone(),
+					image_view: views.1,
+				};
+				function.make_arguments(context, (graded, table, Buffer::make_item_buffer(context, params)?))
+			},
+			|arguments| arguments.arguments.2.record_upload(context, engine, params),
+		)?;
+
+		let draw = self.triangle.draw_args(arguments);
+		let stage = vk::PipelineStageFlags2KHR::FRAGMENT_SHADER;
+		builtin::sample_color_attachment(context, engine, &mut source.image, stage, |engine| {
+			engine.pass(context, target, function, Some(draw))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SMALL_CUBE: &str = "# A comment
+TITLE \"Small\"
+LUT_3D_SIZE 2
+DOMAIN_MIN 0 0 0
+DOMAIN_MAX 1 1 1
+
+0 0 0
+1 0 0
+0 1 0
+1 1 0
+0 0 1
+1 0 1
+0 1 1
+1 1 1
+";
+
+	#[test]
+	fn parse_reads_a_cube_file() {
+		let lut = CubeLut::parse(SMALL_CUBE).unwrap();
+		assert_eq!(lut.title.as_deref(), Some("Small"));
+		assert_eq!(lut.size(), 2);
+		assert_eq!(
+			lut,
+			CubeLut {
+				title: lut.title.clone(),
+				..CubeLut::identity(2)
+			}
+		);
+	}
+
+	#[test]
+	fn parse_rejects_bad_files() {
+		assert!(matches!(
+			CubeLut::parse("TITLE \"Empty\"\n"),
+			Err(CubeError::MissingSize)
+		));
+		assert!(matches!(
+			CubeLut::parse("0 0 0\nLUT_3D_SIZE 2\n"),
+			Err(CubeError::Malformed { line: 1, .. })
+		));
+		assert!(matches!(
+			CubeLut::parse("LUT_3D_SIZE 1\n"),
+			Err(CubeError::UnsupportedSize(1))
+		));
+		assert!(matches!(
+			CubeLut::parse("LUT_1D_SIZE 16\n"),
+			Err(CubeError::OneDimensional)
+		));
+		assert!(matches!(
+			CubeLut::parse("LUT_3D_SIZE 2\n0 0 0\n"),
+			Err(CubeError::WrongEntryCount { expected: 8, found: 1 })
+		));
+		assert!(matches!(
+			CubeLut::parse("LUT_3D_SIZE two\n"),
+			Err(CubeError::Malformed { line: 1, .. })
+		));
+		assert!(matches!(
+			CubeLut::parse("LUT_3D_SIZE 2\n0 0\n"),
+			Err(CubeError::Malformed { line: 2, .. })
+		));
+		let reversed = SMALL_CUBE.replace("DOMAIN_MAX 1 1 1", "DOMAIN_MAX 0 1 1");
+		assert!(matches!(CubeLut::parse(&reversed), Err(CubeError::EmptyDomain)));
+		let late_keyword = format!("{}LUT_3D_INPUT_RANGE 0 1\n", SMALL_CUBE);
+		assert!(matches!(
+			CubeLut::parse(&late_keyword),
+			Err(CubeError::Malformed { line: 15, .. })
+		));
+	}
+
+	#[test]
+	fn f32_to_f16_converts_normal_values() {
+		assert_eq!(f32_to_f16(0.0), 0x0000);
+		assert_eq!(f32_to_f16(-0.0), 0x8000);
+		assert_eq!(f32_to_f16(1.0), 0x3c00);
+		assert_eq!(f32_to_f16(0.5), 0x3800);
+		assert_eq!(f32_to_f16(-2.0), 0xc000);
+		assert_eq!(f32_to_f16(65504.0), 0x7bff);
+		assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+	}
+
+	#[test]
+	fn f32_to_f16_handles_special_values() {
+		assert_eq!(f32_to_f16(1.0e6), 0x7c00);
+		assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+		assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+		assert_eq!(f32_to_f16(f32::NAN) & 0x7e00, 0x7e00);
+		assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+		assert_eq!(f32_to_f16(2f32.powi(-26)), 0x0000);
+	}
+}
@@ -0,0 +1,439 @@
+//! An immediate mode overlay of rectangles and text in pixel coordinates, for things like frame
+//! time counters and debug readouts.
+//!
+//! Rectangles and text are added to a `Hud` during a frame and drawn over a target, in the order
+//! they were added, by `Hud::draw`, which also clears them. Coordinates are in pixels from the top
+//! left corner of the target. Text is drawn with a built-in font of 3x5 pixel glyphs covering
+//! digits, letters (lowercase letters are drawn as uppercase) and common punctuation, scaled up by
+//! whole pixels to stay crisp.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use rk::vk;
+
+use crate::{
+	atlas::UvRect,
+	buffer::{Buffer, IndexBufferUsage, VertexBufferUsage},
+	color::Rgba,
+	function::{
+		compile_shader, Argument, ArgumentsContainer, AttributeDesc, AttributeFormat, Binding, BindingDesc,
+		BindingType, DepthTest, FunctionDef, FunctionImpl, FunctionOptions, FunctionPrototype, Parameter,
+		WriteArgument, WriteSampledImageArgument,
+	},
+	image::{format::R8G8B8A8Unorm, usage, FormatType, Image, MipmapMode, SampledImage, SamplerHandle},
+	math::*,
+	pass::{RenderPass, RenderPassPrototype},
+	render::{DrawArgs, RenderEngine},
+	shader::ShaderStage,
+	target::Target,
+	Context, MarsResult,
+};
+
+const HUD_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec4 color;
+
+layout(location = 0) out vec2 f_uv;
+layout(location = 1) out vec4 f_color;
+
+void main() {
+	f_uv = uv;
+	f_color = color;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const HUD_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D tex;
+
+layout(location = 0) in vec2 f_uv;
+layout(location = 1) in vec4 f_color;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	color = texture(tex, f_uv) * f_color;
+}
+"#;
+
+/// The width and height of glyphs of the built-in font, in font pixels
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+/// The distance between the starts of consecutive characters and lines, in font pixels
+const ADVANCE: f32 = 4.0;
+const LINE_HEIGHT: f32 = 6.0;
+
+/// The glyph of `c` in the built-in font, as five rows of three bits from top to bottom, with the
+/// leftmost pixel of each row in its highest bit
+fn glyph(c: char) -> u16 {
+	match c.to_ascii_uppercase() {
+		'0' => 0b111_101_101_101_111,
+		'1' => 0b010_110_010_010_111,
+		'2' => 0b111_001_111_100_111,
+		'3' => 0b111_001_111_001_111,
+		'4' => 0b101_101_111_001_001,
+		'5' => 0b111_100_111_001_111,
+		'6' => 0b111_100_111_101_111,
+		'7' => 0b111_001_001_001_001,
+		'8' => 0b111_101_111_101_111,
+		'9' => 0b111_101_111_001_111,
+		'A' => 0b010_101_111_101_101,
+		'B' => 0b110_101_110_101_110,
+		'C' => 0b011_100_100_100_011,
+		'D' => 0b110_101_101_101_110,
+		'E' => 0b111_100_110_100_111,
+		'F' => 0b111_100_110_100_100,
+		'G' => 0b011_100_101_101_011,
+		'H' => 0b101_101_111_101_101,
+		'I' => 0b111_010_010_010_111,
+		'J' => 0b001_001_001_101_010,
+		'K' => 0b101_101_110_101_101,
+		'L' => 0b100_100_100_100_111,
+		'M' => 0b101_111_111_101_101,
+		'N' => 0b110_101_101_101_101,
+		'O' => 0b010_101_101_101_010,
+		'P' => 0b110_101_110_100_100,
+		'Q' => 0b010_101_101_110_011,
+		'R' => 0b110_101_110_101_101,
+		'S' => 0b011_100_010_001_110,
+		'T' => 0b111_010_010_010_010,
+		'U' => 0b101_101_101_101_111,
+		'V' => 0b101_101_101_101_010,
+		'W' => 0b101_101_111_111_101,
+		'X' => 0b101_101_010_101_101,
+		'Y' => 0b101_101_010_010_010,
+		'Z' => 0b111_001_010_100_111,
+		' ' => 0,
+		'.' => 0b000_000_000_000_010,
+		',' => 0b000_000_000_010_100,
+		':' => 0b000_010_000_010_000,
+		';' => 0b000_010_000_010_100,
+		'-' => 0b000_000_111_000_000,
+		'+' => 0b000_010_111_010_000,
+		'=' => 0b000_111_000_111_000,
+		'_' => 0b000_000_000_000_111,
+		'/' => 0b001_001_010_100_100,
+		'%' => 0b101_001_010_100_101,
+		'(' => 0b010_100_100_100_010,
+		')' => 0b010_001_001_001_010,
+		'[' => 0b110_100_100_100_110,
+		']' => 0b011_001_001_001_011,
+		'<' => 0b001_010_100_010_001,
+		'>' => 0b100_010_001_010_100,
+		'!' => 0b010_010_010_000_010,
+		'\'' => 0b010_010_000_000_000,
+		'"' => 0b101_101_000_000_000,
+		'#' => 0b101_111_101_111_101,
+		'*' => 0b000_101_010_101_000,
+		_ => 0b110_001_010_000_010,
+	}
+}
+
+/// The size in pixels of `text` drawn by `Hud::text` at `scale`
+pub fn text_size(text: &str, scale: u32) -> Vec2 {
+	let lines = text.split('\n');
+	let (count, longest) = lines.fold((0, 0), |(count, longest), line| {
+		(count + 1, longest.max(line.chars().count()))
+	});
+	let scale = scale as f32;
+	let width = if longest > 0 {
+		((longest - 1) as f32 * ADVANCE + GLYPH_WIDTH as f32) * scale
+	} else {
+		0.0
+	};
+	let height = ((count - 1) as f32 * LINE_HEIGHT + GLYPH_HEIGHT as f32) * scale;
+	Vec2::new(width, height)
+}
+
+/// A corner of a HUD rectangle
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct HudVertex {
+	/// In pixels until drawn, when it's mapped to normalized device coordinates
+	pub position: Vec2,
+	pub uv: Vec2,
+	pub color: Rgba,
+}
+
+unsafe impl Parameter for HudVertex {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![
+			AttributeDesc {
+				format: AttributeFormat::Vec2F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec2F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec4F,
+				offset: None,
+			},
+		]
+	}
+}
+
+pub struct HudFunction<P>(PhantomData<P>);
+
+impl<P> FunctionPrototype for HudFunction<P>
+where
+	P: RenderPassPrototype,
+{
+	type RenderPass = P;
+	type VertexInput = HudVertex;
+	type Bindings = (HudTextureBinding,);
+	type PushConstants = ();
+}
+
+/// The texture a batch of rectangles is drawn with
+pub struct HudTextureBinding;
+
+unsafe impl Binding for HudTextureBinding {
+	type Argument = HudTextureArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+/// The image must outlive the arguments this is written to
+pub struct HudTextureArgument {
+	sampler: Arc<SamplerHandle>,
+	image_view: vk::ImageView,
+	image_layout: vk::ImageLayout,
+}
+
+impl Argument for HudTextureArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: self.image_layout,
+		})
+	}
+}
+
+/// A texture added to a `Hud` with `Hud::add_texture`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HudTexture(usize);
+
+/// Rectangles using the same texture added one after the other, drawn together
+struct HudBatch {
+	texture: usize,
+	/// Four corners per rectangle, in pixels
+	vertices: Vec<HudVertex>,
+}
+
+/// An overlay drawn over targets of render passes of prototype `P`. See the module documentation.
+pub struct Hud<P: RenderPassPrototype> {
+	function: FunctionDef<HudFunction<P>>,
+	/// The arguments of each added texture, `None` once removed. The first is a white texel that
+	/// solid rectangles and text are drawn with.
+	textures: Vec<Option<ArgumentsContainer<HudFunction<P>>>>,
+	_white: SampledImage<R8G8B8A8Unorm>,
+	batches: Vec<HudBatch>,
+	// Reused between frames by batches drawing the same number of rectangles, since draws always
+	// use every index of their index buffer
+	buffers: Vec<(Buffer<VertexBufferUsage, [HudVertex]>, Buffer<IndexBufferUsage, [u32]>)>,
+}
+
+impl<P> Hud<P>
+where
+	P: RenderPassPrototype,
+{
+	pub fn create(context: &Context, render_pass: &RenderPass<P>) -> MarsResult<Self> {
+		let vert = compile_shader(HUD_VERTEX_SHADER, "hud.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(HUD_FRAGMENT_SHADER, "hud.frag", ShaderStage::Fragment)?;
+		let mut function = FunctionDef::create_with_options(
+			context,
+			render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			&FunctionOptions {
+				depth_test: DepthTest::Always,
+				..Default::default()
+			},
+		)?;
+		let white = Image::make_image_from_pixels(
+			context,
+			usage::SampledImage,
+			vk::Extent2D { width: 1, height: 1 },
+			&[[255, 255, 255, 255]],
+			MipmapMode::None,
+		)?;
+		let white = SampledImage::create(context, white)?;
+		let white_arguments = texture_arguments(context, &mut function, &white)?;
+		Ok(Self {
+			function,
+			textures: vec![Some(white_arguments)],
+			_white: white,
+			batches: Vec::new(),
+			buffers: Vec::new(),
+		})
+	}
+
+	/// Makes `image` available to `textured_rect`. The image must outlive the HUD, or be removed
+	/// with `remove_texture` before it's dropped.
+	pub fn add_texture<F: FormatType>(&mut self, context: &Context, image: &SampledImage<F>) -> MarsResult<HudTexture> {
+		let arguments = texture_arguments(context, &mut self.function, image)?;
+		self.textures.push(Some(arguments));
+		Ok(HudTexture(self.textures.len() - 1))
+	}
+
+	/// Removes a texture added with `add_texture`, after which it can't be drawn with anymore. Must
+	/// not be called between drawing with the texture and the next `draw`.
+	pub fn remove_texture(&mut self, texture: HudTexture) {
+		assert_ne!(texture.0, 0, "not a texture added with `add_texture`");
+		self.textures[texture.0] = None;
+	}
+
+	/// Removes everything added since the last draw
+	pub fn clear(&mut self) {
+		self.batches.clear();
+	}
+
+	/// A solid rectangle with its top left corner at `position`
+	pub fn rect(&mut self, position: Vec2, size: Vec2, color: Rgba) {
+		self.push_rect(0, position, size, Vec2::zeros(), Vec2::new(1.0, 1.0), color);
+	}
+
+	/// A rectangle with its top left corner at `position`, showing the region `uv` of `texture`
+	/// multiplied by `tint`
+	pub fn textured_rect(&mut self, texture: HudTexture, position: Vec2, size: Vec2, uv: UvRect, tint: Rgba) {
+		assert!(
+			self.textures.get(texture.0).map_or(false, Option::is_some),
+			"drew with a texture that was removed"
+		);
+		self.push_rect(texture.0, position, size, uv.min, uv.max, tint);
+	}
+
+	/// `text` with the top left corner of its first character at `position`, with each pixel of the
+	/// font `scale` pixels wide. Lines are separated by `\n`.
+	pub fn text(&mut self, position: Vec2, text: &str, scale: u32, color: Rgba) {
+		let scale = scale as f32;
+		let mut origin = position;
+		for c in text.chars() {
+			if c == '\n' {
+				origin = Vec2::new(position.x, origin.y + LINE_HEIGHT * scale);
+				continue;
+			}
+			let glyph = glyph(c);
+			for row in 0..GLYPH_HEIGHT {
+				for column in 0..GLYPH_WIDTH {
+					let bit = (GLYPH_HEIGHT - 1 - row) * GLYPH_WIDTH + (GLYPH_WIDTH - 1 - column);
+					if glyph & (1 << bit) != 0 {
+						let pixel = origin + Vec2::new(column as f32, row as f32) * scale;
+						self.rect(pixel, Vec2::new(scale, scale), color);
+					}
+				}
+			}
+			origin.x += ADVANCE * scale;
+		}
+	}
+
+	fn push_rect(&mut self, texture: usize, position: Vec2, size: Vec2, uv_min: Vec2, uv_max: Vec2, color: Rgba) {
+		if self.batches.last().map(|batch| batch.texture) != Some(texture) {
+			self.batches.push(HudBatch {
+				texture,
+				vertices: Vec::new(),
+			});
+		}
+		let vertex = |x: f32, y: f32, u: f32, v: f32| HudVertex {
+			position: Vec2::new(x, y),
+			uv: Vec2::new(u, v),
+			color,
+		};
+		let (min, max) = (position, position + size);
+		let batch = self.batches.last_mut().unwrap();
+		batch.vertices.extend_from_slice(&[
+			vertex(min.x, min.y, uv_min.x, uv_min.y),
+			vertex(max.x, min.y, uv_max.x, uv_min.y),
+			vertex(max.x, max.y, uv_max.x, uv_max.y),
+			vertex(min.x, max.y, uv_min.x, uv_max.y),
+		]);
+	}
+
+	/// Draws everything added since the last draw over `target`, then clears it
+	pub fn draw(&mut self, context: &Context, engine: &mut RenderEngine, target: &mut Target<P>) -> MarsResult<()> {
+		if self.batches.is_empty() {
+			return Ok(());
+		}
+		let result = self.draw_batches(context, engine, target);
+		self.batches.clear();
+		result
+	}
+
+	fn draw_batches(&mut self, context: &Context, engine: &mut RenderEngine, target: &mut Target<P>) -> MarsResult<()> {
+		// Clip space has +Y pointing down like pixel coordinates, so only the scale differs
+		let extent = target.attachments().extent();
+		let scale = Vec2::new(2.0 / extent.width as f32, 2.0 / extent.height as f32);
+		for (i, batch) in self.batches.iter_mut().enumerate() {
+			for vertex in &mut batch.vertices {
+				vertex.position = vertex.position.component_mul(&scale) - Vec2::new(1.0, 1.0);
+			}
+			match self.buffers.get(i) {
+				Some((vertices, _)) if vertices.len == batch.vertices.len() => {
+					vertices.record_upload(context, engine, 0, &batch.vertices)?
+				}
+				_ => {
+					let indices = (0..batch.vertices.len() as u32 / 4)
+						.flat_map(|rect| {
+							let first = rect * 4;
+							vec![first, first + 1, first + 2, first + 2, first + 3, first]
+						})
+						.collect::<Vec<_>>();
+					let buffers = (
+						Buffer::make_array_buffer(context, &batch.vertices)?,
+						Buffer::make_array_buffer(context, &indices)?,
+					);
+					if i < self.buffers.len() {
+						self.buffers[i] = buffers;
+					} else {
+						self.buffers.push(buffers);
+					}
+				}
+			}
+		}
+
+		let textures = &self.textures;
+		let draws = self
+			.batches
+			.iter()
+			.zip(&self.buffers)
+			.map(|(batch, (vertices, indices))| DrawArgs {
+				bindings: textures[batch.texture].as_ref().unwrap(),
+				vertices,
+				indices,
+				push_constants: (),
+			})
+			.collect::<Vec<_>>();
+		engine.pass(context, target, &self.function, draws)
+	}
+}
+
+fn texture_arguments<P, F>(
+	context: &Context,
+	function: &mut FunctionDef<HudFunction<P>>,
+	image: &SampledImage<F>,
+) -> MarsResult<ArgumentsContainer<HudFunction<P>>>
+where
+	P: RenderPassPrototype,
+	F: FormatType,
+{
+	function.make_arguments(
+		context,
+		(HudTextureArgument {
+			sampler: image.sampler.sampler.clone(),
+			image_view: image.image_view.image_view.raw,
+			image_layout: image.image.layout,
+		},),
+	)
+}
@@ -0,0 +1,493 @@
+//! Image based lighting: baking an environment cubemap into the maps PBR shading samples for
+//! ambient light.
+//!
+//! `IblBaker` holds the compute functions that do the baking, and is created once and reused for
+//! every environment. It bakes
+//! - an irradiance cubemap, the cosine-weighted convolution of the environment, for diffuse light
+//! - a prefiltered cubemap, whose mip levels are the environment convolved with the GGX
+//!   distribution of increasing roughness, from 0 at the base level to 1 at the last, for specular
+//!   light
+//! - a BRDF lookup table of the scale and bias to the Fresnel reflectance at normal incidence, in
+//!   red and green, indexed by the cosine of the view angle in `u` and roughness in `v`, which
+//!   together with the prefiltered cubemap makes up the split sum approximation
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	cubemap::{Cubemap, SampledCubemap, CUBE_DIRECTION_GLSL},
+	function::{compile_shader, Binding, BindingDesc, BindingType, StorageImageViews},
+	image::{
+		format::R16G16Sfloat, usage, DynImageUsage, FormatType, Image, ImageViewHandle, SampleCount1, SampledImage,
+	},
+	render::RenderEngine,
+	shader::ShaderStage,
+	sync::ImageTransition,
+	tonemap::HdrFormat,
+	Context, MarsResult,
+};
+
+// `CUBE_DIRECTION` is replaced with `CUBE_DIRECTION_GLSL`
+const IRRADIANCE_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform samplerCube environment;
+layout(set = 0, binding = 1, rgba16f) uniform writeonly image2DArray irradiance;
+
+const float PI = 3.14159265359;
+const float STEP = 0.025;
+
+CUBE_DIRECTION
+
+void main() {
+	ivec2 size = imageSize(irradiance).xy;
+	ivec3 texel = ivec3(gl_GlobalInvocationID);
+	if (texel.x >= size.x || texel.y >= size.y) {
+		return;
+	}
+	vec3 normal = cube_direction(uint(texel.z), (vec2(texel.xy) + 0.5) / vec2(size));
+	vec3 up = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(0.0, 0.0, 1.0);
+	vec3 right = normalize(cross(up, normal));
+	up = cross(normal, right);
+
+	// A mip of about 64x64 is detailed enough for the convolution, and keeps small bright texels
+	// from aliasing at this step size
+	float lod = max(log2(float(textureSize(environment, 0).x) / 64.0), 0.0);
+	vec3 sum = vec3(0.0);
+	float count = 0.0;
+	for (float phi = 0.0; phi < 2.0 * PI; phi += STEP) {
+		for (float theta = 0.0; theta < 0.5 * PI; theta += STEP) {
+			vec3 tangent = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+			vec3 direction = tangent.x * right + tangent.y * up + tangent.z * normal;
+			sum += textureLod(environment, direction, lod).rgb * cos(theta) * sin(theta);
+			count += 1.0;
+		}
+	}
+	imageStore(irradiance, texel, vec4(PI * sum / count, 1.0));
+}
+"#;
+
+// GGX importance sampling shared by the prefiltering and BRDF shaders
+const GGX_GLSL: &str = r#"
+const float PI = 3.14159265359;
+
+float radical_inverse(uint bits) {
+	bits = (bits << 16u) | (bits >> 16u);
+	bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+	bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+	bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+	bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+	return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint count) {
+	return vec2(float(i) / float(count), radical_inverse(i));
+}
+
+// Returns a half vector around `n` distributed by GGX with `roughness`
+vec3 importance_sample_ggx(vec2 xi, vec3 n, float roughness) {
+	float a = roughness * roughness;
+	float phi = 2.0 * PI * xi.x;
+	float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+	float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+	vec3 h = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+	vec3 up = abs(n.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+	vec3 tangent = normalize(cross(up, n));
+	vec3 bitangent = cross(n, tangent);
+	return normalize(tangent * h.x + bitangent * h.y + n * h.z);
+}
+
+float distribution_ggx(float n_dot_h, float roughness) {
+	float a = roughness * roughness;
+	float a2 = a * a;
+	float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+	return a2 / (PI * d * d);
+}
+"#;
+
+// `CUBE_DIRECTION` and `GGX` are replaced with `CUBE_DIRECTION_GLSL` and `GGX_GLSL`
+const PREFILTER_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0) uniform samplerCube environment;
+layout(set = 0, binding = 1, rgba16f) uniform writeonly image2DArray prefiltered;
+layout(set = 0, binding = 2) uniform PrefilterParams {
+	float roughness;
+	uint sample_count;
+};
+
+CUBE_DIRECTION
+
+GGX
+
+void main() {
+	ivec2 size = imageSize(prefiltered).xy;
+	ivec3 texel = ivec3(gl_GlobalInvocationID);
+	if (texel.x >= size.x || texel.y >= size.y) {
+		return;
+	}
+	// The view direction is assumed to be the normal, which loses the stretched reflections of
+	// grazing angles but makes the result independent of the view
+	vec3 n = cube_direction(uint(texel.z), (vec2(texel.xy) + 0.5) / vec2(size));
+	vec3 v = n;
+
+	float source_size = float(textureSize(environment, 0).x);
+	float texel_angle = 4.0 * PI / (6.0 * source_size * source_size);
+	vec3 sum = vec3(0.0);
+	float weight = 0.0;
+	for (uint i = 0u; i < sample_count; i++) {
+		vec3 h = importance_sample_ggx(hammersley(i, sample_count), n, roughness);
+		vec3 l = normalize(2.0 * dot(v, h) * h - v);
+		float n_dot_l = dot(n, l);
+		if (n_dot_l > 0.0) {
+			// Sampling the mip whose texels cover the solid angle of the sample removes most of the
+			// noise of bright texels (GPU Gems 3, chapter 20)
+			float n_dot_h = max(dot(n, h), 0.0);
+			float pdf = distribution_ggx(n_dot_h, roughness) * 0.25 + 0.0001;
+			float sample_angle = 1.0 / (float(sample_count) * pdf);
+			float lod = roughness == 0.0 ? 0.0 : max(0.5 * log2(sample_angle / texel_angle) + 1.0, 0.0);
+			sum += textureLod(environment, l, lod).rgb * n_dot_l;
+			weight += n_dot_l;
+		}
+	}
+	imageStore(prefiltered, texel, vec4(sum / max(weight, 0.0001), 1.0));
+}
+"#;
+
+// `GGX` is replaced with `GGX_GLSL`
+const BRDF_SHADER: &str = r#"
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 0, rg16f) uniform writeonly image2D lut;
+
+const uint SAMPLE_COUNT = 1024u;
+
+GGX
+
+// The Smith-Schlick geometry term, with k remapped for image based lighting
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+	float k = roughness * roughness / 2.0;
+	return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+void main() {
+	ivec2 size = imageSize(lut);
+	ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+	if (texel.x >= size.x || texel.y >= size.y) {
+		return;
+	}
+	float n_dot_v = (float(texel.x) + 0.5) / float(size.x);
+	float roughness = (float(texel.y) + 0.5) / float(size.y);
+	vec3 v = vec3(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+	vec3 n = vec3(0.0, 0.0, 1.0);
+
+	float scale = 0.0;
+	float bias = 0.0;
+	for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+		vec3 h = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), n, roughness);
+		vec3 l = normalize(2.0 * dot(v, h) * h - v);
+		float n_dot_l = max(l.z, 0.0);
+		if (n_dot_l > 0.0) {
+			float n_dot_h = max(h.z, 0.0);
+			float v_dot_h = max(dot(v, h), 0.0);
+			float g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+			float visibility = g * v_dot_h / (n_dot_h * n_dot_v);
+			float fresnel = pow(1.0 - v_dot_h, 5.0);
+			scale += (1.0 - fresnel) * visibility;
+			bias += fresnel * visibility;
+		}
+	}
+	imageStore(lut, texel, vec4(scale, bias, 0.0, 0.0) / float(SAMPLE_COUNT));
+}
+"#;
+
+/// The format of the BRDF lookup table
+pub type BrdfLutFormat = R16G16Sfloat;
+
+/// Sizes and sample counts of the maps an `IblBaker` bakes
+#[derive(Debug, Copy, Clone)]
+pub struct IblOptions {
+	/// The size of the faces of the irradiance cubemap, which is smooth enough to be tiny
+	pub irradiance_size: u32,
+	/// The size of the faces of the base level of the prefiltered cubemap
+	pub prefiltered_size: u32,
+	/// The number of mip levels of the prefiltered cubemap, the last of which has a roughness of 1
+	pub prefiltered_mip_levels: u32,
+	/// The number of samples of the environment taken for each texel of the prefiltered cubemap
+	pub sample_count: u32,
+	/// The size of the BRDF lookup table
+	pub brdf_lut_size: u32,
+}
+
+impl Default for IblOptions {
+	fn default() -> Self {
+		Self {
+			irradiance_size: 32,
+			prefiltered_size: 256,
+			prefiltered_mip_levels: 6,
+			sample_count: 1024,
+			brdf_lut_size: 512,
+		}
+	}
+}
+
+/// The maps baked from an environment for image based lighting. See the module documentation.
+pub struct IblMaps {
+	pub irradiance: Cubemap<HdrFormat>,
+	pub prefiltered: Cubemap<HdrFormat>,
+	pub brdf_lut: SampledImage<BrdfLutFormat>,
+}
+
+struct IrradianceFunction;
+
+impl ComputeFunctionPrototype for IrradianceFunction {
+	type Bindings = (SampledCubemap, IblOutput);
+}
+
+struct PrefilterFunction;
+
+impl ComputeFunctionPrototype for PrefilterFunction {
+	type Bindings = (SampledCubemap, IblOutput, PrefilterParams);
+}
+
+struct BrdfFunction;
+
+impl ComputeFunctionPrototype for BrdfFunction {
+	type Bindings = (IblOutput,);
+}
+
+/// The map being baked, written as a storage image
+struct IblOutput;
+
+unsafe impl Binding for IblOutput {
+	type Argument = StorageImageViews;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageImage,
+			count: 1,
+		}
+	}
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PrefilterParams {
+	roughness: f32,
+	sample_count: u32,
+}
+
+unsafe impl Binding for PrefilterParams {
+	type Argument = Buffer<UniformBufferUsage, PrefilterParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+/// What the last bake dispatched with, kept alive until the next so the views outlive the
+/// dispatches writing through them
+#[derive(Default)]
+struct InFlight {
+	irradiance: Vec<ComputeArgumentsContainer<IrradianceFunction>>,
+	prefilter: Vec<ComputeArgumentsContainer<PrefilterFunction>>,
+	brdf: Vec<ComputeArgumentsContainer<BrdfFunction>>,
+	views: Vec<ImageViewHandle>,
+}
+
+/// Bakes environment cubemaps into the maps for image based lighting. See the module
+/// documentation.
+pub struct IblBaker {
+	irradiance: ComputeFunctionDef<IrradianceFunction>,
+	prefilter: ComputeFunctionDef<PrefilterFunction>,
+	brdf: ComputeFunctionDef<BrdfFunction>,
+	in_flight: InFlight,
+}
+
+impl IblBaker {
+	pub fn create(context: &Context) -> MarsResult<Self> {
+		let irradiance = IRRADIANCE_SHADER.replace("CUBE_DIRECTION", CUBE_DIRECTION_GLSL);
+		let prefilter = PREFILTER_SHADER
+			.replace("CUBE_DIRECTION", CUBE_DIRECTION_GLSL)
+			.replace("GGX", GGX_GLSL);
+		let brdf = BRDF_SHADER.replace("GGX", GGX_GLSL);
+		let irradiance = compile_shader(&irradiance, "irradiance.comp", ShaderStage::Compute)?;
+		let prefilter = compile_shader(&prefilter, "prefilter.comp", ShaderStage::Compute)?;
+		let brdf = compile_shader(&brdf, "brdf.comp", ShaderStage::Compute)?;
+		Ok(Self {
+			irradiance: ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(irradiance) })?,
+			prefilter: ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(prefilter) })?,
+			brdf: ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(brdf) })?,
+			in_flight: InFlight::default(),
+		})
+	}
+
+	/// Bakes all the maps for `environment`, which should have a full mip chain for the
+	/// prefiltering to sample from. Must not be called inside a batch, as the BRDF lookup table is
+	/// transitioned for sampling right after its dispatch.
+	pub fn bake(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		environment: &Cubemap<HdrFormat>,
+		options: &IblOptions,
+	) -> MarsResult<IblMaps> {
+		self.in_flight = InFlight::default();
+		let irradiance = self.bake_irradiance(context, engine, environment, options.irradiance_size)?;
+		let prefiltered = self.bake_prefiltered(
+			context,
+			engine,
+			environment,
+			options.prefiltered_size,
+			options.prefiltered_mip_levels,
+			options.sample_count,
+		)?;
+		let brdf_lut = self.bake_brdf_lut(context, engine, options.brdf_lut_size)?;
+		Ok(IblMaps {
+			irradiance,
+			prefiltered,
+			brdf_lut,
+		})
+	}
+
+	/// Bakes the irradiance cubemap of `environment`, with faces of `size` texels
+	pub fn bake_irradiance(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		environment: &Cubemap<HdrFormat>,
+		size: u32,
+	) -> MarsResult<Cubemap<HdrFormat>> {
+		let irradiance = Cubemap::create(context, size, 1)?;
+		let view = irradiance.face_view(0)?;
+		let arguments = self
+			.irradiance
+			.make_arguments(context, (environment.argument(), StorageImageViews(vec![view.raw])))?;
+		engine.dispatch(context, &self.irradiance, &arguments, cube_group_count(size))?;
+		self.in_flight.irradiance.push(arguments);
+		self.in_flight.views.push(view);
+		Ok(irradiance)
+	}
+
+	/// Bakes the prefiltered cubemap of `environment`, with faces of `size` texels at the base level
+	/// and `mip_levels` levels of increasing roughness, taking `sample_count` samples per texel
+	pub fn bake_prefiltered(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		environment: &Cubemap<HdrFormat>,
+		size: u32,
+		mip_levels: u32,
+		sample_count: u32,
+	) -> MarsResult<Cubemap<HdrFormat>> {
+		let prefiltered = Cubemap::create(context, size, mip_levels)?;
+		for mip_level in 0..mip_levels {
+			let roughness = if mip_levels > 1 {
+				mip_level as f32 / (mip_levels - 1) as f32
+			} else {
+				0.0
+			};
+			let params = Buffer::make_item_buffer(
+				context,
+				PrefilterParams {
+					roughness,
+					// A perfect mirror only ever reflects one direction
+					sample_count: if mip_level == 0 { 1 } else { sample_count },
+				},
+			)?;
+			let view = prefiltered.face_view(mip_level)?;
+			let arguments = self.prefilter.make_arguments(
+				context,
+				(environment.argument(), StorageImageViews(vec![view.raw]), params),
+			)?;
+			let mip_size = (size >> mip_level).max(1);
+			engine.dispatch(context, &self.prefilter, &arguments, cube_group_count(mip_size))?;
+			self.in_flight.prefilter.push(arguments);
+			self.in_flight.views.push(view);
+		}
+		Ok(prefiltered)
+	}
+
+	/// Bakes the BRDF lookup table, of `size` by `size` texels. The table doesn't depend on the
+	/// environment, so one can be shared by every environment. Must not be called inside a batch,
+	/// as the table is transitioned for sampling right after its dispatch.
+	pub fn bake_brdf_lut(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		size: u32,
+	) -> MarsResult<SampledImage<BrdfLutFormat>> {
+		let extent = vk::Extent2D {
+			width: size,
+			height: size,
+		};
+		let mut image = unsafe {
+			Image::<usage::SampledImage, BrdfLutFormat, SampleCount1>::create_raw(
+				context,
+				DynImageUsage::SAMPLED | DynImageUsage::STORAGE,
+				BrdfLutFormat::as_raw(),
+				extent,
+				1,
+				1,
+			)?
+		};
+		image.transition(
+			context,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::GENERAL,
+			},
+		)?;
+		let view = ImageViewHandle::create(
+			&image.image,
+			vk::ImageViewType::TYPE_2D,
+			BrdfLutFormat::as_raw(),
+			vk::ImageSubresourceRange {
+				aspect_mask: vk::ImageAspectFlags::COLOR,
+				base_mip_level: 0,
+				level_count: 1,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+		)?;
+		let arguments = self
+			.brdf
+			.make_arguments(context, (StorageImageViews(vec![view.raw]),))?;
+		engine.dispatch(context, &self.brdf, &arguments, [(size + 7) / 8, (size + 7) / 8, 1])?;
+		self.in_flight.brdf.push(arguments);
+		self.in_flight.views.push(view);
+		image.transition(
+			context,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+				old_layout: vk::ImageLayout::GENERAL,
+				new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			},
+		)?;
+		SampledImage::create(context, image)
+	}
+}
+
+/// The workgroups covering the six faces of a cubemap level of `size` texels
+fn cube_group_count(size: u32) -> [u32; 3] {
+	[(size + 7) / 8, (size + 7) / 8, 6]
+}
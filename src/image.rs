@@ -1,12 +1,17 @@
-use std::marker::PhantomData;
-
-use rk::{
-	image::{Image as RkImage, ImageLayoutTransition, ImageView as RkImageView, Sampler as RkSampler},
-	vk,
+use std::{
+	marker::PhantomData,
+	ops::{Deref, Range},
+	sync::Arc,
 };
 
+use rk::{ash::extensions::khr, device::Device, image::Sampler as RkSampler, vk};
+
 use crate::{
-	buffer::{Buffer, TransferSrcBufferUsage},
+	destruction::DestructionQueue,
+	memory::{find_memory_type, Allocation, DeviceBuffer, ResourceKind},
+	raw_device, raw_instance, raw_physical_device,
+	render::RenderEngine,
+	sync::{self, ImageTransition},
 	Context, MarsResult,
 };
 
@@ -44,9 +49,11 @@ pub use self::{
 /// there is no `DynImageUsage` analog for image formats). However, this may change in the future,
 /// depending on API requirements.
 pub struct Image<U: ImageUsageType, F: FormatType, S: SampleCountType> {
-	pub(crate) image: RkImage,
+	pub(crate) image: ImageHandle,
 	pub(crate) layout: vk::ImageLayout,
 	pub(crate) extent: vk::Extent2D,
+	pub(crate) layers: u32,
+	pub(crate) mip_levels: u32,
 	pub(crate) usage: DynImageUsage,
 	_phantom: PhantomData<(U, F, S)>,
 }
@@ -62,73 +69,379 @@ where
 		usage: DynImageUsage,
 		format: vk::Format,
 		extent: vk::Extent2D,
+		layers: u32,
+		mip_levels: u32,
 	) -> MarsResult<Self> {
-		let extent3d = vk::Extent3D {
-			width: extent.width,
-			height: extent.height,
-			depth: 1,
-		};
+		Self::create_raw_with_memory(
+			context,
+			usage,
+			format,
+			extent,
+			layers,
+			mip_levels,
+			vk::ImageCreateFlags::empty(),
+			&[vk::MemoryPropertyFlags::DEVICE_LOCAL],
+		)
+	}
 
-		let image = RkImage::create(
-			&context.device,
+	/// Like `create_raw`, but creates the image with `flags`, like `CUBE_COMPATIBLE`
+	pub(crate) unsafe fn create_raw_with_flags(
+		context: &Context,
+		usage: DynImageUsage,
+		format: vk::Format,
+		extent: vk::Extent2D,
+		layers: u32,
+		mip_levels: u32,
+		flags: vk::ImageCreateFlags,
+	) -> MarsResult<Self> {
+		Self::create_raw_with_memory(
+			context,
+			usage,
 			format,
-			extent3d,
-			usage.as_raw(),
-			S::as_raw(),
-			vk::ImageLayout::UNDEFINED,
-			vk::MemoryPropertyFlags::DEVICE_LOCAL,
+			extent,
+			layers,
+			mip_levels,
+			flags,
+			&[vk::MemoryPropertyFlags::DEVICE_LOCAL],
+		)
+	}
+
+	/// Creates an image backed by memory with the first of `properties` the device has a memory type
+	/// for
+	#[allow(clippy::too_many_arguments)]
+	unsafe fn create_raw_with_memory(
+		context: &Context,
+		usage: DynImageUsage,
+		format: vk::Format,
+		extent: vk::Extent2D,
+		layers: u32,
+		mip_levels: u32,
+		flags: vk::ImageCreateFlags,
+		properties: &[vk::MemoryPropertyFlags],
+	) -> MarsResult<Self> {
+		let create_info = vk::ImageCreateInfo::builder()
+			.flags(flags)
+			.image_type(vk::ImageType::TYPE_2D)
+			.format(format)
+			.extent(vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			})
+			.mip_levels(mip_levels)
+			.array_layers(layers)
+			.samples(S::as_raw())
+			.tiling(vk::ImageTiling::OPTIMAL)
+			.usage(usage.as_raw())
+			.sharing_mode(vk::SharingMode::EXCLUSIVE)
+			.initial_layout(vk::ImageLayout::UNDEFINED);
+		let image = ImageHandle::create(
+			context,
+			&create_info,
+			properties,
+			vk::ExternalMemoryHandleTypeFlags::empty(),
 		)?;
 
 		Ok(Self {
 			image,
 			layout: vk::ImageLayout::UNDEFINED,
 			extent,
+			layers,
+			mip_levels,
 			usage,
 			_phantom: PhantomData,
 		})
 	}
 
+	/// Creates an image that's only ever used as an attachment within a single render pass, whose
+	/// contents are never loaded from or stored to memory. It's backed by lazily allocated memory
+	/// when the device has it, which tile-based GPUs may never need to actually allocate.
+	pub fn create_transient(context: &Context, usage: U, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		assert!(layers > 0);
+		unsafe {
+			Self::create_raw_with_memory(
+				context,
+				usage.as_dyn() | DynImageUsage::TRANSIENT_ATTACHMENT,
+				F::as_raw(),
+				extent,
+				layers,
+				1,
+				vk::ImageCreateFlags::empty(),
+				&[
+					vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::LAZILY_ALLOCATED,
+					vk::MemoryPropertyFlags::DEVICE_LOCAL,
+				],
+			)
+		}
+	}
+
 	pub fn create(context: &Context, usage: U, extent: vk::Extent2D) -> MarsResult<Self> {
-		unsafe { Self::create_raw(context, usage.as_dyn(), F::as_raw(), extent) }
+		unsafe { Self::create_raw(context, usage.as_dyn(), F::as_raw(), extent, 1, 1) }
 	}
 
-	pub fn make_image(context: &Context, usage: U, extent: vk::Extent2D, data: &[u8]) -> MarsResult<Self> {
+	/// Creates an image with multiple array layers, such as the attachments of a multiview render
+	/// pass
+	pub fn create_layered(context: &Context, usage: U, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		assert!(layers > 0);
+		unsafe { Self::create_raw(context, usage.as_dyn(), F::as_raw(), extent, layers, 1) }
+	}
+
+	/// Creates an image with a chain of `mip_levels` mip levels, which are left uninitialized. At
+	/// most `max_mip_levels(extent)` levels can be created.
+	pub fn create_with_mips(context: &Context, usage: U, extent: vk::Extent2D, mip_levels: u32) -> MarsResult<Self> {
+		assert!(mip_levels > 0 && mip_levels <= max_mip_levels(extent));
+		unsafe { Self::create_raw(context, usage.as_dyn(), F::as_raw(), extent, 1, mip_levels) }
+	}
+
+	/// Creates an image from tightly packed texel data, `extent.width * extent.height` texels of
+	/// type `F::Pixel` in rows from top to bottom
+	pub fn make_image_from_pixels(
+		context: &Context,
+		usage: U,
+		extent: vk::Extent2D,
+		pixels: &[F::Pixel],
+		mipmaps: MipmapMode,
+	) -> MarsResult<Self> {
+		// Pixel types are arrays of plain numbers, with no padding
+		let data = unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u8, std::mem::size_of_val(pixels)) };
+		Self::make_image(context, usage, extent, data, mipmaps)
+	}
+
+	/// Creates an image from tightly packed texel data, given as the bytes of `F::Pixel`s. With
+	/// `MipmapMode::Generate`, the rest of the mip chain is generated from the data, and the image
+	/// gets the `TRANSFER_SRC` usage for it.
+	pub fn make_image(
+		context: &Context,
+		usage: U,
+		extent: vk::Extent2D,
+		data: &[u8],
+		mipmaps: MipmapMode,
+	) -> MarsResult<Self> {
+		trace_span!("Image::make_image", width = extent.width, height = extent.height);
+		assert_eq!(
+			data.len(),
+			extent.width as usize * extent.height as usize * std::mem::size_of::<F::Pixel>(),
+			"image data doesn't match the extent and format of the image"
+		);
+		let (mip_levels, mip_usage) = match mipmaps {
+			MipmapMode::None => (1, DynImageUsage::empty()),
+			MipmapMode::Generate => (max_mip_levels(extent), DynImageUsage::TRANSFER_SRC),
+		};
 		let mut image = unsafe {
 			Self::create_raw(
 				context,
-				usage.as_dyn() | DynImageUsage::TRANSFER_DST,
+				usage.as_dyn() | DynImageUsage::TRANSFER_DST | mip_usage,
 				F::as_raw(),
 				extent,
+				1,
+				mip_levels,
 			)?
 		};
-		image.transition(
-			context,
-			&ImageLayoutTransition {
-				aspect: F::aspect(),
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::MEMORY_READ,
-				old_layout: vk::ImageLayout::UNDEFINED,
-				new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		let staged = context.staging.stage(context, data, copy_alignment::<F>())?;
+
+		let raw_image = image.image.raw;
+		let transition = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			src_access_mask: vk::AccessFlags2KHR::NONE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::UNDEFINED,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		let region = vk::BufferImageCopy {
+			buffer_offset: staged.offset,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: F::aspect(),
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
 			},
-		)?;
+			image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+			image_extent: vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, raw_image, &transition);
+			raw_device(&context.device).cmd_copy_buffer_to_image(
+				command_buffer,
+				staged.buffer,
+				raw_image,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&[region],
+			);
+		})?;
+		image.layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+		if mip_levels > 1 {
+			image.generate_mipmaps(context)?;
+		}
 
-		let staging_buffer = Buffer::<TransferSrcBufferUsage, _>::make_array_buffer(context, data)?;
+		Ok(image)
+	}
 
-		unsafe {
-			context.device.copy_buffer_to_image(
-				&context.queue,
-				&context.command_pool,
-				&staging_buffer.buffer,
-				&image.image,
-				extent,
-				F::aspect(),
-			)?;
-			image.layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+	/// Fills every mip level below the first by blitting each level down from the one above it with
+	/// linear filtering. Every level must be in `TRANSFER_DST_OPTIMAL` layout, with the first one
+	/// written, and they're all left in `TRANSFER_SRC_OPTIMAL` layout. Fails with
+	/// `ERROR_FORMAT_NOT_SUPPORTED` if the format can't be blitted with linear filtering.
+	pub(crate) fn generate_mipmaps(&mut self, context: &Context) -> MarsResult<()> {
+		assert!(
+			self.usage
+				.contains(DynImageUsage::TRANSFER_SRC | DynImageUsage::TRANSFER_DST),
+			"images need the TRANSFER_SRC and TRANSFER_DST usages to generate mipmaps"
+		);
+		assert_eq!(
+			self.layout,
+			vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			"mipmaps are generated from images in TRANSFER_DST_OPTIMAL layout"
+		);
+		let properties = unsafe {
+			raw_instance(&context.instance)
+				.get_physical_device_format_properties(raw_physical_device(&context.physical_device), F::as_raw())
+		};
+		let features = vk::FormatFeatureFlags::BLIT_SRC
+			| vk::FormatFeatureFlags::BLIT_DST
+			| vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+		if !properties.optimal_tiling_features.contains(features) {
+			return Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED);
 		}
 
-		Ok(image)
+		let image = self.image.raw;
+		// Each level is read by the blit into the next once it's been written
+		let written = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::BLIT,
+			src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_READ,
+			old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			for level in 1..self.mip_levels {
+				sync::record_mip_transition(context, command_buffer, image, &written, level - 1..level);
+				let src = self.mip_extent(level - 1);
+				let dst = self.mip_extent(level);
+				let region = vk::ImageBlit {
+					src_subresource: vk::ImageSubresourceLayers {
+						aspect_mask: F::aspect(),
+						mip_level: level - 1,
+						base_array_layer: 0,
+						layer_count: self.layers,
+					},
+					src_offsets: [
+						vk::Offset3D { x: 0, y: 0, z: 0 },
+						vk::Offset3D {
+							x: src.width as i32,
+							y: src.height as i32,
+							z: 1,
+						},
+					],
+					dst_subresource: vk::ImageSubresourceLayers {
+						aspect_mask: F::aspect(),
+						mip_level: level,
+						base_array_layer: 0,
+						layer_count: self.layers,
+					},
+					dst_offsets: [
+						vk::Offset3D { x: 0, y: 0, z: 0 },
+						vk::Offset3D {
+							x: dst.width as i32,
+							y: dst.height as i32,
+							z: 1,
+						},
+					],
+				};
+				raw_device(&context.device).cmd_blit_image(
+					command_buffer,
+					image,
+					vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+					image,
+					vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+					&[region],
+					vk::Filter::LINEAR,
+				);
+			}
+			let last = self.mip_levels - 1;
+			sync::record_mip_transition(context, command_buffer, image, &written, last..last + 1);
+		})?;
+		self.layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+		Ok(())
+	}
+
+	/// Reads the texels of the first layer back from the device, in rows from top to bottom, waiting
+	/// for everything submitted before to complete. The image needs the `TRANSFER_SRC` usage and must be in a layout copies can read from, like color
+	/// attachments always are between passes.
+	pub fn read_pixels(&self, context: &Context) -> MarsResult<Vec<F::Pixel>> {
+		assert_eq!(
+			S::as_raw(),
+			vk::SampleCountFlags::TYPE_1,
+			"multisampled images must be resolved to be read back"
+		);
+		assert!(
+			self.usage.contains(DynImageUsage::TRANSFER_SRC),
+			"images read back need the TRANSFER_SRC usage"
+		);
+		assert!(
+			self.layout == vk::ImageLayout::TRANSFER_SRC_OPTIMAL || self.layout == vk::ImageLayout::GENERAL,
+			"images can't be read back in layout {:?}",
+			self.layout
+		);
+		let texel_count = self.extent.width as usize * self.extent.height as usize;
+		let size = (texel_count * std::mem::size_of::<F::Pixel>()) as u64;
+		let buffer = DeviceBuffer::create(
+			context,
+			size,
+			vk::BufferUsageFlags::TRANSFER_DST,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+		)?;
+		let region = vk::BufferImageCopy {
+			buffer_offset: 0,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: F::aspect(),
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+			image_extent: vk::Extent3D {
+				width: self.extent.width,
+				height: self.extent.height,
+				depth: 1,
+			},
+		};
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_memory_barrier(
+				context,
+				command_buffer,
+				vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+				vk::AccessFlags2KHR::MEMORY_WRITE,
+				vk::PipelineStageFlags2KHR::COPY,
+				vk::AccessFlags2KHR::TRANSFER_READ,
+			);
+			raw_device(&context.device).cmd_copy_image_to_buffer(
+				command_buffer,
+				self.image.raw,
+				self.layout,
+				buffer.buffer,
+				&[region],
+			);
+			sync::record_memory_barrier(
+				context,
+				command_buffer,
+				vk::PipelineStageFlags2KHR::COPY,
+				vk::AccessFlags2KHR::TRANSFER_WRITE,
+				vk::PipelineStageFlags2KHR::HOST,
+				vk::AccessFlags2KHR::HOST_READ,
+			);
+		})?;
+		unsafe { buffer.read(0, texel_count) }
 	}
 
 	/// Returns all of the usages this image supports. (This may be more than the usage type
@@ -141,6 +454,22 @@ where
 		self.extent
 	}
 
+	pub fn layers(&self) -> u32 {
+		self.layers
+	}
+
+	pub fn mip_levels(&self) -> u32 {
+		self.mip_levels
+	}
+
+	/// The extent of mip level `level`, halved for every level and rounded down to at least 1
+	pub fn mip_extent(&self, level: u32) -> vk::Extent2D {
+		vk::Extent2D {
+			width: (self.extent.width >> level).max(1),
+			height: (self.extent.height >> level).max(1),
+		}
+	}
+
 	pub fn cast_usage<U2: ImageUsageType>(self, usage: U2) -> Result<Image<U2, F, S>, Self> {
 		if self.usage.as_dyn().contains(usage.as_dyn()) {
 			Ok(unsafe { self.cast_unchecked() })
@@ -172,6 +501,8 @@ where
 			image,
 			layout,
 			extent,
+			layers,
+			mip_levels,
 			usage,
 			_phantom,
 		} = self;
@@ -179,6 +510,8 @@ where
 			image,
 			layout,
 			extent,
+			layers,
+			mip_levels,
 			usage,
 			_phantom: PhantomData,
 		}
@@ -196,34 +529,280 @@ where
 		&mut *(self as *mut Self as *mut Image<U2, F2, S2>)
 	}
 
-	pub unsafe fn from_raw(&mut self, raw: RkImage, usage: U, extent: vk::Extent2D, layout: vk::ImageLayout) -> Self {
+	/// Wraps an image created outside of mars, such as by a video decoder, a capture API or an
+	/// OpenXR runtime, so it can be sampled or rendered to like any other image.
+	///
+	/// The image stays owned by whoever created it. Mars never destroys it or frees its memory, so
+	/// it must outlive the returned image, every view of it, and every submission using them.
+	/// `layout` is the layout the image is in when it's wrapped. `usage` must be a subset of the
+	/// usage the image was created with, `F` and `S` must match its format and sample count, and it
+	/// must be a 2D image with a single layer and mip level.
+	pub unsafe fn from_raw(
+		context: &Context,
+		raw: vk::Image,
+		layout: vk::ImageLayout,
+		usage: U,
+		extent: vk::Extent2D,
+	) -> Self {
+		Self::wrap_raw(context, raw, usage.as_dyn(), extent, 1, layout)
+	}
+
+	/// Wraps an image owned by something else, which must outlive the returned image
+	pub(crate) unsafe fn wrap_raw(
+		context: &Context,
+		raw: vk::Image,
+		usage: DynImageUsage,
+		extent: vk::Extent2D,
+		layers: u32,
+		layout: vk::ImageLayout,
+	) -> Self {
 		Self {
-			image: raw,
+			image: ImageHandle {
+				device: context.device.clone(),
+				raw,
+				memory: None,
+				destruction: context.destruction.clone(),
+			},
 			layout,
 			extent,
-			usage: usage.as_dyn(),
+			layers,
+			mip_levels: 1,
+			usage,
 			_phantom: PhantomData,
 		}
 	}
 
-	pub unsafe fn raw(&self) -> &RkImage {
-		&self.image
+	pub unsafe fn raw(&self) -> vk::Image {
+		self.image.raw
 	}
 
-	// TODO: worry about image synchronization... or don't
-	pub(crate) fn transition(&mut self, context: &Context, transition: &ImageLayoutTransition) -> MarsResult<()> {
-		unsafe {
-			context.queue.with_lock(|| {
-				rk::image::transition_image_layout(&context.queue, &context.command_pool, &mut self.image, transition)
-			})?;
+	/// Creates a linearly tiled image in its own allocation, whose memory can be exported as
+	/// `handle_type` to share it with other APIs and processes
+	pub(crate) unsafe fn create_exportable(
+		context: &Context,
+		usage: DynImageUsage,
+		extent: vk::Extent2D,
+		handle_type: vk::ExternalMemoryHandleTypeFlags,
+	) -> MarsResult<Self> {
+		let mut external_info = vk::ExternalMemoryImageCreateInfo::builder().handle_types(handle_type);
+		let create_info = vk::ImageCreateInfo::builder()
+			.image_type(vk::ImageType::TYPE_2D)
+			.format(F::as_raw())
+			.extent(vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			})
+			.mip_levels(1)
+			.array_layers(1)
+			.samples(S::as_raw())
+			.tiling(vk::ImageTiling::LINEAR)
+			.usage(usage.as_raw())
+			.sharing_mode(vk::SharingMode::EXCLUSIVE)
+			.initial_layout(vk::ImageLayout::UNDEFINED)
+			.push_next(&mut external_info);
+		let image = ImageHandle::create(
+			context,
+			&create_info,
+			&[vk::MemoryPropertyFlags::DEVICE_LOCAL],
+			handle_type,
+		)?;
+
+		Ok(Self {
+			image,
+			layout: vk::ImageLayout::UNDEFINED,
+			extent,
+			layers: 1,
+			mip_levels: 1,
+			usage,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Exports the memory of an image created with `create_exportable` as a file descriptor, which
+	/// the caller owns
+	#[cfg(unix)]
+	pub(crate) unsafe fn export_memory_fd(
+		&self,
+		context: &Context,
+		handle_type: vk::ExternalMemoryHandleTypeFlags,
+	) -> MarsResult<std::os::unix::io::RawFd> {
+		let loader = khr::ExternalMemoryFd::new(raw_instance(&context.instance), raw_device(&context.device));
+		let get_info = vk::MemoryGetFdInfoKHR::builder()
+			.memory(
+				self.image
+					.memory
+					.as_ref()
+					.expect("Exported the memory of an image mars didn't allocate")
+					.memory,
+			)
+			.handle_type(handle_type);
+		loader.get_memory_fd(&get_info)
+	}
+
+	/// Creates an image bound to memory that `allocate` allocates given the image and its memory
+	/// requirements, such as memory imported from outside of Vulkan. The image is created in
+	/// `UNDEFINED` layout, and its memory is freed along with it.
+	pub(crate) unsafe fn create_external<A>(
+		context: &Context,
+		create_info: &vk::ImageCreateInfo,
+		usage: DynImageUsage,
+		allocate: A,
+	) -> MarsResult<Self>
+	where
+		A: FnOnce(vk::Image, vk::MemoryRequirements) -> MarsResult<vk::DeviceMemory>,
+	{
+		let device = raw_device(&context.device);
+		let image = device.create_image(create_info, None)?;
+		let requirements = device.get_image_memory_requirements(image);
+		let memory = allocate(image, requirements).map_err(|e| {
+			device.destroy_image(image, None);
+			e
+		})?;
+		let memory = context.allocator.adopt(memory, requirements.size);
+		if let Err(e) = device.bind_image_memory(image, memory.memory, 0) {
+			device.destroy_image(image, None);
+			return Err(e);
+		}
+
+		Ok(Self {
+			image: ImageHandle {
+				device: context.device.clone(),
+				raw: image,
+				memory: Some(memory),
+				destruction: context.destruction.clone(),
+			},
+			layout: vk::ImageLayout::UNDEFINED,
+			extent: vk::Extent2D {
+				width: create_info.extent.width,
+				height: create_info.extent.height,
+			},
+			layers: create_info.array_layers,
+			mip_levels: create_info.mip_levels,
+			usage,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Returns where the texels of the first layer and mip level of `aspect` are in the memory of a
+	/// linearly tiled image, or of a memory plane of an image with a DRM format modifier
+	pub(crate) fn subresource_layout(&self, aspect: vk::ImageAspectFlags) -> vk::SubresourceLayout {
+		let subresource = vk::ImageSubresource {
+			aspect_mask: aspect,
+			mip_level: 0,
+			array_layer: 0,
 		};
+		unsafe { raw_device(&self.image.device).get_image_subresource_layout(self.image.raw, subresource) }
+	}
+
+	// TODO: worry about image synchronization... or don't
+	pub(crate) fn transition(&mut self, context: &Context, transition: &ImageTransition) -> MarsResult<()> {
+		let image = self.image.raw;
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, image, transition);
+		})?;
+		self.layout = transition.new_layout;
+		Ok(())
+	}
+
+	/// Like `transition`, but if `engine` is recording a frame the transition is recorded into it, in
+	/// order with the passes recorded before and after it
+	pub(crate) fn record_transition(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		transition: &ImageTransition,
+	) -> MarsResult<()> {
+		let image = self.image.raw;
+		engine.record(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, image, transition);
+		})?;
 		self.layout = transition.new_layout;
 		Ok(())
 	}
 }
 
+/// A Vulkan image along with the memory bound to it. Images that mars didn't allocate (like ones
+/// passed to `Image::from_raw`) have no memory and aren't destroyed on drop.
+pub(crate) struct ImageHandle {
+	pub(crate) device: Device,
+	pub(crate) raw: vk::Image,
+	memory: Option<Allocation>,
+	destruction: DestructionQueue,
+}
+
+impl ImageHandle {
+	/// Creates an image and allocates memory for it from the context's allocator. If `export` isn't
+	/// empty, the memory is a dedicated allocation that can be exported as those handle types.
+	pub(crate) fn create(
+		context: &Context,
+		create_info: &vk::ImageCreateInfo,
+		properties: &[vk::MemoryPropertyFlags],
+		export: vk::ExternalMemoryHandleTypeFlags,
+	) -> MarsResult<Self> {
+		let device = raw_device(&context.device);
+		unsafe {
+			let image = device.create_image(create_info, None)?;
+			let requirements = device.get_image_memory_requirements(image);
+			let memory_type = properties
+				.iter()
+				.map(|&properties| find_memory_type(context, requirements.memory_type_bits, properties))
+				.find(Result::is_ok)
+				.unwrap_or(Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY));
+			let memory = memory_type
+				.and_then(|memory_type| {
+					if export.is_empty() {
+						let kind = if create_info.tiling == vk::ImageTiling::OPTIMAL {
+							ResourceKind::OptimalImage
+						} else {
+							ResourceKind::LinearImage
+						};
+						return context.allocator.allocate(requirements, memory_type, kind);
+					}
+					let mut export_info = vk::ExportMemoryAllocateInfo::builder().handle_types(export);
+					let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+					let allocate_info = vk::MemoryAllocateInfo::builder()
+						.allocation_size(requirements.size)
+						.memory_type_index(memory_type)
+						.push_next(&mut export_info)
+						.push_next(&mut dedicated_info);
+					context.allocator.allocate_dedicated(&allocate_info)
+				})
+				.map_err(|e| {
+					device.destroy_image(image, None);
+					e
+				})?;
+			if let Err(e) = device.bind_image_memory(image, memory.memory, memory.offset) {
+				device.destroy_image(image, None);
+				return Err(e);
+			}
+
+			Ok(Self {
+				device: context.device.clone(),
+				raw: image,
+				memory: Some(memory),
+				destruction: context.destruction.clone(),
+			})
+		}
+	}
+}
+
+impl Drop for ImageHandle {
+	fn drop(&mut self) {
+		if let Some(memory) = self.memory.take() {
+			let device = self.device.clone();
+			let raw = self.raw;
+			self.destruction.defer(move || unsafe {
+				raw_device(&device).destroy_image(raw, None);
+				// The memory goes back to the allocator once the image is destroyed
+				drop(memory);
+			});
+		}
+	}
+}
+
 pub struct ImageView<U: ImageUsageType, F: FormatType, S: SampleCountType> {
-	pub(crate) image_view: RkImageView,
+	pub(crate) image_view: ImageViewHandle,
 	pub(crate) usage: DynImageUsage,
 	_phantom: PhantomData<(U, F, S)>,
 }
@@ -234,8 +813,25 @@ where
 	F: FormatType,
 	S: SampleCountType,
 {
+	/// Creates a view of the whole image, which is an array view if the image has multiple layers
 	pub fn create(image: &Image<U, F, S>) -> MarsResult<Self> {
-		let image_view = unsafe { RkImageView::create(&image.image, F::aspect())? };
+		let view_type = if image.layers > 1 {
+			vk::ImageViewType::TYPE_2D_ARRAY
+		} else {
+			vk::ImageViewType::TYPE_2D
+		};
+		let image_view = ImageViewHandle::create(
+			&image.image,
+			view_type,
+			F::as_raw(),
+			vk::ImageSubresourceRange {
+				aspect_mask: F::aspect(),
+				base_mip_level: 0,
+				level_count: image.mip_levels,
+				base_array_layer: 0,
+				layer_count: image.layers,
+			},
+		)?;
 		Ok(Self {
 			image_view,
 			usage: image.usage,
@@ -243,6 +839,79 @@ where
 		})
 	}
 
+	/// Creates an array view of every layer of the image, even if it only has one, for binding as a
+	/// `sampler2DArray` or `texture2DArray`
+	pub fn create_array(image: &Image<U, F, S>) -> MarsResult<Self> {
+		let image_view = ImageViewHandle::create(
+			&image.image,
+			vk::ImageViewType::TYPE_2D_ARRAY,
+			F::as_raw(),
+			vk::ImageSubresourceRange {
+				aspect_mask: F::aspect(),
+				base_mip_level: 0,
+				level_count: image.mip_levels,
+				base_array_layer: 0,
+				layer_count: image.layers,
+			},
+		)?;
+		Ok(Self {
+			image_view,
+			usage: image.usage,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Creates a view of the mip levels `mips` and array layers `layers` of the image, which is an
+	/// array view if it has more than one layer. Views of a single mip level can be rendered into,
+	/// for generating a mip chain or a Hi-Z pyramid one level at a time with the extent given by
+	/// `Image::mip_extent`, and views of a single layer render into that layer alone.
+	pub fn create_subresource(image: &Image<U, F, S>, mips: Range<u32>, layers: Range<u32>) -> MarsResult<Self> {
+		assert!(
+			mips.start < mips.end && mips.end <= image.mip_levels,
+			"mip levels {:?} are out of range for an image with {} levels",
+			mips,
+			image.mip_levels
+		);
+		assert!(
+			layers.start < layers.end && layers.end <= image.layers,
+			"layers {:?} are out of range for an image with {} layers",
+			layers,
+			image.layers
+		);
+		let view_type = if layers.len() > 1 {
+			vk::ImageViewType::TYPE_2D_ARRAY
+		} else {
+			vk::ImageViewType::TYPE_2D
+		};
+		let image_view = ImageViewHandle::create(
+			&image.image,
+			view_type,
+			F::as_raw(),
+			vk::ImageSubresourceRange {
+				aspect_mask: F::aspect(),
+				base_mip_level: mips.start,
+				level_count: mips.end - mips.start,
+				base_array_layer: layers.start,
+				layer_count: layers.end - layers.start,
+			},
+		)?;
+		Ok(Self {
+			image_view,
+			usage: image.usage,
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Creates a view of mip level `level` of every layer of the image
+	pub fn create_mip(image: &Image<U, F, S>, level: u32) -> MarsResult<Self> {
+		Self::create_subresource(image, level..level + 1, 0..image.layers)
+	}
+
+	/// Creates a view of every mip level of layer `layer` of the image
+	pub fn create_layer(image: &Image<U, F, S>, layer: u32) -> MarsResult<Self> {
+		Self::create_subresource(image, 0..image.mip_levels, layer..layer + 1)
+	}
+
 	/// Returns all of the usages this image supports. (This may be more than the usage type
 	/// parameter indicates).
 	pub fn usage(&self) -> DynImageUsage {
@@ -301,14 +970,155 @@ where
 	}
 }
 
+pub(crate) struct ImageViewHandle {
+	device: Device,
+	pub(crate) raw: vk::ImageView,
+	destruction: DestructionQueue,
+}
+
+impl ImageViewHandle {
+	pub(crate) fn create(
+		image: &ImageHandle,
+		view_type: vk::ImageViewType,
+		format: vk::Format,
+		subresource_range: vk::ImageSubresourceRange,
+	) -> MarsResult<Self> {
+		let create_info = vk::ImageViewCreateInfo::builder()
+			.image(image.raw)
+			.view_type(view_type)
+			.format(format)
+			.subresource_range(subresource_range);
+		let raw = unsafe { raw_device(&image.device).create_image_view(&create_info, None)? };
+		Ok(Self {
+			device: image.device.clone(),
+			raw,
+			destruction: image.destruction.clone(),
+		})
+	}
+}
+
+impl Drop for ImageViewHandle {
+	fn drop(&mut self) {
+		let device = self.device.clone();
+		let raw = self.raw;
+		self.destruction.defer(move || unsafe {
+			raw_device(&device).destroy_image_view(raw, None);
+		});
+	}
+}
+
+/// The alignment of texel data staged for copying into an image of format `F`. Copies out of
+/// buffers have to start at a multiple of the texel size and of 4 bytes.
+pub(crate) fn copy_alignment<F: FormatType>() -> u64 {
+	let texel_size = std::mem::size_of::<F::Pixel>() as u64;
+	texel_size * 4 / gcd(texel_size, 4)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+	if b == 0 {
+		a
+	} else {
+		gcd(b, a % b)
+	}
+}
+
+/// Whether an image made from texel data gets a mip chain
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MipmapMode {
+	/// The image has a single mip level
+	None,
+	/// The image has a full mip chain down to 1x1, generated from the data by blitting each level
+	/// down from the one above it. Sampling it from a distance averages the texels that fall in a
+	/// pixel instead of picking a few of them, which keeps textures from shimmering.
+	Generate,
+}
+
+/// The number of mip levels in a full mip chain for an image of size `extent`, down to 1x1
+pub fn max_mip_levels(extent: vk::Extent2D) -> u32 {
+	32 - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
 pub struct Sampler {
-	pub(crate) sampler: RkSampler,
+	pub(crate) sampler: Arc<SamplerHandle>,
 }
 
 impl Sampler {
+	/// Creates a sampler with rk's default settings. Use `create_trilinear` to sample the mip chain
+	/// of an image.
 	pub fn create(context: &Context) -> MarsResult<Self> {
 		let sampler = context.device.create_sampler()?;
-		Ok(Self { sampler })
+		Ok(Self {
+			sampler: Arc::new(SamplerHandle {
+				device: context.device.clone(),
+				raw: **sampler,
+				rk: Some(sampler),
+				destruction: context.destruction.clone(),
+			}),
+		})
+	}
+
+	/// Creates a sampler that filters linearly within and between mip levels (trilinear filtering),
+	/// repeats textures outside of [0, 1], and can sample every level of an image's mip chain
+	pub fn create_trilinear(context: &Context) -> MarsResult<Self> {
+		let create_info = vk::SamplerCreateInfo::builder()
+			.mag_filter(vk::Filter::LINEAR)
+			.min_filter(vk::Filter::LINEAR)
+			.mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+			.address_mode_u(vk::SamplerAddressMode::REPEAT)
+			.address_mode_v(vk::SamplerAddressMode::REPEAT)
+			.address_mode_w(vk::SamplerAddressMode::REPEAT)
+			.min_lod(0.0)
+			.max_lod(vk::LOD_CLAMP_NONE);
+		let raw = unsafe { raw_device(&context.device).create_sampler(&create_info, None)? };
+		Ok(Self {
+			sampler: Arc::new(SamplerHandle {
+				device: context.device.clone(),
+				raw,
+				rk: None,
+				destruction: context.destruction.clone(),
+			}),
+		})
+	}
+}
+
+/// A Vulkan sampler, shared by the arguments it's written to
+pub(crate) struct SamplerHandle {
+	device: Device,
+	raw: vk::Sampler,
+	/// The sampler `raw` belongs to if rk created it, which destroys it when dropped
+	rk: Option<RkSampler>,
+	destruction: DestructionQueue,
+}
+
+impl Deref for SamplerHandle {
+	type Target = vk::Sampler;
+
+	fn deref(&self) -> &Self::Target {
+		&self.raw
+	}
+}
+
+impl Drop for SamplerHandle {
+	fn drop(&mut self) {
+		match self.rk.take() {
+			Some(sampler) => self.destruction.destroy(sampler),
+			None => {
+				let device = self.device.clone();
+				let raw = self.raw;
+				self.destruction.defer(move || unsafe {
+					raw_device(&device).destroy_sampler(raw, None);
+				});
+			}
+		}
+	}
+}
+
+/// A trilinear sampler for images with a mip chain, and the default sampler otherwise
+fn sampler_for_mips(context: &Context, mip_levels: u32) -> MarsResult<Sampler> {
+	if mip_levels > 1 {
+		Sampler::create_trilinear(context)
+	} else {
+		Sampler::create(context)
 	}
 }
 
@@ -335,24 +1145,229 @@ where
 	}
 
 	pub fn create(context: &Context, mut image: Image<usage::SampledImage, F, SampleCount1>) -> MarsResult<Self> {
-		if image.layout != vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL {
-			let transition = ImageLayoutTransition {
-				aspect: F::aspect(),
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::ALL_GRAPHICS,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::SHADER_READ,
-				old_layout: image.layout,
-				new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-			};
-			image.transition(context, &transition)?;
-		}
+		make_shader_readable(context, &mut image)?;
 		let image_view = ImageView::create(&image)?;
-		let sampler = Sampler::create(context)?;
+		let sampler = sampler_for_mips(context, image.mip_levels)?;
 		Ok(Self::new(image, image_view, sampler))
 	}
 }
 
+/// An image sampled with a separately bound `Sampler`, so that one sampler can be used with many
+/// textures
+pub struct Texture<F: FormatType> {
+	pub image: Image<usage::SampledImage, F, SampleCount1>,
+	pub image_view: ImageView<usage::SampledImage, F, SampleCount1>,
+}
+
+impl<F> Texture<F>
+where
+	F: FormatType,
+{
+	pub fn new(
+		image: Image<usage::SampledImage, F, SampleCount1>,
+		image_view: ImageView<usage::SampledImage, F, SampleCount1>,
+	) -> Self {
+		Self { image, image_view }
+	}
+
+	pub fn create(context: &Context, mut image: Image<usage::SampledImage, F, SampleCount1>) -> MarsResult<Self> {
+		make_shader_readable(context, &mut image)?;
+		let image_view = ImageView::create(&image)?;
+		Ok(Self::new(image, image_view))
+	}
+}
+
+/// An image with many array layers of the same extent and format, like the sprites of an animation
+/// or the cascades of a shadow map, which shaders index by layer. Layers are uploaded one at a time
+/// with `upload_layer`, and can be rendered into through views from `ImageView::create_layer`.
+pub struct ImageArray<U: ImageUsageType, F: FormatType> {
+	pub image: Image<U, F, SampleCount1>,
+}
+
+impl<U, F> ImageArray<U, F>
+where
+	U: ImageUsageType,
+	F: FormatType,
+{
+	/// Creates an array of `layers` uninitialized layers of `extent`. It gets the `TRANSFER_DST`
+	/// usage so that layers can be uploaded to it.
+	pub fn create(context: &Context, usage: U, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		assert!(layers > 0);
+		let image = unsafe {
+			Image::create_raw(
+				context,
+				usage.as_dyn() | DynImageUsage::TRANSFER_DST,
+				F::as_raw(),
+				extent,
+				layers,
+				1,
+			)?
+		};
+		Ok(Self { image })
+	}
+
+	/// Replaces the contents of layer `layer` with tightly packed texel data, `extent.width *
+	/// extent.height` texels of type `F::Pixel` in rows from top to bottom
+	pub fn upload_layer_pixels(&mut self, context: &Context, layer: u32, pixels: &[F::Pixel]) -> MarsResult<()> {
+		// Pixel types are arrays of plain numbers, with no padding
+		let data = unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u8, std::mem::size_of_val(pixels)) };
+		self.upload_layer(context, layer, data)
+	}
+
+	/// Replaces the contents of layer `layer` with tightly packed texel data, given as the bytes of
+	/// `F::Pixel`s. The array is left in the layout it was in, unless it had never been written, in
+	/// which case it's left in `TRANSFER_DST_OPTIMAL` layout with its other layers undefined.
+	pub fn upload_layer(&mut self, context: &Context, layer: u32, data: &[u8]) -> MarsResult<()> {
+		trace_span!("ImageArray::upload_layer", layer);
+		let extent = self.image.extent;
+		assert!(
+			layer < self.image.layers,
+			"layer {} is out of range for an array with {} layers",
+			layer,
+			self.image.layers
+		);
+		assert_eq!(
+			data.len(),
+			extent.width as usize * extent.height as usize * std::mem::size_of::<F::Pixel>(),
+			"layer data doesn't match the extent and format of the array"
+		);
+		let staged = context.staging.stage(context, data, copy_alignment::<F>())?;
+
+		// The whole image changes layout so that every layer stays in the same one. Nothing can be
+		// waited on more precisely, since the array may have been used by any kind of command.
+		let old_layout = self.image.layout;
+		let to_transfer = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			src_access_mask: vk::AccessFlags2KHR::MEMORY_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		let restore = match old_layout {
+			vk::ImageLayout::UNDEFINED | vk::ImageLayout::TRANSFER_DST_OPTIMAL => None,
+			_ => Some(ImageTransition {
+				aspect: F::aspect(),
+				src_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+				src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::MEMORY_READ | vk::AccessFlags2KHR::MEMORY_WRITE,
+				old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				new_layout: old_layout,
+			}),
+		};
+		let region = vk::BufferImageCopy {
+			buffer_offset: staged.offset,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: F::aspect(),
+				mip_level: 0,
+				base_array_layer: layer,
+				layer_count: 1,
+			},
+			image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+			image_extent: vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+		};
+		let raw_image = self.image.image.raw;
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			if old_layout != vk::ImageLayout::TRANSFER_DST_OPTIMAL {
+				sync::record_image_transition(context, command_buffer, raw_image, &to_transfer);
+			}
+			raw_device(&context.device).cmd_copy_buffer_to_image(
+				command_buffer,
+				staged.buffer,
+				raw_image,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&[region],
+			);
+			if let Some(restore) = &restore {
+				sync::record_image_transition(context, command_buffer, raw_image, restore);
+			}
+		})?;
+		if restore.is_none() {
+			self.image.layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+		}
+		Ok(())
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.image.extent
+	}
+
+	pub fn layers(&self) -> u32 {
+		self.image.layers
+	}
+}
+
+/// An image array along with an array view of it and a sampler, bound as a combined image sampler
+/// and declared in shaders as a `sampler2DArray`
+pub struct SampledImageArray<F: FormatType> {
+	pub array: ImageArray<usage::SampledImage, F>,
+	pub image_view: ImageView<usage::SampledImage, F, SampleCount1>,
+	pub sampler: Sampler,
+}
+
+impl<F> SampledImageArray<F>
+where
+	F: FormatType,
+{
+	pub fn new(
+		array: ImageArray<usage::SampledImage, F>,
+		image_view: ImageView<usage::SampledImage, F, SampleCount1>,
+		sampler: Sampler,
+	) -> Self {
+		Self {
+			array,
+			image_view,
+			sampler,
+		}
+	}
+
+	pub fn create(context: &Context, mut array: ImageArray<usage::SampledImage, F>) -> MarsResult<Self> {
+		make_shader_readable(context, &mut array.image)?;
+		let image_view = ImageView::create_array(&array.image)?;
+		let sampler = sampler_for_mips(context, array.image.mip_levels)?;
+		Ok(Self::new(array, image_view, sampler))
+	}
+
+	/// Replaces the contents of layer `layer`, leaving the array readable by shaders. Arguments
+	/// already written with the array don't have to be rewritten.
+	pub fn upload_layer(&mut self, context: &Context, layer: u32, data: &[u8]) -> MarsResult<()> {
+		self.array.upload_layer(context, layer, data)
+	}
+
+	/// Like `upload_layer`, with the data given as texels
+	pub fn upload_layer_pixels(&mut self, context: &Context, layer: u32, pixels: &[F::Pixel]) -> MarsResult<()> {
+		self.array.upload_layer_pixels(context, layer, pixels)
+	}
+}
+
+/// Transitions a freshly uploaded image to the layout shaders sample from, if it isn't already
+fn make_shader_readable<F: FormatType>(
+	context: &Context,
+	image: &mut Image<usage::SampledImage, F, SampleCount1>,
+) -> MarsResult<()> {
+	if image.layout != vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL {
+		let transition = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::ALL_TRANSFER,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+			src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+			old_layout: image.layout,
+			new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		};
+		image.transition(context, &transition)?;
+	}
+	Ok(())
+}
+
 pub mod usage {
 	use rk::vk;
 
@@ -370,6 +1385,7 @@ pub mod usage {
 			const COLOR_ATTACHMENT = vk::ImageUsageFlags::COLOR_ATTACHMENT.as_raw();
 			const DEPTH_STENCIL_ATTACHMENT = vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT.as_raw();
 			const INPUT_ATTACHMENT = vk::ImageUsageFlags::INPUT_ATTACHMENT.as_raw();
+			const TRANSIENT_ATTACHMENT = vk::ImageUsageFlags::TRANSIENT_ATTACHMENT.as_raw();
 		}
 	}
 
@@ -414,10 +1430,13 @@ pub mod format {
 	use rk::vk;
 
 	pub unsafe trait FormatType {
-		// TODO: this is not what I mean by pixel. Right now for R8G8B8A8 this is defined as Vec4,
-		// when technically it should be a [u8; 4] by the name. The only purpose for this right now
-		// is for clear values, so maybe the name should be changed to something like that.
-		type Pixel;
+		/// A single texel as it's laid out in memory, for uploading and reading back image data
+		type Pixel: Copy;
+		/// The value attachments of this format are cleared to, a `ColorClearValue` or
+		/// `DepthClearValue` depending on the aspect
+		type ClearValue;
+		/// Whether the format holds unnormalized integers, which attachments can't blend
+		const INTEGER: bool;
 
 		fn as_raw() -> vk::Format;
 
@@ -425,29 +1444,54 @@ pub mod format {
 	}
 
 	macro_rules! format {
-		($name:ident, $raw:ident, $aspect:ident, $pixel:ty) => {
+		($name:ident, $raw:ident, $($aspect:ident)|+, $pixel:ty, $clear:ty, integer) => {
+			format!($name, $raw, $($aspect)|+, $pixel, $clear, true);
+		};
+		($name:ident, $raw:ident, $($aspect:ident)|+, $pixel:ty, $clear:ty) => {
+			format!($name, $raw, $($aspect)|+, $pixel, $clear, false);
+		};
+		($name:ident, $raw:ident, $($aspect:ident)|+, $pixel:ty, $clear:ty, $integer:literal) => {
 			pub struct $name;
 
 			unsafe impl FormatType for $name {
 				type Pixel = $pixel;
+				type ClearValue = $clear;
+				const INTEGER: bool = $integer;
 
 				fn as_raw() -> vk::Format {
 					vk::Format::$raw
 				}
 
 				fn aspect() -> vk::ImageAspectFlags {
-					vk::ImageAspectFlags::$aspect
+					$(vk::ImageAspectFlags::$aspect)|+
 				}
 			}
 		};
 	}
 
-	format!(B8G8R8A8Unorm, B8G8R8A8_UNORM, COLOR, Vec4);
+	format!(B8G8R8A8Unorm, B8G8R8A8_UNORM, COLOR, [u8; 4], Vec4);
+
+	format!(R8Unorm, R8_UNORM, COLOR, u8, f32);
+	format!(R8G8B8A8Unorm, R8G8B8A8_UNORM, COLOR, [u8; 4], Vec4);
+	format!(R8G8B8A8Srgb, R8G8B8A8_SRGB, COLOR, [u8; 4], Vec4);
+
+	// Half floats have no Rust type, so their texels are the raw bits of each component
+	format!(R16G16B16A16Sfloat, R16G16B16A16_SFLOAT, COLOR, [u16; 4], Vec4);
+
+	format!(R16G16Sfloat, R16G16_SFLOAT, COLOR, [u16; 2], Vec4);
+	format!(R32Sfloat, R32_SFLOAT, COLOR, f32, f32);
 
-	format!(R8G8B8A8Unorm, R8G8B8A8_UNORM, COLOR, Vec4);
-	format!(R8G8B8A8Srgb, R8G8B8A8_SRGB, COLOR, Vec4);
+	format!(R8G8B8A8Uint, R8G8B8A8_UINT, COLOR, [u8; 4], Vec4<u32>, integer);
+	format!(R32Uint, R32_UINT, COLOR, u32, u32, integer);
+	format!(R32Sint, R32_SINT, COLOR, i32, i32, integer);
+	format!(R32G32B32A32Uint, R32G32B32A32_UINT, COLOR, [u32; 4], Vec4<u32>, integer);
+	format!(R32G32B32A32Sint, R32G32B32A32_SINT, COLOR, [i32; 4], Vec4<i32>, integer);
 
-	format!(D32Sfloat, D32_SFLOAT, DEPTH, f32);
+	format!(D32Sfloat, D32_SFLOAT, DEPTH, f32, f32);
+	// Formats with a stencil aspect, for functions with a `StencilTest`. Their texels are laid out
+	// however the implementation likes, so they aren't meant to be uploaded or read back.
+	format!(D24UnormS8Uint, D24_UNORM_S8_UINT, DEPTH | STENCIL, u32, f32);
+	format!(D32SfloatS8Uint, D32_SFLOAT_S8_UINT, DEPTH | STENCIL, [u32; 2], f32);
 }
 
 pub mod samples {
@@ -1,41 +1,221 @@
+use std::{
+	ffi::{CStr, CString},
+	os::raw::c_void,
+	sync::Mutex,
+};
+
+use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 use thiserror::Error;
 
 use rk::{
 	ash::extensions,
-	command::CommandPool,
+	command::{CommandBuffer, CommandPool, Recording},
 	device::{Device, Queue},
 	instance::Instance,
 	PhysicalDevice, PhysicalDeviceChooser,
 };
 
+use crate::{
+	debug::ValidationCapture,
+	destruction::DestructionQueue,
+	device::{DeviceExtension, DeviceFeatures, ExtensionFeatures},
+	fault::{Breadcrumbs, DeviceFaultReport},
+	memory::{Allocator, MemoryStats},
+	pass::RenderPassCache,
+	staging::StagingBelt,
+};
+
 // Look at all these leaks
 pub use rk;
 pub use rk::ash;
 pub use rk::ash::vk;
 
+/// Enters a `tracing` span for the rest of the enclosing block, when the `tracing` feature is
+/// enabled. Takes the same arguments as `tracing::info_span!`.
+macro_rules! trace_span {
+	($($args:tt)*) => {
+		#[cfg(feature = "tracing")]
+		let _span = tracing::info_span!($($args)*).entered();
+	};
+}
+
+pub mod accel;
+pub mod atlas;
 pub mod buffer;
+pub(crate) mod builtin;
+pub mod bundle;
+pub mod camera;
+#[cfg(feature = "renderdoc")]
+pub mod capture;
+pub mod color;
+pub mod compute;
+#[cfg(feature = "controllers")]
+pub mod controller;
+pub mod cubemap;
+pub mod culling;
+pub mod debug;
+pub mod debugdraw;
+pub mod deferred;
+pub(crate) mod destruction;
+pub mod device;
+pub mod display;
+#[cfg(target_os = "linux")]
+pub mod dmabuf;
+pub mod drawlist;
+pub mod equirect;
+pub mod exposure;
+pub mod fault;
 pub mod function;
+pub mod grading;
+pub mod hud;
+pub mod ibl;
 pub mod image;
 pub mod math;
+pub mod memory;
+pub mod mipmap;
+pub mod particles;
 pub mod pass;
+pub mod pbr;
+pub(crate) mod pipeline;
+pub mod prepass;
+pub mod registry;
 pub mod render;
+#[cfg(target_os = "linux")]
+pub mod scanout;
+pub mod shader;
+pub mod shapes;
+pub mod skybox;
+pub mod sparse;
+pub mod ssao;
+pub(crate) mod staging;
+pub mod stats;
+pub mod submit;
+pub(crate) mod sync;
+pub mod taa;
 pub mod target;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tonemap;
+pub(crate) mod validation;
 pub mod window;
+#[cfg(feature = "xr")]
+pub mod xr;
 
 pub type MarsResult<T> = rk::VkResult<T>;
 
+/// The instance, device and queue everything in mars is created from.
+///
+/// Contexts are `Send` and `Sync`, so one context can be shared between threads to upload resources
+/// and record work off the main thread. The queue is locked for every submission and the context's
+/// command pool for every command buffer recorded from it, so work submitted from several threads
+/// at once is serialized rather than racing.
 pub struct Context {
+	pub(crate) entry: ash::Entry,
+	pub(crate) instance: Instance,
 	pub(crate) physical_device: PhysicalDevice,
 	pub(crate) device: Device,
+	/// Must be locked with `Queue::with_lock` around every use
 	pub(crate) queue: Queue,
-	pub(crate) command_pool: CommandPool,
+	/// The family of `queue`, which supports graphics and transfer operations
+	pub(crate) queue_family_index: u32,
+	/// More queues from the family of `queue` with their priorities, requested with
+	/// `DeviceFeatures::queue_priorities`. Locked like `queue`.
+	pub(crate) extra_queues: Vec<(f32, Queue)>,
+	/// Queues from other families, along with the family's index, for work `queue` can't do. There's
+	/// one for the family presenting to the window given to `Context::create_for_window` if the
+	/// family of `queue` can't, and one for sparse binding if that's enabled and the family of
+	/// `queue` can't do it. Locked like `queue`.
+	pub(crate) family_queues: Vec<(u32, Queue)>,
+	/// Command pools need external synchronization from allocating command buffers out of them
+	/// until freeing them, so the pool is locked for that whole time
+	pub(crate) command_pool: Mutex<CommandPool>,
+	/// Holds dropped resources until the submissions that might use them complete
+	pub(crate) destruction: DestructionQueue,
+	/// Suballocates the memory of buffers and images
+	pub(crate) allocator: Allocator,
+	/// Stages the data of uploads copied to the device
+	pub(crate) staging: StagingBelt,
+	/// Render passes shared by identical render pass prototypes
+	pub(crate) render_passes: RenderPassCache,
+	pub(crate) features: vk::PhysicalDeviceFeatures,
+	/// Whether the device has resizable BAR, see `Context::has_resizable_bar`
+	pub(crate) resizable_bar: bool,
+	pub(crate) extensions: Vec<DeviceExtension>,
+	/// The fragment sizes functions can shade at and the sample counts each works with, empty
+	/// without `DeviceExtension::FragmentShadingRate`
+	pub(crate) shading_rates: Vec<vk::PhysicalDeviceFragmentShadingRateKHR>,
+	/// Whether `VK_KHR_display` was enabled on the instance
+	pub(crate) direct_display: bool,
+	pub(crate) synchronization2: Option<extensions::khr::Synchronization2>,
+	pub(crate) acceleration_structure: Option<extensions::khr::AccelerationStructure>,
+	pub(crate) mesh_shader: Option<extensions::ext::MeshShader>,
+	pub(crate) extended_dynamic_state: Option<extensions::ext::ExtendedDynamicState>,
+	pub(crate) draw_indirect_count: Option<extensions::khr::DrawIndirectCount>,
+	pub(crate) conditional_rendering: Option<vk::ExtConditionalRenderingFn>,
+	pub(crate) image_drm_format_modifier: Option<vk::ExtImageDrmFormatModifierFn>,
+	pub(crate) device_fault: Option<vk::ExtDeviceFaultFn>,
+	pub(crate) diagnostic_checkpoints: Option<vk::NvDeviceDiagnosticCheckpointsFn>,
+	/// The labels of recorded passes, for reporting where the device was lost
+	pub(crate) breadcrumbs: Breadcrumbs,
 	#[allow(unused)]
 	pub(crate) debug_messenger: Option<rk::DebugUtilsMessengerInner>,
+	/// Keeps validation messages for tests, once enabled
+	pub(crate) validation_capture: Mutex<Option<ValidationCapture>>,
+	/// RenderDoc, if it could be loaded
+	#[cfg(feature = "renderdoc")]
+	pub(crate) frame_capture: Option<capture::FrameCapture>,
 }
 
 impl Context {
 	pub fn create<C: PhysicalDeviceChooser>(app_name: &str, chooser: C) -> Result<Self, ContextCreateError> {
-		let instance = create_instance(app_name)?;
+		Self::create_with_features(app_name, chooser, &DeviceFeatures::default())
+	}
+
+	pub fn create_with_features<C: PhysicalDeviceChooser>(
+		app_name: &str,
+		chooser: C,
+		features: &DeviceFeatures,
+	) -> Result<Self, ContextCreateError> {
+		Self::create_inner(app_name, features, &RawExtensions::default(), None, |_| Ok(chooser))
+	}
+
+	/// Creates a context that can present to `window`. Contexts from the other constructors can only
+	/// present with their graphics queue, which on the rare device whose graphics queue family can't
+	/// present to a window means `WindowEngine::new` fails with `ERROR_INCOMPATIBLE_DISPLAY_KHR`.
+	pub fn create_for_window<C: PhysicalDeviceChooser, W: HasRawWindowHandle>(
+		app_name: &str,
+		chooser: C,
+		features: &DeviceFeatures,
+		window: &W,
+	) -> Result<Self, ContextCreateError> {
+		Self::create_inner(
+			app_name,
+			features,
+			&RawExtensions::default(),
+			Some(window.raw_window_handle()),
+			|_| Ok(chooser),
+		)
+	}
+
+	/// Creates a context with additional extensions enabled by name, and a chooser that may depend
+	/// on the instance, for integrations like OpenXR that dictate parts of device creation
+	pub(crate) fn create_inner<C, P>(
+		app_name: &str,
+		features: &DeviceFeatures,
+		raw_extensions: &RawExtensions,
+		window: Option<RawWindowHandle>,
+		chooser: P,
+	) -> Result<Self, ContextCreateError>
+	where
+		C: PhysicalDeviceChooser,
+		P: FnOnce(&Instance) -> Result<C, ContextCreateError>,
+	{
+		trace_span!("Context::create", app_name);
+		// RenderDoc has to be loaded before the instance is created to hook it
+		#[cfg(feature = "renderdoc")]
+		let frame_capture = capture::FrameCapture::load();
+		let (entry, instance, direct_display) =
+			create_instance(app_name, &raw_extensions.instance, features.direct_display)?;
 
 		let debug_messenger = rk::create_debug_report_callback(
 			&instance,
@@ -46,19 +226,287 @@ impl Context {
 		.map_err(|_| log::warn!("Failed to create debug report callback"))
 		.ok();
 
+		let chooser = chooser(&instance)?;
 		let physical_device =
 			rk::PhysicalDevice::choose(&instance, chooser).map_err(|_| ContextCreateError::NoDevice)?;
-		let (device, queue) = create_device(&physical_device)?;
-		let command_pool = CommandPool::create(&device)?;
+		let requested = features;
+		let supported_features =
+			unsafe { raw_instance(&instance).get_physical_device_features(raw_physical_device(&physical_device)) };
+		let features = requested
+			.resolve(&supported_features)
+			.map_err(ContextCreateError::MissingFeatures)?;
+		let extensions = requested
+			.resolve_extensions(&instance, &physical_device)
+			.map_err(ContextCreateError::MissingExtensions)?;
+		let priorities: Vec<f32> = requested
+			.queue_priorities
+			.iter()
+			.map(|priority| priority.max(0.0).min(1.0))
+			.collect();
+		// The surface is only needed to find a queue family that can present to the window
+		let surface_loader = extensions::khr::Surface::new(&entry, raw_instance(&instance));
+		let surface = match window {
+			Some(handle) => Some(unsafe { crate::window::create_surface(&entry, &instance, handle)? }),
+			None => None,
+		};
+		let device = create_device(
+			&instance,
+			&physical_device,
+			&features,
+			&extensions,
+			&raw_extensions.device,
+			&priorities,
+			surface.map(|surface| (&surface_loader, surface)),
+		);
+		if let Some(surface) = surface {
+			unsafe { surface_loader.destroy_surface(surface, None) };
+		}
+		let (device, queue_family_index, mut queues) = device?;
+		let (_, mut family) = queues.remove(0);
+		let queue = family.remove(0);
+		let extra_queues = priorities.into_iter().zip(family).collect();
+		let queues = queues
+			.into_iter()
+			.map(|(index, mut family)| (index, family.remove(0)))
+			.collect();
+		let command_pool = Mutex::new(CommandPool::create(&device)?);
+
+		let synchronization2 = if extensions.contains(&DeviceExtension::Synchronization2) {
+			Some(extensions::khr::Synchronization2::new(
+				raw_instance(&instance),
+				raw_device(&device),
+			))
+		} else {
+			None
+		};
+		let acceleration_structure = if extensions.contains(&DeviceExtension::AccelerationStructure) {
+			Some(extensions::khr::AccelerationStructure::new(
+				raw_instance(&instance),
+				raw_device(&device),
+			))
+		} else {
+			None
+		};
+		let mesh_shader = if extensions.contains(&DeviceExtension::MeshShader) {
+			Some(extensions::ext::MeshShader::new(
+				raw_instance(&instance),
+				raw_device(&device),
+			))
+		} else {
+			None
+		};
+		let extended_dynamic_state = if extensions.contains(&DeviceExtension::ExtendedDynamicState) {
+			Some(extensions::ext::ExtendedDynamicState::new(
+				raw_instance(&instance),
+				raw_device(&device),
+			))
+		} else {
+			None
+		};
+		let draw_indirect_count = if extensions.contains(&DeviceExtension::DrawIndirectCount) {
+			Some(extensions::khr::DrawIndirectCount::new(
+				raw_instance(&instance),
+				raw_device(&device),
+			))
+		} else {
+			None
+		};
+		// ash has no loaders for these extensions, so their functions are loaded directly
+		let load = |name: &CStr| -> *const c_void {
+			unsafe {
+				std::mem::transmute(
+					raw_instance(&instance).get_device_proc_addr(raw_device(&device).handle(), name.as_ptr()),
+				)
+			}
+		};
+		let conditional_rendering = if extensions.contains(&DeviceExtension::ConditionalRendering) {
+			Some(vk::ExtConditionalRenderingFn::load(load))
+		} else {
+			None
+		};
+		let image_drm_format_modifier = if extensions.contains(&DeviceExtension::ImageDrmFormatModifier) {
+			Some(vk::ExtImageDrmFormatModifierFn::load(load))
+		} else {
+			None
+		};
+		let device_fault = if extensions.contains(&DeviceExtension::DeviceFault) {
+			Some(vk::ExtDeviceFaultFn::load(load))
+		} else {
+			None
+		};
+		let diagnostic_checkpoints = if extensions.contains(&DeviceExtension::DiagnosticCheckpoints) {
+			Some(vk::NvDeviceDiagnosticCheckpointsFn::load(load))
+		} else {
+			None
+		};
+		let shading_rates = if extensions.contains(&DeviceExtension::FragmentShadingRate) {
+			unsafe { function::supported_shading_rates(&entry, &instance, &physical_device)? }
+		} else {
+			Vec::new()
+		};
+		let resizable_bar = memory::has_resizable_bar(&instance, &physical_device);
+		if resizable_bar {
+			log::info!("Resizable BAR is available, vertex, index and uniform buffers will be device local");
+		}
+		let allocator = Allocator::new(&instance, &physical_device, &device);
 
 		Ok(Self {
+			entry,
+			instance,
 			physical_device,
 			device,
 			queue,
+			queue_family_index,
+			extra_queues,
+			family_queues: queues,
 			command_pool,
+			destruction: DestructionQueue::new(),
+			allocator,
+			staging: StagingBelt::new(),
+			render_passes: RenderPassCache::default(),
+			features,
+			resizable_bar,
+			extensions,
+			shading_rates,
+			direct_display,
+			synchronization2,
+			acceleration_structure,
+			mesh_shader,
+			extended_dynamic_state,
+			draw_indirect_count,
+			conditional_rendering,
+			image_drm_format_modifier,
+			device_fault,
+			diagnostic_checkpoints,
+			breadcrumbs: Breadcrumbs::new(),
 			debug_messenger,
+			validation_capture: Mutex::new(None),
+			#[cfg(feature = "renderdoc")]
+			frame_capture,
 		})
 	}
+
+	/// Returns the device features that were enabled when this context was created
+	pub fn features(&self) -> &vk::PhysicalDeviceFeatures {
+		&self.features
+	}
+
+	/// Returns whether the device has resizable BAR, a device local memory heap the host can write to
+	/// that's larger than the fixed 256MiB window. If so, vertex, index and uniform buffers are
+	/// created in device local memory, and uploads to them write to it directly instead of staging.
+	pub fn has_resizable_bar(&self) -> bool {
+		self.resizable_bar
+	}
+
+	/// Returns how much device memory the context has allocated for buffers and images, and how
+	/// much of it is in use. Most resources are suballocated from shared blocks of memory, so the
+	/// number of allocations made from the device stays far below its limit.
+	pub fn memory_stats(&self) -> MemoryStats {
+		self.allocator.stats()
+	}
+
+	/// The number of queues work can be submitted to with `CommandPools::submit_on`, which is one
+	/// more than the number of extra queues created from `DeviceFeatures::queue_priorities`. Queue 0
+	/// is the one everything else in mars submits to.
+	pub fn queue_count(&self) -> usize {
+		1 + self.extra_queues.len()
+	}
+
+	/// The priority queue `index` was created with, or `None` if there's no such queue
+	pub fn queue_priority(&self, index: usize) -> Option<f32> {
+		match index {
+			0 => Some(1.0),
+			_ => self.extra_queues.get(index - 1).map(|(priority, _)| *priority),
+		}
+	}
+
+	/// Queue `index` of the graphics family, as counted by `queue_count`
+	pub(crate) fn graphics_queue(&self, index: usize) -> &Queue {
+		match index {
+			0 => &self.queue,
+			_ => &self.extra_queues[index - 1].1,
+		}
+	}
+
+	/// Returns whether a device extension was enabled when this context was created
+	pub fn has_extension(&self, extension: DeviceExtension) -> bool {
+		self.extensions.contains(&extension)
+	}
+
+	/// Returns whether `VK_KHR_display` was enabled, which presenting straight to displays needs. It's
+	/// requested with `DeviceFeatures::direct_display`.
+	pub fn has_direct_display(&self) -> bool {
+		self.direct_display
+	}
+
+	/// Returns what is known about the device being lost, if a submission has failed because of it
+	pub fn device_fault(&self) -> Option<DeviceFaultReport> {
+		self.breadcrumbs.fault()
+	}
+}
+
+// The instance, device and extension loaders are handles and function tables that are never
+// mutated after creation. The queues are only used inside `Queue::with_lock`, and every other field
+// with state changed through `&self` keeps that state behind a mutex: the command pool, the
+// breadcrumbs, the validation capture and RenderDoc directly, and the destruction queue, allocator,
+// staging belt and render pass cache internally (the destruction queue only holds `Send`
+// destructors; see the others' own `Send` and `Sync` impls for why what they guard is safe to
+// share).
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+/// Extensions to enable by name on top of the ones mars enables itself
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RawExtensions {
+	pub instance: Vec<CString>,
+	pub device: Vec<CString>,
+}
+
+// rk doesn't wrap everything mars needs, so these give access to the underlying ash objects
+
+pub(crate) fn raw_instance(instance: &Instance) -> &ash::Instance {
+	instance
+}
+
+pub(crate) fn raw_device(device: &Device) -> &ash::Device {
+	device
+}
+
+pub(crate) fn raw_physical_device(physical_device: &PhysicalDevice) -> vk::PhysicalDevice {
+	let physical_device: &vk::PhysicalDevice = physical_device;
+	*physical_device
+}
+
+pub(crate) fn raw_queue(queue: &Queue) -> vk::Queue {
+	let queue: &vk::Queue = queue;
+	*queue
+}
+
+pub(crate) fn raw_pipeline_layout(pipeline_layout: &rk::pipe::PipelineLayout) -> vk::PipelineLayout {
+	let pipeline_layout: &vk::PipelineLayout = pipeline_layout;
+	*pipeline_layout
+}
+
+pub(crate) fn raw_descriptor_set_layout(
+	descriptor_set_layout: &rk::pipe::DescriptorSetLayout,
+) -> vk::DescriptorSetLayout {
+	let descriptor_set_layout: &vk::DescriptorSetLayout = descriptor_set_layout;
+	*descriptor_set_layout
+}
+
+pub(crate) fn raw_descriptor_set(descriptor_set: &rk::descriptor::DescriptorSet) -> vk::DescriptorSet {
+	let descriptor_set: &vk::DescriptorSet = descriptor_set;
+	*descriptor_set
+}
+
+pub(crate) fn raw_command_buffer(command_buffer: &CommandBuffer<Recording>) -> vk::CommandBuffer {
+	let command_buffer: &vk::CommandBuffer = command_buffer;
+	*command_buffer
+}
+
+pub(crate) fn raw_command_pool(command_pool: &CommandPool) -> vk::CommandPool {
+	let command_pool: &vk::CommandPool = command_pool;
+	*command_pool
 }
 
 #[derive(Debug, Error)]
@@ -69,18 +517,40 @@ pub enum ContextCreateError {
 	NoDevice,
 	#[error("No queue supporting graphics and transfer operations was found on the selected device")]
 	NoQueue,
+	#[error("The selected device does not support the required features {0:?}")]
+	MissingFeatures(Vec<&'static str>),
+	#[error("The selected device does not support the required extensions {0:?}")]
+	MissingExtensions(Vec<DeviceExtension>),
 	#[error("Vulkan error: {0}")]
 	VulkanError(#[from] vk::Result),
 }
 
-fn create_instance(app_name: &str) -> Result<Instance, ContextCreateError> {
+/// Creates the instance, along with whether `VK_KHR_display` was enabled. It's only enabled when
+/// `direct_display` asks for it and the loader has it, since instance creation fails otherwise.
+fn create_instance(
+	app_name: &str,
+	raw_extensions: &[CString],
+	direct_display: bool,
+) -> Result<(ash::Entry, Instance, bool), ContextCreateError> {
 	let entry = rk::create_entry().expect("Failed to load Vulkan entry");
 
 	let mut extensions = Instance::new_extensions_list();
 	extensions.add_extension::<extensions::ext::DebugUtils>();
 	extensions.add_extension::<extensions::khr::Surface>();
+	let direct_display = direct_display && {
+		let available = entry.enumerate_instance_extension_properties(None)?;
+		available
+			.iter()
+			.any(|properties| unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) } == extensions::khr::Display::name())
+	};
+	if direct_display {
+		extensions.add_extension::<extensions::khr::Display>();
+	}
 	extensions.add_extension::<extensions::khr::XlibSurface>();
 	extensions.add_extension::<extensions::khr::WaylandSurface>();
+	for extension in raw_extensions {
+		extensions.add_extension_name(extension);
+	}
 
 	let instance = Instance::create(
 		&entry,
@@ -93,20 +563,85 @@ fn create_instance(app_name: &str) -> Result<Instance, ContextCreateError> {
 		&extensions,
 	)?;
 
-	Ok(instance)
+	Ok((entry, instance, direct_display))
 }
 
-fn create_device(physical_device: &PhysicalDevice) -> Result<(Device, Queue), ContextCreateError> {
+/// Creates the device along with its queues, the graphics and transfer family's first. The graphics
+/// family gets extra queues with `extra_priorities`, as many as fit in it. Other families only get a
+/// queue when the graphics family can't do their work: presenting to `present_surface`, or sparse
+/// binding when that's enabled.
+fn create_device(
+	instance: &Instance,
+	physical_device: &PhysicalDevice,
+	features: &vk::PhysicalDeviceFeatures,
+	extensions: &[DeviceExtension],
+	raw_extensions: &[CString],
+	extra_priorities: &[f32],
+	present_surface: Option<(&extensions::khr::Surface, vk::SurfaceKHR)>,
+) -> Result<(Device, u32, Vec<(u32, Vec<Queue>)>), ContextCreateError> {
 	let queue_family_index = physical_device
 		.find_queue_family_index(vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER)
 		.ok_or(ContextCreateError::NoQueue)?;
+	let families = unsafe {
+		raw_instance(instance).get_physical_device_queue_family_properties(raw_physical_device(physical_device))
+	};
+	let extra_count = extra_priorities
+		.len()
+		.min(families[queue_family_index as usize].queue_count as usize - 1);
+	let graphics_priorities: Vec<f32> = std::iter::once(1.0)
+		.chain(extra_priorities[..extra_count].iter().copied())
+		.collect();
+	let mut other_families = Vec::new();
+	if let Some((surface_loader, surface)) = present_surface {
+		let supports_present = |index: u32| unsafe {
+			surface_loader.get_physical_device_surface_support(raw_physical_device(physical_device), index, surface)
+		};
+		if !supports_present(queue_family_index)? {
+			for index in 0..families.len() as u32 {
+				if supports_present(index)? {
+					other_families.push(index);
+					break;
+				}
+			}
+		}
+	}
+	let supports_sparse = |index: u32| {
+		families[index as usize]
+			.queue_flags
+			.contains(vk::QueueFlags::SPARSE_BINDING)
+	};
+	if features.sparse_binding == vk::TRUE && !supports_sparse(queue_family_index) {
+		if let Some(index) = (0..families.len() as u32).find(|&index| supports_sparse(index)) {
+			if !other_families.contains(&index) {
+				other_families.push(index);
+			}
+		}
+	}
+	let queue_families: Vec<(u32, &[f32])> = std::iter::once((queue_family_index, graphics_priorities.as_slice()))
+		.chain(other_families.into_iter().map(|index| (index, &[1.0][..])))
+		.collect();
 	let mut device_extensions = Device::new_extensions_list();
 	device_extensions.add_extension::<extensions::khr::Swapchain>();
-	let (device, queue) = Device::create(
+	let mut extension_features = ExtensionFeatures::default();
+	for extension in extensions {
+		device_extensions.add_extension_name(extension.name());
+		extension_features.enable(*extension);
+	}
+	for extension in raw_extensions {
+		device_extensions.add_extension_name(extension);
+	}
+	let mut features = vk::PhysicalDeviceFeatures2::builder().features(*features).build();
+	features.p_next = unsafe { extension_features.chain(extensions) };
+	let (device, queues) = Device::create_with_queue_priorities(
 		physical_device,
-		queue_family_index,
+		&queue_families,
 		vec![String::from("VK_LAYER_KHRONOS_validation")],
 		&device_extensions,
+		&features,
 	)?;
-	Ok((device, queue))
+	Ok((
+		device,
+		queue_family_index,
+		queue_families.into_iter().map(|(index, _)| index).zip(queues).collect(),
+	))
 }
@@ -4,10 +4,13 @@ pub type Vec2<S = Scalar> = nalgebra::Vector2<S>;
 pub type Vec3<S = Scalar> = nalgebra::Vector3<S>;
 pub type Vec4<S = Scalar> = nalgebra::Vector4<S>;
 
+pub type Mat3<S = Scalar> = nalgebra::Matrix3<S>;
 pub type Mat4<S = Scalar> = nalgebra::Matrix4<S>;
 
 pub type Point3<S = Scalar> = nalgebra::Point3<S>;
 
+pub type Quat<S = Scalar> = nalgebra::UnitQuaternion<S>;
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct Mvp {
@@ -16,6 +19,27 @@ pub struct Mvp {
 	pub proj: Mat4,
 }
 
+// The matrices are plain columns of floats with no padding between them
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Mvp {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Mvp {}
+
+// The vector and matrix aliases get their implementations from nalgebra's own bytemuck feature
+#[cfg(feature = "bytemuck")]
+#[allow(dead_code)]
+fn assert_pod() {
+	fn pod<T: bytemuck::Pod>() {}
+	pod::<Vec2>();
+	pod::<Vec3>();
+	pod::<Vec4>();
+	pod::<Mat4>();
+	pod::<Mvp>();
+	pod::<Vec3A>();
+	pod::<GpuMat3>();
+}
+
 impl Mvp {
 	pub fn new(model: Mat4, view: Mat4, proj: Mat4) -> Self {
 		Self { model, view, proj }
@@ -25,3 +49,411 @@ impl Mvp {
 		Self::new(Mat4::identity(), Mat4::identity(), Mat4::identity())
 	}
 }
+
+/// A `Vec3` padded to 16 bytes, the size and alignment of a `vec3` in a uniform or storage block.
+/// Using it for `vec3` members followed by another vector keeps the following members where the
+/// shader expects them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C, align(16))]
+pub struct Vec3A {
+	pub v: Vec3,
+	_padding: f32,
+}
+
+impl Vec3A {
+	pub fn new(x: f32, y: f32, z: f32) -> Self {
+		Self::from(Vec3::new(x, y, z))
+	}
+}
+
+impl From<Vec3> for Vec3A {
+	fn from(v: Vec3) -> Self {
+		Self { v, _padding: 0.0 }
+	}
+}
+
+impl From<Vec3A> for Vec3 {
+	fn from(v: Vec3A) -> Self {
+		v.v
+	}
+}
+
+impl std::ops::Deref for Vec3A {
+	type Target = Vec3;
+
+	fn deref(&self) -> &Vec3 {
+		&self.v
+	}
+}
+
+impl std::ops::DerefMut for Vec3A {
+	fn deref_mut(&mut self) -> &mut Vec3 {
+		&mut self.v
+	}
+}
+
+/// A `Mat3` laid out like a `mat3` in a uniform or storage block, where every column is padded to
+/// the size of a `vec4`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct GpuMat3 {
+	pub columns: [Vec4; 3],
+}
+
+impl From<Mat3> for GpuMat3 {
+	fn from(m: Mat3) -> Self {
+		let column = |i: usize| {
+			let c = m.column(i);
+			Vec4::new(c[0], c[1], c[2], 0.0)
+		};
+		Self {
+			columns: [column(0), column(1), column(2)],
+		}
+	}
+}
+
+impl From<GpuMat3> for Mat3 {
+	fn from(m: GpuMat3) -> Self {
+		Mat3::from_columns(&[m.columns[0].xyz(), m.columns[1].xyz(), m.columns[2].xyz()])
+	}
+}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Vec3A {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Vec3A {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for GpuMat3 {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for GpuMat3 {}
+
+/// A perspective projection with reverse-Z and no far plane, following Vulkan's clip space
+/// conventions (with +Y pointing down). Depth is 1 at the near plane and approaches 0 infinitely far
+/// away, so it must be used with `DepthConvention::Reverse`: a `DepthTest::Greater` depth test and
+/// depth attachments cleared to 0.
+pub fn infinite_reverse_perspective(aspect: f32, fov_y: f32, near: f32) -> Mat4 {
+	let f = 1.0 / (fov_y / 2.0).tan();
+	let mut proj = Mat4::zeros();
+	proj[(0, 0)] = f / aspect;
+	proj[(1, 1)] = -f;
+	proj[(2, 3)] = near;
+	proj[(3, 2)] = -1.0;
+	proj
+}
+
+/// The number of different offsets `taa_jitter` cycles through
+pub const TAA_JITTER_LENGTH: u64 = 8;
+
+/// Element `index` of the Halton sequence of `base`, a low-discrepancy sequence from 0 to 1 whose
+/// first elements cover the range evenly
+pub fn halton(mut index: u32, base: u32) -> f32 {
+	let mut fraction = 1.0;
+	let mut result = 0.0;
+	while index > 0 {
+		fraction /= base as f32;
+		result += fraction * (index % base) as f32;
+		index /= base;
+	}
+	result
+}
+
+/// The sub-pixel offset to render frame `frame` with for temporal anti-aliasing, in pixels from
+/// -0.5 to 0.5. Consecutive frames cycle through the first `TAA_JITTER_LENGTH` points of the
+/// Halton (2, 3) sequence.
+pub fn taa_jitter(frame: u64) -> Vec2 {
+	// The sequence starts at 0, which would be an offset of exactly -0.5 on both axes
+	let index = (frame % TAA_JITTER_LENGTH) as u32 + 1;
+	Vec2::new(halton(index, 2) - 0.5, halton(index, 3) - 0.5)
+}
+
+/// Shifts everything `proj` projects by `jitter` pixels of a viewport `viewport_size` pixels
+/// across, like the offsets of `taa_jitter`. Works with perspective and orthographic projections.
+pub fn jitter_projection(proj: &Mat4, jitter: Vec2, viewport_size: Vec2) -> Mat4 {
+	// Normalized device coordinates span 2 units across the viewport
+	let offset = Vec3::new(2.0 * jitter.x / viewport_size.x, 2.0 * jitter.y / viewport_size.y, 0.0);
+	Mat4::new_translation(&offset) * proj
+}
+
+/// A translation, rotation, and scale, applied to points in the reverse order (scale first)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+	pub translation: Vec3,
+	pub rotation: Quat,
+	pub scale: Vec3,
+}
+
+impl Transform {
+	pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+		Self {
+			translation,
+			rotation,
+			scale,
+		}
+	}
+
+	pub fn identity() -> Self {
+		Self::new(Vec3::zeros(), Quat::identity(), Vec3::new(1.0, 1.0, 1.0))
+	}
+
+	pub fn from_translation(translation: Vec3) -> Self {
+		Self {
+			translation,
+			..Self::identity()
+		}
+	}
+
+	pub fn from_rotation(rotation: Quat) -> Self {
+		Self {
+			rotation,
+			..Self::identity()
+		}
+	}
+
+	pub fn from_scale(scale: Vec3) -> Self {
+		Self {
+			scale,
+			..Self::identity()
+		}
+	}
+
+	/// Rotates about `axisangle`, whose length is the angle in radians, like `Isometry3::new`
+	pub fn from_axis_angle(translation: Vec3, axisangle: Vec3) -> Self {
+		Self::new(translation, Quat::new(axisangle), Vec3::new(1.0, 1.0, 1.0))
+	}
+
+	pub fn with_translation(self, translation: Vec3) -> Self {
+		Self { translation, ..self }
+	}
+
+	pub fn with_rotation(self, rotation: Quat) -> Self {
+		Self { rotation, ..self }
+	}
+
+	pub fn with_scale(self, scale: Vec3) -> Self {
+		Self { scale, ..self }
+	}
+
+	/// The model matrix of this transform
+	pub fn to_matrix(&self) -> Mat4 {
+		Mat4::new_translation(&self.translation)
+			* self.rotation.to_homogeneous()
+			* Mat4::new_nonuniform_scaling(&self.scale)
+	}
+
+	pub fn transform_point(&self, point: &Point3) -> Point3 {
+		Point3::from(self.translation) + self.transform_vector(&point.coords)
+	}
+
+	/// Applies the scale and rotation of this transform to a direction, without translating it
+	pub fn transform_vector(&self, vector: &Vec3) -> Vec3 {
+		self.rotation * vector.component_mul(&self.scale)
+	}
+
+	/// Applies `child` first and then this transform, like nesting `child` under this transform in a
+	/// scene graph. The result is exact unless this transform has a non-uniform scale and `child`
+	/// is rotated, in which case the combined transform would need a shear.
+	pub fn then(&self, child: &Transform) -> Transform {
+		Transform {
+			translation: self.transform_point(&Point3::from(child.translation)).coords,
+			rotation: self.rotation * child.rotation,
+			scale: self.scale.component_mul(&child.scale),
+		}
+	}
+
+	/// The transform that undoes this one. As with `then`, the result is only exact for uniform
+	/// scales.
+	pub fn inverse(&self) -> Transform {
+		let rotation = self.rotation.inverse();
+		let scale = self.scale.map(|s| 1.0 / s);
+		Transform {
+			translation: -(rotation * self.translation).component_mul(&scale),
+			rotation,
+			scale,
+		}
+	}
+}
+
+impl Default for Transform {
+	fn default() -> Self {
+		Self::identity()
+	}
+}
+
+impl std::ops::Mul for Transform {
+	type Output = Transform;
+
+	fn mul(self, rhs: Transform) -> Transform {
+		self.then(&rhs)
+	}
+}
+
+impl From<Transform> for Mat4 {
+	fn from(transform: Transform) -> Mat4 {
+		transform.to_matrix()
+	}
+}
+
+/// Conversion between a math type and its glam equivalent, for projects that already do their math
+/// with glam. Vertex parameters and uniform bindings can also use the glam types directly.
+#[cfg(feature = "glam")]
+pub trait GlamConvert {
+	type Glam;
+
+	fn from_glam(glam: Self::Glam) -> Self;
+
+	fn to_glam(&self) -> Self::Glam;
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Vec2 {
+	type Glam = ::glam::Vec2;
+
+	fn from_glam(glam: Self::Glam) -> Self {
+		let [x, y]: [f32; 2] = glam.into();
+		Vec2::new(x, y)
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		::glam::Vec2::new(self.x, self.y)
+	}
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Vec3 {
+	type Glam = ::glam::Vec3;
+
+	fn from_glam(glam: Self::Glam) -> Self {
+		let [x, y, z]: [f32; 3] = glam.into();
+		Vec3::new(x, y, z)
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		::glam::Vec3::new(self.x, self.y, self.z)
+	}
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Vec4 {
+	type Glam = ::glam::Vec4;
+
+	fn from_glam(glam: Self::Glam) -> Self {
+		let [x, y, z, w]: [f32; 4] = glam.into();
+		Vec4::new(x, y, z, w)
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		::glam::Vec4::new(self.x, self.y, self.z, self.w)
+	}
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Mat4 {
+	type Glam = ::glam::Mat4;
+
+	// Both store their columns contiguously
+	fn from_glam(glam: Self::Glam) -> Self {
+		Mat4::from_column_slice(&glam.to_cols_array())
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		let mut columns = [0.0; 16];
+		columns.copy_from_slice(self.as_slice());
+		::glam::Mat4::from_cols_array(&columns)
+	}
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Quat {
+	type Glam = ::glam::Quat;
+
+	fn from_glam(glam: Self::Glam) -> Self {
+		let [x, y, z, w]: [f32; 4] = glam.into();
+		Quat::new_normalize(nalgebra::Quaternion::new(w, x, y, z))
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		let q = self.quaternion();
+		::glam::Quat::from_xyzw(q.i, q.j, q.k, q.w)
+	}
+}
+
+#[cfg(feature = "glam")]
+impl GlamConvert for Mvp {
+	type Glam = (::glam::Mat4, ::glam::Mat4, ::glam::Mat4);
+
+	fn from_glam((model, view, proj): Self::Glam) -> Self {
+		Mvp::new(Mat4::from_glam(model), Mat4::from_glam(view), Mat4::from_glam(proj))
+	}
+
+	fn to_glam(&self) -> Self::Glam {
+		(self.model.to_glam(), self.view.to_glam(), self.proj.to_glam())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_close(a: &Mat4, b: &Mat4) {
+		assert!((a - b).amax() < 1e-5, "{} != {}", a, b);
+	}
+
+	#[test]
+	fn halton() {
+		assert_eq!(super::halton(0, 2), 0.0);
+		assert_eq!(super::halton(1, 2), 0.5);
+		assert_eq!(super::halton(2, 2), 0.25);
+		assert_eq!(super::halton(3, 2), 0.75);
+		assert_eq!(super::halton(1, 3), 1.0 / 3.0);
+		assert_eq!(super::halton(2, 3), 2.0 / 3.0);
+		assert!((super::halton(4, 3) - 4.0 / 9.0).abs() < 1e-6);
+	}
+
+	#[test]
+	fn taa_jitter_cycles_through_distinct_offsets() {
+		let offsets = (0..TAA_JITTER_LENGTH).map(taa_jitter).collect::<Vec<_>>();
+		assert_eq!(offsets[0], Vec2::new(0.0, 1.0 / 3.0 - 0.5));
+		for (i, offset) in offsets.iter().enumerate() {
+			assert!(offset.iter().all(|x| x.abs() < 0.5));
+			assert!(offsets[..i].iter().all(|other| other != offset));
+			assert_eq!(taa_jitter(i as u64 + TAA_JITTER_LENGTH), *offset);
+		}
+	}
+
+	#[test]
+	fn then_matches_matrix_product() {
+		let parent = Transform::new(
+			Vec3::new(1.0, 2.0, 3.0),
+			Quat::from_euler_angles(0.3, -0.5, 1.2),
+			Vec3::repeat(2.0),
+		);
+		let child = Transform::new(
+			Vec3::new(-4.0, 0.5, 1.0),
+			Quat::from_euler_angles(-1.0, 0.2, 0.4),
+			Vec3::new(0.5, 3.0, 1.0),
+		);
+		assert_close(
+			&parent.then(&child).to_matrix(),
+			&(parent.to_matrix() * child.to_matrix()),
+		);
+		assert_close(&(parent * child).to_matrix(), &parent.then(&child).to_matrix());
+	}
+
+	#[test]
+	fn inverse_undoes_the_transform() {
+		let transform = Transform::new(
+			Vec3::new(1.0, -2.0, 3.0),
+			Quat::from_euler_angles(0.7, 0.1, -0.4),
+			Vec3::repeat(0.25),
+		);
+		let inverse = transform.inverse();
+		assert_close(&transform.then(&inverse).to_matrix(), &Mat4::identity());
+		assert_close(&inverse.then(&transform).to_matrix(), &Mat4::identity());
+		let point = Point3::new(5.0, 6.0, -7.0);
+		let back = inverse.transform_point(&transform.transform_point(&point));
+		assert!((back - point).amax() < 1e-4);
+	}
+}
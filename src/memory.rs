@@ -0,0 +1,622 @@
+//! Suballocation of device memory.
+//!
+//! Devices only allow a few thousand memory allocations to exist at once, so instead of each
+//! buffer and image getting an allocation of its own, memory is allocated in large blocks that
+//! resources are placed in side by side. Resources too large to share a block, and ones whose
+//! memory is exported or imported, still get an allocation of their own. Host visible blocks are
+//! mapped for as long as they exist, so mapping a buffer is free.
+
+use std::{
+	os::raw::c_void,
+	sync::{Arc, Mutex},
+};
+
+use rk::{device::Device, instance::Instance, vk, PhysicalDevice};
+
+use crate::{raw_device, raw_instance, raw_physical_device, Context, MarsResult};
+
+/// Memory is allocated in blocks of this size, or an eighth of the heap for small heaps
+const BLOCK_SIZE: u64 = 64 << 20;
+
+/// Statistics of the device memory a context has allocated, from `Context::memory_stats`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MemoryStats {
+	/// The number of blocks that resources are suballocated from
+	pub blocks: usize,
+	/// The total size of the blocks in bytes
+	pub block_bytes: u64,
+	/// The number of resources placed in blocks
+	pub suballocations: usize,
+	/// The bytes of the blocks in use by resources, including the padding for their alignment
+	pub suballocated_bytes: u64,
+	/// The number of resources with an allocation of their own
+	pub dedicated_allocations: usize,
+	/// The total size of those allocations in bytes
+	pub dedicated_bytes: u64,
+}
+
+impl MemoryStats {
+	/// The number of memory allocations made from the device, which is limited by
+	/// `maxMemoryAllocationCount`
+	pub fn device_allocations(&self) -> usize {
+		self.blocks + self.dedicated_allocations
+	}
+}
+
+/// What a suballocation is for. Each kind of resource is placed in blocks of its own, so
+/// linear and optimally tiled resources are never neighbours that would have to be
+/// `bufferImageGranularity` apart, and only buffers that need it are in blocks allocated with
+/// device addresses.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum ResourceKind {
+	Buffer,
+	DeviceAddressBuffer,
+	LinearImage,
+	OptimalImage,
+}
+
+/// Hands out memory for buffers and images, suballocated from blocks when they're small enough
+#[derive(Clone)]
+pub(crate) struct Allocator {
+	state: Arc<Mutex<AllocatorState>>,
+}
+
+// The mappings of the blocks are only written through by the allocations they're handed out to,
+// which never overlap
+unsafe impl Send for Allocator {}
+unsafe impl Sync for Allocator {}
+
+struct AllocatorState {
+	device: Device,
+	memory_properties: vk::PhysicalDeviceMemoryProperties,
+	pools: Vec<Pool>,
+	next_block: u64,
+	dedicated_allocations: usize,
+	dedicated_bytes: u64,
+}
+
+/// The blocks of one memory type that one kind of resource is suballocated from
+struct Pool {
+	memory_type: u32,
+	kind: ResourceKind,
+	blocks: Vec<Block>,
+}
+
+struct Block {
+	id: u64,
+	memory: vk::DeviceMemory,
+	size: u64,
+	/// Where the whole block is mapped, or null if it isn't host visible
+	mapped: *mut u8,
+	/// The unused ranges of the block as offsets and sizes, sorted by offset and never adjacent
+	free: Vec<(u64, u64)>,
+	/// The number of allocations in the block
+	allocations: usize,
+}
+
+impl Block {
+	/// Takes `size` bytes at an offset that's a multiple of `alignment` from the first free range
+	/// they fit in
+	fn suballocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+		let (index, offset) = self.free.iter().enumerate().find_map(|(i, &(start, len))| {
+			let offset = align(start, alignment);
+			if offset + size <= start + len {
+				Some((i, offset))
+			} else {
+				None
+			}
+		})?;
+		let (start, len) = self.free.remove(index);
+		let end = start + len;
+		// The padding before the allocation and the rest after it stay free
+		if offset + size < end {
+			self.free.insert(index, (offset + size, end - offset - size));
+		}
+		if start < offset {
+			self.free.insert(index, (start, offset - start));
+		}
+		self.allocations += 1;
+		Some(offset)
+	}
+
+	/// Returns a range to the free ranges, merging it with its neighbours
+	fn release(&mut self, offset: u64, size: u64) {
+		let index = self.free.partition_point(|&(start, _)| start < offset);
+		self.free.insert(index, (offset, size));
+		if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+			self.free[index].1 += self.free.remove(index + 1).1;
+		}
+		if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+			self.free[index - 1].1 += self.free.remove(index).1;
+		}
+		self.allocations -= 1;
+	}
+
+	fn used(&self) -> u64 {
+		self.size - self.free.iter().map(|&(_, len)| len).sum::<u64>()
+	}
+}
+
+/// Memory bound to a buffer or image, which is returned to the allocator when dropped
+pub(crate) struct Allocation {
+	pub(crate) memory: vk::DeviceMemory,
+	pub(crate) offset: u64,
+	pub(crate) size: u64,
+	/// Where the allocation is mapped, or null if it isn't host visible
+	mapped: *mut u8,
+	/// The memory type, kind and id of the block the allocation is in, or `None` if it has an
+	/// allocation of its own
+	block: Option<(u32, ResourceKind, u64)>,
+	allocator: Allocator,
+}
+
+// Allocations never overlap, so only their owner writes through their mapping
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+	/// Returns where the allocation is mapped, if it's host visible
+	pub(crate) fn mapped(&self) -> Option<*mut c_void> {
+		if self.mapped.is_null() {
+			None
+		} else {
+			Some(self.mapped as *mut c_void)
+		}
+	}
+}
+
+impl Drop for Allocation {
+	fn drop(&mut self) {
+		self.allocator.free(self);
+	}
+}
+
+impl Allocator {
+	pub(crate) fn new(instance: &Instance, physical_device: &PhysicalDevice, device: &Device) -> Self {
+		let memory_properties = unsafe {
+			raw_instance(instance).get_physical_device_memory_properties(raw_physical_device(physical_device))
+		};
+		Self {
+			state: Arc::new(Mutex::new(AllocatorState {
+				device: device.clone(),
+				memory_properties,
+				pools: Vec::new(),
+				next_block: 0,
+				dedicated_allocations: 0,
+				dedicated_bytes: 0,
+			})),
+		}
+	}
+
+	/// Allocates memory of `memory_type` for a resource of `kind` with `requirements`. It's placed
+	/// in a block unless it's larger than half of one.
+	pub(crate) fn allocate(
+		&self,
+		requirements: vk::MemoryRequirements,
+		memory_type: u32,
+		kind: ResourceKind,
+	) -> MarsResult<Allocation> {
+		let mut state = self.state.lock().unwrap();
+		let block_size = state.block_size(memory_type);
+		if requirements.size > block_size / 2 {
+			let mut flags_info = vk::MemoryAllocateFlagsInfo::builder();
+			if kind == ResourceKind::DeviceAddressBuffer {
+				flags_info = flags_info.flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+			}
+			let allocate_info = vk::MemoryAllocateInfo::builder()
+				.allocation_size(requirements.size)
+				.memory_type_index(memory_type)
+				.push_next(&mut flags_info);
+			drop(state);
+			return unsafe { self.allocate_dedicated(&allocate_info) };
+		}
+
+		let pool_index = match state
+			.pools
+			.iter()
+			.position(|pool| pool.memory_type == memory_type && pool.kind == kind)
+		{
+			Some(index) => index,
+			None => {
+				state.pools.push(Pool {
+					memory_type,
+					kind,
+					blocks: Vec::new(),
+				});
+				state.pools.len() - 1
+			}
+		};
+		let found = state.pools[pool_index].blocks.iter_mut().find_map(|block| {
+			block
+				.suballocate(requirements.size, requirements.alignment)
+				.map(|offset| (block.id, block.memory, block.mapped, offset))
+		});
+		let (id, memory, mapped, offset) = match found {
+			Some(found) => found,
+			None => {
+				let mut block = state.allocate_block(memory_type, kind, block_size)?;
+				let offset = block
+					.suballocate(requirements.size, requirements.alignment)
+					.expect("Allocation doesn't fit in a new block");
+				let found = (block.id, block.memory, block.mapped, offset);
+				state.pools[pool_index].blocks.push(block);
+				found
+			}
+		};
+		Ok(Allocation {
+			memory,
+			offset,
+			size: requirements.size,
+			mapped: if mapped.is_null() {
+				mapped
+			} else {
+				unsafe { mapped.add(offset as usize) }
+			},
+			block: Some((memory_type, kind, id)),
+			allocator: self.clone(),
+		})
+	}
+
+	/// Makes an allocation of its own with `allocate_info`, for memory that has to be dedicated to a
+	/// resource, like memory that's exported
+	pub(crate) unsafe fn allocate_dedicated(&self, allocate_info: &vk::MemoryAllocateInfo) -> MarsResult<Allocation> {
+		let mut state = self.state.lock().unwrap();
+		let device = raw_device(&state.device);
+		let memory = device.allocate_memory(allocate_info, None)?;
+		let mapped = match state.map(memory, allocate_info.memory_type_index) {
+			Ok(mapped) => mapped,
+			Err(e) => {
+				device.free_memory(memory, None);
+				return Err(e);
+			}
+		};
+		state.dedicated_allocations += 1;
+		state.dedicated_bytes += allocate_info.allocation_size;
+		Ok(Allocation {
+			memory,
+			offset: 0,
+			size: allocate_info.allocation_size,
+			mapped,
+			block: None,
+			allocator: self.clone(),
+		})
+	}
+
+	/// Takes ownership of memory allocated elsewhere, like memory imported from outside of Vulkan,
+	/// to be freed like a dedicated allocation
+	pub(crate) fn adopt(&self, memory: vk::DeviceMemory, size: u64) -> Allocation {
+		let mut state = self.state.lock().unwrap();
+		state.dedicated_allocations += 1;
+		state.dedicated_bytes += size;
+		Allocation {
+			memory,
+			offset: 0,
+			size,
+			mapped: std::ptr::null_mut(),
+			block: None,
+			allocator: self.clone(),
+		}
+	}
+
+	fn free(&self, allocation: &Allocation) {
+		let mut state = self.state.lock().unwrap();
+		let (memory_type, kind, id) = match allocation.block {
+			Some(block) => block,
+			None => {
+				state.dedicated_allocations -= 1;
+				state.dedicated_bytes -= allocation.size;
+				// Freeing the memory unmaps it too
+				unsafe { raw_device(&state.device).free_memory(allocation.memory, None) };
+				return;
+			}
+		};
+		let device = state.device.clone();
+		let pool = state
+			.pools
+			.iter_mut()
+			.find(|pool| pool.memory_type == memory_type && pool.kind == kind)
+			.expect("Freed an allocation from a pool that doesn't exist");
+		let index = pool
+			.blocks
+			.iter()
+			.position(|block| block.id == id)
+			.expect("Freed an allocation from a block that doesn't exist");
+		let block = &mut pool.blocks[index];
+		block.release(allocation.offset, allocation.size);
+		// One empty block is kept around for the next allocation instead of being freed
+		if block.allocations == 0 && pool.blocks.len() > 1 {
+			let block = pool.blocks.swap_remove(index);
+			unsafe { raw_device(&device).free_memory(block.memory, None) };
+		}
+	}
+
+	pub(crate) fn stats(&self) -> MemoryStats {
+		let state = self.state.lock().unwrap();
+		let mut stats = MemoryStats {
+			dedicated_allocations: state.dedicated_allocations,
+			dedicated_bytes: state.dedicated_bytes,
+			..Default::default()
+		};
+		for block in state.pools.iter().flat_map(|pool| &pool.blocks) {
+			stats.blocks += 1;
+			stats.block_bytes += block.size;
+			stats.suballocations += block.allocations;
+			stats.suballocated_bytes += block.used();
+		}
+		stats
+	}
+}
+
+impl AllocatorState {
+	fn block_size(&self, memory_type: u32) -> u64 {
+		let heap_index = self.memory_properties.memory_types[memory_type as usize].heap_index;
+		BLOCK_SIZE.min(self.memory_properties.memory_heaps[heap_index as usize].size / 8)
+	}
+
+	fn allocate_block(&mut self, memory_type: u32, kind: ResourceKind, size: u64) -> MarsResult<Block> {
+		let device = raw_device(&self.device);
+		let mut flags_info = vk::MemoryAllocateFlagsInfo::builder();
+		if kind == ResourceKind::DeviceAddressBuffer {
+			flags_info = flags_info.flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+		}
+		let allocate_info = vk::MemoryAllocateInfo::builder()
+			.allocation_size(size)
+			.memory_type_index(memory_type)
+			.push_next(&mut flags_info);
+		unsafe {
+			let memory = device.allocate_memory(&allocate_info, None)?;
+			let mapped = match self.map(memory, memory_type) {
+				Ok(mapped) => mapped,
+				Err(e) => {
+					device.free_memory(memory, None);
+					return Err(e);
+				}
+			};
+			self.next_block += 1;
+			Ok(Block {
+				id: self.next_block,
+				memory,
+				size,
+				mapped,
+				free: vec![(0, size)],
+				allocations: 0,
+			})
+		}
+	}
+
+	/// Maps the whole of `memory` if its type is host visible, or returns null
+	unsafe fn map(&self, memory: vk::DeviceMemory, memory_type: u32) -> MarsResult<*mut u8> {
+		let properties = self.memory_properties.memory_types[memory_type as usize].property_flags;
+		if !properties.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+			return Ok(std::ptr::null_mut());
+		}
+		let mapped = raw_device(&self.device).map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?;
+		Ok(mapped as *mut u8)
+	}
+}
+
+impl Drop for AllocatorState {
+	fn drop(&mut self) {
+		// Every allocation holds on to the allocator, so the blocks are all empty by now
+		let device = raw_device(&self.device);
+		for block in self.pools.drain(..).flat_map(|pool| pool.blocks) {
+			unsafe { device.free_memory(block.memory, None) };
+		}
+	}
+}
+
+fn align(offset: u64, alignment: u64) -> u64 {
+	(offset + alignment - 1) / alignment * alignment
+}
+
+/// A buffer with memory from the context's allocator, which backs `Buffer`s and internal uses
+/// that rk's buffers don't cover (such as buffers that need device addresses)
+pub(crate) struct DeviceBuffer {
+	device: Device,
+	pub(crate) buffer: vk::Buffer,
+	allocation: Allocation,
+	pub(crate) size: u64,
+}
+
+impl DeviceBuffer {
+	pub(crate) fn create(
+		context: &Context,
+		size: u64,
+		usage: vk::BufferUsageFlags,
+		properties: vk::MemoryPropertyFlags,
+	) -> MarsResult<Self> {
+		let device = raw_device(&context.device);
+		unsafe {
+			let create_info = vk::BufferCreateInfo::builder()
+				.size(size)
+				.usage(usage)
+				.sharing_mode(vk::SharingMode::EXCLUSIVE);
+			let buffer = device.create_buffer(&create_info, None)?;
+			let requirements = device.get_buffer_memory_requirements(buffer);
+			let kind = if usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+				ResourceKind::DeviceAddressBuffer
+			} else {
+				ResourceKind::Buffer
+			};
+			let allocation = find_memory_type(context, requirements.memory_type_bits, properties)
+				.and_then(|memory_type| context.allocator.allocate(requirements, memory_type, kind))
+				.map_err(|e| {
+					device.destroy_buffer(buffer, None);
+					e
+				})?;
+			if let Err(e) = device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+				device.destroy_buffer(buffer, None);
+				return Err(e);
+			}
+
+			Ok(Self {
+				device: context.device.clone(),
+				buffer,
+				allocation,
+				size,
+			})
+		}
+	}
+
+	/// Creates a host visible buffer filled with `data`
+	pub(crate) fn make<T: Copy>(context: &Context, usage: vk::BufferUsageFlags, data: &[T]) -> MarsResult<Self> {
+		Self::make_with_properties(
+			context,
+			usage,
+			vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+			data,
+		)
+	}
+
+	/// Creates a buffer filled with `data` in memory with `properties`, which must include host
+	/// visible and coherent
+	pub(crate) fn make_with_properties<T: Copy>(
+		context: &Context,
+		usage: vk::BufferUsageFlags,
+		properties: vk::MemoryPropertyFlags,
+		data: &[T],
+	) -> MarsResult<Self> {
+		let size = (data.len() * std::mem::size_of::<T>()) as u64;
+		let buffer = Self::create(context, size, usage, properties)?;
+		unsafe { buffer.write(0, data)? };
+		Ok(buffer)
+	}
+
+	/// Returns where the buffer is mapped. The buffer must be host visible and coherent.
+	pub(crate) unsafe fn map(&self) -> MarsResult<*mut c_void> {
+		self.allocation.mapped().ok_or(vk::Result::ERROR_MEMORY_MAP_FAILED)
+	}
+
+	/// Writes `data` at `offset` bytes into the buffer. The buffer must be host visible and coherent.
+	pub(crate) unsafe fn write<T: Copy>(&self, offset: u64, data: &[T]) -> MarsResult<()> {
+		let ptr = (self.map()? as *mut u8).add(offset as usize);
+		std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut T, data.len());
+		Ok(())
+	}
+
+	/// Reads `len` values from `offset` bytes into the buffer. The buffer must be host visible and
+	/// coherent.
+	pub(crate) unsafe fn read<T: Copy>(&self, offset: u64, len: usize) -> MarsResult<Vec<T>> {
+		let ptr = (self.map()? as *const u8).add(offset as usize);
+		Ok(std::slice::from_raw_parts(ptr as *const T, len).to_vec())
+	}
+
+	pub(crate) fn device_address(&self) -> vk::DeviceAddress {
+		let info = vk::BufferDeviceAddressInfo::builder().buffer(self.buffer);
+		unsafe { raw_device(&self.device).get_buffer_device_address(&info) }
+	}
+}
+
+impl Drop for DeviceBuffer {
+	fn drop(&mut self) {
+		// The allocation is freed after the buffer is destroyed
+		unsafe { raw_device(&self.device).destroy_buffer(self.buffer, None) };
+	}
+}
+
+/// Heaps at most this large that are both device local and host visible are the fixed 256MiB
+/// window into video memory PCIe devices have without resizable BAR
+const BAR_WINDOW_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Returns whether the device has resizable BAR, meaning host visible memory in a device local heap
+/// larger than the fixed BAR window, which the host can write to directly instead of staging
+pub(crate) fn has_resizable_bar(instance: &Instance, physical_device: &PhysicalDevice) -> bool {
+	let memory_properties =
+		unsafe { raw_instance(instance).get_physical_device_memory_properties(raw_physical_device(physical_device)) };
+	let properties = vk::MemoryPropertyFlags::DEVICE_LOCAL
+		| vk::MemoryPropertyFlags::HOST_VISIBLE
+		| vk::MemoryPropertyFlags::HOST_COHERENT;
+	memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+		.iter()
+		.filter(|memory_type| memory_type.property_flags.contains(properties))
+		.map(|memory_type| memory_properties.memory_heaps[memory_type.heap_index as usize])
+		.any(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL) && heap.size > BAR_WINDOW_SIZE)
+}
+
+/// Finds the index of a memory type allowed by `type_bits` that has all of `properties`
+pub(crate) fn find_memory_type(
+	context: &Context,
+	type_bits: u32,
+	properties: vk::MemoryPropertyFlags,
+) -> MarsResult<u32> {
+	let memory_properties = unsafe {
+		raw_instance(&context.instance)
+			.get_physical_device_memory_properties(raw_physical_device(&context.physical_device))
+	};
+	(0..memory_properties.memory_type_count)
+		.find(|&i| {
+			type_bits & (1 << i) != 0
+				&& memory_properties.memory_types[i as usize]
+					.property_flags
+					.contains(properties)
+		})
+		.ok_or(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn block(size: u64) -> Block {
+		Block {
+			id: 0,
+			memory: vk::DeviceMemory::null(),
+			size,
+			mapped: std::ptr::null_mut(),
+			free: vec![(0, size)],
+			allocations: 0,
+		}
+	}
+
+	#[test]
+	fn suballocate_aligns_and_keeps_padding_free() {
+		let mut block = block(1024);
+		assert_eq!(block.suballocate(10, 1), Some(0));
+		assert_eq!(block.suballocate(100, 64), Some(64));
+		assert_eq!(block.free, vec![(10, 54), (164, 860)]);
+		assert_eq!(block.used(), 110);
+		assert_eq!(block.allocations, 2);
+	}
+
+	#[test]
+	fn suballocate_fills_padding_before_the_rest() {
+		let mut block = block(1024);
+		block.suballocate(10, 1).unwrap();
+		block.suballocate(100, 64).unwrap();
+		assert_eq!(block.suballocate(40, 4), Some(12));
+		assert_eq!(block.free, vec![(10, 2), (52, 12), (164, 860)]);
+	}
+
+	#[test]
+	fn suballocate_fails_when_nothing_fits() {
+		let mut block = block(256);
+		assert_eq!(block.suballocate(256, 1), Some(0));
+		assert!(block.free.is_empty());
+		assert_eq!(block.suballocate(1, 1), None);
+		assert_eq!(block.allocations, 1);
+	}
+
+	#[test]
+	fn release_merges_with_both_neighbours() {
+		let mut block = block(300);
+		let a = block.suballocate(100, 1).unwrap();
+		let b = block.suballocate(100, 1).unwrap();
+		let c = block.suballocate(100, 1).unwrap();
+		block.release(a, 100);
+		block.release(c, 100);
+		assert_eq!(block.free, vec![(0, 100), (200, 100)]);
+		block.release(b, 100);
+		assert_eq!(block.free, vec![(0, 300)]);
+		assert_eq!(block.allocations, 0);
+		assert_eq!(block.used(), 0);
+	}
+
+	#[test]
+	fn release_keeps_separate_ranges_apart() {
+		let mut block = block(300);
+		let a = block.suballocate(100, 1).unwrap();
+		block.suballocate(100, 1).unwrap();
+		block.release(a, 100);
+		assert_eq!(block.free, vec![(0, 100), (200, 100)]);
+	}
+}
@@ -0,0 +1,360 @@
+//! Mip chain generation with a compute shader, as an alternative to a chain of blits.
+//!
+//! `MipGenerator` downsamples a whole mip chain of up to 13 levels in a single dispatch, in the style
+//! of AMD's single pass downsampler (SPD). Every workgroup reduces a 64x64 block of the base level
+//! down to mip 6, and the last workgroup to finish goes on to reduce mip 6 down to the end of the
+//! chain, so no barriers between levels are needed on the host side.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, StorageBufferUsage},
+	builtin::CachedArguments,
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	function::{compile_shader, Binding, BindingDesc, BindingType, Storage, StorageImageViews},
+	image::{DynImageUsage, FormatType, Image, ImageUsageType, ImageViewHandle, SampleCount1},
+	render::RenderEngine,
+	shader::ShaderStage,
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+/// The most mip levels a chain can have to be generated by a `MipGenerator`, including the base
+/// level. This covers images of up to 4096x4096.
+pub const MAX_MIP_LEVELS: u32 = 13;
+
+const MIP_CHAIN_LEN: u32 = MAX_MIP_LEVELS - 1;
+
+// `FORMAT` is replaced with the GLSL image format qualifier of the image being downsampled
+const SPD_SHADER: &str = r#"
+#version 450
+
+// Generates up to 12 mips below mip 0 in a single dispatch, in the style of AMD's single pass
+// downsampler. Each workgroup reduces a 64x64 block of mip 0 down to mip 6, and the last workgroup
+// to finish reduces mip 6 down to the rest of the chain.
+
+layout(local_size_x = 256) in;
+
+layout(set = 0, binding = 0, FORMAT) uniform readonly image2D source;
+// Mip `i + 1` of the image
+layout(set = 0, binding = 1, FORMAT) uniform coherent image2D mips[12];
+layout(set = 0, binding = 2) coherent buffer Globals {
+	uint counter;
+	uint mip_count;
+};
+
+shared vec4 tile[32][32];
+shared bool is_last;
+
+ivec2 mip_size(uint mip) {
+	switch (mip) {
+	case 0: return imageSize(mips[0]);
+	case 1: return imageSize(mips[1]);
+	case 2: return imageSize(mips[2]);
+	case 3: return imageSize(mips[3]);
+	case 4: return imageSize(mips[4]);
+	case 5: return imageSize(mips[5]);
+	case 6: return imageSize(mips[6]);
+	case 7: return imageSize(mips[7]);
+	case 8: return imageSize(mips[8]);
+	case 9: return imageSize(mips[9]);
+	case 10: return imageSize(mips[10]);
+	default: return imageSize(mips[11]);
+	}
+}
+
+vec4 load(int mip, ivec2 p) {
+	if (mip < 0) {
+		return imageLoad(source, min(p, imageSize(source) - 1));
+	}
+	p = min(p, mip_size(uint(mip)) - 1);
+	switch (mip) {
+	case 0: return imageLoad(mips[0], p);
+	case 1: return imageLoad(mips[1], p);
+	case 2: return imageLoad(mips[2], p);
+	case 3: return imageLoad(mips[3], p);
+	case 4: return imageLoad(mips[4], p);
+	case 5: return imageLoad(mips[5], p);
+	case 6: return imageLoad(mips[6], p);
+	case 7: return imageLoad(mips[7], p);
+	case 8: return imageLoad(mips[8], p);
+	case 9: return imageLoad(mips[9], p);
+	case 10: return imageLoad(mips[10], p);
+	default: return imageLoad(mips[11], p);
+	}
+}
+
+void store(uint mip, ivec2 p, vec4 value) {
+	if (mip >= mip_count || any(greaterThanEqual(p, mip_size(mip)))) {
+		return;
+	}
+	switch (mip) {
+	case 0: imageStore(mips[0], p, value); break;
+	case 1: imageStore(mips[1], p, value); break;
+	case 2: imageStore(mips[2], p, value); break;
+	case 3: imageStore(mips[3], p, value); break;
+	case 4: imageStore(mips[4], p, value); break;
+	case 5: imageStore(mips[5], p, value); break;
+	case 6: imageStore(mips[6], p, value); break;
+	case 7: imageStore(mips[7], p, value); break;
+	case 8: imageStore(mips[8], p, value); break;
+	case 9: imageStore(mips[9], p, value); break;
+	case 10: imageStore(mips[10], p, value); break;
+	default: imageStore(mips[11], p, value); break;
+	}
+}
+
+// Reduces the 64x64 block at `origin` of `input_mip` (-1 being the source) into the mips from
+// `first` to `first + 5`
+void downsample_block(int input_mip, ivec2 origin, uint first) {
+	uint index = gl_LocalInvocationIndex;
+	for (uint i = 0; i < 4; i++) {
+		uint texel = index + i * 256;
+		ivec2 local = ivec2(texel % 32, texel / 32);
+		ivec2 p = origin + local * 2;
+		vec4 value = (load(input_mip, p) + load(input_mip, p + ivec2(1, 0)) + load(input_mip, p + ivec2(0, 1))
+			+ load(input_mip, p + ivec2(1, 1))) * 0.25;
+		store(first, origin / 2 + local, value);
+		tile[local.y][local.x] = value;
+	}
+	barrier();
+
+	uint size = 16;
+	for (uint level = 1; level < 6; level++) {
+		ivec2 local = ivec2(index % size, index / size);
+		bool active = index < size * size;
+		vec4 value;
+		if (active) {
+			ivec2 p = local * 2;
+			value = (tile[p.y][p.x] + tile[p.y][p.x + 1] + tile[p.y + 1][p.x] + tile[p.y + 1][p.x + 1]) * 0.25;
+		}
+		barrier();
+		if (active) {
+			store(first + level, origin / (2 << level) + local, value);
+			tile[local.y][local.x] = value;
+		}
+		barrier();
+		size /= 2;
+	}
+}
+
+void main() {
+	downsample_block(-1, ivec2(gl_WorkGroupID.xy) * 64, 0);
+	if (mip_count <= 6) {
+		return;
+	}
+
+	memoryBarrierImage();
+	barrier();
+	if (gl_LocalInvocationIndex == 0) {
+		uint groups = gl_NumWorkGroups.x * gl_NumWorkGroups.y;
+		is_last = atomicAdd(counter, 1) == groups - 1;
+	}
+	barrier();
+	if (!is_last) {
+		return;
+	}
+
+	downsample_block(5, ivec2(0), 6);
+	if (gl_LocalInvocationIndex == 0) {
+		counter = 0;
+	}
+}
+"#;
+
+/// The GLSL image format qualifier for storage images of format `F`, if mip generation supports it
+fn format_qualifier<F: FormatType>() -> Option<&'static str> {
+	match F::as_raw() {
+		vk::Format::R8G8B8A8_UNORM => Some("rgba8"),
+		vk::Format::R16G16B16A16_SFLOAT => Some("rgba16f"),
+		_ => None,
+	}
+}
+
+struct MipFunction<F>(PhantomData<F>);
+
+impl<F> ComputeFunctionPrototype for MipFunction<F>
+where
+	F: FormatType,
+{
+	type Bindings = (MipSource, MipChain, Storage<SpdGlobals>);
+}
+
+/// The base level of the mip chain
+struct MipSource;
+
+unsafe impl Binding for MipSource {
+	type Argument = StorageImageViews;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageImage,
+			count: 1,
+		}
+	}
+}
+
+/// The levels of the mip chain below the base level. Levels past the end of the chain are bound to
+/// the last level and never written.
+struct MipChain;
+
+unsafe impl Binding for MipChain {
+	type Argument = StorageImageViews;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::StorageImage,
+			count: MIP_CHAIN_LEN,
+		}
+	}
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SpdGlobals {
+	// The number of workgroups that have finished the first six levels
+	counter: u32,
+	// The number of levels to generate below the base level
+	mip_count: u32,
+}
+
+struct MipArguments<F: FormatType> {
+	arguments: ComputeArgumentsContainer<MipFunction<F>>,
+	// The views written through the arguments, one per mip level
+	_views: Vec<ImageViewHandle>,
+}
+
+/// Generates the mip chains of images of format `F`. Only `R8G8B8A8Unorm` and
+/// `R16G16B16A16Sfloat` images are supported.
+pub struct MipGenerator<F: FormatType> {
+	function: ComputeFunctionDef<MipFunction<F>>,
+	// Keyed by the image downsampled and its number of mip levels
+	arguments: CachedArguments<(vk::Image, u32), MipArguments<F>>,
+}
+
+impl<F> MipGenerator<F>
+where
+	F: FormatType,
+{
+	/// Creates a mip generator, failing with `ERROR_FORMAT_NOT_SUPPORTED` if images of format `F`
+	/// can't be downsampled
+	pub fn create(context: &Context) -> MarsResult<Self> {
+		let qualifier = format_qualifier::<F>().ok_or(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
+		let source = SPD_SHADER.replace("FORMAT", qualifier);
+		let comp = compile_shader(&source, "spd.comp", ShaderStage::Compute)?;
+		let function = ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(comp) })?;
+		Ok(Self {
+			function,
+			arguments: CachedArguments::new(),
+		})
+	}
+
+	/// Fills every level of the mip chain of `image` below the base level by repeatedly averaging
+	/// 2x2 blocks of texels. The image must have been created with the `STORAGE` usage and a single
+	/// array layer, and have at most `MAX_MIP_LEVELS` levels.
+	pub fn generate<U>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		image: &mut Image<U, F, SampleCount1>,
+	) -> MarsResult<()>
+	where
+		U: ImageUsageType,
+	{
+		assert!(
+			image.usage().contains(DynImageUsage::STORAGE),
+			"images must have the storage usage to generate mips with compute"
+		);
+		assert_eq!(image.layers(), 1, "mip generation doesn't support layered images");
+		assert!(
+			image.mip_levels() <= MAX_MIP_LEVELS,
+			"too many mip levels to generate in one dispatch"
+		);
+
+		let mip_levels = image.mip_levels();
+		if mip_levels < 2 {
+			return Ok(());
+		}
+
+		let function = &mut self.function;
+		let cached = self.arguments.make_or_reuse((image.image.raw, mip_levels), || {
+			let views = (0..mip_levels)
+				.map(|level| {
+					ImageViewHandle::create(
+						&image.image,
+						vk::ImageViewType::TYPE_2D,
+						F::as_raw(),
+						vk::ImageSubresourceRange {
+							aspect_mask: vk::ImageAspectFlags::COLOR,
+							base_mip_level: level,
+							level_count: 1,
+							base_array_layer: 0,
+							layer_count: 1,
+						},
+					)
+				})
+				.collect::<MarsResult<Vec<_>>>()?;
+			let mut chain = views[1..].iter().map(|view| view.raw).collect::<Vec<_>>();
+			let last = *chain.last().unwrap();
+			chain.resize(MIP_CHAIN_LEN as usize, last);
+			let globals = Buffer::<StorageBufferUsage, _>::make_item_buffer(
+				context,
+				SpdGlobals {
+					counter: 0,
+					mip_count: mip_levels - 1,
+				},
+			)?;
+			let arguments = function.make_arguments(
+				context,
+				(StorageImageViews(vec![views[0].raw]), StorageImageViews(chain), globals),
+			)?;
+			Ok(MipArguments {
+				arguments,
+				_views: views,
+			})
+		})?;
+		let arguments = &cached.arguments;
+
+		let layout = image.layout;
+		image.record_transition(
+			context,
+			engine,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::MEMORY_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_STORAGE_READ | vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+				old_layout: layout,
+				new_layout: vk::ImageLayout::GENERAL,
+			},
+		)?;
+		let extent = image.extent();
+		let result = engine.dispatch(
+			context,
+			&self.function,
+			arguments,
+			[(extent.width + 63) / 64, (extent.height + 63) / 64, 1],
+		);
+		// There's no going back to the undefined layout, so images that had never been used are left
+		// in the general layout
+		if layout != vk::ImageLayout::UNDEFINED && layout != vk::ImageLayout::GENERAL {
+			image.record_transition(
+				context,
+				engine,
+				&ImageTransition {
+					aspect: vk::ImageAspectFlags::COLOR,
+					src_stage_mask: vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+					dst_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+					src_access_mask: vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+					dst_access_mask: vk::AccessFlags2KHR::MEMORY_READ | vk::AccessFlags2KHR::MEMORY_WRITE,
+					old_layout: vk::ImageLayout::GENERAL,
+					new_layout: layout,
+				},
+			)?;
+		}
+		result
+	}
+}
@@ -0,0 +1,361 @@
+//! GPU particle systems.
+//!
+//! Particles live in a storage buffer on the device. Every update, a compute shader integrates the
+//! living particles and appends their indices to a list, counting them into the instance count of
+//! an indirect draw. The particles are then drawn as camera-facing billboards with that draw, so the
+//! number of living particles never has to be read back on the host.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	buffer::{
+		Buffer, IndexBufferUsage, IndirectBufferUsage, StorageBufferUsage, UniformBufferUsage, VertexBufferUsage,
+	},
+	color::Rgba,
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionImpl, ComputeFunctionPrototype},
+	function::{
+		compile_shader, ArgumentsContainer, Binding, BindingDesc, BindingType, FunctionDef, FunctionImpl,
+		FunctionPrototype, RawStorage, RawStorageArgument,
+	},
+	math::*,
+	pass::{RenderPass, RenderPassPrototype},
+	render::{IndirectDrawArgs, RenderEngine},
+	shader::ShaderStage,
+	target::Target,
+	Context, MarsResult,
+};
+
+const PARTICLE_DECLARATIONS: &str = r#"
+struct Particle {
+	vec3 position;
+	float life;
+	vec3 velocity;
+	float size;
+	vec4 color;
+};
+"#;
+
+const SIMULATE_SHADER: &str = r#"
+layout(local_size_x = 256) in;
+
+struct DrawCommand {
+	uint index_count;
+	uint instance_count;
+	uint first_index;
+	int vertex_offset;
+	uint first_instance;
+};
+
+layout(set = 0, binding = 0) buffer Particles {
+	Particle particles[];
+};
+layout(set = 0, binding = 1) writeonly buffer Alive {
+	uint alive[];
+};
+layout(set = 0, binding = 2) buffer Commands {
+	DrawCommand command;
+};
+layout(set = 0, binding = 3) uniform Simulation {
+	vec4 gravity;
+	float dt;
+	uint count;
+};
+
+void main() {
+	uint i = gl_GlobalInvocationID.x;
+	if (i >= count) {
+		return;
+	}
+	Particle particle = particles[i];
+	if (particle.life <= 0.0) {
+		return;
+	}
+
+	particle.velocity += gravity.xyz * dt;
+	particle.position += particle.velocity * dt;
+	particle.life -= dt;
+	particles[i] = particle;
+
+	if (particle.life > 0.0) {
+		alive[atomicAdd(command.instance_count, 1)] = i;
+	}
+}
+"#;
+
+const BILLBOARD_VERTEX_SHADER: &str = r#"
+layout(location = 0) in vec2 corner;
+
+layout(set = 0, binding = 0) uniform Camera {
+	mat4 model;
+	mat4 view;
+	mat4 proj;
+};
+layout(set = 0, binding = 1) readonly buffer Particles {
+	Particle particles[];
+};
+layout(set = 0, binding = 2) readonly buffer Alive {
+	uint alive[];
+};
+
+layout(location = 0) out vec2 uv;
+layout(location = 1) out vec4 color;
+
+void main() {
+	Particle particle = particles[alive[gl_InstanceIndex]];
+	vec4 center = view * model * vec4(particle.position, 1.0);
+	center.xy += corner * particle.size;
+	gl_Position = proj * center;
+	uv = corner;
+	color = particle.color;
+}
+"#;
+
+const BILLBOARD_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+	float falloff = 1.0 - dot(uv, uv);
+	if (falloff <= 0.0) {
+		discard;
+	}
+	out_color = vec4(color.rgb, color.a * falloff);
+}
+"#;
+
+/// The state of a single particle, laid out as the `Particle` struct of the particle shaders
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Particle {
+	pub position: Vec3,
+	/// The remaining lifetime of the particle in seconds. Particles with no lifetime left are dead
+	/// and aren't simulated or drawn.
+	pub life: f32,
+	pub velocity: Vec3,
+	/// The half-width of the particle's billboard in view space
+	pub size: f32,
+	pub color: Rgba,
+}
+
+impl Particle {
+	fn dead() -> Self {
+		Self {
+			position: Vec3::zeros(),
+			life: 0.0,
+			velocity: Vec3::zeros(),
+			size: 0.0,
+			color: Rgba::TRANSPARENT,
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct SimulationParams {
+	gravity: Vec4,
+	dt: f32,
+	count: u32,
+}
+
+unsafe impl Binding for SimulationParams {
+	type Argument = Buffer<UniformBufferUsage, SimulationParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+struct SimulateFunction;
+
+impl ComputeFunctionPrototype for SimulateFunction {
+	// The particles, the list of living particles, and the draw command
+	type Bindings = (RawStorage, RawStorage, RawStorage, SimulationParams);
+}
+
+struct BillboardFunction<G>(PhantomData<G>);
+
+impl<G> FunctionPrototype for BillboardFunction<G>
+where
+	G: RenderPassPrototype,
+{
+	type RenderPass = G;
+	type VertexInput = Vec2;
+	// The camera, the particles, and the list of living particles
+	type Bindings = (Mvp, RawStorage, RawStorage);
+	type PushConstants = ();
+}
+
+/// A fixed number of particles simulated and drawn on the device, drawn in render passes of type
+/// `G`. New particles replace the oldest ones once every slot has been used.
+pub struct ParticleSystem<G: RenderPassPrototype> {
+	simulate: ComputeFunctionDef<SimulateFunction>,
+	simulate_arguments: ComputeArgumentsContainer<SimulateFunction>,
+	billboard: FunctionDef<BillboardFunction<G>>,
+	billboard_arguments: ArgumentsContainer<BillboardFunction<G>>,
+	// The arguments above refer to these buffers by handle, so they're declared after them to be
+	// dropped last
+	particles: Buffer<StorageBufferUsage, [Particle]>,
+	_alive: Buffer<StorageBufferUsage, [u32]>,
+	commands: Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]>,
+	vertices: Buffer<VertexBufferUsage, [Vec2]>,
+	indices: Buffer<IndexBufferUsage, [u32]>,
+	capacity: usize,
+	next: usize,
+	/// The acceleration applied to every particle, in units per second squared
+	pub gravity: Vec3,
+}
+
+impl<G> ParticleSystem<G>
+where
+	G: RenderPassPrototype,
+{
+	pub fn create(context: &Context, render_pass: &RenderPass<G>, capacity: usize) -> MarsResult<Self> {
+		assert!(capacity > 0);
+
+		let particles = Buffer::make_array_buffer(context, &vec![Particle::dead(); capacity])?;
+		let alive = Buffer::make_array_buffer(context, &vec![0u32; capacity])?;
+		// A quad with its corners at +/-1, instanced once per living particle
+		let vertices = Buffer::make_array_buffer(
+			context,
+			&[
+				Vec2::new(-1.0, -1.0),
+				Vec2::new(1.0, -1.0),
+				Vec2::new(-1.0, 1.0),
+				Vec2::new(1.0, 1.0),
+			],
+		)?;
+		let indices = Buffer::make_array_buffer(context, &[0, 1, 2, 2, 1, 3])?;
+		let commands = Buffer::make_array_buffer(
+			context,
+			&[vk::DrawIndexedIndirectCommand {
+				index_count: indices.len as u32,
+				instance_count: 0,
+				first_index: 0,
+				vertex_offset: 0,
+				first_instance: 0,
+			}],
+		)?;
+
+		let comp = compile_shader(
+			&format!("#version 450\n{}{}", PARTICLE_DECLARATIONS, SIMULATE_SHADER),
+			"particles.comp",
+			ShaderStage::Compute,
+		)?;
+		let mut simulate = ComputeFunctionDef::create(context, unsafe { ComputeFunctionImpl::from_raw(comp) })?;
+		let simulate_arguments = simulate.make_arguments(
+			context,
+			(
+				RawStorageArgument::new(&particles),
+				RawStorageArgument::new(&alive),
+				RawStorageArgument::new(&commands),
+				Buffer::make_item_buffer(
+					context,
+					SimulationParams {
+						gravity: Vec4::zeros(),
+						dt: 0.0,
+						count: capacity as u32,
+					},
+				)?,
+			),
+		)?;
+
+		let vert = compile_shader(
+			&format!("#version 450\n{}{}", PARTICLE_DECLARATIONS, BILLBOARD_VERTEX_SHADER),
+			"particles.vert",
+			ShaderStage::Vertex,
+		)?;
+		let frag = compile_shader(BILLBOARD_FRAGMENT_SHADER, "particles.frag", ShaderStage::Fragment)?;
+		let mut billboard = FunctionDef::create(context, render_pass, unsafe { FunctionImpl::from_raw(vert, frag) })?;
+		let billboard_arguments = billboard.make_arguments(
+			context,
+			(
+				Buffer::make_item_buffer(context, Mvp::identity())?,
+				RawStorageArgument::new(&particles),
+				RawStorageArgument::new(&alive),
+			),
+		)?;
+
+		Ok(Self {
+			simulate,
+			simulate_arguments,
+			billboard,
+			billboard_arguments,
+			particles,
+			_alive: alive,
+			commands,
+			vertices,
+			indices,
+			capacity,
+			next: 0,
+			gravity: Vec3::zeros(),
+		})
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Adds particles to the system, overwriting the oldest slots once the system is full
+	pub fn emit(&mut self, particles: &[Particle]) -> MarsResult<()> {
+		let capacity = self.capacity;
+		let mut next = self.next;
+		self.particles.with_map_mut(|slots| {
+			for particle in particles {
+				slots[next] = *particle;
+				next = (next + 1) % capacity;
+			}
+		})?;
+		self.next = next;
+		Ok(())
+	}
+
+	/// Advances the simulation by `dt` seconds
+	pub fn update(&mut self, context: &Context, engine: &mut RenderEngine, dt: f32) -> MarsResult<()> {
+		self.commands.with_map_mut(|commands| commands[0].instance_count = 0)?;
+		let gravity = self.gravity;
+		self.simulate_arguments.arguments.3.with_map_mut(|params| {
+			params.gravity = Vec4::new(gravity.x, gravity.y, gravity.z, 0.0);
+			params.dt = dt;
+		})?;
+
+		let group_count = (self.capacity as u32 + 255) / 256;
+		engine.dispatch(context, &self.simulate, &self.simulate_arguments, [group_count, 1, 1])
+	}
+
+	/// Draws the living particles into `target` as billboards facing the camera described by `view`
+	/// and `proj`
+	pub fn draw(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		target: &mut Target<G>,
+		view: Mat4,
+		proj: Mat4,
+	) -> MarsResult<()> {
+		self.billboard_arguments
+			.arguments
+			.0
+			.with_map_mut(|mvp| *mvp = Mvp::new(Mat4::identity(), view, proj))?;
+		engine.pass_indirect(
+			context,
+			target,
+			&self.billboard,
+			Some(IndirectDrawArgs {
+				bindings: &self.billboard_arguments,
+				vertices: &self.vertices,
+				indices: &self.indices,
+				commands: &self.commands,
+				push_constants: (),
+			}),
+		)
+	}
+}
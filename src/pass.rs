@@ -1,29 +1,93 @@
-use std::{marker::PhantomData, sync::Arc};
-
-use rk::{
-	image::{ImageLayoutTransition, ImageViewInner as RkImageViewInner},
-	pass::{self, RenderPass as RkRenderPass},
-	vk,
+use std::{
+	collections::HashMap,
+	marker::PhantomData,
+	sync::{Arc, Mutex, Weak},
 };
 
+use rk::{device::Device, pass, vk};
+
 use crate::{
+	device::DeviceExtension,
 	image::{
 		samples::SampleCount1, usage, DynImageUsage, FormatType, Image, ImageView, MultiSampleCountType,
 		SampleCountType,
 	},
 	math::*,
+	raw_device,
+	sync::ImageTransition,
 	Context, MarsResult,
 };
 
+/// The attachments of a render pass. Color attachments can be `()` and the depth attachment
+/// `NoDepthAttachment` for depth-only passes and passes with no attachments at all, see
+/// `AttachmentlessPass`.
 pub trait RenderPassPrototype {
 	type SampleCount: SampleCountType;
 	type InputAttachments: InputAttachments;
 	type ColorAttachments: ColorAttachments<Self::SampleCount>;
 	type DepthAttachment: DepthAttachmentType<Self::SampleCount>;
+
+	/// The views rendered by each draw when using multiview, with bit `n` set for view `n`. Every
+	/// attachment gets one array layer per view up to the highest one set. Zero (the default)
+	/// disables multiview, non-zero masks require the `Multiview` device extension.
+	const VIEW_MASK: u32 = 0;
+
+	/// The number of array layers of every attachment for layered rendering, where each primitive is
+	/// drawn into the layer its function writes to `gl_Layer`. That's written by a geometry shader
+	/// (see `FunctionImpl::from_raw_with_geometry`), or by the vertex shader with the
+	/// `ShaderViewportIndexLayer` device extension, and primitives that don't write it go to layer
+	/// 0. This renders all six faces of a cubemap shadow map, or every cascade of a shadow map
+	/// array, in a single pass. Can't be combined with multiview.
+	const LAYERS: u32 = 1;
+}
+
+/// A render pass without any attachments, for functions with `FunctionOptions::rasterizer_discard`
+/// that write storage buffers from their vertex shaders, or for passes only recorded for their
+/// queries. Its targets still have an extent, which is the render area.
+pub struct AttachmentlessPass;
+
+impl RenderPassPrototype for AttachmentlessPass {
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = ();
+	type DepthAttachment = NoDepthAttachment;
+}
+
+/// The number of array layers the attachments of a render pass need
+pub(crate) fn layer_count<G: RenderPassPrototype>() -> u32 {
+	if G::VIEW_MASK == 0 {
+		G::LAYERS.max(1)
+	} else {
+		32 - G::VIEW_MASK.leading_zeros()
+	}
+}
+
+/// The number of layers of the framebuffers of a render pass. Multiview render passes broadcast to
+/// the attachment layers themselves, so their framebuffers have a single layer.
+pub(crate) fn framebuffer_layers<G: RenderPassPrototype>() -> u32 {
+	if G::VIEW_MASK == 0 {
+		G::LAYERS.max(1)
+	} else {
+		1
+	}
+}
+
+/// The aspects of the depth attachment of a render pass, which include the stencil aspect if its
+/// format has one
+pub(crate) fn depth_aspect<G: RenderPassPrototype>() -> vk::ImageAspectFlags {
+	match <G::DepthAttachment as DepthAttachmentType<G::SampleCount>>::desc().map(|desc| desc.format) {
+		Some(vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT) => {
+			vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+		}
+		Some(_) => vk::ImageAspectFlags::DEPTH,
+		None => vk::ImageAspectFlags::empty(),
+	}
 }
 
 pub struct RenderPass<G: RenderPassPrototype> {
-	pub(crate) render_pass: RkRenderPass,
+	pub(crate) render_pass: Arc<RenderPassHandle>,
+	/// The subpass of `render_pass` that `G` describes
+	pub(crate) subpass: u32,
 	_phantom: PhantomData<G>,
 }
 
@@ -32,21 +96,256 @@ where
 	G: RenderPassPrototype,
 {
 	pub fn create(context: &Context) -> MarsResult<Self> {
+		assert!(
+			G::VIEW_MASK == 0 || G::LAYERS <= 1,
+			"render passes can't use both multiview and layered rendering"
+		);
+		if G::VIEW_MASK != 0 && !context.has_extension(DeviceExtension::Multiview) {
+			return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+		}
 		let (attachments, subpasses, dependencies) = get_render_pass_desc::<G>();
-		let render_pass = unsafe {
-			context
-				.device
-				.create_render_pass(attachments, subpasses, dependencies)?
-		};
-		Ok(Self {
+		let render_pass =
+			RenderPassHandle::get_or_create(context, &attachments, &subpasses, &dependencies, G::VIEW_MASK)?;
+		Ok(Self::from_handle(render_pass, 0))
+	}
+
+	/// Uses one subpass of a render pass created elsewhere, which must match `G`
+	pub(crate) fn from_handle(render_pass: Arc<RenderPassHandle>, subpass: u32) -> Self {
+		Self {
 			render_pass,
+			subpass,
 			_phantom: PhantomData,
+		}
+	}
+}
+
+pub(crate) struct RenderPassHandle {
+	device: Device,
+	pub(crate) raw: vk::RenderPass,
+	/// The format and sample count of every attachment, in the order of the framebuffer
+	pub(crate) attachments: Vec<(vk::Format, vk::SampleCountFlags)>,
+}
+
+impl RenderPassHandle {
+	/// Returns the render pass created from the same description if it's still alive, and creates it
+	/// otherwise. Render pass prototypes describing the same attachments share one `VkRenderPass`.
+	pub(crate) fn get_or_create(
+		context: &Context,
+		attachments: &[pass::Attachment],
+		subpasses: &[pass::Subpass],
+		dependencies: &[vk::SubpassDependency],
+		view_mask: u32,
+	) -> MarsResult<Arc<Self>> {
+		let key = RenderPassKey::new(attachments, subpasses, dependencies, view_mask);
+		let mut render_passes = context.render_passes.0.lock().unwrap();
+		if let Some(render_pass) = render_passes.get(&key).and_then(Weak::upgrade) {
+			return Ok(render_pass);
+		}
+		let render_pass = Arc::new(Self::create(context, attachments, subpasses, dependencies, view_mask)?);
+		render_passes.retain(|_key, render_pass| render_pass.strong_count() > 0);
+		render_passes.insert(key, Arc::downgrade(&render_pass));
+		Ok(render_pass)
+	}
+
+	fn create(
+		context: &Context,
+		attachments: &[pass::Attachment],
+		subpasses: &[pass::Subpass],
+		dependencies: &[vk::SubpassDependency],
+		view_mask: u32,
+	) -> MarsResult<Self> {
+		let attachment_descs = attachments
+			.iter()
+			.map(|attachment| (attachment.format, attachment.samples))
+			.collect();
+		let attachments = attachments
+			.iter()
+			.map(|attachment| vk::AttachmentDescription {
+				flags: vk::AttachmentDescriptionFlags::empty(),
+				format: attachment.format,
+				samples: attachment.samples,
+				load_op: attachment.load_op,
+				store_op: attachment.store_op,
+				stencil_load_op: attachment.stencil_load_op,
+				stencil_store_op: attachment.stencil_store_op,
+				initial_layout: attachment.initial_layout,
+				final_layout: attachment.final_layout,
+			})
+			.collect::<Vec<_>>();
+		let reference = |r: &pass::AttachmentRef| vk::AttachmentReference {
+			attachment: r.attachment,
+			layout: r.layout,
+		};
+		// The references have to be collected up front so they outlive the subpass descriptions
+		let refs = subpasses
+			.iter()
+			.map(|subpass| {
+				let input_refs = subpass.input_attachments.iter().map(reference).collect::<Vec<_>>();
+				let color_refs = subpass
+					.color_attachments
+					.iter()
+					.map(|c| reference(&c.color))
+					.collect::<Vec<_>>();
+				// Resolve attachments are given for either all color attachments or none of them
+				let resolve_refs = subpass
+					.color_attachments
+					.iter()
+					.map(|c| {
+						c.resolve.as_ref().map(reference).unwrap_or(vk::AttachmentReference {
+							attachment: vk::ATTACHMENT_UNUSED,
+							layout: vk::ImageLayout::UNDEFINED,
+						})
+					})
+					.collect::<Vec<_>>();
+				let depth_ref = subpass.depth_stencil_attachment.as_ref().map(reference);
+				(input_refs, color_refs, resolve_refs, depth_ref)
+			})
+			.collect::<Vec<_>>();
+		let descriptions = subpasses
+			.iter()
+			.zip(&refs)
+			.map(|(subpass, (input_refs, color_refs, resolve_refs, depth_ref))| {
+				let mut description = vk::SubpassDescription::builder()
+					.pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+					.input_attachments(input_refs)
+					.color_attachments(color_refs);
+				if subpass.color_attachments.iter().any(|c| c.resolve.is_some()) {
+					description = description.resolve_attachments(resolve_refs);
+				}
+				if let Some(depth_ref) = depth_ref {
+					description = description.depth_stencil_attachment(depth_ref);
+				}
+				description.build()
+			})
+			.collect::<Vec<_>>();
+
+		let view_masks = vec![view_mask; descriptions.len()];
+		let correlation_masks = [view_mask];
+		let mut multiview_info = vk::RenderPassMultiviewCreateInfo::builder()
+			.view_masks(&view_masks)
+			.correlation_masks(&correlation_masks);
+		let mut create_info = vk::RenderPassCreateInfo::builder()
+			.attachments(&attachments)
+			.subpasses(&descriptions)
+			.dependencies(dependencies);
+		if view_mask != 0 {
+			create_info = create_info.push_next(&mut multiview_info);
+		}
+
+		let raw = unsafe { raw_device(&context.device).create_render_pass(&create_info, None)? };
+		Ok(Self {
+			device: context.device.clone(),
+			raw,
+			attachments: attachment_descs,
 		})
 	}
 }
 
-fn get_render_pass_desc<G: RenderPassPrototype>() -> (Vec<pass::Attachment>, Vec<pass::Subpass>, Vec<pass::Dependency>)
-{
+/// The render passes created by a context, which are dropped once nothing uses them
+#[derive(Default)]
+pub(crate) struct RenderPassCache(Mutex<HashMap<RenderPassKey, Weak<RenderPassHandle>>>);
+
+type AttachmentRefKey = (u32, vk::ImageLayout);
+
+/// Everything a render pass is created from
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) struct RenderPassKey {
+	attachments: Vec<(
+		vk::Format,
+		vk::SampleCountFlags,
+		vk::AttachmentLoadOp,
+		vk::AttachmentStoreOp,
+		vk::AttachmentLoadOp,
+		vk::AttachmentStoreOp,
+		vk::ImageLayout,
+		vk::ImageLayout,
+	)>,
+	subpasses: Vec<(
+		Vec<AttachmentRefKey>,
+		Vec<(AttachmentRefKey, Option<AttachmentRefKey>)>,
+		Option<AttachmentRefKey>,
+	)>,
+	dependencies: Vec<(
+		u32,
+		u32,
+		vk::PipelineStageFlags,
+		vk::PipelineStageFlags,
+		vk::AccessFlags,
+		vk::AccessFlags,
+		vk::DependencyFlags,
+	)>,
+	view_mask: u32,
+}
+
+impl RenderPassKey {
+	fn new(
+		attachments: &[pass::Attachment],
+		subpasses: &[pass::Subpass],
+		dependencies: &[vk::SubpassDependency],
+		view_mask: u32,
+	) -> Self {
+		let reference = |r: &pass::AttachmentRef| (r.attachment, r.layout);
+		Self {
+			attachments: attachments
+				.iter()
+				.map(|a| {
+					(
+						a.format,
+						a.samples,
+						a.load_op,
+						a.store_op,
+						a.stencil_load_op,
+						a.stencil_store_op,
+						a.initial_layout,
+						a.final_layout,
+					)
+				})
+				.collect(),
+			subpasses: subpasses
+				.iter()
+				.map(|subpass| {
+					(
+						subpass.input_attachments.iter().map(reference).collect(),
+						subpass
+							.color_attachments
+							.iter()
+							.map(|c| (reference(&c.color), c.resolve.as_ref().map(reference)))
+							.collect(),
+						subpass.depth_stencil_attachment.as_ref().map(reference),
+					)
+				})
+				.collect(),
+			dependencies: dependencies
+				.iter()
+				.map(|d| {
+					(
+						d.src_subpass,
+						d.dst_subpass,
+						d.src_stage_mask,
+						d.dst_stage_mask,
+						d.src_access_mask,
+						d.dst_access_mask,
+						d.dependency_flags,
+					)
+				})
+				.collect(),
+			view_mask,
+		}
+	}
+}
+
+impl Drop for RenderPassHandle {
+	fn drop(&mut self) {
+		unsafe {
+			raw_device(&self.device).destroy_render_pass(self.raw, None);
+		}
+	}
+}
+
+/// Describes the single subpass render pass of `G`, with the dependencies of
+/// `external_dependencies`.
+pub(crate) fn get_render_pass_desc<G: RenderPassPrototype>(
+) -> (Vec<pass::Attachment>, Vec<pass::Subpass>, Vec<vk::SubpassDependency>) {
 	let mut attachments = Vec::new();
 	let mut input_refs = Vec::new();
 	let mut color_refs = Vec::new();
@@ -100,7 +399,47 @@ fn get_render_pass_desc<G: RenderPassPrototype>() -> (Vec<pass::Attachment>, Vec
 		depth_stencil_attachment: depth_ref,
 	};
 
-	(attachments, vec![subpass], Vec::new())
+	(attachments, vec![subpass], external_dependencies(0).to_vec())
+}
+
+/// The dependencies of a render pass on the commands around it, which are all that orders passes
+/// after each other. The first subpass waits for earlier attachment writes and for earlier shader
+/// and transfer reads of its attachments, and the shaders, dispatches and copies after the pass,
+/// ending with `last_subpass`, see what it rendered.
+pub(crate) fn external_dependencies(last_subpass: u32) -> [vk::SubpassDependency; 2] {
+	let attachment_stages = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+		| vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+		| vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+	let read_stages = vk::PipelineStageFlags::VERTEX_SHADER
+		| vk::PipelineStageFlags::FRAGMENT_SHADER
+		| vk::PipelineStageFlags::COMPUTE_SHADER
+		| vk::PipelineStageFlags::TRANSFER;
+	let attachment_writes = vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+	[
+		vk::SubpassDependency {
+			src_subpass: vk::SUBPASS_EXTERNAL,
+			dst_subpass: 0,
+			// Reads only need to finish before the attachments are written again, like the blit of
+			// the previous frame's image onto the swapchain
+			src_stage_mask: attachment_stages | read_stages,
+			dst_stage_mask: attachment_stages | vk::PipelineStageFlags::FRAGMENT_SHADER,
+			src_access_mask: attachment_writes,
+			dst_access_mask: attachment_writes
+				| vk::AccessFlags::COLOR_ATTACHMENT_READ
+				| vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+				| vk::AccessFlags::INPUT_ATTACHMENT_READ,
+			dependency_flags: vk::DependencyFlags::empty(),
+		},
+		vk::SubpassDependency {
+			src_subpass: last_subpass,
+			dst_subpass: vk::SUBPASS_EXTERNAL,
+			src_stage_mask: attachment_stages,
+			dst_stage_mask: read_stages,
+			src_access_mask: attachment_writes,
+			dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::TRANSFER_READ,
+			dependency_flags: vk::DependencyFlags::empty(),
+		},
+	]
 }
 
 pub struct Attachments<G: RenderPassPrototype> {
@@ -116,9 +455,21 @@ where
 {
 	// TODO: allow more granular specification of usages
 	pub fn create(context: &Context, extent: vk::Extent2D, color_usages: DynImageUsage) -> MarsResult<Self> {
-		let input_attachments = G::InputAttachments::create(context, DynImageUsage::empty(), extent)?;
-		let color_attachments = G::ColorAttachments::create(context, color_usages, extent)?;
-		let depth_attachment = G::DepthAttachment::create(context, DynImageUsage::empty(), extent)?;
+		Self::create_with_usages(context, extent, color_usages, DynImageUsage::empty())
+	}
+
+	/// Creates the attachments with extra usages for the depth attachment too, like `SAMPLED` to
+	/// read the depth of a frame from shaders afterwards
+	pub fn create_with_usages(
+		context: &Context,
+		extent: vk::Extent2D,
+		color_usages: DynImageUsage,
+		depth_usages: DynImageUsage,
+	) -> MarsResult<Self> {
+		let layers = layer_count::<G>();
+		let input_attachments = G::InputAttachments::create(context, DynImageUsage::empty(), extent, layers)?;
+		let color_attachments = G::ColorAttachments::create(context, color_usages, extent, layers)?;
+		let depth_attachment = G::DepthAttachment::create(context, depth_usages, extent, layers)?;
 		Ok(Self {
 			extent,
 			input_attachments,
@@ -143,7 +494,32 @@ where
 		&self.depth_attachment
 	}
 
-	pub(crate) fn as_raw(&self) -> Vec<Arc<RkImageViewInner>> {
+	/// The color attachments, for changing settings like the clear values of transient attachments
+	/// before the attachments are used for a target
+	pub fn color_attachments_mut(&mut self) -> &mut G::ColorAttachments {
+		&mut self.color_attachments
+	}
+
+	pub fn depth_attachment_mut(&mut self) -> &mut G::DepthAttachment {
+		&mut self.depth_attachment
+	}
+
+	/// The values every attachment is cleared to when a render pass begins, in the order of the
+	/// framebuffer. They're only used by attachments that aren't loaded from memory.
+	pub(crate) fn load_clear_values(&self) -> Vec<vk::ClearValue> {
+		let unused = vk::ClearValue {
+			color: vk::ClearColorValue { float32: [0.0; 4] },
+		};
+		let mut clear_values = vec![unused; G::InputAttachments::desc().len()];
+		for (color, resolve) in self.color_attachments.load_clear_values() {
+			clear_values.push(color);
+			clear_values.extend(resolve);
+		}
+		clear_values.extend(self.depth_attachment.load_clear_value());
+		clear_values
+	}
+
+	pub(crate) fn as_raw(&self) -> Vec<vk::ImageView> {
 		self.input_attachments
 			.as_raw()
 			.into_iter()
@@ -181,7 +557,7 @@ where
 		}
 		if let Some(depth_stencil) = depth.as_raw() {
 			clear_attachments.push(vk::ClearAttachment {
-				aspect_mask: vk::ImageAspectFlags::DEPTH,
+				aspect_mask: depth_aspect::<G>(),
 				color_attachment: vk::ATTACHMENT_UNUSED,
 				clear_value: vk::ClearValue { depth_stencil },
 			})
@@ -193,11 +569,11 @@ where
 pub unsafe trait InputAttachments: Sized {
 	fn desc() -> Vec<pass::Attachment>;
 
-	fn as_raw(&self) -> Vec<Arc<RkImageViewInner>>;
+	fn as_raw(&self) -> Vec<vk::ImageView>;
 
 	fn clears(&self, color: Vec4, depth: f32) -> Vec<vk::ClearValue>;
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self>;
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self>;
 }
 
 unsafe impl InputAttachments for () {
@@ -205,7 +581,7 @@ unsafe impl InputAttachments for () {
 		Vec::new()
 	}
 
-	fn as_raw(&self) -> Vec<Arc<RkImageViewInner>> {
+	fn as_raw(&self) -> Vec<vk::ImageView> {
 		Vec::new()
 	}
 
@@ -213,7 +589,7 @@ unsafe impl InputAttachments for () {
 		Vec::new()
 	}
 
-	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D) -> MarsResult<Self> {
+	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D, _layers: u32) -> MarsResult<Self> {
 		Ok(())
 	}
 }
@@ -231,12 +607,27 @@ unsafe impl InputAttachments for () {
 
 pub unsafe trait ColorAttachmentType<S: SampleCountType>: Sized {
 	type ClearValue: ColorClearValue;
+	/// Whether the attachment's format is an integer format, which can't be blended
+	const INTEGER: bool;
 
 	fn desc() -> (pass::Attachment, Option<pass::Attachment>);
 
-	fn as_raw(&self) -> (Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>);
+	fn as_raw(&self) -> (vk::ImageView, Option<vk::ImageView>);
+
+	/// The single-sampled image holding what was rendered into the attachment, which is the resolve
+	/// image of multisampled attachments, along with its view
+	fn output(&self) -> (vk::Image, vk::ImageView);
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self>;
+	/// The values the attachment and its resolve attachment are cleared to when a render pass
+	/// begins, if their load operation is `CLEAR`
+	fn load_clear_values(&self) -> (vk::ClearValue, Option<vk::ClearValue>) {
+		let clear = vk::ClearValue {
+			color: vk::ClearColorValue { float32: [0.0; 4] },
+		};
+		(clear, Self::desc().1.map(|_| clear))
+	}
+
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self>;
 }
 
 // TODO: use a subtrait that ensures the format is a color format
@@ -261,9 +652,10 @@ where
 unsafe impl<F> ColorAttachmentType<SampleCount1> for ColorAttachment<F>
 where
 	F: FormatType,
-	F::Pixel: ColorClearValue,
+	F::ClearValue: ColorClearValue,
 {
-	type ClearValue = F::Pixel;
+	type ClearValue = F::ClearValue;
+	const INTEGER: bool = F::INTEGER;
 
 	fn desc() -> (pass::Attachment, Option<pass::Attachment>) {
 		// TODO: implement subtype traits for formats and image usages to avoid these asserts
@@ -284,20 +676,25 @@ where
 		)
 	}
 
-	fn as_raw(&self) -> (Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>) {
-		(self.view.image_view.clone(), None)
+	fn as_raw(&self) -> (vk::ImageView, Option<vk::ImageView>) {
+		(self.view.image_view.raw, None)
+	}
+
+	fn output(&self) -> (vk::Image, vk::ImageView) {
+		(unsafe { self.image.raw() }, self.view.image_view.raw)
 	}
 
-	fn create(context: &Context, usage: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self> {
-		let mut image = Image::create(context, usage | DynImageUsage::COLOR_ATTACHMENT, extent)?;
+	fn create(context: &Context, usage: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		let mut image = Image::create_layered(context, usage | DynImageUsage::COLOR_ATTACHMENT, extent, layers)?;
 		image.transition(
 			context,
-			&ImageLayoutTransition {
+			&ImageTransition {
 				aspect: vk::ImageAspectFlags::COLOR,
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::MEMORY_READ,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
 				old_layout: vk::ImageLayout::UNDEFINED,
 				new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
 			},
@@ -321,10 +718,11 @@ pub struct MultisampledColorAttachment<F: FormatType, S: MultiSampleCountType> {
 unsafe impl<F, S> ColorAttachmentType<S> for MultisampledColorAttachment<F, S>
 where
 	F: FormatType,
-	F::Pixel: ColorClearValue,
+	F::ClearValue: ColorClearValue,
 	S: MultiSampleCountType,
 {
-	type ClearValue = F::Pixel;
+	type ClearValue = F::ClearValue;
+	const INTEGER: bool = F::INTEGER;
 
 	fn desc() -> (pass::Attachment, Option<pass::Attachment>) {
 		assert!(F::aspect().contains(vk::ImageAspectFlags::COLOR));
@@ -353,38 +751,48 @@ where
 		)
 	}
 
-	fn as_raw(&self) -> (Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>) {
+	fn as_raw(&self) -> (vk::ImageView, Option<vk::ImageView>) {
 		(
-			self.color_image_view.image_view.clone(),
-			Some(self.resolve_image_view.image_view.clone()),
+			self.color_image_view.image_view.raw,
+			Some(self.resolve_image_view.image_view.raw),
 		)
 	}
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self> {
-		let mut color_image = Image::create(context, usages | DynImageUsage::COLOR_ATTACHMENT, extent)?;
+	fn output(&self) -> (vk::Image, vk::ImageView) {
+		(
+			unsafe { self.resolve_image.raw() },
+			self.resolve_image_view.image_view.raw,
+		)
+	}
+
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		let mut color_image = Image::create_layered(context, usages | DynImageUsage::COLOR_ATTACHMENT, extent, layers)?;
 		color_image.transition(
 			context,
-			&ImageLayoutTransition {
+			&ImageTransition {
 				aspect: vk::ImageAspectFlags::COLOR,
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::MEMORY_READ,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
 				old_layout: vk::ImageLayout::UNDEFINED,
 				new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
 			},
 		)?;
 		let color_image = color_image.cast_usage(usage::ColorAttachment).map_err(|_| ()).unwrap();
 		let color_image_view = ImageView::create(&color_image)?;
-		let mut resolve_image = Image::create(context, usages | DynImageUsage::COLOR_ATTACHMENT, extent)?;
+		let mut resolve_image =
+			Image::create_layered(context, usages | DynImageUsage::COLOR_ATTACHMENT, extent, layers)?;
 		resolve_image.transition(
 			context,
-			&ImageLayoutTransition {
+			&ImageTransition {
 				aspect: vk::ImageAspectFlags::COLOR,
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::MEMORY_READ,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
 				old_layout: vk::ImageLayout::UNDEFINED,
 				new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
 			},
@@ -403,70 +811,158 @@ where
 	}
 }
 
-pub unsafe trait ColorAttachments<S: SampleCountType>: Sized {
-	type ClearValues: ColorClearValues;
+/// A multisampled color attachment whose multisampled image is transient, see
+/// `Image::create_transient`. Only the resolved image is kept in memory, which saves the memory and
+/// bandwidth of the multisampled image on tile-based GPUs.
+///
+/// The multisampled image is cleared to `clear_value` when each pass begins instead of being loaded,
+/// and the resolved image is overwritten when the pass ends. So every pass draws over a cleared
+/// image, and the attachment suits targets that are drawn with a single pass per frame.
+pub struct TransientMultisampledColorAttachment<F: FormatType, S: MultiSampleCountType> {
+	#[allow(unused)]
+	pub color_image: Image<usage::ColorAttachment, F, S>,
+	pub color_image_view: ImageView<usage::ColorAttachment, F, S>,
+	#[allow(unused)]
+	pub resolve_image: Image<usage::ColorAttachment, F, SampleCount1>,
+	pub resolve_image_view: ImageView<usage::ColorAttachment, F, SampleCount1>,
+	/// The color every pass starts with
+	pub clear_value: F::ClearValue,
+}
 
-	fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)>;
+unsafe impl<F, S> ColorAttachmentType<S> for TransientMultisampledColorAttachment<F, S>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue + Default,
+	S: MultiSampleCountType,
+{
+	type ClearValue = F::ClearValue;
+	const INTEGER: bool = F::INTEGER;
 
-	fn as_raw(&self) -> Vec<(Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>)>;
+	fn desc() -> (pass::Attachment, Option<pass::Attachment>) {
+		assert!(F::aspect().contains(vk::ImageAspectFlags::COLOR));
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self>;
-}
+		(
+			pass::Attachment {
+				format: F::as_raw(),
+				samples: S::as_raw(),
+				load_op: vk::AttachmentLoadOp::CLEAR,
+				store_op: vk::AttachmentStoreOp::DONT_CARE,
+				stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+				stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+				initial_layout: vk::ImageLayout::UNDEFINED,
+				final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+			},
+			// Resolving overwrites the whole image, so it doesn't need to be loaded either
+			Some(pass::Attachment {
+				format: F::as_raw(),
+				samples: vk::SampleCountFlags::TYPE_1,
+				load_op: vk::AttachmentLoadOp::DONT_CARE,
+				store_op: vk::AttachmentStoreOp::STORE,
+				stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+				stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+				initial_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				final_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			}),
+		)
+	}
 
-unsafe impl<S: SampleCountType> ColorAttachments<S> for () {
-	type ClearValues = ();
+	fn as_raw(&self) -> (vk::ImageView, Option<vk::ImageView>) {
+		(
+			self.color_image_view.image_view.raw,
+			Some(self.resolve_image_view.image_view.raw),
+		)
+	}
 
-	fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)> {
-		Vec::new()
+	fn output(&self) -> (vk::Image, vk::ImageView) {
+		(
+			unsafe { self.resolve_image.raw() },
+			self.resolve_image_view.image_view.raw,
+		)
 	}
 
-	fn as_raw(&self) -> Vec<(Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>)> {
-		Vec::new()
+	fn load_clear_values(&self) -> (vk::ClearValue, Option<vk::ClearValue>) {
+		let color = vk::ClearValue {
+			color: self.clear_value.as_raw(),
+		};
+		(color, Some(color))
 	}
 
-	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D) -> MarsResult<Self> {
-		Ok(())
+	// The multisampled image is only ever an attachment, so it gets none of the extra usages
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		let color_image = Image::create_transient(context, usage::ColorAttachment, extent, layers)?;
+		let color_image_view = ImageView::create(&color_image)?;
+		let mut resolve_image =
+			Image::create_layered(context, usages | DynImageUsage::COLOR_ATTACHMENT, extent, layers)?;
+		resolve_image.transition(
+			context,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			},
+		)?;
+		let resolve_image = resolve_image
+			.cast_usage(usage::ColorAttachment)
+			.map_err(|_| ())
+			.unwrap();
+		let resolve_image_view = ImageView::create(&resolve_image)?;
+		Ok(Self {
+			color_image,
+			color_image_view,
+			resolve_image,
+			resolve_image_view,
+			clear_value: Default::default(),
+		})
 	}
 }
 
-unsafe impl<S, A> ColorAttachments<S> for (A,)
-where
-	S: SampleCountType,
-	A: ColorAttachmentType<S>,
-{
-	type ClearValues = (A::ClearValue,);
+pub unsafe trait ColorAttachments<S: SampleCountType>: Sized {
+	type ClearValues: ColorClearValues;
+
+	fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)>;
+
+	/// Whether each attachment has an integer format, in the order of `desc`
+	fn integer() -> Vec<bool>;
+
+	fn as_raw(&self) -> Vec<(vk::ImageView, Option<vk::ImageView>)>;
+
+	fn outputs(&self) -> Vec<(vk::Image, vk::ImageView)>;
+
+	fn load_clear_values(&self) -> Vec<(vk::ClearValue, Option<vk::ClearValue>)>;
+
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self>;
+}
+
+unsafe impl<S: SampleCountType> ColorAttachments<S> for () {
+	type ClearValues = ();
 
 	fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)> {
-		vec![A::desc()]
+		Vec::new()
 	}
 
-	fn as_raw(&self) -> Vec<(Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>)> {
-		vec![self.0.as_raw()]
+	fn integer() -> Vec<bool> {
+		Vec::new()
 	}
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self> {
-		Ok((A::create(context, usages, extent)?,))
+	fn as_raw(&self) -> Vec<(vk::ImageView, Option<vk::ImageView>)> {
+		Vec::new()
 	}
-}
 
-unsafe impl<S, A, B> ColorAttachments<S> for (A, B)
-where
-	S: SampleCountType,
-	A: ColorAttachmentType<S>,
-	B: ColorAttachmentType<S>,
-{
-	type ClearValues = (A::ClearValue, B::ClearValue);
-
-	fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)> {
-		vec![A::desc(), B::desc()]
+	fn outputs(&self) -> Vec<(vk::Image, vk::ImageView)> {
+		Vec::new()
 	}
 
-	fn as_raw(&self) -> Vec<(Arc<RkImageViewInner>, Option<Arc<RkImageViewInner>>)> {
-		vec![self.0.as_raw(), self.1.as_raw()]
+	fn load_clear_values(&self) -> Vec<(vk::ClearValue, Option<vk::ClearValue>)> {
+		Vec::new()
 	}
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self> {
-		Ok((A::create(context, usages, extent)?, B::create(context, usages, extent)?))
+	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D, _layers: u32) -> MarsResult<Self> {
+		Ok(())
 	}
 }
 
@@ -475,11 +971,19 @@ pub unsafe trait DepthAttachmentType<S: SampleCountType>: Sized {
 
 	fn desc() -> Option<pass::Attachment>;
 
-	fn as_raw(&self) -> Option<Arc<RkImageViewInner>>;
+	fn as_raw(&self) -> Option<vk::ImageView>;
 
 	fn clear(&self, depth: f32) -> Option<vk::ClearValue>;
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self>;
+	/// The value the attachment is cleared to when a render pass begins, if its load operation is
+	/// `CLEAR`
+	fn load_clear_value(&self) -> Option<vk::ClearValue> {
+		Self::desc().map(|_| vk::ClearValue {
+			depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+		})
+	}
+
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self>;
 }
 
 pub struct NoDepthAttachment;
@@ -494,7 +998,7 @@ where
 		None
 	}
 
-	fn as_raw(&self) -> Option<Arc<RkImageViewInner>> {
+	fn as_raw(&self) -> Option<vk::ImageView> {
 		None
 	}
 
@@ -502,7 +1006,7 @@ where
 		None
 	}
 
-	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D) -> MarsResult<Self> {
+	fn create(_context: &Context, _usages: DynImageUsage, _extent: vk::Extent2D, _layers: u32) -> MarsResult<Self> {
 		Ok(NoDepthAttachment)
 	}
 }
@@ -528,28 +1032,34 @@ where
 unsafe impl<F, S> DepthAttachmentType<S> for DepthAttachment<F, S>
 where
 	F: FormatType,
-	F::Pixel: DepthClearValue,
+	F::ClearValue: DepthClearValue,
 	S: SampleCountType,
 {
-	type ClearValue = F::Pixel;
+	type ClearValue = F::ClearValue;
 
 	fn desc() -> Option<pass::Attachment> {
 		assert!(F::aspect().contains(vk::ImageAspectFlags::DEPTH));
 
+		// The stencil is kept between passes like the depth, if the format has one
+		let (stencil_load_op, stencil_store_op) = if F::aspect().contains(vk::ImageAspectFlags::STENCIL) {
+			(vk::AttachmentLoadOp::LOAD, vk::AttachmentStoreOp::STORE)
+		} else {
+			(vk::AttachmentLoadOp::DONT_CARE, vk::AttachmentStoreOp::DONT_CARE)
+		};
 		Some(pass::Attachment {
 			format: F::as_raw(),
 			samples: S::as_raw(),
 			load_op: vk::AttachmentLoadOp::LOAD,
 			store_op: vk::AttachmentStoreOp::STORE,
-			stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
-			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			stencil_load_op,
+			stencil_store_op,
 			initial_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
 			final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
 		})
 	}
 
-	fn as_raw(&self) -> Option<Arc<RkImageViewInner>> {
-		Some(self.view.image_view.clone())
+	fn as_raw(&self) -> Option<vk::ImageView> {
+		Some(self.view.image_view.raw)
 	}
 
 	fn clear(&self, depth: f32) -> Option<vk::ClearValue> {
@@ -558,17 +1068,23 @@ where
 		})
 	}
 
-	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D) -> MarsResult<Self> {
-		let mut image = Image::create(context, usages | DynImageUsage::DEPTH_STENCIL_ATTACHMENT, extent)?;
+	fn create(context: &Context, usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		let mut image = Image::create_layered(
+			context,
+			usages | DynImageUsage::DEPTH_STENCIL_ATTACHMENT,
+			extent,
+			layers,
+		)?;
 		image.transition(
 			context,
-			&ImageLayoutTransition {
-				aspect: vk::ImageAspectFlags::DEPTH,
-				src_stage_mask: vk::PipelineStageFlags::TOP_OF_PIPE,
-				dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-				src_access_mask: vk::AccessFlags::empty(),
-				dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-					| vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+			&ImageTransition {
+				aspect: F::aspect(),
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::EARLY_FRAGMENT_TESTS
+					| vk::PipelineStageFlags2KHR::LATE_FRAGMENT_TESTS,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_READ
+					| vk::AccessFlags2KHR::DEPTH_STENCIL_ATTACHMENT_WRITE,
 				old_layout: vk::ImageLayout::UNDEFINED,
 				new_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
 			},
@@ -579,6 +1095,74 @@ where
 	}
 }
 
+/// A depth attachment backed by a transient image, see `Image::create_transient`. Its depth is
+/// cleared to `clear_depth` when each pass begins and discarded when the pass ends, so it can't be
+/// sampled, and depth testing doesn't carry over from one pass to the next.
+pub struct TransientDepthAttachment<F: FormatType, S: SampleCountType> {
+	pub image: Image<usage::DepthStencilAttachment, F, S>,
+	pub view: ImageView<usage::DepthStencilAttachment, F, S>,
+	/// The depth every pass starts with, 1.0 unless changed (use 0.0 with reverse-Z)
+	pub clear_depth: f32,
+}
+
+unsafe impl<F, S> DepthAttachmentType<S> for TransientDepthAttachment<F, S>
+where
+	F: FormatType,
+	F::ClearValue: DepthClearValue,
+	S: SampleCountType,
+{
+	type ClearValue = F::ClearValue;
+
+	fn desc() -> Option<pass::Attachment> {
+		assert!(F::aspect().contains(vk::ImageAspectFlags::DEPTH));
+
+		Some(pass::Attachment {
+			format: F::as_raw(),
+			samples: S::as_raw(),
+			load_op: vk::AttachmentLoadOp::CLEAR,
+			store_op: vk::AttachmentStoreOp::DONT_CARE,
+			stencil_load_op: if F::aspect().contains(vk::ImageAspectFlags::STENCIL) {
+				vk::AttachmentLoadOp::CLEAR
+			} else {
+				vk::AttachmentLoadOp::DONT_CARE
+			},
+			stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+			initial_layout: vk::ImageLayout::UNDEFINED,
+			final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+		})
+	}
+
+	fn as_raw(&self) -> Option<vk::ImageView> {
+		Some(self.view.image_view.raw)
+	}
+
+	fn clear(&self, depth: f32) -> Option<vk::ClearValue> {
+		Some(vk::ClearValue {
+			depth_stencil: vk::ClearDepthStencilValue { depth, stencil: 0 },
+		})
+	}
+
+	fn load_clear_value(&self) -> Option<vk::ClearValue> {
+		Some(vk::ClearValue {
+			depth_stencil: vk::ClearDepthStencilValue {
+				depth: self.clear_depth,
+				stencil: 0,
+			},
+		})
+	}
+
+	// Transient images can only be attachments, so the extra usages are ignored
+	fn create(context: &Context, _usages: DynImageUsage, extent: vk::Extent2D, layers: u32) -> MarsResult<Self> {
+		let image = Image::create_transient(context, usage::DepthStencilAttachment, extent, layers)?;
+		let view = ImageView::create(&image)?;
+		Ok(Self {
+			image,
+			view,
+			clear_depth: 1.0,
+		})
+	}
+}
+
 pub trait ColorClearValue {
 	fn as_raw(&self) -> vk::ClearColorValue;
 }
@@ -591,6 +1175,72 @@ impl ColorClearValue for Vec4 {
 	}
 }
 
+#[cfg(feature = "glam")]
+impl ColorClearValue for ::glam::Vec4 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		let float32: [f32; 4] = (*self).into();
+		vk::ClearColorValue { float32 }
+	}
+}
+
+impl ColorClearValue for f32 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			float32: [*self, 0.0, 0.0, 0.0],
+		}
+	}
+}
+
+// Integer formats are cleared with integer values, which only make sense for attachments of an
+// integer format like `R32Uint`
+impl ColorClearValue for u32 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			uint32: [*self, 0, 0, 0],
+		}
+	}
+}
+
+impl ColorClearValue for i32 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			int32: [*self, 0, 0, 0],
+		}
+	}
+}
+
+impl ColorClearValue for Vec4<u32> {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			uint32: [self.x, self.y, self.z, self.w],
+		}
+	}
+}
+
+impl ColorClearValue for Vec4<i32> {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		vk::ClearColorValue {
+			int32: [self.x, self.y, self.z, self.w],
+		}
+	}
+}
+
+#[cfg(feature = "glam")]
+impl ColorClearValue for ::glam::UVec4 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		let uint32: [u32; 4] = (*self).into();
+		vk::ClearColorValue { uint32 }
+	}
+}
+
+#[cfg(feature = "glam")]
+impl ColorClearValue for ::glam::IVec4 {
+	fn as_raw(&self) -> vk::ClearColorValue {
+		let int32: [i32; 4] = (*self).into();
+		vk::ClearColorValue { int32 }
+	}
+}
+
 pub trait ColorClearValues {
 	fn as_raw(&self) -> Vec<vk::ClearColorValue>;
 }
@@ -601,23 +1251,73 @@ impl ColorClearValues for () {
 	}
 }
 
-impl<A> ColorClearValues for (A,)
-where
-	A: ColorClearValue,
-{
-	fn as_raw(&self) -> Vec<vk::ClearColorValue> {
-		vec![self.0.as_raw()]
-	}
+/// Implements `ColorAttachments` and `ColorClearValues` for tuples, given the type parameter and
+/// index of each element of each tuple
+macro_rules! tuple_color_attachments {
+	($(($($T:ident $i:tt),+))+) => {
+		$(
+			unsafe impl<S, $($T),+> ColorAttachments<S> for ($($T,)+)
+			where
+				S: SampleCountType,
+				$($T: ColorAttachmentType<S>,)+
+			{
+				type ClearValues = ($($T::ClearValue,)+);
+
+				fn desc() -> Vec<(pass::Attachment, Option<pass::Attachment>)> {
+					vec![$($T::desc()),+]
+				}
+
+				fn integer() -> Vec<bool> {
+					vec![$($T::INTEGER),+]
+				}
+
+				fn as_raw(&self) -> Vec<(vk::ImageView, Option<vk::ImageView>)> {
+					vec![$(self.$i.as_raw()),+]
+				}
+
+				fn outputs(&self) -> Vec<(vk::Image, vk::ImageView)> {
+					vec![$(self.$i.output()),+]
+				}
+
+				fn load_clear_values(&self) -> Vec<(vk::ClearValue, Option<vk::ClearValue>)> {
+					vec![$(self.$i.load_clear_values()),+]
+				}
+
+				fn create(
+					context: &Context,
+					usages: DynImageUsage,
+					extent: vk::Extent2D,
+					layers: u32,
+				) -> MarsResult<Self> {
+					Ok(($($T::create(context, usages, extent, layers)?,)+))
+				}
+			}
+
+			impl<$($T),+> ColorClearValues for ($($T,)+)
+			where
+				$($T: ColorClearValue,)+
+			{
+				fn as_raw(&self) -> Vec<vk::ClearColorValue> {
+					vec![$(self.$i.as_raw()),+]
+				}
+			}
+		)+
+	};
 }
 
-impl<A, B> ColorClearValues for (A, B)
-where
-	A: ColorClearValue,
-	B: ColorClearValue,
-{
-	fn as_raw(&self) -> Vec<vk::ClearColorValue> {
-		vec![self.0.as_raw(), self.1.as_raw()]
-	}
+tuple_color_attachments! {
+	(A 0)
+	(A 0, B 1)
+	(A 0, B 1, C 2)
+	(A 0, B 1, C 2, D 3)
+	(A 0, B 1, C 2, D 3, E 4)
+	(A 0, B 1, C 2, D 3, E 4, F 5)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10)
+	(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11)
 }
 
 pub trait DepthClearValue {
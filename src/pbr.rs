@@ -0,0 +1,451 @@
+//! Physically based shading with the metallic-roughness material model, ready to draw with.
+//!
+//! A `PbrFunction` draws meshes of `PbrVertex`es with the bindings
+//! - 0: the `Mvp` of the mesh
+//! - 1: the `PbrScene`, the camera position and a directional light
+//! - 2: the `MaterialFactors` of the mesh's material
+//! - 3: the `MaterialTextures` of the mesh's material: albedo, normal, metallic-roughness, ambient
+//!   occlusion and emissive, following glTF's conventions for what each channel holds
+//!
+//! It writes linear radiance to the first color attachment of its render pass, which should be a
+//! floating point attachment that's tone mapped afterwards, like the `HdrFormat` of `tonemap`.
+//! Shaders of custom materials can start from `PBR_VERTEX_SHADER` and `PBR_FRAGMENT_SHADER`.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	function::{
+		compile_shader, Argument, AttributeDesc, AttributeFormat, Binding, BindingDesc, BindingType, FunctionDef,
+		FunctionImpl, FunctionOptions, FunctionPrototype, Parameter, WriteArgument, WriteSampledImageArgument,
+	},
+	image::{
+		format::{R8G8B8A8Srgb, R8G8B8A8Unorm},
+		usage, FormatType, Image, MipmapMode, SampledImage,
+	},
+	math::*,
+	pass::{RenderPass, RenderPassPrototype},
+	shader::ShaderStage,
+	shapes::MeshData,
+	Context, MarsResult,
+};
+
+/// The GLSL vertex shader of `PbrFunction`
+pub const PBR_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform Mvp {
+	mat4 model;
+	mat4 view;
+	mat4 proj;
+} mvp;
+
+layout(location = 0) in vec3 v_position;
+layout(location = 1) in vec3 v_normal;
+layout(location = 2) in vec4 v_tangent;
+layout(location = 3) in vec2 v_uv;
+
+layout(location = 0) out vec3 f_position;
+layout(location = 1) out vec3 f_normal;
+layout(location = 2) out vec4 f_tangent;
+layout(location = 3) out vec2 f_uv;
+
+void main() {
+	vec4 world = mvp.model * vec4(v_position, 1.0);
+	mat3 normal_matrix = mat3(transpose(inverse(mvp.model)));
+	f_position = world.xyz;
+	f_normal = normal_matrix * v_normal;
+	f_tangent = vec4(mat3(mvp.model) * v_tangent.xyz, v_tangent.w);
+	f_uv = v_uv;
+	gl_Position = mvp.proj * mvp.view * world;
+}
+"#;
+
+/// The GLSL fragment shader of `PbrFunction`
+pub const PBR_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 1) uniform Scene {
+	vec3 camera_position;
+	vec3 light_direction;
+	vec3 light_color;
+	float light_intensity;
+	vec3 ambient;
+} scene;
+
+layout(set = 0, binding = 2) uniform Material {
+	vec4 base_color;
+	vec3 emissive;
+	float metallic;
+	float roughness;
+	float normal_scale;
+	float occlusion_strength;
+} material;
+
+// Albedo, normal, metallic-roughness, occlusion and emissive
+layout(set = 0, binding = 3) uniform sampler2D textures[5];
+
+layout(location = 0) in vec3 f_position;
+layout(location = 1) in vec3 f_normal;
+layout(location = 2) in vec4 f_tangent;
+layout(location = 3) in vec2 f_uv;
+
+layout(location = 0) out vec4 color;
+
+const float PI = 3.14159265359;
+
+float distribution_ggx(float n_dot_h, float roughness) {
+	float a = roughness * roughness;
+	float a2 = a * a;
+	float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+	return a2 / (PI * d * d);
+}
+
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+	float r = roughness + 1.0;
+	float k = r * r / 8.0;
+	float g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+	float g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+	return g_v * g_l;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+	return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+void main() {
+	vec4 albedo = texture(textures[0], f_uv) * material.base_color;
+	vec3 tangent_normal = texture(textures[1], f_uv).xyz * 2.0 - 1.0;
+	tangent_normal.xy *= material.normal_scale;
+	// glTF keeps roughness in green and metalness in blue
+	vec4 metallic_roughness = texture(textures[2], f_uv);
+	float metallic = clamp(metallic_roughness.b * material.metallic, 0.0, 1.0);
+	float roughness = clamp(metallic_roughness.g * material.roughness, 0.04, 1.0);
+	float occlusion = mix(1.0, texture(textures[3], f_uv).r, material.occlusion_strength);
+	vec3 emissive = texture(textures[4], f_uv).rgb * material.emissive;
+
+	vec3 normal = normalize(f_normal);
+	vec3 tangent = normalize(f_tangent.xyz - normal * dot(normal, f_tangent.xyz));
+	vec3 bitangent = cross(normal, tangent) * f_tangent.w;
+	vec3 n = normalize(mat3(tangent, bitangent, normal) * tangent_normal);
+	vec3 v = normalize(scene.camera_position - f_position);
+	vec3 l = normalize(-scene.light_direction);
+	vec3 h = normalize(v + l);
+
+	float n_dot_v = max(dot(n, v), 0.0001);
+	float n_dot_l = max(dot(n, l), 0.0);
+	float n_dot_h = max(dot(n, h), 0.0);
+	vec3 f0 = mix(vec3(0.04), albedo.rgb, metallic);
+	vec3 f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+	vec3 specular = distribution_ggx(n_dot_h, roughness) * geometry_smith(n_dot_v, n_dot_l, roughness) * f
+		/ (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+	vec3 diffuse = (1.0 - f) * (1.0 - metallic) * albedo.rgb / PI;
+	vec3 radiance = scene.light_color * scene.light_intensity;
+
+	vec3 lit = (diffuse + specular) * radiance * n_dot_l;
+	vec3 ambient = scene.ambient * albedo.rgb * occlusion;
+	color = vec4(lit + ambient + emissive, albedo.a);
+}
+"#;
+
+/// The vertex layout of `PbrFunction`, matching a vertex shader with `vec3`, `vec3`, `vec4` and
+/// `vec2` inputs at locations 0 to 3
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct PbrVertex {
+	pub position: Vec3,
+	pub normal: Vec3,
+	/// The direction of increasing `u` in `xyz`, and in `w` the sign the bitangent, the cross
+	/// product of the normal and tangent, is multiplied by to point in the direction of increasing
+	/// `v`
+	pub tangent: Vec4,
+	pub uv: Vec2,
+}
+
+impl PbrVertex {
+	pub fn new(position: Vec3, normal: Vec3, tangent: Vec4, uv: Vec2) -> Self {
+		Self {
+			position,
+			normal,
+			tangent,
+			uv,
+		}
+	}
+
+	/// The vertices of `mesh` with tangents derived from its texture coordinates, each the average of
+	/// the tangents of the triangles sharing the vertex
+	pub fn from_mesh(mesh: &MeshData) -> Vec<Self> {
+		let mut tangents = vec![Vec3::zeros(); mesh.vertices.len()];
+		let mut bitangents = vec![Vec3::zeros(); mesh.vertices.len()];
+		for triangle in mesh.indices.chunks_exact(3) {
+			let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+			let (va, vb, vc) = (&mesh.vertices[a], &mesh.vertices[b], &mesh.vertices[c]);
+			let (e1, e2) = (vb.position - va.position, vc.position - va.position);
+			let (d1, d2) = (vb.uv - va.uv, vc.uv - va.uv);
+			let det = d1.x * d2.y - d2.x * d1.y;
+			if det.abs() < f32::EPSILON {
+				continue;
+			}
+			let tangent = (e1 * d2.y - e2 * d1.y) / det;
+			let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+			for &i in &[a, b, c] {
+				tangents[i] += tangent;
+				bitangents[i] += bitangent;
+			}
+		}
+		mesh.vertices
+			.iter()
+			.zip(tangents.iter().zip(&bitangents))
+			.map(|(vertex, (tangent, bitangent))| {
+				let normal = vertex.normal;
+				// Vertices not part of any triangle with distinct texture coordinates get any tangent
+				// perpendicular to the normal
+				let tangent = tangent - normal * normal.dot(tangent);
+				let tangent = tangent.try_normalize(f32::EPSILON).unwrap_or_else(|| {
+					normal
+						.cross(&Vec3::x())
+						.try_normalize(f32::EPSILON)
+						.unwrap_or_else(Vec3::z)
+				});
+				let handedness = if normal.cross(&tangent).dot(bitangent) < 0.0 {
+					-1.0
+				} else {
+					1.0
+				};
+				Self::new(
+					vertex.position,
+					normal,
+					Vec4::new(tangent.x, tangent.y, tangent.z, handedness),
+					vertex.uv,
+				)
+			})
+			.collect()
+	}
+}
+
+unsafe impl Parameter for PbrVertex {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![
+			AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec4F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec2F,
+				offset: None,
+			},
+		]
+	}
+}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for PbrVertex {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for PbrVertex {}
+
+/// The camera and lighting shared by every mesh drawn in a scene
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct PbrScene {
+	pub camera_position: Vec3A,
+	/// The direction the light travels in, from the light towards the scene
+	pub light_direction: Vec3A,
+	pub light_color: Vec3,
+	pub light_intensity: f32,
+	/// Light reaching every surface from every direction, scaled by ambient occlusion
+	pub ambient: Vec3A,
+}
+
+impl PbrScene {
+	pub fn new(camera_position: Vec3, light_direction: Vec3, light_color: Vec3, light_intensity: f32) -> Self {
+		Self {
+			camera_position: camera_position.into(),
+			light_direction: light_direction.into(),
+			light_color,
+			light_intensity,
+			ambient: Vec3A::new(0.03, 0.03, 0.03),
+		}
+	}
+}
+
+unsafe impl Binding for PbrScene {
+	type Argument = Buffer<UniformBufferUsage, PbrScene>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+/// The factors every texture of a material is multiplied by, which for untextured materials are the
+/// material
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct MaterialFactors {
+	/// Linear RGB and alpha
+	pub base_color: Vec4,
+	/// Linear RGB, black by default
+	pub emissive: Vec3,
+	pub metallic: f32,
+	pub roughness: f32,
+	/// Scales the X and Y of normals sampled from the normal map
+	pub normal_scale: f32,
+	/// How much of the ambient occlusion map is applied, from 0 to 1
+	pub occlusion_strength: f32,
+	_padding: f32,
+}
+
+impl Default for MaterialFactors {
+	fn default() -> Self {
+		Self {
+			base_color: Vec4::new(1.0, 1.0, 1.0, 1.0),
+			emissive: Vec3::zeros(),
+			metallic: 1.0,
+			roughness: 1.0,
+			normal_scale: 1.0,
+			occlusion_strength: 1.0,
+			_padding: 0.0,
+		}
+	}
+}
+
+unsafe impl Binding for MaterialFactors {
+	type Argument = Buffer<UniformBufferUsage, MaterialFactors>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for PbrScene {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for PbrScene {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for MaterialFactors {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for MaterialFactors {}
+
+/// The textures of a material, bound as an array of five combined image samplers
+pub struct MaterialTextures {
+	/// sRGB color and alpha
+	pub albedo: SampledImage<R8G8B8A8Srgb>,
+	/// Tangent space normals, with X, Y and Z mapped from -1..1 to 0..1
+	pub normal: SampledImage<R8G8B8A8Unorm>,
+	/// Roughness in green and metalness in blue
+	pub metallic_roughness: SampledImage<R8G8B8A8Unorm>,
+	/// Ambient occlusion in red
+	pub occlusion: SampledImage<R8G8B8A8Unorm>,
+	/// sRGB emitted color
+	pub emissive: SampledImage<R8G8B8A8Srgb>,
+}
+
+impl MaterialTextures {
+	/// Textures of a single texel that leave the factors of a material unchanged, for untextured
+	/// materials
+	pub fn untextured(context: &Context) -> MarsResult<Self> {
+		Ok(Self {
+			albedo: single_texel(context, [255, 255, 255, 255])?,
+			normal: single_texel(context, [128, 128, 255, 255])?,
+			metallic_roughness: single_texel(context, [255, 255, 255, 255])?,
+			occlusion: single_texel(context, [255, 255, 255, 255])?,
+			emissive: single_texel(context, [255, 255, 255, 255])?,
+		})
+	}
+}
+
+fn single_texel<F>(context: &Context, pixel: F::Pixel) -> MarsResult<SampledImage<F>>
+where
+	F: FormatType,
+{
+	let extent = vk::Extent2D { width: 1, height: 1 };
+	let image = Image::make_image_from_pixels(context, usage::SampledImage, extent, &[pixel], MipmapMode::None)?;
+	SampledImage::create(context, image)
+}
+
+unsafe impl Binding for MaterialTextures {
+	type Argument = Self;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 5,
+		}
+	}
+}
+
+impl Argument for MaterialTextures {
+	fn as_write(&self) -> WriteArgument {
+		fn write<F: FormatType>(image: &SampledImage<F>) -> WriteArgument<'static> {
+			WriteArgument::SampledImage(WriteSampledImageArgument {
+				sampler: image.sampler.sampler.clone(),
+				image_view: image.image_view.image_view.raw,
+				image_layout: image.image.layout,
+			})
+		}
+		WriteArgument::Array(vec![
+			write(&self.albedo),
+			write(&self.normal),
+			write(&self.metallic_roughness),
+			write(&self.occlusion),
+			write(&self.emissive),
+		])
+	}
+}
+
+/// A function shading meshes of `PbrVertex`es in render passes of prototype `P`. See the module
+/// documentation.
+pub struct PbrFunction<P>(PhantomData<P>);
+
+impl<P> FunctionPrototype for PbrFunction<P>
+where
+	P: RenderPassPrototype,
+{
+	type RenderPass = P;
+	type VertexInput = PbrVertex;
+	type Bindings = (Mvp, PbrScene, MaterialFactors, MaterialTextures);
+	type PushConstants = ();
+}
+
+impl<P> PbrFunction<P>
+where
+	P: RenderPassPrototype,
+{
+	pub fn create(context: &Context, render_pass: &RenderPass<P>) -> MarsResult<FunctionDef<Self>> {
+		Self::create_with_options(context, render_pass, &FunctionOptions::default())
+	}
+
+	pub fn create_with_options(
+		context: &Context,
+		render_pass: &RenderPass<P>,
+		options: &FunctionOptions,
+	) -> MarsResult<FunctionDef<Self>> {
+		let vert = compile_shader(PBR_VERTEX_SHADER, "pbr.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(PBR_FRAGMENT_SHADER, "pbr.frag", ShaderStage::Fragment)?;
+		FunctionDef::create_with_options(
+			context,
+			render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			options,
+		)
+	}
+}
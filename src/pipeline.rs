@@ -0,0 +1,292 @@
+use std::ffi::CStr;
+
+use rk::{device::Device, vk};
+
+use crate::{raw_device, MarsResult};
+
+/// A graphics pipeline created directly through ash, for pipeline configurations that rk's
+/// `create_pipeline` doesn't cover
+pub(crate) struct GraphicsPipeline {
+	device: Device,
+	pub(crate) pipeline: vk::Pipeline,
+}
+
+/// Everything needed to create a `GraphicsPipeline`
+pub(crate) struct GraphicsPipelineDesc<'a> {
+	/// The SPIR-V code of each shader stage, with a `main` entry point
+	pub shaders: Vec<(vk::ShaderStageFlags, &'a [u32])>,
+	/// The values of the specialization constants of every stage, the `i`th one having constant ID `i`
+	pub specialization_constants: &'a [u32],
+	/// The vertex input state, or `None` for pipelines without vertex input (like mesh pipelines)
+	pub vertex_input: Option<(
+		&'a [vk::VertexInputBindingDescription],
+		&'a [vk::VertexInputAttributeDescription],
+	)>,
+	pub color_blend_attachments: &'a [vk::PipelineColorBlendAttachmentState],
+	pub multisample_state: vk::PipelineMultisampleStateCreateInfo,
+	pub topology: vk::PrimitiveTopology,
+	pub depth_test: bool,
+	pub depth_write: bool,
+	pub depth_compare_op: vk::CompareOp,
+	/// The stencil test of front and back faces, if there is one. The references are ignored, since
+	/// they're dynamic.
+	pub stencil: Option<(vk::StencilOpState, vk::StencilOpState)>,
+	/// Whether fragment depths are clamped to the depth range instead of primitives being clipped
+	/// against the near and far planes, requires the `depthClamp` device feature
+	pub depth_clamp: bool,
+	/// Whether primitives are discarded before rasterization, leaving the attachments untouched
+	pub rasterizer_discard: bool,
+	/// A fixed fragment size for the whole pipeline, requires `VK_KHR_fragment_shading_rate`
+	pub fragment_shading_rate: Option<vk::Extent2D>,
+	/// Whether the cull mode, topology and depth compare op are dynamic, which requires
+	/// `VK_EXT_extended_dynamic_state`. The values above are ignored for dynamic state.
+	pub extended_dynamic_state: bool,
+	pub layout: vk::PipelineLayout,
+	pub render_pass: vk::RenderPass,
+	pub subpass: u32,
+	/// The pipeline to derive this one from. Every graphics pipeline allows derivatives, so any
+	/// can be a base.
+	pub base_pipeline: Option<vk::Pipeline>,
+}
+
+impl GraphicsPipeline {
+	pub(crate) fn create(device: &Device, desc: &GraphicsPipelineDesc) -> MarsResult<Self> {
+		let raw = raw_device(device);
+		let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+
+		let mut modules = Vec::with_capacity(desc.shaders.len());
+		for &(_, code) in &desc.shaders {
+			let create_info = vk::ShaderModuleCreateInfo::builder().code(code);
+			match unsafe { raw.create_shader_module(&create_info, None) } {
+				Ok(module) => modules.push(module),
+				Err(e) => {
+					destroy_modules(raw, &modules);
+					return Err(e);
+				}
+			}
+		}
+		let (map_entries, specialization_data) = specialization_map(desc.specialization_constants);
+		let specialization_info = vk::SpecializationInfo::builder()
+			.map_entries(&map_entries)
+			.data(&specialization_data);
+		let stages = desc
+			.shaders
+			.iter()
+			.zip(&modules)
+			.map(|(&(stage, _), &module)| {
+				vk::PipelineShaderStageCreateInfo::builder()
+					.stage(stage)
+					.module(module)
+					.name(entry_point)
+					.specialization_info(&specialization_info)
+					.build()
+			})
+			.collect::<Vec<_>>();
+
+		let (vertex_bindings, vertex_attributes) = desc.vertex_input.unwrap_or((&[], &[]));
+		let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+			.vertex_binding_descriptions(vertex_bindings)
+			.vertex_attribute_descriptions(vertex_attributes);
+		let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+			.topology(desc.topology)
+			.primitive_restart_enable(false);
+		// The viewport and scissor are dynamic, but their counts still need to be given here
+		let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+			.viewport_count(1)
+			.scissor_count(1);
+		let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+			.depth_clamp_enable(desc.depth_clamp)
+			.rasterizer_discard_enable(desc.rasterizer_discard)
+			.polygon_mode(vk::PolygonMode::FILL)
+			.cull_mode(vk::CullModeFlags::NONE)
+			.front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+			.depth_bias_enable(true)
+			.line_width(1.0);
+		let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+			.depth_test_enable(desc.depth_test)
+			.depth_write_enable(desc.depth_write)
+			.depth_compare_op(desc.depth_compare_op)
+			.depth_bounds_test_enable(false)
+			.stencil_test_enable(desc.stencil.is_some())
+			.front(desc.stencil.map_or_else(Default::default, |(front, _back)| front))
+			.back(desc.stencil.map_or_else(Default::default, |(_front, back)| back));
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+			.logic_op_enable(false)
+			.attachments(desc.color_blend_attachments)
+			.blend_constants([1.0, 1.0, 1.0, 1.0]);
+		// Everything set by `DynamicState` when passes are recorded
+		let mut dynamic_states = vec![
+			vk::DynamicState::VIEWPORT,
+			vk::DynamicState::SCISSOR,
+			vk::DynamicState::LINE_WIDTH,
+			vk::DynamicState::DEPTH_BIAS,
+			vk::DynamicState::BLEND_CONSTANTS,
+			vk::DynamicState::STENCIL_REFERENCE,
+		];
+		if desc.extended_dynamic_state {
+			dynamic_states.push(vk::DynamicState::CULL_MODE_EXT);
+			dynamic_states.push(vk::DynamicState::DEPTH_COMPARE_OP_EXT);
+			// Mesh pipelines have no input assembly to take a topology
+			if desc.vertex_input.is_some() {
+				dynamic_states.push(vk::DynamicState::PRIMITIVE_TOPOLOGY_EXT);
+			}
+		}
+		let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+
+		let mut create_info = vk::GraphicsPipelineCreateInfo::builder()
+			.stages(&stages)
+			.viewport_state(&viewport_state)
+			.rasterization_state(&rasterization_state)
+			.multisample_state(&desc.multisample_state)
+			.depth_stencil_state(&depth_stencil_state)
+			.color_blend_state(&color_blend_state)
+			.dynamic_state(&dynamic_state)
+			.layout(desc.layout)
+			.render_pass(desc.render_pass)
+			.subpass(desc.subpass);
+		let mut flags = vk::PipelineCreateFlags::ALLOW_DERIVATIVES;
+		if let Some(base_pipeline) = desc.base_pipeline {
+			flags |= vk::PipelineCreateFlags::DERIVATIVE;
+			create_info = create_info.base_pipeline_handle(base_pipeline).base_pipeline_index(-1);
+		}
+		create_info = create_info.flags(flags);
+		// Per-primitive and attachment shading rates are ignored in favour of the pipeline rate
+		let mut fragment_shading_rate_state = vk::PipelineFragmentShadingRateStateCreateInfoKHR::builder()
+			.fragment_size(
+				desc.fragment_shading_rate
+					.unwrap_or(vk::Extent2D { width: 1, height: 1 }),
+			)
+			.combiner_ops([vk::FragmentShadingRateCombinerOpKHR::KEEP; 2]);
+		if desc.fragment_shading_rate.is_some() {
+			create_info = create_info.push_next(&mut fragment_shading_rate_state);
+		}
+		// Vertex input and input assembly state must be left out entirely for mesh pipelines
+		if desc.vertex_input.is_some() {
+			create_info = create_info
+				.vertex_input_state(&vertex_input_state)
+				.input_assembly_state(&input_assembly_state);
+		}
+
+		let result = unsafe { raw.create_graphics_pipelines(vk::PipelineCache::null(), &[create_info.build()], None) };
+		destroy_modules(raw, &modules);
+		let pipeline = result.map_err(|(_, e)| e)?[0];
+
+		Ok(Self {
+			device: device.clone(),
+			pipeline,
+		})
+	}
+}
+
+impl Drop for GraphicsPipeline {
+	fn drop(&mut self) {
+		unsafe {
+			raw_device(&self.device).destroy_pipeline(self.pipeline, None);
+		}
+	}
+}
+
+/// A pipeline layout created directly through ash, since rk's always have one descriptor set layout
+pub(crate) struct PipelineLayout {
+	device: Device,
+	pub(crate) layout: vk::PipelineLayout,
+}
+
+impl PipelineLayout {
+	pub(crate) fn create(
+		device: &Device,
+		set_layouts: &[vk::DescriptorSetLayout],
+		push_constant_ranges: &[vk::PushConstantRange],
+	) -> MarsResult<Self> {
+		let create_info = vk::PipelineLayoutCreateInfo::builder()
+			.set_layouts(set_layouts)
+			.push_constant_ranges(push_constant_ranges);
+		let layout = unsafe { raw_device(device).create_pipeline_layout(&create_info, None)? };
+		Ok(Self {
+			device: device.clone(),
+			layout,
+		})
+	}
+}
+
+impl Drop for PipelineLayout {
+	fn drop(&mut self) {
+		unsafe {
+			raw_device(&self.device).destroy_pipeline_layout(self.layout, None);
+		}
+	}
+}
+
+/// A compute pipeline created directly through ash
+pub(crate) struct ComputePipeline {
+	device: Device,
+	pub(crate) pipeline: vk::Pipeline,
+}
+
+impl ComputePipeline {
+	/// Creates a pipeline from the SPIR-V code of a compute shader with a `main` entry point, given
+	/// the values of its specialization constants like `GraphicsPipelineDesc`
+	pub(crate) fn create(
+		device: &Device,
+		code: &[u32],
+		specialization_constants: &[u32],
+		layout: vk::PipelineLayout,
+	) -> MarsResult<Self> {
+		let raw = raw_device(device);
+		let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+
+		let create_info = vk::ShaderModuleCreateInfo::builder().code(code);
+		let module = unsafe { raw.create_shader_module(&create_info, None)? };
+		let (map_entries, specialization_data) = specialization_map(specialization_constants);
+		let specialization_info = vk::SpecializationInfo::builder()
+			.map_entries(&map_entries)
+			.data(&specialization_data);
+		let stage = vk::PipelineShaderStageCreateInfo::builder()
+			.stage(vk::ShaderStageFlags::COMPUTE)
+			.module(module)
+			.name(entry_point)
+			.specialization_info(&specialization_info);
+		let create_info = vk::ComputePipelineCreateInfo::builder()
+			.stage(stage.build())
+			.layout(layout);
+
+		let result = unsafe { raw.create_compute_pipelines(vk::PipelineCache::null(), &[create_info.build()], None) };
+		destroy_modules(raw, &[module]);
+		let pipeline = result.map_err(|(_, e)| e)?[0];
+
+		Ok(Self {
+			device: device.clone(),
+			pipeline,
+		})
+	}
+}
+
+impl Drop for ComputePipeline {
+	fn drop(&mut self) {
+		unsafe {
+			raw_device(&self.device).destroy_pipeline(self.pipeline, None);
+		}
+	}
+}
+
+/// Lays out 32-bit specialization constants with consecutive IDs starting from 0
+fn specialization_map(constants: &[u32]) -> (Vec<vk::SpecializationMapEntry>, Vec<u8>) {
+	let map_entries = (0..constants.len() as u32)
+		.map(|i| vk::SpecializationMapEntry {
+			constant_id: i,
+			offset: i * 4,
+			size: 4,
+		})
+		.collect();
+	let data = constants
+		.iter()
+		.flat_map(|value| value.to_ne_bytes().to_vec())
+		.collect();
+	(map_entries, data)
+}
+
+fn destroy_modules(device: &rk::ash::Device, modules: &[vk::ShaderModule]) {
+	for &module in modules {
+		unsafe { device.destroy_shader_module(module, None) };
+	}
+}
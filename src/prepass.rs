@@ -0,0 +1,113 @@
+//! Depth pre-passes, which render the depth of a scene before shading it.
+//!
+//! Scenes with a lot of overdraw can first draw their geometry into a `DepthPrePass` with a cheap
+//! function, then draw it again in the main pass with `DepthTest::Equal`. Since the main pass loads
+//! the depth written by the pre-pass, only the visible fragments get past the depth test and the
+//! expensive fragment shader runs once per pixel.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	image::{DynImageUsage, FormatType, Image, ImageView, SampleCount1},
+	pass::{
+		layer_count, Attachments, ColorAttachments, DepthAttachment, DepthClearValue, InputAttachments, RenderPass,
+		RenderPassPrototype,
+	},
+	target::{Target, TargetError},
+	Context, MarsResult,
+};
+
+/// A render pass prototype with no color attachments, only a depth attachment of format `D`
+pub struct DepthPrePass<D>(PhantomData<D>);
+
+impl<D> RenderPassPrototype for DepthPrePass<D>
+where
+	D: FormatType,
+	D::ClearValue: DepthClearValue,
+{
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = ();
+	type DepthAttachment = DepthAttachment<D, SampleCount1>;
+}
+
+/// A target for a depth pre-pass along with a target for the main pass that shares its depth
+/// attachment
+pub struct DepthPrePassTargets<G, D>
+where
+	G: RenderPassPrototype<SampleCount = SampleCount1, DepthAttachment = DepthAttachment<D, SampleCount1>>,
+	D: FormatType,
+	D::ClearValue: DepthClearValue,
+{
+	// The main target's depth attachment only aliases the depth target's image, so it has to be
+	// dropped first
+	main: Target<G>,
+	depth: Target<DepthPrePass<D>>,
+}
+
+impl<G, D> DepthPrePassTargets<G, D>
+where
+	G: RenderPassPrototype<SampleCount = SampleCount1, DepthAttachment = DepthAttachment<D, SampleCount1>>,
+	D: FormatType,
+	D::ClearValue: DepthClearValue,
+{
+	pub fn create(
+		context: &Context,
+		depth_pass: &RenderPass<DepthPrePass<D>>,
+		main_pass: &RenderPass<G>,
+		extent: vk::Extent2D,
+		color_usages: DynImageUsage,
+	) -> Result<Self, TargetError> {
+		assert_eq!(G::VIEW_MASK, 0, "depth pre-passes don't support multiview");
+
+		let depth_attachments = Attachments::<DepthPrePass<D>>::create(context, extent, DynImageUsage::empty())?;
+		let depth_attachment = unsafe { alias_depth_attachment(context, &depth_attachments.depth_attachment)? };
+		let layers = layer_count::<G>();
+		let main_attachments = Attachments {
+			extent,
+			input_attachments: G::InputAttachments::create(context, DynImageUsage::empty(), extent, layers)?,
+			color_attachments: G::ColorAttachments::create(context, color_usages, extent, layers)?,
+			depth_attachment,
+		};
+
+		Ok(Self {
+			main: Target::create(context, main_pass, main_attachments)?,
+			depth: Target::create(context, depth_pass, depth_attachments)?,
+		})
+	}
+
+	/// The target to render the depth pre-pass into. Clearing it clears the depth of the main
+	/// target too.
+	pub fn depth_target(&mut self) -> &mut Target<DepthPrePass<D>> {
+		&mut self.depth
+	}
+
+	/// The target to shade into after the depth pre-pass, whose depth attachment holds the depth
+	/// written by the pre-pass
+	pub fn main_target(&mut self) -> &mut Target<G> {
+		&mut self.main
+	}
+}
+
+/// Creates a depth attachment using the same image as `depth_attachment`, which must outlive it
+unsafe fn alias_depth_attachment<D>(
+	context: &Context,
+	depth_attachment: &DepthAttachment<D, SampleCount1>,
+) -> MarsResult<DepthAttachment<D, SampleCount1>>
+where
+	D: FormatType,
+{
+	let source = &depth_attachment.image;
+	let image = Image::wrap_raw(
+		context,
+		source.raw(),
+		source.usage(),
+		source.extent(),
+		source.layers(),
+		source.layout,
+	);
+	let view = ImageView::create(&image)?;
+	Ok(DepthAttachment::new(image, view))
+}
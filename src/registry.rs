@@ -0,0 +1,105 @@
+//! A registry of functions, so that creating the same function again returns the one created
+//! before instead of compiling its pipeline again.
+//!
+//! Games that load levels often create the functions of every level from scratch, even though most
+//! of them were already created for an earlier level. Functions created through a
+//! `FunctionRegistry` are keyed by their prototype, render pass, shaders and options, and the
+//! registry keeps them alive until it's cleared, so loading a level again is nearly free.
+
+use std::{
+	any::{Any, TypeId},
+	collections::HashMap,
+	sync::Arc,
+};
+
+use rk::vk;
+
+use crate::{
+	function::{FunctionDef, FunctionImpl, FunctionOptions, FunctionPrototype},
+	pass::{RenderPass, RenderPassHandle},
+	Context, MarsResult,
+};
+
+/// What a registered function was created from
+#[derive(PartialEq, Eq, Hash)]
+struct FunctionKey {
+	prototype: TypeId,
+	/// Kept alive by the entry, so the handle can't be reused for another render pass while the key
+	/// exists
+	render_pass: vk::RenderPass,
+	subpass: u32,
+	/// The SPIR-V of the vertex, geometry and fragment shaders
+	shaders: (Vec<u32>, Option<Vec<u32>>, Option<Vec<u32>>),
+	options: FunctionOptions,
+}
+
+struct FunctionEntry {
+	/// An `Arc<FunctionDef<F>>` for the prototype `F` of the key
+	function: Box<dyn Any>,
+	_render_pass: Arc<RenderPassHandle>,
+}
+
+#[derive(Default)]
+pub struct FunctionRegistry {
+	functions: HashMap<FunctionKey, FunctionEntry>,
+}
+
+impl FunctionRegistry {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns the function created before from the same prototype, render pass, shaders and
+	/// options, or creates it with `FunctionDef::create_with_options` if there isn't one
+	pub fn get_or_create<F: FunctionPrototype + 'static>(
+		&mut self,
+		context: &Context,
+		render_pass: &RenderPass<F::RenderPass>,
+		function_impl: FunctionImpl<F>,
+		options: &FunctionOptions,
+	) -> MarsResult<Arc<FunctionDef<F>>> {
+		let key = FunctionKey {
+			prototype: TypeId::of::<F>(),
+			render_pass: render_pass.render_pass.raw,
+			subpass: render_pass.subpass,
+			shaders: (
+				function_impl.vert.clone(),
+				function_impl.geom.clone(),
+				function_impl.frag.clone(),
+			),
+			options: options.clone(),
+		};
+		if let Some(entry) = self.functions.get(&key) {
+			return Ok(entry.function.downcast_ref::<Arc<FunctionDef<F>>>().unwrap().clone());
+		}
+		let function = Arc::new(FunctionDef::create_with_options(
+			context,
+			render_pass,
+			function_impl,
+			options,
+		)?);
+		self.functions.insert(
+			key,
+			FunctionEntry {
+				function: Box::new(function.clone()),
+				_render_pass: render_pass.render_pass.clone(),
+			},
+		);
+		Ok(function)
+	}
+
+	/// The number of functions in the registry
+	pub fn len(&self) -> usize {
+		self.functions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.functions.is_empty()
+	}
+
+	/// Drops the registry's references to its functions, which are destroyed once nothing else uses
+	/// them
+	pub fn clear(&mut self) {
+		self.functions.clear();
+	}
+}
@@ -1,29 +1,343 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	thread::{self, ThreadId},
+	time::Instant,
+};
+
 use rk::{
 	command::{CommandBuffer, CommandPool, Recording},
+	descriptor::DescriptorSet,
+	device::Device,
 	vk,
 };
 
 use crate::{
-	buffer::{Buffer, IndexBufferUsage, VertexBufferUsage},
-	function::{ArgumentsContainer, FunctionDef, FunctionPrototype},
-	pass::{ColorAttachments, DepthAttachmentType, RenderPassPrototype},
+	buffer::{Buffer, ConditionBufferUsage, IndexBufferUsage, IndexType, IndirectBufferUsage, VertexBufferUsage},
+	compute::{ComputeArgumentsContainer, ComputeFunctionDef, ComputeFunctionPrototype},
+	destruction::{GpuUse, RecordedUses},
+	drawlist::DrawList,
+	fault,
+	function::{
+		push_constant_bytes, ArgumentsContainer, DepthTest, DynamicState, FixedState, FunctionDef, FunctionPrototype,
+		MeshArgumentsContainer, MeshFunctionDef, MeshFunctionPrototype,
+	},
+	pass::{framebuffer_layers, ColorAttachments, DepthAttachmentType, RenderPassPrototype},
+	raw_command_buffer, raw_command_pool, raw_descriptor_set, raw_device, raw_pipeline_layout,
+	staging::Staged,
+	stats::{FrameStats, StatsCollector, TimedPass},
+	submit::SubmitHandle,
+	sync,
 	target::Target,
 	Context, MarsResult,
 };
 
+/// A command pool for every thread that records commands, so that threads recording at the same
+/// time never contend on one pool. Pools are created the first time a thread uses them and live
+/// as long as the `CommandPools`.
+pub struct CommandPools {
+	device: Device,
+	pools: Mutex<HashMap<ThreadId, Arc<Mutex<CommandPool>>>>,
+	workers: Mutex<Vec<Arc<Mutex<CommandPool>>>>,
+}
+
+// Each pool is only used by the thread it was created for, behind a lock that's never contended
+unsafe impl Send for CommandPools {}
+unsafe impl Sync for CommandPools {}
+
+impl CommandPools {
+	pub fn new(context: &Context) -> Self {
+		Self {
+			device: context.device.clone(),
+			pools: Mutex::new(HashMap::new()),
+			workers: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Pools for the workers of parallel recording, which are reused by every parallel pass instead
+	/// of each short-lived worker thread getting its own pool
+	pub(crate) fn worker_pools(&self, count: usize) -> MarsResult<Vec<Arc<Mutex<CommandPool>>>> {
+		let mut workers = self.workers.lock().unwrap();
+		while workers.len() < count {
+			workers.push(Arc::new(Mutex::new(CommandPool::create(&self.device)?)));
+		}
+		Ok(workers[..count].to_vec())
+	}
+
+	/// Runs `f` with the calling thread's pool, creating it if the thread hasn't used one yet.
+	/// Command buffers allocated from the pool must be freed within `f` or a later call on the same
+	/// thread.
+	pub(crate) fn with_current<T, F: FnOnce(&CommandPool) -> MarsResult<T>>(&self, f: F) -> MarsResult<T> {
+		let pool = self.current()?;
+		let pool = pool.lock().unwrap();
+		f(&pool)
+	}
+
+	/// The calling thread's pool, creating it if the thread hasn't used one yet
+	fn current(&self) -> MarsResult<Arc<Mutex<CommandPool>>> {
+		let mut pools = self.pools.lock().unwrap();
+		match pools.get(&thread::current().id()) {
+			Some(pool) => Ok(pool.clone()),
+			None => {
+				let pool = Arc::new(Mutex::new(CommandPool::create(&self.device)?));
+				pools.insert(thread::current().id(), pool.clone());
+				Ok(pool)
+			}
+		}
+	}
+
+	/// Records commands into a command buffer from the calling thread's pool, submits it and waits
+	/// for it to complete, for uploads and other work done off the main thread
+	pub fn submit<R: FnOnce(vk::CommandBuffer)>(&self, context: &Context, record: R) -> MarsResult<()> {
+		self.with_current(|pool| sync::one_time_submit_with_pool(context, pool, &context.queue, record))
+	}
+
+	/// Like `submit`, but submits to queue `queue` of the context, one of the `Context::queue_count`
+	/// queues, so background work can run on a lower priority queue than frames
+	pub fn submit_on<R: FnOnce(vk::CommandBuffer)>(
+		&self,
+		context: &Context,
+		queue: usize,
+		record: R,
+	) -> MarsResult<()> {
+		assert!(queue < context.queue_count(), "no such queue");
+		let queue = context.graphics_queue(queue);
+		self.with_current(|pool| sync::one_time_submit_with_pool(context, pool, queue, record))
+	}
+
+	/// The number of threads that have been given a pool
+	pub fn thread_count(&self) -> usize {
+		self.pools.lock().unwrap().len()
+	}
+}
+
 pub struct RenderEngine {
-	pub(crate) command_pool: CommandPool,
+	pub(crate) command_pools: Arc<CommandPools>,
+	/// The batch being recorded, if any
+	batch: Option<Batch>,
+	/// The label of the passes recorded next, reported if the device is lost while running them
+	label: Option<String>,
+	/// The dynamic state of the passes recorded next
+	dynamic_state: DynamicState,
+	stats: Option<StatsCollector>,
+}
+
+/// A secondary command buffer, along with the pool it was allocated from
+type Secondary = (Arc<Mutex<CommandPool>>, vk::CommandBuffer);
+
+/// The command buffer every pass of a batch is recorded into
+struct Batch {
+	command_buffer: CommandBuffer<Recording>,
+	/// The thread whose pool the command buffer was allocated from
+	thread: ThreadId,
+	/// Secondary command buffers of parallel passes, freed once the batch has executed
+	secondaries: Vec<Secondary>,
+	/// The resources recorded into the batch
+	recorded: RecordedUses,
+	/// Data staged for copies recorded into the batch, kept until the batch has executed
+	staged: Vec<Staged>,
 }
 
 impl RenderEngine {
 	pub fn new(context: &Context) -> MarsResult<Self> {
-		let command_pool = CommandPool::create(&context.device)?;
+		let command_pools = Arc::new(CommandPools::new(context));
 
-		let this = Self { command_pool };
+		let this = Self {
+			command_pools,
+			batch: None,
+			label: None,
+			dynamic_state: DynamicState::default(),
+			stats: None,
+		};
 
 		Ok(this)
 	}
 
+	/// The per-thread command pools the engine records with, which can be shared with other threads
+	/// for background uploads
+	pub fn command_pools(&self) -> Arc<CommandPools> {
+		self.command_pools.clone()
+	}
+
+	/// Labels the clears, passes and dispatches recorded from now on, so that if the device is lost
+	/// while running them the crash report says which ones they were. See the `fault` module.
+	pub fn set_label(&mut self, label: Option<&str>) {
+		self.label = label.map(String::from);
+	}
+
+	/// Sets the line width, depth bias, blend constants, stencil reference and so on that the passes
+	/// recorded from now on draw with. Fails with `ERROR_EXTENSION_NOT_PRESENT` if the state sets a
+	/// cull mode, topology or depth comparison without the `ExtendedDynamicState` extension enabled,
+	/// and with `ERROR_FEATURE_NOT_PRESENT` if it sets a line width other than 1 without the
+	/// `wide_lines` feature or a depth bias clamp without the `depth_bias_clamp` feature.
+	pub fn set_dynamic_state(&mut self, context: &Context, state: DynamicState) -> MarsResult<()> {
+		if state.is_extended() && context.extended_dynamic_state.is_none() {
+			return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+		}
+		if state.line_width != 1.0 && context.features.wide_lines == vk::FALSE {
+			log::error!("A line width of {} requires the wide_lines feature", state.line_width);
+			return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+		}
+		if state.depth_bias.clamp != 0.0 && context.features.depth_bias_clamp == vk::FALSE {
+			log::error!("A depth bias clamp requires the depth_bias_clamp feature");
+			return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+		}
+		self.dynamic_state = state;
+		Ok(())
+	}
+
+	pub fn dynamic_state(&self) -> &DynamicState {
+		&self.dynamic_state
+	}
+
+	/// Starts collecting the statistics returned by `take_frame_stats`. See the `stats` module.
+	pub fn enable_stats(&mut self, context: &Context) -> MarsResult<()> {
+		if self.stats.is_none() {
+			self.stats = Some(StatsCollector::create(context)?);
+		}
+		Ok(())
+	}
+
+	pub fn disable_stats(&mut self) {
+		self.stats = None;
+	}
+
+	/// Returns the statistics of everything recorded since the last call, or `None` if statistics
+	/// aren't enabled. Called once a frame, this gives the statistics of each frame.
+	pub fn take_frame_stats(&mut self) -> Option<FrameStats> {
+		self.stats.as_mut().map(StatsCollector::take)
+	}
+
+	/// Records every clear, pass and dispatch made by `record` into one command buffer, submitted
+	/// once `record` returns, instead of submitting and waiting for each of them on its own. Each
+	/// pass waits for the ones before it and sees everything they wrote, so a pass can sample a
+	/// target rendered by an earlier pass, like a shadow map. Uploads made with
+	/// `Buffer::record_upload` are recorded into the batch in order with its passes, but other
+	/// uploads and work made during the batch are still submitted right away, ahead of the batch.
+	/// Batches can't be nested.
+	pub fn batch<T, R: FnOnce(&mut Self) -> MarsResult<T>>(&mut self, context: &Context, record: R) -> MarsResult<T> {
+		trace_span!("RenderEngine::batch");
+		self.begin_frame()?;
+		let recorded = record(self);
+		let submitted = self.finish_batch(context, recorded.is_ok());
+		let value = recorded?;
+		submitted?;
+		Ok(value)
+	}
+
+	/// Starts recording a frame. Like `batch`, every clear, pass and dispatch recorded until
+	/// `end_frame` goes into one command buffer that's submitted once, for render loops where a
+	/// closure around the whole frame is awkward. The frame must be ended on the thread it was
+	/// begun on, and frames can't be nested or begun during a batch.
+	pub fn begin_frame(&mut self) -> MarsResult<()> {
+		assert!(self.batch.is_none(), "Frames and batches can't be nested");
+		let command_buffer = self
+			.command_pools
+			.with_current(|pool| CommandBuffer::allocate(pool)?.begin())?;
+		self.batch = Some(Batch {
+			command_buffer,
+			thread: thread::current().id(),
+			secondaries: Vec::new(),
+			recorded: RecordedUses::default(),
+			staged: Vec::new(),
+		});
+		Ok(())
+	}
+
+	/// Submits everything recorded since `begin_frame` and waits for it to complete
+	pub fn end_frame(&mut self, context: &Context) -> MarsResult<()> {
+		assert!(self.batch.is_some(), "No frame is being recorded");
+		self.finish_batch(context, true)
+	}
+
+	/// Submits everything recorded since `begin_frame` without waiting for it, so the next frame can
+	/// be recorded while the device executes this one. The returned handle tracks the submission,
+	/// and can be passed to `WindowEngine::present_after` to present the frame once it's rendered.
+	///
+	/// Resources used by the frame are kept alive until it completes. Statistics of its passes are
+	/// collected once the handle has seen it complete.
+	pub fn end_frame_async(&mut self, context: &Context) -> MarsResult<SubmitHandle> {
+		assert!(self.batch.is_some(), "No frame is being recorded");
+		// Looked up first so the frame is still being recorded if it fails, instead of its command
+		// buffers being dropped without the pool locked
+		let pool = self.command_pools.current()?;
+		let Batch {
+			command_buffer,
+			secondaries,
+			recorded,
+			staged,
+			..
+		} = self.take_batch();
+		let raw = raw_command_buffer(&command_buffer);
+		let ended = {
+			let _pool = pool.lock().unwrap();
+			command_buffer.end()
+		};
+		let device = context.device.clone();
+		let command_buffer = match ended {
+			Ok(command_buffer) => command_buffer,
+			Err(e) => {
+				free_secondaries(&device, secondaries);
+				return Err(e);
+			}
+		};
+		let stats_complete = self.stats.as_mut().map(StatsCollector::submit_async);
+		let release = Box::new(move || {
+			{
+				let _pool = pool.lock().unwrap();
+				drop(command_buffer);
+			}
+			free_secondaries(&device, secondaries);
+			drop(staged);
+		});
+		SubmitHandle::submit(context, raw, &recorded, stats_complete, release)
+	}
+
+	/// Whether a frame or batch is being recorded
+	pub fn is_recording_frame(&self) -> bool {
+		self.batch.is_some()
+	}
+
+	/// Takes the batch being recorded
+	fn take_batch(&mut self) -> Batch {
+		let batch = self.batch.take().unwrap();
+		assert_eq!(
+			batch.thread,
+			thread::current().id(),
+			"Frames must be ended on the thread they were begun on"
+		);
+		batch
+	}
+
+	/// Ends the batch being recorded, submitting it if `submit` is set, and frees its command
+	/// buffers
+	fn finish_batch(&mut self, context: &Context, submit: bool) -> MarsResult<()> {
+		let Batch {
+			command_buffer,
+			secondaries,
+			recorded,
+			staged,
+			..
+		} = self.take_batch();
+		let raw = raw_command_buffer(&command_buffer);
+		let command_pools = self.command_pools.clone();
+		let result = command_pools.with_current(|_pool| {
+			// The pool is locked while the command buffer is dropped, which frees it
+			let command_buffer = command_buffer.end()?;
+			if submit {
+				sync::submit_and_wait(context, raw, &recorded)?;
+			}
+			drop(command_buffer);
+			Ok(())
+		});
+		if let Some(stats) = &mut self.stats {
+			stats.resolve();
+		}
+		free_secondaries(&context.device, secondaries);
+		drop(staged);
+		result
+	}
+
 	pub fn clear<G: RenderPassPrototype>(
 		&mut self,
 		context: &Context,
@@ -31,17 +345,9 @@ impl RenderEngine {
 		colors: <G::ColorAttachments as ColorAttachments<G::SampleCount>>::ClearValues,
 		depth: <G::DepthAttachment as DepthAttachmentType<G::SampleCount>>::ClearValue,
 	) -> MarsResult<()> {
-		self.submit(context, |_this, command_buffer| {
+		self.submit(context, |_this, command_buffer, _recorded| {
 			unsafe {
-				command_buffer.begin_render_pass(
-					&target.render_pass,
-					&target.framebuffer,
-					vk::Rect2D {
-						offset: vk::Offset2D { x: 0, y: 0 },
-						extent: target.attachments.extent(),
-					},
-					&[],
-				)?;
+				begin_render_pass(context, raw_command_buffer(command_buffer), target);
 				let clear_attachments = target.attachments.clears(colors, depth);
 				let clear_rects = vec![
 					vk::ClearRect {
@@ -50,124 +356,1299 @@ impl RenderEngine {
 							extent: target.attachments.extent(),
 						},
 						base_array_layer: 0,
-						layer_count: 1,
+						// Multiview passes clear every view with a single layer
+						layer_count: framebuffer_layers::<G>(),
 					};
 					clear_attachments.len()
 				];
 				command_buffer.clear_attachments(&clear_attachments, &clear_rects);
-				command_buffer.end_render_pass();
+				raw_device(&context.device).cmd_end_render_pass(raw_command_buffer(command_buffer));
 			}
 
 			Ok(())
 		})
 	}
 
-	pub fn pass<'a, F: FunctionPrototype + 'a, I: IntoIterator<Item = DrawArgs<'a, F>>>(
+	pub fn pass<'a, F: FunctionPrototype + 'a, X: IndexType, I: IntoIterator<Item = DrawArgs<'a, F, X>>>(
 		&mut self,
 		context: &Context,
 		target: &mut Target<F::RenderPass>,
 		function: &FunctionDef<F>,
 		draws: I,
 	) -> MarsResult<()> {
-		self.submit(context, |_this, command_buffer| {
+		self.record_pass(context, target, function.into(), draws, |this, command_buffer, draw| {
+			record_indexed_draw(context, this, command_buffer, &draw)
+		})
+	}
+
+	/// Records the draws of a draw list in one render pass, after sorting them so that draws using
+	/// the same pipeline, descriptor set and buffers are next to each other. Only the state that
+	/// differs from the previous draw is bound, which saves a lot of CPU and driver time when many
+	/// draws share functions and meshes.
+	pub fn pass_list<G: RenderPassPrototype>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<G>,
+		list: &mut DrawList<G>,
+	) -> MarsResult<()> {
+		list.sort();
+		let dynamic_state = self.dynamic_state;
+		self.submit(context, |this, command_buffer, recorded| {
 			unsafe {
-				command_buffer.begin_render_pass(
-					&target.render_pass,
-					&target.framebuffer,
-					vk::Rect2D {
-						offset: vk::Offset2D { x: 0, y: 0 },
-						extent: target.attachments.extent,
-					},
-					&[],
-				)?;
-				command_buffer.set_viewport(vk::Viewport {
-					x: 0.0,
-					y: 0.0,
-					width: target.attachments.extent.width as f32,
-					height: target.attachments.extent.height as f32,
-					min_depth: 0.0,
-					max_depth: 1.0,
-				});
+				let device = raw_device(&context.device);
+				let raw = raw_command_buffer(command_buffer);
+				begin_render_pass(context, raw, target);
 				command_buffer.set_scissor(vk::Rect2D {
 					offset: vk::Offset2D { x: 0, y: 0 },
-					extent: vk::Extent2D {
-						width: target.attachments.extent.width,
-						height: target.attachments.extent.height,
-					},
+					extent: target.attachments.extent,
 				});
-				command_buffer.bind_pipeline(vk::PipelineBindPoint::GRAPHICS, &function.pipeline);
-				for draw in draws {
-					command_buffer.bind_descriptor_set(&function.pipeline_layout, &draw.bindings.descriptor_set);
-					command_buffer.bind_vertex_buffers(0, &[&draw.vertices.buffer], &[0]);
-					command_buffer.bind_index_buffer(&draw.indices.buffer, 0, vk::IndexType::UINT32);
-					command_buffer.draw_indexed(draw.indices.len as u32, 1, 0, 0, 0);
+				let mut pipeline = vk::Pipeline::null();
+				let mut flip_viewport = None;
+				let mut descriptor_set = None;
+				let mut vertices = vk::Buffer::null();
+				let mut indices = (vk::Buffer::null(), vk::IndexType::UINT32);
+				for draw in &list.draws {
+					draw.mark_used(recorded);
+					if draw.pipeline != pipeline {
+						device.cmd_bind_pipeline(raw, vk::PipelineBindPoint::GRAPHICS, draw.pipeline);
+						record_dynamic_state(
+							device,
+							context.extended_dynamic_state.as_ref(),
+							raw,
+							&dynamic_state,
+							draw.fixed_state,
+						);
+						pipeline = draw.pipeline;
+						// Functions can have incompatible pipeline layouts, so the descriptor set
+						// is bound again after switching
+						descriptor_set = None;
+					}
+					if flip_viewport != Some(draw.flip_viewport) {
+						command_buffer.set_viewport(viewport(target.attachments.extent, draw.flip_viewport));
+						flip_viewport = Some(draw.flip_viewport);
+					}
+					if draw.descriptor_set.is_some() && draw.descriptor_set != descriptor_set {
+						device.cmd_bind_descriptor_sets(
+							raw,
+							vk::PipelineBindPoint::GRAPHICS,
+							draw.pipeline_layout,
+							0,
+							&[draw.descriptor_set.unwrap()],
+							&[],
+						);
+						descriptor_set = draw.descriptor_set;
+					}
+					if draw.vertices != vertices {
+						device.cmd_bind_vertex_buffers(raw, 0, &[draw.vertices], &[0]);
+						vertices = draw.vertices;
+					}
+					if (draw.indices, draw.index_type) != indices {
+						device.cmd_bind_index_buffer(raw, draw.indices, 0, draw.index_type);
+						indices = (draw.indices, draw.index_type);
+					}
+					let (offset, size) = draw.push_constants;
+					if size > 0 {
+						device.cmd_push_constants(
+							raw,
+							draw.pipeline_layout,
+							draw.push_constant_stages,
+							0,
+							&list.push_constants[offset..offset + size],
+						);
+					}
+					device.cmd_draw_indexed(raw, draw.index_count, 1, 0, 0, 0);
+					this.count_draw(Some(draw.index_count));
 				}
-				command_buffer.end_render_pass();
+				device.cmd_end_render_pass(raw);
 			}
 
 			Ok(())
 		})
 	}
 
-	fn submit<R: FnOnce(&mut Self, &mut CommandBuffer<Recording>) -> MarsResult<()>>(
+	/// Like `pass`, but the draws are split between several threads that each record their share into
+	/// a secondary command buffer, which are then executed in order in the render pass. Only worth
+	/// it for scenes with many thousands of draws, since fewer draws are recorded on one thread.
+	pub fn pass_par<'a, F: FunctionPrototype + 'a, X: IndexType, I: IntoIterator<Item = DrawArgs<'a, F, X>>>(
 		&mut self,
 		context: &Context,
-		recording: R,
+		target: &mut Target<F::RenderPass>,
+		function: &FunctionDef<F>,
+		draws: I,
 	) -> MarsResult<()> {
-		let command_buffer = CommandBuffer::allocate(&self.command_pool)?;
-		let mut command_buffer = command_buffer.begin()?;
-
-		recording(self, &mut command_buffer)?;
-		let command_buffer = command_buffer.end()?;
-		let command_buffer = unsafe {
-			context
-				.queue
-				.with_lock(|| context.queue.submit(command_buffer, &[], &[]))?
-		};
-		command_buffer.wait()?;
+		const MIN_DRAWS_PER_THREAD: usize = 1024;
+
+		let args = draws.into_iter().collect::<Vec<_>>();
+		// Only the handles are sent to the other threads
+		let draws = args
+			.iter()
+			.map(|draw| {
+				self.count_draw(Some(draw.indices.len as u32));
+				RawDraw {
+					descriptor_set: draw.bindings.descriptor_set.as_ref().map(raw_descriptor_set),
+					vertices: draw.vertices.raw(),
+					indices: draw.indices.raw(),
+					index_type: X::as_raw(),
+					index_count: draw.indices.len as u32,
+					push_constants: push_constant_bytes(&draw.push_constants).to_vec(),
+				}
+			})
+			.collect::<Vec<_>>();
+		let available = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+		let threads = available.min(draws.len() / MIN_DRAWS_PER_THREAD).max(1);
+		let chunk_size = ((draws.len() + threads - 1) / threads).max(1);
+
+		// The worker pools stay locked while the secondary command buffers are recorded
+		let worker_pools = self.command_pools.worker_pools(threads)?;
+		let locked_pools = worker_pools.iter().map(|pool| pool.lock().unwrap()).collect::<Vec<_>>();
+		let raw_pools = locked_pools
+			.iter()
+			.map(|pool| raw_command_pool(pool))
+			.collect::<Vec<_>>();
+		let device = raw_device(&context.device);
+		let mut secondaries = Vec::new();
+		let dynamic_state = self.dynamic_state;
 
+		let result = self.submit(context, |_this, command_buffer, recorded| {
+			for draw in &args {
+				draw.mark_used(recorded);
+			}
+			let state = SecondaryState {
+				render_pass: target.render_pass.raw,
+				framebuffer: target.framebuffer.raw,
+				extent: target.attachments.extent,
+				pipeline: function.pipeline.pipeline,
+				pipeline_layout: function.pipeline_layout,
+				push_constant_stages: function.push_constant_stages,
+				flip_viewport: function.flip_viewport,
+				dynamic_state,
+				fixed_state: function.fixed_state,
+			};
+			let extended = context.extended_dynamic_state.as_ref();
+			let recorded = thread::scope(|scope| {
+				let workers = draws
+					.chunks(chunk_size)
+					.zip(&raw_pools)
+					.map(|(chunk, &pool)| {
+						scope.spawn(move || unsafe { record_secondary(device, extended, pool, state, chunk) })
+					})
+					.collect::<Vec<_>>();
+				workers
+					.into_iter()
+					.map(|worker| worker.join().expect("Draw recording thread panicked"))
+					.collect::<Vec<_>>()
+			});
+			// Buffers that were recorded are kept to be freed even if another thread failed
+			let mut error = None;
+			for (index, result) in recorded.into_iter().enumerate() {
+				match result {
+					Ok(secondary) => secondaries.push((index, secondary)),
+					Err(e) => error = Some(e),
+				}
+			}
+			if let Some(e) = error {
+				return Err(e);
+			}
+
+			unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				begin_render_pass_with_contents(context, raw, target, vk::SubpassContents::SECONDARY_COMMAND_BUFFERS);
+				let buffers = secondaries.iter().map(|&(_, secondary)| secondary).collect::<Vec<_>>();
+				if !buffers.is_empty() {
+					device.cmd_execute_commands(raw, &buffers);
+				}
+				device.cmd_end_render_pass(raw);
+			}
+
+			Ok(())
+		});
+
+		drop(locked_pools);
+		match &mut self.batch {
+			// The batch hasn't executed yet, so the secondaries are freed once it has
+			Some(batch) => batch.secondaries.extend(
+				secondaries
+					.into_iter()
+					.map(|(index, secondary)| (worker_pools[index].clone(), secondary)),
+			),
+			None => {
+				for (index, secondary) in secondaries {
+					let pool = worker_pools[index].lock().unwrap();
+					unsafe { device.free_command_buffers(raw_command_pool(&pool), &[secondary]) };
+				}
+			}
+		}
+		result
+	}
+
+	/// Like `pass`, but the parameters of each draw (the index count, instance count, and so on) are
+	/// read from a buffer on the device, so they can be written by a compute shader. Every command in
+	/// the buffer is drawn, and more than one command per buffer requires the `multiDrawIndirect`
+	/// device feature.
+	pub fn pass_indirect<
+		'a,
+		F: FunctionPrototype + 'a,
+		X: IndexType,
+		I: IntoIterator<Item = IndirectDrawArgs<'a, F, X>>,
+	>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<F::RenderPass>,
+		function: &FunctionDef<F>,
+		draws: I,
+	) -> MarsResult<()> {
+		self.record_pass(
+			context,
+			target,
+			function.into(),
+			draws,
+			|this, command_buffer, draw| unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				record_bind_buffers(context, raw, draw.vertices, draw.indices);
+				raw_device(&context.device).cmd_draw_indexed_indirect(
+					raw,
+					draw.commands.raw(),
+					0,
+					draw.commands.len as u32,
+					std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+				);
+				for _ in 0..draw.commands.len {
+					this.count_draw(None);
+				}
+			},
+		)
+	}
+
+	/// Like `pass_indirect`, but the number of commands drawn from each buffer is read from a count
+	/// buffer on the device too, capped at the length of the command buffer. This lets a compute
+	/// shader write a compacted list of draws without the count being read back on the host. Fails
+	/// with `ERROR_EXTENSION_NOT_PRESENT` unless the `DrawIndirectCount` extension is enabled.
+	pub fn pass_indirect_count<
+		'a,
+		F: FunctionPrototype + 'a,
+		X: IndexType,
+		I: IntoIterator<Item = IndirectCountDrawArgs<'a, F, X>>,
+	>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<F::RenderPass>,
+		function: &FunctionDef<F>,
+		draws: I,
+	) -> MarsResult<()> {
+		let draw_indirect_count = context
+			.draw_indirect_count
+			.as_ref()
+			.ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+		self.record_pass(
+			context,
+			target,
+			function.into(),
+			draws,
+			|this, command_buffer, draw| unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				record_bind_buffers(context, raw, draw.vertices, draw.indices);
+				draw_indirect_count.cmd_draw_indexed_indirect_count(
+					raw,
+					draw.commands.raw(),
+					0,
+					draw.count.raw(),
+					0,
+					draw.commands.len as u32,
+					std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+				);
+				// How many of the commands are drawn is only known on the device
+				this.count_draw(None);
+			},
+		)
+	}
+
+	/// Like `pass`, but each draw is skipped if the 32-bit value in its condition buffer is zero (or
+	/// non-zero, if the condition is inverted). The condition is read on the device when the draw
+	/// executes, so it can be the result of an earlier compute pass like an occlusion test. Fails
+	/// with `ERROR_EXTENSION_NOT_PRESENT` unless the `ConditionalRendering` extension is enabled.
+	pub fn pass_conditional<
+		'a,
+		F: FunctionPrototype + 'a,
+		X: IndexType,
+		I: IntoIterator<Item = ConditionalDrawArgs<'a, F, X>>,
+	>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<F::RenderPass>,
+		function: &FunctionDef<F>,
+		draws: I,
+	) -> MarsResult<()> {
+		let conditional_rendering = context
+			.conditional_rendering
+			.as_ref()
+			.ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+		self.record_pass(
+			context,
+			target,
+			function.into(),
+			draws,
+			|this, command_buffer, draw| unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				let flags = if draw.inverted {
+					vk::ConditionalRenderingFlagsEXT::INVERTED
+				} else {
+					vk::ConditionalRenderingFlagsEXT::empty()
+				};
+				let begin_info = vk::ConditionalRenderingBeginInfoEXT::builder()
+					.buffer(draw.condition.raw())
+					.offset(0)
+					.flags(flags);
+				(conditional_rendering.cmd_begin_conditional_rendering_ext)(raw, &*begin_info);
+				record_bind_buffers(context, raw, draw.vertices, draw.indices);
+				command_buffer.draw_indexed(draw.indices.len as u32, 1, 0, 0, 0);
+				this.count_draw(Some(draw.indices.len as u32));
+				(conditional_rendering.cmd_end_conditional_rendering_ext)(raw);
+			},
+		)
+	}
+
+	/// Runs a mesh function once for each draw, dispatching its task (or mesh, if it has no task
+	/// shader) workgroups
+	pub fn mesh_pass<'a, F: MeshFunctionPrototype + 'a, I: IntoIterator<Item = MeshDrawArgs<'a, F>>>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<F::RenderPass>,
+		function: &MeshFunctionDef<F>,
+		draws: I,
+	) -> MarsResult<()> {
+		let mesh_shader = context
+			.mesh_shader
+			.as_ref()
+			.ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?;
+		self.record_pass(
+			context,
+			target,
+			function.into(),
+			draws,
+			|this, command_buffer, draw| unsafe {
+				let [x, y, z] = draw.group_count;
+				mesh_shader.cmd_draw_mesh_tasks(raw_command_buffer(command_buffer), x, y, z);
+				this.count_draw(None);
+			},
+		)
+	}
+
+	/// Records a render pass over `target` with a single subpass drawing `draws` with `function`
+	fn record_pass<G, D, I, R>(
+		&mut self,
+		context: &Context,
+		target: &mut Target<G>,
+		function: PassFunction,
+		draws: I,
+		record: R,
+	) -> MarsResult<()>
+	where
+		G: RenderPassPrototype,
+		D: PassDraw,
+		I: IntoIterator<Item = D>,
+		R: FnMut(&mut Self, &mut CommandBuffer<Recording>, D),
+	{
+		let dynamic_state = self.dynamic_state;
+		self.submit(context, |this, command_buffer, recorded| {
+			unsafe {
+				begin_render_pass(context, raw_command_buffer(command_buffer), target);
+				this.record_subpass(
+					context,
+					command_buffer,
+					recorded,
+					target.attachments.extent,
+					function,
+					&dynamic_state,
+					draws,
+					record,
+				);
+				raw_device(&context.device).cmd_end_render_pass(raw_command_buffer(command_buffer));
+			}
+
+			Ok(())
+		})
+	}
+
+	/// Records the draws of the current subpass of a render pass covering `extent`. The viewport,
+	/// scissor, pipeline and dynamic state of `function` are set first, and each draw's descriptor
+	/// set and push constants are bound before `record` records the draw itself.
+	#[allow(clippy::too_many_arguments)]
+	pub(crate) unsafe fn record_subpass<D, I, R>(
+		&mut self,
+		context: &Context,
+		command_buffer: &mut CommandBuffer<Recording>,
+		recorded: &RecordedUses,
+		extent: vk::Extent2D,
+		function: PassFunction,
+		dynamic_state: &DynamicState,
+		draws: I,
+		mut record: R,
+	) where
+		D: PassDraw,
+		I: IntoIterator<Item = D>,
+		R: FnMut(&mut Self, &mut CommandBuffer<Recording>, D),
+	{
+		let device = raw_device(&context.device);
+		let raw = raw_command_buffer(command_buffer);
+		command_buffer.set_viewport(viewport(extent, function.flip_viewport));
+		command_buffer.set_scissor(vk::Rect2D {
+			offset: vk::Offset2D { x: 0, y: 0 },
+			extent,
+		});
+		device.cmd_bind_pipeline(raw, vk::PipelineBindPoint::GRAPHICS, function.pipeline);
+		record_dynamic_state(
+			device,
+			context.extended_dynamic_state.as_ref(),
+			raw,
+			dynamic_state,
+			function.fixed_state,
+		);
+		for draw in draws {
+			draw.mark_used(recorded);
+			if let Some(descriptor_set) = draw.descriptor_set() {
+				device.cmd_bind_descriptor_sets(
+					raw,
+					vk::PipelineBindPoint::GRAPHICS,
+					function.pipeline_layout,
+					0,
+					&[raw_descriptor_set(descriptor_set)],
+					&[],
+				);
+			}
+			if !function.push_constant_stages.is_empty() {
+				device.cmd_push_constants(
+					raw,
+					function.pipeline_layout,
+					function.push_constant_stages,
+					0,
+					draw.push_constants(),
+				);
+			}
+			record(self, command_buffer, draw);
+		}
+	}
+
+	/// Dispatches `group_count` workgroups of a compute function and waits for them to finish
+	pub fn dispatch<F: ComputeFunctionPrototype>(
+		&mut self,
+		context: &Context,
+		function: &ComputeFunctionDef<F>,
+		arguments: &ComputeArgumentsContainer<F>,
+		group_count: [u32; 3],
+	) -> MarsResult<()> {
+		self.submit(context, |_this, command_buffer, recorded| {
+			unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				record_dispatch(context, raw, recorded, function, arguments, group_count);
+				// Storage buffers written by the dispatch are often read by draws next, like the
+				// parameters of indirect draws
+				sync::record_memory_barrier(
+					context,
+					raw,
+					vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+					vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+					vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+					vk::AccessFlags2KHR::MEMORY_READ,
+				);
+			}
+
+			Ok(())
+		})
+	}
+
+	/// Dispatches a compute function that writes indirect draws into `draws`, so culling and level
+	/// of detail selection can run entirely on the device. The count, if any, is zeroed on the device
+	/// first so the function can append commands with `atomicAdd`, and barriers on the commands and
+	/// count make them visible to the indirect draws recorded after.
+	pub fn dispatch_draws<F: ComputeFunctionPrototype>(
+		&mut self,
+		context: &Context,
+		function: &ComputeFunctionDef<F>,
+		arguments: &ComputeArgumentsContainer<F>,
+		group_count: [u32; 3],
+		draws: GeneratedDraws,
+	) -> MarsResult<()> {
+		self.submit(context, |_this, command_buffer, recorded| {
+			unsafe {
+				let raw = raw_command_buffer(command_buffer);
+				draws.commands.gpu_use.mark(recorded);
+				let mut buffers = vec![draws.commands.raw()];
+				buffers.extend(draws.count.map(|count| count.raw()));
+
+				// Draws recorded earlier may still be reading the last commands
+				sync::record_buffer_barrier(
+					context,
+					raw,
+					&buffers,
+					vk::PipelineStageFlags2KHR::DRAW_INDIRECT,
+					vk::AccessFlags2KHR::NONE,
+					vk::PipelineStageFlags2KHR::CLEAR | vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+					vk::AccessFlags2KHR::NONE,
+				);
+				if let Some(count) = draws.count {
+					count.gpu_use.mark(recorded);
+					raw_device(&context.device).cmd_fill_buffer(raw, count.raw(), 0, vk::WHOLE_SIZE, 0);
+					sync::record_buffer_barrier(
+						context,
+						raw,
+						&[count.raw()],
+						vk::PipelineStageFlags2KHR::CLEAR,
+						vk::AccessFlags2KHR::TRANSFER_WRITE,
+						vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+						vk::AccessFlags2KHR::SHADER_STORAGE_READ | vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+					);
+				}
+
+				record_dispatch(context, raw, recorded, function, arguments, group_count);
+				sync::record_buffer_barrier(
+					context,
+					raw,
+					&buffers,
+					vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+					vk::AccessFlags2KHR::SHADER_STORAGE_WRITE,
+					vk::PipelineStageFlags2KHR::DRAW_INDIRECT,
+					vk::AccessFlags2KHR::INDIRECT_COMMAND_READ,
+				);
+			}
+
+			Ok(())
+		})
+	}
+
+	pub(crate) fn count_draw(&mut self, index_count: Option<u32>) {
+		if let Some(stats) = &mut self.stats {
+			stats.count_draw(index_count);
+		}
+	}
+
+	/// Writes the timestamp starting a recording, if statistics are enabled
+	fn begin_stats(&mut self, command_buffer: vk::CommandBuffer) -> Option<TimedPass> {
+		let label = self.label.as_deref().unwrap_or(fault::UNLABELLED);
+		let stats = self.stats.as_mut()?;
+		unsafe { stats.begin(command_buffer, label) }
+	}
+
+	/// Writes the timestamp ending a recording begun at `start`, and counts the time it took
+	fn end_stats(&mut self, command_buffer: vk::CommandBuffer, pass: Option<TimedPass>, start: Instant) {
+		if let Some(stats) = &mut self.stats {
+			stats.add_cpu_time(start.elapsed());
+			unsafe { stats.end(command_buffer, pass) };
+		}
+	}
+
+	/// Records commands outside of any render pass, into the batch being recorded if there is one
+	pub(crate) fn record<R: FnOnce(vk::CommandBuffer)>(&mut self, context: &Context, recording: R) -> MarsResult<()> {
+		self.submit(context, |_this, command_buffer, _recorded| {
+			recording(raw_command_buffer(command_buffer));
+			Ok(())
+		})
+	}
+
+	/// Records a copy out of `staged` into the resource tracked by `gpu_use`, like `record`. The
+	/// staged data is kept until the batch has executed, or until the copy has been submitted and
+	/// has completed outside of a batch.
+	pub(crate) fn record_copy<R: FnOnce(vk::CommandBuffer)>(
+		&mut self,
+		context: &Context,
+		staged: Staged,
+		gpu_use: &GpuUse,
+		copy: R,
+	) -> MarsResult<()> {
+		self.submit(context, |_this, command_buffer, recorded| {
+			gpu_use.mark(recorded);
+			let raw = raw_command_buffer(command_buffer);
+			unsafe {
+				// Draws recorded or submitted before may still be reading the old contents, and the
+				// ones after read the new contents
+				sync::record_memory_barrier(
+					context,
+					raw,
+					vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+					vk::AccessFlags2KHR::NONE,
+					vk::PipelineStageFlags2KHR::COPY,
+					vk::AccessFlags2KHR::TRANSFER_WRITE,
+				);
+				copy(raw);
+				sync::record_memory_barrier(
+					context,
+					raw,
+					vk::PipelineStageFlags2KHR::COPY,
+					vk::AccessFlags2KHR::TRANSFER_WRITE,
+					vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+					vk::AccessFlags2KHR::MEMORY_READ,
+				);
+			}
+			Ok(())
+		})?;
+		if let Some(batch) = &mut self.batch {
+			batch.staged.push(staged);
+		}
 		Ok(())
 	}
+
+	pub(crate) fn submit<R: FnOnce(&mut Self, &mut CommandBuffer<Recording>, &RecordedUses) -> MarsResult<()>>(
+		&mut self,
+		context: &Context,
+		recording: R,
+	) -> MarsResult<()> {
+		trace_span!(
+			"RenderEngine::record",
+			label = self.label.as_deref().unwrap_or_default()
+		);
+		if let Some(mut batch) = self.batch.take() {
+			let raw = raw_command_buffer(&batch.command_buffer);
+			unsafe { context.breadcrumbs.record(context, raw, self.label.as_deref()) };
+			let pass = self.begin_stats(raw);
+			let start = Instant::now();
+			let result = recording(self, &mut batch.command_buffer, &batch.recorded);
+			self.end_stats(raw, pass, start);
+			self.batch = Some(batch);
+			return result;
+		}
+
+		// The recording gets the engine too, so the pools can't stay borrowed from it
+		let command_pools = self.command_pools.clone();
+		command_pools.with_current(|pool| {
+			let command_buffer = CommandBuffer::allocate(pool)?;
+			let mut command_buffer = command_buffer.begin()?;
+
+			let raw = raw_command_buffer(&command_buffer);
+			unsafe { context.breadcrumbs.record(context, raw, self.label.as_deref()) };
+			let pass = self.begin_stats(raw);
+			let start = Instant::now();
+			let recorded = RecordedUses::default();
+			let result = recording(self, &mut command_buffer, &recorded);
+			self.end_stats(raw, pass, start);
+			result?;
+			let command_buffer = command_buffer.end()?;
+			let submitted = sync::submit_and_wait(context, raw, &recorded);
+			if let Some(stats) = &mut self.stats {
+				stats.resolve();
+			}
+			submitted?;
+			drop(command_buffer);
+
+			Ok(())
+		})
+	}
+}
+
+/// Frees the secondary command buffers of a batch that has executed
+fn free_secondaries(device: &Device, secondaries: Vec<Secondary>) {
+	let device = raw_device(device);
+	for (pool, secondary) in secondaries {
+		let pool = pool.lock().unwrap();
+		unsafe { device.free_command_buffers(raw_command_pool(&pool), &[secondary]) };
+	}
+}
+
+/// Records binding a compute function with its arguments and dispatching it
+unsafe fn record_dispatch<F: ComputeFunctionPrototype>(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	recorded: &RecordedUses,
+	function: &ComputeFunctionDef<F>,
+	arguments: &ComputeArgumentsContainer<F>,
+	group_count: [u32; 3],
+) {
+	let device = raw_device(&context.device);
+	arguments.mark_used(recorded);
+	device.cmd_bind_pipeline(
+		command_buffer,
+		vk::PipelineBindPoint::COMPUTE,
+		function.pipeline.pipeline,
+	);
+	device.cmd_bind_descriptor_sets(
+		command_buffer,
+		vk::PipelineBindPoint::COMPUTE,
+		raw_pipeline_layout(&function.pipeline_layout),
+		0,
+		&[raw_descriptor_set(&arguments.descriptor_set)],
+		&[],
+	);
+	let [x, y, z] = group_count;
+	device.cmd_dispatch(command_buffer, x, y, z);
+}
+
+/// Records the dynamic state of the pipeline just bound, with `fixed` filling in what `state`
+/// doesn't override. Pipelines are only created with the extended dynamic state if `extended` is
+/// given.
+unsafe fn record_dynamic_state(
+	device: &rk::ash::Device,
+	extended: Option<&rk::ash::extensions::ext::ExtendedDynamicState>,
+	command_buffer: vk::CommandBuffer,
+	state: &DynamicState,
+	fixed: FixedState,
+) {
+	device.cmd_set_line_width(command_buffer, state.line_width);
+	device.cmd_set_depth_bias(
+		command_buffer,
+		state.depth_bias.constant_factor,
+		state.depth_bias.clamp,
+		state.depth_bias.slope_factor,
+	);
+	device.cmd_set_blend_constants(command_buffer, &state.blend_constants);
+	device.cmd_set_stencil_reference(
+		command_buffer,
+		vk::StencilFaceFlags::FRONT_AND_BACK,
+		state.stencil_reference.unwrap_or(fixed.stencil_reference),
+	);
+	if let Some(extended) = extended {
+		extended.cmd_set_cull_mode(command_buffer, state.cull_mode.into());
+		let compare_op = state
+			.depth_compare
+			.map_or(fixed.depth_compare_op, DepthTest::compare_op);
+		extended.cmd_set_depth_compare_op(command_buffer, compare_op);
+		// Mesh functions have no topology to set
+		if let Some(topology) = fixed.topology {
+			let topology = state.topology.map_or(topology, Into::into);
+			extended.cmd_set_primitive_topology(command_buffer, topology);
+		}
+	}
+}
+
+/// The viewport covering `extent`, flipped vertically for functions that render with Y pointing up
+fn viewport(extent: vk::Extent2D, flip: bool) -> vk::Viewport {
+	let height = extent.height as f32;
+	vk::Viewport {
+		x: 0.0,
+		y: if flip { height } else { 0.0 },
+		width: extent.width as f32,
+		height: if flip { -height } else { height },
+		min_depth: 0.0,
+		max_depth: 1.0,
+	}
+}
+
+unsafe fn begin_render_pass<G: RenderPassPrototype>(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	target: &Target<G>,
+) {
+	begin_render_pass_with_contents(context, command_buffer, target, vk::SubpassContents::INLINE)
+}
+
+unsafe fn begin_render_pass_with_contents<G: RenderPassPrototype>(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	target: &Target<G>,
+	contents: vk::SubpassContents,
+) {
+	begin_raw_render_pass(
+		context,
+		command_buffer,
+		target.render_pass.raw,
+		target.framebuffer.raw,
+		target.attachments.extent,
+		&target.attachments.load_clear_values(),
+		contents,
+	)
+}
+
+/// Begins a render pass over the whole of `framebuffer`, clearing its attachments to `clear_values`
+pub(crate) unsafe fn begin_raw_render_pass(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	render_pass: vk::RenderPass,
+	framebuffer: vk::Framebuffer,
+	extent: vk::Extent2D,
+	clear_values: &[vk::ClearValue],
+	contents: vk::SubpassContents,
+) {
+	let begin_info = vk::RenderPassBeginInfo::builder()
+		.render_pass(render_pass)
+		.framebuffer(framebuffer)
+		.render_area(vk::Rect2D {
+			offset: vk::Offset2D { x: 0, y: 0 },
+			extent,
+		})
+		.clear_values(clear_values);
+	raw_device(&context.device).cmd_begin_render_pass(command_buffer, &begin_info, contents);
+}
+
+/// The handles of a draw, which unlike `DrawArgs` can be sent to other threads
+struct RawDraw {
+	descriptor_set: Option<vk::DescriptorSet>,
+	vertices: vk::Buffer,
+	indices: vk::Buffer,
+	index_type: vk::IndexType,
+	index_count: u32,
+	/// Empty if the function has no push constants
+	push_constants: Vec<u8>,
+}
+
+/// What secondary command buffers recording part of a pass need to know about it
+#[derive(Copy, Clone)]
+struct SecondaryState {
+	render_pass: vk::RenderPass,
+	framebuffer: vk::Framebuffer,
+	extent: vk::Extent2D,
+	pipeline: vk::Pipeline,
+	pipeline_layout: vk::PipelineLayout,
+	push_constant_stages: vk::ShaderStageFlags,
+	flip_viewport: bool,
+	dynamic_state: DynamicState,
+	fixed_state: FixedState,
+}
+
+/// Records draws into a new secondary command buffer from `pool`, continuing the render pass
+unsafe fn record_secondary(
+	device: &rk::ash::Device,
+	extended: Option<&rk::ash::extensions::ext::ExtendedDynamicState>,
+	pool: vk::CommandPool,
+	state: SecondaryState,
+	draws: &[RawDraw],
+) -> MarsResult<vk::CommandBuffer> {
+	let SecondaryState {
+		render_pass,
+		framebuffer,
+		extent,
+		pipeline,
+		pipeline_layout,
+		push_constant_stages,
+		flip_viewport,
+		dynamic_state,
+		fixed_state,
+	} = state;
+	let allocate_info = vk::CommandBufferAllocateInfo::builder()
+		.command_pool(pool)
+		.level(vk::CommandBufferLevel::SECONDARY)
+		.command_buffer_count(1);
+	let command_buffer = device.allocate_command_buffers(&allocate_info)?[0];
+	let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+		.render_pass(render_pass)
+		.subpass(0)
+		.framebuffer(framebuffer);
+	let begin_info = vk::CommandBufferBeginInfo::builder()
+		.flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+		.inheritance_info(&inheritance_info);
+	let result = (|| {
+		device.begin_command_buffer(command_buffer, &begin_info)?;
+		device.cmd_set_viewport(command_buffer, 0, &[viewport(extent, flip_viewport)]);
+		device.cmd_set_scissor(
+			command_buffer,
+			0,
+			&[vk::Rect2D {
+				offset: vk::Offset2D { x: 0, y: 0 },
+				extent,
+			}],
+		);
+		device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+		record_dynamic_state(device, extended, command_buffer, &dynamic_state, fixed_state);
+		for draw in draws {
+			if let Some(descriptor_set) = draw.descriptor_set {
+				device.cmd_bind_descriptor_sets(
+					command_buffer,
+					vk::PipelineBindPoint::GRAPHICS,
+					pipeline_layout,
+					0,
+					&[descriptor_set],
+					&[],
+				);
+			}
+			if !draw.push_constants.is_empty() {
+				device.cmd_push_constants(
+					command_buffer,
+					pipeline_layout,
+					push_constant_stages,
+					0,
+					&draw.push_constants,
+				);
+			}
+			device.cmd_bind_vertex_buffers(command_buffer, 0, &[draw.vertices], &[0]);
+			device.cmd_bind_index_buffer(command_buffer, draw.indices, 0, draw.index_type);
+			device.cmd_draw_indexed(command_buffer, draw.index_count, 1, 0, 0, 0);
+		}
+		device.end_command_buffer(command_buffer)
+	})();
+	match result {
+		Ok(()) => Ok(command_buffer),
+		Err(e) => {
+			device.free_command_buffers(pool, &[command_buffer]);
+			Err(e)
+		}
+	}
 }
 
-pub struct DrawArgs<'a, F: FunctionPrototype> {
+pub struct DrawArgs<'a, F: FunctionPrototype, X: IndexType = u32> {
 	pub bindings: &'a ArgumentsContainer<F>,
 	pub vertices: &'a Buffer<VertexBufferUsage, [F::VertexInput]>,
-	pub indices: &'a Buffer<IndexBufferUsage, [u32]>,
+	pub indices: &'a Buffer<IndexBufferUsage, [X]>,
+	/// The function's push constants for this draw
+	pub push_constants: F::PushConstants,
 }
 
-impl<'a, F>
+impl<'a, F, X>
 	From<(
 		&'a ArgumentsContainer<F>,
 		&'a Buffer<VertexBufferUsage, [F::VertexInput]>,
-		&'a Buffer<IndexBufferUsage, [u32]>,
-	)> for DrawArgs<'a, F>
+		&'a Buffer<IndexBufferUsage, [X]>,
+	)> for DrawArgs<'a, F, X>
 where
 	F: FunctionPrototype,
+	X: IndexType,
+	F::PushConstants: Default,
 {
 	fn from(
 		t: (
 			&'a ArgumentsContainer<F>,
 			&'a Buffer<VertexBufferUsage, [F::VertexInput]>,
-			&'a Buffer<IndexBufferUsage, [u32]>,
+			&'a Buffer<IndexBufferUsage, [X]>,
 		),
 	) -> Self {
 		Self {
 			bindings: t.0,
 			vertices: t.1,
 			indices: t.2,
+			push_constants: Default::default(),
 		}
 	}
 }
 
-impl<'a, F> Clone for DrawArgs<'a, F> where F: FunctionPrototype {
+impl<'a, F, X>
+	From<(
+		&'a ArgumentsContainer<F>,
+		&'a Buffer<VertexBufferUsage, [F::VertexInput]>,
+		&'a Buffer<IndexBufferUsage, [X]>,
+		F::PushConstants,
+	)> for DrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn from(
+		t: (
+			&'a ArgumentsContainer<F>,
+			&'a Buffer<VertexBufferUsage, [F::VertexInput]>,
+			&'a Buffer<IndexBufferUsage, [X]>,
+			F::PushConstants,
+		),
+	) -> Self {
+		Self {
+			bindings: t.0,
+			vertices: t.1,
+			indices: t.2,
+			push_constants: t.3,
+		}
+	}
+}
+
+impl<'a, F, X> Clone for DrawArgs<'a, F, X> where F: FunctionPrototype, X: IndexType {
 	fn clone(&self) -> Self {
 		Self {
 			bindings: self.bindings,
 			vertices: self.vertices,
 			indices: self.indices,
+			push_constants: self.push_constants,
+		}
+	}
+}
+
+impl<'a, F, X> Copy for DrawArgs<'a, F, X> where F: FunctionPrototype, X: IndexType { }
+
+impl<'a, F, X> PassDraw for DrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn mark_used(&self, recorded: &RecordedUses) {
+		self.bindings.mark_used(recorded);
+		self.vertices.gpu_use.mark(recorded);
+		self.indices.gpu_use.mark(recorded);
+	}
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet> {
+		self.bindings.descriptor_set.as_ref()
+	}
+
+	fn push_constants(&self) -> &[u8] {
+		push_constant_bytes(&self.push_constants)
+	}
+}
+
+/// What a pass needs to know about the graphics or mesh function it draws with
+#[derive(Copy, Clone)]
+pub(crate) struct PassFunction {
+	pipeline: vk::Pipeline,
+	pipeline_layout: vk::PipelineLayout,
+	/// The stages reading the push constants, empty if the function has none
+	push_constant_stages: vk::ShaderStageFlags,
+	flip_viewport: bool,
+	fixed_state: FixedState,
+}
+
+impl<F: FunctionPrototype> From<&FunctionDef<F>> for PassFunction {
+	fn from(function: &FunctionDef<F>) -> Self {
+		Self {
+			pipeline: function.pipeline.pipeline,
+			pipeline_layout: function.pipeline_layout.layout,
+			push_constant_stages: function.push_constant_stages,
+			flip_viewport: function.flip_viewport,
+			fixed_state: function.fixed_state,
 		}
 	}
 }
 
-impl<'a, F> Copy for DrawArgs<'a, F> where F: FunctionPrototype { }
+impl<F: MeshFunctionPrototype> From<&MeshFunctionDef<F>> for PassFunction {
+	fn from(function: &MeshFunctionDef<F>) -> Self {
+		Self {
+			pipeline: function.pipeline.pipeline,
+			pipeline_layout: function.pipeline_layout.layout,
+			push_constant_stages: function.push_constant_stages,
+			flip_viewport: function.flip_viewport,
+			fixed_state: function.fixed_state,
+		}
+	}
+}
+
+/// A draw of a pass, whose arguments are bound by `RenderEngine::record_subpass` before the draw is
+/// recorded
+pub(crate) trait PassDraw {
+	/// Marks the buffers the draw uses as used by the submission being recorded
+	fn mark_used(&self, recorded: &RecordedUses);
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet>;
+
+	/// The bytes of the draw's push constants, empty if the function has none
+	fn push_constants(&self) -> &[u8];
+}
+
+/// Records binding the vertex and index buffers of a draw
+unsafe fn record_bind_buffers<V, X: IndexType>(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	vertices: &Buffer<VertexBufferUsage, [V]>,
+	indices: &Buffer<IndexBufferUsage, [X]>,
+) {
+	let device = raw_device(&context.device);
+	device.cmd_bind_vertex_buffers(command_buffer, 0, &[vertices.raw()], &[0]);
+	device.cmd_bind_index_buffer(command_buffer, indices.raw(), 0, X::as_raw());
+}
+
+/// Records an indexed draw of every index of `draw`, for `RenderEngine::record_subpass`
+pub(crate) fn record_indexed_draw<F: FunctionPrototype, X: IndexType>(
+	context: &Context,
+	engine: &mut RenderEngine,
+	command_buffer: &mut CommandBuffer<Recording>,
+	draw: &DrawArgs<F, X>,
+) {
+	unsafe {
+		record_bind_buffers(context, raw_command_buffer(command_buffer), draw.vertices, draw.indices);
+		command_buffer.draw_indexed(draw.indices.len as u32, 1, 0, 0, 0);
+	}
+	engine.count_draw(Some(draw.indices.len as u32));
+}
+
+pub struct IndirectDrawArgs<'a, F: FunctionPrototype, X: IndexType = u32> {
+	pub bindings: &'a ArgumentsContainer<F>,
+	pub vertices: &'a Buffer<VertexBufferUsage, [F::VertexInput]>,
+	pub indices: &'a Buffer<IndexBufferUsage, [X]>,
+	pub commands: &'a Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]>,
+	/// The function's push constants for every draw read from `commands`
+	pub push_constants: F::PushConstants,
+}
+
+impl<'a, F, X> Clone for IndirectDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn clone(&self) -> Self {
+		Self {
+			bindings: self.bindings,
+			vertices: self.vertices,
+			indices: self.indices,
+			commands: self.commands,
+			push_constants: self.push_constants,
+		}
+	}
+}
+
+impl<'a, F, X> Copy for IndirectDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+}
+
+impl<'a, F, X> PassDraw for IndirectDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn mark_used(&self, recorded: &RecordedUses) {
+		self.bindings.mark_used(recorded);
+		self.vertices.gpu_use.mark(recorded);
+		self.indices.gpu_use.mark(recorded);
+		self.commands.gpu_use.mark(recorded);
+	}
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet> {
+		self.bindings.descriptor_set.as_ref()
+	}
+
+	fn push_constants(&self) -> &[u8] {
+		push_constant_bytes(&self.push_constants)
+	}
+}
+
+/// The indirect draw buffers a compute function writes in `RenderEngine::dispatch_draws`, bound to
+/// it as `IndirectStorage`
+#[derive(Copy, Clone)]
+pub struct GeneratedDraws<'a> {
+	pub commands: &'a Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]>,
+	/// The number of commands written, for `RenderEngine::pass_indirect_count`
+	pub count: Option<&'a Buffer<IndirectBufferUsage, u32>>,
+}
+
+pub struct IndirectCountDrawArgs<'a, F: FunctionPrototype, X: IndexType = u32> {
+	pub bindings: &'a ArgumentsContainer<F>,
+	pub vertices: &'a Buffer<VertexBufferUsage, [F::VertexInput]>,
+	pub indices: &'a Buffer<IndexBufferUsage, [X]>,
+	pub commands: &'a Buffer<IndirectBufferUsage, [vk::DrawIndexedIndirectCommand]>,
+	/// The number of commands to draw from the start of `commands`
+	pub count: &'a Buffer<IndirectBufferUsage, u32>,
+	/// The function's push constants for every draw read from `commands`
+	pub push_constants: F::PushConstants,
+}
+
+impl<'a, F, X> Clone for IndirectCountDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn clone(&self) -> Self {
+		Self {
+			bindings: self.bindings,
+			vertices: self.vertices,
+			indices: self.indices,
+			commands: self.commands,
+			count: self.count,
+			push_constants: self.push_constants,
+		}
+	}
+}
+
+impl<'a, F, X> Copy for IndirectCountDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+}
+
+impl<'a, F, X> PassDraw for IndirectCountDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn mark_used(&self, recorded: &RecordedUses) {
+		self.bindings.mark_used(recorded);
+		self.vertices.gpu_use.mark(recorded);
+		self.indices.gpu_use.mark(recorded);
+		self.commands.gpu_use.mark(recorded);
+		self.count.gpu_use.mark(recorded);
+	}
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet> {
+		self.bindings.descriptor_set.as_ref()
+	}
+
+	fn push_constants(&self) -> &[u8] {
+		push_constant_bytes(&self.push_constants)
+	}
+}
+
+pub struct ConditionalDrawArgs<'a, F: FunctionPrototype, X: IndexType = u32> {
+	pub bindings: &'a ArgumentsContainer<F>,
+	pub vertices: &'a Buffer<VertexBufferUsage, [F::VertexInput]>,
+	pub indices: &'a Buffer<IndexBufferUsage, [X]>,
+	/// The value deciding whether the draw happens
+	pub condition: &'a Buffer<ConditionBufferUsage, u32>,
+	/// Draw only if the condition is zero instead
+	pub inverted: bool,
+	/// The function's push constants for this draw
+	pub push_constants: F::PushConstants,
+}
+
+impl<'a, F, X> Clone for ConditionalDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn clone(&self) -> Self {
+		Self {
+			bindings: self.bindings,
+			vertices: self.vertices,
+			indices: self.indices,
+			condition: self.condition,
+			inverted: self.inverted,
+			push_constants: self.push_constants,
+		}
+	}
+}
+
+impl<'a, F, X> Copy for ConditionalDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+}
+
+impl<'a, F, X> PassDraw for ConditionalDrawArgs<'a, F, X>
+where
+	F: FunctionPrototype,
+	X: IndexType,
+{
+	fn mark_used(&self, recorded: &RecordedUses) {
+		self.bindings.mark_used(recorded);
+		self.vertices.gpu_use.mark(recorded);
+		self.indices.gpu_use.mark(recorded);
+		self.condition.gpu_use.mark(recorded);
+	}
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet> {
+		self.bindings.descriptor_set.as_ref()
+	}
+
+	fn push_constants(&self) -> &[u8] {
+		push_constant_bytes(&self.push_constants)
+	}
+}
+
+pub struct MeshDrawArgs<'a, F: MeshFunctionPrototype> {
+	pub bindings: &'a MeshArgumentsContainer<F>,
+	/// The number of workgroups to dispatch in each dimension
+	pub group_count: [u32; 3],
+	/// The function's push constants for this draw
+	pub push_constants: F::PushConstants,
+}
+
+impl<'a, F> From<(&'a MeshArgumentsContainer<F>, [u32; 3])> for MeshDrawArgs<'a, F>
+where
+	F: MeshFunctionPrototype<PushConstants = ()>,
+{
+	fn from(t: (&'a MeshArgumentsContainer<F>, [u32; 3])) -> Self {
+		Self {
+			bindings: t.0,
+			group_count: t.1,
+			push_constants: (),
+		}
+	}
+}
+
+impl<'a, F> PassDraw for MeshDrawArgs<'a, F>
+where
+	F: MeshFunctionPrototype,
+{
+	fn mark_used(&self, recorded: &RecordedUses) {
+		self.bindings.mark_used(recorded);
+	}
+
+	fn descriptor_set(&self) -> Option<&DescriptorSet> {
+		self.bindings.descriptor_set.as_ref()
+	}
+
+	fn push_constants(&self) -> &[u8] {
+		push_constant_bytes(&self.push_constants)
+	}
+}
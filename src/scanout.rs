@@ -0,0 +1,112 @@
+//! Rendering straight into buffers a display controller can scan out, for compositor-less Linux
+//! devices that drive the display through KMS themselves.
+//!
+//! A `ScanoutImage` is an ordinary image whose memory is exported as a DMA-BUF and laid out
+//! linearly, so it can be handed to KMS without copying: its file descriptor, stride, offset and
+//! DRM format are everything `drmModeAddFB2` needs to wrap it in a framebuffer, which can then be
+//! flipped to a CRTC by whatever is managing modesetting. Rendering into the image and presenting
+//! it are then separated only by `ScanoutImage::prepare_for_scanout`.
+//!
+//! Scanout images require the `ExternalMemoryDmaBuf` device extension.
+
+use std::{
+	fs::File,
+	os::unix::io::{AsRawFd, FromRawFd, RawFd},
+};
+
+use rk::vk;
+
+use crate::{
+	device::DeviceExtension,
+	image::{FormatType, Image, ImageUsageType, SampleCount1},
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+const HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags = vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT;
+
+/// An image whose memory is shared as a DMA-BUF for scanout
+pub struct ScanoutImage<U: ImageUsageType, F: FormatType> {
+	pub image: Image<U, F, SampleCount1>,
+	/// The exported DMA-BUF, closed when the image is dropped
+	dma_buf: File,
+	layout: vk::SubresourceLayout,
+}
+
+impl<U: ImageUsageType, F: FormatType> ScanoutImage<U, F> {
+	/// Creates an image with `usage` and exports its memory. Fails with
+	/// `ERROR_EXTENSION_NOT_PRESENT` unless the `ExternalMemoryDmaBuf` extension is enabled.
+	pub fn create(context: &Context, usage: U, extent: vk::Extent2D) -> MarsResult<Self> {
+		if !context.has_extension(DeviceExtension::ExternalMemoryDmaBuf) {
+			return Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT);
+		}
+		unsafe {
+			let image = Image::create_exportable(context, usage.as_dyn(), extent, HANDLE_TYPE)?;
+			let fd = image.export_memory_fd(context, HANDLE_TYPE)?;
+			let layout = image.subresource_layout(F::aspect());
+			Ok(Self {
+				image,
+				dma_buf: File::from_raw_fd(fd),
+				layout,
+			})
+		}
+	}
+
+	/// The file descriptor of the DMA-BUF, which stays owned by this image. Duplicate it to keep it
+	/// open for longer.
+	pub fn fd(&self) -> RawFd {
+		self.dma_buf.as_raw_fd()
+	}
+
+	/// The offset of the first texel in the DMA-BUF, in bytes
+	pub fn offset(&self) -> u32 {
+		self.layout.offset as u32
+	}
+
+	/// The distance between the starts of consecutive rows, in bytes
+	pub fn stride(&self) -> u32 {
+		self.layout.row_pitch as u32
+	}
+
+	/// The size of the DMA-BUF in bytes
+	pub fn size(&self) -> u64 {
+		self.layout.size
+	}
+
+	/// The DRM fourcc code of the image's format, or `None` if it has no DRM equivalent
+	pub fn drm_format(&self) -> Option<u32> {
+		drm_format(F::as_raw())
+	}
+
+	/// Waits for the rendering recorded into the image so far to be visible outside of Vulkan, and
+	/// moves it into the layout external users expect. Call this after rendering and before the
+	/// image is flipped to the display.
+	pub fn prepare_for_scanout(&mut self, context: &Context) -> MarsResult<()> {
+		let transition = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::ALL_COMMANDS,
+			src_access_mask: vk::AccessFlags2KHR::MEMORY_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::MEMORY_READ,
+			old_layout: self.image.layout,
+			new_layout: vk::ImageLayout::GENERAL,
+		};
+		self.image.transition(context, &transition)
+	}
+}
+
+/// Maps a Vulkan format to the DRM fourcc code with the same memory layout. DRM codes name their
+/// channels from the most significant bit of a little-endian word, so they read backwards compared
+/// to Vulkan's byte-ordered names.
+pub fn drm_format(format: vk::Format) -> Option<u32> {
+	let fourcc = |code: &[u8; 4]| u32::from_le_bytes(*code);
+	match format {
+		vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Some(fourcc(b"AR24")),
+		vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Some(fourcc(b"AB24")),
+		vk::Format::A2R10G10B10_UNORM_PACK32 => Some(fourcc(b"AR30")),
+		vk::Format::A2B10G10R10_UNORM_PACK32 => Some(fourcc(b"AB30")),
+		vk::Format::R5G6B5_UNORM_PACK16 => Some(fourcc(b"RG16")),
+		vk::Format::R16G16B16A16_SFLOAT => Some(fourcc(b"AB4H")),
+		_ => None,
+	}
+}
@@ -0,0 +1,329 @@
+//! Compiling shaders to SPIR-V at runtime.
+//!
+//! GLSL is compiled with shaderc by default. Enabling the `naga` feature compiles it with naga
+//! instead, which is pure Rust and doesn't need a C++ toolchain to build, though its GLSL frontend
+//! supports fewer extensions than glslang.
+//!
+//! The `naga` feature also allows compiling WGSL, so shaders can be shared with wgpu-based tools.
+//!
+//! GLSL can `#include` other files through an `IncludeResolver`, which looks them up either on
+//! disk with `FileIncludes` or in memory with `VirtualIncludes`.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[cfg(not(any(feature = "shaderc", feature = "naga")))]
+compile_error!("either the `shaderc` or the `naga` feature has to be enabled to compile shaders");
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShaderStage {
+	Vertex,
+	Fragment,
+	Compute,
+	Task,
+	Mesh,
+	Geometry,
+}
+
+#[derive(Debug, Error)]
+pub enum ShaderError {
+	#[error("Failed to compile {filename}: {message}")]
+	Compilation { filename: String, message: String },
+	#[error("The {0:?} stage isn't supported by the shader compiler backend")]
+	UnsupportedStage(ShaderStage),
+	#[error("{filename} has no {stage:?} entry point named {entry_point}")]
+	MissingEntryPoint {
+		filename: String,
+		entry_point: String,
+		stage: ShaderStage,
+	},
+}
+
+/// A file included by a shader
+pub struct Include {
+	/// The name the included file is known by, which its own includes are resolved relative to
+	pub name: String,
+	pub source: String,
+}
+
+/// Looks up the files shaders `#include`
+pub trait IncludeResolver {
+	/// Finds the file `requested` by an include directive in the file named `requesting`
+	fn resolve(&self, requested: &str, requesting: &str) -> Option<Include>;
+}
+
+/// Resolves includes from the filesystem, first relative to the including file and then relative
+/// to each of the include directories in order
+#[derive(Debug, Clone, Default)]
+pub struct FileIncludes {
+	pub directories: Vec<PathBuf>,
+}
+
+impl FileIncludes {
+	pub fn new(directories: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+		Self {
+			directories: directories.into_iter().map(Into::into).collect(),
+		}
+	}
+}
+
+impl IncludeResolver for FileIncludes {
+	fn resolve(&self, requested: &str, requesting: &str) -> Option<Include> {
+		let relative = Path::new(requesting).parent().map(|parent| parent.join(requested));
+		relative
+			.into_iter()
+			.chain(self.directories.iter().map(|directory| directory.join(requested)))
+			.find_map(|path| {
+				let source = std::fs::read_to_string(&path).ok()?;
+				Some(Include {
+					name: path.to_string_lossy().into_owned(),
+					source,
+				})
+			})
+	}
+}
+
+/// Resolves includes from sources kept in memory, keyed by the exact name they're included by
+#[derive(Debug, Clone, Default)]
+pub struct VirtualIncludes {
+	files: HashMap<String, String>,
+}
+
+impl VirtualIncludes {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn insert(&mut self, name: impl Into<String>, source: impl Into<String>) -> &mut Self {
+		self.files.insert(name.into(), source.into());
+		self
+	}
+}
+
+impl IncludeResolver for VirtualIncludes {
+	fn resolve(&self, requested: &str, _requesting: &str) -> Option<Include> {
+		self.files.get(requested).map(|source| Include {
+			name: requested.to_owned(),
+			source: source.clone(),
+		})
+	}
+}
+
+#[derive(Default)]
+pub struct CompileOptions<'a> {
+	/// Macros defined before the source, as names and values
+	pub defines: Vec<(String, String)>,
+	/// Resolves `#include` directives, which are errors without one
+	pub includes: Option<&'a dyn IncludeResolver>,
+}
+
+impl<'a> CompileOptions<'a> {
+	pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.defines.push((name.into(), value.into()));
+		self
+	}
+
+	pub fn includes(mut self, includes: &'a dyn IncludeResolver) -> Self {
+		self.includes = Some(includes);
+		self
+	}
+}
+
+/// Compiles GLSL source for `stage` into SPIR-V. `filename` is only used in error messages and to
+/// resolve includes relative to.
+pub fn compile_glsl(source: &str, filename: &str, stage: ShaderStage) -> Result<Vec<u32>, ShaderError> {
+	compile_glsl_with_options(source, filename, stage, &CompileOptions::default())
+}
+
+pub fn compile_glsl_with_options(
+	source: &str,
+	filename: &str,
+	stage: ShaderStage,
+	options: &CompileOptions,
+) -> Result<Vec<u32>, ShaderError> {
+	backend::compile_glsl(source, filename, stage, options)
+}
+
+/// Compiles the entry point named `entry_point` of WGSL source into SPIR-V. The entry point is
+/// renamed to `main` like mars expects, and the other entry points are left out.
+#[cfg(feature = "naga")]
+pub fn compile_wgsl(
+	source: &str,
+	filename: &str,
+	stage: ShaderStage,
+	entry_point: &str,
+) -> Result<Vec<u32>, ShaderError> {
+	backend::compile_wgsl(source, filename, stage, entry_point)
+}
+
+#[cfg(not(feature = "naga"))]
+mod backend {
+	use super::{CompileOptions, ShaderError, ShaderStage};
+
+	pub(super) fn compile_glsl(
+		source: &str,
+		filename: &str,
+		stage: ShaderStage,
+		options: &CompileOptions,
+	) -> Result<Vec<u32>, ShaderError> {
+		let kind = match stage {
+			ShaderStage::Vertex => shaderc::ShaderKind::Vertex,
+			ShaderStage::Fragment => shaderc::ShaderKind::Fragment,
+			ShaderStage::Compute => shaderc::ShaderKind::Compute,
+			ShaderStage::Task => shaderc::ShaderKind::Task,
+			ShaderStage::Mesh => shaderc::ShaderKind::Mesh,
+			ShaderStage::Geometry => shaderc::ShaderKind::Geometry,
+		};
+		let mut compiler = shaderc::Compiler::new().expect("Failed to initialize compiler");
+		let mut compile_options = shaderc::CompileOptions::new().expect("Failed to initialize compiler options");
+		for (name, value) in &options.defines {
+			compile_options.add_macro_definition(name, Some(value.as_str()));
+		}
+		if let Some(includes) = options.includes {
+			compile_options.set_include_callback(move |requested, _include_type, requesting, _depth| {
+				includes
+					.resolve(requested, requesting)
+					.map(|include| shaderc::ResolvedInclude {
+						resolved_name: include.name,
+						content: include.source,
+					})
+					.ok_or_else(|| format!("Couldn't resolve include {}", requested))
+			});
+		}
+		let artifact = compiler
+			.compile_into_spirv(source, kind, filename, "main", Some(&compile_options))
+			.map_err(|e| ShaderError::Compilation {
+				filename: filename.to_owned(),
+				message: e.to_string(),
+			})?;
+		Ok(artifact.as_binary().to_owned())
+	}
+}
+
+#[cfg(feature = "naga")]
+mod backend {
+	use naga::{
+		back::spv,
+		front::{glsl, wgsl},
+		valid::{Capabilities, ValidationFlags, Validator},
+		Module,
+	};
+
+	use super::{CompileOptions, IncludeResolver, ShaderError, ShaderStage};
+
+	/// How deeply includes can nest before they're assumed to be recursive
+	const MAX_INCLUDE_DEPTH: usize = 32;
+
+	pub(super) fn compile_glsl(
+		source: &str,
+		filename: &str,
+		stage: ShaderStage,
+		options: &CompileOptions,
+	) -> Result<Vec<u32>, ShaderError> {
+		let stage = match stage {
+			ShaderStage::Vertex => naga::ShaderStage::Vertex,
+			ShaderStage::Fragment => naga::ShaderStage::Fragment,
+			ShaderStage::Compute => naga::ShaderStage::Compute,
+			ShaderStage::Task | ShaderStage::Mesh | ShaderStage::Geometry => {
+				return Err(ShaderError::UnsupportedStage(stage))
+			}
+		};
+		// naga's preprocessor handles defines but not includes, so they're expanded beforehand
+		let source = expand_includes(source, filename, options.includes, 0)?;
+		let mut glsl_options = glsl::Options::from(stage);
+		glsl_options.defines.extend(options.defines.iter().cloned());
+		let module = glsl::Parser::default()
+			.parse(&glsl_options, &source)
+			.map_err(|errors| ShaderError::Compilation {
+				filename: filename.to_owned(),
+				message: errors.iter().map(|e| e.kind.to_string()).collect::<Vec<_>>().join("\n"),
+			})?;
+		write_spirv(&module, filename)
+	}
+
+	pub(super) fn compile_wgsl(
+		source: &str,
+		filename: &str,
+		stage: ShaderStage,
+		entry_point: &str,
+	) -> Result<Vec<u32>, ShaderError> {
+		let naga_stage = match stage {
+			ShaderStage::Vertex => naga::ShaderStage::Vertex,
+			ShaderStage::Fragment => naga::ShaderStage::Fragment,
+			ShaderStage::Compute => naga::ShaderStage::Compute,
+			ShaderStage::Task | ShaderStage::Mesh | ShaderStage::Geometry => {
+				return Err(ShaderError::UnsupportedStage(stage))
+			}
+		};
+		let mut module = wgsl::parse_str(source).map_err(|e| ShaderError::Compilation {
+			filename: filename.to_owned(),
+			message: e.emit_to_string(source),
+		})?;
+		module
+			.entry_points
+			.retain(|e| e.name == entry_point && e.stage == naga_stage);
+		match module.entry_points.first_mut() {
+			Some(entry) => entry.name = "main".to_owned(),
+			None => {
+				return Err(ShaderError::MissingEntryPoint {
+					filename: filename.to_owned(),
+					entry_point: entry_point.to_owned(),
+					stage,
+				})
+			}
+		}
+		write_spirv(&module, filename)
+	}
+
+	/// Replaces every `#include "file"` or `#include <file>` line with the contents of the file
+	fn expand_includes(
+		source: &str,
+		filename: &str,
+		includes: Option<&dyn IncludeResolver>,
+		depth: usize,
+	) -> Result<String, ShaderError> {
+		let error = |message: String| ShaderError::Compilation {
+			filename: filename.to_owned(),
+			message,
+		};
+		let mut expanded = String::with_capacity(source.len());
+		for line in source.lines() {
+			let directive = line.trim_start();
+			if directive.starts_with("#extension") && directive.contains("GL_GOOGLE_include_directive") {
+				continue;
+			}
+			if let Some(requested) = directive.strip_prefix("#include") {
+				let requested = requested.trim().trim_matches(|c| c == '"' || c == '<' || c == '>');
+				if depth >= MAX_INCLUDE_DEPTH {
+					return Err(error(format!("Includes nested too deeply at {}", requested)));
+				}
+				let include = includes
+					.and_then(|includes| includes.resolve(requested, filename))
+					.ok_or_else(|| error(format!("Couldn't resolve include {}", requested)))?;
+				expanded.push_str(&expand_includes(&include.source, &include.name, includes, depth + 1)?);
+			} else {
+				expanded.push_str(line);
+			}
+			expanded.push('\n');
+		}
+		Ok(expanded)
+	}
+
+	fn write_spirv(module: &Module, filename: &str) -> Result<Vec<u32>, ShaderError> {
+		let error = |message: String| ShaderError::Compilation {
+			filename: filename.to_owned(),
+			message,
+		};
+		let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+			.validate(module)
+			.map_err(|e| error(e.to_string()))?;
+		// The sources are written for Vulkan's coordinate space already
+		let mut options = spv::Options::default();
+		options.flags.remove(spv::WriterFlags::ADJUST_COORDINATE_SPACE);
+		spv::write_vec(module, &info, &options, None).map_err(|e| error(e.to_string()))
+	}
+}
@@ -0,0 +1,313 @@
+//! Generators for common primitive meshes.
+//!
+//! Every shape is centered on the origin with +Y up, uses the `Vertex` layout, and winds its
+//! triangles counter-clockwise when seen from outside.
+
+use std::f32::consts::PI;
+
+use crate::{
+	buffer::{Buffer, IndexBufferUsage, VertexBufferUsage},
+	function::{AttributeDesc, AttributeFormat, Parameter},
+	math::*,
+	Context, MarsResult,
+};
+
+/// A vertex with a position, a normal, and texture coordinates, matching a vertex shader with
+/// `vec3`, `vec3` and `vec2` inputs at locations 0, 1 and 2
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct Vertex {
+	pub position: Vec3,
+	pub normal: Vec3,
+	pub uv: Vec2,
+}
+
+impl Vertex {
+	pub fn new(position: Vec3, normal: Vec3, uv: Vec2) -> Self {
+		Self { position, normal, uv }
+	}
+}
+
+unsafe impl Parameter for Vertex {
+	fn attributes() -> Vec<AttributeDesc> {
+		vec![
+			AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec3F,
+				offset: None,
+			},
+			AttributeDesc {
+				format: AttributeFormat::Vec2F,
+				offset: None,
+			},
+		]
+	}
+}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Vertex {}
+
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for Vertex {}
+
+/// The vertices and triangle list indices of a mesh
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+	pub vertices: Vec<Vertex>,
+	pub indices: Vec<u32>,
+}
+
+impl MeshData {
+	/// Uploads the mesh into a vertex buffer and an index buffer
+	pub fn buffers(
+		&self,
+		context: &Context,
+	) -> MarsResult<(Buffer<VertexBufferUsage, [Vertex]>, Buffer<IndexBufferUsage, [u32]>)> {
+		let vertices = Buffer::make_array_buffer(context, &self.vertices)?;
+		let indices = Buffer::make_array_buffer(context, &self.indices)?;
+		Ok((vertices, indices))
+	}
+
+	// Adds a quad with corners `a`, `b`, `c` and `d` in counter-clockwise order
+	fn push_quad(&mut self, a: u32, b: u32, c: u32, d: u32) {
+		self.indices.extend_from_slice(&[a, b, c, c, d, a]);
+	}
+}
+
+/// A cube with sides of length `size`, with separate vertices for each face so that its edges are
+/// sharp
+pub fn cube(size: f32) -> MeshData {
+	let h = size / 2.0;
+	// The normal of each face, and two axes along the face with `u.cross(v) == normal`
+	let faces = [
+		(Vec3::x(), -Vec3::z(), Vec3::y()),
+		(-Vec3::x(), Vec3::z(), Vec3::y()),
+		(Vec3::y(), Vec3::x(), -Vec3::z()),
+		(-Vec3::y(), Vec3::x(), Vec3::z()),
+		(Vec3::z(), Vec3::x(), Vec3::y()),
+		(-Vec3::z(), -Vec3::x(), Vec3::y()),
+	];
+	let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+
+	let mut mesh = MeshData::default();
+	for (normal, u, v) in faces.iter() {
+		let first = mesh.vertices.len() as u32;
+		for (x, y) in corners.iter() {
+			mesh.vertices.push(Vertex::new(
+				(normal + u * *x + v * *y) * h,
+				*normal,
+				Vec2::new((x + 1.0) / 2.0, (1.0 - y) / 2.0),
+			));
+		}
+		mesh.push_quad(first, first + 1, first + 2, first + 3);
+	}
+	mesh
+}
+
+/// A plane in the XZ plane facing +Y, split into `subdivisions` quads along each side
+pub fn plane(width: f32, depth: f32, subdivisions: u32) -> MeshData {
+	assert!(subdivisions > 0);
+	let n = subdivisions;
+
+	let mut mesh = MeshData::default();
+	for j in 0..=n {
+		for i in 0..=n {
+			let (u, v) = (i as f32 / n as f32, j as f32 / n as f32);
+			mesh.vertices.push(Vertex::new(
+				Vec3::new((u - 0.5) * width, 0.0, (v - 0.5) * depth),
+				Vec3::y(),
+				Vec2::new(u, v),
+			));
+		}
+	}
+	for j in 0..n {
+		for i in 0..n {
+			let a = j * (n + 1) + i;
+			let b = a + n + 1;
+			mesh.push_quad(a, b, b + 1, a + 1);
+		}
+	}
+	mesh
+}
+
+/// A sphere made of `rings` rings of latitude, each split into `segments` quads
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> MeshData {
+	assert!(segments >= 3 && rings >= 2);
+	let rows = (0..=rings).map(|r| {
+		let t = r as f32 / rings as f32;
+		(t * PI, 0.0, t)
+	});
+	revolve(radius, segments, rows)
+}
+
+/// A capsule, a cylinder of height `height` capped with hemispheres of radius `radius`, along the Y
+/// axis. Each hemisphere has `rings` rings of latitude.
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> MeshData {
+	assert!(segments >= 3 && rings >= 1);
+	let total = height + 2.0 * radius;
+	let row = move |phi: f32, offset: f32| {
+		let y = radius * phi.cos() + offset;
+		(phi, offset, (total / 2.0 - y) / total)
+	};
+	let top = (0..=rings).map(move |r| row(r as f32 / rings as f32 * PI / 2.0, height / 2.0));
+	let bottom = (0..=rings).map(move |r| row(PI / 2.0 + r as f32 / rings as f32 * PI / 2.0, -height / 2.0));
+	revolve(radius, segments, top.chain(bottom))
+}
+
+/// Revolves rows of a sphere of radius `radius` around the Y axis. Each row is given as its angle
+/// from +Y, the height it's offset by, and its texture coordinate along the axis. The first and
+/// last rows are expected to be the poles.
+fn revolve(radius: f32, segments: u32, rows: impl Iterator<Item = (f32, f32, f32)>) -> MeshData {
+	let mut mesh = MeshData::default();
+	let mut row_count = 0;
+	for (phi, offset, v) in rows {
+		for s in 0..=segments {
+			let u = s as f32 / segments as f32;
+			let theta = u * 2.0 * PI;
+			let normal = Vec3::new(phi.sin() * theta.sin(), phi.cos(), phi.sin() * theta.cos());
+			mesh.vertices.push(Vertex::new(
+				normal * radius + Vec3::new(0.0, offset, 0.0),
+				normal,
+				Vec2::new(u, v),
+			));
+		}
+		row_count += 1;
+	}
+	for r in 0..row_count - 1 {
+		for s in 0..segments {
+			let a = r * (segments + 1) + s;
+			let b = a + segments + 1;
+			// The triangles touching the poles would have no area
+			if r != row_count - 2 {
+				mesh.indices.extend_from_slice(&[a, b, b + 1]);
+			}
+			if r != 0 {
+				mesh.indices.extend_from_slice(&[b + 1, a + 1, a]);
+			}
+		}
+	}
+	mesh
+}
+
+/// A cone with its base of radius `radius` facing -Y and its tip `height` above the base
+pub fn cone(radius: f32, height: f32, segments: u32) -> MeshData {
+	assert!(segments >= 3);
+	let base_y = -height / 2.0;
+	let tip = Vec3::new(0.0, height / 2.0, 0.0);
+	let side_normal = |theta: f32| Vec3::new(theta.sin() * height, radius, theta.cos() * height).normalize();
+	let angle = |s: u32| s as f32 / segments as f32 * 2.0 * PI;
+
+	let mut mesh = MeshData::default();
+	// The side, with a separate tip vertex for each segment so the normals are smooth around it
+	for s in 0..=segments {
+		let theta = angle(s);
+		mesh.vertices.push(Vertex::new(
+			Vec3::new(theta.sin() * radius, base_y, theta.cos() * radius),
+			side_normal(theta),
+			Vec2::new(s as f32 / segments as f32, 1.0),
+		));
+	}
+	let tips = mesh.vertices.len() as u32;
+	for s in 0..segments {
+		let middle = (s as f32 + 0.5) / segments as f32;
+		mesh.vertices
+			.push(Vertex::new(tip, side_normal(middle * 2.0 * PI), Vec2::new(middle, 0.0)));
+		mesh.indices.extend_from_slice(&[tips + s, s, s + 1]);
+	}
+
+	// The base
+	let center = mesh.vertices.len() as u32;
+	mesh.vertices.push(Vertex::new(
+		Vec3::new(0.0, base_y, 0.0),
+		-Vec3::y(),
+		Vec2::new(0.5, 0.5),
+	));
+	for s in 0..segments {
+		let theta = angle(s);
+		mesh.vertices.push(Vertex::new(
+			Vec3::new(theta.sin() * radius, base_y, theta.cos() * radius),
+			-Vec3::y(),
+			Vec2::new(0.5 + theta.sin() / 2.0, 0.5 + theta.cos() / 2.0),
+		));
+	}
+	for s in 0..segments {
+		let current = center + 1 + s;
+		let next = center + 1 + (s + 1) % segments;
+		mesh.indices.extend_from_slice(&[center, next, current]);
+	}
+	mesh
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Checks that the indices are in range, the normals are unit length, and every triangle faces
+	/// away from `inside`
+	fn check_mesh(mesh: &MeshData, inside: Vec3) {
+		assert_eq!(mesh.indices.len() % 3, 0);
+		assert!(mesh.indices.iter().all(|&i| (i as usize) < mesh.vertices.len()));
+		for vertex in &mesh.vertices {
+			assert!((vertex.normal.norm() - 1.0).abs() < 1e-5);
+		}
+		for triangle in mesh.indices.chunks(3) {
+			let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize].position);
+			let facing = (b - a).cross(&(c - a));
+			assert!(
+				facing.dot(&((a + b + c) / 3.0 - inside)) > 0.0,
+				"{:?} faces inwards",
+				triangle
+			);
+		}
+	}
+
+	#[test]
+	fn cube() {
+		let mesh = super::cube(2.0);
+		assert_eq!(mesh.vertices.len(), 24);
+		assert_eq!(mesh.indices.len(), 36);
+		assert!(mesh.vertices.iter().all(|v| v.position.iter().all(|x| x.abs() == 1.0)));
+		check_mesh(&mesh, Vec3::zeros());
+	}
+
+	#[test]
+	fn plane() {
+		let mesh = super::plane(4.0, 2.0, 3);
+		assert_eq!(mesh.vertices.len(), 16);
+		assert_eq!(mesh.indices.len(), 54);
+		assert_eq!(mesh.vertices[0].position, Vec3::new(-2.0, 0.0, -1.0));
+		assert_eq!(mesh.vertices[15].position, Vec3::new(2.0, 0.0, 1.0));
+		check_mesh(&mesh, -Vec3::y());
+	}
+
+	#[test]
+	fn uv_sphere() {
+		let mesh = super::uv_sphere(2.0, 8, 4);
+		assert_eq!(mesh.vertices.len(), 5 * 9);
+		// The rings around the poles have one triangle per segment instead of two
+		assert_eq!(mesh.indices.len(), 3 * (2 * 8 * 4 - 2 * 8));
+		assert!(mesh.vertices.iter().all(|v| (v.position.norm() - 2.0).abs() < 1e-5));
+		check_mesh(&mesh, Vec3::zeros());
+	}
+
+	#[test]
+	fn capsule() {
+		let mesh = super::capsule(0.5, 2.0, 8, 3);
+		assert_eq!(mesh.vertices.len(), 8 * 9);
+		let top = mesh.vertices.iter().map(|v| v.position.y).fold(f32::MIN, f32::max);
+		let bottom = mesh.vertices.iter().map(|v| v.position.y).fold(f32::MAX, f32::min);
+		assert!((top - 1.5).abs() < 1e-5 && (bottom + 1.5).abs() < 1e-5);
+		check_mesh(&mesh, Vec3::zeros());
+	}
+
+	#[test]
+	fn cone() {
+		let mesh = super::cone(1.0, 2.0, 6);
+		assert_eq!(mesh.vertices.len(), 7 + 6 + 1 + 6);
+		assert_eq!(mesh.indices.len(), 3 * 6 * 2);
+		check_mesh(&mesh, Vec3::zeros());
+	}
+}
@@ -0,0 +1,170 @@
+//! Drawing a cubemap behind everything else in a scene.
+//!
+//! A `Skybox` draws a triangle covering the whole target at the depth of the far plane, and only
+//! keeps the fragments where the depth attachment still holds that depth, so it should be drawn
+//! after the scene, in a render pass that loads the scene's depth. Since the test is for equality,
+//! the sky costs nothing where geometry covers it, and nothing is drawn over the scene regardless
+//! of how far away the scene reaches.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	builtin::{CachedArguments, FullscreenTriangle},
+	camera::Camera,
+	cubemap::{Cubemap, SampledCubemap},
+	function::{
+		compile_shader, ArgumentsContainer, Binding, BindingDesc, BindingType, DepthConvention, DepthTest, FunctionDef,
+		FunctionImpl, FunctionOptions, FunctionPrototype,
+	},
+	image::FormatType,
+	math::*,
+	pass::{RenderPass, RenderPassPrototype},
+	render::RenderEngine,
+	shader::ShaderStage,
+	target::Target,
+	Context, MarsResult,
+};
+
+const SKYBOX_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(constant_id = 0) const float FAR_DEPTH = 1.0;
+
+layout(set = 0, binding = 0) uniform Skybox {
+	mat4 inverse_view_proj;
+	float intensity;
+};
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec3 direction;
+
+void main() {
+	// Any depth between the near and far planes unprojects to a point along the view ray, and the
+	// view matrix has no translation, so the point is the direction of the ray
+	vec4 world = inverse_view_proj * vec4(position, 0.5, 1.0);
+	direction = world.xyz / world.w;
+	gl_Position = vec4(position, FAR_DEPTH, 1.0);
+}
+"#;
+
+const SKYBOX_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform Skybox {
+	mat4 inverse_view_proj;
+	float intensity;
+};
+layout(set = 0, binding = 1) uniform samplerCube environment;
+
+layout(location = 0) in vec3 direction;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	color = vec4(textureLod(environment, direction, 0.0).rgb * intensity, 1.0);
+}
+"#;
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct SkyboxParams {
+	inverse_view_proj: Mat4,
+	intensity: f32,
+	_padding: [f32; 3],
+}
+
+unsafe impl Binding for SkyboxParams {
+	type Argument = Buffer<UniformBufferUsage, SkyboxParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+struct SkyboxFunction<P>(PhantomData<P>);
+
+impl<P> FunctionPrototype for SkyboxFunction<P>
+where
+	P: RenderPassPrototype,
+{
+	type RenderPass = P;
+	type VertexInput = Vec2;
+	type Bindings = (SkyboxParams, SampledCubemap);
+	type PushConstants = ();
+}
+
+/// Draws cubemaps as the background of targets of render passes of prototype `P`, which must have
+/// a depth attachment. See the module documentation.
+pub struct Skybox<P: RenderPassPrototype> {
+	function: FunctionDef<SkyboxFunction<P>>,
+	// Keyed by the view of the cubemap they read
+	arguments: CachedArguments<vk::ImageView, ArgumentsContainer<SkyboxFunction<P>>>,
+	triangle: FullscreenTriangle,
+}
+
+impl<P> Skybox<P>
+where
+	P: RenderPassPrototype,
+{
+	/// Creates a skybox for scenes drawn with the depth convention `depth`, whose depth attachments
+	/// are cleared to `depth.clear_value()`
+	pub fn create(context: &Context, render_pass: &RenderPass<P>, depth: DepthConvention) -> MarsResult<Self> {
+		let vert = compile_shader(SKYBOX_VERTEX_SHADER, "skybox.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(SKYBOX_FRAGMENT_SHADER, "skybox.frag", ShaderStage::Fragment)?;
+		let function = FunctionDef::create_with_options(
+			context,
+			render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			&FunctionOptions {
+				depth_test: DepthTest::Equal,
+				specialization_constants: vec![depth.clear_value().to_bits()],
+				..Default::default()
+			},
+		)?;
+
+		Ok(Self {
+			function,
+			arguments: CachedArguments::new(),
+			triangle: FullscreenTriangle::create(context)?,
+		})
+	}
+
+	/// Draws `cubemap` as seen by `camera` wherever nothing has been drawn in `target`, with its
+	/// colors multiplied by `intensity`
+	pub fn draw<F: FormatType>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		target: &mut Target<P>,
+		cubemap: &Cubemap<F>,
+		camera: &Camera,
+		intensity: f32,
+	) -> MarsResult<()> {
+		// The sky is infinitely far away, so only the camera's rotation matters
+		let rotation = camera.orientation.inverse().to_homogeneous();
+		let inverse_view_proj = (camera.proj() * rotation).try_inverse().unwrap_or_else(Mat4::identity);
+		let params = SkyboxParams {
+			inverse_view_proj,
+			intensity,
+			_padding: [0.0; 3],
+		};
+
+		let function = &mut self.function;
+		let arguments = self.arguments.make_or_update(
+			cubemap.view.raw,
+			|| {
+				let params = Buffer::make_item_buffer(context, params)?;
+				function.make_arguments(context, (params, cubemap.argument()))
+			},
+			|arguments| arguments.arguments.0.record_upload(context, engine, params),
+		)?;
+		engine.pass(context, target, function, Some(self.triangle.draw_args(arguments)))
+	}
+}
@@ -0,0 +1,535 @@
+//! Sparse (virtual) textures, which can be far larger than the memory backing them.
+//!
+//! A `SparseTexture` is a sampled image with no memory of its own. It's divided into tiles, the
+//! device's sparse block size for its format, and memory is only bound to the tiles that are made
+//! resident, out of a pool of pages with a fixed budget. Tiles are made resident and evicted on
+//! demand, like the pages of a megatexture as the camera moves, and their texels are uploaded
+//! through the context's staging belt like any other upload. The smallest mips, which are too
+//! small to be tiled (the mip tail), are always resident.
+//!
+//! Shaders sampling a tile that isn't resident read zeros on most devices, or undefined values if
+//! the device's `residency_non_resident_strict` property isn't set. Sparse textures need the
+//! `sparse_binding` and `sparse_residency_image2_d` device features, and a queue family that
+//! supports sparse binding.
+
+use std::{collections::HashMap, mem::ManuallyDrop};
+
+use rk::{
+	device::{Device, Queue},
+	vk,
+};
+
+use crate::{
+	destruction::{DestructionQueue, RecordedUses},
+	image::{copy_alignment, usage, DynImageUsage, FormatType, Image, ImageView, SampleCount1, Texture},
+	memory::find_memory_type,
+	raw_device, raw_instance, raw_physical_device, raw_queue, sync,
+	sync::ImageTransition,
+	Context, MarsResult,
+};
+
+/// The number of pages allocated at once when the pool runs out of free ones
+const PAGES_PER_BLOCK: u64 = 64;
+
+/// A tile of a mip level of a sparse texture, counted in tiles from the top left
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Tile {
+	pub mip_level: u32,
+	pub x: u32,
+	pub y: u32,
+}
+
+/// A page of memory a tile can be bound to
+#[derive(Debug, Copy, Clone)]
+struct Page {
+	memory: vk::DeviceMemory,
+	offset: u64,
+}
+
+/// The memory tiles are bound to, allocated in blocks of pages as needed up to a budget
+struct PagePool {
+	memory_type: u32,
+	page_size: u64,
+	capacity: u64,
+	blocks: Vec<vk::DeviceMemory>,
+	free: Vec<Page>,
+}
+
+impl PagePool {
+	fn allocated(&self) -> u64 {
+		self.blocks.len() as u64 * PAGES_PER_BLOCK
+	}
+
+	fn take(&mut self, context: &Context) -> MarsResult<Page> {
+		if self.free.is_empty() {
+			if self.allocated() >= self.capacity {
+				return Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY);
+			}
+			let allocate_info = vk::MemoryAllocateInfo::builder()
+				.allocation_size(self.page_size * PAGES_PER_BLOCK)
+				.memory_type_index(self.memory_type);
+			let memory = unsafe { raw_device(&context.device).allocate_memory(&allocate_info, None)? };
+			self.blocks.push(memory);
+			self.free.extend((0..PAGES_PER_BLOCK).rev().map(|page| Page {
+				memory,
+				offset: page * self.page_size,
+			}));
+		}
+		Ok(self.free.pop().unwrap())
+	}
+}
+
+/// A sampled image whose tiles are bound to memory on demand. See the module documentation.
+pub struct SparseTexture<F: FormatType> {
+	/// Wraps `raw`, which the sparse texture owns and destroys itself. Only taken out when the
+	/// sparse texture is dropped, so the view is destroyed before the image.
+	texture: ManuallyDrop<Texture<F>>,
+	raw: vk::Image,
+	tile_extent: vk::Extent2D,
+	/// The first mip level too small to be tiled, or the number of mip levels if they all are
+	first_mip_tail_level: u32,
+	/// The memory the mip tail and metadata are always bound to
+	opaque_memory: Vec<vk::DeviceMemory>,
+	pool: PagePool,
+	resident: HashMap<Tile, Page>,
+	device: Device,
+	destruction: DestructionQueue,
+}
+
+impl<F: FormatType> SparseTexture<F> {
+	/// Creates a sparse texture of `extent` with `mip_levels` mip levels, with no tiles resident and
+	/// memory for at most `max_resident_tiles` of them. Fails with `ERROR_FEATURE_NOT_PRESENT` if the
+	/// context doesn't support sparse textures, and with `ERROR_FORMAT_NOT_SUPPORTED` if they can't
+	/// have format `F`.
+	pub fn create(
+		context: &Context,
+		extent: vk::Extent2D,
+		mip_levels: u32,
+		max_resident_tiles: u64,
+	) -> MarsResult<Self> {
+		if context.features.sparse_binding == vk::FALSE
+			|| context.features.sparse_residency_image2_d == vk::FALSE
+			|| sparse_queue(context).is_none()
+		{
+			return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+		}
+		let usage = DynImageUsage::SAMPLED | DynImageUsage::TRANSFER_DST;
+		let format_properties = unsafe {
+			raw_instance(&context.instance).get_physical_device_sparse_image_format_properties(
+				raw_physical_device(&context.physical_device),
+				F::as_raw(),
+				vk::ImageType::TYPE_2D,
+				vk::SampleCountFlags::TYPE_1,
+				usage.as_raw(),
+				vk::ImageTiling::OPTIMAL,
+			)
+		};
+		if format_properties.is_empty() {
+			return Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED);
+		}
+
+		let create_info = vk::ImageCreateInfo::builder()
+			.flags(vk::ImageCreateFlags::SPARSE_BINDING | vk::ImageCreateFlags::SPARSE_RESIDENCY)
+			.image_type(vk::ImageType::TYPE_2D)
+			.format(F::as_raw())
+			.extent(vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			})
+			.mip_levels(mip_levels)
+			.array_layers(1)
+			.samples(vk::SampleCountFlags::TYPE_1)
+			.tiling(vk::ImageTiling::OPTIMAL)
+			.usage(usage.as_raw())
+			.sharing_mode(vk::SharingMode::EXCLUSIVE)
+			.initial_layout(vk::ImageLayout::UNDEFINED);
+		let device = raw_device(&context.device);
+		let raw = unsafe { device.create_image(&create_info, None)? };
+		let mut opaque_memory = Vec::new();
+		let result = unsafe {
+			Self::create_with_image(context, raw, extent, mip_levels, max_resident_tiles, &mut opaque_memory)
+		};
+		if result.is_err() {
+			unsafe {
+				device.destroy_image(raw, None);
+				for memory in opaque_memory {
+					device.free_memory(memory, None);
+				}
+			}
+		}
+		result
+	}
+
+	/// Binds the mip tail of `raw` and wraps it, keeping the memory it allocates in `opaque_memory`
+	/// so it can be freed if this fails
+	unsafe fn create_with_image(
+		context: &Context,
+		raw: vk::Image,
+		extent: vk::Extent2D,
+		mip_levels: u32,
+		max_resident_tiles: u64,
+		opaque_memory: &mut Vec<vk::DeviceMemory>,
+	) -> MarsResult<Self> {
+		let device = raw_device(&context.device);
+		let memory_requirements = device.get_image_memory_requirements(raw);
+		let sparse_requirements = device.get_image_sparse_memory_requirements(raw);
+		let color = sparse_requirements
+			.iter()
+			.find(|requirements| requirements.format_properties.aspect_mask.contains(F::aspect()))
+			.ok_or(vk::Result::ERROR_FORMAT_NOT_SUPPORTED)?;
+		let granularity = color.format_properties.image_granularity;
+		let memory_type = find_memory_type(
+			context,
+			memory_requirements.memory_type_bits,
+			vk::MemoryPropertyFlags::DEVICE_LOCAL,
+		)?;
+
+		// The mip tail, and the metadata some formats have, are bound once and stay resident
+		let mut opaque_binds = Vec::new();
+		let mut first_mip_tail_level = mip_levels;
+		for requirements in &sparse_requirements {
+			let is_metadata = requirements
+				.format_properties
+				.aspect_mask
+				.contains(vk::ImageAspectFlags::METADATA);
+			if !is_metadata && requirements.image_mip_tail_first_lod >= mip_levels {
+				continue;
+			}
+			let allocate_info = vk::MemoryAllocateInfo::builder()
+				.allocation_size(requirements.image_mip_tail_size)
+				.memory_type_index(memory_type);
+			let memory = device.allocate_memory(&allocate_info, None)?;
+			opaque_memory.push(memory);
+			opaque_binds.push(vk::SparseMemoryBind {
+				resource_offset: requirements.image_mip_tail_offset,
+				size: requirements.image_mip_tail_size,
+				memory,
+				memory_offset: 0,
+				flags: if is_metadata {
+					vk::SparseMemoryBindFlags::METADATA
+				} else {
+					vk::SparseMemoryBindFlags::empty()
+				},
+			});
+			if !is_metadata {
+				first_mip_tail_level = requirements.image_mip_tail_first_lod;
+			}
+		}
+		if !opaque_binds.is_empty() {
+			let opaque_info = [vk::SparseImageOpaqueMemoryBindInfo::builder()
+				.image(raw)
+				.binds(&opaque_binds)
+				.build()];
+			let bind_info = vk::BindSparseInfo::builder().image_opaque_binds(&opaque_info).build();
+			bind_sparse(context, bind_info)?;
+		}
+
+		let mut image = Image::<usage::SampledImage, F, SampleCount1>::wrap_raw(
+			context,
+			raw,
+			DynImageUsage::SAMPLED | DynImageUsage::TRANSFER_DST,
+			extent,
+			1,
+			vk::ImageLayout::UNDEFINED,
+		);
+		image.mip_levels = mip_levels;
+		image.transition(
+			context,
+			&ImageTransition {
+				aspect: F::aspect(),
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			},
+		)?;
+		let image_view = ImageView::create(&image)?;
+
+		Ok(Self {
+			texture: ManuallyDrop::new(Texture::new(image, image_view)),
+			raw,
+			tile_extent: vk::Extent2D {
+				width: granularity.width,
+				height: granularity.height,
+			},
+			first_mip_tail_level,
+			opaque_memory: std::mem::take(opaque_memory),
+			pool: PagePool {
+				memory_type,
+				// Every tile takes one sparse block, whose size is the alignment of the image's memory
+				page_size: memory_requirements.alignment,
+				capacity: max_resident_tiles,
+				blocks: Vec::new(),
+				free: Vec::new(),
+			},
+			resident: HashMap::new(),
+			device: context.device.clone(),
+			destruction: context.destruction.clone(),
+		})
+	}
+
+	/// The texture to bind to functions that sample it
+	pub fn texture(&self) -> &Texture<F> {
+		&self.texture
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.texture.image.extent
+	}
+
+	/// The extent of a tile in texels
+	pub fn tile_extent(&self) -> vk::Extent2D {
+		self.tile_extent
+	}
+
+	/// The first mip level of the mip tail, which is always resident and isn't tiled. Mip levels
+	/// from here on are uploaded with `upload_mip_tail`.
+	pub fn first_mip_tail_level(&self) -> u32 {
+		self.first_mip_tail_level
+	}
+
+	/// The number of tiles across and down `mip_level`
+	pub fn tile_count(&self, mip_level: u32) -> (u32, u32) {
+		let extent = mip_extent(self.extent(), mip_level);
+		(
+			(extent.width + self.tile_extent.width - 1) / self.tile_extent.width,
+			(extent.height + self.tile_extent.height - 1) / self.tile_extent.height,
+		)
+	}
+
+	pub fn is_resident(&self, tile: Tile) -> bool {
+		self.resident.contains_key(&tile)
+	}
+
+	/// The tiles that are resident, in no particular order
+	pub fn resident_tiles(&self) -> impl Iterator<Item = Tile> + '_ {
+		self.resident.keys().copied()
+	}
+
+	/// The most tiles that can be resident at once
+	pub fn max_resident_tiles(&self) -> u64 {
+		self.pool.capacity
+	}
+
+	/// Binds memory to `tile` if it isn't resident yet, and uploads its texels. `data` is tightly
+	/// packed texels of the tile, in rows from top to bottom, clipped to the edges of the mip level.
+	/// Fails with `ERROR_OUT_OF_POOL_MEMORY` if the maximum number of tiles are already resident, in
+	/// which case some have to be evicted first.
+	pub fn make_resident(&mut self, context: &Context, tile: Tile, data: &[u8]) -> MarsResult<()> {
+		assert!(
+			tile.mip_level < self.first_mip_tail_level(),
+			"tile is in the mip tail, which is always resident"
+		);
+		let (columns, rows) = self.tile_count(tile.mip_level);
+		assert!(tile.x < columns && tile.y < rows, "tile is outside of the mip level");
+		let (offset, extent) = self.tile_region(tile);
+		assert_eq!(
+			data.len(),
+			extent.width as usize * extent.height as usize * std::mem::size_of::<F::Pixel>(),
+			"tile data doesn't match the extent of the tile"
+		);
+
+		if !self.resident.contains_key(&tile) {
+			let page = self.pool.take(context)?;
+			if let Err(e) = self.bind_tile(context, tile, Some(page)) {
+				self.pool.free.push(page);
+				return Err(e);
+			}
+			self.resident.insert(tile, page);
+		}
+		self.upload(context, tile.mip_level, offset, extent, data)
+	}
+
+	/// Unbinds the memory of `tile` and returns it to the pool, if the tile is resident
+	pub fn evict(&mut self, context: &Context, tile: Tile) -> MarsResult<()> {
+		if let Some(page) = self.resident.remove(&tile) {
+			self.bind_tile(context, tile, None)?;
+			self.pool.free.push(page);
+		}
+		Ok(())
+	}
+
+	/// Uploads the texels of `mip_level` of the mip tail, tightly packed in rows from top to bottom
+	pub fn upload_mip_tail(&mut self, context: &Context, mip_level: u32, data: &[u8]) -> MarsResult<()> {
+		assert!(
+			mip_level >= self.first_mip_tail_level() && mip_level < self.texture.image.mip_levels,
+			"mip level isn't in the mip tail"
+		);
+		let extent = mip_extent(self.extent(), mip_level);
+		assert_eq!(
+			data.len(),
+			extent.width as usize * extent.height as usize * std::mem::size_of::<F::Pixel>(),
+			"mip data doesn't match the extent of the mip level"
+		);
+		self.upload(context, mip_level, vk::Offset3D { x: 0, y: 0, z: 0 }, extent, data)
+	}
+
+	/// The texels `tile` covers, clipped to its mip level
+	fn tile_region(&self, tile: Tile) -> (vk::Offset3D, vk::Extent2D) {
+		let mip = mip_extent(self.extent(), tile.mip_level);
+		let x = tile.x * self.tile_extent.width;
+		let y = tile.y * self.tile_extent.height;
+		(
+			vk::Offset3D {
+				x: x as i32,
+				y: y as i32,
+				z: 0,
+			},
+			vk::Extent2D {
+				width: self.tile_extent.width.min(mip.width - x),
+				height: self.tile_extent.height.min(mip.height - y),
+			},
+		)
+	}
+
+	/// Binds `tile` to `page`, or unbinds it if there's no page, and waits for the binding to
+	/// complete
+	fn bind_tile(&self, context: &Context, tile: Tile, page: Option<Page>) -> MarsResult<()> {
+		let (offset, extent) = self.tile_region(tile);
+		let (memory, memory_offset) = page.map_or((vk::DeviceMemory::null(), 0), |page| (page.memory, page.offset));
+		let binds = [vk::SparseImageMemoryBind {
+			subresource: vk::ImageSubresource {
+				aspect_mask: F::aspect(),
+				mip_level: tile.mip_level,
+				array_layer: 0,
+			},
+			offset,
+			extent: vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+			memory,
+			memory_offset,
+			flags: vk::SparseMemoryBindFlags::empty(),
+		}];
+		let image_info = [vk::SparseImageMemoryBindInfo::builder()
+			.image(self.raw)
+			.binds(&binds)
+			.build()];
+		let bind_info = vk::BindSparseInfo::builder().image_binds(&image_info).build();
+		unsafe { bind_sparse(context, bind_info) }
+	}
+
+	/// Copies `data` into the region of `mip_level` at `offset` through the staging belt
+	fn upload(
+		&self,
+		context: &Context,
+		mip_level: u32,
+		offset: vk::Offset3D,
+		extent: vk::Extent2D,
+		data: &[u8],
+	) -> MarsResult<()> {
+		let staged = context.staging.stage(context, data, copy_alignment::<F>())?;
+		let to_transfer = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			src_access_mask: vk::AccessFlags2KHR::NONE,
+			dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			old_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+			new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+		};
+		let to_sampled = ImageTransition {
+			aspect: F::aspect(),
+			src_stage_mask: vk::PipelineStageFlags2KHR::COPY,
+			dst_stage_mask: vk::PipelineStageFlags2KHR::FRAGMENT_SHADER,
+			src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+			dst_access_mask: vk::AccessFlags2KHR::SHADER_SAMPLED_READ,
+			old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		};
+		let region = vk::BufferImageCopy {
+			buffer_offset: staged.offset,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: F::aspect(),
+				mip_level,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_offset: offset,
+			image_extent: vk::Extent3D {
+				width: extent.width,
+				height: extent.height,
+				depth: 1,
+			},
+		};
+		let raw = self.raw;
+		sync::one_time_submit(context, |command_buffer| unsafe {
+			sync::record_image_transition(context, command_buffer, raw, &to_transfer);
+			raw_device(&context.device).cmd_copy_buffer_to_image(
+				command_buffer,
+				staged.buffer,
+				raw,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				&[region],
+			);
+			sync::record_image_transition(context, command_buffer, raw, &to_sampled);
+		})
+	}
+}
+
+impl<F: FormatType> Drop for SparseTexture<F> {
+	fn drop(&mut self) {
+		unsafe { ManuallyDrop::drop(&mut self.texture) };
+		let device = self.device.clone();
+		let raw = self.raw;
+		let mut memories = std::mem::take(&mut self.pool.blocks);
+		memories.append(&mut self.opaque_memory);
+		self.destruction.defer(move || unsafe {
+			let device = raw_device(&device);
+			device.destroy_image(raw, None);
+			for memory in memories {
+				device.free_memory(memory, None);
+			}
+		});
+	}
+}
+
+/// Returns a queue that supports sparse binding, preferring the context's main queue
+fn sparse_queue(context: &Context) -> Option<&Queue> {
+	let families = unsafe {
+		raw_instance(&context.instance)
+			.get_physical_device_queue_family_properties(raw_physical_device(&context.physical_device))
+	};
+	let supports_sparse = |family: u32| {
+		families[family as usize]
+			.queue_flags
+			.contains(vk::QueueFlags::SPARSE_BINDING)
+	};
+	if supports_sparse(context.queue_family_index) {
+		Some(&context.queue)
+	} else {
+		context
+			.family_queues
+			.iter()
+			.find(|(family, _)| supports_sparse(*family))
+			.map(|(_, queue)| queue)
+	}
+}
+
+/// Submits a sparse binding operation and waits for it to complete
+unsafe fn bind_sparse(context: &Context, bind_info: vk::BindSparseInfo) -> MarsResult<()> {
+	let queue = sparse_queue(context).ok_or(vk::Result::ERROR_FEATURE_NOT_PRESENT)?;
+	let device = raw_device(&context.device);
+	let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+	let serial = context.destruction.begin_submission(&RecordedUses::default());
+	let result = queue
+		.with_lock(|| device.queue_bind_sparse(raw_queue(queue), &[bind_info], fence))
+		.and_then(|()| device.wait_for_fences(&[fence], true, u64::MAX));
+	device.destroy_fence(fence, None);
+	context.destruction.end_submission(serial);
+	result
+}
+
+fn mip_extent(extent: vk::Extent2D, mip_level: u32) -> vk::Extent2D {
+	vk::Extent2D {
+		width: (extent.width >> mip_level).max(1),
+		height: (extent.height >> mip_level).max(1),
+	}
+}
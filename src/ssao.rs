@@ -0,0 +1,451 @@
+//! Screen-space ambient occlusion, which darkens creases and corners by estimating how much of the
+//! hemisphere above each visible surface is blocked by nearby geometry.
+//!
+//! The scene is first drawn into an `OffscreenTarget<NormalDepthPass>`, whose color attachment
+//! holds the view space normal of each surface in RGB and its distance along the view axis in A.
+//! `NormalDepthFunction` draws `shapes::Vertex` meshes that way, and other functions can do the same
+//! with `NORMAL_DEPTH_FRAGMENT_SHADER`. The attachment must be cleared to zero, which marks the
+//! background as unoccluded.
+//!
+//! `Ssao::apply` then renders the occlusion into one `OffscreenTarget<OcclusionPass>` and blurs it
+//! into another. Lighting functions read the blurred target through a `TargetOutput` binding inside
+//! `OffscreenTarget::sample`, where 1 is fully lit and 0 is fully occluded. The occlusion targets
+//! can be smaller than the normal and depth target, like half its size, to save fill rate.
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	builtin::{CachedArguments, FullscreenTriangle},
+	function::{
+		compile_shader, ArgumentsContainer, Binding, BindingDesc, BindingType, FunctionDef, FunctionImpl,
+		FunctionPrototype,
+	},
+	image::{
+		format::{D32Sfloat, R16G16B16A16Sfloat, R8G8B8A8Unorm, R8Unorm},
+		usage, Image, MipmapMode, SampleCount1, SampledImage,
+	},
+	math::*,
+	pass::{ColorAttachment, DepthAttachment, NoDepthAttachment, RenderPass, RenderPassPrototype},
+	render::RenderEngine,
+	shader::ShaderStage,
+	shapes::Vertex,
+	target::{OffscreenTarget, TargetOutput, TargetOutputArgument},
+	Context, MarsResult,
+};
+
+pub const NORMAL_DEPTH_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform Mvp {
+	mat4 model;
+	mat4 view;
+	mat4 proj;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+
+layout(location = 0) out vec3 view_position;
+layout(location = 1) out vec3 view_normal;
+
+void main() {
+	mat4 model_view = view * model;
+	vec4 view_space = model_view * vec4(position, 1.0);
+	view_position = view_space.xyz;
+	view_normal = mat3(transpose(inverse(model_view))) * normal;
+	gl_Position = proj * view_space;
+}
+"#;
+
+/// Writes the normal and depth of a surface from its view space position and normal, at locations
+/// 0 and 1
+pub const NORMAL_DEPTH_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec3 view_position;
+layout(location = 1) in vec3 view_normal;
+
+layout(location = 0) out vec4 normal_depth;
+
+void main() {
+	// The view looks down -Z, so distances in front of it are the negated Z
+	normal_depth = vec4(normalize(view_normal), -view_position.z);
+}
+"#;
+
+const FULLSCREEN_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = position * 0.5 + 0.5;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const SSAO_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+// Must match KERNEL_SIZE
+const int KERNEL_SIZE = 16;
+
+layout(set = 0, binding = 0) uniform sampler2D normal_depth;
+layout(set = 0, binding = 1) uniform sampler2D noise;
+layout(set = 0, binding = 2) uniform Ssao {
+	mat4 proj;
+	mat4 inverse_proj;
+	vec4 kernel[KERNEL_SIZE];
+	float radius;
+	float bias;
+	float power;
+};
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+// The point `depth` in front of the view along the ray through `uv`
+vec3 view_position(vec2 uv, float depth) {
+	vec4 ray = inverse_proj * vec4(uv * 2.0 - 1.0, 0.5, 1.0);
+	vec3 point = ray.xyz / ray.w;
+	return point * (depth / -point.z);
+}
+
+void main() {
+	vec4 surface = texture(normal_depth, uv);
+	if (surface.w <= 0.0) {
+		color = vec4(1.0);
+		return;
+	}
+	vec3 position = view_position(uv, surface.w);
+	vec3 normal = normalize(surface.xyz);
+
+	// Each pixel of a tile of the noise texture rotates the kernel differently, which the blur then
+	// averages out
+	ivec2 noise_size = textureSize(noise, 0);
+	vec3 random = texelFetch(noise, ivec2(gl_FragCoord.xy) % noise_size, 0).xyz * 2.0 - 1.0;
+	vec3 tangent = normalize(random - normal * dot(random, normal));
+	mat3 tbn = mat3(tangent, cross(normal, tangent), normal);
+
+	float occlusion = 0.0;
+	for (int i = 0; i < KERNEL_SIZE; i++) {
+		vec3 sample_position = position + tbn * kernel[i].xyz * radius;
+		vec4 clip = proj * vec4(sample_position, 1.0);
+		vec2 sample_uv = clip.xy / clip.w * 0.5 + 0.5;
+		float scene_depth = texture(normal_depth, sample_uv).w;
+		// Geometry much closer than the surface is something else in front of it, which shouldn't
+		// darken it
+		float range = smoothstep(0.0, 1.0, radius / abs(surface.w - scene_depth));
+		bool occluded = scene_depth > 0.0 && scene_depth <= -sample_position.z - bias;
+		occlusion += occluded ? range : 0.0;
+	}
+	float visibility = pow(1.0 - occlusion / float(KERNEL_SIZE), power);
+	color = vec4(visibility, 0.0, 0.0, 1.0);
+}
+"#;
+
+const BLUR_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+// Must match NOISE_SIZE
+const int NOISE_SIZE = 4;
+
+layout(set = 0, binding = 0) uniform sampler2D occlusion;
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	// A box blur the size of a noise tile covers every rotation of the kernel once
+	vec2 texel = 1.0 / vec2(textureSize(occlusion, 0));
+	float sum = 0.0;
+	for (int y = 0; y < NOISE_SIZE; y++) {
+		for (int x = 0; x < NOISE_SIZE; x++) {
+			vec2 offset = vec2(x, y) - float(NOISE_SIZE / 2);
+			sum += texture(occlusion, uv + offset * texel).r;
+		}
+	}
+	color = vec4(sum / float(NOISE_SIZE * NOISE_SIZE), 0.0, 0.0, 1.0);
+}
+"#;
+
+/// The number of samples taken around each surface
+const KERNEL_SIZE: usize = 16;
+/// The width and height of the tile of random kernel rotations
+const NOISE_SIZE: u32 = 4;
+
+pub type NormalDepthFormat = R16G16B16A16Sfloat;
+pub type OcclusionFormat = R8Unorm;
+
+/// The render pass the scene's normals and depth are drawn into. See the module documentation.
+pub struct NormalDepthPass;
+
+impl RenderPassPrototype for NormalDepthPass {
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<NormalDepthFormat>,);
+	type DepthAttachment = DepthAttachment<D32Sfloat, SampleCount1>;
+}
+
+/// A function drawing meshes of `shapes::Vertex`es into a `NormalDepthPass`
+pub struct NormalDepthFunction;
+
+impl FunctionPrototype for NormalDepthFunction {
+	type RenderPass = NormalDepthPass;
+	type VertexInput = Vertex;
+	type Bindings = (Mvp,);
+	type PushConstants = ();
+}
+
+impl NormalDepthFunction {
+	pub fn create(context: &Context, render_pass: &RenderPass<NormalDepthPass>) -> MarsResult<FunctionDef<Self>> {
+		let vert = compile_shader(NORMAL_DEPTH_VERTEX_SHADER, "normal_depth.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(NORMAL_DEPTH_FRAGMENT_SHADER, "normal_depth.frag", ShaderStage::Fragment)?;
+		FunctionDef::create(context, render_pass, unsafe { FunctionImpl::from_raw(vert, frag) })
+	}
+}
+
+/// The render pass occlusion is rendered and blurred into, with a single channel holding the
+/// visibility of each pixel
+pub struct OcclusionPass;
+
+impl RenderPassPrototype for OcclusionPass {
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<OcclusionFormat>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+struct SsaoFunction;
+
+impl FunctionPrototype for SsaoFunction {
+	type RenderPass = OcclusionPass;
+	type VertexInput = Vec2;
+	type Bindings = (TargetOutput, TargetOutput, SsaoParams);
+	type PushConstants = ();
+}
+
+struct BlurFunction;
+
+impl FunctionPrototype for BlurFunction {
+	type RenderPass = OcclusionPass;
+	type VertexInput = Vec2;
+	type Bindings = (TargetOutput,);
+	type PushConstants = ();
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct SsaoParams {
+	proj: Mat4,
+	inverse_proj: Mat4,
+	kernel: [Vec4; KERNEL_SIZE],
+	radius: f32,
+	bias: f32,
+	power: f32,
+	_padding: f32,
+}
+
+unsafe impl Binding for SsaoParams {
+	type Argument = Buffer<UniformBufferUsage, SsaoParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SsaoOptions {
+	/// How far from each surface, in view space units, geometry can occlude it
+	pub radius: f32,
+	/// How far a sample has to be behind the scene to count as occluded, which avoids surfaces
+	/// shadowing themselves from the imprecision of the depth
+	pub bias: f32,
+	/// The exponent the visibility is raised to, with larger values darkening occluded areas more
+	pub power: f32,
+}
+
+impl Default for SsaoOptions {
+	fn default() -> Self {
+		Self {
+			radius: 0.5,
+			bias: 0.025,
+			power: 1.0,
+		}
+	}
+}
+
+/// Renders ambient occlusion from the normals and depth of a scene. See the module documentation.
+pub struct Ssao {
+	render_pass: RenderPass<OcclusionPass>,
+	ssao: FunctionDef<SsaoFunction>,
+	blur: FunctionDef<BlurFunction>,
+	noise: SampledImage<R8G8B8A8Unorm>,
+	kernel: [Vec4; KERNEL_SIZE],
+	options: SsaoOptions,
+	// Keyed by the views of the targets they read
+	ssao_arguments: CachedArguments<vk::ImageView, ArgumentsContainer<SsaoFunction>>,
+	blur_arguments: CachedArguments<vk::ImageView, ArgumentsContainer<BlurFunction>>,
+	triangle: FullscreenTriangle,
+}
+
+impl Ssao {
+	pub fn create(context: &Context, options: SsaoOptions) -> MarsResult<Self> {
+		let render_pass = RenderPass::create(context)?;
+		let vert = compile_shader(FULLSCREEN_VERTEX_SHADER, "ssao.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(SSAO_FRAGMENT_SHADER, "ssao.frag", ShaderStage::Fragment)?;
+		let ssao = FunctionDef::create(context, &render_pass, unsafe {
+			FunctionImpl::from_raw(vert.clone(), frag)
+		})?;
+		let frag = compile_shader(BLUR_FRAGMENT_SHADER, "ssao_blur.frag", ShaderStage::Fragment)?;
+		let blur = FunctionDef::create(context, &render_pass, unsafe { FunctionImpl::from_raw(vert, frag) })?;
+
+		let mut random = Random::new(0x5a0);
+		let noise = make_noise(context, &mut random)?;
+		let kernel = make_kernel(&mut random);
+
+		Ok(Self {
+			render_pass,
+			ssao,
+			blur,
+			noise,
+			kernel,
+			options,
+			ssao_arguments: CachedArguments::new(),
+			blur_arguments: CachedArguments::new(),
+			triangle: FullscreenTriangle::create(context)?,
+		})
+	}
+
+	/// The render pass to create occlusion targets with
+	pub fn render_pass(&self) -> &RenderPass<OcclusionPass> {
+		&self.render_pass
+	}
+
+	pub fn options(&self) -> &SsaoOptions {
+		&self.options
+	}
+
+	/// Changes the options used from the next `apply` on
+	pub fn set_options(&mut self, options: SsaoOptions) {
+		self.options = options;
+	}
+
+	/// Renders the occlusion of the scene in `normal_depth`, drawn with the projection matrix
+	/// `proj`, into `occlusion`, then blurs it into `blurred`
+	pub fn apply(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		normal_depth: &mut OffscreenTarget<NormalDepthPass>,
+		proj: &Mat4,
+		occlusion: &mut OffscreenTarget<OcclusionPass>,
+		blurred: &mut OffscreenTarget<OcclusionPass>,
+	) -> MarsResult<()> {
+		let params = SsaoParams {
+			proj: *proj,
+			inverse_proj: proj.try_inverse().unwrap_or_else(Mat4::identity),
+			kernel: self.kernel,
+			radius: self.options.radius,
+			bias: self.options.bias,
+			power: self.options.power,
+			_padding: 0.0,
+		};
+		let (ssao, blur, noise) = (&mut self.ssao, &mut self.blur, &self.noise);
+		let input = normal_depth.output(0);
+		let ssao_arguments = self.ssao_arguments.make_or_update(
+			input.image_view,
+			|| {
+				let noise_argument = TargetOutputArgument {
+					sampler: noise.sampler.sampler.clone(),
+					image_view: noise.image_view.image_view.raw,
+				};
+				let params = Buffer::make_item_buffer(context, params)?;
+				ssao.make_arguments(context, (input, noise_argument, params))
+			},
+			|arguments| arguments.arguments.2.record_upload(context, engine, params),
+		)?;
+		let input = occlusion.output(0);
+		let blur_arguments = self
+			.blur_arguments
+			.make_or_reuse(input.image_view, || blur.make_arguments(context, (input,)))?;
+
+		let triangle = &self.triangle;
+		normal_depth.sample(context, engine, |engine| {
+			engine.pass(
+				context,
+				occlusion.target(),
+				ssao,
+				Some(triangle.draw_args(ssao_arguments)),
+			)
+		})?;
+		occlusion.sample(context, engine, |engine| {
+			engine.pass(
+				context,
+				blurred.target(),
+				blur,
+				Some(triangle.draw_args(blur_arguments)),
+			)
+		})
+	}
+}
+
+/// A tile of random rotations around the view space normal, as unit vectors in the tangent plane
+/// packed into 0 to 1
+fn make_noise(context: &Context, random: &mut Random) -> MarsResult<SampledImage<R8G8B8A8Unorm>> {
+	let pack = |x: f32| ((x * 0.5 + 0.5) * 255.0).round() as u8;
+	let pixels = (0..NOISE_SIZE * NOISE_SIZE)
+		.map(|_| {
+			let direction = Vec2::new(random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0);
+			let direction = direction.try_normalize(f32::EPSILON).unwrap_or_else(Vec2::x);
+			[pack(direction.x), pack(direction.y), pack(0.0), 255]
+		})
+		.collect::<Vec<_>>();
+	let extent = vk::Extent2D {
+		width: NOISE_SIZE,
+		height: NOISE_SIZE,
+	};
+	let image = Image::make_image_from_pixels(context, usage::SampledImage, extent, &pixels, MipmapMode::None)?;
+	SampledImage::create(context, image)
+}
+
+/// Points in the hemisphere around +Z, more of them closer to the center where occlusion matters
+/// most
+fn make_kernel(random: &mut Random) -> [Vec4; KERNEL_SIZE] {
+	let mut kernel = [Vec4::zeros(); KERNEL_SIZE];
+	for (i, point) in kernel.iter_mut().enumerate() {
+		let direction = Vec3::new(random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0, random.next());
+		let direction = direction.try_normalize(f32::EPSILON).unwrap_or_else(Vec3::z);
+		let t = i as f32 / KERNEL_SIZE as f32;
+		let scale = 0.1 + 0.9 * t * t;
+		let offset = direction * random.next() * scale;
+		*point = Vec4::new(offset.x, offset.y, offset.z, 0.0);
+	}
+	kernel
+}
+
+/// A small xorshift generator, so the noise and kernel are the same on every run
+struct Random(u32);
+
+impl Random {
+	fn new(seed: u32) -> Self {
+		Self(seed.max(1))
+	}
+
+	/// A number from 0 to 1
+	fn next(&mut self) -> f32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 17;
+		self.0 ^= self.0 << 5;
+		(self.0 >> 8) as f32 / (1 << 24) as f32
+	}
+}
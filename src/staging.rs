@@ -0,0 +1,134 @@
+//! A staging belt for uploads, in the style of wgpu's.
+//!
+//! Uploads are copied into large persistently mapped chunks of host visible memory instead of a
+//! new staging buffer each, and copied from there to their destination on the device. Space in a
+//! chunk is handed out front to back, and a chunk is rewound once every upload staged in it has
+//! been copied out, which the context's destruction queue reports after the submissions that might
+//! be copying have completed.
+
+use std::{
+	os::raw::c_void,
+	sync::{Arc, Mutex},
+};
+
+use rk::vk;
+
+use crate::{destruction::DestructionQueue, memory::DeviceBuffer, Context, MarsResult};
+
+/// The size of the chunks uploads are staged in. Larger uploads get a chunk of their own, which is
+/// freed instead of reused once the upload is done.
+const CHUNK_SIZE: u64 = 4 << 20;
+
+#[derive(Clone, Default)]
+pub(crate) struct StagingBelt {
+	chunks: Arc<Mutex<Vec<Chunk>>>,
+}
+
+// The chunks are only written through their mappings while the lock is held, at offsets no other
+// upload has been given
+unsafe impl Send for StagingBelt {}
+unsafe impl Sync for StagingBelt {}
+
+struct Chunk {
+	id: u64,
+	buffer: DeviceBuffer,
+	ptr: *mut c_void,
+	/// How far into the chunk space has been handed out
+	cursor: u64,
+	/// The number of uploads staged in the chunk that haven't been copied out yet
+	outstanding: usize,
+}
+
+/// Data copied into a staging chunk, ready to be copied out of `buffer` at `offset`. The space is
+/// reused once this is dropped and the submissions made until then have completed, so it has to
+/// be kept alive until the copy out of it has been submitted.
+pub(crate) struct Staged {
+	pub(crate) buffer: vk::Buffer,
+	pub(crate) offset: u64,
+	chunk: u64,
+	belt: StagingBelt,
+	destruction: DestructionQueue,
+}
+
+impl StagingBelt {
+	pub(crate) fn new() -> Self {
+		Self::default()
+	}
+
+	/// Copies `data` into a chunk at an offset that's a multiple of `alignment`
+	pub(crate) fn stage(&self, context: &Context, data: &[u8], alignment: u64) -> MarsResult<Staged> {
+		let size = data.len() as u64;
+		let mut chunks = self.chunks.lock().unwrap();
+		let found = chunks.iter().position(|chunk| {
+			let offset = align(chunk.cursor, alignment);
+			offset + size <= chunk.buffer.size
+		});
+		let index = match found {
+			Some(index) => index,
+			None => {
+				let chunk_size = size.max(CHUNK_SIZE);
+				let buffer = DeviceBuffer::create(
+					context,
+					chunk_size,
+					vk::BufferUsageFlags::TRANSFER_SRC,
+					vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+				)?;
+				let ptr = unsafe { buffer.map()? };
+				let id = chunks.iter().map(|chunk| chunk.id + 1).max().unwrap_or(0);
+				chunks.push(Chunk {
+					id,
+					buffer,
+					ptr,
+					cursor: 0,
+					outstanding: 0,
+				});
+				chunks.len() - 1
+			}
+		};
+
+		let chunk = &mut chunks[index];
+		let offset = align(chunk.cursor, alignment);
+		unsafe {
+			std::ptr::copy_nonoverlapping(data.as_ptr(), (chunk.ptr as *mut u8).add(offset as usize), data.len());
+		}
+		chunk.cursor = offset + size;
+		chunk.outstanding += 1;
+		Ok(Staged {
+			buffer: chunk.buffer.buffer,
+			offset,
+			chunk: chunk.id,
+			belt: self.clone(),
+			destruction: context.destruction.clone(),
+		})
+	}
+
+	fn release(&self, id: u64) {
+		let mut chunks = self.chunks.lock().unwrap();
+		let index = chunks
+			.iter()
+			.position(|chunk| chunk.id == id)
+			.expect("Released an upload from a chunk that doesn't exist");
+		let chunk = &mut chunks[index];
+		chunk.outstanding -= 1;
+		if chunk.outstanding == 0 {
+			if chunk.buffer.size > CHUNK_SIZE {
+				// Its memory goes back to the allocator
+				chunks.swap_remove(index);
+			} else {
+				chunk.cursor = 0;
+			}
+		}
+	}
+}
+
+impl Drop for Staged {
+	fn drop(&mut self) {
+		let belt = self.belt.clone();
+		let chunk = self.chunk;
+		self.destruction.defer(move || belt.release(chunk));
+	}
+}
+
+fn align(offset: u64, alignment: u64) -> u64 {
+	(offset + alignment - 1) / alignment * alignment
+}
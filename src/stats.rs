@@ -0,0 +1,285 @@
+//! Statistics of the work a `RenderEngine` records each frame, for on-screen HUDs and performance
+//! logging.
+//!
+//! Statistics are off until `RenderEngine::enable_stats`, after which the engine times the
+//! recording of every clear, pass and dispatch on the CPU, brackets each of them with timestamps to
+//! time them on the GPU, and counts the draws it records. `RenderEngine::take_frame_stats` returns
+//! the totals since it was last called, so calling it once a frame gives per-frame statistics.
+//!
+//! With the `tracy` feature, and a Tracy client running, the timestamps are also sent to Tracy as
+//! GPU zones named after the label of each pass (see `RenderEngine::set_label`).
+
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use rk::{device::Device, vk};
+
+use crate::{destruction::DestructionQueue, raw_device, raw_instance, raw_physical_device, Context, MarsResult};
+
+/// The number of timestamp queries available to the submissions in flight. Each pass takes two, and
+/// passes past the limit aren't timed on the GPU.
+const QUERY_COUNT: u32 = 256;
+
+/// What a `RenderEngine` recorded over a frame
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FrameStats {
+	/// The time spent recording commands, not counting waiting for submissions to complete
+	pub cpu_record_time: Duration,
+	/// The time the device spent executing the recorded commands, or `None` if the queue can't
+	/// write timestamps
+	pub gpu_time: Option<Duration>,
+	pub draw_calls: u32,
+	/// The triangles drawn, counting every three indices of a draw as a triangle. Indirect and mesh
+	/// draws count as draw calls, but their triangles aren't known on the CPU and aren't counted.
+	pub triangles: u64,
+}
+
+/// Collects the statistics of a `RenderEngine`
+pub(crate) struct StatsCollector {
+	device: Device,
+	destruction: DestructionQueue,
+	/// `None` if the queue can't write timestamps
+	query_pool: Option<vk::QueryPool>,
+	/// Nanoseconds per timestamp tick
+	timestamp_period: f64,
+	/// The bits of timestamps that hold the time
+	timestamp_mask: u64,
+	next_query: u32,
+	/// The passes timed since results were last read
+	pending: Vec<TimedPass>,
+	/// The passes of asynchronous submissions, read once the flag of their submission is set
+	in_flight: Vec<(Arc<AtomicBool>, Vec<TimedPass>)>,
+	gpu_nanos: f64,
+	frame: FrameStats,
+	#[cfg(feature = "tracy")]
+	tracy: Option<tracy_client::GpuContext>,
+}
+
+/// A pass bracketed by a pair of timestamp queries
+pub(crate) struct TimedPass {
+	query: u32,
+	#[cfg(feature = "tracy")]
+	span: Option<tracy_client::GpuSpan>,
+}
+
+impl StatsCollector {
+	pub(crate) fn create(context: &Context) -> MarsResult<Self> {
+		let instance = raw_instance(&context.instance);
+		let physical_device = raw_physical_device(&context.physical_device);
+		let (valid_bits, timestamp_period) = unsafe {
+			let families = instance.get_physical_device_queue_family_properties(physical_device);
+			let properties = instance.get_physical_device_properties(physical_device);
+			(
+				families[context.queue_family_index as usize].timestamp_valid_bits,
+				properties.limits.timestamp_period,
+			)
+		};
+		let query_pool = if valid_bits == 0 {
+			None
+		} else {
+			let create_info = vk::QueryPoolCreateInfo::builder()
+				.query_type(vk::QueryType::TIMESTAMP)
+				.query_count(QUERY_COUNT);
+			Some(unsafe { raw_device(&context.device).create_query_pool(&create_info, None)? })
+		};
+		let mut collector = Self {
+			device: context.device.clone(),
+			destruction: context.destruction.clone(),
+			query_pool,
+			timestamp_period: timestamp_period as f64,
+			timestamp_mask: if valid_bits >= 64 {
+				u64::MAX
+			} else {
+				(1 << valid_bits) - 1
+			},
+			next_query: 0,
+			pending: Vec::new(),
+			in_flight: Vec::new(),
+			gpu_nanos: 0.0,
+			frame: FrameStats::default(),
+			#[cfg(feature = "tracy")]
+			tracy: None,
+		};
+		#[cfg(feature = "tracy")]
+		{
+			collector.tracy = collector.create_tracy_context(context)?;
+		}
+		Ok(collector)
+	}
+
+	/// Creates a Tracy GPU context if a Tracy client is running, calibrated with a timestamp read
+	/// from the device right away
+	#[cfg(feature = "tracy")]
+	fn create_tracy_context(&self, context: &Context) -> MarsResult<Option<tracy_client::GpuContext>> {
+		let (client, query_pool) = match (tracy_client::Client::running(), self.query_pool) {
+			(Some(client), Some(query_pool)) => (client, query_pool),
+			_ => return Ok(None),
+		};
+		let device = raw_device(&self.device);
+		crate::sync::one_time_submit(context, |command_buffer| unsafe {
+			device.cmd_reset_query_pool(command_buffer, query_pool, 0, 1);
+			device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool, 0);
+		})?;
+		let mut timestamp = [0u64];
+		unsafe {
+			device.get_query_pool_results(
+				query_pool,
+				0,
+				1,
+				&mut timestamp,
+				vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+			)?;
+		}
+		let gpu_context = client
+			.new_gpu_context(
+				Some("mars"),
+				tracy_client::GpuContextType::Vulkan,
+				(timestamp[0] & self.timestamp_mask) as i64,
+				self.timestamp_period as f32,
+			)
+			.map_err(|e| log::warn!("Failed to create a Tracy GPU context: {:?}", e))
+			.ok();
+		Ok(gpu_context)
+	}
+
+	/// Writes the timestamp starting a pass labelled `label`, if there are queries left. Must be
+	/// recorded outside of a render pass.
+	pub(crate) unsafe fn begin(&mut self, command_buffer: vk::CommandBuffer, label: &str) -> Option<TimedPass> {
+		let query_pool = self.query_pool?;
+		if self.next_query + 2 > QUERY_COUNT {
+			if self.in_flight.is_empty() {
+				return None;
+			}
+			// Asynchronous submissions keep their queries until they complete, so the queries wrap
+			// around to the ones that have been read
+			self.next_query = 0;
+		}
+		let query = self.next_query;
+		let in_use = |pass: &TimedPass| pass.query == query;
+		if self.pending.iter().any(in_use) || self.in_flight.iter().any(|(_, passes)| passes.iter().any(in_use)) {
+			return None;
+		}
+		self.next_query += 2;
+		let device = raw_device(&self.device);
+		device.cmd_reset_query_pool(command_buffer, query_pool, query, 2);
+		device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, query_pool, query);
+		#[cfg(not(feature = "tracy"))]
+		let _ = label;
+		Some(TimedPass {
+			query,
+			#[cfg(feature = "tracy")]
+			span: self
+				.tracy
+				.as_ref()
+				.and_then(|tracy| tracy.span_alloc(label, "RenderEngine::record", file!(), line!()).ok()),
+		})
+	}
+
+	/// Writes the timestamp ending a pass begun with `begin`
+	pub(crate) unsafe fn end(&mut self, command_buffer: vk::CommandBuffer, pass: Option<TimedPass>) {
+		if let (Some(query_pool), Some(mut pass)) = (self.query_pool, pass) {
+			raw_device(&self.device).cmd_write_timestamp(
+				command_buffer,
+				vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+				query_pool,
+				pass.query + 1,
+			);
+			#[cfg(feature = "tracy")]
+			if let Some(span) = &mut pass.span {
+				span.end_zone();
+			}
+			self.pending.push(pass);
+		}
+	}
+
+	/// Adds up the timestamps written by the submissions that have completed. Passes whose
+	/// submission failed never wrote theirs, and are skipped.
+	pub(crate) fn resolve(&mut self) {
+		let pending = std::mem::take(&mut self.pending);
+		self.read(pending);
+		let (complete, in_flight) = std::mem::take(&mut self.in_flight)
+			.into_iter()
+			.partition::<Vec<_>, _>(|(complete, _)| complete.load(Ordering::Acquire));
+		self.in_flight = in_flight;
+		for (_, passes) in complete {
+			self.read(passes);
+		}
+		if self.in_flight.is_empty() {
+			self.next_query = 0;
+		}
+	}
+
+	/// Hands the passes timed since results were last read to an asynchronous submission, returning
+	/// the flag to set once it has completed. They're read by the first `resolve` after that.
+	pub(crate) fn submit_async(&mut self) -> Arc<AtomicBool> {
+		let complete = Arc::new(AtomicBool::new(false));
+		let passes = std::mem::take(&mut self.pending);
+		self.in_flight.push((complete.clone(), passes));
+		complete
+	}
+
+	fn read(&mut self, passes: Vec<TimedPass>) {
+		let query_pool = match self.query_pool {
+			Some(query_pool) => query_pool,
+			None => return,
+		};
+		let device = raw_device(&self.device);
+		for pass in passes {
+			let mut timestamps = [0u64; 2];
+			let result = unsafe {
+				device.get_query_pool_results(
+					query_pool,
+					pass.query,
+					2,
+					&mut timestamps,
+					vk::QueryResultFlags::TYPE_64,
+				)
+			};
+			if result.is_ok() {
+				let [start, end] = [timestamps[0] & self.timestamp_mask, timestamps[1] & self.timestamp_mask];
+				let ticks = end.wrapping_sub(start) & self.timestamp_mask;
+				self.gpu_nanos += ticks as f64 * self.timestamp_period;
+				#[cfg(feature = "tracy")]
+				if let Some(span) = pass.span {
+					span.upload_timestamp(start as i64, end as i64);
+				}
+			}
+		}
+	}
+
+	pub(crate) fn add_cpu_time(&mut self, time: Duration) {
+		self.frame.cpu_record_time += time;
+	}
+
+	/// Counts a draw of `index_count` indices, or of an unknown number for indirect and mesh draws
+	pub(crate) fn count_draw(&mut self, index_count: Option<u32>) {
+		self.frame.draw_calls += 1;
+		self.frame.triangles += index_count.map_or(0, |index_count| index_count as u64 / 3);
+	}
+
+	/// Returns the statistics collected since the last call, and starts collecting anew
+	pub(crate) fn take(&mut self) -> FrameStats {
+		let mut frame = std::mem::take(&mut self.frame);
+		if self.query_pool.is_some() {
+			frame.gpu_time = Some(Duration::from_nanos(self.gpu_nanos as u64));
+		}
+		self.gpu_nanos = 0.0;
+		frame
+	}
+}
+
+impl Drop for StatsCollector {
+	fn drop(&mut self) {
+		if let Some(query_pool) = self.query_pool {
+			// Asynchronous submissions may still be writing timestamps
+			let device = self.device.clone();
+			self.destruction
+				.defer(move || unsafe { raw_device(&device).destroy_query_pool(query_pool, None) });
+		}
+	}
+}
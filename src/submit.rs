@@ -0,0 +1,152 @@
+//! Asynchronous submission of recorded frames.
+//!
+//! `RenderEngine::end_frame_async` submits a frame without waiting for it, returning a
+//! `SubmitHandle` that tracks it with a fence. The handle signals a semaphore once the frame
+//! completes, which `WindowEngine::present_after` waits on on the device, so the next frame can be
+//! recorded while the device renders and presents this one.
+
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc,
+};
+
+use rk::{device::Device, vk};
+
+use crate::{
+	destruction::{DestructionQueue, RecordedUses},
+	raw_device, raw_queue, sync, Context, MarsResult,
+};
+
+/// A submission that may still be executing. Dropping the handle waits for it to complete, since
+/// its command buffers can only be freed after that.
+///
+/// Handles free their command buffers into the pool of the thread that recorded them, so they
+/// can't be sent to other threads.
+pub struct SubmitHandle {
+	device: Device,
+	destruction: DestructionQueue,
+	fence: vk::Fence,
+	/// Signaled along with the fence, for presentation to wait on
+	semaphore: vk::Semaphore,
+	/// Whether something has waited on the semaphore, which can only be waited on once
+	semaphore_waited: bool,
+	serial: u64,
+	/// Set once the submission has completed, for statistics of its passes to be read
+	stats_complete: Option<Arc<AtomicBool>>,
+	/// Frees the command buffers of the submission, `None` once it has completed
+	release: Option<Box<dyn FnOnce()>>,
+}
+
+impl SubmitHandle {
+	/// Submits `command_buffer` to the context's queue without waiting for it. `recorded` are the
+	/// resources recorded into it. `release` frees the command buffer and anything else the
+	/// submission uses, and is called once it completes, or right away if submitting fails.
+	pub(crate) fn submit(
+		context: &Context,
+		command_buffer: vk::CommandBuffer,
+		recorded: &RecordedUses,
+		stats_complete: Option<Arc<AtomicBool>>,
+		release: Box<dyn FnOnce()>,
+	) -> MarsResult<Self> {
+		let device = raw_device(&context.device);
+		let (fence, semaphore) = unsafe {
+			let created = device
+				.create_fence(&vk::FenceCreateInfo::default(), None)
+				.and_then(
+					|fence| match device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) {
+						Ok(semaphore) => Ok((fence, semaphore)),
+						Err(e) => {
+							device.destroy_fence(fence, None);
+							Err(e)
+						}
+					},
+				);
+			match created {
+				Ok(created) => created,
+				Err(e) => {
+					release();
+					return Err(e);
+				}
+			}
+		};
+		let serial = context.destruction.begin_submission(recorded);
+		let mut handle = Self {
+			device: context.device.clone(),
+			destruction: context.destruction.clone(),
+			fence,
+			semaphore,
+			semaphore_waited: false,
+			serial,
+			stats_complete,
+			release: Some(release),
+		};
+		let queue = &context.queue;
+		let submitted = queue.with_lock(|| unsafe {
+			sync::queue_submit(context, raw_queue(queue), command_buffer, &[], &[semaphore], fence)
+		});
+		if let Err(e) = submitted {
+			// Nothing was submitted, so there's nothing to wait for
+			handle.complete();
+			return Err(e);
+		}
+		Ok(handle)
+	}
+
+	/// Returns whether the submission has completed, without blocking
+	pub fn is_complete(&mut self) -> MarsResult<bool> {
+		if self.release.is_none() {
+			return Ok(true);
+		}
+		let complete = unsafe { raw_device(&self.device).get_fence_status(self.fence)? };
+		if complete {
+			self.complete();
+		}
+		Ok(complete)
+	}
+
+	/// Blocks until the submission has completed
+	pub fn wait(&mut self) -> MarsResult<()> {
+		if self.release.is_some() {
+			unsafe { raw_device(&self.device).wait_for_fences(&[self.fence], true, u64::MAX)? };
+			self.complete();
+		}
+		Ok(())
+	}
+
+	/// Returns the semaphore signaled once the submission completes, for the next submission to
+	/// wait on, or `None` if it's already been waited on
+	pub(crate) fn take_semaphore(&mut self) -> Option<vk::Semaphore> {
+		if self.semaphore_waited {
+			None
+		} else {
+			self.semaphore_waited = true;
+			Some(self.semaphore)
+		}
+	}
+
+	fn complete(&mut self) {
+		if let Some(release) = self.release.take() {
+			release();
+			if let Some(stats_complete) = &self.stats_complete {
+				stats_complete.store(true, Ordering::Release);
+			}
+			self.destruction.end_submission(self.serial);
+		}
+	}
+}
+
+impl Drop for SubmitHandle {
+	fn drop(&mut self) {
+		if let Err(e) = self.wait() {
+			log::error!("Failed to wait for a submission: {:?}", e);
+			self.complete();
+		}
+		unsafe { raw_device(&self.device).destroy_fence(self.fence, None) };
+		// A submission waiting on the semaphore was submitted after this one, so it can still be
+		// executing
+		let semaphore = self.semaphore;
+		let device = self.device.clone();
+		self.destruction
+			.defer(move || unsafe { raw_device(&device).destroy_semaphore(semaphore, None) });
+	}
+}
@@ -0,0 +1,420 @@
+use std::ops::Range;
+
+use rk::{
+	command::{CommandBuffer, CommandPool},
+	device::Queue,
+	vk,
+};
+
+use crate::{destruction::RecordedUses, fault, raw_command_buffer, raw_device, raw_queue, Context, MarsResult};
+
+/// A layout transition of a whole image, along with the memory dependency around it.
+///
+/// Stage and access masks are given in their `VK_KHR_synchronization2` form, and are converted to
+/// the closest legacy masks when the extension isn't available.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ImageTransition {
+	pub aspect: vk::ImageAspectFlags,
+	pub src_stage_mask: vk::PipelineStageFlags2KHR,
+	pub dst_stage_mask: vk::PipelineStageFlags2KHR,
+	pub src_access_mask: vk::AccessFlags2KHR,
+	pub dst_access_mask: vk::AccessFlags2KHR,
+	pub old_layout: vk::ImageLayout,
+	pub new_layout: vk::ImageLayout,
+}
+
+pub(crate) unsafe fn record_image_transition(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	image: vk::Image,
+	transition: &ImageTransition,
+) {
+	record_queue_transfer(
+		context,
+		command_buffer,
+		image,
+		transition,
+		vk::QUEUE_FAMILY_IGNORED,
+		vk::QUEUE_FAMILY_IGNORED,
+	);
+}
+
+/// Records one half of a transfer of an image's ownership from one queue family to another, along
+/// with a layout transition. The same transfer has to be recorded on a queue of each family, first
+/// releasing the image on the source family and then acquiring it on the destination family.
+pub(crate) unsafe fn record_queue_transfer(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	image: vk::Image,
+	transition: &ImageTransition,
+	src_queue_family_index: u32,
+	dst_queue_family_index: u32,
+) {
+	let subresource_range = vk::ImageSubresourceRange {
+		aspect_mask: transition.aspect,
+		base_mip_level: 0,
+		level_count: vk::REMAINING_MIP_LEVELS,
+		base_array_layer: 0,
+		layer_count: vk::REMAINING_ARRAY_LAYERS,
+	};
+	record_image_barrier(
+		context,
+		command_buffer,
+		image,
+		transition,
+		subresource_range,
+		src_queue_family_index,
+		dst_queue_family_index,
+	);
+}
+
+/// Like `record_image_transition`, but only transitions mip levels `mips` of every layer, for
+/// images whose levels are in different layouts while a mip chain is generated
+pub(crate) unsafe fn record_mip_transition(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	image: vk::Image,
+	transition: &ImageTransition,
+	mips: Range<u32>,
+) {
+	let subresource_range = vk::ImageSubresourceRange {
+		aspect_mask: transition.aspect,
+		base_mip_level: mips.start,
+		level_count: mips.end - mips.start,
+		base_array_layer: 0,
+		layer_count: vk::REMAINING_ARRAY_LAYERS,
+	};
+	record_image_barrier(
+		context,
+		command_buffer,
+		image,
+		transition,
+		subresource_range,
+		vk::QUEUE_FAMILY_IGNORED,
+		vk::QUEUE_FAMILY_IGNORED,
+	);
+}
+
+unsafe fn record_image_barrier(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	image: vk::Image,
+	transition: &ImageTransition,
+	subresource_range: vk::ImageSubresourceRange,
+	src_queue_family_index: u32,
+	dst_queue_family_index: u32,
+) {
+	if let Some(synchronization2) = &context.synchronization2 {
+		let barriers = [vk::ImageMemoryBarrier2KHR::builder()
+			.src_stage_mask(transition.src_stage_mask)
+			.src_access_mask(transition.src_access_mask)
+			.dst_stage_mask(transition.dst_stage_mask)
+			.dst_access_mask(transition.dst_access_mask)
+			.old_layout(transition.old_layout)
+			.new_layout(transition.new_layout)
+			.src_queue_family_index(src_queue_family_index)
+			.dst_queue_family_index(dst_queue_family_index)
+			.image(image)
+			.subresource_range(subresource_range)
+			.build()];
+		let dependency_info = vk::DependencyInfoKHR::builder().image_memory_barriers(&barriers);
+		synchronization2.cmd_pipeline_barrier2(command_buffer, &dependency_info);
+	} else {
+		let barriers = [vk::ImageMemoryBarrier::builder()
+			.src_access_mask(legacy_access(transition.src_access_mask))
+			.dst_access_mask(legacy_access(transition.dst_access_mask))
+			.old_layout(transition.old_layout)
+			.new_layout(transition.new_layout)
+			.src_queue_family_index(src_queue_family_index)
+			.dst_queue_family_index(dst_queue_family_index)
+			.image(image)
+			.subresource_range(subresource_range)
+			.build()];
+		raw_device(&context.device).cmd_pipeline_barrier(
+			command_buffer,
+			legacy_stages(transition.src_stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE),
+			legacy_stages(transition.dst_stage_mask, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+			vk::DependencyFlags::empty(),
+			&[],
+			&[],
+			&barriers,
+		);
+	}
+}
+
+/// Records a global memory barrier
+pub(crate) unsafe fn record_memory_barrier(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	src_stage_mask: vk::PipelineStageFlags2KHR,
+	src_access_mask: vk::AccessFlags2KHR,
+	dst_stage_mask: vk::PipelineStageFlags2KHR,
+	dst_access_mask: vk::AccessFlags2KHR,
+) {
+	if let Some(synchronization2) = &context.synchronization2 {
+		let barriers = [vk::MemoryBarrier2KHR::builder()
+			.src_stage_mask(src_stage_mask)
+			.src_access_mask(src_access_mask)
+			.dst_stage_mask(dst_stage_mask)
+			.dst_access_mask(dst_access_mask)
+			.build()];
+		let dependency_info = vk::DependencyInfoKHR::builder().memory_barriers(&barriers);
+		synchronization2.cmd_pipeline_barrier2(command_buffer, &dependency_info);
+	} else {
+		let barriers = [vk::MemoryBarrier::builder()
+			.src_access_mask(legacy_access(src_access_mask))
+			.dst_access_mask(legacy_access(dst_access_mask))
+			.build()];
+		raw_device(&context.device).cmd_pipeline_barrier(
+			command_buffer,
+			legacy_stages(src_stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE),
+			legacy_stages(dst_stage_mask, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+			vk::DependencyFlags::empty(),
+			&barriers,
+			&[],
+			&[],
+		);
+	}
+}
+
+/// Records a memory barrier covering the whole of each of `buffers`
+pub(crate) unsafe fn record_buffer_barrier(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	buffers: &[vk::Buffer],
+	src_stage_mask: vk::PipelineStageFlags2KHR,
+	src_access_mask: vk::AccessFlags2KHR,
+	dst_stage_mask: vk::PipelineStageFlags2KHR,
+	dst_access_mask: vk::AccessFlags2KHR,
+) {
+	if let Some(synchronization2) = &context.synchronization2 {
+		let barriers = buffers
+			.iter()
+			.map(|&buffer| {
+				vk::BufferMemoryBarrier2KHR::builder()
+					.src_stage_mask(src_stage_mask)
+					.src_access_mask(src_access_mask)
+					.dst_stage_mask(dst_stage_mask)
+					.dst_access_mask(dst_access_mask)
+					.src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+					.dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+					.buffer(buffer)
+					.offset(0)
+					.size(vk::WHOLE_SIZE)
+					.build()
+			})
+			.collect::<Vec<_>>();
+		let dependency_info = vk::DependencyInfoKHR::builder().buffer_memory_barriers(&barriers);
+		synchronization2.cmd_pipeline_barrier2(command_buffer, &dependency_info);
+	} else {
+		let barriers = buffers
+			.iter()
+			.map(|&buffer| {
+				vk::BufferMemoryBarrier::builder()
+					.src_access_mask(legacy_access(src_access_mask))
+					.dst_access_mask(legacy_access(dst_access_mask))
+					.src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+					.dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+					.buffer(buffer)
+					.offset(0)
+					.size(vk::WHOLE_SIZE)
+					.build()
+			})
+			.collect::<Vec<_>>();
+		raw_device(&context.device).cmd_pipeline_barrier(
+			command_buffer,
+			legacy_stages(src_stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE),
+			legacy_stages(dst_stage_mask, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+			vk::DependencyFlags::empty(),
+			&[],
+			&barriers,
+			&[],
+		);
+	}
+}
+
+/// A semaphore a submission waits on before the given stages execute
+pub(crate) type SemaphoreWait = (vk::Semaphore, vk::PipelineStageFlags2KHR);
+
+/// Records commands into a fresh command buffer from the context's pool, submits it, and waits for
+/// it to complete. The pool stays locked until the command buffer is freed.
+pub(crate) fn one_time_submit<R: FnOnce(vk::CommandBuffer)>(context: &Context, record: R) -> MarsResult<()> {
+	one_time_submit_after(context, None, record)
+}
+
+/// Like `one_time_submit`, but the submission waits on `wait` first, if given
+pub(crate) fn one_time_submit_after<R: FnOnce(vk::CommandBuffer)>(
+	context: &Context,
+	wait: Option<SemaphoreWait>,
+	record: R,
+) -> MarsResult<()> {
+	let command_pool = context.command_pool.lock().unwrap();
+	let waits = wait.as_ref().map_or(&[][..], std::slice::from_ref);
+	record_and_submit(context, &command_pool, &context.queue, waits, record)
+}
+
+/// Like `one_time_submit`, but allocates the command buffer from `command_pool`, which must not be
+/// used by any other thread until this returns, and submits it to `queue`, which must be one of the
+/// context's queues of the pool's family
+pub(crate) fn one_time_submit_with_pool<R: FnOnce(vk::CommandBuffer)>(
+	context: &Context,
+	command_pool: &CommandPool,
+	queue: &Queue,
+	record: R,
+) -> MarsResult<()> {
+	record_and_submit(context, command_pool, queue, &[], record)
+}
+
+fn record_and_submit<R: FnOnce(vk::CommandBuffer)>(
+	context: &Context,
+	command_pool: &CommandPool,
+	queue: &Queue,
+	waits: &[SemaphoreWait],
+	record: R,
+) -> MarsResult<()> {
+	let command_buffer = CommandBuffer::allocate(command_pool)?;
+	let command_buffer = command_buffer.begin()?;
+	let raw = raw_command_buffer(&command_buffer);
+	record(raw);
+	let command_buffer = command_buffer.end()?;
+	submit_and_wait_after(context, queue, raw, waits, &RecordedUses::default())?;
+	drop(command_buffer);
+	Ok(())
+}
+
+/// Submits a command buffer to the context's queue and blocks until it has finished executing.
+/// `recorded` are the resources recorded into the command buffer.
+pub(crate) fn submit_and_wait(
+	context: &Context,
+	command_buffer: vk::CommandBuffer,
+	recorded: &RecordedUses,
+) -> MarsResult<()> {
+	submit_and_wait_after(context, &context.queue, command_buffer, &[], recorded)
+}
+
+/// Like `submit_and_wait`, but submits to `queue`, which must be one of the context's queues
+pub(crate) fn submit_and_wait_on(
+	context: &Context,
+	queue: &Queue,
+	command_buffer: vk::CommandBuffer,
+) -> MarsResult<()> {
+	submit_and_wait_after(context, queue, command_buffer, &[], &RecordedUses::default())
+}
+
+/// Like `submit_and_wait_on`, but the submission waits on `waits` first
+fn submit_and_wait_after(
+	context: &Context,
+	queue: &Queue,
+	command_buffer: vk::CommandBuffer,
+	waits: &[SemaphoreWait],
+	recorded: &RecordedUses,
+) -> MarsResult<()> {
+	trace_span!("submit_and_wait");
+	let device = raw_device(&context.device);
+	unsafe {
+		let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+		let serial = context.destruction.begin_submission(recorded);
+		let result = queue
+			.with_lock(|| queue_submit(context, raw_queue(queue), command_buffer, waits, &[], fence))
+			.and_then(|()| device.wait_for_fences(&[fence], true, u64::MAX));
+		device.destroy_fence(fence, None);
+		context.destruction.end_submission(serial);
+		if result == Err(vk::Result::ERROR_DEVICE_LOST) {
+			fault::report_device_lost(context, raw_queue(queue));
+		}
+		result
+	}
+}
+
+/// Submits a command buffer to one of the context's queues, waiting on `waits` before it executes
+/// and signaling `signals` and `fence` once it completes. The queue must already be locked.
+pub(crate) unsafe fn queue_submit(
+	context: &Context,
+	queue: vk::Queue,
+	command_buffer: vk::CommandBuffer,
+	waits: &[SemaphoreWait],
+	signals: &[vk::Semaphore],
+	fence: vk::Fence,
+) -> MarsResult<()> {
+	if let Some(synchronization2) = &context.synchronization2 {
+		let wait_infos = waits
+			.iter()
+			.map(|&(semaphore, stage_mask)| {
+				vk::SemaphoreSubmitInfoKHR::builder()
+					.semaphore(semaphore)
+					.stage_mask(stage_mask)
+					.build()
+			})
+			.collect::<Vec<_>>();
+		let signal_infos = signals
+			.iter()
+			.map(|&semaphore| {
+				vk::SemaphoreSubmitInfoKHR::builder()
+					.semaphore(semaphore)
+					.stage_mask(vk::PipelineStageFlags2KHR::ALL_COMMANDS)
+					.build()
+			})
+			.collect::<Vec<_>>();
+		let command_buffer_infos = [vk::CommandBufferSubmitInfoKHR::builder()
+			.command_buffer(command_buffer)
+			.build()];
+		let submit_info = vk::SubmitInfo2KHR::builder()
+			.wait_semaphore_infos(&wait_infos)
+			.command_buffer_infos(&command_buffer_infos)
+			.signal_semaphore_infos(&signal_infos)
+			.build();
+		synchronization2.queue_submit2(queue, &[submit_info], fence)
+	} else {
+		let wait_semaphores = waits.iter().map(|&(semaphore, _)| semaphore).collect::<Vec<_>>();
+		let wait_stages = waits
+			.iter()
+			.map(|&(_, stage_mask)| legacy_stages(stage_mask, vk::PipelineStageFlags::TOP_OF_PIPE))
+			.collect::<Vec<_>>();
+		let command_buffers = [command_buffer];
+		let submit_info = vk::SubmitInfo::builder()
+			.wait_semaphores(&wait_semaphores)
+			.wait_dst_stage_mask(&wait_stages)
+			.command_buffers(&command_buffers)
+			.signal_semaphores(signals)
+			.build();
+		raw_device(&context.device).queue_submit(queue, &[submit_info], fence)
+	}
+}
+
+fn legacy_stages(stages: vk::PipelineStageFlags2KHR, if_empty: vk::PipelineStageFlags) -> vk::PipelineStageFlags {
+	type Stages2 = vk::PipelineStageFlags2KHR;
+
+	// The stages from the original API keep their bit positions, the new ones need mapping down to
+	// the (coarser) stage that used to contain them
+	let mut legacy = vk::PipelineStageFlags::from_raw(stages.as_raw() as u32);
+	if stages.intersects(Stages2::COPY | Stages2::RESOLVE | Stages2::BLIT | Stages2::CLEAR) {
+		legacy |= vk::PipelineStageFlags::TRANSFER;
+	}
+	if stages.intersects(Stages2::INDEX_INPUT | Stages2::VERTEX_ATTRIBUTE_INPUT) {
+		legacy |= vk::PipelineStageFlags::VERTEX_INPUT;
+	}
+	if stages.intersects(Stages2::PRE_RASTERIZATION_SHADERS) {
+		legacy |= vk::PipelineStageFlags::VERTEX_SHADER
+			| vk::PipelineStageFlags::TESSELLATION_CONTROL_SHADER
+			| vk::PipelineStageFlags::TESSELLATION_EVALUATION_SHADER
+			| vk::PipelineStageFlags::GEOMETRY_SHADER;
+	}
+	if legacy.is_empty() {
+		if_empty
+	} else {
+		legacy
+	}
+}
+
+fn legacy_access(access: vk::AccessFlags2KHR) -> vk::AccessFlags {
+	type Access2 = vk::AccessFlags2KHR;
+
+	let mut legacy = vk::AccessFlags::from_raw(access.as_raw() as u32);
+	if access.intersects(Access2::SHADER_SAMPLED_READ | Access2::SHADER_STORAGE_READ) {
+		legacy |= vk::AccessFlags::SHADER_READ;
+	}
+	if access.intersects(Access2::SHADER_STORAGE_WRITE) {
+		legacy |= vk::AccessFlags::SHADER_WRITE;
+	}
+	legacy
+}
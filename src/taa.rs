@@ -0,0 +1,231 @@
+//! Temporal anti-aliasing, which smooths edges by accumulating frames rendered with different
+//! sub-pixel offsets.
+//!
+//! Each frame, the scene is drawn with its projection offset by `math::taa_jitter`, using
+//! `math::jitter_projection`, into an `OffscreenTarget` with a velocity attachment alongside its
+//! color. The velocity attachment is a `ColorAttachment<VelocityFormat>` cleared to zero, which
+//! functions write the screen space motion of each surface into with `VELOCITY_GLSL`, from the clip
+//! space positions of this frame and the last computed without jitter. `TaaResolver::apply` then
+//! blends the new frame with the history in a `HistoryTarget`, following the motion to where each
+//! pixel was in the last frame, and leaves the result in front of the history target.
+
+use std::marker::PhantomData;
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, UniformBufferUsage},
+	builtin::{CachedArguments, FullscreenTriangle},
+	function::{
+		compile_shader, ArgumentsContainer, Binding, BindingDesc, BindingType, FunctionDef, FunctionImpl,
+		FunctionPrototype,
+	},
+	image::{format::R16G16Sfloat, FormatType, SampleCount1},
+	math::*,
+	pass::{ColorAttachment, ColorClearValue, NoDepthAttachment, RenderPass, RenderPassPrototype},
+	render::RenderEngine,
+	shader::ShaderStage,
+	target::{HistoryTarget, OffscreenTarget, TargetOutput},
+	Context, MarsResult,
+};
+
+/// GLSL for the value functions write into velocity attachments, given the clip space position of
+/// a surface this frame and the last, both without jitter. The velocity is how far the surface
+/// moved since the last frame in texture coordinates.
+pub const VELOCITY_GLSL: &str = r#"
+vec2 taa_velocity(vec4 current_clip, vec4 previous_clip) {
+	return (current_clip.xy / current_clip.w - previous_clip.xy / previous_clip.w) * 0.5;
+}
+"#;
+
+const RESOLVE_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = position * 0.5 + 0.5;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const RESOLVE_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D current;
+layout(set = 0, binding = 1) uniform sampler2D velocity;
+layout(set = 0, binding = 2) uniform sampler2D history;
+layout(set = 0, binding = 3) uniform Resolve {
+	float blend;
+	uint has_history;
+};
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	vec4 current_color = texture(current, uv);
+	vec2 previous_uv = uv - texture(velocity, uv).xy;
+	bool on_screen = all(greaterThanEqual(previous_uv, vec2(0.0))) && all(lessThanEqual(previous_uv, vec2(1.0)));
+	if (has_history == 0 || !on_screen) {
+		color = current_color;
+		return;
+	}
+
+	// History outside the range of the pixel's neighborhood this frame is of something that's no
+	// longer visible there, and would otherwise leave ghosts behind moving objects
+	vec2 texel = 1.0 / vec2(textureSize(current, 0));
+	vec3 low = current_color.rgb;
+	vec3 high = current_color.rgb;
+	for (int y = -1; y <= 1; y++) {
+		for (int x = -1; x <= 1; x++) {
+			vec3 neighbor = texture(current, uv + vec2(x, y) * texel).rgb;
+			low = min(low, neighbor);
+			high = max(high, neighbor);
+		}
+	}
+	vec3 previous = clamp(texture(history, previous_uv).rgb, low, high);
+	color = vec4(mix(previous, current_color.rgb, blend), current_color.a);
+}
+"#;
+
+/// The format of velocity attachments, holding the motion of each pixel in texture coordinates
+pub type VelocityFormat = R16G16Sfloat;
+
+/// The render pass TAA resolves into, with a single color attachment of format `F`. History
+/// targets for a `TaaResolver` are made of it.
+pub struct TaaPass<F>(PhantomData<F>);
+
+impl<F> RenderPassPrototype for TaaPass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<F>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+struct ResolveFunction<F>(PhantomData<F>);
+
+impl<F> FunctionPrototype for ResolveFunction<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type RenderPass = TaaPass<F>;
+	type VertexInput = Vec2;
+	type Bindings = (TargetOutput, TargetOutput, TargetOutput, ResolveParams);
+	type PushConstants = ();
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct ResolveParams {
+	blend: f32,
+	has_history: u32,
+	_padding: [f32; 2],
+}
+
+unsafe impl Binding for ResolveParams {
+	type Argument = Buffer<UniformBufferUsage, ResolveParams>;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::Uniform,
+			count: 1,
+		}
+	}
+}
+
+/// The views of the color, velocity and history a set of resolve arguments reads
+type ResolveInputs = (vk::ImageView, vk::ImageView, vk::ImageView);
+
+/// Blends new frames into the history of `HistoryTarget<TaaPass<F>>`s. See the module
+/// documentation.
+pub struct TaaResolver<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	render_pass: RenderPass<TaaPass<F>>,
+	function: FunctionDef<ResolveFunction<F>>,
+	blend: f32,
+	// One set of arguments for each history target being read
+	arguments: [CachedArguments<ResolveInputs, ArgumentsContainer<ResolveFunction<F>>>; 2],
+	triangle: FullscreenTriangle,
+}
+
+impl<F> TaaResolver<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	/// Creates a resolver giving new frames a weight of `blend` against the history, where lower
+	/// values smooth more but take longer to catch up with changes. 0.1 is typical.
+	pub fn create(context: &Context, blend: f32) -> MarsResult<Self> {
+		let render_pass = RenderPass::create(context)?;
+		let vert = compile_shader(RESOLVE_VERTEX_SHADER, "taa.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(RESOLVE_FRAGMENT_SHADER, "taa.frag", ShaderStage::Fragment)?;
+		let function = FunctionDef::create(context, &render_pass, unsafe { FunctionImpl::from_raw(vert, frag) })?;
+
+		Ok(Self {
+			render_pass,
+			function,
+			blend,
+			arguments: [CachedArguments::new(), CachedArguments::new()],
+			triangle: FullscreenTriangle::create(context)?,
+		})
+	}
+
+	/// The render pass to create history targets with
+	pub fn render_pass(&self) -> &RenderPass<TaaPass<F>> {
+		&self.render_pass
+	}
+
+	/// Blends color attachment `color` of `scene` into `history`, following the motion in its
+	/// velocity attachment `velocity`, and swaps the history so the result is in front. The history
+	/// target must be the size of the scene.
+	pub fn apply<G: RenderPassPrototype>(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		scene: &mut OffscreenTarget<G>,
+		color: usize,
+		velocity: usize,
+		history: &mut HistoryTarget<TaaPass<F>>,
+	) -> MarsResult<()> {
+		let params = ResolveParams {
+			blend: self.blend,
+			has_history: history.has_history() as u32,
+			_padding: [0.0; 2],
+		};
+		let slot = history.front_index();
+		let (back, front) = history.split();
+
+		let (current, motion, previous) = (scene.output(color), scene.output(velocity), front.output(0));
+		let inputs = (current.image_view, motion.image_view, previous.image_view);
+		let function = &mut self.function;
+		let arguments = self.arguments[slot].make_or_update(
+			inputs,
+			|| {
+				let params = Buffer::make_item_buffer(context, params)?;
+				function.make_arguments(context, (current, motion, previous, params))
+			},
+			|arguments| arguments.arguments.3.record_upload(context, engine, params),
+		)?;
+
+		let draw = self.triangle.draw_args(arguments);
+		scene.sample(context, engine, |engine| {
+			front.sample(context, engine, |engine| {
+				engine.pass(context, back.target(), function, Some(draw))
+			})
+		})?;
+		history.swap();
+		Ok(())
+	}
+}
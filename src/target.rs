@@ -1,22 +1,59 @@
 use std::sync::Arc;
 
-use rk::pass::{Framebuffer, RenderPassInner};
+use rk::{device::Device, vk};
+use thiserror::Error;
 
 use crate::{
-	pass::{Attachments, RenderPass, RenderPassPrototype},
+	builtin,
+	function::{Argument, Binding, BindingDesc, BindingType, WriteArgument, WriteSampledImageArgument},
+	image::{DynImageUsage, Sampler, SamplerHandle},
+	pass::{
+		framebuffer_layers, get_render_pass_desc, Attachments, ColorAttachments, RenderPass, RenderPassHandle,
+		RenderPassPrototype,
+	},
+	raw_device,
+	render::RenderEngine,
+	sync::{self, ImageTransition},
 	Context, MarsResult,
 };
 
+#[derive(Debug, Error)]
+pub enum TargetError {
+	#[error("The render pass has {expected} attachments but {found} were given")]
+	AttachmentCount { expected: usize, found: usize },
+	#[error("Attachment {index} has format {found:?} but the render pass expects {expected:?}")]
+	Format {
+		index: usize,
+		expected: vk::Format,
+		found: vk::Format,
+	},
+	#[error("Attachment {index} has sample count {found:?} but the render pass expects {expected:?}")]
+	SampleCount {
+		index: usize,
+		expected: vk::SampleCountFlags,
+		found: vk::SampleCountFlags,
+	},
+	#[error("Vulkan error: {0}")]
+	VulkanError(#[from] vk::Result),
+}
+
 pub struct Target<G: RenderPassPrototype> {
-	pub(crate) render_pass: Arc<RenderPassInner>,
+	pub(crate) render_pass: Arc<RenderPassHandle>,
 	pub(crate) attachments: Attachments<G>,
 	pub(crate) framebuffer: Framebuffer,
 }
 
 impl<G: RenderPassPrototype> Target<G> {
-	pub fn create(context: &Context, render_pass: &RenderPass<G>, attachments: Attachments<G>) -> MarsResult<Self> {
+	/// Creates a target rendering into `attachments`, which must match the attachments of the render
+	/// pass. Render passes used for a single subpass of a larger pass can't be given targets this way.
+	pub fn create(
+		context: &Context,
+		render_pass: &RenderPass<G>,
+		attachments: Attachments<G>,
+	) -> Result<Self, TargetError> {
 		let render_pass = render_pass.render_pass.clone();
-		let framebuffer = Self::create_framebuffer(context, &render_pass, &attachments)?;
+		validate_attachments::<G>(&render_pass)?;
+		let framebuffer = Framebuffer::create(context, &render_pass, &attachments)?;
 		Ok(Self {
 			render_pass,
 			attachments,
@@ -24,8 +61,9 @@ impl<G: RenderPassPrototype> Target<G> {
 		})
 	}
 
-	pub fn change_attachments(&mut self, context: &Context, attachments: Attachments<G>) -> MarsResult<()> {
-		self.framebuffer = Self::create_framebuffer(context, &self.render_pass, &attachments)?;
+	pub fn change_attachments(&mut self, context: &Context, attachments: Attachments<G>) -> Result<(), TargetError> {
+		validate_attachments::<G>(&self.render_pass)?;
+		self.framebuffer = Framebuffer::create(context, &self.render_pass, &attachments)?;
 		self.attachments = attachments;
 		Ok(())
 	}
@@ -37,15 +75,261 @@ impl<G: RenderPassPrototype> Target<G> {
 	pub fn color_attachments(&self) -> &G::ColorAttachments {
 		&self.attachments.color_attachments
 	}
+}
+
+/// A target rendered into off screen and then read by other functions, like a shadow map or a
+/// reflection. Its color attachments are created sampleable, and `sample` moves them into the layout
+/// shaders read from while the functions reading them are drawn, and back afterwards.
+pub struct OffscreenTarget<G: RenderPassPrototype> {
+	target: Target<G>,
+	sampler: Sampler,
+}
+
+impl<G: RenderPassPrototype> OffscreenTarget<G> {
+	pub fn create(context: &Context, render_pass: &RenderPass<G>, extent: vk::Extent2D) -> Result<Self, TargetError> {
+		let attachments = Attachments::create(context, extent, DynImageUsage::SAMPLED)?;
+		let target = Target::create(context, render_pass, attachments)?;
+		let sampler = Sampler::create(context)?;
+		Ok(Self { target, sampler })
+	}
+
+	/// Creates new attachments of `extent`. Arguments made from the old outputs must be made again.
+	pub fn resize(&mut self, context: &Context, extent: vk::Extent2D) -> Result<(), TargetError> {
+		let attachments = Attachments::create(context, extent, DynImageUsage::SAMPLED)?;
+		self.target.change_attachments(context, attachments)
+	}
+
+	/// The target to render into
+	pub fn target(&mut self) -> &mut Target<G> {
+		&mut self.target
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.target.attachments.extent
+	}
 
-	fn create_framebuffer(
+	/// The argument for a `TargetOutput` binding reading color attachment `index`, resolved if it's
+	/// multisampled. It must only be drawn with inside `sample`, and not outlive the target.
+	pub fn output(&self, index: usize) -> TargetOutputArgument {
+		let (_image, image_view) = self.target.attachments.color_attachments.outputs()[index];
+		TargetOutputArgument {
+			sampler: self.sampler.sampler.clone(),
+			image_view,
+		}
+	}
+
+	/// Makes the color attachments readable by shaders for the passes and dispatches recorded by
+	/// `record`, which can then draw functions bound to the target's outputs. The attachments go back
+	/// to being rendered into afterwards.
+	pub fn sample<T, R: FnOnce(&mut RenderEngine) -> MarsResult<T>>(
+		&mut self,
 		context: &Context,
-		render_pass: &Arc<RenderPassInner>,
+		engine: &mut RenderEngine,
+		record: R,
+	) -> MarsResult<T> {
+		let images = self
+			.target
+			.attachments
+			.color_attachments
+			.outputs()
+			.into_iter()
+			.map(|(image, _view)| image)
+			.collect::<Vec<_>>();
+		let (readable, renderable) = builtin::sampling_transitions(
+			vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+			vk::PipelineStageFlags2KHR::FRAGMENT_SHADER | vk::PipelineStageFlags2KHR::COMPUTE_SHADER,
+		);
+		let transition = |engine: &mut RenderEngine, transition: &ImageTransition| {
+			engine.record(context, |command_buffer| {
+				for &image in &images {
+					unsafe { sync::record_image_transition(context, command_buffer, image, transition) };
+				}
+			})
+		};
+
+		transition(engine, &readable)?;
+		let result = record(engine);
+		transition(engine, &renderable)?;
+		result
+	}
+}
+
+/// A pair of offscreen targets that take turns being rendered into, for effects that read what they
+/// rendered the frame before, like temporal anti-aliasing. Each frame renders into the back target,
+/// reading the front one as history, then `swap`s them so the new frame is in front.
+pub struct HistoryTarget<G: RenderPassPrototype> {
+	targets: [OffscreenTarget<G>; 2],
+	front: usize,
+	has_history: bool,
+}
+
+impl<G: RenderPassPrototype> HistoryTarget<G> {
+	pub fn create(context: &Context, render_pass: &RenderPass<G>, extent: vk::Extent2D) -> Result<Self, TargetError> {
+		Ok(Self {
+			targets: [
+				OffscreenTarget::create(context, render_pass, extent)?,
+				OffscreenTarget::create(context, render_pass, extent)?,
+			],
+			front: 0,
+			has_history: false,
+		})
+	}
+
+	/// Creates new attachments of `extent` for both targets, which discards the history
+	pub fn resize(&mut self, context: &Context, extent: vk::Extent2D) -> Result<(), TargetError> {
+		for target in &mut self.targets {
+			target.resize(context, extent)?;
+		}
+		self.has_history = false;
+		Ok(())
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.targets[0].extent()
+	}
+
+	/// Whether the front target holds a rendered frame. It doesn't until the first `swap` after the
+	/// targets are created or resized.
+	pub fn has_history(&self) -> bool {
+		self.has_history
+	}
+
+	/// The most recently completed frame
+	pub fn front(&mut self) -> &mut OffscreenTarget<G> {
+		&mut self.targets[self.front]
+	}
+
+	/// The target to render the next frame into
+	pub fn back(&mut self) -> &mut OffscreenTarget<G> {
+		&mut self.targets[1 - self.front]
+	}
+
+	/// The back and front targets at once, for rendering into one while sampling the other
+	pub fn split(&mut self) -> (&mut OffscreenTarget<G>, &mut OffscreenTarget<G>) {
+		let (first, second) = self.targets.split_at_mut(1);
+		if self.front == 0 {
+			(&mut second[0], &mut first[0])
+		} else {
+			(&mut first[0], &mut second[0])
+		}
+	}
+
+	/// Which of the two targets is in front, 0 or 1, for caching arguments made for each of them
+	pub fn front_index(&self) -> usize {
+		self.front
+	}
+
+	/// Moves the frame rendered into the back target to the front
+	pub fn swap(&mut self) {
+		self.front = 1 - self.front;
+		self.has_history = true;
+	}
+}
+
+/// A color attachment of an `OffscreenTarget`, sampled by a shader
+pub struct TargetOutput;
+
+unsafe impl Binding for TargetOutput {
+	type Argument = TargetOutputArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+pub struct TargetOutputArgument {
+	pub(crate) sampler: Arc<SamplerHandle>,
+	pub(crate) image_view: vk::ImageView,
+}
+
+impl Argument for TargetOutputArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		})
+	}
+}
+
+/// Checks that the attachments described by `G` can be used as the framebuffer of `render_pass`
+fn validate_attachments<G: RenderPassPrototype>(render_pass: &RenderPassHandle) -> Result<(), TargetError> {
+	let (attachments, _subpasses, _dependencies) = get_render_pass_desc::<G>();
+	if attachments.len() != render_pass.attachments.len() {
+		return Err(TargetError::AttachmentCount {
+			expected: render_pass.attachments.len(),
+			found: attachments.len(),
+		});
+	}
+	for (index, (attachment, &(format, samples))) in attachments.iter().zip(&render_pass.attachments).enumerate() {
+		if attachment.format != format {
+			return Err(TargetError::Format {
+				index,
+				expected: format,
+				found: attachment.format,
+			});
+		}
+		if attachment.samples != samples {
+			return Err(TargetError::SampleCount {
+				index,
+				expected: samples,
+				found: attachment.samples,
+			});
+		}
+	}
+	Ok(())
+}
+
+pub(crate) struct Framebuffer {
+	device: Device,
+	pub(crate) raw: vk::Framebuffer,
+}
+
+impl Framebuffer {
+	fn create<G: RenderPassPrototype>(
+		context: &Context,
+		render_pass: &RenderPassHandle,
 		attachments: &Attachments<G>,
-	) -> MarsResult<Framebuffer> {
-		let extent = attachments.extent();
-		context
-			.device
-			.create_framebuffer(render_pass, attachments.as_raw(), extent.width, extent.height, 1)
+	) -> MarsResult<Self> {
+		Self::create_raw(
+			context,
+			render_pass,
+			attachments.extent(),
+			framebuffer_layers::<G>(),
+			&attachments.as_raw(),
+		)
+	}
+
+	/// Creates a framebuffer with `layers` layers from image views given in the order of the render
+	/// pass attachments
+	pub(crate) fn create_raw(
+		context: &Context,
+		render_pass: &RenderPassHandle,
+		extent: vk::Extent2D,
+		layers: u32,
+		views: &[vk::ImageView],
+	) -> MarsResult<Self> {
+		let create_info = vk::FramebufferCreateInfo::builder()
+			.render_pass(render_pass.raw)
+			.attachments(views)
+			.width(extent.width)
+			.height(extent.height)
+			.layers(layers);
+		let raw = unsafe { raw_device(&context.device).create_framebuffer(&create_info, None)? };
+		Ok(Self {
+			device: context.device.clone(),
+			raw,
+		})
+	}
+}
+
+impl Drop for Framebuffer {
+	fn drop(&mut self) {
+		unsafe {
+			raw_device(&self.device).destroy_framebuffer(self.raw, None);
+		}
 	}
 }
@@ -0,0 +1,209 @@
+//! Utilities for rendering regression tests: rendering headlessly into offscreen attachments,
+//! reading the result back, and comparing it against reference ("golden") images.
+//!
+//! A test creates a context with `headless_context`, renders into a target made by
+//! `render_offscreen`, reads a color attachment back with `Image::read_pixels`, and checks it with
+//! `assert_matches_golden`. References are PNG files. A missing reference fails the test, unless
+//! the `MARS_UPDATE_GOLDEN` environment variable is set, which writes every reference from the
+//! rendered images, for adding new tests or when rendering changes on purpose. When an image is
+//! missing its reference or doesn't match it, it's written next to the reference with an
+//! `.actual.png` extension so the two can be compared by eye.
+
+use std::{env, path::Path};
+
+use image::RgbaImage;
+use rk::vk;
+
+use crate::{
+	device::PreferDiscrete,
+	image::DynImageUsage,
+	pass::{Attachments, RenderPass, RenderPassPrototype},
+	render::RenderEngine,
+	target::{Target, TargetError},
+	Context, ContextCreateError, MarsResult,
+};
+
+/// The environment variable that makes `assert_matches_golden` write references instead of
+/// comparing against them
+pub const UPDATE_GOLDEN_VAR: &str = "MARS_UPDATE_GOLDEN";
+
+/// Creates a context without any window, on the first discrete GPU or whatever else is available,
+/// that captures validation errors and warnings so tests can check for them with
+/// `Context::assert_no_validation_errors`
+pub fn headless_context(app_name: &str) -> Result<Context, ContextCreateError> {
+	let context = Context::create(app_name, PreferDiscrete)?;
+	context.capture_validation_messages(
+		vk::DebugUtilsMessageSeverityFlagsEXT::ERROR | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+	)?;
+	Ok(context)
+}
+
+/// Creates a target of `extent` whose color attachments can be read back, and renders into it with
+/// `render`
+pub fn render_offscreen<G, R>(
+	context: &Context,
+	engine: &mut RenderEngine,
+	render_pass: &RenderPass<G>,
+	extent: vk::Extent2D,
+	render: R,
+) -> Result<Target<G>, TargetError>
+where
+	G: RenderPassPrototype,
+	R: FnOnce(&mut RenderEngine, &mut Target<G>) -> MarsResult<()>,
+{
+	let attachments = Attachments::create(context, extent, DynImageUsage::TRANSFER_SRC)?;
+	let mut target = Target::create(context, render_pass, attachments)?;
+	render(engine, &mut target)?;
+	Ok(target)
+}
+
+/// How far an image is from a reference
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Comparison {
+	/// The number of texels with a channel differing by more than the tolerance
+	pub mismatched: usize,
+	/// The largest difference of any channel of any texel
+	pub max_difference: u8,
+}
+
+impl Comparison {
+	pub fn matches(&self) -> bool {
+		self.mismatched == 0
+	}
+}
+
+/// Compares RGBA8 texels, in rows from top to bottom, against a reference of the same extent. Texels
+/// only match if none of their channels differ by more than `tolerance`, which allows for the small
+/// differences in rasterization and filtering between devices.
+///
+/// # Panics
+/// If the reference is a different size than `extent`, or there aren't exactly as many texels as
+/// `extent` covers
+pub fn compare(actual: &[[u8; 4]], extent: vk::Extent2D, reference: &RgbaImage, tolerance: u8) -> Comparison {
+	assert_eq!(
+		actual.len(),
+		extent.width as usize * extent.height as usize,
+		"the rendered texels don't cover the extent"
+	);
+	assert_eq!(
+		(reference.width(), reference.height()),
+		(extent.width, extent.height),
+		"the reference image is a different size than the rendered one"
+	);
+	let mut comparison = Comparison {
+		mismatched: 0,
+		max_difference: 0,
+	};
+	for (texel, reference) in actual.iter().zip(reference.pixels()) {
+		let difference = texel
+			.iter()
+			.zip(&reference.0)
+			.map(|(&a, &b)| (a as i16 - b as i16).unsigned_abs() as u8)
+			.max()
+			.unwrap_or(0);
+		comparison.max_difference = comparison.max_difference.max(difference);
+		if difference > tolerance {
+			comparison.mismatched += 1;
+		}
+	}
+	comparison
+}
+
+/// Checks rendered RGBA8 texels against the reference PNG at `path`, panicking if more than
+/// `max_mismatched` texels differ from it by more than `tolerance`. See the module documentation
+/// for how references are created and updated.
+pub fn assert_matches_golden<P: AsRef<Path>>(
+	path: P,
+	actual: &[[u8; 4]],
+	extent: vk::Extent2D,
+	tolerance: u8,
+	max_mismatched: usize,
+) {
+	let path = path.as_ref();
+	let rendered = to_image(actual, extent);
+	let actual_path = path.with_extension("actual.png");
+	if env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+		log::warn!("Writing golden image {}", path.display());
+		rendered
+			.save(path)
+			.unwrap_or_else(|e| panic!("failed to write golden image {}: {}", path.display(), e));
+		return;
+	}
+	if !path.exists() {
+		let _ = rendered.save(&actual_path);
+		panic!(
+			"golden image {} doesn't exist. The rendered image was written to {}, and running with {} set \
+			 writes it as the reference.",
+			path.display(),
+			actual_path.display(),
+			UPDATE_GOLDEN_VAR
+		);
+	}
+
+	let reference = image::open(path)
+		.unwrap_or_else(|e| panic!("failed to read golden image {}: {}", path.display(), e))
+		.to_rgba();
+	let comparison = compare(actual, extent, &reference, tolerance);
+	if comparison.mismatched > max_mismatched {
+		let _ = rendered.save(&actual_path);
+		panic!(
+			"{} texels differ from golden image {} by up to {}, more than the {} allowed. The rendered image \
+			 was written to {}.",
+			comparison.mismatched,
+			path.display(),
+			comparison.max_difference,
+			max_mismatched,
+			actual_path.display()
+		);
+	}
+}
+
+fn to_image(texels: &[[u8; 4]], extent: vk::Extent2D) -> RgbaImage {
+	let bytes = texels.iter().flatten().copied().collect();
+	RgbaImage::from_raw(extent.width, extent.height, bytes).expect("texels don't match the extent")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const EXTENT: vk::Extent2D = vk::Extent2D { width: 2, height: 2 };
+
+	fn reference() -> RgbaImage {
+		to_image(
+			&[[0, 0, 0, 255], [255, 0, 0, 255], [0, 255, 0, 255], [10, 20, 30, 40]],
+			EXTENT,
+		)
+	}
+
+	#[test]
+	fn compare_counts_texels_beyond_the_tolerance() {
+		let same = [[0, 0, 0, 255], [255, 0, 0, 255], [0, 255, 0, 255], [10, 20, 30, 40]];
+		assert_eq!(
+			compare(&same, EXTENT, &reference(), 0),
+			Comparison {
+				mismatched: 0,
+				max_difference: 0,
+			}
+		);
+		let close = [[2, 0, 0, 255], [255, 0, 0, 250], [0, 255, 0, 255], [10, 23, 30, 40]];
+		let comparison = compare(&close, EXTENT, &reference(), 3);
+		assert_eq!(comparison.max_difference, 5);
+		assert_eq!(comparison.mismatched, 1);
+		assert!(!comparison.matches());
+		assert!(compare(&close, EXTENT, &reference(), 5).matches());
+	}
+
+	#[test]
+	#[should_panic(expected = "don't cover the extent")]
+	fn compare_rejects_too_few_texels() {
+		compare(&[[0, 0, 0, 255]; 3], EXTENT, &reference(), 0);
+	}
+
+	#[test]
+	#[should_panic(expected = "different size")]
+	fn compare_rejects_references_of_another_size() {
+		let extent = vk::Extent2D { width: 4, height: 1 };
+		compare(&[[0, 0, 0, 255]; 4], extent, &reference(), 0);
+	}
+}
@@ -0,0 +1,262 @@
+//! A post pass that tone maps an HDR color attachment down to an LDR target, like one with the
+//! format of the swapchain.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use rk::vk;
+
+use crate::{
+	buffer::{Buffer, StorageBufferUsage},
+	builtin::{self, CachedArguments, FullscreenTriangle},
+	exposure::{AutoExposure, ExposureState},
+	function::{
+		compile_shader, Argument, ArgumentsContainer, Binding, BindingDesc, BindingType, FunctionDef, FunctionImpl,
+		FunctionOptions, FunctionPrototype, RawStorage, RawStorageArgument, WriteArgument, WriteSampledImageArgument,
+	},
+	image::{format::R16G16B16A16Sfloat, DynImageUsage, FormatType, SampleCount1, Sampler, SamplerHandle},
+	math::*,
+	pass::{ColorAttachment, ColorClearValue, NoDepthAttachment, RenderPass, RenderPassPrototype},
+	render::RenderEngine,
+	shader::ShaderStage,
+	target::Target,
+	Context, MarsResult,
+};
+
+const TONE_MAP_VERTEX_SHADER: &str = r#"
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = position * 0.5 + 0.5;
+	gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const TONE_MAP_FRAGMENT_SHADER: &str = r#"
+#version 450
+
+layout(constant_id = 0) const uint OPERATOR = 0;
+
+layout(set = 0, binding = 0) uniform sampler2D hdr;
+layout(set = 0, binding = 1) readonly buffer Exposure {
+	float average_luminance;
+	float exposure;
+};
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+vec3 reinhard(vec3 x) {
+	return x / (1.0 + x);
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+	const float a = 2.51;
+	const float b = 0.03;
+	const float c = 2.43;
+	const float d = 0.59;
+	const float e = 0.14;
+	return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+void main() {
+	// Alpha is passed through untouched, for windows composited with what's behind them
+	vec4 radiance = texture(hdr, uv);
+	vec3 exposed = radiance.rgb * exposure;
+	vec3 mapped = OPERATOR == 0 ? reinhard(exposed) : aces(exposed);
+	color = vec4(mapped, radiance.a);
+}
+"#;
+
+/// The format of the HDR attachments consumed by tone mapping
+pub type HdrFormat = R16G16B16A16Sfloat;
+
+/// The curve used to map HDR colors into the displayable range
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ToneMapOperator {
+	Reinhard,
+	Aces,
+}
+
+impl ToneMapOperator {
+	fn specialization_constant(self) -> u32 {
+		match self {
+			ToneMapOperator::Reinhard => 0,
+			ToneMapOperator::Aces => 1,
+		}
+	}
+}
+
+/// The render pass tone mapping writes into, with a single color attachment of format `F`
+pub struct ToneMapPass<F>(PhantomData<F>);
+
+impl<F> RenderPassPrototype for ToneMapPass<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type SampleCount = SampleCount1;
+	type InputAttachments = ();
+	type ColorAttachments = (ColorAttachment<F>,);
+	type DepthAttachment = NoDepthAttachment;
+}
+
+struct ToneMapFunction<F>(PhantomData<F>);
+
+impl<F> FunctionPrototype for ToneMapFunction<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	type RenderPass = ToneMapPass<F>;
+	type VertexInput = Vec2;
+	// The HDR attachment and the exposure
+	type Bindings = (HdrInput, RawStorage);
+	type PushConstants = ();
+}
+
+/// The HDR attachment being tone mapped, sampled by the tone mapping shader
+pub struct HdrInput;
+
+unsafe impl Binding for HdrInput {
+	type Argument = HdrInputArgument;
+
+	fn description() -> BindingDesc {
+		BindingDesc {
+			binding_type: BindingType::SampledImage,
+			count: 1,
+		}
+	}
+}
+
+pub struct HdrInputArgument {
+	pub(crate) sampler: Arc<SamplerHandle>,
+	pub(crate) image_view: vk::ImageView,
+}
+
+impl Argument for HdrInputArgument {
+	fn as_write(&self) -> WriteArgument {
+		WriteArgument::SampledImage(WriteSampledImageArgument {
+			sampler: self.sampler.clone(),
+			image_view: self.image_view,
+			image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		})
+	}
+}
+
+/// Tone maps HDR color attachments into targets with a color attachment of format `F`
+pub struct ToneMapper<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	render_pass: RenderPass<ToneMapPass<F>>,
+	function: FunctionDef<ToneMapFunction<F>>,
+	sampler: Sampler,
+	// Keyed by the attachment and exposure buffer they read
+	arguments: CachedArguments<(vk::ImageView, vk::Buffer), ArgumentsContainer<ToneMapFunction<F>>>,
+	triangle: FullscreenTriangle,
+	// The exposure used without auto-exposure, declared after the arguments referring to it to be
+	// dropped last
+	fixed_exposure: Buffer<StorageBufferUsage, ExposureState>,
+}
+
+impl<F> ToneMapper<F>
+where
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	pub fn create(context: &Context, operator: ToneMapOperator) -> MarsResult<Self> {
+		let render_pass = RenderPass::create(context)?;
+		let vert = compile_shader(TONE_MAP_VERTEX_SHADER, "tonemap.vert", ShaderStage::Vertex)?;
+		let frag = compile_shader(TONE_MAP_FRAGMENT_SHADER, "tonemap.frag", ShaderStage::Fragment)?;
+		let function = FunctionDef::create_with_options(
+			context,
+			&render_pass,
+			unsafe { FunctionImpl::from_raw(vert, frag) },
+			&FunctionOptions {
+				specialization_constants: vec![operator.specialization_constant()],
+				..Default::default()
+			},
+		)?;
+		let sampler = Sampler::create(context)?;
+		let triangle = FullscreenTriangle::create(context)?;
+		let fixed_exposure = Buffer::make_item_buffer(context, ExposureState::identity())?;
+
+		Ok(Self {
+			render_pass,
+			function,
+			sampler,
+			arguments: CachedArguments::new(),
+			triangle,
+			fixed_exposure,
+		})
+	}
+
+	/// The render pass to create tone mapping targets with
+	pub fn render_pass(&self) -> &RenderPass<ToneMapPass<F>> {
+		&self.render_pass
+	}
+
+	/// Tone maps `source` into the color attachment of `target`. The source must have been created
+	/// with the `SAMPLED` usage.
+	pub fn apply(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		source: &mut ColorAttachment<HdrFormat>,
+		target: &mut Target<ToneMapPass<F>>,
+	) -> MarsResult<()> {
+		let exposure = RawStorageArgument::new(&self.fixed_exposure);
+		self.apply_exposed(context, engine, source, exposure, target)
+	}
+
+	/// Tone maps `source` like `apply`, with its colors multiplied by the exposure `exposure` has
+	/// adapted to
+	pub fn apply_with_exposure(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		source: &mut ColorAttachment<HdrFormat>,
+		exposure: &AutoExposure,
+		target: &mut Target<ToneMapPass<F>>,
+	) -> MarsResult<()> {
+		let exposure = RawStorageArgument::new(exposure.exposure());
+		self.apply_exposed(context, engine, source, exposure, target)
+	}
+
+	fn apply_exposed(
+		&mut self,
+		context: &Context,
+		engine: &mut RenderEngine,
+		source: &mut ColorAttachment<HdrFormat>,
+		exposure: RawStorageArgument,
+		target: &mut Target<ToneMapPass<F>>,
+	) -> MarsResult<()> {
+		assert!(
+			source.image.usage().contains(DynImageUsage::SAMPLED),
+			"tone mapped attachments must be sampleable"
+		);
+
+		let key = (source.view.image_view.raw, exposure.buffer());
+		let (function, sampler) = (&mut self.function, &self.sampler);
+		let arguments = self.arguments.make_or_reuse(key, || {
+			let hdr = HdrInputArgument {
+				sampler: sampler.sampler.clone(),
+				image_view: key.0,
+			};
+			function.make_arguments(context, (hdr, exposure))
+		})?;
+
+		let draw = self.triangle.draw_args(arguments);
+		let stage = vk::PipelineStageFlags2KHR::FRAGMENT_SHADER;
+		builtin::sample_color_attachment(context, engine, &mut source.image, stage, |engine| {
+			engine.pass(context, target, function, Some(draw))
+		})
+	}
+}
@@ -0,0 +1,466 @@
+//! Checks of function arguments that Vulkan doesn't report on its own, run in debug builds.
+//!
+//! Uniform buffers smaller than the block the shader declares, or images bound while in a layout
+//! shaders can't read from, are undefined behavior that usually shows up as garbage on screen
+//! rather than as an error. Functions reflect the uniform blocks of their shaders when they're
+//! created so `make_arguments` can catch these mistakes before writing the descriptors.
+//!
+//! The same reflection checks the shaders of precompiled bundles against the bindings of the
+//! functions they're loaded for.
+
+use std::collections::{HashMap, HashSet};
+
+use rk::vk;
+
+use crate::{
+	function::{BindingType, WriteArgument},
+	shader::ShaderStage,
+};
+
+const MAGIC: u32 = 0x0723_0203;
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+/// The sizes in bytes of the uniform blocks of a function's shaders, by binding
+#[derive(Default)]
+pub(crate) struct UniformBlockSizes(HashMap<u32, u64>);
+
+impl UniformBlockSizes {
+	/// Reflects the uniform blocks of descriptor set 0 in every shader. Nothing is reflected in
+	/// release builds, where the arguments aren't validated.
+	pub(crate) fn reflect(shaders: &[&[u32]]) -> Self {
+		let mut sizes = HashMap::new();
+		if cfg!(debug_assertions) {
+			for shader in shaders {
+				for (binding, size) in Module::parse(shader).uniform_block_sizes() {
+					let entry = sizes.entry(binding).or_insert(0);
+					*entry = size.max(*entry);
+				}
+			}
+		}
+		Self(sizes)
+	}
+}
+
+/// Panics with a description of the first argument that doesn't fit its binding. Does nothing in
+/// release builds.
+pub(crate) fn validate_arguments(uniform_sizes: &UniformBlockSizes, writes: &[WriteArgument]) {
+	if !cfg!(debug_assertions) {
+		return;
+	}
+	for (binding, write) in writes.iter().enumerate() {
+		validate_write(uniform_sizes, binding, write);
+	}
+}
+
+fn validate_write(uniform_sizes: &UniformBlockSizes, binding: usize, write: &WriteArgument) {
+	match write {
+		WriteArgument::Uniform(write) => {
+			let size = write.size();
+			if let Some(&expected) = uniform_sizes.0.get(&(binding as u32)) {
+				if size < expected {
+					panic!(
+						"The uniform buffer given for binding {} is {} bytes but the shader's uniform block is {} \
+						 bytes. Check that the binding type matches the shader's layout, like using `Vec3A` for \
+						 `vec3`s.",
+						binding, size, expected
+					);
+				}
+			}
+		}
+		WriteArgument::SampledImage(write) => check_readable_layout(binding, "sampled image", write.image_layout),
+		WriteArgument::InputAttachment(write) => check_readable_layout(binding, "input attachment", write.image_layout),
+		WriteArgument::SampledImageOnly(write) => check_readable_layout(binding, "texture", write.image_layout),
+		WriteArgument::Array(elements) => {
+			for element in elements {
+				validate_write(uniform_sizes, binding, element);
+			}
+		}
+		WriteArgument::AccelerationStructure(_)
+		| WriteArgument::StorageBuffer(_)
+		| WriteArgument::StorageImage(_)
+		| WriteArgument::Sampler(_) => {}
+	}
+}
+
+fn check_readable_layout(binding: usize, kind: &str, layout: vk::ImageLayout) {
+	let readable = [
+		vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+		vk::ImageLayout::GENERAL,
+		vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+		vk::ImageLayout::DEPTH_READ_ONLY_OPTIMAL,
+	];
+	if !readable.contains(&layout) {
+		panic!(
+			"The {} given for binding {} is in layout {:?}, which shaders can't read from. Transition it to \
+			 SHADER_READ_ONLY_OPTIMAL before making the arguments.",
+			kind, binding, layout
+		);
+	}
+}
+
+/// A descriptor binding of set 0 used by a shader
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ReflectedBinding {
+	pub(crate) binding: u32,
+	pub(crate) binding_type: BindingType,
+	/// The number of descriptors, or `None` for runtime-sized arrays
+	pub(crate) count: Option<u32>,
+}
+
+/// Whether the words are a SPIR-V module with a `main` entry point for `stage`
+pub(crate) fn has_entry_point(words: &[u32], stage: ShaderStage) -> bool {
+	let models: &[u32] = match stage {
+		ShaderStage::Vertex => &[0],
+		ShaderStage::Fragment => &[4],
+		ShaderStage::Compute => &[5],
+		ShaderStage::Task => &[5267, 5364],
+		ShaderStage::Mesh => &[5268, 5365],
+		ShaderStage::Geometry => &[3],
+	};
+	words.first() == Some(&MAGIC) && Module::parse(words).entry_points.iter().any(|m| models.contains(m))
+}
+
+/// The descriptor bindings of set 0 a shader declares
+pub(crate) fn reflect_bindings(words: &[u32]) -> Vec<ReflectedBinding> {
+	Module::parse(words).descriptor_bindings()
+}
+
+/// The parts of a SPIR-V module needed to reflect its descriptor bindings
+#[derive(Default)]
+struct Module {
+	/// The execution models of the entry points named `main`
+	entry_points: Vec<u32>,
+	/// Type instructions by result id, without the opcode word
+	types: HashMap<u32, (u32, Vec<u32>)>,
+	constants: HashMap<u32, u32>,
+	pointers: HashMap<u32, (u32, u32)>,
+	/// Descriptor variables, their pointer types and their storage classes
+	variables: Vec<(u32, u32, u32)>,
+	decorations: HashMap<(u32, u32), u32>,
+	/// Decorations without any literals, like `Block`
+	flags: HashSet<(u32, u32)>,
+	member_decorations: HashMap<(u32, u32, u32), u32>,
+}
+
+impl Module {
+	fn parse(words: &[u32]) -> Self {
+		let mut module = Self::default();
+		// Skip the header
+		let mut i = 5;
+		while i < words.len() {
+			let count = (words[i] >> 16) as usize;
+			let opcode = words[i] & 0xffff;
+			if count == 0 || i + count > words.len() {
+				break;
+			}
+			let operands = &words[i + 1..i + count];
+			match opcode {
+				OP_ENTRY_POINT if operands.len() >= 3 => {
+					// The name is a nul terminated string packed into the remaining words
+					let name = operands[2..]
+						.iter()
+						.flat_map(|word| word.to_le_bytes().to_vec())
+						.take_while(|&byte| byte != 0)
+						.collect::<Vec<_>>();
+					if name == b"main" {
+						module.entry_points.push(operands[0]);
+					}
+				}
+				OP_TYPE_INT
+				| OP_TYPE_FLOAT
+				| OP_TYPE_VECTOR
+				| OP_TYPE_MATRIX
+				| OP_TYPE_IMAGE
+				| OP_TYPE_SAMPLER
+				| OP_TYPE_SAMPLED_IMAGE
+				| OP_TYPE_ARRAY
+				| OP_TYPE_RUNTIME_ARRAY
+				| OP_TYPE_STRUCT
+				| OP_TYPE_ACCELERATION_STRUCTURE => {
+					module.types.insert(operands[0], (opcode, operands[1..].to_vec()));
+				}
+				OP_TYPE_POINTER => {
+					module.pointers.insert(operands[0], (operands[1], operands[2]));
+				}
+				OP_CONSTANT if operands.len() >= 3 => {
+					module.constants.insert(operands[1], operands[2]);
+				}
+				OP_VARIABLE
+					if [
+						STORAGE_CLASS_UNIFORM_CONSTANT,
+						STORAGE_CLASS_UNIFORM,
+						STORAGE_CLASS_STORAGE_BUFFER,
+					]
+					.contains(&operands[2]) =>
+				{
+					module.variables.push((operands[1], operands[0], operands[2]));
+				}
+				OP_DECORATE if operands.len() >= 3 => {
+					module.decorations.insert((operands[0], operands[1]), operands[2]);
+				}
+				OP_DECORATE if operands.len() == 2 => {
+					module.flags.insert((operands[0], operands[1]));
+				}
+				OP_MEMBER_DECORATE if operands.len() >= 4 => {
+					module
+						.member_decorations
+						.insert((operands[0], operands[1], operands[2]), operands[3]);
+				}
+				_ => {}
+			}
+			i += count;
+		}
+		module
+	}
+
+	/// The variables of descriptor set 0 with their bindings, pointee types and storage classes
+	fn set_variables(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+		self.variables
+			.iter()
+			.filter(move |(variable, ..)| self.decorations.get(&(*variable, DECORATION_DESCRIPTOR_SET)) == Some(&0))
+			.filter_map(move |(variable, pointer, storage_class)| {
+				let binding = *self.decorations.get(&(*variable, DECORATION_BINDING))?;
+				let &(_, pointee) = self.pointers.get(pointer)?;
+				Some((binding, pointee, *storage_class))
+			})
+	}
+
+	fn uniform_block_sizes(&self) -> Vec<(u32, u64)> {
+		self.set_variables()
+			.filter(|&(_, _, storage_class)| storage_class == STORAGE_CLASS_UNIFORM)
+			.filter_map(|(binding, pointee, _)| {
+				// Every block in an array of blocks has its own buffer
+				let block = match self.types.get(&pointee)? {
+					(OP_TYPE_ARRAY, operands) => operands[0],
+					_ => pointee,
+				};
+				if self.flags.contains(&(block, DECORATION_BUFFER_BLOCK)) {
+					return None;
+				}
+				Some((binding, self.size_of(block, None)?))
+			})
+			.collect()
+	}
+
+	fn descriptor_bindings(&self) -> Vec<ReflectedBinding> {
+		self.set_variables()
+			.filter_map(|(binding, pointee, storage_class)| {
+				// Arrays of descriptors take up several elements of one binding
+				let (element, count) = match self.types.get(&pointee)? {
+					(OP_TYPE_ARRAY, operands) => (operands[0], Some(*self.constants.get(&operands[1])?)),
+					(OP_TYPE_RUNTIME_ARRAY, operands) => (operands[0], None),
+					_ => (pointee, Some(1)),
+				};
+				let binding_type = match storage_class {
+					STORAGE_CLASS_STORAGE_BUFFER => BindingType::StorageBuffer,
+					STORAGE_CLASS_UNIFORM if self.flags.contains(&(element, DECORATION_BUFFER_BLOCK)) => {
+						BindingType::StorageBuffer
+					}
+					STORAGE_CLASS_UNIFORM => BindingType::Uniform,
+					_ => match self.types.get(&element)? {
+						(OP_TYPE_SAMPLED_IMAGE, _) => BindingType::SampledImage,
+						(OP_TYPE_ACCELERATION_STRUCTURE, _) => BindingType::AccelerationStructure,
+						(OP_TYPE_IMAGE, operands) if operands[1] == DIM_SUBPASS_DATA => BindingType::InputAttachment,
+						// Images not used with a sampler are storage images
+						(OP_TYPE_IMAGE, operands) if operands[5] == 2 => BindingType::StorageImage,
+						(OP_TYPE_IMAGE, operands) if operands[1] != DIM_BUFFER => BindingType::SampledImageOnly,
+						(OP_TYPE_SAMPLER, _) => BindingType::Sampler,
+						_ => return None,
+					},
+				};
+				Some(ReflectedBinding {
+					binding,
+					binding_type,
+					count,
+				})
+			})
+			.collect()
+	}
+
+	/// The size of a type in a block, given the matrix stride of the member it's in if any
+	fn size_of(&self, ty: u32, matrix_stride: Option<u32>) -> Option<u64> {
+		let (opcode, operands) = self.types.get(&ty)?;
+		Some(match *opcode {
+			OP_TYPE_INT | OP_TYPE_FLOAT => operands[0] as u64 / 8,
+			OP_TYPE_VECTOR => operands[1] as u64 * self.size_of(operands[0], None)?,
+			OP_TYPE_MATRIX => {
+				let column = match matrix_stride {
+					Some(stride) => stride as u64,
+					None => self.size_of(operands[0], None)?,
+				};
+				operands[1] as u64 * column
+			}
+			OP_TYPE_ARRAY => {
+				let length = *self.constants.get(&operands[1])? as u64;
+				let stride = match self.decorations.get(&(ty, DECORATION_ARRAY_STRIDE)) {
+					Some(&stride) => stride as u64,
+					None => self.size_of(operands[0], matrix_stride)?,
+				};
+				length * stride
+			}
+			OP_TYPE_STRUCT => {
+				let mut size = 0;
+				for (member, &member_ty) in operands.iter().enumerate() {
+					let member = member as u32;
+					let offset = *self.member_decorations.get(&(ty, member, DECORATION_OFFSET))? as u64;
+					let stride = self
+						.member_decorations
+						.get(&(ty, member, DECORATION_MATRIX_STRIDE))
+						.copied();
+					size = size.max(offset + self.size_of(member_ty, stride)?);
+				}
+				size
+			}
+			_ => return None,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DECORATION_BLOCK: u32 = 2;
+
+	fn module(instructions: &[(u32, &[u32])]) -> Vec<u32> {
+		let mut words = vec![MAGIC, 0x0001_0000, 0, 100, 0];
+		for &(opcode, operands) in instructions {
+			words.push((operands.len() as u32 + 1) << 16 | opcode);
+			words.extend_from_slice(operands);
+		}
+		words
+	}
+
+	/// A vertex shader with a uniform block at binding 1, a storage buffer at binding 2, an array of
+	/// three sampled images at binding 0, and a uniform block in set 1 that's ignored
+	fn shader() -> Vec<u32> {
+		module(&[
+			(OP_ENTRY_POINT, &[0, 30, u32::from_le_bytes(*b"main"), 0]),
+			(OP_TYPE_FLOAT, &[1, 32]),
+			(OP_TYPE_VECTOR, &[2, 1, 4]),
+			(OP_TYPE_MATRIX, &[3, 2, 4]),
+			(OP_TYPE_INT, &[4, 32, 0]),
+			(OP_CONSTANT, &[4, 5, 3]),
+			(OP_TYPE_ARRAY, &[6, 2, 5]),
+			(OP_DECORATE, &[6, DECORATION_ARRAY_STRIDE, 16]),
+			// struct { mat4 at 0; vec4[3] at 64; float at 112 }
+			(OP_TYPE_STRUCT, &[7, 3, 6, 1]),
+			(OP_DECORATE, &[7, DECORATION_BLOCK]),
+			(OP_MEMBER_DECORATE, &[7, 0, DECORATION_OFFSET, 0]),
+			(OP_MEMBER_DECORATE, &[7, 0, DECORATION_MATRIX_STRIDE, 16]),
+			(OP_MEMBER_DECORATE, &[7, 1, DECORATION_OFFSET, 64]),
+			(OP_MEMBER_DECORATE, &[7, 2, DECORATION_OFFSET, 112]),
+			(OP_TYPE_POINTER, &[8, STORAGE_CLASS_UNIFORM, 7]),
+			(OP_VARIABLE, &[8, 9, STORAGE_CLASS_UNIFORM]),
+			(OP_DECORATE, &[9, DECORATION_DESCRIPTOR_SET, 0]),
+			(OP_DECORATE, &[9, DECORATION_BINDING, 1]),
+			(OP_TYPE_RUNTIME_ARRAY, &[11, 1]),
+			(OP_TYPE_STRUCT, &[12, 11]),
+			(OP_DECORATE, &[12, DECORATION_BLOCK]),
+			(OP_MEMBER_DECORATE, &[12, 0, DECORATION_OFFSET, 0]),
+			(OP_TYPE_POINTER, &[13, STORAGE_CLASS_STORAGE_BUFFER, 12]),
+			(OP_VARIABLE, &[13, 14, STORAGE_CLASS_STORAGE_BUFFER]),
+			(OP_DECORATE, &[14, DECORATION_DESCRIPTOR_SET, 0]),
+			(OP_DECORATE, &[14, DECORATION_BINDING, 2]),
+			(OP_TYPE_IMAGE, &[15, 1, 1, 0, 0, 0, 1, 0]),
+			(OP_TYPE_SAMPLED_IMAGE, &[16, 15]),
+			(OP_TYPE_ARRAY, &[17, 16, 5]),
+			(OP_TYPE_POINTER, &[18, STORAGE_CLASS_UNIFORM_CONSTANT, 17]),
+			(OP_VARIABLE, &[18, 19, STORAGE_CLASS_UNIFORM_CONSTANT]),
+			(OP_DECORATE, &[19, DECORATION_DESCRIPTOR_SET, 0]),
+			(OP_DECORATE, &[19, DECORATION_BINDING, 0]),
+			(OP_VARIABLE, &[8, 20, STORAGE_CLASS_UNIFORM]),
+			(OP_DECORATE, &[20, DECORATION_DESCRIPTOR_SET, 1]),
+			(OP_DECORATE, &[20, DECORATION_BINDING, 0]),
+		])
+	}
+
+	#[test]
+	fn parse_finds_entry_points() {
+		let words = shader();
+		assert_eq!(Module::parse(&words).entry_points, vec![0]);
+		assert!(has_entry_point(&words, ShaderStage::Vertex));
+		assert!(!has_entry_point(&words, ShaderStage::Fragment));
+		assert!(!has_entry_point(&words[1..], ShaderStage::Vertex));
+	}
+
+	#[test]
+	fn parse_stops_at_truncated_instructions() {
+		let words = shader();
+		let module = Module::parse(&words[..words.len() - 1]);
+		assert_eq!(module.variables.len(), 4);
+		assert!(!module.decorations.contains_key(&(20, DECORATION_BINDING)));
+		let mut words = words;
+		words[5] &= 0xffff;
+		assert!(Module::parse(&words).entry_points.is_empty());
+	}
+
+	#[test]
+	fn size_of_follows_offsets_and_strides() {
+		let module = Module::parse(&shader());
+		assert_eq!(module.size_of(1, None), Some(4));
+		assert_eq!(module.size_of(2, None), Some(16));
+		assert_eq!(module.size_of(3, None), Some(64));
+		assert_eq!(module.size_of(3, Some(32)), Some(128));
+		assert_eq!(module.size_of(6, None), Some(48));
+		assert_eq!(module.size_of(7, None), Some(116));
+		// Runtime arrays have no size
+		assert_eq!(module.size_of(12, None), None);
+		assert_eq!(module.uniform_block_sizes(), vec![(1, 116)]);
+	}
+
+	#[test]
+	fn reflect_bindings_of_set_zero() {
+		assert_eq!(
+			reflect_bindings(&shader()),
+			vec![
+				ReflectedBinding {
+					binding: 1,
+					binding_type: BindingType::Uniform,
+					count: Some(1),
+				},
+				ReflectedBinding {
+					binding: 2,
+					binding_type: BindingType::StorageBuffer,
+					count: Some(1),
+				},
+				ReflectedBinding {
+					binding: 0,
+					binding_type: BindingType::SampledImage,
+					count: Some(3),
+				},
+			]
+		);
+	}
+}
@@ -1,62 +1,947 @@
-use raw_window_handle::HasRawWindowHandle;
+use std::time::Duration;
+
+use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 
 use rk::{
+	ash::{self, extensions::khr},
+	device::{Device, Queue},
+	instance::Instance,
 	vk,
-	wsi::{PresentationEngine, Surface},
 };
 
 use crate::{
+	destruction::{DestructionQueue, RecordedUses},
+	display::{self, Display, DisplayMode},
 	image::{usage, FormatType, Image, SampleCount1},
+	raw_device, raw_instance, raw_physical_device, raw_queue,
 	render::RenderEngine,
+	submit::SubmitHandle,
+	sync::{self, ImageTransition},
 	Context, MarsResult,
 };
 
 pub struct WindowEngine {
 	pub render: RenderEngine,
-	pub(crate) presentation_engine: PresentationEngine,
+	/// The swapchain, or `None` while suspended
+	pub(crate) swapchain: Option<Swapchain>,
 	pub(crate) current_extent: vk::Extent2D,
+	options: WindowOptions,
+	/// Whether the last present was skipped, see `WindowOptions::acquire_timeout`
+	skipped: bool,
 }
 
 impl WindowEngine {
 	pub fn new<W: HasRawWindowHandle>(context: &Context, window: &W) -> MarsResult<Self> {
-		let handle = window.raw_window_handle();
-		let surface = unsafe { Surface::create_from_raw_handle(&context.physical_device, handle).unwrap() };
-		let surface_info = unsafe { surface.get_info()? };
-		let surface_format = surface_info.formats[0];
-		let swapchain = context
-			.device
-			.create_swapchain(&surface, vk::ImageUsageFlags::TRANSFER_DST, surface_format, None)
-			.unwrap();
-		let surface_size = swapchain.current_extent();
-		let presentation_engine = unsafe { PresentationEngine::new(swapchain).unwrap() };
+		Self::new_with_options(context, window, &WindowOptions::default())
+	}
+
+	pub fn new_with_options<W: HasRawWindowHandle>(
+		context: &Context,
+		window: &W,
+		options: &WindowOptions,
+	) -> MarsResult<Self> {
+		let surface = unsafe { create_surface(&context.entry, &context.instance, window.raw_window_handle())? };
+		Self::from_surface(context, surface, options)
+	}
+
+	/// Creates an engine presenting straight to a display, without any window system in between.
+	/// `mode` must be one of the display's modes. Requires a context created with
+	/// `DeviceFeatures::direct_display`.
+	pub fn new_for_display(
+		context: &Context,
+		display: &Display,
+		mode: &DisplayMode,
+		options: &WindowOptions,
+	) -> MarsResult<Self> {
+		let surface = unsafe { display::create_surface(context, display, mode)? };
+		Self::from_surface(context, surface, options)
+	}
+
+	fn from_surface(context: &Context, surface: vk::SurfaceKHR, options: &WindowOptions) -> MarsResult<Self> {
+		let swapchain = unsafe { Swapchain::create(context, surface, options)? };
+		let surface_size = swapchain.extent;
 
 		//let render_pass = RenderPass::create(context)?;
 		let render = RenderEngine::new(context)?;
 
 		Ok(Self {
 			render,
-			presentation_engine,
+			swapchain: Some(swapchain),
 			current_extent: surface_size,
+			options: options.clone(),
+			skipped: false,
 		})
 	}
 
+	/// Destroys the surface and swapchain while keeping every other resource alive, for platforms
+	/// like Android that take the window away while the app is in the background. Presenting does
+	/// nothing until `resume` is called.
+	pub fn suspend(&mut self) {
+		self.swapchain = None;
+	}
+
+	/// Creates a new surface and swapchain for `window` after `suspend`, returning the new extent
+	/// of the surface. Does nothing but return the current extent if the engine isn't suspended.
+	pub fn resume<W: HasRawWindowHandle>(&mut self, context: &Context, window: &W) -> MarsResult<vk::Extent2D> {
+		if self.swapchain.is_none() {
+			let swapchain = unsafe {
+				let surface = create_surface(&context.entry, &context.instance, window.raw_window_handle())?;
+				Swapchain::create(context, surface, &self.options)?
+			};
+			if !swapchain.is_minimized() {
+				self.current_extent = swapchain.extent;
+			}
+			self.swapchain = Some(swapchain);
+		}
+		Ok(self.current_extent)
+	}
+
+	/// Returns whether the engine is suspended, and has no surface to present to
+	pub fn is_suspended(&self) -> bool {
+		self.swapchain.is_none()
+	}
+
+	/// Presents `image`, returning the new extent of the surface if it changed. The image is
+	/// blitted onto a swapchain image without waiting for the blit, which is waited on once its frame
+	/// is presented again, see `WindowOptions::frames_in_flight`.
 	pub fn present<F: FormatType>(
 		&mut self,
 		context: &Context,
 		image: &Image<usage::TransferSrc, F, SampleCount1>,
 	) -> MarsResult<Option<vk::Extent2D>> {
-		context
-			.queue
-			.with_lock(|| unsafe { self.presentation_engine.present(&context.queue, &image.image) })
-			.map(|opt| {
-				opt.map(|new_extent| {
-					self.current_extent = new_extent;
-					new_extent
-				})
-			})
+		trace_span!("WindowEngine::present");
+		self.present_inner(context, image, None)
+	}
+
+	/// Like `present`, but the blit waits on the device for `submission` to complete instead of the
+	/// caller having waited for it, for an image rendered by a frame ended with
+	/// `RenderEngine::end_frame_async`. The next frame can be recorded right away, while the device
+	/// is still rendering and presenting this one. The submission is kept until its frame is
+	/// presented again, so its resources live as long as it may be executing.
+	pub fn present_after<F: FormatType>(
+		&mut self,
+		context: &Context,
+		image: &Image<usage::TransferSrc, F, SampleCount1>,
+		submission: SubmitHandle,
+	) -> MarsResult<Option<vk::Extent2D>> {
+		trace_span!("WindowEngine::present_after");
+		self.present_inner(context, image, Some(submission))
+	}
+
+	fn present_inner<F: FormatType>(
+		&mut self,
+		context: &Context,
+		image: &Image<usage::TransferSrc, F, SampleCount1>,
+		submission: Option<SubmitHandle>,
+	) -> MarsResult<Option<vk::Extent2D>> {
+		let swapchain = match &mut self.swapchain {
+			Some(swapchain) => swapchain,
+			None => return Ok(None),
+		};
+		let mut resized = false;
+		self.skipped = false;
+		if swapchain.is_minimized() {
+			// Nothing can be presented until the surface has a size again
+			swapchain.recreate(context)?;
+			if swapchain.is_minimized() {
+				return Ok(None);
+			}
+			resized = true;
+		}
+		let timeout = self
+			.options
+			.acquire_timeout
+			.map_or(u64::MAX, |timeout| timeout.as_nanos().min(u64::MAX as u128) as u64);
+		let presented = swapchain.present(
+			context,
+			image.image.raw,
+			image.extent,
+			image.layout,
+			timeout,
+			submission,
+		);
+		let outdated = match presented {
+			Ok(Some(suboptimal)) => suboptimal,
+			Ok(None) => {
+				self.skipped = true;
+				return Ok(None);
+			}
+			Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+			Err(e) => return Err(e),
+		};
+		#[cfg(feature = "renderdoc")]
+		if let Some(capture) = &context.frame_capture {
+			capture.frame_presented();
+		}
+		if outdated {
+			swapchain.recreate(context)?;
+			resized = !swapchain.is_minimized();
+		}
+		if resized {
+			self.current_extent = swapchain.extent;
+			Ok(Some(self.current_extent))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Returns whether the window is minimized, or otherwise has a surface with no area. Presenting
+	/// is skipped until it has a size again, and the first present after that reports the new
+	/// extent. `current_extent` keeps the last extent the window had in the meantime.
+	pub fn is_minimized(&self) -> bool {
+		self.swapchain.as_ref().map_or(false, Swapchain::is_minimized)
 	}
 
 	pub fn current_extent(&self) -> vk::Extent2D {
 		self.current_extent
 	}
+
+	/// Returns whether the last call to `present` skipped its frame because no swapchain image
+	/// became available within `WindowOptions::acquire_timeout`. The frame was still rendered into
+	/// its offscreen image, it just wasn't shown.
+	pub fn was_skipped(&self) -> bool {
+		self.skipped
+	}
+
+	/// Returns the number of images the swapchain actually has, which can differ from the requested
+	/// buffering depending on what the surface supports. There are none while suspended or
+	/// minimized.
+	pub fn image_count(&self) -> u32 {
+		self.swapchain
+			.as_ref()
+			.map_or(0, |swapchain| swapchain.images.len() as u32)
+	}
+
+	/// Returns the number of frames that can be in flight at once
+	pub fn frames_in_flight(&self) -> usize {
+		self.options.frames_in_flight.map_or(1, FramesInFlight::count)
+	}
+
+	/// Returns the composite alpha mode the swapchain actually uses, or the requested one while
+	/// suspended
+	pub fn composite_alpha(&self) -> CompositeAlpha {
+		self.swapchain
+			.as_ref()
+			.map_or(self.options.composite_alpha, |swapchain| swapchain.composite_alpha)
+	}
+}
+
+/// How a window's swapchain is created
+#[derive(Debug, Clone, Default)]
+pub struct WindowOptions {
+	/// How many images the swapchain should have, clamped to what the surface supports. Deeper
+	/// buffering smooths over frames that take longer to render at the cost of latency. `None` asks
+	/// for one image more than the surface's minimum.
+	pub buffering: Option<Buffering>,
+	/// How the compositor blends the window with what's behind it. Falls back to the surface's
+	/// default when it doesn't support the requested mode, which `WindowEngine::composite_alpha`
+	/// reports.
+	pub composite_alpha: CompositeAlpha,
+	/// How long presenting waits for a swapchain image before skipping the frame, instead of
+	/// blocking the thread until the compositor gives one back. Keeps the render loop going when
+	/// the compositor is under heavy load or throttles background windows. `None` always waits.
+	pub acquire_timeout: Option<Duration>,
+	/// How many frames can be in flight at once. Each frame has its own command pool, semaphores
+	/// and fence, and presenting only waits for the frame presented that many presents ago, so
+	/// recording a frame overlaps the device executing the ones before it. `None` keeps one frame
+	/// in flight.
+	pub frames_in_flight: Option<FramesInFlight>,
+}
+
+/// The number of frames a `WindowEngine` can have in flight
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FramesInFlight {
+	Two,
+	Three,
+}
+
+impl FramesInFlight {
+	fn count(self) -> usize {
+		match self {
+			FramesInFlight::Two => 2,
+			FramesInFlight::Three => 3,
+		}
+	}
+}
+
+/// The number of images to request for a swapchain
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Buffering {
+	Double,
+	Triple,
+}
+
+impl Buffering {
+	fn image_count(self) -> u32 {
+		match self {
+			Buffering::Double => 2,
+			Buffering::Triple => 3,
+		}
+	}
+}
+
+/// How the alpha of presented images is treated when the compositor blends a window with what's
+/// behind it. The multiplied modes make the window transparent wherever the alpha is below one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompositeAlpha {
+	/// Alpha is ignored and the window is fully opaque
+	Opaque,
+	/// Colors have already been multiplied by their alpha
+	PreMultiplied,
+	/// Colors haven't been multiplied by their alpha, and the compositor does it
+	PostMultiplied,
+	/// Whatever the window system was told through its own API
+	Inherit,
+}
+
+impl Default for CompositeAlpha {
+	fn default() -> Self {
+		CompositeAlpha::Opaque
+	}
+}
+
+impl CompositeAlpha {
+	fn as_raw(self) -> vk::CompositeAlphaFlagsKHR {
+		match self {
+			CompositeAlpha::Opaque => vk::CompositeAlphaFlagsKHR::OPAQUE,
+			CompositeAlpha::PreMultiplied => vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+			CompositeAlpha::PostMultiplied => vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+			CompositeAlpha::Inherit => vk::CompositeAlphaFlagsKHR::INHERIT,
+		}
+	}
+
+	/// Picks `self` if the surface supports it, or else the first supported mode in the order
+	/// they're declared in
+	fn choose(self, supported: vk::CompositeAlphaFlagsKHR) -> Self {
+		if supported.contains(self.as_raw()) {
+			return self;
+		}
+		let fallback = [
+			CompositeAlpha::Opaque,
+			CompositeAlpha::PreMultiplied,
+			CompositeAlpha::PostMultiplied,
+			CompositeAlpha::Inherit,
+		]
+		.iter()
+		.copied()
+		.find(|mode| supported.contains(mode.as_raw()))
+		.unwrap_or(CompositeAlpha::Opaque);
+		log::warn!(
+			"The surface doesn't support composite alpha mode {:?}, falling back to {:?}",
+			self,
+			fallback
+		);
+		fallback
+	}
+}
+
+/// A window surface and the swapchain presenting to it. Images are presented by blitting them onto
+/// the swapchain images.
+pub(crate) struct Swapchain {
+	device: Device,
+	destruction: DestructionQueue,
+	surface_loader: khr::Surface,
+	swapchain_loader: khr::Swapchain,
+	surface: vk::SurfaceKHR,
+	swapchain: vk::SwapchainKHR,
+	images: Vec<vk::Image>,
+	format: vk::SurfaceFormatKHR,
+	extent: vk::Extent2D,
+	buffering: Option<Buffering>,
+	/// The requested composite alpha mode
+	requested_composite_alpha: CompositeAlpha,
+	/// The composite alpha mode of the current swapchain
+	composite_alpha: CompositeAlpha,
+	/// The queue family images are presented from, when the context's queue can't present to the
+	/// surface. Presented images are transferred to it from the context's queue family.
+	present_family: Option<u32>,
+	/// A pool of the present family for recording the other half of those transfers
+	present_command_pool: Option<vk::CommandPool>,
+	/// The frames that can be in flight, used in turn
+	frames: Vec<PresentFrame>,
+	/// The frame presented next
+	frame_index: usize,
+}
+
+/// The resources of a frame in flight, from acquiring a swapchain image to presenting it
+struct PresentFrame {
+	/// A pool of the context's queue family for recording the blit, reset each time the frame is
+	/// used
+	command_pool: vk::CommandPool,
+	command_buffer: vk::CommandBuffer,
+	/// Signaled once the blit has completed, created signaled
+	fence: vk::Fence,
+	/// Signaled once the swapchain image is acquired, for the blit to wait on
+	acquired: vk::Semaphore,
+	/// Signaled once the blit has completed, for the present to wait on
+	blitted: vk::Semaphore,
+	/// The serial of the blit's submission, while it may still be executing
+	serial: Option<u64>,
+	/// The submission that rendered the presented image, kept until the frame is used again
+	submission: Option<SubmitHandle>,
+}
+
+impl PresentFrame {
+	unsafe fn create(device: &rk::ash::Device, queue_family_index: u32) -> MarsResult<Self> {
+		// Destroying null handles does nothing, so a frame that's only partly created can be
+		// destroyed
+		let mut frame = Self {
+			command_pool: vk::CommandPool::null(),
+			command_buffer: vk::CommandBuffer::null(),
+			fence: vk::Fence::null(),
+			acquired: vk::Semaphore::null(),
+			blitted: vk::Semaphore::null(),
+			serial: None,
+			submission: None,
+		};
+		let created = (|| -> MarsResult<()> {
+			let create_info = vk::CommandPoolCreateInfo::builder()
+				.flags(vk::CommandPoolCreateFlags::TRANSIENT)
+				.queue_family_index(queue_family_index);
+			frame.command_pool = device.create_command_pool(&create_info, None)?;
+			let allocate_info = vk::CommandBufferAllocateInfo::builder()
+				.command_pool(frame.command_pool)
+				.level(vk::CommandBufferLevel::PRIMARY)
+				.command_buffer_count(1);
+			frame.command_buffer = device.allocate_command_buffers(&allocate_info)?[0];
+			let create_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+			frame.fence = device.create_fence(&create_info, None)?;
+			frame.acquired = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+			frame.blitted = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+			Ok(())
+		})();
+		match created {
+			Ok(()) => Ok(frame),
+			Err(e) => {
+				frame.destroy(device);
+				Err(e)
+			}
+		}
+	}
+
+	/// Destroys the frame, which must not be in flight
+	unsafe fn destroy(&self, device: &rk::ash::Device) {
+		device.destroy_semaphore(self.blitted, None);
+		device.destroy_semaphore(self.acquired, None);
+		device.destroy_fence(self.fence, None);
+		device.destroy_command_pool(self.command_pool, None);
+	}
+}
+
+impl Swapchain {
+	/// Creates a swapchain presenting to `surface`, which it takes ownership of
+	unsafe fn create(context: &Context, surface: vk::SurfaceKHR, options: &WindowOptions) -> MarsResult<Self> {
+		let surface_loader = khr::Surface::new(&context.entry, raw_instance(&context.instance));
+		let swapchain_loader = khr::Swapchain::new(raw_instance(&context.instance), raw_device(&context.device));
+
+		let physical_device = raw_physical_device(&context.physical_device);
+		let destroy_surface = |e| {
+			surface_loader.destroy_surface(surface, None);
+			e
+		};
+		let present_family = find_present_family(context, &surface_loader, surface).map_err(destroy_surface)?;
+		let format = surface_loader
+			.get_physical_device_surface_formats(physical_device, surface)
+			.map(|formats| choose_format(&formats, options.composite_alpha))
+			.map_err(destroy_surface)?;
+		let device = raw_device(&context.device);
+		let present_command_pool = match present_family {
+			Some(family) => {
+				let create_info = vk::CommandPoolCreateInfo::builder()
+					.flags(vk::CommandPoolCreateFlags::TRANSIENT)
+					.queue_family_index(family);
+				Some(
+					device
+						.create_command_pool(&create_info, None)
+						.map_err(destroy_surface)?,
+				)
+			}
+			None => None,
+		};
+		let frame_count = options.frames_in_flight.map_or(1, FramesInFlight::count);
+		let mut frames = Vec::with_capacity(frame_count);
+		for _ in 0..frame_count {
+			match PresentFrame::create(device, context.queue_family_index) {
+				Ok(frame) => frames.push(frame),
+				Err(e) => {
+					for frame in &frames {
+						frame.destroy(device);
+					}
+					if let Some(command_pool) = present_command_pool {
+						device.destroy_command_pool(command_pool, None);
+					}
+					return Err(destroy_surface(e));
+				}
+			}
+		}
+
+		let mut swapchain = Self {
+			device: context.device.clone(),
+			destruction: context.destruction.clone(),
+			surface_loader,
+			swapchain_loader,
+			surface,
+			swapchain: vk::SwapchainKHR::null(),
+			images: Vec::new(),
+			format,
+			extent: vk::Extent2D { width: 0, height: 0 },
+			buffering: options.buffering,
+			requested_composite_alpha: options.composite_alpha,
+			composite_alpha: options.composite_alpha,
+			present_family,
+			present_command_pool,
+			frames,
+			frame_index: 0,
+		};
+		swapchain.recreate(context)?;
+		Ok(swapchain)
+	}
+
+	fn is_minimized(&self) -> bool {
+		self.swapchain == vk::SwapchainKHR::null()
+	}
+
+	/// Creates a new swapchain matching the current size of the surface, replacing the old one. If
+	/// the surface has no area, there's no swapchain until it's recreated again.
+	fn recreate(&mut self, context: &Context) -> MarsResult<()> {
+		let physical_device = raw_physical_device(&context.physical_device);
+		self.wait_for_frames()?;
+		unsafe {
+			let capabilities = self
+				.surface_loader
+				.get_physical_device_surface_capabilities(physical_device, self.surface)?;
+			let extent = if capabilities.current_extent.width != u32::MAX {
+				capabilities.current_extent
+			} else {
+				capabilities.min_image_extent
+			};
+			if extent.width == 0 || extent.height == 0 {
+				// Swapchains can't have an empty extent, so there's none until the surface has a
+				// size again
+				if self.swapchain != vk::SwapchainKHR::null() {
+					self.swapchain_loader.destroy_swapchain(self.swapchain, None);
+				}
+				self.swapchain = vk::SwapchainKHR::null();
+				self.images.clear();
+				self.extent = extent;
+				return Ok(());
+			}
+			let mut image_count = match self.buffering {
+				Some(buffering) => buffering.image_count().max(capabilities.min_image_count),
+				None => capabilities.min_image_count + 1,
+			};
+			let composite_alpha = self
+				.requested_composite_alpha
+				.choose(capabilities.supported_composite_alpha);
+			if capabilities.max_image_count > 0 {
+				image_count = image_count.min(capabilities.max_image_count);
+			}
+
+			let create_info = vk::SwapchainCreateInfoKHR::builder()
+				.surface(self.surface)
+				.min_image_count(image_count)
+				.image_format(self.format.format)
+				.image_color_space(self.format.color_space)
+				.image_extent(extent)
+				.image_array_layers(1)
+				.image_usage(vk::ImageUsageFlags::TRANSFER_DST)
+				.image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+				.pre_transform(capabilities.current_transform)
+				.composite_alpha(composite_alpha.as_raw())
+				.present_mode(vk::PresentModeKHR::FIFO)
+				.clipped(true)
+				.old_swapchain(self.swapchain);
+			let swapchain = self.swapchain_loader.create_swapchain(&create_info, None)?;
+			if self.swapchain != vk::SwapchainKHR::null() {
+				// Every blit has completed, so nothing can still be using the old swapchain
+				self.swapchain_loader.destroy_swapchain(self.swapchain, None);
+			}
+			self.swapchain = swapchain;
+			self.images = self.swapchain_loader.get_swapchain_images(swapchain)?;
+			self.extent = extent;
+			self.composite_alpha = composite_alpha;
+		}
+		Ok(())
+	}
+
+	/// Blits `image` onto the next swapchain image and presents it, returning whether the swapchain
+	/// is suboptimal for the surface, or `None` if no image could be acquired within `timeout`
+	/// nanoseconds and nothing was presented. The blit waits on `submission` if given, which is kept
+	/// until the frame is used again, and isn't waited for unless images have to be transferred to
+	/// another queue family.
+	fn present(
+		&mut self,
+		context: &Context,
+		image: vk::Image,
+		extent: vk::Extent2D,
+		layout: vk::ImageLayout,
+		timeout: u64,
+		submission: Option<SubmitHandle>,
+	) -> MarsResult<Option<bool>> {
+		let frame = self.frame_index;
+		// The frame's command buffer and semaphores are reused, so its last blit has to complete
+		self.wait_for_frame(frame)?;
+		self.frames[frame].submission = submission;
+		unsafe {
+			// Once an image is acquired it has to be presented, so only the acquire itself times out
+			let acquired = self.swapchain_loader.acquire_next_image(
+				self.swapchain,
+				timeout,
+				self.frames[frame].acquired,
+				vk::Fence::null(),
+			);
+			let (index, acquire_suboptimal) = match acquired {
+				Ok(acquired) => acquired,
+				Err(vk::Result::TIMEOUT | vk::Result::NOT_READY) => return Ok(None),
+				Err(e) => return Err(e),
+			};
+			let swapchain_image = self.images[index as usize];
+
+			let present_transition = ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::BLIT,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				src_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::NONE,
+				old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+				new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+			};
+			let mut waits = vec![(self.frames[frame].acquired, vk::PipelineStageFlags2KHR::BLIT)];
+			if let Some(semaphore) = self.frames[frame]
+				.submission
+				.as_mut()
+				.and_then(SubmitHandle::take_semaphore)
+			{
+				waits.push((semaphore, vk::PipelineStageFlags2KHR::BLIT));
+			}
+			self.submit_blit(context, frame, &waits, |this, command_buffer| {
+				this.record_blit(
+					context,
+					command_buffer,
+					image,
+					extent,
+					layout,
+					swapchain_image,
+					&present_transition,
+				);
+			})?;
+
+			let (present_queue, present_waits) = match self.present_family {
+				Some(family) => {
+					// The release has to complete before the acquire can be submitted
+					self.wait_for_frame(frame)?;
+					let queue = family_queue(context, family);
+					let acquire = ImageTransition {
+						src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+						src_access_mask: vk::AccessFlags2KHR::NONE,
+						..present_transition
+					};
+					self.submit_on_present_queue(context, queue, |command_buffer| {
+						sync::record_queue_transfer(
+							context,
+							command_buffer,
+							swapchain_image,
+							&acquire,
+							context.queue_family_index,
+							family,
+						);
+					})?;
+					(queue, Vec::new())
+				}
+				None => (&context.queue, vec![self.frames[frame].blitted]),
+			};
+			self.frame_index = (frame + 1) % self.frames.len();
+
+			let swapchains = [self.swapchain];
+			let indices = [index];
+			let present_info = vk::PresentInfoKHR::builder()
+				.wait_semaphores(&present_waits)
+				.swapchains(&swapchains)
+				.image_indices(&indices);
+			let present_suboptimal = present_queue.with_lock(|| {
+				self.swapchain_loader
+					.queue_present(raw_queue(present_queue), &present_info)
+			})?;
+
+			Ok(Some(acquire_suboptimal || present_suboptimal))
+		}
+	}
+
+	/// Records blitting `image` onto `swapchain_image` and transitioning it for presentation
+	#[allow(clippy::too_many_arguments)]
+	unsafe fn record_blit(
+		&self,
+		context: &Context,
+		command_buffer: vk::CommandBuffer,
+		image: vk::Image,
+		extent: vk::Extent2D,
+		layout: vk::ImageLayout,
+		swapchain_image: vk::Image,
+		present_transition: &ImageTransition,
+	) {
+		let region = vk::ImageBlit {
+			src_subresource: color_subresource(),
+			src_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, offset(extent)],
+			dst_subresource: color_subresource(),
+			dst_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, offset(self.extent)],
+		};
+		let present_family = self.present_family.unwrap_or(vk::QUEUE_FAMILY_IGNORED);
+		let src_family = if self.present_family.is_some() {
+			context.queue_family_index
+		} else {
+			vk::QUEUE_FAMILY_IGNORED
+		};
+		sync::record_memory_barrier(
+			context,
+			command_buffer,
+			vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+			vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+			vk::PipelineStageFlags2KHR::BLIT,
+			vk::AccessFlags2KHR::TRANSFER_READ,
+		);
+		sync::record_image_transition(
+			context,
+			command_buffer,
+			swapchain_image,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::BLIT,
+				src_access_mask: vk::AccessFlags2KHR::NONE,
+				dst_access_mask: vk::AccessFlags2KHR::TRANSFER_WRITE,
+				old_layout: vk::ImageLayout::UNDEFINED,
+				new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			},
+		);
+		raw_device(&self.device).cmd_blit_image(
+			command_buffer,
+			image,
+			layout,
+			swapchain_image,
+			vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+			&[region],
+			vk::Filter::LINEAR,
+		);
+		sync::record_queue_transfer(
+			context,
+			command_buffer,
+			swapchain_image,
+			present_transition,
+			src_family,
+			present_family,
+		);
+	}
+
+	/// Records a blit into the command buffer of frame `frame` and submits it to the context's
+	/// queue, after `waits`, without waiting for it to complete. The blit signals the frame's fence
+	/// and, unless images are transferred to another family for presenting, its `blitted`
+	/// semaphore. The frame's last blit must have completed.
+	unsafe fn submit_blit<R: FnOnce(&Self, vk::CommandBuffer)>(
+		&mut self,
+		context: &Context,
+		frame: usize,
+		waits: &[sync::SemaphoreWait],
+		record: R,
+	) -> MarsResult<()> {
+		let device = raw_device(&self.device);
+		let PresentFrame {
+			command_pool,
+			command_buffer,
+			fence,
+			blitted,
+			..
+		} = self.frames[frame];
+		device.reset_command_pool(command_pool, vk::CommandPoolResetFlags::empty())?;
+		let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+		device.begin_command_buffer(command_buffer, &begin_info)?;
+		record(self, command_buffer);
+		device.end_command_buffer(command_buffer)?;
+		device.reset_fences(&[fence])?;
+		let signals = if self.present_family.is_none() {
+			vec![blitted]
+		} else {
+			Vec::new()
+		};
+		let serial = context.destruction.begin_submission(&RecordedUses::default());
+		let queue = &context.queue;
+		let submitted =
+			queue.with_lock(|| sync::queue_submit(context, raw_queue(queue), command_buffer, waits, &signals, fence));
+		if let Err(e) = submitted {
+			context.destruction.end_submission(serial);
+			// The fence will never be signaled, so it's recreated signaled instead of being waited on
+			device.destroy_fence(fence, None);
+			let create_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+			self.frames[frame].fence = device.create_fence(&create_info, None)?;
+			return Err(e);
+		}
+		self.frames[frame].serial = Some(serial);
+		Ok(())
+	}
+
+	/// Waits for the last blit of frame `frame` to complete, and drops the submission it presented.
+	/// The blit is marked as complete even if waiting fails, since the device has been lost then.
+	fn wait_for_frame(&mut self, frame: usize) -> MarsResult<()> {
+		let frame = &mut self.frames[frame];
+		let waited = unsafe { raw_device(&self.device).wait_for_fences(&[frame.fence], true, u64::MAX) };
+		if let Some(serial) = frame.serial.take() {
+			self.destruction.end_submission(serial);
+		}
+		frame.submission = None;
+		waited
+	}
+
+	/// Waits for the blits of every frame to complete
+	fn wait_for_frames(&mut self) -> MarsResult<()> {
+		let mut result = Ok(());
+		for frame in 0..self.frames.len() {
+			result = result.and(self.wait_for_frame(frame));
+		}
+		result
+	}
+
+	/// Records commands into a command buffer from the present family's pool, submits it to the
+	/// present queue, and waits for it to complete
+	unsafe fn submit_on_present_queue<R: FnOnce(vk::CommandBuffer)>(
+		&self,
+		context: &Context,
+		queue: &Queue,
+		record: R,
+	) -> MarsResult<()> {
+		let device = raw_device(&self.device);
+		let allocate_info = vk::CommandBufferAllocateInfo::builder()
+			.command_pool(self.present_command_pool.unwrap())
+			.level(vk::CommandBufferLevel::PRIMARY)
+			.command_buffer_count(1);
+		let command_buffer = device.allocate_command_buffers(&allocate_info)?[0];
+		let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+		let result = device.begin_command_buffer(command_buffer, &begin_info).and_then(|()| {
+			record(command_buffer);
+			device.end_command_buffer(command_buffer)?;
+			sync::submit_and_wait_on(context, queue, command_buffer)
+		});
+		device.free_command_buffers(self.present_command_pool.unwrap(), &[command_buffer]);
+		result
+	}
+}
+
+impl Drop for Swapchain {
+	fn drop(&mut self) {
+		if let Err(e) = self.wait_for_frames() {
+			log::error!("Failed to wait for the blits onto the swapchain: {:?}", e);
+		}
+		unsafe {
+			for frame in &self.frames {
+				frame.destroy(raw_device(&self.device));
+			}
+			self.swapchain_loader.destroy_swapchain(self.swapchain, None);
+			self.surface_loader.destroy_surface(self.surface, None);
+			if let Some(command_pool) = self.present_command_pool {
+				raw_device(&self.device).destroy_command_pool(command_pool, None);
+			}
+		}
+	}
+}
+
+/// Finds the queue family to present to `surface` from, which is `None` if the context's own queue
+/// can present to it. Other families only have queues if the context was created for a window with
+/// `Context::create_for_window`.
+unsafe fn find_present_family(
+	context: &Context,
+	surface_loader: &khr::Surface,
+	surface: vk::SurfaceKHR,
+) -> MarsResult<Option<u32>> {
+	let physical_device = raw_physical_device(&context.physical_device);
+	if surface_loader.get_physical_device_surface_support(physical_device, context.queue_family_index, surface)? {
+		return Ok(None);
+	}
+	for (family, _) in &context.family_queues {
+		if surface_loader.get_physical_device_surface_support(physical_device, *family, surface)? {
+			return Ok(Some(*family));
+		}
+	}
+	Err(vk::Result::ERROR_INCOMPATIBLE_DISPLAY_KHR)
+}
+
+fn family_queue(context: &Context, family: u32) -> &Queue {
+	context
+		.family_queues
+		.iter()
+		.find(|(index, _)| *index == family)
+		.map(|(_, queue)| queue)
+		.unwrap()
+}
+
+pub(crate) unsafe fn create_surface(
+	entry: &ash::Entry,
+	instance: &Instance,
+	handle: RawWindowHandle,
+) -> MarsResult<vk::SurfaceKHR> {
+	let instance = raw_instance(instance);
+	match handle {
+		#[cfg(any(
+			target_os = "linux",
+			target_os = "dragonfly",
+			target_os = "freebsd",
+			target_os = "netbsd",
+			target_os = "openbsd"
+		))]
+		RawWindowHandle::Xlib(handle) => {
+			let create_info = vk::XlibSurfaceCreateInfoKHR::builder()
+				.dpy(handle.display as *mut _)
+				.window(handle.window);
+			khr::XlibSurface::new(entry, instance).create_xlib_surface(&create_info, None)
+		}
+		#[cfg(any(
+			target_os = "linux",
+			target_os = "dragonfly",
+			target_os = "freebsd",
+			target_os = "netbsd",
+			target_os = "openbsd"
+		))]
+		RawWindowHandle::Wayland(handle) => {
+			let create_info = vk::WaylandSurfaceCreateInfoKHR::builder()
+				.display(handle.display)
+				.surface(handle.surface);
+			khr::WaylandSurface::new(entry, instance).create_wayland_surface(&create_info, None)
+		}
+		_ => Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT),
+	}
+}
+
+/// Picks the surface's preferred format, unless the window can be transparent, in which case a
+/// format with a full alpha channel is preferred so the presented alpha isn't quantized away
+fn choose_format(formats: &[vk::SurfaceFormatKHR], composite_alpha: CompositeAlpha) -> vk::SurfaceFormatKHR {
+	const ALPHA_FORMATS: [vk::Format; 4] = [
+		vk::Format::B8G8R8A8_SRGB,
+		vk::Format::B8G8R8A8_UNORM,
+		vk::Format::R8G8B8A8_SRGB,
+		vk::Format::R8G8B8A8_UNORM,
+	];
+	let transparent = matches!(
+		composite_alpha,
+		CompositeAlpha::PreMultiplied | CompositeAlpha::PostMultiplied
+	);
+	if transparent {
+		if let Some(format) = formats.iter().find(|format| ALPHA_FORMATS.contains(&format.format)) {
+			return *format;
+		}
+	}
+	formats[0]
+}
+
+fn color_subresource() -> vk::ImageSubresourceLayers {
+	vk::ImageSubresourceLayers {
+		aspect_mask: vk::ImageAspectFlags::COLOR,
+		mip_level: 0,
+		base_array_layer: 0,
+		layer_count: 1,
+	}
+}
+
+fn offset(extent: vk::Extent2D) -> vk::Offset3D {
+	vk::Offset3D {
+		x: extent.width as i32,
+		y: extent.height as i32,
+		z: 1,
+	}
 }
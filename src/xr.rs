@@ -0,0 +1,358 @@
+//! OpenXR integration, enabled with the `xr` feature.
+//!
+//! A headset renderer creates its `Context` with `create_context` so that the Vulkan instance and
+//! device meet the runtime's requirements, then renders each frame into the targets of an
+//! `XrSession`. Both eyes are drawn at once using a multiview render pass with a view mask of
+//! `0b11`.
+
+use std::{ffi::CString, os::raw::c_void};
+
+use openxr::{self as xr, Vulkan};
+use rk::{
+	vk::{self, Handle},
+	PhysicalDevice, PhysicalDeviceChooser,
+};
+use thiserror::Error;
+
+use crate::{
+	device::DeviceFeatures,
+	image::{usage, DynImageUsage, FormatType, Image, ImageView, SampleCount1},
+	pass::{Attachments, ColorAttachment, ColorClearValue, DepthAttachmentType, RenderPass, RenderPassPrototype},
+	raw_device, raw_instance, raw_physical_device,
+	sync::ImageTransition,
+	target::{Target, TargetError},
+	Context, ContextCreateError, RawExtensions,
+};
+
+const VIEW_TYPE: xr::ViewConfigurationType = xr::ViewConfigurationType::PRIMARY_STEREO;
+
+#[derive(Debug, Error)]
+pub enum XrError {
+	#[error("OpenXR error: {0}")]
+	XrError(#[from] xr::sys::Result),
+	#[error(transparent)]
+	ContextCreateError(#[from] ContextCreateError),
+	#[error("Vulkan error: {0}")]
+	VulkanError(#[from] vk::Result),
+	#[error(transparent)]
+	TargetError(#[from] TargetError),
+	#[error("The OpenXR runtime requires a Vulkan version mars doesn't use ({0})")]
+	UnsupportedVersion(xr::Version),
+	#[error("The OpenXR runtime doesn't support the render pass color format {0:?}")]
+	UnsupportedFormat(vk::Format),
+}
+
+/// Creates a context on the physical device the OpenXR runtime uses for `system`, with the
+/// instance and device extensions the runtime requires
+pub fn create_context(
+	instance: &xr::Instance,
+	system: xr::SystemId,
+	app_name: &str,
+	features: &DeviceFeatures,
+) -> Result<Context, XrError> {
+	// mars creates Vulkan 1.2 instances
+	let requirements = instance.graphics_requirements::<Vulkan>(system)?;
+	let version = xr::Version::new(1, 2, 0);
+	if requirements.min_api_version_supported > version {
+		return Err(XrError::UnsupportedVersion(requirements.min_api_version_supported));
+	}
+
+	let raw_extensions = RawExtensions {
+		instance: extension_names(&instance.vulkan_legacy_instance_extensions(system)?),
+		device: extension_names(&instance.vulkan_legacy_device_extensions(system)?),
+	};
+	let mut xr_error = None;
+	let context = Context::create_inner(app_name, features, &raw_extensions, None, |vk_instance| {
+		let handle = raw_instance(vk_instance).handle();
+		match unsafe { instance.vulkan_graphics_device(system, handle.as_raw() as usize as *const c_void) } {
+			Ok(physical_device) => Ok(XrDeviceChooser(vk::PhysicalDevice::from_raw(
+				physical_device as usize as u64,
+			))),
+			Err(e) => {
+				xr_error = Some(e);
+				Err(ContextCreateError::NoDevice)
+			}
+		}
+	});
+	match (context, xr_error) {
+		(_, Some(e)) => Err(e.into()),
+		(context, None) => Ok(context?),
+	}
+}
+
+fn extension_names(names: &str) -> Vec<CString> {
+	names
+		.split_ascii_whitespace()
+		.map(|name| CString::new(name).unwrap())
+		.collect()
+}
+
+struct XrDeviceChooser(vk::PhysicalDevice);
+
+impl PhysicalDeviceChooser for XrDeviceChooser {
+	fn choose(&self, physical_devices: &[PhysicalDevice]) -> Option<usize> {
+		physical_devices
+			.iter()
+			.position(|physical_device| raw_physical_device(physical_device) == self.0)
+	}
+}
+
+/// A running OpenXR session along with a target for each image of its swapchain
+pub struct XrSession<G, F>
+where
+	G: RenderPassPrototype<SampleCount = SampleCount1, InputAttachments = (), ColorAttachments = (ColorAttachment<F>,)>,
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	// The targets wrap the swapchain images, so they have to be dropped before the swapchain
+	targets: Vec<Target<G>>,
+	swapchain: xr::Swapchain<Vulkan>,
+	stage: xr::Space,
+	frame_waiter: xr::FrameWaiter,
+	frame_stream: xr::FrameStream<Vulkan>,
+	session: xr::Session<Vulkan>,
+	extent: vk::Extent2D,
+	running: bool,
+}
+
+/// A frame begun with `XrSession::begin_frame`, which must be passed to `XrSession::end_frame`
+pub struct XrFrame {
+	pub state: xr::FrameState,
+	/// The pose and field of view of each eye at the predicted display time
+	pub views: Vec<xr::View>,
+	image_index: Option<usize>,
+}
+
+impl<G, F> XrSession<G, F>
+where
+	G: RenderPassPrototype<SampleCount = SampleCount1, InputAttachments = (), ColorAttachments = (ColorAttachment<F>,)>,
+	F: FormatType,
+	F::ClearValue: ColorClearValue,
+{
+	pub fn create(
+		context: &Context,
+		instance: &xr::Instance,
+		system: xr::SystemId,
+		render_pass: &RenderPass<G>,
+	) -> Result<Self, XrError> {
+		assert_eq!(G::VIEW_MASK, 0b11, "OpenXR render passes must render both views");
+
+		let queue_family_index = context.queue_family_index;
+		let (session, frame_waiter, frame_stream) = unsafe {
+			instance.create_session::<Vulkan>(
+				system,
+				&xr::vulkan::SessionCreateInfo {
+					instance: raw_instance(&context.instance).handle().as_raw() as usize as *const c_void,
+					physical_device: raw_physical_device(&context.physical_device).as_raw() as usize as *const c_void,
+					device: raw_device(&context.device).handle().as_raw() as usize as *const c_void,
+					queue_family_index,
+					queue_index: 0,
+				},
+			)?
+		};
+		let stage = session.create_reference_space(xr::ReferenceSpaceType::STAGE, xr::Posef::IDENTITY)?;
+
+		let format = F::as_raw();
+		if !session
+			.enumerate_swapchain_formats()?
+			.contains(&(format.as_raw() as u32))
+		{
+			return Err(XrError::UnsupportedFormat(format));
+		}
+		// Both eyes share one layered image, so they're assumed to have the same resolution
+		let view = instance.enumerate_view_configuration_views(system, VIEW_TYPE)?[0];
+		let extent = vk::Extent2D {
+			width: view.recommended_image_rect_width,
+			height: view.recommended_image_rect_height,
+		};
+		let swapchain = session.create_swapchain(&xr::SwapchainCreateInfo {
+			create_flags: xr::SwapchainCreateFlags::EMPTY,
+			usage_flags: xr::SwapchainUsageFlags::COLOR_ATTACHMENT | xr::SwapchainUsageFlags::TRANSFER_SRC,
+			format: format.as_raw() as u32,
+			sample_count: 1,
+			width: extent.width,
+			height: extent.height,
+			face_count: 1,
+			array_size: 2,
+			mip_count: 1,
+		})?;
+
+		let targets = swapchain
+			.enumerate_images()?
+			.into_iter()
+			.map(|raw| {
+				let image = unsafe {
+					Image::<usage::ColorAttachment, F, SampleCount1>::wrap_raw(
+						context,
+						vk::Image::from_raw(raw),
+						DynImageUsage::COLOR_ATTACHMENT | DynImageUsage::TRANSFER_SRC,
+						extent,
+						2,
+						vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+					)
+				};
+				let view = ImageView::create(&image)?;
+				let attachments = Attachments {
+					extent,
+					input_attachments: (),
+					color_attachments: (ColorAttachment::new(image, view),),
+					depth_attachment: G::DepthAttachment::create(context, DynImageUsage::empty(), extent, 2)?,
+				};
+				Target::create(context, render_pass, attachments)
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(Self {
+			targets,
+			swapchain,
+			stage,
+			frame_waiter,
+			frame_stream,
+			session,
+			extent,
+			running: false,
+		})
+	}
+
+	/// Handles pending OpenXR events, beginning and ending the session as the runtime asks.
+	/// Returns `false` once the session has exited and rendering should stop.
+	pub fn poll_events(&mut self, instance: &xr::Instance) -> Result<bool, XrError> {
+		let mut buffer = xr::EventDataBuffer::new();
+		while let Some(event) = instance.poll_event(&mut buffer)? {
+			if let xr::Event::SessionStateChanged(change) = event {
+				match change.state() {
+					xr::SessionState::READY => {
+						self.session.begin(VIEW_TYPE)?;
+						self.running = true;
+					}
+					xr::SessionState::STOPPING => {
+						self.session.end()?;
+						self.running = false;
+					}
+					xr::SessionState::EXITING | xr::SessionState::LOSS_PENDING => return Ok(false),
+					_ => {}
+				}
+			}
+		}
+		Ok(true)
+	}
+
+	/// Whether the session is running, in which case frames should be submitted
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	pub fn extent(&self) -> vk::Extent2D {
+		self.extent
+	}
+
+	/// Waits for the runtime to be ready for the next frame and begins it. If the runtime wants the
+	/// frame rendered, a swapchain image is acquired and its target is available through `target`.
+	pub fn begin_frame(&mut self, context: &Context) -> Result<XrFrame, XrError> {
+		let state = self.frame_waiter.wait()?;
+		self.frame_stream.begin()?;
+		let (_, views) = self
+			.session
+			.locate_views(VIEW_TYPE, state.predicted_display_time, &self.stage)?;
+
+		let image_index = if state.should_render {
+			let index = self.swapchain.acquire_image()? as usize;
+			self.swapchain.wait_image(xr::Duration::INFINITE)?;
+			// The runtime hands out images in the color attachment layout, while mars keeps color
+			// attachments in the transfer source layout between passes
+			self.color_image(index).transition(
+				context,
+				&ImageTransition {
+					aspect: vk::ImageAspectFlags::COLOR,
+					src_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+					dst_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+					src_access_mask: vk::AccessFlags2KHR::NONE,
+					dst_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_READ
+						| vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+					old_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+					new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				},
+			)?;
+			Some(index)
+		} else {
+			None
+		};
+
+		Ok(XrFrame {
+			state,
+			views,
+			image_index,
+		})
+	}
+
+	/// Returns the target to render the frame into, if the runtime wants it rendered
+	pub fn target(&mut self, frame: &XrFrame) -> Option<&mut Target<G>> {
+		frame.image_index.map(move |index| &mut self.targets[index])
+	}
+
+	/// Hands the rendered frame back to the runtime for display
+	pub fn end_frame(&mut self, context: &Context, frame: XrFrame) -> Result<(), XrError> {
+		#[cfg(feature = "renderdoc")]
+		if let Some(capture) = &context.frame_capture {
+			capture.frame_presented();
+		}
+		let time = frame.state.predicted_display_time;
+		let index = match frame.image_index {
+			Some(index) => index,
+			None => {
+				self.frame_stream.end(time, xr::EnvironmentBlendMode::OPAQUE, &[])?;
+				return Ok(());
+			}
+		};
+
+		self.color_image(index).transition(
+			context,
+			&ImageTransition {
+				aspect: vk::ImageAspectFlags::COLOR,
+				src_stage_mask: vk::PipelineStageFlags2KHR::COLOR_ATTACHMENT_OUTPUT,
+				dst_stage_mask: vk::PipelineStageFlags2KHR::NONE,
+				src_access_mask: vk::AccessFlags2KHR::COLOR_ATTACHMENT_WRITE,
+				dst_access_mask: vk::AccessFlags2KHR::NONE,
+				old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+				new_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+			},
+		)?;
+		self.swapchain.release_image()?;
+
+		let rect = xr::Rect2Di {
+			offset: xr::Offset2Di { x: 0, y: 0 },
+			extent: xr::Extent2Di {
+				width: self.extent.width as i32,
+				height: self.extent.height as i32,
+			},
+		};
+		let projection_views = frame
+			.views
+			.iter()
+			.enumerate()
+			.map(|(eye, view)| {
+				xr::CompositionLayerProjectionView::new()
+					.pose(view.pose)
+					.fov(view.fov)
+					.sub_image(
+						xr::SwapchainSubImage::new()
+							.swapchain(&self.swapchain)
+							.image_array_index(eye as u32)
+							.image_rect(rect),
+					)
+			})
+			.collect::<Vec<_>>();
+		self.frame_stream.end(
+			time,
+			xr::EnvironmentBlendMode::OPAQUE,
+			&[&xr::CompositionLayerProjection::new()
+				.space(&self.stage)
+				.views(&projection_views)],
+		)?;
+		Ok(())
+	}
+
+	fn color_image(&mut self, index: usize) -> &mut Image<usage::ColorAttachment, F, SampleCount1> {
+		&mut self.targets[index].attachments.color_attachments.0.image
+	}
+}